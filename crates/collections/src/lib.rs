@@ -3,6 +3,13 @@
 //!
 //! For now most of the collections comes directly from Cranelift (through the `cranelift_cranelift_entity`
 //! crate that is re-exported from `cranelift_codegen`).
+//!
+//! The kernel heap is small, when the number of entities is known in advance prefer the
+//! capacity-aware constructors (`PrimaryMap::with_capacity`, `SecondaryMap::with_capacity` and
+//! `FrozenMapBuilder::with_capacity`) or `reserve` over growing the maps one entity at a time.
+//!
+//! The iteration order of `HashMap` is not deterministic, prefer `BTreeMap` for maps which are
+//! enumerated into an output, such as the exports of a module.
 
 extern crate alloc;
 use alloc::vec::Vec;
@@ -12,6 +19,7 @@ use core::ops::{Index, IndexMut};
 
 // ——————————————————————————————— Re-Exports ——————————————————————————————— //
 
+pub use alloc::collections::BTreeMap;
 pub use cranelift_entity::entity_impl;
 pub use cranelift_entity::{EntityRef, PrimaryMap, SecondaryMap};
 pub use hashbrown::HashMap;
@@ -21,7 +29,7 @@ pub use hashbrown::HashMap;
 /// A fixed lenght map with tagged indexes.
 ///
 /// The values can still be modified, but the set of key is fixed. A new FrozenMap can be created
-/// either by consuming a PrimaryMap, or by mapping another FrozenMap.
+/// either by consuming a PrimaryMap or a FrozenMapBuilder, or by mapping another FrozenMap.
 #[derive(Debug, Clone)]
 pub struct FrozenMap<K, V> {
     elems: Vec<V>,
@@ -155,6 +163,73 @@ impl<K, V> FrozenMap<K, V> {
     }
 }
 
+/// A builder for `FrozenMap`, entities can be pushed until the map is frozen.
+///
+/// Unlike freezing a `PrimaryMap`, the storage is moved into the `FrozenMap` without being
+/// re-allocated, and can be reserved upfront.
+#[derive(Debug, Clone)]
+pub struct FrozenMapBuilder<K, V> {
+    elems: Vec<V>,
+    unused: PhantomData<K>,
+}
+
+impl<K, V> FrozenMapBuilder<K, V>
+where
+    K: EntityRef,
+{
+    /// Creates an empty builder.
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Creates an empty builder, with room for at least `capacity` elements.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            elems: Vec::with_capacity(capacity),
+            unused: PhantomData,
+        }
+    }
+
+    /// Reserves room for at least `additional` more elements.
+    pub fn reserve(&mut self, additional: usize) {
+        self.elems.reserve(additional);
+    }
+
+    /// Appends an element, returns its key.
+    pub fn push(&mut self, v: V) -> K {
+        let k = K::new(self.elems.len());
+        self.elems.push(v);
+        k
+    }
+
+    /// Get the number of elements in the builder.
+    pub fn len(&self) -> usize {
+        self.elems.len()
+    }
+
+    /// Returns true if no element has been pushed yet.
+    pub fn is_empty(&self) -> bool {
+        self.elems.is_empty()
+    }
+
+    /// Freezes the builder, no new items can be added afterward.
+    pub fn freeze(self) -> FrozenMap<K, V> {
+        FrozenMap {
+            elems: self.elems,
+            unused: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Default for FrozenMapBuilder<K, V>
+where
+    K: EntityRef,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Immutable indexing into a `FrozenMap`.
 impl<K, V> Index<K> for FrozenMap<K, V>
 where
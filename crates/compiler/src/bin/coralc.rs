@@ -1,25 +1,58 @@
 use core::arch::asm;
+use std::collections::HashMap;
 use std::fs;
+use std::sync::Arc;
 
-use coral_compiler::userspace_alloc::LibcAllocator;
-use coral_compiler::X86_64Compiler;
-use wasm::{Compiler, Instance, WasmModule};
+use coral_compiler::userspace_alloc::{MMapArea, Runtime};
+use coral_compiler::{
+    compile_bundle, CompilationStats, Compiler, CompilerOptions, FuncStats, X86_64Compiler,
+};
+use wasm::{
+    FuncInfo, FuncType, GlobInfo, GlobInit, HeapInfo, HeapKind, ImportKind, Instance, ItemRef,
+    Module, TableInfo, WasmModule, NULL_REF,
+};
+
+// Modules are called by hand, following the System V calling convention.
+const _: () = assert!(matches!(wasm::CALL_CONV, wasm::CallConv::SystemV));
+
+/// The inlining threshold reported on by `inspect`.
+const INLINE_THRESHOLD: u32 = 16;
 
 fn main() {
-    println!("Kranelift");
+    println!("Coral compiler");
 
     let args: Vec<String> = std::env::args().collect();
-    if args.len() <= 1 {
-        println!(
-            "Usage: {} <wasm_file> [<import_1_name> <import_1_wasm_file> ...]",
-            args[0]
-        );
-        return;
-    } else {
-        println!("Compiling: {}", &args[1]);
+    match args.get(1).map(String::as_str) {
+        None => usage(&args[0]),
+        Some("inspect") => match args.get(2) {
+            Some(file) => inspect(file),
+            None => usage(&args[0]),
+        },
+        Some("bundle") if args.len() >= 5 && args.len() % 2 == 1 => bundle(&args[2], &args[3..]),
+        Some("bundle") => usage(&args[0]),
+        Some(_) => run(&args),
     }
+}
 
-    let alloc = LibcAllocator::new();
+fn usage(bin: &str) {
+    println!(
+        "Usage: {} <wasm_file> [<import_1_name> <import_1_wasm_file> ...]",
+        bin
+    );
+    println!("       {} inspect <wasm_file>", bin);
+    println!("Modules can be provided in either the binary or the text format.");
+    println!(
+        "       {} bundle <output> <module_1_name> <module_1_wasm_file> ...",
+        bin
+    );
+}
+
+// ——————————————————————————————————— Run —————————————————————————————————— //
+
+/// Compiles and instantiates a module, then calls its `main` function.
+fn run(args: &[String]) {
+    println!("Compiling: {}", &args[1]);
+    let runtime = Runtime::new();
 
     // Iterate over the args 2 by 2, the first item is the module name, the second the file
     let imported_modules = args[2..]
@@ -37,13 +70,13 @@ fn main() {
         .map(|(name, module)| {
             (
                 name.as_str(),
-                Instance::instantiate(module, vec![], &alloc).unwrap(),
+                Arc::new(Instance::instantiate(module, &[], &runtime).unwrap()),
             )
         })
-        .collect::<Vec<(&str, Instance<LibcAllocator>)>>();
+        .collect::<Vec<(&str, Arc<Instance<Arc<MMapArea>>>)>>();
 
     let module = compile(&args[1]);
-    let instance = Instance::instantiate(&module, imported_instances, &alloc).unwrap();
+    let instance = Instance::instantiate(&module, &imported_instances, &runtime).unwrap();
 
     // Great, now let's try to call that function by hand
     unsafe {
@@ -67,15 +100,295 @@ fn main() {
     }
 }
 
+// ————————————————————————————————— Bundle ————————————————————————————————— //
+
+/// Compiles a bundle of modules, given in instantiation order, and writes it to `output`.
+fn bundle(output: &str, args: &[String]) {
+    let modules = args
+        .chunks(2)
+        .map(|arg| (arg[0].as_str(), read(&arg[1])))
+        .collect::<Vec<(&str, Vec<u8>)>>();
+    let modules = modules
+        .iter()
+        .map(|(name, wasm)| (*name, wasm.as_slice()))
+        .collect::<Vec<(&str, &[u8])>>();
+    let bundle = match compile_bundle(&modules, CompilerOptions::default()) {
+        Ok(bundle) => bundle,
+        Err(err) => {
+            println!("Bundle Error: {} ('{}')", err, modules[err.module()].0);
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = fs::write(output, &bundle) {
+        println!("File Error: {}", err);
+        std::process::exit(1);
+    }
+    println!("Bundle: {} modules, {} bytes", modules.len(), bundle.len());
+}
+
+// ————————————————————————————————— Inspect ———————————————————————————————— //
+
+/// Compiles a module and prints its metadata.
+fn inspect(file: &str) {
+    println!("Inspecting: {}", file);
+    let (module, stats) = compile_with_stats(file, CompilerOptions::default());
+    let (shared, _) = compile_with_stats(
+        file,
+        CompilerOptions {
+            shared_traps: true,
+            ..Default::default()
+        },
+    );
+    let (inlined, inlined_stats) = compile_with_stats(
+        file,
+        CompilerOptions {
+            inline_threshold: INLINE_THRESHOLD,
+            ..Default::default()
+        },
+    );
+
+    let exported_as = |item: ItemRef| {
+        let names: Vec<&str> = module.export_names(item).collect();
+        if names.is_empty() {
+            String::new()
+        } else {
+            format!(", exported as {}", names.join(", "))
+        }
+    };
+    let mut import_names: HashMap<ItemRef, String> = HashMap::new();
+    for import in module.imports() {
+        let module_name = &module.import_modules()[import.module];
+        import_names.insert(import.item(), format!("{}.{}", module_name, import.field));
+    }
+    let import_name = |item: ItemRef| &import_names[&item];
+
+    let metadata = module.metadata();
+    println!("\nModule:");
+    println!(
+        "  name: {}",
+        metadata.name.as_deref().unwrap_or("<anonymous>")
+    );
+    println!("  hash: {:016x}", metadata.hash);
+    for producer in &metadata.producers {
+        println!("  produced by {}", producer);
+    }
+    for aliases in &metadata.aliases {
+        println!("  aliases: {}", aliases.names.join(", "));
+    }
+
+    let cpu_features: Vec<_> = module.required_cpu_features().names().collect();
+    println!("\nCPU features: {}", cpu_features.join(", "));
+
+    let size = module.code().len();
+    let shared_size = shared.code().len();
+    println!("\nCode size: {} bytes", size);
+    println!(
+        "  with shared trap blocks: {} bytes ({:+} bytes)",
+        shared_size,
+        shared_size as i64 - size as i64
+    );
+    let inlined_size = inlined.code().len();
+    let inlined_calls: u32 = inlined_stats
+        .values()
+        .map(|stats| stats.inlined_calls)
+        .sum();
+    println!(
+        "  with inlining: {} bytes ({:+} bytes, {} calls inlined)",
+        inlined_size,
+        inlined_size as i64 - size as i64,
+        inlined_calls
+    );
+
+    println!("\nImports:");
+    for import in module.imports() {
+        let kind = match import.kind {
+            ImportKind::Func { index, .. } => format!("func[{}]", index.as_u32()),
+            ImportKind::Heap { index, .. } => format!("memory[{}]", index.as_u32()),
+            ImportKind::Table { index, .. } => format!("table[{}]", index.as_u32()),
+            ImportKind::Glob { index } => format!("global[{}]", index.as_u32()),
+        };
+        println!("  {} as {}", import_name(import.item()), kind);
+    }
+
+    println!("\nFunctions:");
+    for (idx, func) in module.funcs().iter() {
+        let ty = |ty_idx| format_type(&module.types()[ty_idx]);
+        let desc = match func {
+            FuncInfo::Owned {
+                offset,
+                size,
+                ty: ty_idx,
+            } => {
+                let relocs = module
+                    .relocs()
+                    .iter()
+                    .filter(|reloc| *offset <= reloc.offset && reloc.offset < offset + size)
+                    .count();
+                let jump_tables = match stats[idx] {
+                    FuncStats { jump_tables: 0, .. } => String::new(),
+                    FuncStats {
+                        jump_tables,
+                        jump_table_entries,
+                        ..
+                    } => format!(
+                        ", {} jump tables ({} entries)",
+                        jump_tables, jump_table_entries
+                    ),
+                };
+                format!(
+                    "{} at 0x{:x}, {} bytes, {} relocations, {} trap sites{}",
+                    ty(*ty_idx),
+                    offset,
+                    size,
+                    relocs,
+                    stats[idx].trap_sites,
+                    jump_tables
+                )
+            }
+            FuncInfo::Imported { ty: ty_idx } => format!(
+                "{} imported from {}",
+                ty(*ty_idx),
+                import_name(ItemRef::Func(idx))
+            ),
+            FuncInfo::Native { ty: ty_idx, .. } => format!("{} native", ty(*ty_idx)),
+        };
+        println!(
+            "  func[{}] {}{}",
+            idx.as_u32(),
+            desc,
+            exported_as(ItemRef::Func(idx))
+        );
+    }
+
+    println!("\nMemories:");
+    for (idx, heap) in module.heaps().iter() {
+        let desc = match heap {
+            HeapInfo::Owned {
+                min_size,
+                kind: HeapKind::Static { max_size },
+            } => format!("{} to {} pages", min_size, max_size),
+            HeapInfo::Owned {
+                min_size,
+                kind: HeapKind::Dynamic,
+            } => format!("{} pages, growable", min_size),
+            HeapInfo::Imported { min_size } => format!(
+                "{} pages imported from {}",
+                min_size,
+                import_name(ItemRef::Heap(idx))
+            ),
+        };
+        println!(
+            "  memory[{}] {}{}",
+            idx.as_u32(),
+            desc,
+            exported_as(ItemRef::Heap(idx))
+        );
+    }
+
+    println!("\nTables:");
+    for (idx, table) in module.tables().iter() {
+        let desc = match table {
+            TableInfo::Owned {
+                min_size,
+                max_size: Some(max_size),
+                ty,
+            } => format!("{:?}, {} to {} elements", ty, min_size, max_size),
+            TableInfo::Owned {
+                min_size,
+                max_size: None,
+                ty,
+            } => format!("{:?}, {} elements, growable", ty, min_size),
+            TableInfo::Imported { ty } => {
+                format!(
+                    "{:?} imported from {}",
+                    ty,
+                    import_name(ItemRef::Table(idx))
+                )
+            }
+            TableInfo::Native { ptr, ty } => format!("{:?}, {} native elements", ty, ptr.len()),
+            TableInfo::Handles { capacity } => format!("{} handles", capacity),
+        };
+        println!(
+            "  table[{}] {}{}",
+            idx.as_u32(),
+            desc,
+            exported_as(ItemRef::Table(idx))
+        );
+    }
+
+    println!("\nGlobals:");
+    for (idx, glob) in module.globs().iter() {
+        let desc = match glob {
+            GlobInfo::Owned { init } => match init {
+                GlobInit::I32(x) => format!("i32 = {}", x),
+                GlobInit::I64(x) => format!("i64 = {}", x),
+                GlobInit::F32(x) => format!("f32 = {}", f32::from_bits(*x)),
+                GlobInit::F64(x) => format!("f64 = {}", f64::from_bits(*x)),
+                GlobInit::Ref(NULL_REF) => String::from("ref = null"),
+                GlobInit::Ref(x) => format!("ref = {:#x}", x),
+            },
+            GlobInfo::Imported => format!("imported from {}", import_name(ItemRef::Glob(idx))),
+        };
+        println!(
+            "  global[{}] {}{}",
+            idx.as_u32(),
+            desc,
+            exported_as(ItemRef::Glob(idx))
+        );
+    }
+
+    println!("\nCode:");
+    println!("  {} bytes", module.code().len());
+    println!("  {} relocations", module.relocs().len());
+    println!("  {} stack maps", module.stack_maps().len());
+    println!("  {} trap sites", module.trap_sites().len());
+    println!("  {} data segments", module.data_segments().len());
+    println!("  {} table segments", module.table_segments().len());
+    if let Some(start) = module.start() {
+        println!("  start function: func[{}]", start.as_u32());
+    }
+}
+
+fn format_type(ty: &FuncType) -> String {
+    format!("{:?} -> {:?}", ty.args(), ty.ret())
+}
+
+// ————————————————————————————————— Utils —————————————————————————————————— //
+
 fn compile(file: &str) -> WasmModule {
-    let bytecode = match fs::read(file) {
+    compile_with_stats(file, CompilerOptions::default()).0
+}
+
+fn compile_with_stats(file: &str, options: CompilerOptions) -> (WasmModule, CompilationStats) {
+    let bytecode = read(file);
+    let mut comp = X86_64Compiler::with_options(options);
+    if let Err(err) = comp.parse(&bytecode) {
+        println!("Parse Error: {}", err);
+        std::process::exit(1);
+    }
+    match comp.compile_with_stats() {
+        Ok(result) => result,
+        Err(err) => {
+            println!("Compile Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reads a module in either the binary or the text format, detected from the content of the file.
+fn read(file: &str) -> Vec<u8> {
+    let bytes = match fs::read(file) {
         Ok(b) => b,
         Err(err) => {
             println!("File Error: {}", err);
             std::process::exit(1);
         }
     };
-    let mut comp = X86_64Compiler::new();
-    comp.parse(&bytecode).unwrap();
-    comp.compile().unwrap()
+    match wat::parse_bytes(&bytes) {
+        Ok(wasm) => wasm.into_owned(),
+        Err(err) => {
+            println!("Parse Error: {}", err);
+            std::process::exit(1);
+        }
+    }
 }
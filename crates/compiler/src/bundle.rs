@@ -0,0 +1,82 @@
+//! Bundle compilation
+//!
+//! Compiles a group of modules ahead of time and serializes them as a bundle (see
+//! `wasm::Bundle`), with the imports between the modules of the bundle resolved by name.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use wasm::{BundleBuilder, Module};
+
+use crate::compiler::{Compiler, CompilerError, CompilerOptions, X86_64Compiler};
+
+/// The errors that might occur during the compilation of a bundle.
+#[derive(Debug)]
+pub enum BundleCompilerError {
+    /// Two modules of the bundle share the same name.
+    DuplicateName { module: usize },
+    /// A module of the bundle failed to compile.
+    FailedToCompile { module: usize, error: CompilerError },
+}
+
+impl BundleCompilerError {
+    /// Returns the index of the module responsible for the error.
+    pub fn module(&self) -> usize {
+        match self {
+            BundleCompilerError::DuplicateName { module }
+            | BundleCompilerError::FailedToCompile { module, .. } => *module,
+        }
+    }
+}
+
+impl fmt::Display for BundleCompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleCompilerError::DuplicateName { module } => {
+                write!(f, "module {}: duplicate module name", module)
+            }
+            BundleCompilerError::FailedToCompile { module, error } => {
+                write!(f, "module {}: {}", module, error)
+            }
+        }
+    }
+}
+
+/// Compiles a bundle of named modules, given in instantiation order, and returns its serialized
+/// form.
+///
+/// Each module is compiled to be validated, the imports of a module are then bound to the
+/// previous modules of the bundle with the same name. The remaining imports are left to the
+/// embedder.
+///
+/// As for modules, the same inputs always produce the same bundle bytes.
+pub fn compile_bundle(
+    modules: &[(&str, &[u8])],
+    options: CompilerOptions,
+) -> Result<Vec<u8>, BundleCompilerError> {
+    let mut bundle = BundleBuilder::new();
+    for (idx, (name, wasm)) in modules.iter().enumerate() {
+        let previous = &modules[..idx];
+        if previous.iter().any(|(other, _)| other == name) {
+            return Err(BundleCompilerError::DuplicateName { module: idx });
+        }
+
+        let failed = |error| BundleCompilerError::FailedToCompile { module: idx, error };
+        let mut compiler = X86_64Compiler::with_options(options);
+        compiler.parse(wasm).map_err(failed)?;
+        let module = compiler.compile().map_err(failed)?;
+
+        let mut links: Vec<(String, usize)> = Vec::new();
+        for import in module.import_modules().values() {
+            if links.iter().any(|(linked, _)| linked == import) {
+                continue;
+            }
+            if let Some(target) = previous.iter().position(|(other, _)| other == import) {
+                links.push((import.clone(), target));
+            }
+        }
+        bundle.add_module(name, &links, wasm);
+    }
+    Ok(bundle.finish())
+}
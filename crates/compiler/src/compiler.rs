@@ -1,126 +1,392 @@
 use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
-use core::mem;
+use core::{fmt, mem};
 
 use cranelift_codegen::binemit::Reloc as CraneliftRelocKind;
-use cranelift_codegen::{ir, isa, settings, CodegenError, MachReloc};
+use cranelift_codegen::settings::Configurable;
+use cranelift_codegen::{ir, isa, settings, CodegenError, MachReloc, MachStackMap, MachTrap};
 use cranelift_wasm::{
     translate_module, GlobalInit, ModuleTranslationState, WasmError, WasmFuncType, WasmType,
 };
 
-use collections::{EntityRef, FrozenMap, PrimaryMap, SecondaryMap};
+use collections::{EntityRef, FrozenMap, FrozenMapBuilder, HashMap, SecondaryMap};
 use wasm::{
-    DataSegment, FuncIndex, FuncInfo, FuncType, GlobIndex, GlobInfo, GlobInit, HeapIndex, HeapInfo,
-    HeapKind, ItemRef, ModuleInfo, RefType, Reloc, RelocKind, TableIndex, TableInfo, TableSegment,
-    TypeIndex, ValueType, WasmModule,
+    CpuFeatures, DataSegment, FuncIndex, FuncInfo, FuncType, GlobIndex, GlobInfo, GlobInit,
+    HeapIndex, HeapInfo, HeapKind, Import, ImportKind, ItemRef, ModuleInfo, ModuleMetadata,
+    RefType, Reloc, RelocKind, StackMap, TableIndex, TableInfo, TableSegment, TrapCode, TrapSite,
+    TypeIndex, ValueType, WasmLocation, WasmModule, NULL_REF,
 };
 
 use crate::env;
+use crate::inline::SmallFuncs;
 
 // ————————————————————————————————— Traits ————————————————————————————————— //
 
 /// The errors that might occur during compilation.
 ///
 /// TODO: collect cummulated errors.
-/// NOTE: We don't want to allocate in the error path as any allocation can fail.
+/// NOTE: We don't want to allocate in the error path as any allocation can fail, the export name
+/// of a failing function is moved out of the module rather than cloned.
 #[derive(Debug)]
 pub enum CompilerError {
     FailedToParse(WasmError),
-    FailedToCompile(CodegenError),
+    /// The body of a function could not be translated.
+    FailedToTranslate {
+        func: FuncIndex,
+        /// The first name under which the function is exported, if any.
+        name: Option<String>,
+        error: WasmError,
+    },
+    /// The code generation of a function failed.
+    FailedToCompile {
+        func: FuncIndex,
+        /// The first name under which the function is exported, if any.
+        name: Option<String>,
+        error: CodegenError,
+    },
+    /// The module relies on a WebAssembly proposal which is recognized but not supported yet.
+    UnsupportedFeature(Proposal),
+    /// The module imports an item which is not in the allow-list (see `parse_with_allow_list`).
+    ImportNotAllowed {
+        module: String,
+        name: String,
+    },
+}
+
+/// The WebAssembly proposals that are recognized but not supported by the compiler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proposal {
+    /// Tags and the `try`, `catch`, `throw`, `rethrow` and `delegate` instructions.
+    ExceptionHandling,
 }
 
 pub type CompilerResult<T> = Result<T, CompilerError>;
 
+impl CompilerError {
+    /// Returns the index of the function which failed to compile, if the error is specific to a
+    /// function.
+    pub fn func(&self) -> Option<FuncIndex> {
+        match self {
+            CompilerError::FailedToTranslate { func, .. }
+            | CompilerError::FailedToCompile { func, .. } => Some(*func),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompilerError::FailedToParse(err) => write!(f, "failed to parse: {:?}", err),
+            CompilerError::FailedToTranslate { func, name, error } => {
+                write!(f, "failed to translate ")?;
+                write_func(f, *func, name)?;
+                write!(f, ": {:?}", error)
+            }
+            CompilerError::FailedToCompile { func, name, error } => {
+                write!(f, "failed to compile ")?;
+                write_func(f, *func, name)?;
+                write!(f, ": {:?}", error)
+            }
+            CompilerError::UnsupportedFeature(proposal) => {
+                write!(f, "unsupported feature: {:?}", proposal)
+            }
+            CompilerError::ImportNotAllowed { module, name } => {
+                write!(f, "import not allowed: '{}.{}'", module, name)
+            }
+        }
+    }
+}
+
+/// Writes a function index, followed by its export name if any.
+fn write_func(f: &mut fmt::Formatter<'_>, func: FuncIndex, name: &Option<String>) -> fmt::Result {
+    write!(f, "function {}", func.index())?;
+    match name {
+        Some(name) => write!(f, " ('{}')", name),
+        None => Ok(()),
+    }
+}
+
 pub trait Compiler {
     type Module;
 
     fn parse(&mut self, wasm_bytecode: &[u8]) -> CompilerResult<()>;
+
+    /// Compiles the parsed module.
+    ///
+    /// Compilation is deterministic: the same bytecode compiled with the same options always
+    /// yields the same code and metadata, byte for byte. Hash maps are only used for lookups
+    /// during compilation, never enumerated into the output.
     fn compile(self) -> CompilerResult<Self::Module>;
 }
 
 // ———————————————————————————————— Compiler ———————————————————————————————— //
 
+/// Code generation options.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompilerOptions {
+    /// Emit position-independent code.
+    ///
+    /// Code never embeds absolute addresses, symbols that are not reachable through a relative
+    /// call are loaded from a global offset table appended to the code. The only absolute
+    /// relocations left are the entries of that table.
+    pub position_independent: bool,
+
+    /// Trap on memory accesses whose address is not aligned to their alignment hint.
+    ///
+    /// The specification only requires atomic accesses to be aligned, which are always checked,
+    /// and treats the hints of other accesses as a performance hint. With strict alignment the
+    /// hints are enforced instead, and accesses hinted as naturally aligned are compiled as such.
+    pub strict_alignment: bool,
+
+    /// The CPU features the generated code may use, `None` for `CpuFeatures::BASELINE`.
+    ///
+    /// The features are recorded in the module, which can only be instantiated by runtimes
+    /// supporting all of them.
+    pub cpu_features: Option<CpuFeatures>,
+
+    /// Run the Cranelift optimization passes, favoring code size.
+    ///
+    /// This enables constant folding and the other peephole rewrites of the pre-optimization
+    /// pass, loop invariant code motion, global value numbering and dead code elimination. The
+    /// code of a module is copied on each instantiation, so the passes are tuned for size.
+    pub optimize: bool,
+
+    /// Count the calls to each imported function.
+    ///
+    /// The counters live in the VMContext, each direct call to an imported function increments
+    /// its counter. Calls through tables are not counted. The counts are reported by
+    /// `Instance::metrics`.
+    pub call_counters: bool,
+
+    /// Check for preemption requests at loop headers and after calls.
+    ///
+    /// Each check loads the preemption flag from the VMContext and, once preemption has been
+    /// requested (see `Instance::request_preemption`), clears it and calls the preemption handler
+    /// of the runtime. This lets the runtime preempt long running code at well known points,
+    /// without capturing the interrupted state of the guest.
+    pub preemption_checks: bool,
+
+    /// Share the trap blocks of a function.
+    ///
+    /// Each bounds check or conditional trap otherwise branches to its own trap instruction, laid
+    /// out inline. With shared trap blocks all the checks of a function raising the same trap
+    /// jump to a single out-of-line trap block, which shrinks the code. The trap location of these
+    /// traps then only identifies the function, not the offending instruction.
+    pub shared_traps: bool,
+
+    /// Replace the NaNs produced by float arithmetic by the canonical NaN.
+    ///
+    /// The specification lets arithmetic operations return any NaN, and the hardware picks the
+    /// payload and sign from the operands or its own default (negative on x86). With canonical
+    /// NaNs the arithmetic operations (`add`, `sub`, `mul`, `div`, `min`, `max`, `sqrt`, and the
+    /// rounding operations) yield the same bits on every instance and machine, so that executions
+    /// are reproducible and the state of instances can be compared or migrated. The other
+    /// operations (e.g. `neg`, `copysign`, `promote`, `demote`) still propagate the bits of their
+    /// operand, NaNs entering the module (e.g. through memory or imports) are not canonicalized.
+    pub canonicalize_nans: bool,
+
+    /// Inline the direct calls to functions of at most this many Cranelift instructions, 0
+    /// disables inlining.
+    ///
+    /// Only functions performing no calls are inlined, from their original body, so inlining
+    /// never cascades. Inlining saves the cost of the calls and lets the optimizations see through
+    /// them, at the cost of a larger code. Traps raised by inlined code are reported at the call
+    /// site.
+    pub inline_threshold: u32,
+}
+
+/// The imports a module is allowed to declare.
+///
+/// Imports are matched by module and name, the name "*" matches all the items of a module (e.g.
+/// `("coral", "*")`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportAllowList {
+    allowed: Vec<(String, String)>,
+}
+
+impl ImportAllowList {
+    /// No import is allowed.
+    pub const fn new() -> Self {
+        Self {
+            allowed: Vec::new(),
+        }
+    }
+
+    /// Allows the import of an item, or of all the items of the module if the name is "*".
+    pub fn allow(mut self, module: &str, name: &str) -> Self {
+        self.allowed
+            .push((String::from(module), String::from(name)));
+        self
+    }
+
+    pub fn allows(&self, module: &str, name: &str) -> bool {
+        self.allowed.iter().any(|(allowed_module, allowed_name)| {
+            allowed_module == module && (allowed_name == "*" || allowed_name == name)
+        })
+    }
+}
+
+/// The Cranelift ISA flag corresponding to each CPU feature.
+const ISA_FLAGS: [(CpuFeatures, &str); 10] = [
+    (CpuFeatures::SSE3, "has_sse3"),
+    (CpuFeatures::SSSE3, "has_ssse3"),
+    (CpuFeatures::SSE41, "has_sse41"),
+    (CpuFeatures::SSE42, "has_sse42"),
+    (CpuFeatures::POPCNT, "has_popcnt"),
+    (CpuFeatures::AVX, "has_avx"),
+    (CpuFeatures::AVX2, "has_avx2"),
+    (CpuFeatures::BMI1, "has_bmi1"),
+    (CpuFeatures::BMI2, "has_bmi2"),
+    (CpuFeatures::LZCNT, "has_lzcnt"),
+];
+
+/// Statistics about the code generated for a function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FuncStats {
+    /// The number of jump tables, one per `br_table` with at least one target.
+    pub jump_tables: u32,
+    /// The total number of entries of the jump tables.
+    pub jump_table_entries: u32,
+    /// The number of trap sites, i.e. instructions that might trap.
+    pub trap_sites: u32,
+    /// The number of calls inlined into the function.
+    pub inlined_calls: u32,
+}
+
+/// The statistics of each function defined by the module.
+pub type CompilationStats = SecondaryMap<FuncIndex, FuncStats>;
+
 pub struct X86_64Compiler {
     module: env::ModuleEnvironment,
     module_metadata: Option<ModuleTranslationState>,
     target_isa: Box<dyn isa::TargetIsa>,
+    cpu_features: CpuFeatures,
+    shared_traps: bool,
+    inline_threshold: u32,
 }
 
 impl X86_64Compiler {
     pub fn new() -> Self {
-        let flags = settings::Flags::new(settings::builder());
-        let target_isa = isa::lookup_by_name("x86_64")
-            .unwrap()
-            .finish(flags)
-            .unwrap();
-        let module = env::ModuleEnvironment::new(target_isa.frontend_config());
+        Self::with_options(CompilerOptions::default())
+    }
+
+    pub fn with_options(options: CompilerOptions) -> Self {
+        let mut flags = settings::builder();
+        // Emit stack maps for reference types (i.e. externrefs)
+        flags.enable("enable_safepoints").unwrap();
+        // Lower `br_table` to jump tables rather than to chains of conditional branches
+        flags.enable("enable_jump_tables").unwrap();
+        if options.position_independent {
+            flags.enable("is_pic").unwrap();
+        }
+        if options.optimize {
+            flags.set("opt_level", "speed_and_size").unwrap();
+        }
+        if options.canonicalize_nans {
+            flags.enable("enable_nan_canonicalization").unwrap();
+        }
+        let flags = settings::Flags::new(flags);
+        let cpu_features = options.cpu_features.unwrap_or(CpuFeatures::BASELINE);
+        let mut isa_builder = isa::lookup_by_name("x86_64").unwrap();
+        for (feature, flag) in ISA_FLAGS {
+            let enabled = if cpu_features.contains(feature) {
+                "true"
+            } else {
+                "false"
+            };
+            isa_builder.set(flag, enabled).unwrap();
+        }
+        let target_isa = isa_builder.finish(flags).unwrap();
+        let module = env::ModuleEnvironment::new(
+            target_isa.frontend_config(),
+            options.strict_alignment,
+            options.call_counters,
+            options.preemption_checks,
+        );
 
         Self {
             module,
             target_isa,
             module_metadata: None,
+            cpu_features,
+            shared_traps: options.shared_traps,
+            inline_threshold: options.inline_threshold,
         }
     }
 
     /// Builds the type information.
     fn build_types(module_info: &mut env::ModuleInfo) -> FrozenMap<TypeIndex, FuncType> {
-        let mut types = PrimaryMap::with_capacity(module_info.types.len());
+        let mut types = FrozenMapBuilder::with_capacity(module_info.types.len());
         for (_ty_idx, ty) in mem::take(&mut module_info.types) {
             types.push(as_func_type(ty));
         }
-        FrozenMap::freeze(types)
+        types.freeze()
     }
 
-    /// Builds the function information and collect exported names.
+    /// Builds the function information, collect exported names and imports.
     fn build_funcs(
         module_info: &mut env::ModuleInfo,
+        imports: &mut Vec<Import>,
     ) -> (
         FrozenMap<FuncIndex, FuncInfo>,
         SecondaryMap<FuncIndex, Vec<String>>,
     ) {
-        let mut funcs = PrimaryMap::with_capacity(module_info.funcs.len());
-        let mut funcs_names = SecondaryMap::new();
+        let mut funcs = FrozenMapBuilder::with_capacity(module_info.funcs.len());
+        let mut funcs_names = SecondaryMap::with_capacity(module_info.funcs.len());
         for (func_idx, func_names) in mem::take(&mut module_info.funcs) {
             // We move out with `take` to avoid cloning the name
             let ty = TypeIndex::from_u32(func_names.entity.as_u32());
             let func = if let Some(import_info) = module_info.imported_funcs[func_idx].take() {
-                FuncInfo::Imported {
+                imports.push(Import {
                     module: import_info.module,
-                    name: import_info.name,
-                    ty,
-                }
+                    field: import_info.name,
+                    kind: ImportKind::Func {
+                        index: FuncIndex::from_u32(func_idx.as_u32()),
+                        ty,
+                    },
+                });
+                FuncInfo::Imported { ty }
             } else {
                 FuncInfo::Owned {
                     // WARNING: The offset **must** be set once known!
                     offset: 0,
+                    size: 0,
                     ty,
                 }
             };
             let func_idx = funcs.push(func);
             funcs_names[func_idx] = func_names.export_names;
         }
-        (FrozenMap::freeze(funcs), funcs_names)
+        (funcs.freeze(), funcs_names)
     }
 
-    /// Builds heap information and collect exported names.
+    /// Builds heap information, collect exported names and imports.
     fn build_heaps(
         module_info: &mut env::ModuleInfo,
+        imports: &mut Vec<Import>,
     ) -> (
         FrozenMap<HeapIndex, HeapInfo>,
         SecondaryMap<HeapIndex, Vec<String>>,
     ) {
-        let mut heaps = PrimaryMap::new();
-        let mut heaps_names = SecondaryMap::new();
+        let mut heaps = FrozenMapBuilder::with_capacity(module_info.heaps.len());
+        let mut heaps_names = SecondaryMap::with_capacity(module_info.heaps.len());
         for (heap_idx, heap) in mem::take(&mut module_info.heaps) {
             let names = heap.export_names;
             let heap = heap.entity;
             let min_size = heap.minimum as u32;
             let heap = if let Some(import_info) = module_info.imported_heaps[heap_idx].take() {
-                HeapInfo::Imported {
+                imports.push(Import {
                     module: import_info.module,
-                    name: import_info.name,
-                }
+                    field: import_info.name,
+                    kind: ImportKind::Heap {
+                        index: HeapIndex::from_u32(heap_idx.as_u32()),
+                        min_size,
+                    },
+                });
+                HeapInfo::Imported { min_size }
             } else {
                 match heap.maximum {
                     Some(max_size) => HeapInfo::Owned {
@@ -138,29 +404,34 @@ impl X86_64Compiler {
             let heap_idx = heaps.push(heap);
             heaps_names[heap_idx] = names;
         }
-        (FrozenMap::freeze(heaps), heaps_names)
+        (heaps.freeze(), heaps_names)
     }
 
-    /// Builds table information and collect exported names.
+    /// Builds table information, collect exported names and imports.
     fn build_tables(
         module_info: &mut env::ModuleInfo,
+        imports: &mut Vec<Import>,
     ) -> (
         FrozenMap<TableIndex, TableInfo>,
         SecondaryMap<TableIndex, Vec<String>>,
     ) {
-        let mut tables = PrimaryMap::new();
-        let mut tables_names = SecondaryMap::new();
+        let mut tables = FrozenMapBuilder::with_capacity(module_info.tables.len());
+        let mut tables_names = SecondaryMap::with_capacity(module_info.tables.len());
         for (table_idx, table) in mem::take(&mut module_info.tables) {
             // TODO: keep type information into `TableInfo`
             let names = table.export_names;
             let table = table.entity;
             let ty = as_ref_type(table.wasm_ty).expect("Table of non-reference type");
             let table = if let Some(import_info) = module_info.imported_tables[table_idx].take() {
-                TableInfo::Imported {
+                imports.push(Import {
                     module: import_info.module,
-                    name: import_info.name,
-                    ty,
-                }
+                    field: import_info.name,
+                    kind: ImportKind::Table {
+                        index: TableIndex::from_u32(table_idx.as_u32()),
+                        ty,
+                    },
+                });
+                TableInfo::Imported { ty }
             } else {
                 TableInfo::Owned {
                     min_size: table.minimum,
@@ -171,27 +442,32 @@ impl X86_64Compiler {
             let table_idx = tables.push(table);
             tables_names[table_idx] = names;
         }
-        (FrozenMap::freeze(tables), tables_names)
+        (tables.freeze(), tables_names)
     }
 
-    /// Builds global information and collect exported names.
+    /// Builds global information, collect exported names and imports.
     fn build_globs(
         module_info: &mut env::ModuleInfo,
+        imports: &mut Vec<Import>,
     ) -> (
         FrozenMap<GlobIndex, GlobInfo>,
         SecondaryMap<GlobIndex, Vec<String>>,
     ) {
-        let mut globs = PrimaryMap::new();
-        let mut globs_names = SecondaryMap::new();
+        let mut globs = FrozenMapBuilder::with_capacity(module_info.globs.len());
+        let mut globs_names = SecondaryMap::with_capacity(module_info.globs.len());
         for (glob_idx, glob) in mem::take(&mut module_info.globs) {
             let names = glob.export_names;
             let glob = glob.entity;
             // We move out with `take` to avoid cloning the name
             let glob = if let Some(import_info) = module_info.imported_globs[glob_idx].take() {
-                GlobInfo::Imported {
+                imports.push(Import {
                     module: import_info.module,
-                    name: import_info.name,
-                }
+                    field: import_info.name,
+                    kind: ImportKind::Glob {
+                        index: GlobIndex::from_u32(glob_idx.as_u32()),
+                    },
+                });
+                GlobInfo::Imported
             } else {
                 let init = convert_glob_init(glob.initializer);
                 GlobInfo::Owned { init }
@@ -199,7 +475,18 @@ impl X86_64Compiler {
             let glob_idx = globs.push(glob);
             globs_names[glob_idx] = names;
         }
-        (FrozenMap::freeze(globs), globs_names)
+        (globs.freeze(), globs_names)
+    }
+
+    /// Sorts the imports in the order of the import section, where kinds can be interleaved.
+    fn sort_imports(module_info: &env::ModuleInfo, imports: &mut [Import]) {
+        let order: HashMap<ItemRef, usize> = module_info
+            .import_order
+            .iter()
+            .enumerate()
+            .map(|(position, item)| (*item, position))
+            .collect();
+        imports.sort_by_key(|import| order[&import.item()]);
     }
 
     /// Builds data segments.
@@ -228,10 +515,14 @@ impl X86_64Compiler {
                     .base
                     .map(|glob_idx| GlobIndex::from_u32(glob_idx.as_u32())),
                 offset: segment.offset,
+                // Null elements are encoded with the reserved function index, u32::MAX
                 elements: segment
                     .elements
                     .iter()
-                    .map(|func_idx| FuncIndex::from_u32(func_idx.as_u32()))
+                    .map(|func_idx| match func_idx.as_u32() {
+                        u32::MAX => None,
+                        idx => Some(FuncIndex::from_u32(idx)),
+                    })
                     .collect(),
             })
         }
@@ -243,24 +534,65 @@ impl Compiler for X86_64Compiler {
     type Module = WasmModule;
 
     fn parse(&mut self, wasm_bytecode: &[u8]) -> CompilerResult<()> {
+        self.module.info.metadata.hash = ModuleMetadata::content_hash(wasm_bytecode);
         let translation_result = translate_module(wasm_bytecode, &mut self.module);
         match translation_result {
             Ok(module) => {
                 self.module_metadata = Some(module);
                 Ok(())
             }
-            Err(err) => Err(CompilerError::FailedToParse(err)),
+            Err(_) if self.module.denied_import.is_some() => {
+                let (module, name) = self.module.denied_import.take().unwrap();
+                Err(CompilerError::ImportNotAllowed { module, name })
+            }
+            Err(err) => match (self.module.unsupported, self.module.failed_func) {
+                (Some(proposal), _) => Err(CompilerError::UnsupportedFeature(proposal)),
+                (None, Some(func_idx)) => Err(CompilerError::FailedToTranslate {
+                    func: FuncIndex::from_u32(func_idx.as_u32()),
+                    name: take_first(&mut self.module.info.funcs[func_idx].export_names),
+                    error: err,
+                }),
+                (None, None) => Err(CompilerError::FailedToParse(err)),
+            },
         }
     }
 
     fn compile(self) -> CompilerResult<WasmModule> {
+        self.compile_with_stats().map(|(module, _stats)| module)
+    }
+}
+
+impl X86_64Compiler {
+    /// Parses a module which may only import the items of the allow-list, the module is rejected
+    /// with `CompilerError::ImportNotAllowed` otherwise.
+    pub fn parse_with_allow_list(
+        &mut self,
+        wasm_bytecode: &[u8],
+        allow_list: &ImportAllowList,
+    ) -> CompilerResult<()> {
+        self.module.allow_list = Some(allow_list.clone());
+        let result = self.parse(wasm_bytecode);
+        self.module.allow_list = None;
+        result
+    }
+
+    /// Compiles the module, and returns statistics about the generated code.
+    pub fn compile_with_stats(self) -> CompilerResult<(WasmModule, CompilationStats)> {
+        self.start_compilation().finish()
+    }
+
+    /// Prepares the compilation of the module, the functions are then compiled one at a time.
+    pub fn start_compilation(self) -> Compilation {
         let mut module_info = self.module.info;
+        let nb_funcs = module_info.funcs.len();
 
+        let mut imports = Vec::new();
         let types = Self::build_types(&mut module_info);
-        let (funcs, funcs_names) = Self::build_funcs(&mut module_info);
-        let (heaps, heaps_names) = Self::build_heaps(&mut module_info);
-        let (globs, globs_names) = Self::build_globs(&mut module_info);
-        let (tables, tables_names) = Self::build_tables(&mut module_info);
+        let (funcs, funcs_names) = Self::build_funcs(&mut module_info, &mut imports);
+        let (heaps, heaps_names) = Self::build_heaps(&mut module_info, &mut imports);
+        let (globs, globs_names) = Self::build_globs(&mut module_info, &mut imports);
+        let (tables, tables_names) = Self::build_tables(&mut module_info, &mut imports);
+        Self::sort_imports(&module_info, &mut imports);
         let segments = Self::build_segments(&mut module_info);
         let elements = Self::build_elements(&mut module_info);
         let modules = FrozenMap::freeze(module_info.modules);
@@ -271,8 +603,18 @@ impl Compiler for X86_64Compiler {
             .map(|idx| FuncIndex::from_u32(idx.as_u32()));
 
         let mut mod_info = ModuleInfo::new(
-            funcs, types, heaps, tables, globs, modules, segments, elements, start,
+            funcs, types, heaps, tables, globs, modules, imports, segments, elements, start,
         );
+        // Cranelift does not report which instructions it selected, all the enabled features are
+        // conservatively required.
+        mod_info.require_cpu_features(self.cpu_features);
+        if module_info.call_counters {
+            mod_info.enable_call_counters();
+        }
+        if module_info.preemption_checks {
+            mod_info.enable_preemption_checks();
+        }
+        mod_info.set_metadata(module_info.metadata);
         for (func_idx, names) in funcs_names.iter() {
             mod_info.export_func(func_idx, names);
         }
@@ -286,30 +628,159 @@ impl Compiler for X86_64Compiler {
             mod_info.export_glob(glob_idx, names);
         }
 
-        let mut code = Vec::new();
-        let mut relocs = RelocationHandler::new();
+        let mut func_bodies = module_info
+            .func_bodies
+            .into_iter()
+            .map(|(_, body)| body)
+            .collect::<Vec<(ir::Function, cranelift_wasm::FuncIndex)>>();
+        let mut stats = CompilationStats::with_capacity(nb_funcs);
+        let small_funcs = SmallFuncs::collect(&func_bodies, self.inline_threshold);
+        for (func, func_idx) in &mut func_bodies {
+            stats[FuncIndex::new(func_idx.index())].inlined_calls = small_funcs.inline_calls(func);
+        }
+        Compilation {
+            target_isa: self.target_isa,
+            shared_traps: self.shared_traps,
+            mod_info,
+            nb_bodies: func_bodies.len(),
+            func_bodies: func_bodies.into_iter(),
+            code: Vec::new(),
+            relocs: RelocationHandler::new(),
+            stack_maps: Vec::new(),
+            trap_sites: Vec::new(),
+            func_offsets: SecondaryMap::with_capacity(nb_funcs),
+            funcs_names,
+            stats,
+        }
+    }
+}
+
+/// A compilation in progress.
+///
+/// The functions are compiled one at a time, which lets the caller interleave the compilation with
+/// other work and report its progress.
+pub struct Compilation {
+    target_isa: Box<dyn isa::TargetIsa>,
+    shared_traps: bool,
+    mod_info: ModuleInfo,
+    /// The total number of function bodies.
+    nb_bodies: usize,
+    /// The function bodies that remain to be compiled.
+    func_bodies: vec::IntoIter<(ir::Function, cranelift_wasm::FuncIndex)>,
+    code: Vec<u8>,
+    relocs: RelocationHandler,
+    stack_maps: Vec<StackMap>,
+    trap_sites: Vec<TrapSite>,
+    func_offsets: SecondaryMap<FuncIndex, Option<u32>>,
+    /// The export names of the functions, used for error reporting.
+    funcs_names: SecondaryMap<FuncIndex, Vec<String>>,
+    stats: CompilationStats,
+}
+
+impl Compilation {
+    /// Returns the number of functions compiled so far, and the total number of functions to
+    /// compile.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.nb_bodies - self.func_bodies.len(), self.nb_bodies)
+    }
+
+    /// Returns true once all the functions have been compiled.
+    pub fn is_done(&self) -> bool {
+        self.func_bodies.len() == 0
+    }
+
+    /// Compiles the next function, returns false if all the functions were already compiled.
+    pub fn compile_next(&mut self) -> CompilerResult<bool> {
+        let (func, func_idx) = match self.func_bodies.next() {
+            Some(body) => body,
+            None => return Ok(false),
+        };
+        let code = &mut self.code;
+        let offset = code.len() as u32;
+        // transmute index from cranelift_wasm to internal
+        let func_idx = FuncIndex::new(func_idx.index());
+        self.mod_info.update_func_offset(func_idx, offset);
+        self.func_offsets[func_idx] = Some(offset);
+        let mut ctx = cranelift_codegen::Context::for_function(func);
+        if self.shared_traps {
+            // The trap blocks are introduced by the legalizer, which is idempotent
+            ctx.compute_cfg();
+            if let Err(err) = ctx.legalize(&*self.target_isa) {
+                return Err(self.compile_error(func_idx, err));
+            }
+            share_trap_blocks(&mut ctx.func);
+            ctx.compute_cfg();
+        }
 
-        // Compile and emit to memory
-        for (_, (func, func_idx)) in module_info.func_bodies.into_iter() {
-            let offset = code.len() as u32;
-            // transmute index from cranelift_wasm to internal
-            let func_idx = FuncIndex::new(func_idx.index());
-            mod_info.update_func_offset(func_idx, offset);
-            // let fun_info = &self.module.info.funcs[func_idx];
-            // mod_info.register_func(&fun_info.export_names, offset);
-            let mut ctx = cranelift_codegen::Context::for_function(func);
+        self.relocs.set_offset(offset);
+        if let Err(err) = ctx.compile_and_emit(&*self.target_isa, code) {
+            return Err(self.compile_error(func_idx, err));
+        }
+        self.mod_info
+            .update_func_size(func_idx, code.len() as u32 - offset);
+        let result = ctx.mach_compile_result.unwrap().buffer;
+        self.relocs.extend_relocs(result.relocs());
+        self.stack_maps.extend(
+            result
+                .stack_maps()
+                .iter()
+                .map(|stack_map| convert_stack_map(stack_map, offset)),
+        );
+        self.trap_sites.extend(
+            result
+                .traps()
+                .iter()
+                .map(|trap| convert_trap(trap, func_idx, offset)),
+        );
+        self.stats[func_idx] = FuncStats {
+            jump_tables: ctx.func.jump_tables.len() as u32,
+            jump_table_entries: ctx
+                .func
+                .jump_tables
+                .values()
+                .map(|table| table.len() as u32)
+                .sum(),
+            trap_sites: result.traps().len() as u32,
+            ..self.stats[func_idx]
+        };
+        Ok(true)
+    }
 
-            relocs.set_offset(offset);
-            ctx.compile_and_emit(&*self.target_isa, &mut code)
-                .map_err(|err| CompilerError::FailedToCompile(err))?; // TODO: better error handling
-            let result = ctx.mach_compile_result.unwrap().buffer;
-            relocs.extend_relocs(result.relocs());
+    /// Builds the error reported when the code generation of a function fails.
+    fn compile_error(&mut self, func_idx: FuncIndex, error: CodegenError) -> CompilerError {
+        CompilerError::FailedToCompile {
+            func: func_idx,
+            name: take_first(&mut self.funcs_names[func_idx]),
+            error,
         }
+    }
 
-        Ok(WasmModule::new(mod_info, code, relocs.relocs))
+    /// Compiles the remaining functions, if any, and returns the module together with statistics
+    /// about the generated code.
+    pub fn finish(mut self) -> CompilerResult<(WasmModule, CompilationStats)> {
+        while self.compile_next()? {}
+
+        let mut code = self.code;
+        let mut relocs = self.relocs;
+        relocs.resolve_local_calls(&mut code, &self.func_offsets);
+        relocs.build_got(&mut code);
+        let relocs = relocs.into_relocs();
+        let module = WasmModule::new(
+            self.mod_info,
+            code,
+            relocs,
+            self.stack_maps,
+            self.trap_sites,
+        );
+        Ok((module, self.stats))
     }
 }
 
+/// Moves the first name out of a list of export names, without allocating.
+fn take_first(names: &mut Vec<String>) -> Option<String> {
+    mem::take(names).into_iter().next()
+}
+
 fn convert_glob_init(init: GlobalInit) -> GlobInit {
     match init {
         GlobalInit::I32Const(x) => GlobInit::I32(x),
@@ -319,15 +790,123 @@ fn convert_glob_init(init: GlobalInit) -> GlobInit {
         GlobalInit::F64Const(x) => unsafe { GlobInit::F64(core::mem::transmute(x)) },
         GlobalInit::V128Const(_) => todo!(),
         GlobalInit::GetGlobal(_) => todo!(),
-        GlobalInit::RefNullConst => todo!(),
+        GlobalInit::RefNullConst => GlobInit::Ref(NULL_REF),
         GlobalInit::RefFunc(_) => todo!(),
         // Should never happen, we handle imports in a separate case
         GlobalInit::Import => panic!(),
     }
 }
 
+/// Redirects the branches to trap blocks towards a single cold trap block per trap code.
+///
+/// Trap blocks are blocks made of a single trap instruction, such as those inserted by the
+/// legalizer for each conditional trap. The trap blocks left without predecessors are removed
+/// later on by the unreachable code elimination pass. Jump tables are left untouched.
+fn share_trap_blocks(func: &mut ir::Function) {
+    let mut shared: HashMap<ir::TrapCode, ir::Block> = HashMap::new();
+    let mut redirect: HashMap<ir::Block, ir::Block> = HashMap::new();
+    let blocks: Vec<ir::Block> = func.layout.blocks().collect();
+    for block in blocks {
+        let inst = match func.layout.first_inst(block) {
+            Some(inst) if func.layout.last_inst(block) == Some(inst) => inst,
+            _ => continue,
+        };
+        if !func.dfg.block_params(block).is_empty() {
+            continue;
+        }
+        if let ir::InstructionData::Trap {
+            opcode: ir::Opcode::Trap,
+            code,
+        } = func.dfg[inst]
+        {
+            match shared.get(&code) {
+                Some(&shared_block) => {
+                    redirect.insert(block, shared_block);
+                }
+                None => {
+                    // The shared trap is not attributed to any of the original instructions
+                    func.srclocs[inst] = ir::SourceLoc::default();
+                    func.layout.set_cold(block);
+                    shared.insert(code, block);
+                }
+            }
+        }
+    }
+    if redirect.is_empty() {
+        return;
+    }
+
+    for block in func.layout.blocks() {
+        for inst in func.layout.block_insts(block) {
+            if let Some(destination) = func.dfg[inst].branch_destination_mut() {
+                if let Some(&shared_block) = redirect.get(destination) {
+                    *destination = shared_block;
+                }
+            }
+        }
+    }
+}
+
+/// Converts a Cranelift stack map, relative to the function offset, into a module stack map.
+fn convert_stack_map(stack_map: &MachStackMap, func_offset: u32) -> StackMap {
+    StackMap {
+        offset: func_offset + stack_map.offset,
+        offset_end: func_offset + stack_map.offset_end,
+        mapped_words: stack_map.stack_map.mapped_words(),
+        bitmap: stack_map
+            .stack_map
+            .as_slice()
+            .iter()
+            .map(|bitset| bitset.0)
+            .collect(),
+    }
+}
+
+/// Converts a Cranelift trap, relative to the function offset, into a module trap site.
+fn convert_trap(trap: &MachTrap, func_idx: FuncIndex, func_offset: u32) -> TrapSite {
+    let code = match trap.code {
+        ir::TrapCode::StackOverflow => TrapCode::StackOverflow,
+        ir::TrapCode::HeapOutOfBounds => TrapCode::HeapOutOfBounds,
+        ir::TrapCode::HeapMisaligned => TrapCode::HeapMisaligned,
+        ir::TrapCode::TableOutOfBounds => TrapCode::TableOutOfBounds,
+        ir::TrapCode::IndirectCallToNull => TrapCode::IndirectCallToNull,
+        ir::TrapCode::BadSignature => TrapCode::BadSignature,
+        ir::TrapCode::IntegerOverflow => TrapCode::IntegerOverflow,
+        ir::TrapCode::IntegerDivisionByZero => TrapCode::IntegerDivisionByZero,
+        ir::TrapCode::BadConversionToInteger => TrapCode::BadConversionToInteger,
+        ir::TrapCode::UnreachableCodeReached => TrapCode::UnreachableCodeReached,
+        ir::TrapCode::Interrupt => TrapCode::Interrupt,
+        ir::TrapCode::User(_) => TrapCode::Unknown,
+    };
+    // Source locations are offsets within the module bytecode
+    let offset = if trap.srcloc.is_default() {
+        None
+    } else {
+        Some(trap.srcloc.bits())
+    };
+    TrapSite {
+        offset: func_offset + trap.offset,
+        code,
+        location: WasmLocation {
+            func: func_idx,
+            offset,
+        },
+    }
+}
+
 // ——————————————————————————— Relocation Handler ——————————————————————————— //
 
+/// Collects the relocations emitted by Cranelift.
+///
+/// With the x86_64 backend and our settings, the following relocations can be emitted:
+/// - `X86CallPCRel4` for calls to functions of the module, which are resolved at compile time.
+/// - `Abs8` for symbols out of reach of a relative call (i.e. not colocated).
+/// - `X86GOTPCRel4` in place of `Abs8` when emitting position-independent code, the referenced
+///   entries of the global offset table are built by the handler.
+///
+/// `X86PCRel4` and `X86CallPLTRel4` are resolved like `X86CallPCRel4`, as we don't use a procedure
+/// linkage table. The other kinds are either emitted by other backends, or for thread local
+/// storage which is not supported, and fail at instantiation.
 pub struct RelocationHandler {
     relocs: Vec<Reloc>,
     func_offset: u32,
@@ -345,6 +924,11 @@ impl RelocationHandler {
         self.func_offset = offset;
     }
 
+    /// Consumes the handler, returning the remaining relocations.
+    pub fn into_relocs(self) -> Vec<Reloc> {
+        self.relocs
+    }
+
     /// Translate an ir::ExternalName to an item reference.
     pub fn translate(&self, name: &ir::ExternalName) -> ItemRef {
         match name {
@@ -358,6 +942,73 @@ impl RelocationHandler {
         }
     }
 
+    /// Resolves the calls between functions of the module, and removes the corresponding
+    /// relocations.
+    ///
+    /// Those calls are relative to the call site and do not depend on where the code is loaded.
+    /// Calls to other modules go through the VMContext, hence the code of a module without
+    /// remaining relocations can be shared across instances.
+    pub fn resolve_local_calls(
+        &mut self,
+        code: &mut [u8],
+        func_offsets: &SecondaryMap<FuncIndex, Option<u32>>,
+    ) {
+        self.relocs.retain(|reloc| {
+            let target = match (&reloc.kind, reloc.item) {
+                (
+                    RelocKind::X86CallPCRel4 | RelocKind::X86CallPLTRel4 | RelocKind::X86PCRel4,
+                    ItemRef::Func(func),
+                ) => match func_offsets[func] {
+                    Some(target) => target as i64,
+                    None => return true,
+                },
+                _ => return true,
+            };
+            let offset = reloc.offset as usize;
+            let pc_relative = (target + reloc.addend - reloc.offset as i64) as i32;
+            code[offset..][..4].copy_from_slice(&pc_relative.to_le_bytes());
+            false
+        });
+    }
+
+    /// Builds the global offset table, appended to the code.
+    ///
+    /// Each item referenced through the GOT gets an 8 bytes entry, the loads from the GOT are
+    /// resolved relative to the entry and replaced by an `Abs8` relocation of the entry itself.
+    pub fn build_got(&mut self, code: &mut Vec<u8>) {
+        let mut entries: Vec<(ItemRef, u32)> = Vec::new();
+        self.relocs.retain_mut(|reloc| {
+            if reloc.kind != RelocKind::X86GOTPCRel4 {
+                return true;
+            }
+
+            let (entry, is_new) = match entries.iter().find(|(item, _)| *item == reloc.item) {
+                Some((_, entry)) => (*entry, false),
+                None => {
+                    // Entries are 8 bytes aligned
+                    code.resize((code.len() + 7) & !7, 0);
+                    let entry = code.len() as u32;
+                    code.extend_from_slice(&[0; 8]);
+                    entries.push((reloc.item, entry));
+                    (entry, true)
+                }
+            };
+
+            let offset = reloc.offset as usize;
+            let pc_relative = (entry as i64 + reloc.addend - reloc.offset as i64) as i32;
+            code[offset..][..4].copy_from_slice(&pc_relative.to_le_bytes());
+
+            // Only one relocation is needed per entry
+            *reloc = Reloc {
+                offset: entry,
+                kind: RelocKind::Abs8,
+                item: reloc.item,
+                addend: 0,
+            };
+            is_new
+        });
+    }
+
     /// Registers a slice of relocations.
     pub fn extend_relocs(&mut self, relocs: &[MachReloc]) {
         for reloc in relocs {
@@ -417,7 +1068,7 @@ fn as_ref_type(ty: WasmType) -> Option<RefType> {
     }
 }
 
-fn as_func_type(func_ty: WasmFuncType) -> FuncType {
+pub(crate) fn as_func_type(func_ty: WasmFuncType) -> FuncType {
     let mut args = Vec::with_capacity(func_ty.params().len());
     let mut ret = Vec::with_capacity(func_ty.returns().len());
     for ty in func_ty.params() {
@@ -2,11 +2,14 @@
 
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 
 use cranelift_codegen::cursor;
+use cranelift_codegen::cursor::Cursor;
 use cranelift_codegen::ir;
+use cranelift_codegen::ir::immediates::{Ieee32, Ieee64};
 use cranelift_codegen::ir::InstBuilder;
 use cranelift_codegen::isa::{CallConv, TargetFrontendConfig};
 use cranelift_wasm as cw;
@@ -16,20 +19,54 @@ use cranelift_wasm::{
     TypeIndex, WasmType,
 };
 
-use collections::{EntityRef, PrimaryMap, SecondaryMap};
-use wasm::ImportIndex;
+use collections::{entity_impl, EntityRef, HashMap, PrimaryMap, SecondaryMap};
+use wasm::{ImportIndex, ItemRef, ModuleMetadata, WASM_PAGE_SIZE};
 
-/// Size of a wasm page, defined by the standard.
-const WASM_PAGE_SIZE: u64 = 0x10000; // 64 Ki
-/// Width of a VMContext entry. For now the width is independent of the architecture, and thorefore
-/// each entry span 8 bytes even for 32 bits architectures.
-const VMCTX_ENTRY_WIDTH: i32 = 0x8;
+use crate::compiler::{ImportAllowList, Proposal};
+
+// The compiler only targets x86_64, the code it emits runs with a VMContext laid out for the
+// current target: both must agree on the width of the entries.
+const _: () = assert!(wasm::vmctx_entry_width(8) == wasm::VMCTX_ENTRY_WIDTH);
+
+/// The error reported when a module relies on an unsupported proposal.
+fn unsupported(proposal: Proposal) -> cw::WasmError {
+    cw::WasmError::Unsupported(format!("{:?} proposal", proposal))
+}
+
+/// Returns the Cranelift calling convention of compiled functions.
+fn ir_call_conv(call_conv: wasm::CallConv) -> CallConv {
+    match call_conv {
+        wasm::CallConv::SystemV => CallConv::WasmtimeSystemV,
+    }
+}
+
+/// Parses the `producers` custom section, returns the tools as `name version`.
+fn parse_producers(data: &[u8]) -> cw::wasmparser::Result<Vec<String>> {
+    let mut reader = cw::wasmparser::BinaryReader::new(data);
+    let mut producers = Vec::new();
+    // The section is a list of fields (language, processed-by and sdk), each listing tools
+    for _ in 0..reader.read_var_u32()? {
+        let _field = reader.read_string()?;
+        for _ in 0..reader.read_var_u32()? {
+            let name = reader.read_string()?;
+            let version = reader.read_string()?;
+            producers.push(format!("{} {}", name, version));
+        }
+    }
+    Ok(producers)
+}
 
 /// Compute a `ir::ExternalName` for a given wasm function index.
 fn get_func_name(func_index: FuncIndex) -> ir::ExternalName {
     ir::ExternalName::user(0, func_index.as_u32())
 }
 
+/// Index of a unique Cranelift signature. Wasm types with identical signatures share the same
+/// `SignatureIndex`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub struct SignatureIndex(u32);
+entity_impl!(SignatureIndex);
+
 #[derive(Debug)]
 pub struct Exportable<T> {
     /// A wasm entity.
@@ -58,8 +95,6 @@ pub struct ImportedFunc {
     pub module: ImportIndex,
     /// The name of the imported function inside its module.
     pub name: String,
-    /// Index of the function in the VMContext.
-    pub vmctx_idx: i32,
 }
 
 #[derive(Clone)]
@@ -115,8 +150,12 @@ pub struct ModuleInfo {
     pub funcs: PrimaryMap<FuncIndex, Exportable<TypeIndex>>,
     /// TypeID -> Wasm Type
     pub types: PrimaryMap<TypeIndex, cw::WasmFuncType>,
-    /// TypeID -> Cranelift Signature
-    pub func_signatures: SecondaryMap<TypeIndex, Option<ir::Signature>>,
+    /// SigID -> Cranelift Signature, each signature is stored only once
+    pub signatures: PrimaryMap<SignatureIndex, ir::Signature>,
+    /// TypeID -> SigID
+    pub type_signatures: SecondaryMap<TypeIndex, Option<SignatureIndex>>,
+    /// Cranelift Signature -> SigID, used to deduplicate signatures
+    interned_signatures: HashMap<ir::Signature, SignatureIndex>,
     /// FunID -> Option<imported_func_info>
     pub imported_funcs: SecondaryMap<FuncIndex, Option<ImportedFunc>>,
     /// Function bodies
@@ -135,22 +174,36 @@ pub struct ModuleInfo {
     pub imported_tables: SecondaryMap<TableIndex, Option<ImportedTable>>,
     /// The list of imported modules
     pub modules: PrimaryMap<ImportIndex, String>,
+    /// The imported items, in the order of the import section
+    pub import_order: Vec<ItemRef>,
     /// The list of data segments to initialize.
     pub segments: Vec<DataSegment>,
     /// The list of table elements to initialize.
     pub elements: Vec<TableSegment>,
     /// The start function, to be called after memory and table initialization.
     pub start: Option<FuncIndex>,
+    /// The name and producers of the module, the hash is set by the compiler.
+    pub metadata: ModuleMetadata,
     /// The number of imported funcs. The defined functions goes after the imported ones.
     nb_imported_funcs: usize,
     /// Configuration of the target
     target_config: TargetFrontendConfig,
+    /// Whether memory accesses must respect their alignment hint.
+    strict_alignment: bool,
+    /// Whether calls to imported functions are counted in the VMContext.
+    pub call_counters: bool,
+    /// Whether the code checks the preemption flag of the VMContext.
+    pub preemption_checks: bool,
 }
 
 impl ModuleInfo {
-    fn get_func_sig(&self, fun_index: FuncIndex) -> &ir::Signature {
+    fn get_func_sig_idx(&self, fun_index: FuncIndex) -> SignatureIndex {
         let type_idx = self.funcs[fun_index].entity;
-        self.func_signatures[type_idx].as_ref().unwrap()
+        self.type_signatures[type_idx].unwrap()
+    }
+
+    fn get_func_sig(&self, fun_index: FuncIndex) -> &ir::Signature {
+        &self.signatures[self.get_func_sig_idx(fun_index)]
     }
 
     fn get_fun_env(&self) -> FunctionEnvironment {
@@ -158,6 +211,22 @@ impl ModuleInfo {
             target_config: self.target_config,
             info: self,
             vmctx: None,
+            sig_refs: SecondaryMap::new(),
+            strict_alignment: self.strict_alignment,
+            last_inst: None,
+            unsupported: None,
+            preemption_sig: None,
+        }
+    }
+
+    /// Return the index of a signature. The signature is registered if it hasn't been seen yet.
+    fn intern_signature(&mut self, sig: ir::Signature) -> SignatureIndex {
+        if let Some(sig_idx) = self.interned_signatures.get(&sig) {
+            *sig_idx
+        } else {
+            let sig_idx = self.signatures.push(sig.clone());
+            self.interned_signatures.insert(sig, sig_idx);
+            sig_idx
         }
     }
 
@@ -175,22 +244,68 @@ impl ModuleInfo {
         }
     }
 
+    /// Returns the width of the VMContext entries, which depends on the pointer width of the target.
+    fn vmctx_entry_width(&self) -> i32 {
+        wasm::vmctx_entry_width(self.target_config.pointer_bytes() as usize) as i32
+    }
+
+    fn get_vmctx_heap_offset(&self, heap: MemoryIndex) -> i32 {
+        (heap.index() * 2) as i32 * self.vmctx_entry_width()
+    }
+
     fn get_vmctx_table_offset(&self, table: TableIndex) -> i32 {
-        (self.heaps.len() + table.index() * 2) as i32 * VMCTX_ENTRY_WIDTH
+        (self.heaps.len() * 2 + table.index() * 2) as i32 * self.vmctx_entry_width()
+    }
+
+    fn get_vmctx_func_offset(&self, func: FuncIndex) -> i32 {
+        // Imported functions come first, their index is also their index in the VMContext
+        debug_assert!(func.index() < self.nb_imported_funcs);
+        (self.heaps.len() * 2 + self.tables.len() * 2 + func.index()) as i32
+            * self.vmctx_entry_width()
     }
 
     fn get_vmctx_imported_vmctx_offset(&self, module: ImportIndex) -> i32 {
-        (self.heaps.len() + self.tables.len() * 2 + self.nb_imported_funcs + module.index()) as i32
-            * VMCTX_ENTRY_WIDTH
+        (self.heaps.len() * 2 + self.tables.len() * 2 + self.nb_imported_funcs + module.index())
+            as i32
+            * self.vmctx_entry_width()
     }
 
     fn get_vmctx_global_offset(&self, global: GlobalIndex) -> i32 {
-        (self.heaps.len()
+        (self.heaps.len() * 2
             + self.tables.len() * 2
             + self.nb_imported_funcs
             + self.modules.len()
             + global.index()) as i32
-            * VMCTX_ENTRY_WIDTH
+            * self.vmctx_entry_width()
+    }
+
+    fn get_vmctx_counter_offset(&self, func: FuncIndex) -> i32 {
+        // Counters come after the globals, one per imported function
+        debug_assert!(self.call_counters && func.index() < self.nb_imported_funcs);
+        (self.heaps.len() * 2
+            + self.tables.len() * 2
+            + self.nb_imported_funcs
+            + self.modules.len()
+            + self.globs.len()
+            + func.index()) as i32
+            * self.vmctx_entry_width()
+    }
+
+    fn get_vmctx_preemption_flag_offset(&self) -> i32 {
+        // The flag comes after the counters, and is followed by the address of the handler
+        debug_assert!(self.preemption_checks);
+        let counters = if self.call_counters {
+            self.nb_imported_funcs
+        } else {
+            0
+        };
+        (self.heaps.len() * 2
+            + self.tables.len() * 2
+            + self.nb_imported_funcs
+            + self.modules.len()
+            + self.globs.len()
+            + counters) as i32
+            * self.vmctx_entry_width()
     }
 
     /// Translate a wasm type to it's IR representation
@@ -219,14 +334,29 @@ impl TargetEnvironment for ModuleInfo {
 pub struct ModuleEnvironment {
     pub info: ModuleInfo,
     translator: cw::FuncTranslator,
+    /// The unsupported proposal which caused the translation to fail, if any.
+    pub unsupported: Option<Proposal>,
+    /// The function whose translation caused the translation to fail, if any.
+    pub failed_func: Option<FuncIndex>,
+    /// The imports the module may declare, all imports are allowed if `None`.
+    pub allow_list: Option<ImportAllowList>,
+    /// The import which is not in the allow-list and caused the translation to fail, if any.
+    pub denied_import: Option<(String, String)>,
 }
 
 impl ModuleEnvironment {
-    pub fn new(target_config: TargetFrontendConfig) -> Self {
+    pub fn new(
+        target_config: TargetFrontendConfig,
+        strict_alignment: bool,
+        call_counters: bool,
+        preemption_checks: bool,
+    ) -> Self {
         let info = ModuleInfo {
             funcs: PrimaryMap::new(),
             types: PrimaryMap::new(),
-            func_signatures: SecondaryMap::new(),
+            signatures: PrimaryMap::new(),
+            type_signatures: SecondaryMap::new(),
+            interned_signatures: HashMap::new(),
             imported_funcs: SecondaryMap::new(),
             func_bodies: PrimaryMap::new(),
             heaps: PrimaryMap::new(),
@@ -236,16 +366,25 @@ impl ModuleEnvironment {
             tables: PrimaryMap::new(),
             imported_tables: SecondaryMap::new(),
             modules: PrimaryMap::new(),
+            import_order: Vec::new(),
             segments: Vec::new(),
             elements: Vec::new(),
             start: None,
+            metadata: ModuleMetadata::default(),
             nb_imported_funcs: 0,
             target_config,
+            strict_alignment,
+            call_counters,
+            preemption_checks,
         };
 
         Self {
             info,
             translator: cw::FuncTranslator::new(),
+            unsupported: None,
+            failed_func: None,
+            allow_list: None,
+            denied_import: None,
         }
     }
 }
@@ -257,10 +396,52 @@ impl TargetEnvironment for ModuleEnvironment {
 }
 
 impl<'data> cw::ModuleEnvironment<'data> for ModuleEnvironment {
+    // The `reserve_*` hooks are called with the size of each section before its entities are
+    // declared, so that the maps are allocated at once rather than grown one entity at a time.
+
+    fn reserve_types(&mut self, num: u32) -> cw::WasmResult<()> {
+        self.info.types.reserve_exact(num as usize);
+        Ok(())
+    }
+
+    fn reserve_func_types(&mut self, num: u32) -> cw::WasmResult<()> {
+        self.info.funcs.reserve_exact(num as usize);
+        Ok(())
+    }
+
+    fn reserve_tables(&mut self, num: u32) -> cw::WasmResult<()> {
+        self.info.tables.reserve_exact(num as usize);
+        Ok(())
+    }
+
+    fn reserve_memories(&mut self, num: u32) -> cw::WasmResult<()> {
+        self.info.heaps.reserve_exact(num as usize);
+        Ok(())
+    }
+
+    fn reserve_globals(&mut self, num: u32) -> cw::WasmResult<()> {
+        self.info.globs.reserve_exact(num as usize);
+        Ok(())
+    }
+
+    fn reserve_table_elements(&mut self, num: u32) -> cw::WasmResult<()> {
+        self.info.elements.reserve_exact(num as usize);
+        Ok(())
+    }
+
+    fn reserve_data_initializers(&mut self, num: u32) -> cw::WasmResult<()> {
+        self.info.segments.reserve_exact(num as usize);
+        Ok(())
+    }
+
+    fn reserve_function_bodies(&mut self, bodies: u32, _code_section_offset: u64) {
+        self.info.func_bodies.reserve_exact(bodies as usize);
+    }
+
     fn declare_type_func(&mut self, wasm_func_type: cw::WasmFuncType) -> cw::WasmResult<()> {
         // A small type conversion function
         let mut wasm_to_ir = |ty: &WasmType| ir::AbiParam::new(self.info.wasm_to_ir_type(*ty));
-        let mut sig = ir::Signature::new(CallConv::WasmtimeSystemV);
+        let mut sig = ir::Signature::new(ir_call_conv(wasm::CALL_CONV));
         sig.params
             .extend(wasm_func_type.params().iter().map(&mut wasm_to_ir));
         sig.params.push(ir::AbiParam::special(
@@ -271,7 +452,8 @@ impl<'data> cw::ModuleEnvironment<'data> for ModuleEnvironment {
             .extend(wasm_func_type.returns().iter().map(&mut wasm_to_ir));
 
         let ty_idx = self.info.types.push(wasm_func_type);
-        self.info.func_signatures[ty_idx] = Some(sig);
+        let sig_idx = self.info.intern_signature(sig);
+        self.info.type_signatures[ty_idx] = Some(sig_idx);
         Ok(())
     }
 
@@ -281,14 +463,19 @@ impl<'data> cw::ModuleEnvironment<'data> for ModuleEnvironment {
         module: &'data str,
         field: &'data str,
     ) -> cw::WasmResult<()> {
+        self.check_import(module, field)?;
+        // The import section precedes the function section, imported functions therefore occupy
+        // the lowest indices. The VMContext layout and `define_function_body` rely on it.
+        debug_assert_eq!(self.info.funcs.len(), self.info.nb_imported_funcs);
         let index = self.info.funcs.push(Exportable::new(ty_idx));
         self.info.nb_imported_funcs += 1;
-        let vmctx_idx = self.info.nb_imported_funcs as i32;
         let module_idx = self.info.get_module_idx(module);
+        self.info
+            .import_order
+            .push(ItemRef::Func(wasm::FuncIndex::from_u32(index.as_u32())));
         self.info.imported_funcs[index] = Some(ImportedFunc {
             module: module_idx,
             name: field.to_string(),
-            vmctx_idx,
         });
         Ok(())
     }
@@ -299,8 +486,12 @@ impl<'data> cw::ModuleEnvironment<'data> for ModuleEnvironment {
         module: &'data str,
         field: &'data str,
     ) -> cw::WasmResult<()> {
+        self.check_import(module, field)?;
         let index = self.info.tables.push(Exportable::new(table));
         let module_idx = self.info.get_module_idx(module);
+        self.info
+            .import_order
+            .push(ItemRef::Table(wasm::TableIndex::from_u32(index.as_u32())));
         self.info.imported_tables[index] = Some(ImportedTable {
             module: module_idx,
             name: field.to_string(),
@@ -314,8 +505,12 @@ impl<'data> cw::ModuleEnvironment<'data> for ModuleEnvironment {
         module: &'data str,
         field: &'data str,
     ) -> cw::WasmResult<()> {
+        self.check_import(module, field)?;
         let index = self.info.heaps.push(Exportable::new(memory));
         let module_idx = self.info.get_module_idx(module);
+        self.info
+            .import_order
+            .push(ItemRef::Heap(wasm::HeapIndex::from_u32(index.as_u32())));
         self.info.imported_heaps[index] = Some(ImportedHeap {
             module: module_idx,
             name: field.to_string(),
@@ -329,9 +524,15 @@ impl<'data> cw::ModuleEnvironment<'data> for ModuleEnvironment {
         module: &'data str,
         field: &'data str,
     ) -> cw::WasmResult<()> {
+        self.check_import(module, field)?;
         let index = self.info.globs.push(Exportable::new(global));
         let module_idx = self.info.get_module_idx(module);
-        // TODO: what if we didn't parse all function declaration yet, is that still correct?
+        // Imports of different kinds can be interleaved, but each kind has its own index space and
+        // all imports are declared before the module's own definitions: the index of the global is
+        // the number of globals imported so far, regardless of the imports of other kinds.
+        self.info
+            .import_order
+            .push(ItemRef::Glob(wasm::GlobIndex::from_u32(index.as_u32())));
         self.info.imported_globs[index] = Some(ImportedGlob {
             module: module_idx,
             name: field.to_string(),
@@ -445,8 +646,14 @@ impl<'data> cw::ModuleEnvironment<'data> for ModuleEnvironment {
         let name = get_func_name(func_index);
         let sig = self.info.get_func_sig(func_index);
         let mut fun = ir::Function::with_name_signature(name, sig.clone());
-        self.translator
-            .translate_body(&mut validator, body, &mut fun, &mut fun_env)?;
+        let translation =
+            self.translator
+                .translate_body(&mut validator, body, &mut fun, &mut fun_env);
+        self.unsupported = self.unsupported.or(fun_env.unsupported);
+        if translation.is_err() {
+            self.failed_func = Some(func_index);
+        }
+        translation?;
         self.info.func_bodies.push((fun, func_index));
         Ok(())
     }
@@ -467,6 +674,73 @@ impl<'data> cw::ModuleEnvironment<'data> for ModuleEnvironment {
         self.info.segments.push(data_segment);
         Ok(())
     }
+
+    fn declare_module_name(&mut self, name: &'data str) {
+        self.info.metadata.name = Some(name.to_owned());
+    }
+
+    fn custom_section(&mut self, name: &'data str, data: &'data [u8]) -> cw::WasmResult<()> {
+        // Metadata are informative only, a malformed producers section is ignored
+        if name == "producers" {
+            if let Ok(producers) = parse_producers(data) {
+                self.info.metadata.producers = producers;
+            }
+        }
+        Ok(())
+    }
+
+    fn declare_tag_import(
+        &mut self,
+        _tag: cw::Tag,
+        _module: &'data str,
+        _field: &'data str,
+    ) -> cw::WasmResult<()> {
+        self.reject(Proposal::ExceptionHandling)
+    }
+
+    fn declare_tag(&mut self, _tag: cw::Tag) -> cw::WasmResult<()> {
+        self.reject(Proposal::ExceptionHandling)
+    }
+
+    fn declare_tag_export(
+        &mut self,
+        _tag_index: cw::TagIndex,
+        _name: &'data str,
+    ) -> cw::WasmResult<()> {
+        self.reject(Proposal::ExceptionHandling)
+    }
+
+    fn wasm_features(&self) -> cw::wasmparser::WasmFeatures {
+        // Exceptions pass validation so that they can be reported as unsupported rather than
+        // invalid, they are then rejected during translation.
+        cw::wasmparser::WasmFeatures {
+            exceptions: true,
+            multi_memory: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl ModuleEnvironment {
+    /// Records that the module relies on an unsupported proposal, and fails the translation.
+    fn reject(&mut self, proposal: Proposal) -> cw::WasmResult<()> {
+        self.unsupported = Some(proposal);
+        Err(unsupported(proposal))
+    }
+
+    /// Fails the translation if the import is not in the allow-list.
+    fn check_import(&mut self, module: &str, field: &str) -> cw::WasmResult<()> {
+        match &self.allow_list {
+            Some(allow_list) if !allow_list.allows(module, field) => {
+                self.denied_import = Some((module.to_string(), field.to_string()));
+                Err(cw::WasmError::User(format!(
+                    "import '{}.{}' is not allowed",
+                    module, field
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 struct FunctionEnvironment<'info> {
@@ -475,6 +749,18 @@ struct FunctionEnvironment<'info> {
 
     /// A global variable containing the VMContext
     vmctx: Option<ir::GlobalValue>,
+
+    /// The signatures already imported into the function.
+    sig_refs: SecondaryMap<SignatureIndex, Option<ir::SigRef>>,
+
+    /// Whether memory accesses must respect their alignment hint.
+    strict_alignment: bool,
+    /// The last instruction emitted before the operator being translated, if any.
+    last_inst: Option<ir::Inst>,
+    /// The unsupported proposal used by the function, if any.
+    unsupported: Option<Proposal>,
+    /// The signature of the preemption handler, imported on first use.
+    preemption_sig: Option<ir::SigRef>,
 }
 
 impl<'info> FunctionEnvironment<'info> {
@@ -487,6 +773,199 @@ impl<'info> FunctionEnvironment<'info> {
             vmctx
         }
     }
+
+    /// Return a reference to a signature, the signature is imported into the function only the
+    /// first time it is used.
+    fn sig_ref(&mut self, func: &mut ir::Function, sig_idx: SignatureIndex) -> ir::SigRef {
+        if let Some(sig_ref) = self.sig_refs[sig_idx] {
+            sig_ref
+        } else {
+            let signature = self.info.signatures[sig_idx].clone();
+            let sig_ref = func.import_signature(signature);
+            self.sig_refs[sig_idx] = Some(sig_ref);
+            sig_ref
+        }
+    }
+}
+
+impl<'info> FunctionEnvironment<'info> {
+    /// Materializes the value of a global with a constant initializer, returns `None` for other
+    /// initializers.
+    ///
+    /// The value is defined at the top of the entry block, so that it dominates all its uses.
+    fn make_const_global(func: &mut ir::Function, global: &cw::Global) -> Option<ir::Value> {
+        let entry = func.layout.entry_block()?;
+        let mut pos = cursor::FuncCursor::new(func).at_first_insertion_point(entry);
+        let value = match global.initializer {
+            cw::GlobalInit::I32Const(x) => pos.ins().iconst(ir::types::I32, x as i64),
+            cw::GlobalInit::I64Const(x) => pos.ins().iconst(ir::types::I64, x),
+            cw::GlobalInit::F32Const(x) => pos.ins().f32const(Ieee32::with_bits(x)),
+            cw::GlobalInit::F64Const(x) => pos.ins().f64const(Ieee64::with_bits(x)),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+impl<'info> FunctionEnvironment<'info> {
+    /// Checks the alignment of the memory access emitted by the last operator, the access traps
+    /// with `HeapMisaligned` if its address is not aligned to the alignment hint.
+    ///
+    /// Checked accesses whose hint is the natural alignment are flagged as aligned, which lets
+    /// Cranelift pick instructions requiring aligned operands. The check is performed on the
+    /// native address, this assumes that heaps are at least aligned to the largest access.
+    fn check_alignment(&mut self, builder: &mut cw::FunctionBuilder, align: u8, natural: u8) {
+        if align == 0 {
+            // Byte-aligned accesses can't be misaligned
+            return;
+        }
+        let block = match builder.current_block() {
+            Some(block) => block,
+            None => return,
+        };
+
+        // Find the memory access, the operator may have emitted other instructions after it.
+        let mut inst = builder.func.layout.last_inst(block);
+        let (inst, addr, offset) = loop {
+            let current = match inst {
+                Some(current) if inst != self.last_inst => current,
+                _ => return,
+            };
+            match builder.func.dfg[current] {
+                ir::InstructionData::Load { arg, offset, .. } => break (current, arg, offset),
+                ir::InstructionData::Store { args, offset, .. } => {
+                    break (current, args[1], offset)
+                }
+                _ => inst = builder.func.layout.prev_inst(current),
+            }
+        };
+
+        let mut pos = builder.cursor().at_inst(inst);
+        let offset: i32 = offset.into();
+        let addr = if offset == 0 {
+            addr
+        } else {
+            pos.ins().iadd_imm(addr, offset as i64)
+        };
+        let misalignment = pos.ins().band_imm(addr, (1i64 << align) - 1);
+        pos.ins().trapnz(misalignment, ir::TrapCode::HeapMisaligned);
+
+        if align == natural {
+            match &mut builder.func.dfg[inst] {
+                ir::InstructionData::Load { flags, .. }
+                | ir::InstructionData::Store { flags, .. } => flags.set_aligned(),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl<'info> FunctionEnvironment<'info> {
+    /// Emits a preemption check: if the preemption flag of the VMContext is set, the flag is
+    /// cleared and the preemption handler is called. Code emitted afterward goes to a new block.
+    fn check_preemption(&mut self, builder: &mut cw::FunctionBuilder) {
+        let vmctx = builder
+            .func
+            .special_param(ir::ArgumentPurpose::VMContext)
+            .unwrap();
+        let offset = self.info.get_vmctx_preemption_flag_offset();
+        let flags = ir::MemFlags::trusted();
+        let preempt_block = builder.create_block();
+        let continue_block = builder.create_block();
+        builder.set_cold_block(preempt_block);
+
+        // The flag is loaded on every check, it is set asynchronously by the runtime
+        let flag = builder.ins().load(ir::types::I64, flags, vmctx, offset);
+        builder.ins().brnz(flag, preempt_block, &[]);
+        builder.ins().jump(continue_block, &[]);
+        builder.seal_block(preempt_block);
+
+        builder.switch_to_block(preempt_block);
+        let zero = builder.ins().iconst(ir::types::I64, 0);
+        builder.ins().store(flags, zero, vmctx, offset);
+        let handler_offset = offset + self.info.vmctx_entry_width();
+        let handler = builder
+            .ins()
+            .load(self.pointer_type(), flags, vmctx, handler_offset);
+        let sig_ref = self.preemption_sig(builder.func);
+        builder.ins().call_indirect(sig_ref, handler, &[vmctx]);
+        builder.ins().jump(continue_block, &[]);
+        builder.seal_block(continue_block);
+        builder.switch_to_block(continue_block);
+    }
+
+    /// Returns the signature of the preemption handler (see `wasm::PreemptionHandler`), which is
+    /// imported into the function the first time it is used.
+    fn preemption_sig(&mut self, func: &mut ir::Function) -> ir::SigRef {
+        if let Some(sig_ref) = self.preemption_sig {
+            return sig_ref;
+        }
+        let mut sig = ir::Signature::new(ir_call_conv(wasm::CALL_CONV));
+        sig.params.push(ir::AbiParam::special(
+            self.pointer_type(),
+            ir::ArgumentPurpose::VMContext,
+        ));
+        let sig_ref = func.import_signature(sig);
+        self.preemption_sig = Some(sig_ref);
+        sig_ref
+    }
+}
+
+/// Returns the alignment hint and the natural alignment of memory accesses, as log2 of the number
+/// of bytes.
+///
+/// Atomic accesses are not included: they must be naturally aligned, and are always checked.
+fn memory_alignment(op: &cw::wasmparser::Operator) -> Option<(u8, u8)> {
+    use cw::wasmparser::Operator as Op;
+
+    let (memarg, natural) = match op {
+        Op::I32Load8S { memarg }
+        | Op::I32Load8U { memarg }
+        | Op::I64Load8S { memarg }
+        | Op::I64Load8U { memarg }
+        | Op::I32Store8 { memarg }
+        | Op::I64Store8 { memarg }
+        | Op::V128Load8Splat { memarg }
+        | Op::V128Load8Lane { memarg, .. }
+        | Op::V128Store8Lane { memarg, .. } => (memarg, 0),
+        Op::I32Load16S { memarg }
+        | Op::I32Load16U { memarg }
+        | Op::I64Load16S { memarg }
+        | Op::I64Load16U { memarg }
+        | Op::I32Store16 { memarg }
+        | Op::I64Store16 { memarg }
+        | Op::V128Load16Splat { memarg }
+        | Op::V128Load16Lane { memarg, .. }
+        | Op::V128Store16Lane { memarg, .. } => (memarg, 1),
+        Op::I32Load { memarg }
+        | Op::F32Load { memarg }
+        | Op::I64Load32S { memarg }
+        | Op::I64Load32U { memarg }
+        | Op::I32Store { memarg }
+        | Op::F32Store { memarg }
+        | Op::I64Store32 { memarg }
+        | Op::V128Load32Splat { memarg }
+        | Op::V128Load32Zero { memarg }
+        | Op::V128Load32Lane { memarg, .. }
+        | Op::V128Store32Lane { memarg, .. } => (memarg, 2),
+        Op::I64Load { memarg }
+        | Op::F64Load { memarg }
+        | Op::I64Store { memarg }
+        | Op::F64Store { memarg }
+        | Op::V128Load8x8S { memarg }
+        | Op::V128Load8x8U { memarg }
+        | Op::V128Load16x4S { memarg }
+        | Op::V128Load16x4U { memarg }
+        | Op::V128Load32x2S { memarg }
+        | Op::V128Load32x2U { memarg }
+        | Op::V128Load64Splat { memarg }
+        | Op::V128Load64Zero { memarg }
+        | Op::V128Load64Lane { memarg, .. }
+        | Op::V128Store64Lane { memarg, .. } => (memarg, 3),
+        Op::V128Load { memarg } | Op::V128Store { memarg } => (memarg, 4),
+        _ => return None,
+    };
+    Some((memarg.align, natural))
 }
 
 impl<'info> cw::TargetEnvironment for FunctionEnvironment<'info> {
@@ -502,14 +981,22 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
         index: cw::GlobalIndex,
     ) -> cw::WasmResult<cw::GlobalVariable> {
         // There are two kinds of globals: locally defined and imported globals.
-        // - Locally defined globals are stored in the VMContext.
+        // - Locally defined globals are stored in the VMContext. The immutable ones with a
+        //   constant initializer are inlined in the code instead.
         // - Imported globals are stored in a foreign VMContext but are pointed to by an entry
         //   in the local VMContext.
+        let global = self.info.globs[index].entity;
+        let is_imported = self.info.imported_globs[index].is_some();
+        if !global.mutability && !is_imported {
+            if let Some(value) = Self::make_const_global(func, &global) {
+                return Ok(cw::GlobalVariable::Const(value));
+            }
+        }
+
         let vmctx = self.vmctx(func);
         let offset = self.info.get_vmctx_global_offset(index).into();
-        let global = self.info.globs[index].entity;
         let ty = self.info.wasm_to_ir_type(global.wasm_ty);
-        if self.info.imported_globs[index].is_some() {
+        if is_imported {
             let global_ptr = func.create_global_value(ir::GlobalValueData::Load {
                 base: vmctx,
                 offset,
@@ -536,25 +1023,51 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
         index: cw::MemoryIndex,
     ) -> cw::WasmResult<ir::Heap> {
         // Retrieve the memory bound
-        // TODO: handle resizeable heaps
+        // TODO: owned heaps are bounded by their minimum size, they must read their bound through
+        // the VMContext as well once `memory.grow` is supported.
         let memory = &self.info.heaps[index].entity;
-        let bound = memory.minimum * WASM_PAGE_SIZE;
+        let min_size = memory
+            .minimum
+            .checked_mul(WASM_PAGE_SIZE)
+            .ok_or(cw::WasmError::ImplLimitExceeded)?;
 
-        // Heaps addresses are stored in the VMContext
+        // Heaps addresses and bounds are stored in the VMContext
         let vmctx = self.vmctx(func);
+        let offset = self.info.get_vmctx_heap_offset(index);
         let base = func.create_global_value(ir::GlobalValueData::Load {
             base: vmctx,
-            offset: 0.into(), // TODO: retrieve memory offset
+            offset: offset.into(),
             global_type: self.pointer_type(),
             readonly: false, // TODO: readonly if the heap is static
         });
+
+        // The size of imported heaps is only known at instantiation, might be bigger than the
+        // declared minimum and changes when the exporter grows the heap. In that case the bound is
+        // read through the pointer stored in the VMContext, which points to the exporter's bound.
+        let style = if self.info.imported_heaps[index].is_some() {
+            let bound_ptr = func.create_global_value(ir::GlobalValueData::Load {
+                base: vmctx,
+                offset: (offset + self.info.vmctx_entry_width()).into(),
+                global_type: self.pointer_type(),
+                readonly: true,
+            });
+            let bound_gv = func.create_global_value(ir::GlobalValueData::Load {
+                base: bound_ptr,
+                offset: 0.into(),
+                global_type: self.pointer_type(),
+                readonly: false,
+            });
+            ir::HeapStyle::Dynamic { bound_gv }
+        } else {
+            ir::HeapStyle::Static {
+                bound: min_size.into(),
+            }
+        };
         let heap = func.create_heap(ir::HeapData {
             base,
-            min_size: WASM_PAGE_SIZE.into(),
+            min_size: min_size.into(),
             offset_guard_size: 0.into(),
-            style: ir::HeapStyle::Static {
-                bound: bound.into(),
-            },
+            style,
             index_type: ir::types::I32, // TODO: handle wasm64
         });
         Ok(heap)
@@ -577,9 +1090,16 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
             global_type: pointer_type,
             readonly: false,
         });
-        let bound = func.create_global_value(ir::GlobalValueData::Load {
+        // The bound is stored behind a pointer, so that it can change without recompiling
+        let bound_ptr = func.create_global_value(ir::GlobalValueData::Load {
             base: vmctx,
-            offset: (offset + VMCTX_ENTRY_WIDTH).into(),
+            offset: (offset + self.info.vmctx_entry_width()).into(),
+            global_type: pointer_type,
+            readonly: true,
+        });
+        let bound = func.create_global_value(ir::GlobalValueData::Load {
+            base: bound_ptr,
+            offset: 0.into(),
             global_type: ir::types::I32,
             readonly: false,
         });
@@ -594,10 +1114,11 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
 
     fn make_indirect_sig(
         &mut self,
-        _func: &mut cranelift_codegen::ir::Function,
-        _index: TypeIndex,
+        func: &mut cranelift_codegen::ir::Function,
+        index: TypeIndex,
     ) -> cw::WasmResult<cranelift_codegen::ir::SigRef> {
-        todo!()
+        let sig_idx = self.info.type_signatures[index].unwrap();
+        Ok(self.sig_ref(func, sig_idx))
     }
 
     fn make_direct_func(
@@ -605,14 +1126,14 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
         func: &mut cranelift_codegen::ir::Function,
         index: FuncIndex,
     ) -> cw::WasmResult<cranelift_codegen::ir::FuncRef> {
+        // Imported functions (including native ones) are called indirectly through the VMContext,
+        // see `translate_call`, therefore all direct calls target functions of the same module.
         let name = get_func_name(index);
-        let signature = self.info.get_func_sig(index);
-        // TODO: can we somehow avoid cloning here? Maybe keep a map of SigRef somewhere.
-        let signature = func.import_signature(signature.clone());
+        let signature = self.sig_ref(func, self.info.get_func_sig_idx(index));
         Ok(func.import_function(ir::ExtFuncData {
             name,
             signature,
-            colocated: self.info.imported_funcs[index].is_none(),
+            colocated: true,
         }))
     }
 
@@ -625,33 +1146,48 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
     ) -> cw::WasmResult<ir::Inst> {
         // There is a distinction for functions defined inside and outside the module.
         // Functions defined inside can be called directly, whereas the context must be changed for
-        // functions defined outside.
+        // functions defined outside. The address of functions defined outside is loaded from the
+        // VMContext, so that the code does not depend on the instance imports and can be shared
+        // across instances.
         if let Some(func) = &self.info.imported_funcs[callee_idx] {
             // Indirect call
             let vmctx = self.vmctx(pos.func);
             let vmctx_offset = self.info.get_vmctx_imported_vmctx_offset(func.module);
-            // NOTE: we could use the following address for a relative call, which would remove the
-            // need for inter-module relocations and therefore allow code sharing.
-            //
-            // let func_addr = pos.func.create_global_value(ir::GlobalValueData::Load {
-            //     base: vmctx,
-            //     offset: func_offset.into(),
-            //     global_type: self.pointer_type(),
-            //     readonly: false, // Because we might want to support hot swapping in the future
-            // });
+            let func_offset = self.info.get_vmctx_func_offset(callee_idx);
+            let func_addr = pos.func.create_global_value(ir::GlobalValueData::Load {
+                base: vmctx,
+                offset: func_offset.into(),
+                global_type: self.pointer_type(),
+                readonly: false, // Because we might want to support hot swapping in the future
+            });
             let callee_vmctx = pos.func.create_global_value(ir::GlobalValueData::Load {
                 base: vmctx,
                 offset: vmctx_offset.into(),
                 global_type: self.pointer_type(),
                 readonly: false, // Because we might want to support hot swapping in the future
             });
+            let func_addr = pos.ins().global_value(self.pointer_type(), func_addr);
             let callee_vmctx = pos.ins().global_value(self.pointer_type(), callee_vmctx);
 
+            // Count the call, counters are not shared across threads
+            if self.info.call_counters {
+                let caller_vmctx = pos
+                    .func
+                    .special_param(ir::ArgumentPurpose::VMContext)
+                    .unwrap();
+                let offset = self.info.get_vmctx_counter_offset(callee_idx);
+                let flags = ir::MemFlags::trusted();
+                let count = pos.ins().load(ir::types::I64, flags, caller_vmctx, offset);
+                let count = pos.ins().iadd_imm(count, 1);
+                pos.ins().store(flags, count, caller_vmctx, offset);
+            }
+
             // Append the called module's vmctx to the call arguments
             let mut real_call_args = Vec::with_capacity(call_args.len() + 1);
             real_call_args.extend(call_args);
             real_call_args.push(callee_vmctx);
-            Ok(pos.ins().call(callee, &real_call_args)) // TODO: use a relative call
+            let sig_ref = pos.func.dfg.ext_funcs[callee].signature;
+            Ok(pos.ins().call_indirect(sig_ref, func_addr, &real_call_args))
         } else {
             // Direct call
             //
@@ -669,15 +1205,54 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
 
     fn translate_call_indirect(
         &mut self,
-        _pos: &mut cw::FunctionBuilder<'_>,
+        builder: &mut cw::FunctionBuilder<'_>,
         _table_index: cw::TableIndex,
-        _table: cranelift_codegen::ir::Table,
-        _sig_index: TypeIndex,
-        _sig_ref: cranelift_codegen::ir::SigRef,
-        _callee: cranelift_codegen::ir::Value,
-        _call_args: &[cranelift_codegen::ir::Value],
+        table: cranelift_codegen::ir::Table,
+        sig_index: TypeIndex,
+        sig_ref: cranelift_codegen::ir::SigRef,
+        callee: cranelift_codegen::ir::Value,
+        call_args: &[cranelift_codegen::ir::Value],
     ) -> cw::WasmResult<cranelift_codegen::ir::Inst> {
-        todo!()
+        // Function tables store the signature and VMContext of each entry in two arrays following
+        // the function pointers, see `wasm::FuncTable`.
+        let ty = crate::compiler::as_func_type(self.info.types[sig_index].clone());
+        let signature = ty.signature_id().ok_or_else(|| {
+            cw::WasmError::Unsupported("call_indirect with too many arguments and results".into())
+        })?;
+        let pointer_type = self.pointer_type();
+        let flags = ir::MemFlags::trusted().with_table();
+
+        // The index is bound checked when computing the address of the entry
+        let entry_addr = builder.ins().table_addr(pointer_type, table, callee, 0);
+        let bound = builder.func.tables[table].bound_gv;
+        let bound = builder.ins().global_value(ir::types::I32, bound);
+        let bound = builder.ins().uextend(pointer_type, bound);
+        let array_size = builder.ins().imul_imm(bound, pointer_type.bytes() as i64);
+
+        let func_addr = builder.ins().load(pointer_type, flags, entry_addr, 0);
+        builder
+            .ins()
+            .trapz(func_addr, ir::TrapCode::IndirectCallToNull);
+
+        let signature_addr = builder.ins().iadd(entry_addr, array_size);
+        let entry_signature = builder.ins().load(ir::types::I64, flags, signature_addr, 0);
+        let mismatch = builder.ins().icmp_imm(
+            ir::condcodes::IntCC::NotEqual,
+            entry_signature,
+            signature as i64,
+        );
+        builder.ins().trapnz(mismatch, ir::TrapCode::BadSignature);
+
+        let vmctx_addr = builder.ins().iadd(signature_addr, array_size);
+        let callee_vmctx = builder.ins().load(pointer_type, flags, vmctx_addr, 0);
+
+        // Append the callee's vmctx to the call arguments
+        let mut real_call_args = Vec::with_capacity(call_args.len() + 1);
+        real_call_args.extend(call_args);
+        real_call_args.push(callee_vmctx);
+        Ok(builder
+            .ins()
+            .call_indirect(sig_ref, func_addr, &real_call_args))
     }
 
     fn translate_memory_grow(
@@ -770,16 +1345,16 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
     fn translate_table_get(
         &mut self,
         builder: &mut cw::FunctionBuilder,
-        _table_index: cw::TableIndex,
+        table_index: cw::TableIndex,
         table: ir::Table,
         index: ir::Value,
     ) -> cw::WasmResult<ir::Value> {
-        // TODO: get the type corresponding to the table!
-        let table_type = cw::WasmType::ExternRef; // TODO: change me!
+        let table_type = self.info.tables[table_index].entity.wasm_ty;
         let pointer_type = self.pointer_type();
         let reference_type = self.reference_type(table_type);
 
-        // Load the element from the table.
+        // Load the element from the table, the entries of function tables start with the function
+        // pointer, which is null for empty entries (see `wasm::FuncTable`).
         let elem_addr = builder.ins().table_addr(pointer_type, table, index, 0);
         let flags = ir::MemFlags::trusted().with_table();
         let elem = builder.ins().load(reference_type, flags, elem_addr, 0);
@@ -789,11 +1364,18 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
     fn translate_table_set(
         &mut self,
         builder: &mut cw::FunctionBuilder,
-        _table_index: cw::TableIndex,
+        table_index: cw::TableIndex,
         table: cranelift_codegen::ir::Table,
         value: cranelift_codegen::ir::Value,
         index: cranelift_codegen::ir::Value,
     ) -> cw::WasmResult<()> {
+        // Funcref values do not carry the signature and VMContext of the function, writing them
+        // would break the typechecking of indirect calls.
+        if self.info.tables[table_index].entity.wasm_ty == WasmType::FuncRef {
+            return Err(cw::WasmError::Unsupported(
+                "table.set on funcref tables".into(),
+            ));
+        }
         let pointer_type = self.pointer_type();
 
         // Store the element into the table.
@@ -849,6 +1431,24 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
         todo!()
     }
 
+    fn translate_ref_null(
+        &mut self,
+        mut pos: cranelift_codegen::cursor::FuncCursor,
+        ty: WasmType,
+    ) -> cw::WasmResult<ir::Value> {
+        // Null references are all-zero words for both reference types, see `wasm::NULL_REF`
+        Ok(pos.ins().null(self.reference_type(ty)))
+    }
+
+    fn translate_ref_is_null(
+        &mut self,
+        mut pos: cranelift_codegen::cursor::FuncCursor,
+        value: ir::Value,
+    ) -> cw::WasmResult<ir::Value> {
+        let is_null = pos.ins().is_null(value);
+        Ok(pos.ins().bint(ir::types::I32, is_null))
+    }
+
     fn translate_ref_func(
         &mut self,
         _pos: cranelift_codegen::cursor::FuncCursor,
@@ -900,4 +1500,58 @@ impl<'info> cw::FuncEnvironment for FunctionEnvironment<'info> {
     fn unsigned_add_overflow_condition(&self) -> cranelift_codegen::ir::condcodes::IntCC {
         todo!()
     }
+
+    fn before_translate_operator(
+        &mut self,
+        op: &cw::wasmparser::Operator,
+        builder: &mut cw::FunctionBuilder,
+        _state: &cw::FuncTranslationState,
+    ) -> cw::WasmResult<()> {
+        use cw::wasmparser::Operator;
+
+        if let Operator::Try { .. }
+        | Operator::Catch { .. }
+        | Operator::Throw { .. }
+        | Operator::Rethrow { .. }
+        | Operator::Delegate { .. }
+        | Operator::CatchAll = op
+        {
+            self.unsupported = Some(Proposal::ExceptionHandling);
+            return Err(unsupported(Proposal::ExceptionHandling));
+        }
+        if self.strict_alignment {
+            self.last_inst = builder
+                .current_block()
+                .and_then(|block| builder.func.layout.last_inst(block));
+        }
+        Ok(())
+    }
+
+    fn after_translate_operator(
+        &mut self,
+        op: &cw::wasmparser::Operator,
+        builder: &mut cw::FunctionBuilder,
+        state: &cw::FuncTranslationState,
+    ) -> cw::WasmResult<()> {
+        use cw::wasmparser::Operator;
+
+        if self.strict_alignment {
+            if let Some((align, natural)) = memory_alignment(op) {
+                self.check_alignment(builder, align, natural);
+            }
+        }
+        if self.info.preemption_checks && state.reachable() {
+            if let Operator::Call { .. } | Operator::CallIndirect { .. } = op {
+                self.check_preemption(builder);
+            }
+        }
+        Ok(())
+    }
+
+    fn translate_loop_header(&mut self, builder: &mut cw::FunctionBuilder) -> cw::WasmResult<()> {
+        if self.info.preemption_checks {
+            self.check_preemption(builder);
+        }
+        Ok(())
+    }
 }
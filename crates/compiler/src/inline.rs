@@ -0,0 +1,255 @@
+//! Inlining
+//!
+//! A pre-pass over the Cranelift IR of the module, run before the functions are compiled one by
+//! one: direct calls to very small functions of the module are replaced by a copy of their body.
+//! This saves the call overhead (spilling, argument moves, prologue and epilogue), and exposes the
+//! inlined code to the optimizations of the caller.
+//!
+//! Only leaf functions are inlined, and they are inlined from their original body: inlining never
+//! cascades, so that the growth of the code stays bounded by the size threshold.
+
+use alloc::vec::Vec;
+
+use cranelift_codegen::ir::{self, InstBuilder};
+use cranelift_wasm::FuncIndex;
+
+use collections::HashMap;
+
+/// The functions of a module small enough to be inlined at their call sites.
+pub(crate) struct SmallFuncs {
+    /// The body of the functions, indexed by their name (see `env::get_func_name`).
+    bodies: HashMap<u32, ir::Function>,
+}
+
+impl SmallFuncs {
+    /// Collects the functions made of at most `threshold` instructions, none if the threshold is
+    /// 0.
+    pub(crate) fn collect(bodies: &[(ir::Function, FuncIndex)], threshold: u32) -> Self {
+        let bodies = bodies
+            .iter()
+            .filter(|(func, _)| threshold > 0 && is_inlinable(func, threshold))
+            .map(|(func, func_idx)| (func_idx.as_u32(), func.clone()))
+            .collect();
+        Self { bodies }
+    }
+
+    /// Inlines the calls to small functions within a function, returns the number of inlined
+    /// calls.
+    pub(crate) fn inline_calls(&self, func: &mut ir::Function) -> u32 {
+        if self.bodies.is_empty() {
+            return 0;
+        }
+        let mut call_sites = Vec::new();
+        for block in func.layout.blocks() {
+            for inst in func.layout.block_insts(block) {
+                if let Some(callee) = self.callee(func, inst) {
+                    call_sites.push((inst, callee));
+                }
+            }
+        }
+
+        // Inlining splits the blocks of the calls, but never moves the calls themselves
+        for (call, callee) in &call_sites {
+            Inliner::new(func, callee).inline(*call);
+        }
+        call_sites.len() as u32
+    }
+
+    /// Returns the body of the function called by an instruction, if it is a direct call to a
+    /// small function.
+    fn callee(&self, func: &ir::Function, inst: ir::Inst) -> Option<&ir::Function> {
+        match func.dfg[inst] {
+            ir::InstructionData::Call { func_ref, .. } => match func.dfg.ext_funcs[func_ref].name {
+                ir::ExternalName::User { index, .. } => self.bodies.get(&index),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Returns true if the function can be inlined: it must be small, perform no calls, and only refer
+/// to entities that can be copied to the caller (global values and heaps).
+fn is_inlinable(func: &ir::Function, threshold: u32) -> bool {
+    let mut size = 0;
+    for block in func.layout.blocks() {
+        for inst in func.layout.block_insts(block) {
+            size += 1;
+            match func.dfg[inst] {
+                ir::InstructionData::Call { .. }
+                | ir::InstructionData::CallIndirect { .. }
+                | ir::InstructionData::FuncAddr { .. }
+                | ir::InstructionData::TableAddr { .. }
+                | ir::InstructionData::StackLoad { .. }
+                | ir::InstructionData::StackStore { .. }
+                | ir::InstructionData::BranchTable { .. }
+                | ir::InstructionData::UnaryConst { .. }
+                | ir::InstructionData::Shuffle { .. } => return false,
+                _ => {}
+            }
+        }
+    }
+    size <= threshold
+}
+
+// ———————————————————————————————— Inliner ————————————————————————————————— //
+
+/// Copies the body of a callee into a caller, mapping the entities of the callee to their copy.
+struct Inliner<'a> {
+    caller: &'a mut ir::Function,
+    callee: &'a ir::Function,
+    values: HashMap<ir::Value, ir::Value>,
+    blocks: HashMap<ir::Block, ir::Block>,
+    global_values: HashMap<ir::GlobalValue, ir::GlobalValue>,
+    heaps: HashMap<ir::Heap, ir::Heap>,
+}
+
+impl<'a> Inliner<'a> {
+    fn new(caller: &'a mut ir::Function, callee: &'a ir::Function) -> Self {
+        Self {
+            caller,
+            callee,
+            values: HashMap::new(),
+            blocks: HashMap::new(),
+            global_values: HashMap::new(),
+            heaps: HashMap::new(),
+        }
+    }
+
+    /// Replaces a call by a jump to a copy of the callee, whose returns jump back to a
+    /// continuation block holding the instructions following the call.
+    fn inline(mut self, call: ir::Inst) {
+        let srcloc = self.caller.srclocs[call];
+        let args = self.caller.dfg.inst_args(call).to_vec();
+        let results = self.caller.dfg.inst_results(call).to_vec();
+
+        // The values returned by the callee become the parameters of the continuation block
+        let continuation = self.caller.dfg.make_block();
+        let next = self
+            .caller
+            .layout
+            .next_inst(call)
+            .expect("Calls can not terminate a block");
+        self.caller.layout.split_block(continuation, next);
+        self.caller.dfg.clear_results(call);
+        for result in results {
+            let ty = self.caller.dfg.value_type(result);
+            let param = self.caller.dfg.append_block_param(continuation, ty);
+            self.caller.dfg.change_to_alias(result, param);
+        }
+
+        for block in self.callee.layout.blocks() {
+            let copy = self.caller.dfg.make_block();
+            for &param in self.callee.dfg.block_params(block) {
+                let ty = self.callee.dfg.value_type(param);
+                let param_copy = self.caller.dfg.append_block_param(copy, ty);
+                self.values.insert(param, param_copy);
+            }
+            self.caller.layout.insert_block(copy, continuation);
+            if self.callee.layout.is_cold(block) {
+                self.caller.layout.set_cold(copy);
+            }
+            self.blocks.insert(block, copy);
+        }
+
+        // The arguments are mapped once all the instructions are copied, as a value can be used in
+        // a block laid out before the one defining it.
+        let mut insts = Vec::new();
+        for block in self.callee.layout.blocks() {
+            for inst in self.callee.layout.block_insts(block) {
+                let copy = self.copy_inst(inst, continuation);
+                self.caller.layout.append_inst(copy, self.blocks[&block]);
+                self.caller.srclocs[copy] = srcloc;
+                insts.push(copy);
+            }
+        }
+        for inst in insts {
+            let mut data = self.caller.dfg[inst].clone();
+            for arg in data.arguments_mut(&mut self.caller.dfg.value_lists) {
+                *arg = self.values[&self.callee.dfg.resolve_aliases(*arg)];
+            }
+            self.caller.dfg[inst] = data;
+        }
+
+        let entry = self.callee.layout.entry_block().unwrap();
+        self.caller
+            .dfg
+            .replace(call)
+            .jump(self.blocks[&entry], &args);
+    }
+
+    /// Copies an instruction of the callee, returns become jumps to the continuation block.
+    ///
+    /// The arguments of the copy still refer to the values of the callee.
+    fn copy_inst(&mut self, inst: ir::Inst, continuation: ir::Block) -> ir::Inst {
+        let mut data = self.callee.dfg[inst].clone();
+        if data.opcode().is_return() {
+            let args = self.callee.dfg.inst_args(inst);
+            data = ir::InstructionData::Jump {
+                opcode: ir::Opcode::Jump,
+                args: ir::ValueList::from_slice(args, &mut self.caller.dfg.value_lists),
+                destination: continuation,
+            };
+        } else {
+            if let Some(args) = data.take_value_list() {
+                let args = args.as_slice(&self.callee.dfg.value_lists);
+                data.put_value_list(ir::ValueList::from_slice(
+                    args,
+                    &mut self.caller.dfg.value_lists,
+                ));
+            }
+            if let Some(destination) = data.branch_destination_mut() {
+                *destination = self.blocks[&*destination];
+            }
+            match &mut data {
+                ir::InstructionData::UnaryGlobalValue { global_value, .. } => {
+                    *global_value = self.copy_global_value(*global_value);
+                }
+                ir::InstructionData::HeapAddr { heap, .. } => {
+                    *heap = self.copy_heap(*heap);
+                }
+                _ => {}
+            }
+        }
+
+        let copy = self.caller.dfg.make_inst(data);
+        let ctrl_typevar = self.callee.dfg.ctrl_typevar(inst);
+        self.caller.dfg.make_inst_results(copy, ctrl_typevar);
+        let results = self.callee.dfg.inst_results(inst);
+        for (result, result_copy) in results.iter().zip(self.caller.dfg.inst_results(copy)) {
+            self.values.insert(*result, *result_copy);
+        }
+        copy
+    }
+
+    /// Copies a global value of the callee, the VMContext of the callee is the one of the caller.
+    fn copy_global_value(&mut self, global_value: ir::GlobalValue) -> ir::GlobalValue {
+        if let Some(copy) = self.global_values.get(&global_value) {
+            return *copy;
+        }
+        let mut data = self.callee.global_values[global_value].clone();
+        match &mut data {
+            ir::GlobalValueData::Load { base, .. } | ir::GlobalValueData::IAddImm { base, .. } => {
+                *base = self.copy_global_value(*base);
+            }
+            _ => {}
+        }
+        let copy = self.caller.create_global_value(data);
+        self.global_values.insert(global_value, copy);
+        copy
+    }
+
+    fn copy_heap(&mut self, heap: ir::Heap) -> ir::Heap {
+        if let Some(copy) = self.heaps.get(&heap) {
+            return *copy;
+        }
+        let mut data = self.callee.heaps[heap].clone();
+        data.base = self.copy_global_value(data.base);
+        if let ir::HeapStyle::Dynamic { bound_gv } = &mut data.style {
+            *bound_gv = self.copy_global_value(*bound_gv);
+        }
+        let copy = self.caller.create_heap(data);
+        self.heaps.insert(heap, copy);
+        copy
+    }
+}
@@ -1,12 +1,19 @@
 #![no_std]
 #![feature(allocator_api)]
+#![feature(thread_local)]
 
 extern crate alloc;
 
+mod bundle;
 mod compiler;
 mod env;
+mod inline;
 
-pub use compiler::{X86_64Compiler, Compiler};
+pub use bundle::{compile_bundle, BundleCompilerError};
+pub use compiler::{
+    Compilation, CompilationStats, Compiler, CompilerError, CompilerOptions, FuncStats,
+    ImportAllowList, Proposal, X86_64Compiler,
+};
 
 #[cfg(test)]
 mod tests;
@@ -1,7 +1,9 @@
+use alloc::format;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::arch::asm;
+use core::fmt::Write;
 
 use wat;
 
@@ -9,10 +11,12 @@ use crate::alloc;
 use crate::alloc::string::String;
 use crate::compiler;
 use crate::compiler::Compiler;
-use crate::userspace_alloc::{MMapArea, Runtime};
+use crate::userspace_alloc::{self, MMapArea, Runtime};
 use wasm::{
-    as_native_func, ExternRef64, Instance, MemoryArea, Module, ModuleError, NativeModuleBuilder,
-    WasmModule, WasmType,
+    as_native_func, AllocPolicy, CpuFeatures, ExternHandle, ExternRef64, FuncIndex, FuncInfo,
+    GlobIndex, HeapIndex, ImportKind, Instance, MemoryArea, Module, ModuleError, ModuleImage,
+    ModuleMetadata, NativeModuleBuilder, Placement, Quota, RefType, RelocKind, TableIndex,
+    TrapCode, TypeIndex, WasmModule, WasmType,
 };
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -48,6 +52,106 @@ fn start() {
     assert_eq!(module.start().unwrap().as_u32(), 1);
 }
 
+#[test]
+fn start_taken_once() {
+    let module = compile(
+        r#"
+        (module
+            (func $start)
+            (start $start)
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    let instance = Instance::instantiate(&module, &[], &runtime).unwrap();
+    assert_eq!(instance.take_start().map(|f| f.as_u32()), Some(0));
+    assert!(instance.take_start().is_none());
+}
+
+#[test]
+fn start_typecheck() {
+    let bytecode = wat::parse_str(
+        r#"
+        (module
+            (func $start (result i32)
+                i32.const 42
+            )
+            (start $start)
+        )
+    "#,
+    )
+    .unwrap();
+    let mut comp = compiler::X86_64Compiler::new();
+    assert!(comp.parse(&bytecode).is_err());
+}
+
+#[test]
+fn metadata() {
+    // The locked wat does not support the @producers annotation, the section is encoded by hand
+    let bytecode = wat::parse_str(
+        r#"
+        (module $answer
+            (@custom "producers" "\02\08language\01\04Rust\041.60\0cprocessed-by\01\05rustc\061.60.0")
+            (func)
+        )
+    "#,
+    )
+    .unwrap();
+    let mut comp = compiler::X86_64Compiler::new();
+    comp.parse(&bytecode).unwrap();
+    let module = comp.compile().unwrap();
+    let metadata = module.metadata();
+    assert_eq!(metadata.name.as_deref(), Some("answer"));
+    assert_eq!(metadata.producers, ["Rust 1.60", "rustc 1.60.0"]);
+    assert_eq!(metadata.hash, ModuleMetadata::content_hash(&bytecode));
+
+    let anonymous = compile("(module (func))");
+    assert_eq!(anonymous.metadata().name, None);
+    assert!(anonymous.metadata().producers.is_empty());
+    assert_ne!(anonymous.metadata().hash, metadata.hash);
+}
+
+#[test]
+fn exports_order() {
+    let module = compile(
+        r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "zeta"))
+            (func (export "alpha"))
+            (global (export "mid") i32 (i32.const 0))
+        )
+    "#,
+    );
+    // Exports are enumerated by name, not in declaration order
+    let names: Vec<&str> = module.public_items().keys().map(|n| n.as_str()).collect();
+    assert_eq!(names, ["alpha", "memory", "mid", "zeta"]);
+}
+
+#[test]
+fn export_aliases() {
+    let module = compile(
+        r#"
+        (module
+            (func $main (export "main") (export "_start") (export "entry"))
+            (func $other (export "other"))
+        )
+    "#,
+    );
+    let main = module.public_items()["main"];
+    let other = module.public_items()["other"];
+    let names: Vec<&str> = module.export_names(main).collect();
+    assert_eq!(names, ["_start", "entry", "main"]);
+    let names: Vec<&str> = module.export_names(other).collect();
+    assert_eq!(names, ["other"]);
+
+    // Only items exported under several names are recorded as aliases
+    let aliases = &module.metadata().aliases;
+    assert_eq!(aliases.len(), 1);
+    assert_eq!(aliases[0].item, main);
+    assert_eq!(aliases[0].names, ["_start", "entry", "main"]);
+}
+
 #[test]
 fn the_answer() {
     let module = compile(
@@ -98,6 +202,60 @@ fn data_segment() {
     assert_eq!(execute_0(module), 0x63);
 }
 
+#[test]
+fn data_segment_out_of_bounds() {
+    let runtime = Runtime::new();
+    let instantiate = |segment: &str| {
+        let module = compile(&format!("(module (memory $mem 1) {})", segment));
+        Instance::instantiate(&module, &[], &runtime).map(|_| ())
+    };
+
+    // Segments ending at the end of the heap fit, including empty ones
+    assert!(instantiate(r#"(data (i32.const 65533) "abc")"#).is_ok());
+    assert!(instantiate(r#"(data (i32.const 65536) "")"#).is_ok());
+
+    for segment in [
+        r#"(data (i32.const 65534) "abc")"#,
+        r#"(data (i32.const 65537) "")"#,
+        r#"(data (i32.const -1) "a")"#,
+        // Valid segments do not prevent a later one from failing the instantiation
+        r#"(data (i32.const 0) "a") (data (i32.const 65536) "b")"#,
+    ] {
+        assert!(matches!(
+            instantiate(segment),
+            Err(ModuleError::DataSegmentOutOfBounds)
+        ));
+    }
+}
+
+#[test]
+fn global_segment_offset() {
+    let env_module = compile(
+        r#"
+        (module
+            (global (export "base") i32 (i32.const 0))
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    let env = Arc::new(Instance::instantiate(&env_module, &[], &runtime).unwrap());
+
+    // Offsets read from globals are not supported yet, the instantiation fails rather than panics
+    for segment in [
+        r#"(memory 1) (data (global.get $base) "abc")"#,
+        r#"(table 1 funcref) (func $f) (elem (global.get $base) $f)"#,
+    ] {
+        let module = compile(&format!(
+            r#"(module (import "env" "base" (global $base i32)) {})"#,
+            segment
+        ));
+        assert!(matches!(
+            Instance::instantiate(&module, &[("env", env.clone())], &runtime),
+            Err(ModuleError::Unsupported)
+        ));
+    }
+}
+
 #[test]
 fn table_segment() {
     let module = compile(
@@ -119,10 +277,135 @@ fn table_segment() {
     let instance = Instance::instantiate(&module, &[], &runtime).unwrap();
     let one = instance.get_func_addr_by_name("one").unwrap() as u64;
     let two = instance.get_func_addr_by_name("two").unwrap() as u64;
-    assert_eq!(
-        instance.get_table_by_name("table").unwrap().as_ref(),
-        &[one, two]
-    )
+    assert_eq!(instance.get_table_by_name("table").unwrap(), &[one, two])
+}
+
+#[test]
+fn imported_table_segment() {
+    let table_module = compile(
+        r#"
+        (module
+            (table $table 2 funcref)
+            (export "table" (table $table))
+        )
+    "#,
+    );
+    let module = compile(
+        r#"
+        (module
+            (import "tables" "table" (table $table 2 funcref))
+            (func $one (result i32)
+                i32.const 42
+            )
+            (elem (i32.const 1) $one)
+            (export "one" (func $one))
+        )
+    "#,
+    );
+    // The exporter would keep a reference to the function after the importer is dropped
+    let runtime = Runtime::new();
+    let tables = Arc::new(Instance::instantiate(&table_module, &[], &runtime).unwrap());
+    assert!(matches!(
+        Instance::instantiate(&module, &[("tables", tables.clone())], &runtime),
+        Err(ModuleError::ImportedTableSegment)
+    ));
+    assert_eq!(tables.get_table_by_name("table").unwrap(), &[0, 0]);
+}
+
+#[test]
+fn imported_table_size() {
+    // The bound of an imported table is read from the exporting instance, it can therefore be
+    // bigger than the declared minimum.
+    let table_module = compile(
+        r#"
+        (module
+            (table $table 3 funcref)
+            (export "table" (table $table))
+        )
+    "#,
+    );
+    let module = compile(
+        r#"
+        (module
+            (import "tables" "table" (table $table 1 funcref))
+            (func $size (result i32)
+                table.size $table
+            )
+            (export "main" (func $size))
+        )
+    "#,
+    );
+    let answer = execute_0_deps(module, vec![("tables", table_module)]);
+    assert_eq!(answer.return_value, 3);
+}
+
+#[test]
+fn table_segment_out_of_bounds() {
+    let module = compile(
+        r#"
+        (module
+            (func $one)
+            (table $table 2 funcref)
+            (elem (i32.const 1) $one $one)
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    assert!(matches!(
+        Instance::instantiate(&module, &[], &runtime),
+        Err(ModuleError::FailedToInstantiate)
+    ));
+}
+
+#[test]
+fn alloc_policy_quota() {
+    let module = compile(
+        r#"
+        (module
+            (memory 2 2)
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    let quota = Arc::new(Quota::new(0x30000));
+    let policy = AllocPolicy {
+        quota: Some(quota.clone()),
+        ..AllocPolicy::default()
+    };
+
+    // Each instance needs 2 wasm pages of memory and one page of code
+    assert!(Instance::instantiate_with_policy(&module, &[], &runtime, &policy).is_ok());
+    assert_eq!(quota.used(), 0x21000);
+    assert!(matches!(
+        Instance::instantiate_with_policy(&module, &[], &runtime, &policy),
+        Err(ModuleError::QuotaExceeded)
+    ));
+}
+
+#[test]
+fn alloc_policy_grouped_placement() {
+    let module = compile(
+        r#"
+        (module
+            (func $main)
+            (export "main" (func $main))
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    let policy = AllocPolicy {
+        alignment: 0x10000,
+        placement: Placement::Grouped(42),
+        quota: None,
+    };
+
+    // The code areas of both instances are allocated next to each other
+    let first = Instance::instantiate_with_policy(&module, &[], &runtime, &policy).unwrap();
+    let second = Instance::instantiate_with_policy(&module, &[], &runtime, &policy).unwrap();
+    let first = first.get_func_addr_by_name("main").unwrap() as usize;
+    let second = second.get_func_addr_by_name("main").unwrap() as usize;
+    assert_eq!(first % 0x10000, 0);
+    assert_eq!(second - first, 0x10000);
 }
 
 #[test]
@@ -185,139 +468,1562 @@ fn import_memory() {
     assert_eq!(answer.return_value, 42);
 }
 
-// // The Wasm proposal for multi memory is not yet standardized (phase 3 out of 5 at the time of
-// // writing).
-//
-// #[test]
-// fn multi_memory() {
-//     let module = compile(
-//         r#"
-//         (module
-//             (func $zero (result i32)
-//                 i32.const 0
-//                 i32.load
-//             )
-//             (memory $mem_a 1 1)
-//             (memory $mem_b 1 1)
-//             (export "main" (func $zero))
-//         )
-//     "#,
-//     );
-//     assert_eq!(execute_0(module), 0);
-// }
-
 #[test]
-fn call() {
+fn import_memory_size() {
     let module = compile(
         r#"
         (module
-            (func $add_and_square (param $arg1 i32) (param $arg2 i32) (result i32)
-                local.get $arg1
-                local.get $arg2
-                i32.add
-
-                call $square
-            )
-            (func $square (param $arg i32) (result i32)
-                local.get $arg
-                local.get $arg
-                i32.mul
+            (type $t (func))
+            (import "answer" "set_answer"
+                (func $set_answer (type $t))
             )
-            (export "main" (func $add_and_square))
-        )
-    "#,
-    );
-    assert_eq!(execute_2(module, 2, 3), 25);
-}
-
-#[test]
-fn import() {
-    let module = compile(
-        r#"
-        (module
-            (import "answer" "the_answer"
-                (func $the_answer (type $t))
+            (import "answer" "memory"
+                (memory $mem 1)
             )
-            (type $t (func (result i32)))
-            (func $call_imported (result i32)
-                call $the_answer
+            (func $main (result i32)
+                call $set_answer
+                i32.const 0x50000 ;; Beyond the declared minimum size
+                i32.load
             )
-            (export "main" (func $call_imported))
+            (export "main" (func $main))
         )
         "#,
     );
     let imported_module = compile(
         r#"
         (module
-            (func $the_answer (result i32)
+            (func $set_answer
+                i32.const 0x50000
                 i32.const 42
+                i32.store
             )
-            (export "the_answer" (func $the_answer))
+            (memory $mem 16 16)
+            (export "memory" (memory $mem))
+            (export "set_answer" (func $set_answer))
         )
     "#,
     );
     let answer = execute_0_deps(module, vec![("answer", imported_module)]);
     assert_eq!(answer.return_value, 42);
+    assert_eq!(answer.instance.memory_size(HeapIndex::from_u32(0)), 16);
 }
 
 #[test]
-fn import_native_func() {
+fn import_memory_grow() {
+    // Importers read the bound of the exporter through a pointer, they observe the heap growing.
+    let memory_module = compile(
+        r#"
+        (module
+            (memory $mem 2)
+            (export "memory" (memory $mem))
+        )
+    "#,
+    );
     let module = compile(
         r#"
         (module
-            (import "answer" "the_answer"
-                (func $the_answer (type $t))
-            )
-            (type $t (func (result i32)))
-            (func $call_imported (result i32)
-                call $the_answer
+            (import "mem" "memory" (memory $mem 1))
+            (func (export "main") (result i32)
+                i32.const 0x10000 ;; Within the second page
+                i32.load
             )
-            (export "main" (func $call_imported))
         )
-        "#,
+    "#,
     );
+    let runtime = Runtime::new();
+    let memory = Arc::new(Instance::instantiate(&memory_module, &[], &runtime).unwrap());
+    let instance = Instance::instantiate(&module, &[("mem", memory.clone())], &runtime).unwrap();
+    let main = instance.get_func_index_by_name("main").unwrap();
+    let heap = HeapIndex::from_u32(0);
 
-    fn foreign_func() -> i32 {
-        42
-    }
-    as_native_func!(foreign_func; FOREIGN_FUNC; ret: i32);
+    // The area spans two pages, shrink the heap before growing it back
+    unsafe { memory.set_memory_size(heap, 1) };
+    assert_eq!(instance.memory_size(heap), 1);
+    assert_eq!(
+        userspace_alloc::call(&instance, main).unwrap_err().code,
+        TrapCode::HeapOutOfBounds
+    );
+    unsafe { memory.set_memory_size(heap, 2) };
+    assert_eq!(instance.memory_size(heap), 2);
+    assert_eq!(userspace_alloc::call(&instance, main), Ok(0));
+}
 
-    let imported_module = unsafe {
-        NativeModuleBuilder::new()
-            .add_func(String::from("the_answer"), &FOREIGN_FUNC)
-            .build()
-    };
-    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
-    assert_eq!(answer.return_value, 42);
+#[test]
+fn heap_ptr_and_size() {
+    let memory_module = compile(
+        r#"
+        (module
+            (memory $mem 2)
+            (data (i32.const 16) "coral")
+            (export "memory" (memory $mem))
+        )
+    "#,
+    );
+    let module = compile(
+        r#"
+        (module
+            (import "mem" "memory" (memory $mem 1))
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    let memory = Arc::new(Instance::instantiate(&memory_module, &[], &runtime).unwrap());
+    let instance = Instance::instantiate(&module, &[("mem", memory.clone())], &runtime).unwrap();
+
+    let (ptr, size) = instance
+        .get_heap_ptr_and_size(HeapIndex::from_u32(0))
+        .unwrap();
+    assert_eq!(
+        memory.get_heap_ptr_and_size(HeapIndex::from_u32(0)),
+        Some((ptr, size))
+    );
+    assert_eq!(size, 2 * 0x10000);
+    let heap = unsafe { core::slice::from_raw_parts(ptr, size) };
+    assert_eq!(&heap[16..21], b"coral");
+    assert_eq!(instance.get_heap_ptr_and_size(HeapIndex::from_u32(1)), None);
 }
 
 #[test]
-fn multi_value_abi() {
+fn fork() {
     let module = compile(
         r#"
         (module
-            (import "answer" "the_answer"
-                (func $the_answer (type $t))
+            (type $counter (func (result i32)))
+            (memory 1)
+            (global $calls (mut i32) (i32.const 0))
+            (table 1 funcref)
+            (elem (i32.const 0) $bump)
+            (func $bump (result i32)
+                (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                (i32.store (i32.const 0) (i32.add (i32.load (i32.const 0)) (i32.const 1)))
+                (i32.add
+                    (i32.mul (i32.load (i32.const 0)) (i32.const 100))
+                    (global.get $calls))
             )
-            (type $t (func (result i32 i32 i32)))
-            (func $call_imported (result i32)
-                call $the_answer
-                i32.add
-                i32.add
+            (func (export "main") (result i32)
+                (call_indirect (type $counter) (i32.const 0))
             )
-            (export "main" (func $call_imported))
         )
-        "#,
+    "#,
     );
+    let runtime = Runtime::new();
+    let mut parent = Instance::instantiate(&module, &[], &runtime).unwrap();
+    assert_eq!(call_0(&mut parent), 101);
+    assert_eq!(call_0(&mut parent), 202);
 
-    fn foreign_func() -> (i32, i32, i32) {
+    // The heap is copied, but the globals are reset
+    let mut child = parent.fork(&runtime, &AllocPolicy::default()).unwrap();
+    assert_eq!(call_0(&mut child), 301);
+    assert_eq!(call_0(&mut parent), 303);
+    assert_eq!(call_0(&mut child), 402);
+    assert_eq!(call_0(&mut parent), 404);
+}
+
+#[test]
+fn running_instance() {
+    let module = compile("(module (func (export \"main\")))");
+    let runtime = Runtime::new();
+    let parent = Instance::instantiate(&module, &[], &runtime).unwrap();
+    assert!(!parent.is_running());
+    assert!(parent.try_enter());
+    assert!(!parent.try_enter());
+    assert!(parent.is_running());
+
+    // Forks are not running, even if the parent is
+    let child = parent.fork(&runtime, &AllocPolicy::default()).unwrap();
+    assert!(child.try_enter());
+
+    parent.leave();
+    assert!(parent.try_enter());
+}
+
+#[test]
+fn import_memory_too_small() {
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "memory"
+                (memory $mem 4)
+            )
+        )
+        "#,
+    );
+    let imported_module = compile(
+        r#"
+        (module
+            (memory $mem 2 2)
+            (export "memory" (memory $mem))
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    let dependency = Arc::new(Instance::instantiate(&imported_module, &[], &runtime).unwrap());
+    let result = Instance::instantiate(&module, &[("answer", dependency)], &runtime);
+    assert!(matches!(result, Err(ModuleError::FailedToInstantiate)));
+}
+
+// The position of the memory index within memory immediates changed while the multi-memory
+// proposal was standardized. Accesses to the second memory use an offset equal to its index, so
+// that they decode the same way with both encodings.
+
+#[test]
+fn multi_memory() {
+    let module = compile(
+        r#"
+        (module
+            (func $zero (result i32)
+                i32.const 0
+                i32.const 42
+                i32.store $mem_b offset=1
+                i32.const 1
+                i32.load $mem_a
+                i32.const 0
+                i32.load $mem_b offset=1
+                i32.add
+            )
+            (memory $mem_a 1 1)
+            (memory $mem_b 1 1)
+            (export "main" (func $zero))
+        )
+    "#,
+    );
+    assert_eq!(execute_0(module), 42);
+}
+
+#[test]
+fn imported_and_owned_memories() {
+    // The owned memory comes after the imported one, its base must not be read from the VMContext
+    // slot of the imported memory.
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "memory" (memory $imported 1))
+            (memory $owned 1 1)
+            (func $main (result i32)
+                i32.const 7
+                i32.const 2
+                i32.store $owned offset=1
+                i32.const 8
+                i32.load $imported
+                i32.const 7
+                i32.load $owned offset=1
+                i32.mul
+            )
+            (export "main" (func $main))
+        )
+    "#,
+    );
+    let imported_module = compile(
+        r#"
+        (module
+            (memory $mem 1 1)
+            (data (i32.const 8) "\15")
+            (export "memory" (memory $mem))
+        )
+    "#,
+    );
+    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn call() {
+    let module = compile(
+        r#"
+        (module
+            (func $add_and_square (param $arg1 i32) (param $arg2 i32) (result i32)
+                local.get $arg1
+                local.get $arg2
+                i32.add
+
+                call $square
+            )
+            (func $square (param $arg i32) (result i32)
+                local.get $arg
+                local.get $arg
+                i32.mul
+            )
+            (export "main" (func $add_and_square))
+        )
+    "#,
+    );
+    assert_eq!(execute_2(module, 2, 3), 25);
+}
+
+#[test]
+fn duplicate_signatures() {
+    use cranelift_codegen::{isa, settings};
+
+    let wat = r#"
+        (module
+            (type $binop (func (param i32 i32) (result i32)))
+            (type $binop_bis (func (param i32 i32) (result i32)))
+            (func $add (type $binop)
+                local.get 0
+                local.get 1
+                i32.add
+            )
+            (func $mul (type $binop_bis)
+                local.get 0
+                local.get 1
+                i32.mul
+            )
+            (func $add_and_square (type $binop)
+                local.get 0
+                local.get 1
+                call $add
+                local.tee 0
+                local.get 0
+                call $mul
+                i32.const 0
+                call $add
+            )
+            (export "main" (func $add_and_square))
+        )
+    "#;
+    let module = compile(wat);
+    assert_eq!(execute_2(module, 2, 3), 25);
+
+    // The two types share a single signature
+    let bytecode = wat::parse_str(wat).unwrap();
+    let target_isa = isa::lookup_by_name("x86_64")
+        .unwrap()
+        .finish(settings::Flags::new(settings::builder()))
+        .unwrap();
+    let mut env =
+        crate::env::ModuleEnvironment::new(target_isa.frontend_config(), false, false, false);
+    cranelift_wasm::translate_module(&bytecode, &mut env).unwrap();
+    let type_signatures = &env.info.type_signatures;
+    let binop = cranelift_wasm::TypeIndex::from_u32(0);
+    let binop_bis = cranelift_wasm::TypeIndex::from_u32(1);
+    assert!(type_signatures[binop].is_some());
+    assert_eq!(type_signatures[binop], type_signatures[binop_bis]);
+    assert_eq!(env.info.signatures.len(), 1);
+}
+
+#[test]
+fn externref_stack_maps() {
+    let module = compile(
+        r#"
+        (module
+            (func $nop)
+            (func $keep_alive (param externref) (result externref)
+                call $nop
+                local.get 0
+            )
+        )
+    "#,
+    );
+    let stack_maps = module.stack_maps();
+    assert_eq!(stack_maps.len(), 1);
+    assert_eq!(stack_maps[0].live_slots().count(), 1);
+    assert!(module.get_stack_map(stack_maps[0].offset_end).is_some());
+}
+
+#[test]
+fn trap_sites() {
+    let module = compile(
+        r#"
+        (module
+            (func $nop)
+            (func $crash
+                unreachable
+            )
+        )
+    "#,
+    );
+    let crash = FuncIndex::from_u32(1);
+    let site = module
+        .trap_sites()
+        .iter()
+        .find(|site| site.code == TrapCode::UnreachableCodeReached)
+        .copied()
+        .unwrap();
+    assert_eq!(site.location.func, crash);
+    assert!(site.location.offset.is_some());
+
+    let runtime = Runtime::new();
+    let instance = Instance::instantiate(&module, &[], &runtime).unwrap();
+    let func_offset = match module.funcs()[crash] {
+        FuncInfo::Owned { offset, .. } => offset,
+        _ => panic!("Expected an owned function"),
+    };
+    let code = instance.get_func_addr_by_index(crash) as usize - func_offset as usize;
+    assert_eq!(
+        instance.locate_trap(code + site.offset as usize),
+        Some((site.code, site.location))
+    );
+    assert_eq!(instance.locate_trap(code + module.code().len()), None);
+}
+
+#[test]
+fn reachable_code() {
+    // Calls into an instance can execute the code of the instances it imports, but not the code
+    // of its importers.
+    let imported_module = compile(
+        r#"
+        (module
+            (func (export "answer") (result i32)
+                i32.const 42
+            )
+        )
+    "#,
+    );
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "answer" (func $answer (result i32)))
+            (func (export "main") (result i32)
+                call $answer
+            )
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    let dependency = Arc::new(Instance::instantiate(&imported_module, &[], &runtime).unwrap());
+    let instance =
+        Instance::instantiate(&module, &[("answer", dependency.clone())], &runtime).unwrap();
+    let main = instance.get_func_index_by_name("main").unwrap();
+    let answer = dependency.get_func_index_by_name("answer").unwrap();
+    let main_addr = instance.get_func_addr_by_index(main) as usize;
+    let answer_addr = dependency.get_func_addr_by_index(answer) as usize;
+
+    assert!(instance.reaches_code(main_addr));
+    assert!(instance.reaches_code(answer_addr));
+    assert!(dependency.reaches_code(answer_addr));
+    assert!(!dependency.reaches_code(main_addr));
+    assert!(!instance.reaches_code(reachable_code as usize));
+}
+
+#[test]
+fn traps() {
+    let module = compile(
+        r#"
+        (module
+            (memory 1)
+            (func $crash
+                unreachable
+            )
+            (func (export "unreachable")
+                call $crash
+            )
+            (func (export "div") (result i32)
+                i32.const 1
+                i32.const 0
+                i32.div_u
+            )
+            (func (export "oob") (result i32)
+                i32.const 0x10000
+                i32.load
+            )
+            (func (export "ok") (result i32)
+                i32.const 42
+            )
+        )
+    "#,
+    );
+    let runtime = Runtime::new();
+    let instance = Instance::instantiate(&module, &[], &runtime).unwrap();
+    let call = |name: &str| {
+        let func = instance.get_func_index_by_name(name).unwrap();
+        userspace_alloc::call(&instance, func)
+    };
+
+    let trap = call("unreachable").unwrap_err();
+    assert_eq!(trap.code, TrapCode::UnreachableCodeReached);
+    assert_eq!(trap.location.unwrap().func, FuncIndex::from_u32(0));
+    assert_eq!(
+        call("div").unwrap_err().code,
+        TrapCode::IntegerDivisionByZero
+    );
+    assert_eq!(call("oob").unwrap_err().code, TrapCode::HeapOutOfBounds);
+
+    // The instance can still be called after a trap
+    assert_eq!(call("ok"), Ok(42));
+}
+
+#[test]
+fn exceptions_unsupported() {
+    let throw = r#"
+        (module
+            (tag $e)
+            (func
+                throw $e
+            )
+        )
+    "#;
+    let try_catch = r#"
+        (module
+            (func
+                try
+                    nop
+                catch_all
+                end
+            )
+        )
+    "#;
+    for wat in [throw, try_catch] {
+        let bytecode = wat::parse_str(wat).unwrap();
+        let mut comp = compiler::X86_64Compiler::new();
+        assert!(matches!(
+            comp.parse(&bytecode),
+            Err(compiler::CompilerError::UnsupportedFeature(
+                compiler::Proposal::ExceptionHandling
+            ))
+        ));
+    }
+}
+
+#[test]
+fn function_errors() {
+    // The second function is ill-typed
+    let wat = r#"
+        (module
+            (func (export "good") (result i32)
+                i32.const 1
+            )
+            (func (export "bad") (result i32)
+                i64.const 1
+            )
+        )
+    "#;
+    let bytecode = wat::parse_str(wat).unwrap();
+    let mut comp = compiler::X86_64Compiler::new();
+    let err = comp.parse(&bytecode).unwrap_err();
+    assert_eq!(err.func(), Some(FuncIndex::from_u32(1)));
+    match err {
+        compiler::CompilerError::FailedToTranslate { name, .. } => {
+            assert_eq!(name.as_deref(), Some("bad"))
+        }
+        _ => panic!("Unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn import_allow_list() {
+    let wat = r#"
+        (module
+            (import "coral" "print" (func (param i32)))
+            (import "coral" "memory" (memory 1))
+            (import "env" "secret" (global i32))
+        )
+    "#;
+    let bytecode = wat::parse_str(wat).unwrap();
+    let coral_only = compiler::ImportAllowList::new().allow("coral", "*");
+    assert!(coral_only.allows("coral", "anything"));
+    assert!(!coral_only.allows("env", "print"));
+
+    let mut comp = compiler::X86_64Compiler::new();
+    let err = comp
+        .parse_with_allow_list(&bytecode, &coral_only)
+        .unwrap_err();
+    match err {
+        compiler::CompilerError::ImportNotAllowed {
+            ref module,
+            ref name,
+        } => {
+            assert_eq!((module.as_str(), name.as_str()), ("env", "secret"));
+            assert_eq!(format!("{}", err), "import not allowed: 'env.secret'");
+        }
+        _ => panic!("Unexpected error: {}", err),
+    }
+
+    let allow_list = coral_only.allow("env", "secret");
+    let mut comp = compiler::X86_64Compiler::new();
+    comp.parse_with_allow_list(&bytecode, &allow_list).unwrap();
+    comp.compile().unwrap();
+}
+
+#[test]
+fn import() {
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "the_answer"
+                (func $the_answer (type $t))
+            )
+            (type $t (func (result i32)))
+            (func $call_imported (result i32)
+                call $the_answer
+            )
+            (export "main" (func $call_imported))
+        )
+        "#,
+    );
+    let imported_module = compile(
+        r#"
+        (module
+            (func $the_answer (result i32)
+                i32.const 42
+            )
+            (export "the_answer" (func $the_answer))
+        )
+    "#,
+    );
+    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn import_list() {
+    let module = compile(
+        r#"
+        (module
+            (import "env" "memory" (memory 2))
+            (import "env" "log" (func $log (param i32)))
+            (import "answer" "the_answer" (func $the_answer (result i32)))
+            (import "env" "counter" (global i32))
+            (func $main (result i32)
+                call $the_answer
+            )
+        )
+        "#,
+    );
+    let imports: Vec<_> = module
+        .imports()
+        .iter()
+        .map(|import| {
+            let module_name = module.import_modules()[import.module].as_str();
+            (module_name, import.field.as_str(), import.kind)
+        })
+        .collect();
+    assert_eq!(imports.len(), 4);
+    assert!(imports.contains(&(
+        "env",
+        "memory",
+        ImportKind::Heap {
+            index: HeapIndex::from_u32(0),
+            min_size: 2
+        }
+    )));
+    assert!(imports.contains(&(
+        "answer",
+        "the_answer",
+        ImportKind::Func {
+            index: FuncIndex::from_u32(1),
+            ty: TypeIndex::from_u32(1)
+        }
+    )));
+    assert!(imports.contains(&(
+        "env",
+        "counter",
+        ImportKind::Glob {
+            index: GlobIndex::from_u32(0)
+        }
+    )));
+}
+
+#[test]
+fn interleaved_imports() {
+    // Imports of different kinds are interleaved, and the import types are declared after their
+    // first use in the text format.
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "base" (global $base i32))
+            (import "double" "double" (func $double (type $unop)))
+            (import "answer" "memory" (memory 1))
+            (import "answer" "store" (func $store (type $unop)))
+            (import "answer" "table" (table 1 funcref))
+            (type $unop (func (param i32) (result i32)))
+            (func $main (result i32)
+                global.get $base
+                call $double
+                call $store
+                drop
+                i32.const 0
+                i32.load
+            )
+            (export "main" (func $main))
+        )
+        "#,
+    );
+    let imports: Vec<_> = module
+        .imports()
+        .iter()
+        .map(|import| (module.import_modules()[import.module].as_str(), import.kind))
+        .collect();
+    assert_eq!(
+        imports,
+        vec![
+            (
+                "answer",
+                ImportKind::Glob {
+                    index: GlobIndex::from_u32(0)
+                }
+            ),
+            (
+                "double",
+                ImportKind::Func {
+                    index: FuncIndex::from_u32(0),
+                    ty: TypeIndex::from_u32(0)
+                }
+            ),
+            (
+                "answer",
+                ImportKind::Heap {
+                    index: HeapIndex::from_u32(0),
+                    min_size: 1
+                }
+            ),
+            (
+                "answer",
+                ImportKind::Func {
+                    index: FuncIndex::from_u32(1),
+                    ty: TypeIndex::from_u32(0)
+                }
+            ),
+            (
+                "answer",
+                ImportKind::Table {
+                    index: TableIndex::from_u32(0),
+                    ty: RefType::FuncRef
+                }
+            ),
+        ]
+    );
+
+    let answer_module = compile(
+        r#"
+        (module
+            (func $store (param i32) (result i32)
+                i32.const 0
+                local.get 0
+                i32.store
+                local.get 0
+            )
+            (global $base i32 (i32.const 21))
+            (memory $mem 1)
+            (table $table 1 funcref)
+            (export "base" (global $base))
+            (export "memory" (memory $mem))
+            (export "store" (func $store))
+            (export "table" (table $table))
+        )
+    "#,
+    );
+    let double_module = compile(
+        r#"
+        (module
+            (func $double (param i32) (result i32)
+                local.get 0
+                local.get 0
+                i32.add
+            )
+            (export "double" (func $double))
+        )
+    "#,
+    );
+    let answer = execute_0_deps(
+        module,
+        vec![("answer", answer_module), ("double", double_module)],
+    );
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn import_native_func() {
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "the_answer"
+                (func $the_answer (type $t))
+            )
+            (type $t (func (result i32)))
+            (func $call_imported (result i32)
+                call $the_answer
+            )
+            (export "main" (func $call_imported))
+        )
+        "#,
+    );
+
+    fn foreign_func() -> i32 {
+        42
+    }
+    as_native_func!(foreign_func; FOREIGN_FUNC; ret: i32);
+
+    let imported_module = unsafe {
+        NativeModuleBuilder::new()
+            .add_func(String::from("the_answer"), &FOREIGN_FUNC)
+            .build()
+    };
+    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn call_counters() {
+    let wat = r#"
+        (module
+            (import "native" "answer" (func $answer (result i32)))
+            (import "native" "unused" (func $unused (result i32)))
+            (func $main (result i32)
+                call $answer
+                call $answer
+                i32.add
+            )
+            (export "main" (func $main))
+        )
+    "#;
+
+    fn answer() -> i32 {
+        21
+    }
+    as_native_func!(answer; ANSWER; ret: i32);
+    let native = unsafe {
+        NativeModuleBuilder::new()
+            .add_func(String::from("answer"), &ANSWER)
+            .add_func(String::from("unused"), &ANSWER)
+            .build()
+    };
+    let runtime = Runtime::new();
+    let native = Arc::new(Instance::instantiate(&native, &[], &runtime).unwrap());
+    let imports = [("native", native)];
+
+    let options = compiler::CompilerOptions {
+        call_counters: true,
+        ..Default::default()
+    };
+    let module = compile_with_options(wat, options);
+    let instance = Instance::instantiate(&module, &imports, &runtime).unwrap();
+    let main = FuncIndex::from_u32(2);
+    let metrics = instance.metrics();
+    assert_eq!(metrics.calls, 0);
+    assert!(metrics.imported_calls.iter().all(|(_, count)| *count == 0));
+    for _ in 0..3 {
+        assert_eq!(userspace_alloc::call(&instance, main).unwrap(), 42);
+    }
+    let metrics = instance.metrics();
+    assert_eq!(metrics.calls, 3);
+    assert!(metrics.ticks > 0);
+    assert_eq!(
+        metrics.imported_calls,
+        [(FuncIndex::from_u32(0), 6), (FuncIndex::from_u32(1), 0)]
+    );
+
+    // Calls to imported functions are not counted by default
+    let module = compile(wat);
+    let instance = Instance::instantiate(&module, &imports, &runtime).unwrap();
+    userspace_alloc::call(&instance, main).unwrap();
+    assert_eq!(instance.metrics().calls, 1);
+    assert!(instance.metrics().imported_calls.is_empty());
+}
+
+#[test]
+fn preemption_checks() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static PREEMPTIONS: AtomicU32 = AtomicU32::new(0);
+    unsafe extern "sysv64" fn handler(_vmctx: *mut u8) {
+        PREEMPTIONS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Preemption is checked at the top of the loop, and after the call
+    let wat = r#"
+        (module
+            (global $i (mut i32) (i32.const 0))
+            (func $next (result i32)
+                global.get $i
+                i32.const 1
+                i32.add
+                global.set $i
+                global.get $i
+            )
+            (func $main (result i32)
+                (loop $continue
+                    call $next
+                    i32.const 10
+                    i32.lt_u
+                    br_if $continue
+                )
+                global.get $i
+            )
+            (export "main" (func $main))
+        )
+    "#;
+    let options = compiler::CompilerOptions {
+        preemption_checks: true,
+        call_counters: true,
+        ..Default::default()
+    };
+    let module = compile_with_options(wat, options);
+    let main = FuncIndex::from_u32(1);
+    let runtime = Runtime::with_preemption_handler(handler);
+    let instance = Instance::instantiate(&module, &[], &runtime).unwrap();
+    assert_eq!(userspace_alloc::call(&instance, main).unwrap(), 10);
+    assert_eq!(PREEMPTIONS.load(Ordering::SeqCst), 0);
+
+    // The flag is cleared before calling the handler, which is called once per request
+    assert!(instance.request_preemption());
+    assert_eq!(userspace_alloc::call(&instance, main).unwrap(), 11);
+    assert_eq!(PREEMPTIONS.load(Ordering::SeqCst), 1);
+    assert_eq!(userspace_alloc::call(&instance, main).unwrap(), 12);
+    assert_eq!(PREEMPTIONS.load(Ordering::SeqCst), 1);
+
+    // A withdrawn request is ignored
+    assert!(instance.request_preemption());
+    instance.clear_preemption();
+    assert_eq!(userspace_alloc::call(&instance, main).unwrap(), 13);
+    assert_eq!(PREEMPTIONS.load(Ordering::SeqCst), 1);
+
+    // Preemption can not be requested without checks nor handler
+    let instance = Instance::instantiate(&module, &[], &Runtime::new()).unwrap();
+    assert!(!instance.request_preemption());
+    let module = compile(wat);
+    let instance = Instance::instantiate(&module, &[], &runtime).unwrap();
+    assert!(!instance.request_preemption());
+}
+
+#[test]
+fn imported_calls_without_relocations() {
+    // Calls to other modules go through the VMContext and local calls are resolved at compile
+    // time, the code therefore does not need to be patched at instantiation.
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "forty"
+                (func $forty (type $t))
+            )
+            (import "answer" "two"
+                (func $two (type $t))
+            )
+            (type $t (func (result i32)))
+            (func $add_imported (result i32)
+                call $forty
+                call $two
+                i32.add
+            )
+            (func $main (result i32)
+                call $add_imported
+            )
+            (export "main" (func $main))
+        )
+        "#,
+    );
+    assert!(module.relocs().is_empty());
+
+    fn forty() -> i32 {
+        40
+    }
+    fn two() -> i32 {
+        2
+    }
+    as_native_func!(forty; FORTY; ret: i32);
+    as_native_func!(two; TWO; ret: i32);
+
+    let imported_module = unsafe {
+        NativeModuleBuilder::new()
+            .add_func(String::from("forty"), &FORTY)
+            .add_func(String::from("two"), &TWO)
+            .build()
+    };
+    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn multi_value_abi() {
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "the_answer"
+                (func $the_answer (type $t))
+            )
+            (type $t (func (result i32 i32 i32)))
+            (func $call_imported (result i32)
+                call $the_answer
+                i32.add
+                i32.add
+            )
+            (export "main" (func $call_imported))
+        )
+        "#,
+    );
+
+    fn foreign_func() -> (i32, i32, i32) {
         (10, 30, 2)
     }
-    as_native_func!(foreign_func; FOREIGN_FUNC; ret: (i32, i32, i32));
+    as_native_func!(foreign_func; FOREIGN_FUNC; ret: (i32, i32, i32));
+
+    let imported_module = unsafe {
+        NativeModuleBuilder::new()
+            .add_func(String::from("the_answer"), &FOREIGN_FUNC)
+            .build()
+    };
+    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn import_native_table() {
+    let module = compile(
+        r#"
+        (module
+            (import "native_mod" "table"
+                (table $table 2 2 externref)
+            )
+            (func $main (result i32)
+                i32.const 42
+            )
+            (export "main" (func $main))
+        )
+        "#,
+    );
+
+    let ref1 = ExternRef(0x42 as *const u8);
+    let ref2 = ExternRef(0x54 as *const u8);
+    let table = vec![ref1, ref2];
+    let imported_module = NativeModuleBuilder::new()
+        .add_table(String::from("table"), table)
+        .build();
+    let answer = execute_0_deps(module, vec![("native_mod", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn native_handle_table() {
+    let module = compile(
+        r#"
+        (module
+            (import "native_mod" "handles"
+                (table $handles 2 4 externref)
+            )
+            (func $main (result externref)
+                i32.const 1
+                table.get $handles
+            )
+            (export "main" (func $main))
+        )
+        "#,
+    );
+    let native_module = NativeModuleBuilder::new()
+        .add_handle_table(String::from("handles"), 4)
+        .build();
+
+    // Each instance of the native module owns its own handle table
+    let handle = |index| ExternHandle::new(1, index, 0).unwrap();
+    let runtime = Runtime::new();
+    let handles_a = Arc::new(Instance::instantiate(&native_module, &[], &runtime).unwrap());
+    let handles_b = Arc::new(Instance::instantiate(&native_module, &[], &runtime).unwrap());
+    assert_eq!(handles_a.insert_handle(handle(0x42)), Some(0));
+    assert_eq!(handles_a.insert_handle(handle(0x54)), Some(1));
+    assert_eq!(handles_b.insert_handle(handle(0x66)), Some(0));
+    assert_eq!(handles_b.get_handle(1), None);
+
+    // The lower 32 bits of the handle hold its index
+    let mut instance =
+        Instance::instantiate(&module, &[("native_mod", handles_a.clone())], &runtime).unwrap();
+    assert_eq!(call_0(&mut instance), 0x54);
+
+    // Removed slots are re-used
+    assert_eq!(handles_a.remove_handle(0), Some(handle(0x42)));
+    assert_eq!(handles_a.get_handle(0), None);
+    assert_eq!(handles_a.insert_handle(handle(0x78)), Some(0));
+}
+
+#[test]
+fn table_get_set() {
+    // Swith the position of two table items
+    let module = compile(
+        r#"
+        (module
+            (import "native_mod" "table"
+                (table $table 2 2 externref)
+            )
+            (func $main (result i32)
+                i32.const 1
+                i32.const 0
+                table.get $table
+                i32.const 0
+                i32.const 1
+                table.get $table
+                table.set $table
+                table.set $table
+
+                i32.const 42
+            )
+            (export "main" (func $main))
+            (export "table" (table $table))
+        )
+        "#,
+    );
+
+    let ref1 = ExternRef(0x42 as *const u8);
+    let ref2 = ExternRef(0x54 as *const u8);
+    let table = vec![ref1, ref2];
+    let imported_module = NativeModuleBuilder::new()
+        .add_table(String::from("table"), table)
+        .build();
+    let answer = execute_0_deps(module, vec![("native_mod", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+    let table = answer.instance.get_table_by_name("table");
+    assert_eq!(table, Some(&[0x54, 0x42][..]));
+}
+
+#[test]
+fn native_func_table() {
+    let module = compile(
+        r#"
+        (module
+            (import "native_mod" "ops"
+                (table $ops 2 funcref)
+            )
+            (type $unary (func (param i32) (result i32)))
+            (func $main (result i32)
+                i32.const 21
+                i32.const 1
+                call_indirect $ops (type $unary)
+            )
+            (export "main" (func $main))
+        )
+        "#,
+    );
+    assert!(module
+        .trap_sites()
+        .iter()
+        .any(|site| site.code == TrapCode::BadSignature));
+
+    fn answer() -> i32 {
+        42
+    }
+    fn double(x: i32) -> i32 {
+        2 * x
+    }
+    as_native_func!(answer; ANSWER; ret: i32);
+    as_native_func!(double; DOUBLE; args: i32; ret: i32);
+
+    let ops = vec![(&ANSWER).into(), (&DOUBLE).into()];
+    let imported_module = NativeModuleBuilder::new()
+        .add_func_table(String::from("ops"), ops)
+        .build();
+    let answer = execute_0_deps(module, vec![("native_mod", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+
+    // The entries have distinct types, calling them with the same type traps on one of them
+    assert_ne!(ANSWER.ty().signature_id(), DOUBLE.ty().signature_id());
+}
+
+#[test]
+fn call_indirect_context_switch() {
+    let funcs = compile(
+        r#"
+        (module
+            (memory 1 1)
+            (data (i32.const 0) "\2a")
+            (func $load (result i32)
+                i32.const 0
+                i32.load8_u
+            )
+            (table $table 1 funcref)
+            (elem (i32.const 0) $load)
+            (export "table" (table $table))
+        )
+        "#,
+    );
+    let module = compile(
+        r#"
+        (module
+            (import "funcs" "table"
+                (table $table 1 funcref)
+            )
+            (memory 1 1)
+            (type $t (func (result i32)))
+            (func $main (result i32)
+                i32.const 0
+                call_indirect $table (type $t)
+            )
+            (export "main" (func $main))
+        )
+        "#,
+    );
+
+    // The function must run with the memory of the instance it comes from
+    let answer = execute_0_deps(module, vec![("funcs", funcs)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn import_table_typecheck() {
+    let module = compile(
+        r#"
+        (module
+            (import "native_mod" "table"
+                (table $table 1 funcref)
+            )
+        )
+        "#,
+    );
+    let imported_module = NativeModuleBuilder::new()
+        .add_table(String::from("table"), vec![ExternRef(0x42 as *const u8)])
+        .build();
+    assert!(type_error(module, vec![("native_mod", imported_module)]));
+}
+
+#[test]
+fn context_switch() {
+    // The memory must not be shared between instances!
+    let module = compile(
+        r#"
+        (module
+            (import "mod" "zero"
+                (func $zero (type $t))
+            )
+            (type $t (func (result i32)))
+            (func $call_imported (result i32)
+                i32.const 0  ;; Memory addres
+                i32.const 14 ;; Random value
+                i32.store
+
+                call $zero   ;; Should return 0 if memory are not shared
+                i32.const 42
+                i32.add      ;; Should return 42 if memory are not shared
+            )
+            (memory $mem 1 1)
+            (export "main" (func $call_imported))
+        )
+        "#,
+    );
+    let imported_module = compile(
+        r#"
+        (module
+            (func $zero (result i32)
+                i32.const 0
+                i32.load
+            )
+            (memory $mem 1 1)
+            (export "zero" (func $zero))
+        )
+    "#,
+    );
+    let answer = execute_0_deps(module, vec![("mod", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn global_read() {
+    let module = compile(
+        r#"
+        (module
+            (func $the_answer (result i32)
+                global.get $glob
+            )
+            (global $glob i32 (i32.const 42))
+            (export "main" (func $the_answer))
+        )
+    "#,
+    );
+    assert_eq!(execute_0(module), 42);
+}
+
+#[test]
+fn global_immutable_const() {
+    // Immutable globals are inlined as constants, the values must dominate all their uses.
+    let module = compile(
+        r#"
+        (module
+            (func $the_answer (result i32)
+                global.get $half
+                f64.const 0.5
+                f64.eq
+                if (result i32)
+                    global.get $forty
+                    global.get $two
+                    i32.wrap_i64
+                    i32.add
+                else
+                    i32.const 0
+                end
+                global.get $forty
+                i32.const 40
+                i32.eq
+                i32.mul
+            )
+            (global $forty i32 (i32.const 40))
+            (global $two i64 (i64.const 2))
+            (global $half f64 (f64.const 0.5))
+            (export "main" (func $the_answer))
+        )
+    "#,
+    );
+    assert_eq!(execute_0(module), 42);
+}
+
+#[test]
+fn global_write() {
+    let module = compile(
+        r#"
+        (module
+            (func $the_answer (result i32)
+                i32.const 42
+                global.set $glob
+                global.get $glob
+            )
+            (global $glob (mut i32) (i32.const 0))
+            (export "main" (func $the_answer))
+        )
+    "#,
+    );
+    assert_eq!(execute_0(module), 42);
+}
+
+#[test]
+fn global_with_many_funcs() {
+    // Owned functions do not have a slot in the VMContext, writing them would overwrite the
+    // globals (or overflow the VMContext).
+    let module = compile(
+        r#"
+        (module
+            (memory 1)
+            (global $glob (mut i32) (i32.const 42))
+            (func $main (result i32)
+                global.get $glob
+            )
+            (func $increment
+                global.get $glob
+                i32.const 1
+                i32.add
+                global.set $glob
+            )
+            (func $reset
+                i32.const 0
+                global.set $glob
+            )
+            (export "main" (func $main))
+            (export "increment" (func $increment))
+            (export "reset" (func $reset))
+        )
+    "#,
+    );
+    assert_eq!(execute_0(module), 42);
+}
+
+#[test]
+fn import_global() {
+    let module = compile(
+        r#"
+        (module
+            (type $t (func))
+            (import "answer" "set_answer"
+                (func $set_answer (type $t))
+            )
+            (import "answer" "the_answer"
+                (global $the_answer i32)
+            )
+            (func $main (result i32)
+                call $set_answer
+                global.get $the_answer
+            )
+            (export "main" (func $main))
+        )
+        "#,
+    );
+    let imported_module = compile(
+        r#"
+        (module
+            (func $set_answer
+                i32.const 42
+                global.set $answer
+            )
+            (global $answer (mut i32) (i32.const 0))
+            (export "the_answer" (global $answer))
+            (export "set_answer" (func $set_answer))
+        )
+    "#,
+    );
+    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn func_typecheck() {
+    let module = compile(
+        r#"
+        (module
+            (import "answer" "the_answer"
+                (func $the_answer (type $t))
+            )
+            (type $t (func (result i32)))
+            (func $call_imported (result i32)
+                call $the_answer
+            )
+            (export "main" (func $call_imported))
+        )
+        "#,
+    );
+
+    fn foreign_func(_: i32) {}
+    as_native_func!(foreign_func; FOREIGN_FUNC; args: i32; ret: ());
+
+    let imported_module = unsafe {
+        NativeModuleBuilder::new()
+            .add_func(String::from("the_answer"), &FOREIGN_FUNC)
+            .build()
+    };
+
+    assert!(type_error(module, vec![("answer", imported_module)]));
+}
+
+#[test]
+fn position_independent_calls() {
+    let module = compile_with_options(
+        r#"
+        (module
+            (import "answer" "the_answer"
+                (func $the_answer (result i32))
+            )
+            (func $forward (result i32)
+                call $the_answer
+            )
+            (func $main (result i32)
+                call $forward
+            )
+            (export "main" (func $main))
+        )
+        "#,
+        compiler::CompilerOptions {
+            position_independent: true,
+            ..Default::default()
+        },
+    );
+    assert!(module.relocs().is_empty());
+
+    let imported_module = compile(
+        r#"
+        (module
+            (func $the_answer (result i32)
+                i32.const 42
+            )
+            (export "the_answer" (func $the_answer))
+        )
+    "#,
+    );
+    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
+    assert_eq!(answer.return_value, 42);
+}
+
+#[test]
+fn cpu_features() {
+    let wat = r#"
+        (module
+            (func $main (param i32) (result i32)
+                local.get 0
+                i32.popcnt
+            )
+            (export "main" (func $main))
+        )
+    "#;
+    let module = compile(wat);
+    assert_eq!(module.required_cpu_features(), CpuFeatures::BASELINE);
+
+    let features = CpuFeatures::BASELINE
+        .union(CpuFeatures::POPCNT)
+        .union(CpuFeatures::LZCNT);
+    let module = compile_with_options(
+        wat,
+        compiler::CompilerOptions {
+            cpu_features: Some(features),
+            ..Default::default()
+        },
+    );
+    assert_eq!(module.required_cpu_features(), features);
+
+    // The module is rejected by runtimes lacking some of the features
+    let runtime = Runtime::new();
+    match Instance::instantiate(&module, &[], &runtime) {
+        Err(ModuleError::MissingCpuFeatures(missing)) => {
+            assert_eq!(missing, CpuFeatures::POPCNT.union(CpuFeatures::LZCNT))
+        }
+        _ => panic!("Expected missing CPU features"),
+    }
+    let runtime = Runtime::with_cpu_features(features);
+    assert!(Instance::instantiate(&module, &[], &runtime).is_ok());
+}
+
+#[test]
+fn strict_alignment() {
+    let wat = r#"
+        (module
+            (memory 1)
+            (func $main (result i32)
+                i32.const 8
+                i32.const 42
+                i32.store offset=4 align=4
+                i32.const 12
+                i32.load8_u
+                i32.const 4
+                i32.load offset=8 align=2
+                i32.add
+            )
+            (export "main" (func $main))
+        )
+    "#;
+    let misaligned = |module: &WasmModule| {
+        module
+            .trap_sites()
+            .iter()
+            .filter(|site| site.code == TrapCode::HeapMisaligned)
+            .count()
+    };
+
+    // Alignment hints are ignored by default
+    let module = compile(wat);
+    assert_eq!(misaligned(&module), 0);
+
+    let module = compile_with_options(
+        wat,
+        compiler::CompilerOptions {
+            strict_alignment: true,
+            ..Default::default()
+        },
+    );
+    // Byte accesses are never checked
+    assert_eq!(misaligned(&module), 2);
+    assert_eq!(execute_0(module), 84);
+}
+
+#[test]
+fn step_by_step_compilation() {
+    let bytecode = wat::parse_str(
+        r#"
+        (module
+            (import "answer" "forty" (func $forty (result i32)))
+            (func $one (result i32)
+                i32.const 1
+            )
+            (func $main (result i32)
+                call $forty
+                call $one
+                i32.add
+                call $one
+                i32.add
+            )
+            (export "main" (func $main))
+        )
+        "#,
+    )
+    .unwrap();
+    let mut comp = compiler::X86_64Compiler::new();
+    comp.parse(&bytecode).unwrap();
+    let mut compilation = comp.start_compilation();
 
+    // Imported functions are not compiled
+    assert_eq!(compilation.progress(), (0, 2));
+    assert!(compilation.compile_next().unwrap());
+    assert_eq!(compilation.progress(), (1, 2));
+    assert!(!compilation.is_done());
+    assert!(compilation.compile_next().unwrap());
+    assert!(compilation.is_done());
+    assert!(!compilation.compile_next().unwrap());
+    let (module, _) = compilation.finish().unwrap();
+
+    fn forty() -> i32 {
+        40
+    }
+    as_native_func!(forty; FORTY; ret: i32);
     let imported_module = unsafe {
         NativeModuleBuilder::new()
-            .add_func(String::from("the_answer"), &FOREIGN_FUNC)
+            .add_func(String::from("forty"), &FORTY)
             .build()
     };
     let answer = execute_0_deps(module, vec![("answer", imported_module)]);
@@ -325,208 +2031,626 @@ fn multi_value_abi() {
 }
 
 #[test]
-fn import_native_table() {
-    let module = compile(
-        r#"
+fn br_table_jump_table() {
+    // A `br_table` with 100 arms, arm `i` returns `i * 10` and the default returns 1000
+    let mut wat = String::from("(module (func $main (param i32 i32) (result i32)\n");
+    for _ in 0..=100 {
+        wat.push_str("(block\n");
+    }
+    wat.push_str("local.get 0\nbr_table");
+    for depth in 0..=100 {
+        write!(wat, " {}", depth).unwrap();
+    }
+    for depth in 0..=100 {
+        write!(wat, ")\ni32.const {} return\n", depth * 10).unwrap();
+    }
+    wat.push_str(") (export \"main\" (func $main)))");
+
+    let bytecode = wat::parse_str(&wat).unwrap();
+    let mut comp = compiler::X86_64Compiler::new();
+    comp.parse(&bytecode).unwrap();
+    let (module, stats) = comp.compile_with_stats().unwrap();
+    let stats = stats[FuncIndex::from_u32(0)];
+    assert_eq!(stats.jump_tables, 1);
+    assert_eq!(stats.jump_table_entries, 100);
+
+    assert_eq!(execute_2(module, 0, 0), 0);
+    for (arg, expected) in [(42, 420), (99, 990), (100, 1000), (-1, 1000)] {
+        assert_eq!(execute_2(compile(&wat), arg, 0), expected);
+    }
+}
+
+#[test]
+fn optimized_code_size() {
+    let wat = r#"
         (module
-            (import "native_mod" "table"
-                (table $table 2 2 externref)
+            (func $helper (param i32) (result i32)
+                local.get 0
+                i32.const 1
+                i32.add
             )
             (func $main (result i32)
-                i32.const 42
+                (local i32 i32)
+                (loop $loop
+                    i32.const 6
+                    i32.const 7
+                    i32.mul
+                    local.set 1
+                    local.get 0
+                    i32.const 1
+                    i32.add
+                    local.tee 0
+                    i32.const 10
+                    i32.lt_u
+                    br_if $loop
+                )
+                local.get 1
             )
             (export "main" (func $main))
         )
-        "#,
-    );
+    "#;
+    let code_sizes = |module: &WasmModule| {
+        module
+            .funcs()
+            .iter()
+            .map(|(_, func)| match func {
+                FuncInfo::Owned { size, .. } => *size,
+                _ => panic!("Expected an owned function"),
+            })
+            .collect::<Vec<u32>>()
+    };
 
-    let ref1 = ExternRef(0x42 as *const u8);
-    let ref2 = ExternRef(0x54 as *const u8);
-    let table = vec![ref1, ref2];
-    let imported_module = NativeModuleBuilder::new()
-        .add_table(String::from("table"), table)
-        .build();
-    let answer = execute_0_deps(module, vec![("native_mod", imported_module)]);
-    assert_eq!(answer.return_value, 42);
+    let module = compile(wat);
+    let sizes = code_sizes(&module);
+    assert!(sizes.iter().all(|size| *size > 0));
+    assert!(sizes.iter().sum::<u32>() <= module.code().len() as u32);
+
+    let optimized = compile_with_options(
+        wat,
+        compiler::CompilerOptions {
+            optimize: true,
+            ..Default::default()
+        },
+    );
+    let optimized_sizes = code_sizes(&optimized);
+    assert!(optimized_sizes[1] < sizes[1]);
+    assert_eq!(execute_0(optimized), 42);
 }
 
 #[test]
-fn table_get_set() {
-    // Swith the position of two table items
-    let module = compile(
-        r#"
+fn shared_trap_blocks() {
+    let wat = r#"
         (module
-            (import "native_mod" "table"
-                (table $table 2 2 externref)
+            (memory 1)
+            (func $sum (param i32) (result i32)
+                local.get 0
+                i32.load
+                local.get 0
+                i32.load offset=4
+                i32.add
+                local.get 0
+                i32.load offset=8
+                i32.add
+                local.get 0
+                i32.load offset=12
+                i32.add
             )
-            (func $main (result i32)
-                i32.const 1
-                i32.const 0
-                table.get $table
+            (func (export "ok") (result i32)
                 i32.const 0
-                i32.const 1
-                table.get $table
-                table.set $table
-                table.set $table
-
                 i32.const 42
+                i32.store
+                i32.const 0
+                call $sum
+            )
+            (func (export "oob") (result i32)
+                i32.const 0xfff8
+                call $sum
             )
-            (export "main" (func $main))
-            (export "table" (table $table))
         )
-        "#,
-    );
+    "#;
+    let compile_with_stats = |shared_traps| {
+        let bytecode = wat::parse_str(wat).unwrap();
+        let mut comp = compiler::X86_64Compiler::with_options(compiler::CompilerOptions {
+            shared_traps,
+            ..Default::default()
+        });
+        comp.parse(&bytecode).unwrap();
+        comp.compile_with_stats().unwrap()
+    };
+    let (module, stats) = compile_with_stats(false);
+    let (shared, shared_stats) = compile_with_stats(true);
 
-    let ref1 = ExternRef(0x42 as *const u8);
-    let ref2 = ExternRef(0x54 as *const u8);
-    let table = vec![ref1, ref2];
-    let imported_module = NativeModuleBuilder::new()
-        .add_table(String::from("table"), table)
-        .build();
-    let answer = execute_0_deps(module, vec![("native_mod", imported_module)]);
-    assert_eq!(answer.return_value, 42);
-    let table = answer.instance.get_table_by_name("table");
-    assert_eq!(table, Some(&vec![0x54, 0x42].into_boxed_slice()));
+    // The four bounds checks of `sum` share a single trap
+    let sum = FuncIndex::from_u32(0);
+    assert!(shared_stats[sum].trap_sites < stats[sum].trap_sites);
+    assert!(shared.code().len() < module.code().len());
+
+    let runtime = Runtime::new();
+    let instance = Instance::instantiate(&shared, &[], &runtime).unwrap();
+    let call = |name: &str| {
+        let func = instance.get_func_index_by_name(name).unwrap();
+        userspace_alloc::call(&instance, func)
+    };
+    assert_eq!(call("ok"), Ok(42));
+    let trap = call("oob").unwrap_err();
+    assert_eq!(trap.code, TrapCode::HeapOutOfBounds);
+    assert_eq!(trap.location.unwrap().func, sum);
 }
 
 #[test]
-fn context_switch() {
-    // The memory must not be shared between instances!
-    let module = compile(
-        r#"
+fn inline_small_calls() {
+    let wat = r#"
         (module
-            (import "mod" "zero"
-                (func $zero (type $t))
+            (memory 1)
+            (func $add (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.add
             )
-            (type $t (func (result i32)))
-            (func $call_imported (result i32)
-                i32.const 0  ;; Memory addres
-                i32.const 14 ;; Random value
+            (func $load_or_zero (param i32) (result i32)
+                local.get 0
+                i32.const 64
+                i32.ge_u
+                if
+                    i32.const 0
+                    return
+                end
+                local.get 0
+                i32.load
+            )
+            (func $sum (param i32) (result i32)
+                (local i32)
+                (loop $loop
+                    local.get 1
+                    local.get 0
+                    call $load_or_zero
+                    call $add
+                    local.set 1
+                    local.get 0
+                    i32.const 4
+                    call $add
+                    local.tee 0
+                    i32.const 128
+                    i32.lt_u
+                    br_if $loop
+                )
+                local.get 1
+            )
+            (func (export "main") (result i32)
+                i32.const 8
+                i32.const 40
                 i32.store
-
-                call $zero   ;; Should return 0 if memory are not shared
-                i32.const 42
-                i32.add      ;; Should return 42 if memory are not shared
+                i32.const 60
+                i32.const 2
+                i32.store
+                i32.const 0
+                call $sum
             )
-            (memory $mem 1 1)
-            (export "main" (func $call_imported))
         )
-        "#,
+    "#;
+    let compile_with_stats = |inline_threshold| {
+        let bytecode = wat::parse_str(wat).unwrap();
+        let mut comp = compiler::X86_64Compiler::with_options(compiler::CompilerOptions {
+            inline_threshold,
+            ..Default::default()
+        });
+        comp.parse(&bytecode).unwrap();
+        comp.compile_with_stats().unwrap()
+    };
+    let (module, stats) = compile_with_stats(0);
+    let (inlined, inlined_stats) = compile_with_stats(32);
+
+    // `sum` calls are inlined, but not the call to `sum` which is not a leaf function
+    let sum = FuncIndex::from_u32(2);
+    let main = FuncIndex::from_u32(3);
+    assert_eq!(stats[sum].inlined_calls, 0);
+    assert_eq!(inlined_stats[sum].inlined_calls, 3);
+    assert_eq!(inlined_stats[main].inlined_calls, 0);
+
+    assert_eq!(execute_0(module), 42);
+    assert_eq!(execute_0(inlined), 42);
+
+    let optimized = compile_with_options(
+        wat,
+        compiler::CompilerOptions {
+            optimize: true,
+            inline_threshold: 32,
+            ..Default::default()
+        },
     );
-    let imported_module = compile(
-        r#"
+    assert_eq!(execute_0(optimized), 42);
+}
+
+#[test]
+fn null_references() {
+    let wat = r#"
         (module
-            (func $zero (result i32)
+            (type $void (func))
+            (table $handles 4 externref)
+            (table $funcs 4 funcref)
+            (global $handle (mut externref) (ref.null extern))
+            (func $nop)
+            (elem (table $funcs) (i32.const 1) funcref (ref.null func) (ref.func $nop))
+            (func (export "null") (result i32)
+                ref.null extern
+                ref.is_null
+                ref.null func
+                ref.is_null
+                i32.add
+            )
+            (func (export "empty_slots") (result i32)
+                (ref.is_null (table.get $handles (i32.const 3)))
+                (ref.is_null (global.get $handle))
+                i32.add
+                (ref.is_null (table.get $funcs (i32.const 1)))
+                i32.add
+                (ref.is_null (table.get $funcs (i32.const 2)))
+                i32.add
+            )
+            (func (export "call_null") (result i32)
+                (call_indirect $funcs (type $void) (i32.const 1))
                 i32.const 0
-                i32.load
             )
-            (memory $mem 1 1)
-            (export "zero" (func $zero))
         )
-    "#,
-    );
-    let answer = execute_0_deps(module, vec![("mod", imported_module)]);
-    assert_eq!(answer.return_value, 42);
+    "#;
+    let module = compile(wat);
+    let runtime = Runtime::new();
+    let instance = Instance::instantiate(&module, &[], &runtime).unwrap();
+    let call = |name: &str| {
+        let func = instance.get_func_index_by_name(name).unwrap();
+        userspace_alloc::call(&instance, func)
+    };
+    assert_eq!(call("null"), Ok(2));
+    assert_eq!(call("empty_slots"), Ok(3));
+    let trap = call("call_null").unwrap_err();
+    assert_eq!(trap.code, TrapCode::IndirectCallToNull);
 }
 
 #[test]
-fn global_read() {
-    let module = compile(
-        r#"
+fn canonical_nans() {
+    // Returns the bits of a NaN, case 0 propagates a payload, case 1 computes 0/0 and case 2
+    // returns the upper half of sqrt(-1) after a few more operations
+    let wat = r#"
         (module
-            (func $the_answer (result i32)
-                global.get $glob
+            (func $main (param $case i32) (param i32) (result i32)
+                (local $x f64)
+                local.get $case
+                i32.eqz
+                if
+                    f32.const nan:0x200000
+                    f32.const 1
+                    f32.add
+                    i32.reinterpret_f32
+                    return
+                end
+                local.get $case
+                i32.const 1
+                i32.eq
+                if
+                    f32.const 0
+                    f32.const 0
+                    f32.div
+                    i32.reinterpret_f32
+                    return
+                end
+                f64.const -1
+                f64.sqrt
+                f64.const 3.5
+                f64.mul
+                local.tee $x
+                local.get $x
+                f64.sub
+                i64.reinterpret_f64
+                i64.const 32
+                i64.shr_u
+                i32.wrap_i64
             )
-            (global $glob i32 (i32.const 42))
-            (export "main" (func $the_answer))
+            (export "main" (func $main))
         )
-    "#,
-    );
-    assert_eq!(execute_0(module), 42);
+    "#;
+    const CANONICAL_F32: i32 = 0x7fc0_0000;
+    const CANONICAL_F64_UPPER: i32 = 0x7ff8_0000;
+
+    // x86 returns a negative NaN for invalid operations
+    assert_ne!(execute_2(compile(wat), 1, 0), CANONICAL_F32);
+
+    for optimize in [false, true] {
+        let options = compiler::CompilerOptions {
+            canonicalize_nans: true,
+            optimize,
+            ..Default::default()
+        };
+        assert_eq!(
+            execute_2(compile_with_options(wat, options), 0, 0),
+            CANONICAL_F32
+        );
+        assert_eq!(
+            execute_2(compile_with_options(wat, options), 1, 0),
+            CANONICAL_F32
+        );
+        assert_eq!(
+            execute_2(compile_with_options(wat, options), 2, 0),
+            CANONICAL_F64_UPPER
+        );
+    }
 }
 
 #[test]
-fn global_write() {
-    let module = compile(
+fn module_image() {
+    // The first heap holds a large segment, the second a small one
+    let wat = format!(
         r#"
         (module
-            (func $the_answer (result i32)
-                i32.const 42
-                global.set $glob
-                global.get $glob
+            (memory $large 2)
+            (memory $small 1)
+            (data (memory $large) (i32.const 0) "{}")
+            (data (memory $small) (i32.const 0) "\2a")
+            (func (export "write")
+                i32.const 100
+                i32.const 7
+                i32.store8 $large
+                i32.const 0
+                i32.const 7
+                i32.store8 $small
+            )
+            (func (export "read") (result i32)
+                i32.const 100
+                i32.load8_u $large
+                i32.const 0
+                i32.load8_u $small
+                i32.add
             )
-            (global $glob (mut i32) (i32.const 0))
-            (export "main" (func $the_answer))
         )
     "#,
+        "\\2a".repeat(wasm::MODULE_IMAGE_THRESHOLD)
     );
-    assert_eq!(execute_0(module), 42);
+    let module = compile(&wat);
+    let runtime = Runtime::new();
+    let image = ModuleImage::new(&module, &runtime).unwrap();
+    assert!(!image.is_empty());
+
+    let instantiate = || {
+        Instance::instantiate_from_image(&module, &image, &[], &runtime, &AllocPolicy::default())
+            .unwrap()
+    };
+    let call = |instance: &Instance<Arc<MMapArea>>, name: &str| {
+        let func = instance.get_func_index_by_name(name).unwrap();
+        userspace_alloc::call(instance, func)
+    };
+
+    // Writes are not visible to the image, nor to the other instances
+    let first = instantiate();
+    assert_eq!(call(&first, "read"), Ok(0x2a + 0x2a));
+    call(&first, "write").unwrap();
+    assert_eq!(call(&first, "read"), Ok(7 + 7));
+    let second = instantiate();
+    assert_eq!(call(&second, "read"), Ok(0x2a + 0x2a));
+
+    // Small segments are not worth an image
+    let small = compile(r#"(module (memory 1) (data (i32.const 0) "\2a"))"#);
+    assert!(ModuleImage::new(&small, &runtime).unwrap().is_empty());
 }
 
 #[test]
-fn import_global() {
+fn pre_initialization() {
     let module = compile(
         r#"
         (module
-            (type $t (func))
-            (import "answer" "set_answer"
-                (func $set_answer (type $t))
+            (memory 1)
+            (global $counter (mut i32) (i32.const 1))
+            (func $start
+                global.get $counter
+                i32.const 10
+                i32.add
+                global.set $counter
             )
-            (import "answer" "the_answer"
-                (global $the_answer i32)
+            (func (export "init")
+                i32.const 1024
+                i32.const 30
+                i32.store
+                global.get $counter
+                i32.const 1
+                i32.add
+                global.set $counter
             )
-            (func $main (result i32)
-                call $set_answer
-                global.get $the_answer
+            (func (export "main") (result i32)
+                i32.const 1024
+                i32.load
+                global.get $counter
+                i32.add
             )
-            (export "main" (func $main))
+            (start $start)
         )
-        "#,
+    "#,
     );
-    let imported_module = compile(
+    let module = userspace_alloc::pre_initialize(module, "init").unwrap();
+    assert!(module.start().is_none());
+    assert_eq!(module.data_segments().len(), 1);
+    assert_eq!(module.data_segments()[0].offset, 1024);
+
+    // The state left by the start and init functions is restored without running them again
+    assert_eq!(execute_0(module), 30 + 12);
+
+    let module = compile(r#"(module (func (export "main")))"#);
+    assert!(matches!(
+        userspace_alloc::pre_initialize(module, "init"),
+        Err(userspace_alloc::PreInitError::MissingInit)
+    ));
+}
+
+#[test]
+fn global_offset_table() {
+    use cranelift_codegen::binemit::Reloc as CraneliftReloc;
+    use cranelift_codegen::ir::{ExternalName, SourceLoc};
+    use cranelift_codegen::MachReloc;
+
+    // Two `mov rax, [rip + disp32]` loading the address of the same function from the GOT
+    let mut code = vec![
+        0x48, 0x8b, 0x05, 0, 0, 0, 0, //
+        0x48, 0x8b, 0x05, 0, 0, 0, 0, //
+        0xc3,
+    ];
+    let got_reloc = |offset| MachReloc {
+        offset,
+        srcloc: SourceLoc::default(),
+        kind: CraneliftReloc::X86GOTPCRel4,
+        name: ExternalName::user(0, 1),
+        addend: -4,
+    };
+    let mut relocs = compiler::RelocationHandler::new();
+    relocs.extend_relocs(&[got_reloc(3), got_reloc(10)]);
+    relocs.build_got(&mut code);
+
+    // A single entry, 8 bytes aligned, is appended to the code
+    assert_eq!(code.len(), 24);
+    assert_eq!(code[3..7], 9i32.to_le_bytes());
+    assert_eq!(code[10..14], 2i32.to_le_bytes());
+    let relocs = relocs.into_relocs();
+    assert_eq!(relocs.len(), 1);
+    assert_eq!(relocs[0].offset, 16);
+    assert_eq!(relocs[0].kind, RelocKind::Abs8);
+}
+
+#[test]
+fn bundle() {
+    let lib = wat::parse_str(
         r#"
         (module
-            (func $set_answer
+            (func (export "answer") (result i32)
                 i32.const 42
-                global.set $answer
             )
-            (global $answer (mut i32) (i32.const 0))
-            (export "the_answer" (global $answer))
-            (export "set_answer" (func $set_answer))
         )
     "#,
-    );
-    let answer = execute_0_deps(module, vec![("answer", imported_module)]);
-    assert_eq!(answer.return_value, 42);
+    )
+    .unwrap();
+    let main = wat::parse_str(
+        r#"
+        (module
+            (import "lib" "answer" (func $answer (result i32)))
+            (import "host" "log" (func $log (param i32)))
+            (func (export "main") (result i32)
+                call $answer
+            )
+        )
+    "#,
+    )
+    .unwrap();
+    let options = compiler::CompilerOptions::default();
+    let bytes = crate::compile_bundle(&[("lib", &lib), ("main", &main)], options).unwrap();
+
+    // Only the imports from modules of the bundle are linked
+    let bundle = wasm::Bundle::parse(&bytes).unwrap();
+    let modules = bundle.modules();
+    assert_eq!(modules.len(), 2);
+    assert_eq!(modules[0].name, "lib");
+    assert!(modules[0].links.is_empty());
+    assert_eq!(modules[1].name, "main");
+    assert_eq!(modules[1].links, [("lib", 0)]);
+    assert_eq!(modules[1].wasm, main);
+
+    // Errors report the module at fault
+    let err = crate::compile_bundle(&[("lib", &lib), ("lib", &lib)], options).unwrap_err();
+    assert!(matches!(
+        err,
+        crate::BundleCompilerError::DuplicateName { module: 1 }
+    ));
+    let err = crate::compile_bundle(&[("lib", &lib), ("main", b"\0asm")], options).unwrap_err();
+    assert!(matches!(
+        err,
+        crate::BundleCompilerError::FailedToCompile { module: 1, .. }
+    ));
 }
 
 #[test]
-fn func_typecheck() {
-    let module = compile(
+fn deterministic_compilation() {
+    // Exercises the parts of a module built from maps: exports with several names, imports from
+    // interleaved modules, interned signatures, relocations, stack maps and trap sites.
+    let bytecode = wat::parse_str(
         r#"
         (module
-            (import "answer" "the_answer"
-                (func $the_answer (type $t))
+            (import "env" "log" (func $log (param i32)))
+            (import "lib" "memory" (memory 1))
+            (import "env" "now" (func $now (result i64)))
+            (import "lib" "answer" (func $answer (result i32)))
+            (table 2 funcref)
+            (elem (i32.const 0) $pick $keep_alive)
+            (global $counter (export "counter") (mut i32) (i32.const 0))
+            (data (i32.const 16) "coral")
+            (func $keep_alive (param externref) (result externref)
+                call $answer
+                call $log
+                local.get 0
             )
-            (type $t (func (result i32)))
-            (func $call_imported (result i32)
-                call $the_answer
+            (func $pick (export "pick") (export "choose") (param i32) (result i32)
+                (block $b2 (block $b1 (block $b0
+                    local.get 0
+                    br_table $b0 $b1 $b2)
+                    i32.const 10
+                    return)
+                    i32.const 20
+                    return)
+                local.get 0
+                i32.load offset=16
+                i32.const 3
+                i32.div_s
+            )
+            (func (export "main") (result i32)
+                call $now
+                drop
+                global.get $counter
+                i32.const 0
+                call_indirect (param i32) (result i32)
             )
-            (export "main" (func $call_imported))
         )
-        "#,
-    );
-
-    fn foreign_func(_: i32) {}
-    as_native_func!(foreign_func; FOREIGN_FUNC; args: i32; ret: ());
-
-    let imported_module = unsafe {
-        NativeModuleBuilder::new()
-            .add_func(String::from("the_answer"), &FOREIGN_FUNC)
-            .build()
-    };
+    "#,
+    )
+    .unwrap();
+    let options = [
+        compiler::CompilerOptions::default(),
+        compiler::CompilerOptions {
+            position_independent: true,
+            optimize: true,
+            call_counters: true,
+            shared_traps: true,
+            ..Default::default()
+        },
+    ];
+    for options in options {
+        let compile = || {
+            let mut comp = compiler::X86_64Compiler::with_options(options);
+            comp.parse(&bytecode).unwrap();
+            comp.compile().unwrap()
+        };
+        let first = compile();
+        let second = compile();
+        assert_eq!(first.code(), second.code());
+        assert_eq!(first.relocs(), second.relocs());
+        assert_eq!(first.stack_maps(), second.stack_maps());
+        assert_eq!(first.trap_sites(), second.trap_sites());
+        assert_eq!(first.public_items(), second.public_items());
+        assert_eq!(
+            format!("{:?}", first.imports()),
+            format!("{:?}", second.imports())
+        );
+    }
 
-    assert!(type_error(module, vec![("answer", imported_module)]));
+    // Bundles are byte-identical as well
+    let lib = wat::parse_str(
+        r#"
+        (module
+            (func (export "answer") (result i32)
+                i32.const 42
+            )
+        )
+    "#,
+    )
+    .unwrap();
+    let modules: [(&str, &[u8]); 2] = [("lib", &lib), ("main", &bytecode)];
+    let options = compiler::CompilerOptions::default();
+    assert_eq!(
+        crate::compile_bundle(&modules, options).unwrap(),
+        crate::compile_bundle(&modules, options).unwrap()
+    );
 }
 
 #[test]
@@ -567,6 +2691,16 @@ fn compile(wat: &str) -> WasmModule {
     comp.compile().unwrap()
 }
 
+fn compile_with_options(wat: &str, options: compiler::CompilerOptions) -> WasmModule {
+    let bytecode = wat::parse_str(wat).unwrap();
+    let mut comp = compiler::X86_64Compiler::with_options(options);
+    comp.parse(&bytecode).unwrap();
+    comp.compile().unwrap()
+}
+
+// The helpers below pass arguments following the System V calling convention.
+const _: () = assert!(matches!(wasm::CALL_CONV, wasm::CallConv::SystemV));
+
 /// Execute a module, with no arguments passed to the main function.
 fn execute_0(module: impl Module) -> i32 {
     let runtime = Runtime::new();
@@ -1,12 +1,22 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec;
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::cell::{Cell, RefCell};
 use core::marker::PhantomData;
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicU8, Ordering};
 
-use wasm::{HeapKind, MemoryArea, ModuleError, RefType};
+use collections::HashMap;
+use wasm::{
+    AllocPolicy, CpuFeatures, FuncIndex, HeapKind, Instance, MemoryArea, ModuleError, Placement,
+    PreemptionHandler, RefType, TrapCode, WasmLocation, WasmModule, NULL_REF,
+};
 
 const PAGE_SIZE: usize = 0x1000;
+/// Size of the address space region reserved for each placement group.
+const GROUP_REGION_SIZE: usize = 1 << 32;
 
 // —————————————————————————————— Memory Area ——————————————————————————————— //
 
@@ -35,6 +45,18 @@ impl MMapArea {
         }
     }
 
+    fn new(ptr: *mut u8, size: usize) -> Self {
+        Self {
+            ptr: NonNull::new(ptr).unwrap(),
+            size,
+            marker: PhantomData,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.size) }
+    }
+
     fn as_bytes_mut(&mut self) -> &mut [u8] {
         unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.size) }
     }
@@ -61,104 +83,435 @@ impl LibcAllocator {
 }
 
 impl LibcAllocator {
-    fn with_capacity(&self, n: usize) -> Result<MMapArea, ()> {
-        let mut nb_pages = 1;
-        while nb_pages * PAGE_SIZE < n {
-            nb_pages += 1;
+    /// Maps at least `n` bytes, aligned to `align` bytes.
+    fn with_capacity(&self, n: usize, align: usize) -> Result<MMapArea, ()> {
+        let size = Self::round_to_pages(n);
+        if align <= PAGE_SIZE {
+            let ptr = Self::mmap(0, size, libc::PROT_READ | libc::PROT_WRITE, 0)?;
+            return Ok(MMapArea::new(ptr, size));
         }
 
-        let size = PAGE_SIZE * nb_pages;
+        // Over-allocate, then unmap the unaligned head and the tail
+        let ptr = Self::mmap(0, size + align, libc::PROT_READ | libc::PROT_WRITE, 0)? as usize;
+        let start = (ptr + align - 1) & !(align - 1);
+        unsafe {
+            if start > ptr {
+                libc::munmap(ptr as *mut libc::c_void, start - ptr);
+            }
+            libc::munmap((start + size) as *mut libc::c_void, ptr + align - start);
+        }
+        Ok(MMapArea::new(start as *mut u8, size))
+    }
+
+    /// Maps at least `n` bytes at `addr`, which must be within a reserved region.
+    fn with_capacity_at(&self, n: usize, addr: usize) -> Result<MMapArea, ()> {
+        let size = Self::round_to_pages(n);
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let ptr = Self::mmap(addr, size, prot, libc::MAP_FIXED)?;
+        Ok(MMapArea::new(ptr, size))
+    }
+
+    /// Reserves a region of the address space, without backing memory.
+    fn reserve(&self, size: usize) -> Result<usize, ()> {
+        let ptr = Self::mmap(0, size, libc::PROT_NONE, libc::MAP_NORESERVE)?;
+        Ok(ptr as usize)
+    }
+
+    fn mmap(addr: usize, size: usize, prot: i32, flags: i32) -> Result<*mut u8, ()> {
         let ptr = unsafe {
             libc::mmap(
-                0 as *mut libc::c_void,
-                PAGE_SIZE * nb_pages,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                addr as *mut libc::c_void,
+                size,
+                prot,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
                 -1,
                 0,
             )
-        } as *mut u8;
-
-        if let Some(ptr) = NonNull::new(ptr) {
-            Ok(MMapArea {
-                ptr,
-                size,
-                marker: PhantomData,
-            })
-        } else {
+        };
+        if ptr == libc::MAP_FAILED {
             Err(())
+        } else {
+            Ok(ptr as *mut u8)
         }
     }
+
+    /// Rounds a size up to a whole number of pages, with at least one page.
+    fn round_to_pages(n: usize) -> usize {
+        let nb_pages = (n + PAGE_SIZE - 1) / PAGE_SIZE;
+        nb_pages.max(1) * PAGE_SIZE
+    }
 }
 
 // ——————————————————————————— Userspace Runtime ———————————————————————————— //
 
 pub struct Runtime {
     alloc: LibcAllocator,
+    /// The address space regions reserved for each placement group.
+    groups: RefCell<HashMap<u64, GroupRegion>>,
+    /// The CPU features available to instances.
+    cpu_features: CpuFeatures,
+    /// The handler called by instances once preemption has been requested.
+    preemption_handler: Option<PreemptionHandler>,
+}
+
+/// A region of the address space reserved for a placement group.
+struct GroupRegion {
+    /// Next available address.
+    cursor: usize,
+    /// End of the region.
+    end: usize,
 }
 
 impl Runtime {
     pub fn new() -> Self {
         Self {
             alloc: LibcAllocator::new(),
+            groups: RefCell::new(HashMap::new()),
+            cpu_features: CpuFeatures::BASELINE,
+            preemption_handler: None,
+        }
+    }
+
+    /// Creates a runtime which only accepts modules requiring the given CPU features.
+    pub fn with_cpu_features(cpu_features: CpuFeatures) -> Self {
+        Self {
+            cpu_features,
+            ..Self::new()
+        }
+    }
+
+    /// Creates a runtime whose instances call the given handler once preemption has been
+    /// requested.
+    pub fn with_preemption_handler(handler: PreemptionHandler) -> Self {
+        Self {
+            preemption_handler: Some(handler),
+            ..Self::new()
+        }
+    }
+
+    /// Allocates an area according to the allocation policy.
+    fn alloc_area(&self, size: usize, policy: &AllocPolicy) -> Result<MMapArea, ModuleError> {
+        let size = LibcAllocator::round_to_pages(size);
+        policy.charge(size)?;
+        let area = match policy.placement {
+            Placement::Anywhere => self.alloc.with_capacity(size, policy.alignment),
+            Placement::Grouped(group) => {
+                let addr = self.reserve_in_group(group, size, policy)?;
+                self.alloc.with_capacity_at(size, addr)
+            }
+        };
+        area.map_err(|_| ModuleError::RuntimeError)
+    }
+
+    /// Returns the address of the next area of a group, reserving the group region if needed.
+    fn reserve_in_group(
+        &self,
+        group: u64,
+        size: usize,
+        policy: &AllocPolicy,
+    ) -> Result<usize, ModuleError> {
+        let mut groups = self.groups.borrow_mut();
+        if !groups.contains_key(&group) {
+            let start = self
+                .alloc
+                .reserve(GROUP_REGION_SIZE)
+                .map_err(|_| ModuleError::RuntimeError)?;
+            let end = start + GROUP_REGION_SIZE;
+            groups.insert(group, GroupRegion { cursor: start, end });
         }
+
+        let region = groups.get_mut(&group).unwrap();
+        let addr = policy.align(region.cursor);
+        if addr + size > region.end {
+            return Err(ModuleError::RuntimeError);
+        }
+        region.cursor = addr + size;
+        Ok(addr)
     }
 }
 
 unsafe impl wasm::Runtime for Runtime {
     type MemoryArea = Arc<MMapArea>;
-    type Context = ();
+    type Context = AllocPolicy;
+
+    fn cpu_features(&self) -> CpuFeatures {
+        self.cpu_features
+    }
+
+    fn preemption_handler(&self) -> Option<PreemptionHandler> {
+        self.preemption_handler
+    }
 
-    fn create_context(&self) -> Self::Context {}
+    fn create_context(&self, policy: &AllocPolicy) -> Self::Context {
+        policy.clone()
+    }
 
     fn alloc_heap<F>(
         &self,
         min_size: usize,
         _kind: HeapKind,
         initialize: F,
-        _ctx: &mut Self::Context,
+        policy: &mut Self::Context,
     ) -> Result<Self::MemoryArea, ModuleError>
     where
         F: FnOnce(&mut [u8]) -> Result<(), ModuleError>,
     {
-        let mut area = self
-            .alloc
-            .with_capacity(min_size as usize)
-            .map_err(|_| wasm::ModuleError::RuntimeError)?;
+        // Areas are fresh anonymous mappings, which are zeroed by the OS
+        let mut area = self.alloc_area(min_size, policy)?;
         initialize(area.as_bytes_mut())?;
         Ok(Arc::new(area))
     }
 
+    fn fork_heap(
+        &self,
+        heap: &Self::MemoryArea,
+        size: usize,
+        policy: &mut Self::Context,
+    ) -> Result<Self::MemoryArea, ModuleError> {
+        // Heaps are copied eagerly
+        let mut area = self.alloc_area(size, policy)?;
+        area.as_bytes_mut()[..size].copy_from_slice(&heap.as_bytes()[..size]);
+        Ok(Arc::new(area))
+    }
+
     fn alloc_table(
         &self,
         min_size: u32,
         max_size: Option<u32>,
         _ty: RefType,
-        _ctx: &mut Self::Context,
+        policy: &mut Self::Context,
     ) -> Result<Box<[u64]>, ModuleError> {
         let size = if let Some(max_size) = max_size {
             max_size
         } else {
             min_size
         } as usize;
-        Ok(vec![0; size].into_boxed_slice())
+        policy.charge(size * core::mem::size_of::<u64>())?;
+        Ok(vec![NULL_REF; size].into_boxed_slice())
     }
 
     fn alloc_code<F>(
         &self,
         size: usize,
         write_code: F,
-        _ctx: &mut Self::Context,
+        policy: &mut Self::Context,
     ) -> Result<Self::MemoryArea, ModuleError>
     where
         F: FnOnce(&mut [u8]) -> Result<(), ModuleError>,
     {
-        let mut area = self
-            .alloc
-            .with_capacity(size)
-            .map_err(|_| wasm::ModuleError::RuntimeError)?;
+        let mut area = self.alloc_area(size, policy)?;
         write_code(area.as_bytes_mut())?;
         area.set_executable();
         Ok(Arc::new(area))
     }
 }
+
+// ————————————————————————————————— Traps —————————————————————————————————— //
+
+/// A WebAssembly trap, raised by a fault while executing an instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub code: TrapCode,
+    /// The location of the faulting instruction, `None` if it is not within a function.
+    pub location: Option<WasmLocation>,
+}
+
+/// The state to restore if the instance traps, mirroring the recovery points of the kernel.
+#[repr(C)]
+struct RecoveryPoint {
+    /// The stack pointer right before calling into the instance.
+    rsp: u64,
+    /// The address of the instruction following the call into the instance.
+    rip: u64,
+    /// Set by the signal handler when the instance traps.
+    trapped: bool,
+    /// The address of the faulting instruction, set by the signal handler when the instance traps.
+    fault_ip: u64,
+    /// The instance called into, type-erased for the signal handler.
+    instance: *const (),
+    /// Returns true if the address is within the code of the instance.
+    in_code: unsafe fn(*const (), usize) -> bool,
+}
+
+/// The recovery point of the innermost call into an instance on this thread, null if no instance
+/// is executing.
+#[thread_local]
+static RECOVERY_POINT: Cell<*mut RecoveryPoint> = Cell::new(ptr::null_mut());
+
+/// The state of the signal handlers: 0 if not installed, 1 while installing, 2 once installed.
+static HANDLERS: AtomicU8 = AtomicU8::new(0);
+
+/// The signals raised by faulting WebAssembly code.
+const TRAP_SIGNALS: [libc::c_int; 3] = [libc::SIGSEGV, libc::SIGILL, libc::SIGFPE];
+
+/// Calls a function taking no arguments, and returns the raw value of rax or the trap raised by
+/// the function. The call is recorded in the metrics of the instance, with CPU cycles as ticks.
+///
+/// Traps are caught by signal handlers: a fault within the code of the instance resumes execution
+/// right after the call, which then reports the trap. Faults elsewhere are not recovered from and
+/// terminate the process.
+///
+/// NOTE: only faults within the code of `instance` are caught, a trap within an imported
+/// function still terminates the process.
+pub fn call<Area: MemoryArea>(instance: &Instance<Area>, func: FuncIndex) -> Result<u64, Trap> {
+    assert!(
+        instance.get_func_type_by_index(func).args().is_empty(),
+        "Only functions without arguments can be called"
+    );
+    install_signal_handlers();
+
+    let func_ptr = instance.get_func_addr_by_index(func);
+    let vmctx = instance.get_vmctx_ptr();
+    let mut recovery = RecoveryPoint {
+        rsp: 0,
+        rip: 0,
+        trapped: false,
+        fault_ip: 0,
+        instance: instance as *const Instance<Area> as *const (),
+        in_code: in_code::<Area>,
+    };
+    let recovery_ptr: *mut RecoveryPoint = &mut recovery;
+    let start = unsafe { _rdtsc() };
+    let previous = RECOVERY_POINT.replace(recovery_ptr);
+    let rax: u64;
+    unsafe {
+        asm!(
+            // If the instance traps the callee-saved registers are not restored, but LLVM does
+            // not let us mark rbx and rbp as clobbered.
+            "push rbx",
+            "push rbp",
+            // Register the recovery point, execution resumes at label 2 if the instance traps
+            "mov [{recovery}], rsp",
+            "lea r12, [rip + 2f]",
+            "mov [{recovery} + 8], r12",
+            "call {func_ptr}",
+            "2:",
+            "pop rbp",
+            "pop rbx",
+            recovery = in(reg) recovery_ptr,
+            func_ptr = in(reg) func_ptr,
+            in("rdi") vmctx,
+            out("rax") rax,
+            // Callee-saved registers, clobbered if the instance traps
+            out("r12") _,
+            out("r13") _,
+            out("r14") _,
+            out("r15") _,
+            clobber_abi("C"),
+        );
+    }
+    RECOVERY_POINT.set(previous);
+    instance.record_call(unsafe { _rdtsc() } - start);
+
+    // SAFETY: the recovery point might have been updated by the signal handler.
+    if unsafe { ptr::read_volatile(&recovery.trapped) } {
+        let fault_ip = unsafe { ptr::read_volatile(&recovery.fault_ip) };
+        let trap = match instance.locate_trap(fault_ip as usize) {
+            Some((code, location)) => Trap {
+                code,
+                location: Some(location),
+            },
+            None => Trap {
+                code: TrapCode::Unknown,
+                location: None,
+            },
+        };
+        return Err(trap);
+    }
+    Ok(rax)
+}
+
+unsafe fn in_code<Area: MemoryArea>(instance: *const (), addr: usize) -> bool {
+    let instance = &*(instance as *const Instance<Area>);
+    instance.locate_trap(addr).is_some()
+}
+
+/// Installs the signal handlers catching traps, if not already installed.
+fn install_signal_handlers() {
+    match HANDLERS.compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => (),
+        Err(_) => {
+            // Wait for the thread installing the handlers
+            while HANDLERS.load(Ordering::SeqCst) != 2 {
+                core::hint::spin_loop();
+            }
+            return;
+        }
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = core::mem::zeroed();
+        action.sa_sigaction = handle_fault as *const () as usize;
+        // The handler runs on the alternate stack, if any, so that stack overflows can be caught
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        for signal in TRAP_SIGNALS {
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                panic!("Could not install the trap handlers");
+            }
+        }
+    }
+    HANDLERS.store(2, Ordering::SeqCst);
+}
+
+/// Resumes execution right after the innermost call into an instance if the fault was raised by
+/// the code of that instance.
+///
+/// Other faults are not WebAssembly traps: the default action of the signal is restored, so that
+/// the faulting instruction terminates the process once executed again.
+unsafe extern "C" fn handle_fault(
+    signal: libc::c_int,
+    _info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let context = &mut *(context as *mut libc::ucontext_t);
+    let registers = &mut context.uc_mcontext.gregs;
+    let ip = registers[libc::REG_RIP as usize] as u64;
+
+    let recovery = RECOVERY_POINT.get();
+    if !recovery.is_null() && ((*recovery).in_code)((*recovery).instance, ip as usize) {
+        let recovery = &mut *recovery;
+        ptr::write_volatile(&mut recovery.trapped, true);
+        ptr::write_volatile(&mut recovery.fault_ip, ip);
+        registers[libc::REG_RIP as usize] = recovery.rip as libc::greg_t;
+        registers[libc::REG_RSP as usize] = recovery.rsp as libc::greg_t;
+        return;
+    }
+
+    libc::signal(signal, libc::SIG_DFL);
+}
+
+// ——————————————————————————— Pre-Initialization ——————————————————————————— //
+
+/// An error raised while pre-initializing a module.
+#[derive(Debug)]
+pub enum PreInitError {
+    /// The module could not be instantiated, or the snapshot does not match the module.
+    Module(ModuleError),
+    /// The module does not export the initialization function.
+    MissingInit,
+    /// The start or the initialization function trapped.
+    Trap(Trap),
+}
+
+/// Instantiates a module, runs its start function then its `init` export, and returns a module
+/// whose instances start in the resulting state (see `WasmModule::pre_initialized`).
+///
+/// The module can not have imports, and the initialization function must not take arguments.
+pub fn pre_initialize(module: WasmModule, init: &str) -> Result<WasmModule, PreInitError> {
+    let runtime = Runtime::new();
+    let instance = Instance::instantiate(&module, &[], &runtime).map_err(PreInitError::Module)?;
+    let init = instance
+        .get_func_index_by_name(init)
+        .ok_or(PreInitError::MissingInit)?;
+    if let Some(start) = instance.take_start() {
+        call(&instance, start).map_err(PreInitError::Trap)?;
+    }
+    call(&instance, init).map_err(PreInitError::Trap)?;
+
+    let snapshot = instance.snapshot().map_err(PreInitError::Module)?;
+    drop(instance);
+    module
+        .pre_initialized(snapshot)
+        .map_err(PreInitError::Module)
+}
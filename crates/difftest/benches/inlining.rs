@@ -0,0 +1,35 @@
+//! Measures the effect of inlining on the size and speed of the code generated for the corpus.
+
+use std::fs;
+use std::path::Path;
+
+use coral_difftest::{measure, INLINE_THRESHOLD};
+
+/// The number of calls to `main` the run time is averaged over.
+const RUNS: u32 = 1000;
+
+fn main() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+    let mut paths = fs::read_dir(corpus)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("wat".as_ref()))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    println!("Inlining threshold: {} instructions", INLINE_THRESHOLD);
+    println!(
+        "{:<16} {:>10} {:>10} {:>12} {:>12}",
+        "module", "size", "inlined", "time", "inlined"
+    );
+    for path in paths {
+        let bytecode = wat::parse_file(&path).unwrap();
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let baseline = measure(&bytecode, true, false, RUNS).unwrap();
+        let inlined = measure(&bytecode, true, true, RUNS).unwrap();
+        println!(
+            "{:<16} {:>10} {:>10} {:>12?} {:>12?}",
+            name, baseline.code_size, inlined.code_size, baseline.run_time, inlined.run_time
+        );
+    }
+}
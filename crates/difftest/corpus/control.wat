@@ -0,0 +1,40 @@
+;; Branch tables, blocks with results, select and indirect calls.
+(module
+  (memory (export "memory") 1)
+  (type $binop (func (param i32 i32) (result i32)))
+  (table 3 funcref)
+  (elem (i32.const 0) $add $sub $mul)
+  (func $add (type $binop) (i32.add (local.get 0) (local.get 1)))
+  (func $sub (type $binop) (i32.sub (local.get 0) (local.get 1)))
+  (func $mul (type $binop) (i32.mul (local.get 0) (local.get 1)))
+  (func $classify (param i32) (result i32)
+    (block $default
+      (block $two
+        (block $one
+          (block $zero
+            (br_table $zero $one $two $default (local.get 0)))
+          (return (i32.const 100)))
+        (return (i32.const 200)))
+      (return (i32.const 300)))
+    (i32.const -1))
+  (func (export "main") (result i32)
+    (local i32 i32)
+    (loop $next
+      (i32.store
+        (i32.shl (local.get 0) (i32.const 2))
+        (i32.add
+          (call $classify (local.get 0))
+          (call_indirect (type $binop)
+            (local.get 0)
+            (i32.const 7)
+            (i32.rem_u (local.get 0) (i32.const 3)))))
+      (local.set 1
+        (select
+          (local.get 1)
+          (i32.load (i32.shl (local.get 0) (i32.const 2)))
+          (i32.gt_s (local.get 1) (i32.load (i32.shl (local.get 0) (i32.const 2))))))
+      (br_if $next
+        (i32.lt_u
+          (local.tee 0 (i32.add (local.get 0) (i32.const 1)))
+          (i32.const 6))))
+    (local.get 1)))
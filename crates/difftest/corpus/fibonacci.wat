@@ -0,0 +1,33 @@
+;; Iterative and recursive Fibonacci, each result stored in memory.
+(module
+  (memory (export "memory") 1)
+  (func $fib_rec (param i32) (result i64)
+    (if (result i64) (i32.lt_u (local.get 0) (i32.const 2))
+      (then (i64.extend_i32_u (local.get 0)))
+      (else
+        (i64.add
+          (call $fib_rec (i32.sub (local.get 0) (i32.const 1)))
+          (call $fib_rec (i32.sub (local.get 0) (i32.const 2)))))))
+  (func $fib_iter (param i32) (result i64)
+    (local i64 i64 i64)
+    (local.set 2 (i64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get 0)))
+        (local.set 3 (i64.add (local.get 1) (local.get 2)))
+        (local.set 1 (local.get 2))
+        (local.set 2 (local.get 3))
+        (local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+        (br $next)))
+    (local.get 1))
+  (func (export "main") (result i64)
+    (local i32)
+    (loop $fill
+      (i64.store
+        (i32.shl (local.get 0) (i32.const 3))
+        (call $fib_iter (local.get 0)))
+      (br_if $fill
+        (i32.lt_u
+          (local.tee 0 (i32.add (local.get 0) (i32.const 1)))
+          (i32.const 90))))
+    (i64.add (call $fib_rec (i32.const 20)) (call $fib_iter (i32.const 89)))))
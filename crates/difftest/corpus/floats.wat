@@ -0,0 +1,26 @@
+;; Floating point arithmetic and conversions, the results are compared through memory.
+(module
+  (memory (export "memory") 1)
+  (func $store_f32 (param i32 f32)
+    (f32.store (i32.shl (local.get 0) (i32.const 2)) (local.get 1)))
+  (func $store_f64 (param i32 f64)
+    (f64.store (i32.add (i32.const 256) (i32.shl (local.get 0) (i32.const 3))) (local.get 1)))
+  (func (export "main") (result i32)
+    (call $store_f32 (i32.const 0) (f32.add (f32.const 0.1) (f32.const 0.2)))
+    (call $store_f32 (i32.const 1) (f32.sqrt (f32.const 2)))
+    (call $store_f32 (i32.const 2) (f32.min (f32.const -0) (f32.const 0)))
+    (call $store_f32 (i32.const 3) (f32.max (f32.const nan) (f32.const 1)))
+    (call $store_f32 (i32.const 4) (f32.nearest (f32.const 2.5)))
+    (call $store_f32 (i32.const 5) (f32.copysign (f32.const 3) (f32.const -1)))
+    (call $store_f32 (i32.const 6) (f32.convert_i32_u (i32.const -1)))
+    (call $store_f32 (i32.const 7) (f32.demote_f64 (f64.const 1e300)))
+    (call $store_f64 (i32.const 0) (f64.div (f64.const 1) (f64.const 3)))
+    (call $store_f64 (i32.const 1) (f64.floor (f64.const -1.5)))
+    (call $store_f64 (i32.const 2) (f64.trunc (f64.const -1.5)))
+    (call $store_f64 (i32.const 3) (f64.ceil (f64.const -1.5)))
+    (call $store_f64 (i32.const 4) (f64.convert_i64_s (i64.const -9007199254740993)))
+    (call $store_f64 (i32.const 5) (f64.promote_f32 (f32.const 0.1)))
+    (call $store_f64 (i32.const 6) (f64.reinterpret_i64 (i64.const 0x7ff0000000000001)))
+    (i32.add
+      (i32.trunc_f64_s (f64.const -123.9))
+      (i32.trunc_sat_f32_u (f32.const 5e9)))))
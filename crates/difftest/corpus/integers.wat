@@ -0,0 +1,22 @@
+;; Integer edge cases: shifts by large amounts, bit counting and extensions.
+(module
+  (memory (export "memory") 1)
+  (func (export "main") (result i64)
+    (i32.store (i32.const 0) (i32.shl (i32.const 1) (i32.const 33)))
+    (i32.store (i32.const 4) (i32.shr_s (i32.const -8) (i32.const 65)))
+    (i32.store (i32.const 8) (i32.rotr (i32.const 0x12345678) (i32.const 36)))
+    (i32.store (i32.const 12) (i32.clz (i32.const 0)))
+    (i32.store (i32.const 16) (i32.ctz (i32.const 0x80000000)))
+    (i32.store (i32.const 20) (i32.rem_s (i32.const 0x80000000) (i32.const -1)))
+    (i32.store (i32.const 24) (i32.div_u (i32.const -1) (i32.const 7)))
+    (i32.store (i32.const 28) (i32.extend16_s (i32.const 0x8000)))
+    (i64.store (i32.const 32) (i64.rotl (i64.const 0x8000000000000001) (i64.const 127)))
+    (i64.store (i32.const 40) (i64.popcnt (i64.const -1)))
+    (i64.store (i32.const 48) (i64.extend_i32_s (i32.const -2)))
+    (i64.store (i32.const 56) (i64.extend_i32_u (i32.const -2)))
+    (i64.store (i32.const 64) (i64.mul (i64.const 0x7fffffffffffffff) (i64.const 3)))
+    (i64.store (i32.const 72) (i64.rem_u (i64.const -1) (i64.const 10)))
+    (i64.store (i32.const 80) (i64.extend8_s (i64.const 0xff)))
+    (i64.add
+      (i64.load32_s (i32.const 24))
+      (i64.load16_u (i32.const 30)))))
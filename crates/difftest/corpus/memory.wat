@@ -0,0 +1,11 @@
+;; Unaligned and narrow accesses, and data segments.
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 16) "coral\00\01\02\03\04\05\06\07\08")
+  (func (export "main") (result i32)
+    (i64.store offset=3 (i32.const 64) (i64.load offset=1 (i32.const 16)))
+    (i32.store16 (i32.const 101) (i32.load8_u (i32.const 17)))
+    (i64.store8 (i32.const 0xfff9) (i64.const 0x1234))
+    (i32.store (i32.const 0xfffc) (i32.load8_s (i32.const 0xfff9)))
+    (i32.store (i32.const 128) (i32.load (i32.const 21)))
+    (i32.load16_s (i32.const 65))))
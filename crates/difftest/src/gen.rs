@@ -0,0 +1,333 @@
+//! Random Modules
+//!
+//! Generates random modules exercising integer arithmetic, conversions, control flow and memory
+//! accesses. The generated code never traps: divisors are forced to be odd, signed divisions are
+//! not emitted (they overflow on `MIN / -1`), and addresses are masked to stay within the first
+//! page of memory.
+//!
+//! The generator is deterministic, a failing module can be reproduced from its seed.
+
+use std::fmt::Write;
+
+/// The size of the memory accessed by generated modules, in bytes.
+const MEMORY_SIZE: u32 = 0x1_0000;
+
+/// Maximum depth of generated expressions.
+const MAX_DEPTH: u32 = 4;
+
+const I32_BINOPS: &[&str] = &[
+    "i32.add",
+    "i32.sub",
+    "i32.mul",
+    "i32.and",
+    "i32.or",
+    "i32.xor",
+    "i32.shl",
+    "i32.shr_s",
+    "i32.shr_u",
+    "i32.rotl",
+    "i32.rotr",
+];
+const I64_BINOPS: &[&str] = &[
+    "i64.add",
+    "i64.sub",
+    "i64.mul",
+    "i64.and",
+    "i64.or",
+    "i64.xor",
+    "i64.shl",
+    "i64.shr_s",
+    "i64.shr_u",
+    "i64.rotl",
+    "i64.rotr",
+];
+/// Division and remainder, whose divisor must be non-zero.
+const I32_DIVOPS: &[&str] = &["i32.div_u", "i32.rem_u", "i32.rem_s"];
+const I64_DIVOPS: &[&str] = &["i64.div_u", "i64.rem_u", "i64.rem_s"];
+const I32_UNOPS: &[&str] = &[
+    "i32.clz",
+    "i32.ctz",
+    "i32.popcnt",
+    "i32.eqz",
+    "i32.extend8_s",
+];
+const I64_UNOPS: &[&str] = &["i64.clz", "i64.ctz", "i64.popcnt", "i64.extend32_s"];
+const I32_CMPOPS: &[&str] = &[
+    "i32.eq", "i32.ne", "i32.lt_s", "i32.lt_u", "i32.gt_s", "i32.gt_u", "i32.le_s", "i32.ge_u",
+];
+const I64_CMPOPS: &[&str] = &[
+    "i64.eq", "i64.ne", "i64.lt_s", "i64.lt_u", "i64.gt_s", "i64.gt_u", "i64.le_u", "i64.ge_s",
+];
+const I32_LOADS: &[&str] = &["i32.load", "i32.load8_s", "i32.load8_u", "i32.load16_s"];
+const I64_LOADS: &[&str] = &["i64.load", "i64.load8_u", "i64.load16_s", "i64.load32_u"];
+const I32_STORES: &[&str] = &["i32.store", "i32.store8", "i32.store16"];
+const I64_STORES: &[&str] = &["i64.store", "i64.store8", "i64.store32"];
+
+/// A xorshift pseudo-random number generator.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // The state must not be zero
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in `0..bound`.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len() as u64) as usize]
+    }
+
+    /// Returns an interesting constant: either small, close to a boundary or fully random.
+    fn constant(&mut self) -> u64 {
+        match self.below(4) {
+            0 => self.below(16),
+            1 => [
+                0,
+                u64::MAX,
+                i64::MIN as u64,
+                i64::MAX as u64,
+                0xffff_ffff,
+                0x8000_0000,
+            ][self.below(6) as usize],
+            _ => self.next_u64(),
+        }
+    }
+}
+
+/// The type of a generated expression.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Ty {
+    I32,
+    I64,
+}
+
+/// Generates the text of a random module, exporting a `main` function and its memory.
+pub fn module(seed: u64) -> String {
+    let mut gen = Generator {
+        rng: Rng::new(seed),
+        out: String::new(),
+    };
+    gen.out.push_str("(module\n");
+    writeln!(
+        gen.out,
+        "  (memory (export \"memory\") {})",
+        MEMORY_SIZE / 0x1_0000
+    )
+    .unwrap();
+    gen.out
+        .push_str("  (func (export \"main\") (result i64)\n    (local i32 i32 i64 i64)\n");
+    let nb_statements = 1 + gen.rng.below(12);
+    for _ in 0..nb_statements {
+        gen.out.push_str("    ");
+        gen.statement(0);
+        gen.out.push('\n');
+    }
+    gen.out.push_str("    ");
+    gen.expr(Ty::I64, 0);
+    gen.out.push_str("))\n");
+    gen.out
+}
+
+struct Generator {
+    rng: Rng,
+    out: String,
+}
+
+impl Generator {
+    fn statement(&mut self, depth: u32) {
+        // Loops are only emitted at the top level, so that they never reset the counter of an
+        // enclosing loop.
+        let nb_choices = match depth {
+            0 => 5,
+            1 => 4,
+            _ => 3,
+        };
+        match self.rng.below(nb_choices) {
+            0 => {
+                let (local, ty) = self.local();
+                write!(self.out, "(local.set {} ", local).unwrap();
+                self.expr(ty, 0);
+                self.out.push(')');
+            }
+            1 => {
+                let ty = self.ty();
+                let op = match ty {
+                    Ty::I32 => self.rng.pick(I32_STORES),
+                    Ty::I64 => self.rng.pick(I64_STORES),
+                };
+                write!(self.out, "({} ", op).unwrap();
+                self.address();
+                self.out.push(' ');
+                self.expr(ty, 0);
+                self.out.push(')');
+            }
+            2 => {
+                self.out.push_str("(drop ");
+                let ty = self.ty();
+                self.expr(ty, 0);
+                self.out.push(')');
+            }
+            3 => {
+                self.out.push_str("(if ");
+                self.expr(Ty::I32, MAX_DEPTH - 1);
+                self.out.push_str(" (then ");
+                self.statement(depth + 1);
+                self.out.push_str(") (else ");
+                self.statement(depth + 1);
+                self.out.push_str("))");
+            }
+            _ => {
+                // A loop running a bounded number of iterations, counted by local 0
+                let iterations = 1 + self.rng.below(8);
+                write!(self.out, "(local.set 0 (i32.const 0)) (loop $l{} ", depth).unwrap();
+                self.statement(depth + 1);
+                let counter = "(local.tee 0 (i32.add (local.get 0) (i32.const 1)))";
+                write!(
+                    self.out,
+                    " (br_if $l{} (i32.lt_u {} (i32.const {}))))",
+                    depth, counter, iterations
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    fn expr(&mut self, ty: Ty, depth: u32) {
+        let choice = if depth >= MAX_DEPTH {
+            self.rng.below(2)
+        } else {
+            self.rng.below(10)
+        };
+        match (choice, ty) {
+            (0, Ty::I32) => write!(self.out, "(i32.const {})", self.rng.constant() as i32).unwrap(),
+            (0, Ty::I64) => write!(self.out, "(i64.const {})", self.rng.constant() as i64).unwrap(),
+            (1, _) => {
+                let local = self.local_of(ty);
+                write!(self.out, "(local.get {})", local).unwrap();
+            }
+            (2, _) | (3, _) => {
+                let op = match ty {
+                    Ty::I32 => self.rng.pick(I32_BINOPS),
+                    Ty::I64 => self.rng.pick(I64_BINOPS),
+                };
+                self.binop(op, ty, depth);
+            }
+            (4, _) => {
+                let op = match ty {
+                    Ty::I32 => self.rng.pick(I32_DIVOPS),
+                    Ty::I64 => self.rng.pick(I64_DIVOPS),
+                };
+                write!(self.out, "({} ", op).unwrap();
+                self.expr(ty, depth + 1);
+                // An odd divisor is never zero
+                match ty {
+                    Ty::I32 => self.out.push_str(" (i32.or (i32.const 1) "),
+                    Ty::I64 => self.out.push_str(" (i64.or (i64.const 1) "),
+                }
+                self.expr(ty, depth + 1);
+                self.out.push_str("))");
+            }
+            (5, _) => {
+                let op = match ty {
+                    Ty::I32 => self.rng.pick(I32_UNOPS),
+                    Ty::I64 => self.rng.pick(I64_UNOPS),
+                };
+                write!(self.out, "({} ", op).unwrap();
+                self.expr(ty, depth + 1);
+                self.out.push(')');
+            }
+            (6, Ty::I32) => {
+                let operand_ty = self.ty();
+                let op = match operand_ty {
+                    Ty::I32 => self.rng.pick(I32_CMPOPS),
+                    Ty::I64 => self.rng.pick(I64_CMPOPS),
+                };
+                self.binop(op, operand_ty, depth);
+            }
+            (6, Ty::I64) => {
+                let op = if self.rng.below(2) == 0 {
+                    "i64.extend_i32_s"
+                } else {
+                    "i64.extend_i32_u"
+                };
+                write!(self.out, "({} ", op).unwrap();
+                self.expr(Ty::I32, depth + 1);
+                self.out.push(')');
+            }
+            (7, Ty::I32) => {
+                self.out.push_str("(i32.wrap_i64 ");
+                self.expr(Ty::I64, depth + 1);
+                self.out.push(')');
+            }
+            (7, Ty::I64) | (8, _) => {
+                let op = match ty {
+                    Ty::I32 => self.rng.pick(I32_LOADS),
+                    Ty::I64 => self.rng.pick(I64_LOADS),
+                };
+                write!(self.out, "({} ", op).unwrap();
+                self.address();
+                self.out.push(')');
+            }
+            _ => {
+                self.out.push_str("(select ");
+                self.expr(ty, depth + 1);
+                self.out.push(' ');
+                self.expr(ty, depth + 1);
+                self.out.push(' ');
+                self.expr(Ty::I32, depth + 1);
+                self.out.push(')');
+            }
+        }
+    }
+
+    fn binop(&mut self, op: &str, operand_ty: Ty, depth: u32) {
+        write!(self.out, "({} ", op).unwrap();
+        self.expr(operand_ty, depth + 1);
+        self.out.push(' ');
+        self.expr(operand_ty, depth + 1);
+        self.out.push(')');
+    }
+
+    /// Emits an address within the memory, leaving room for 8 bytes accesses.
+    fn address(&mut self) {
+        self.out.push_str("(i32.and ");
+        self.expr(Ty::I32, MAX_DEPTH - 1);
+        write!(self.out, " (i32.const {}))", MEMORY_SIZE - 8).unwrap();
+    }
+
+    fn ty(&mut self) -> Ty {
+        if self.rng.below(2) == 0 {
+            Ty::I32
+        } else {
+            Ty::I64
+        }
+    }
+
+    /// Returns a random local that can be written, with its type.
+    ///
+    /// Local 0 is reserved as a loop counter.
+    fn local(&mut self) -> (u32, Ty) {
+        match self.rng.below(3) {
+            0 => (1, Ty::I32),
+            n => (1 + n as u32, Ty::I64),
+        }
+    }
+
+    fn local_of(&mut self, ty: Ty) -> u32 {
+        match ty {
+            Ty::I32 => self.rng.below(2) as u32,
+            Ty::I64 => 2 + self.rng.below(2) as u32,
+        }
+    }
+}
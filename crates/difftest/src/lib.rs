@@ -0,0 +1,225 @@
+//! Differential Testing
+//!
+//! Runs WebAssembly modules both through the Coral compiler and userspace runtime and through
+//! wasmtime, then compares the outcomes. This is a hosted crate, used to build confidence in the
+//! generated code rather than to run within the kernel.
+//!
+//! The modules under test must export a `main` function taking no arguments and returning at most
+//! one integer, and may export their memory as `memory`. Both engines must agree on the returned
+//! value and on the final content of the memory. Traps are reported as errors rather than compared
+//! across engines, so modules should not trap.
+
+use std::time::{Duration, Instant};
+
+use compiler::userspace_alloc::{self, Runtime};
+use compiler::{Compiler, CompilerOptions, X86_64Compiler};
+use wasm::{Instance, ItemRef, Module, ValueType, WasmModule};
+
+pub mod gen;
+
+/// The inlining threshold of the engines with inlining enabled.
+pub const INLINE_THRESHOLD: u32 = 16;
+
+/// The observable outcome of the execution of a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The value returned by `main`, if any, zero-extended to 64 bits.
+    pub result: Option<u64>,
+    /// The content of the exported memory, if any.
+    pub memory: Option<Vec<u8>>,
+}
+
+/// An engine able to execute a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// The Coral compiler, with or without optimizations and inlining.
+    Coral { optimize: bool, inline: bool },
+    /// The reference engine.
+    Wasmtime,
+}
+
+/// The engines the outcomes are compared across, the reference comes first.
+pub const ENGINES: [Engine; 4] = [
+    Engine::Wasmtime,
+    Engine::Coral {
+        optimize: false,
+        inline: false,
+    },
+    Engine::Coral {
+        optimize: true,
+        inline: false,
+    },
+    Engine::Coral {
+        optimize: true,
+        inline: true,
+    },
+];
+
+impl Engine {
+    /// Executes the `main` function of a module.
+    pub fn run(self, bytecode: &[u8]) -> Result<Outcome, String> {
+        match self {
+            Engine::Coral { optimize, inline } => {
+                run_coral(bytecode, coral_options(optimize, inline))
+            }
+            Engine::Wasmtime => run_wasmtime(bytecode),
+        }
+    }
+}
+
+/// Executes a module on all the engines, and returns an error describing the first mismatch with
+/// the reference engine, if any.
+pub fn check(bytecode: &[u8]) -> Result<Outcome, String> {
+    let reference = ENGINES[0].run(bytecode)?;
+    for engine in &ENGINES[1..] {
+        let outcome = engine.run(bytecode)?;
+        if outcome.result != reference.result {
+            return Err(format!(
+                "{:?} returned {:x?}, expected {:x?}",
+                engine, outcome.result, reference.result
+            ));
+        }
+        if let Some(offset) = memory_mismatch(&outcome.memory, &reference.memory) {
+            return Err(format!(
+                "{:?} memory differs from offset 0x{:x}",
+                engine, offset
+            ));
+        }
+    }
+    Ok(reference)
+}
+
+/// Returns the offset of the first difference between two memories, if any.
+fn memory_mismatch(memory: &Option<Vec<u8>>, reference: &Option<Vec<u8>>) -> Option<usize> {
+    match (memory, reference) {
+        (None, None) => None,
+        (Some(memory), Some(reference)) => {
+            if memory.len() != reference.len() {
+                return Some(memory.len().min(reference.len()));
+            }
+            memory.iter().zip(reference).position(|(a, b)| a != b)
+        }
+        _ => Some(0),
+    }
+}
+
+// ————————————————————————————————— Coral —————————————————————————————————— //
+
+/// The size and speed of the code generated by Coral for a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measurement {
+    /// The size of the code of the module, in bytes.
+    pub code_size: usize,
+    /// The average run time of `main`.
+    pub run_time: Duration,
+}
+
+/// Compiles a module with Coral, then measures the size of its code and the run time of its `main`
+/// function, averaged over `runs` calls on the same instance.
+pub fn measure(
+    bytecode: &[u8],
+    optimize: bool,
+    inline: bool,
+    runs: u32,
+) -> Result<Measurement, String> {
+    let module = compile_coral(bytecode, coral_options(optimize, inline))?;
+    let runtime = Runtime::new();
+    let instance = Instance::instantiate(&module, &[], &runtime)
+        .map_err(|err| format!("Coral failed to instantiate: {:?}", err))?;
+    let main = instance
+        .get_func_index_by_name("main")
+        .ok_or("Missing 'main' export")?;
+
+    let start = Instant::now();
+    for _ in 0..runs {
+        userspace_alloc::call(&instance, main)
+            .map_err(|trap| format!("Coral trapped: {:?}", trap))?;
+    }
+    Ok(Measurement {
+        code_size: module.code().len(),
+        run_time: start.elapsed() / runs.max(1),
+    })
+}
+
+fn coral_options(optimize: bool, inline: bool) -> CompilerOptions {
+    CompilerOptions {
+        optimize,
+        inline_threshold: if inline { INLINE_THRESHOLD } else { 0 },
+        ..Default::default()
+    }
+}
+
+fn compile_coral(bytecode: &[u8], options: CompilerOptions) -> Result<WasmModule, String> {
+    let mut comp = X86_64Compiler::with_options(options);
+    comp.parse(bytecode)
+        .map_err(|err| format!("Coral failed to parse: {:?}", err))?;
+    comp.compile()
+        .map_err(|err| format!("Coral failed to compile: {:?}", err))
+}
+
+fn run_coral(bytecode: &[u8], options: CompilerOptions) -> Result<Outcome, String> {
+    let module = compile_coral(bytecode, options)?;
+    let runtime = Runtime::new();
+    let instance = Instance::instantiate(&module, &[], &runtime)
+        .map_err(|err| format!("Coral failed to instantiate: {:?}", err))?;
+    let main = instance
+        .get_func_index_by_name("main")
+        .ok_or("Missing 'main' export")?;
+    let ret = instance.get_func_type_by_index(main).ret().to_vec();
+
+    let rax = userspace_alloc::call(&instance, main)
+        .map_err(|trap| format!("Coral trapped: {:?}", trap))?;
+    let result = match ret.as_slice() {
+        [] => None,
+        [ValueType::I32] => Some(rax & 0xffff_ffff),
+        [ValueType::I64] => Some(rax),
+        _ => return Err(format!("Unsupported result type: {:?}", ret)),
+    };
+
+    let memory = coral_memory(&module).and_then(|heap| {
+        let (ptr, size) = instance.get_heap_ptr_and_size(heap)?;
+        // SAFETY: the instance is alive and no longer executing.
+        Some(unsafe { core::slice::from_raw_parts(ptr, size) }.to_vec())
+    });
+    Ok(Outcome { result, memory })
+}
+
+/// Returns the index of the exported memory, if any.
+fn coral_memory(module: &WasmModule) -> Option<wasm::HeapIndex> {
+    module
+        .public_items()
+        .iter()
+        .find_map(|(name, item)| match item {
+            ItemRef::Heap(idx) if name == "memory" => Some(*idx),
+            _ => None,
+        })
+}
+
+// ———————————————————————————————— Wasmtime ———————————————————————————————— //
+
+fn run_wasmtime(bytecode: &[u8]) -> Result<Outcome, String> {
+    let engine = wasmtime::Engine::default();
+    let module = wasmtime::Module::new(&engine, bytecode)
+        .map_err(|err| format!("Wasmtime failed to compile: {}", err))?;
+    let mut store = wasmtime::Store::new(&engine, ());
+    let instance = wasmtime::Instance::new(&mut store, &module, &[])
+        .map_err(|err| format!("Wasmtime failed to instantiate: {}", err))?;
+    let main = instance
+        .get_func(&mut store, "main")
+        .ok_or("Missing 'main' export")?;
+
+    let mut results = vec![wasmtime::Val::I32(0); main.ty(&store).results().len()];
+    main.call(&mut store, &[], &mut results)
+        .map_err(|err| format!("Wasmtime trapped: {}", err))?;
+    let result = match results.as_slice() {
+        [] => None,
+        [wasmtime::Val::I32(val)] => Some(*val as u32 as u64),
+        [wasmtime::Val::I64(val)] => Some(*val as u64),
+        _ => return Err(format!("Unsupported results: {:?}", results)),
+    };
+
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .map(|memory| memory.data(&store).to_vec());
+    Ok(Outcome { result, memory })
+}
@@ -0,0 +1,44 @@
+//! Differential tests, comparing the Coral compiler with wasmtime.
+
+use std::fs;
+use std::path::Path;
+
+use coral_difftest::{check, gen};
+
+/// The number of random modules to check, can be overridden with `DIFFTEST_ITERATIONS`.
+const DEFAULT_ITERATIONS: u64 = 200;
+
+#[test]
+fn corpus() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+    let mut paths = fs::read_dir(corpus)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("wat".as_ref()))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let bytecode = wat::parse_file(&path).unwrap();
+        if let Err(err) = check(&bytecode) {
+            panic!("{}: {}", path.display(), err);
+        }
+    }
+}
+
+#[test]
+fn random_modules() {
+    let iterations = std::env::var("DIFFTEST_ITERATIONS")
+        .ok()
+        .and_then(|iterations| iterations.parse().ok())
+        .unwrap_or(DEFAULT_ITERATIONS);
+
+    for seed in 0..iterations {
+        let wat = gen::module(seed);
+        let bytecode = wat::parse_str(&wat).unwrap();
+        if let Err(err) = check(&bytecode) {
+            panic!("Seed {}: {}\n{}", seed, err, wat);
+        }
+    }
+}
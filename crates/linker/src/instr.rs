@@ -6,8 +6,9 @@
 use walrus::ir;
 use walrus::ir::{Instr, InstrSeqId, InstrSeqType};
 use walrus::{
-    FunctionBuilder, FunctionId, FunctionKind, GlobalId, InstrSeqBuilder, LocalFunction, MemoryId,
-    Module, TableId,
+    ActiveDataLocation, DataKind, ElementKind, ExportItem, FunctionBuilder, FunctionId,
+    FunctionKind, GlobalId, GlobalKind, InitExpr, InstrSeqBuilder, LocalFunction, LocalId,
+    MemoryId, Module, TableId,
 };
 
 use std::collections::{HashMap, HashSet};
@@ -70,7 +71,9 @@ impl<'a> FunctionClonner<'a> {
             args.push(new_local_id);
         }
 
-        // Clone function
+        // Clone function, branches to the entry block target the body of the new function
+        self.seq_map
+            .insert(body_instr_seq.id(), builder.func_body_id());
         self.clone_instr_seq(body_instr_seq.id(), &mut builder.func_body());
         builder.finish(args, &mut self.base.funcs)
     }
@@ -99,13 +102,13 @@ impl<'a> FunctionClonner<'a> {
                 table: self.linker.new_table_id(call.table),
             }),
             Instr::LocalGet(get) => Instr::LocalGet(ir::LocalGet {
-                local: self.linker.new_local_id(get.local),
+                local: self.local_id(get.local),
             }),
             Instr::LocalSet(set) => Instr::LocalSet(ir::LocalSet {
-                local: self.linker.new_local_id(set.local),
+                local: self.local_id(set.local),
             }),
             Instr::LocalTee(tee) => Instr::LocalTee(ir::LocalTee {
-                local: self.linker.new_local_id(tee.local),
+                local: self.local_id(tee.local),
             }),
             Instr::GlobalGet(get) => Instr::GlobalGet(ir::GlobalGet {
                 global: self.linker.new_global_id(get.global),
@@ -227,6 +230,19 @@ impl<'a> FunctionClonner<'a> {
         }
     }
 
+    /// Returns the local of the base corresponding to a local of the linkee function.
+    ///
+    /// The arguments are remapped when the function is created, the other locals are added to the
+    /// base when they are first used.
+    fn local_id(&mut self, id: LocalId) -> LocalId {
+        if let Some(new_id) = self.linker.get_local_id(id) {
+            return new_id;
+        }
+        let new_id = self.base.locals.add(self.linkee.locals.get(id).ty());
+        self.linker.remap_local(id, new_id);
+        new_id
+    }
+
     fn get_or_build_instr_seq(
         &mut self,
         seq_id: InstrSeqId,
@@ -264,6 +280,9 @@ impl<'a> FunctionClonner<'a> {
 
 /// A patch can be applied to replace some IDs by other IDs. For instance, an imported function can
 /// be replaced by a concrete function that was added to the module during linking.
+///
+/// Replacements are followed transitively: an import can be replaced by an import of a linkee,
+/// which is replaced in turn once the module providing it is linked.
 #[derive(Default)]
 pub struct Patch {
     funcs: HashMap<FunctionId, FunctionId>,
@@ -277,32 +296,32 @@ impl Patch {
         Self::default()
     }
 
-    fn patched_func_id(&self, id: FunctionId) -> FunctionId {
-        match self.funcs.get(&id) {
-            Some(id) => *id,
-            None => id,
+    fn patched_func_id(&self, mut id: FunctionId) -> FunctionId {
+        while let Some(new_id) = self.funcs.get(&id) {
+            id = *new_id;
         }
+        id
     }
 
-    fn patched_glob_id(&self, id: GlobalId) -> GlobalId {
-        match self.globs.get(&id) {
-            Some(id) => *id,
-            None => id,
+    fn patched_glob_id(&self, mut id: GlobalId) -> GlobalId {
+        while let Some(new_id) = self.globs.get(&id) {
+            id = *new_id;
         }
+        id
     }
 
-    fn patched_table_id(&self, id: TableId) -> TableId {
-        match self.tables.get(&id) {
-            Some(id) => *id,
-            None => id,
+    fn patched_table_id(&self, mut id: TableId) -> TableId {
+        while let Some(new_id) = self.tables.get(&id) {
+            id = *new_id;
         }
+        id
     }
 
-    fn patched_memory_id(&self, id: MemoryId) -> MemoryId {
-        match self.memories.get(&id) {
-            Some(id) => *id,
-            None => id,
+    fn patched_memory_id(&self, mut id: MemoryId) -> MemoryId {
+        while let Some(new_id) = self.memories.get(&id) {
+            id = *new_id;
         }
+        id
     }
 
     pub fn remap_func(&mut self, old: FunctionId, new: FunctionId) {
@@ -323,6 +342,60 @@ impl Patch {
 
     pub fn patch(&self, module: &mut Module) {
         self.patch_funcs(module);
+        self.patch_segments(module);
+        self.patch_globals(module);
+        self.patch_exports(module);
+        module.start = module.start.map(|func| self.patched_func_id(func));
+    }
+
+    fn patch_init_expr(&self, init: &mut InitExpr) {
+        match init {
+            InitExpr::Global(glob) => *glob = self.patched_glob_id(*glob),
+            InitExpr::RefFunc(func) => *func = self.patched_func_id(*func),
+            InitExpr::Value(_) | InitExpr::RefNull(_) => {}
+        }
+    }
+
+    /// Patches the element and data segments, which might refer to imported items.
+    fn patch_segments(&self, module: &mut Module) {
+        for elem in module.elements.iter_mut() {
+            if let ElementKind::Active { table, offset } = &mut elem.kind {
+                *table = self.patched_table_id(*table);
+                self.patch_init_expr(offset);
+            }
+            for func in elem.members.iter_mut().flatten() {
+                *func = self.patched_func_id(*func);
+            }
+        }
+        let data_ids: Vec<_> = module.data.iter().map(|data| data.id()).collect();
+        for data_id in data_ids {
+            if let DataKind::Active(active) = &mut module.data.get_mut(data_id).kind {
+                active.memory = self.patched_memory_id(active.memory);
+                if let ActiveDataLocation::Relative(glob) = &mut active.location {
+                    *glob = self.patched_glob_id(*glob);
+                }
+            }
+        }
+    }
+
+    fn patch_globals(&self, module: &mut Module) {
+        let glob_ids: Vec<_> = module.globals.iter().map(|global| global.id()).collect();
+        for glob_id in glob_ids {
+            if let GlobalKind::Local(init) = &mut module.globals.get_mut(glob_id).kind {
+                self.patch_init_expr(init);
+            }
+        }
+    }
+
+    fn patch_exports(&self, module: &mut Module) {
+        for export in module.exports.iter_mut() {
+            export.item = match export.item {
+                ExportItem::Function(func) => ExportItem::Function(self.patched_func_id(func)),
+                ExportItem::Table(table) => ExportItem::Table(self.patched_table_id(table)),
+                ExportItem::Memory(mem) => ExportItem::Memory(self.patched_memory_id(mem)),
+                ExportItem::Global(glob) => ExportItem::Global(self.patched_glob_id(glob)),
+            };
+        }
     }
 
     fn patch_funcs(&self, module: &mut Module) {
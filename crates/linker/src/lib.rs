@@ -1,17 +1,185 @@
 mod instr;
 
-use std::collections::{HashMap, HashSet};
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use walrus::{
-    DataId, ElementId, ExportItem, FunctionId, FunctionKind, GlobalId, GlobalKind, ImportId,
-    ImportKind, LocalId, MemoryId, Module, TableId, TypeId,
+    DataId, ElementId, ExportItem, FunctionId, FunctionKind, GlobalId, GlobalKind, Import,
+    ImportId, ImportKind, LocalId, MemoryId, Module, Table, TableId, Type, TypeId, ValType,
 };
+use wasmparser::{BinaryReaderError, Validator, WasmFeatures};
 
 /// Links a base module with another provided module.
-pub fn link(base: &mut Module, linkee: &Module, linkee_name: &str) {
-    Linker::new(linkee_name.to_string()).link(base, linkee)
+///
+/// Each import of the base from the linkee must be matched by an export of the linkee with a
+/// compatible type, otherwise linking fails and the base module is left untouched.
+pub fn link(base: &mut Module, linkee: &Module, linkee_name: &str) -> Result<(), LinkError> {
+    let mut session = Linker::session(base);
+    session.add(linkee, linkee_name)?;
+    session.finish();
+    Ok(())
+}
+
+/// Removes the items of a linked module which are not reachable from its exports and start
+/// function.
+///
+/// Linking pulls in all the functions, globals, tables and data of the linkee, even if only some of
+/// its exports are used by the base module.
+pub fn strip_unused(module: &mut Module) {
+    walrus::passes::gc::run(module);
+}
+
+/// Validates an emitted module, with the features supported by the kernel compiler.
+///
+/// Linking can still produce invalid modules, which would otherwise only be rejected once loaded by
+/// the kernel.
+pub fn validate(wasm: &[u8]) -> Result<(), BinaryReaderError> {
+    let features = WasmFeatures {
+        reference_types: true,
+        bulk_memory: true,
+        ..WasmFeatures::default()
+    };
+    Validator::new().wasm_features(features).validate_all(wasm)
+}
+
+/// An import of the base module which can not be resolved from the linkee.
+#[derive(Debug)]
+pub enum LinkError {
+    /// The linkee has no export with the imported name.
+    MissingExport { module: String, name: String },
+    /// The export is not of the imported kind, e.g. a global imported as a function.
+    KindMismatch {
+        module: String,
+        name: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// The export does not match the type or limits of the import.
+    TypeMismatch {
+        module: String,
+        name: String,
+        expected: String,
+        found: String,
+    },
 }
 
-pub(crate) struct Linker {
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::MissingExport { module, name } => {
+                write!(f, "missing export: {}.{}", module, name)
+            }
+            LinkError::KindMismatch {
+                module,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "invalid export type: {}.{}, expected a {} but found a {}",
+                module, name, expected, found
+            ),
+            LinkError::TypeMismatch {
+                module,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "incompatible export: {}.{}, imported as {} but exported as {}",
+                module, name, expected, found
+            ),
+        }
+    }
+}
+
+impl Error for LinkError {}
+
+// ———————————————————————————————— Sessions ———————————————————————————————— //
+
+/// A linking session, linking several modules into the same base.
+///
+/// The imports of the base are indexed once when the session starts, and the uses of the resolved
+/// imports are patched once all the linkees have been added, instead of walking the whole base for
+/// each linkee.
+pub struct LinkSession<'a> {
+    base: &'a mut Module,
+    /// The unresolved imports of the base, by module name.
+    imports: HashMap<String, Vec<ImportId>>,
+    /// The resolved imports, removed from the base when the session finishes.
+    resolved: Vec<ImportId>,
+    /// Redirects the uses of the resolved imports to the linked items.
+    patch: instr::Patch,
+}
+
+impl<'a> LinkSession<'a> {
+    fn new(base: &'a mut Module) -> Self {
+        let mut imports: HashMap<String, Vec<ImportId>> = HashMap::new();
+        for import in base.imports.iter() {
+            imports
+                .entry(import.module.clone())
+                .or_default()
+                .push(import.id());
+        }
+        Self {
+            base,
+            imports,
+            resolved: Vec::new(),
+            patch: instr::Patch::new(),
+        }
+    }
+
+    /// Links a module into the base.
+    ///
+    /// Each import of the base from the linkee must be matched by an export of the linkee with a
+    /// compatible type, otherwise linking fails and the base module is left untouched. The imports
+    /// of the linkee are added to the base, and can be resolved by the linkees added afterward.
+    pub fn add(&mut self, linkee: &Module, linkee_name: &str) -> Result<(), LinkError> {
+        let mut linker = Linker::new(linkee_name.to_string());
+        let imports = match self.imports.get(linkee_name) {
+            Some(imports) => imports.as_slice(),
+            None => &[],
+        };
+        let resolved = linker.resolve_imports(self.base, linkee, imports)?;
+        linker.merge(self.base, linkee);
+        linker.remap_resolved_imports(self.base, &resolved, &mut self.patch);
+
+        self.imports.remove(linkee_name);
+        for import_id in linker.added_imports {
+            let module = self.base.imports.get(import_id).module.clone();
+            self.imports.entry(module).or_default().push(import_id);
+        }
+        self.resolved
+            .extend(resolved.into_iter().map(|(import_id, _)| import_id));
+        Ok(())
+    }
+
+    /// Redirects the uses of the resolved imports to the linked items, then removes the imports
+    /// and the imported items.
+    ///
+    /// Until then the base still uses the imports, and the linked items are unused.
+    pub fn finish(self) {
+        self.patch.patch(self.base);
+        for import_id in self.resolved {
+            match self.base.imports.get(import_id).kind {
+                ImportKind::Function(func_id) => self.base.funcs.delete(func_id),
+                ImportKind::Table(table_id) => self.base.tables.delete(table_id),
+                ImportKind::Memory(mem_id) => self.base.memories.delete(mem_id),
+                ImportKind::Global(glob_id) => self.base.globals.delete(glob_id),
+            }
+            self.base.imports.delete(import_id);
+        }
+    }
+}
+
+// ————————————————————————————————— Linker ————————————————————————————————— //
+
+/// Links a module into a base, the mapping from the items of the linkee to their copy in the base
+/// is only valid while that module is being linked.
+pub struct Linker {
     globals_map: HashMap<GlobalId, GlobalId>,
     tables_map: HashMap<TableId, TableId>,
     funcs_map: HashMap<FunctionId, FunctionId>,
@@ -20,7 +188,8 @@ pub(crate) struct Linker {
     memories_map: HashMap<MemoryId, MemoryId>,
     data_map: HashMap<DataId, DataId>,
     elements_map: HashMap<ElementId, ElementId>,
-    linkee_imports: HashSet<ImportId>,
+    /// The imports of the linkee, added to the base.
+    added_imports: Vec<ImportId>,
     linkee_name: String,
 }
 
@@ -35,11 +204,16 @@ impl Linker {
             memories_map: HashMap::new(),
             data_map: HashMap::new(),
             elements_map: HashMap::new(),
-            linkee_imports: HashSet::new(),
+            added_imports: Vec::new(),
             linkee_name,
         }
     }
 
+    /// Starts a session linking modules into the base, see `LinkSession`.
+    pub fn session(base: &mut Module) -> LinkSession<'_> {
+        LinkSession::new(base)
+    }
+
     pub(crate) fn new_func_id(&self, id: FunctionId) -> FunctionId {
         self.funcs_map[&id]
     }
@@ -56,8 +230,8 @@ impl Linker {
         self.types_map[&id]
     }
 
-    pub(crate) fn new_local_id(&self, id: LocalId) -> LocalId {
-        self.locals_map[&id]
+    pub(crate) fn get_local_id(&self, id: LocalId) -> Option<LocalId> {
+        self.locals_map.get(&id).copied()
     }
 
     pub(crate) fn new_mem_id(&self, id: MemoryId) -> MemoryId {
@@ -76,13 +250,60 @@ impl Linker {
         self.locals_map.insert(old, new);
     }
 
-    fn link(mut self, base: &mut Module, linkee: &Module) {
+    /// Copies all the items of the linkee into the base.
+    ///
+    /// Imports must be resolved before merging anything, so that the base is untouched on error.
+    fn merge(&mut self, base: &mut Module, linkee: &Module) {
+        self.merge_types(base, linkee);
         self.merge_tables(base, linkee);
+        self.merge_memories(base, linkee);
         self.merge_globals(base, linkee);
         self.merge_data(base, linkee);
         self.merge_elements(base, linkee);
         self.merge_funcs(base, linkee);
-        self.remove_resolved_imports(base, linkee);
+    }
+
+    /// Resolves the imports of the base from the linkee to the corresponding exports, after
+    /// checking that their types are compatible.
+    fn resolve_imports(
+        &self,
+        base: &Module,
+        linkee: &Module,
+        imports: &[ImportId],
+    ) -> Result<Vec<(ImportId, ExportItem)>, LinkError> {
+        let mut resolved = Vec::new();
+        for import_id in imports {
+            let import = base.imports.get(*import_id);
+            debug_assert_eq!(import.module, self.linkee_name);
+            let export = linkee
+                .exports
+                .iter()
+                .find(|export| export.name == import.name)
+                .ok_or_else(|| LinkError::MissingExport {
+                    module: import.module.clone(),
+                    name: import.name.clone(),
+                })?;
+            check_import(base, import, linkee, export.item)?;
+            resolved.push((import.id(), export.item));
+        }
+        Ok(resolved)
+    }
+
+    /// Maps each type of the linkee to an identical type of the base, which is added only if the
+    /// base has none, so that linking does not duplicate type entries.
+    fn merge_types(&mut self, base: &mut Module, linkee: &Module) {
+        for ty in linkee.types.iter() {
+            // Walrus also stores the types of function entry blocks, which `find` skips as they are
+            // not part of the type section
+            if linkee.types.find(ty.params(), ty.results()) != Some(ty.id()) {
+                continue;
+            }
+            let new_id = match base.types.find(ty.params(), ty.results()) {
+                Some(type_id) => type_id,
+                None => base.types.add(ty.params(), ty.results()),
+            };
+            self.types_map.insert(ty.id(), new_id);
+        }
     }
 
     fn merge_tables(&mut self, base: &mut Module, linkee: &Module) {
@@ -96,7 +317,7 @@ impl Linker {
                     table.maximum,
                     table.element_ty,
                 );
-                self.linkee_imports.insert(import_id);
+                self.added_imports.push(import_id);
                 table_id
             } else {
                 base.tables
@@ -106,6 +327,27 @@ impl Linker {
         }
     }
 
+    fn merge_memories(&mut self, base: &mut Module, linkee: &Module) {
+        for memory in linkee.memories.iter() {
+            let new_id = if let Some(import_id) = memory.import {
+                let import = linkee.imports.get(import_id);
+                let (mem_id, import_id) = base.add_import_memory(
+                    &import.module,
+                    &import.name,
+                    memory.shared,
+                    memory.initial,
+                    memory.maximum,
+                );
+                self.added_imports.push(import_id);
+                mem_id
+            } else {
+                base.memories
+                    .add_local(memory.shared, memory.initial, memory.maximum)
+            };
+            self.memories_map.insert(memory.id(), new_id);
+        }
+    }
+
     fn merge_globals(&mut self, base: &mut Module, linkee: &Module) {
         for global in linkee.globals.iter() {
             let new_id = match global.kind {
@@ -117,7 +359,7 @@ impl Linker {
                         global.ty,
                         global.mutable,
                     );
-                    self.linkee_imports.insert(import_id);
+                    self.added_imports.push(import_id);
                     glob_id
                 }
                 GlobalKind::Local(init_expr) => {
@@ -146,11 +388,10 @@ impl Linker {
                 FunctionKind::Import(ref func) => {
                     let import_id = func.import;
                     let import = linkee.imports.get(import_id);
-                    let ty = linkee.types.get(func.ty);
-                    let ty_id = base.types.add(ty.params(), ty.results());
+                    let ty_id = self.new_type_id(func.ty);
                     let (func_id, import_id) =
                         base.add_import_func(&import.module, &import.name, ty_id);
-                    self.linkee_imports.insert(import_id);
+                    self.added_imports.push(import_id);
                     func_id
                 }
                 FunctionKind::Local(ref func) => instr::clone_func(self, base, linkee, func),
@@ -160,81 +401,165 @@ impl Linker {
         }
     }
 
-    fn remove_resolved_imports(&mut self, base: &mut Module, linkee: &Module) {
-        let mut to_delete = Vec::new();
-        let mut patch = instr::Patch::new();
-        for import in base.imports.iter() {
-            if self.linkee_imports.contains(&import.id()) {
-                // This import comes from the linkee, so don't try to resolve it
-                continue;
-            }
-            if import.module != self.linkee_name {
-                continue;
-            }
-
-            // TODO: better error messages
-            let export = linkee
-                .exports
-                .iter()
-                .find(|export| export.name == import.name)
-                .expect(&format!(
-                    "Missing export: {}.{}",
-                    import.module, import.name
-                ));
-
-            match import.kind {
-                ImportKind::Function(func_id) => {
-                    let linkee_func_id = match export.item {
-                        ExportItem::Function(func_id) => func_id,
-                        _ => panic!(
-                            "Invalid export type: {}.{}, expected a function",
-                            import.module, import.name
-                        ),
-                    };
-                    let new_func_id = self.new_func_id(linkee_func_id);
-                    patch.remap_func(func_id, new_func_id);
+    /// Records the redirection of the resolved imports to the linked items.
+    fn remap_resolved_imports(
+        &self,
+        base: &Module,
+        resolved: &[(ImportId, ExportItem)],
+        patch: &mut instr::Patch,
+    ) {
+        for (import_id, item) in resolved {
+            match (&base.imports.get(*import_id).kind, *item) {
+                (ImportKind::Function(func_id), ExportItem::Function(linkee_func_id)) => {
+                    patch.remap_func(*func_id, self.new_func_id(linkee_func_id));
                 }
-                ImportKind::Table(table_id) => {
-                    let linkee_table_id = match export.item {
-                        ExportItem::Table(table_id) => table_id,
-                        _ => panic!(
-                            "Invalid export type: {}.{}, expected a table",
-                            import.module, import.name
-                        ),
-                    };
-                    let new_table_id = self.new_table_id(linkee_table_id);
-                    patch.remap_table(table_id, new_table_id);
+                (ImportKind::Table(table_id), ExportItem::Table(linkee_table_id)) => {
+                    patch.remap_table(*table_id, self.new_table_id(linkee_table_id));
                 }
-                ImportKind::Memory(mem_id) => {
-                    let linkee_mem_id = match export.item {
-                        ExportItem::Memory(mem_id) => mem_id,
-                        _ => panic!(
-                            "Invalid export type: {}.{}, expected a memory",
-                            import.module, import.name
-                        ),
-                    };
-                    let new_mem_id = self.new_mem_id(linkee_mem_id);
-                    patch.remap_memory(mem_id, new_mem_id);
+                (ImportKind::Memory(mem_id), ExportItem::Memory(linkee_mem_id)) => {
+                    patch.remap_memory(*mem_id, self.new_mem_id(linkee_mem_id));
                 }
-                ImportKind::Global(glob_id) => {
-                    let linkee_glob_id = match export.item {
-                        ExportItem::Global(glob_id) => glob_id,
-                        _ => panic!(
-                            "Invalid export type: {}.{}, expected a global",
-                            import.module, import.name
-                        ),
-                    };
-                    let new_glob_id = self.new_global_id(linkee_glob_id);
-                    patch.remap_glob(glob_id, new_glob_id);
+                (ImportKind::Global(glob_id), ExportItem::Global(linkee_glob_id)) => {
+                    patch.remap_glob(*glob_id, self.new_global_id(linkee_glob_id));
                 }
+                _ => unreachable!("import kinds are checked when resolving imports"),
             }
-
-            to_delete.push(import.id());
         }
+    }
+}
+
+// ——————————————————————————— Type Compatibility ——————————————————————————— //
 
-        patch.patch(base);
-        for import_id in to_delete {
-            base.imports.delete(import_id);
+/// Checks that an export of the linkee can be used in place of an import of the base.
+///
+/// Functions and globals must have the exact same type. Tables and memories must have the same
+/// element type (resp. sharing), and limits within those of the import.
+fn check_import(
+    base: &Module,
+    import: &Import,
+    linkee: &Module,
+    item: ExportItem,
+) -> Result<(), LinkError> {
+    let (expected, found) = match (&import.kind, item) {
+        (ImportKind::Function(func_id), ExportItem::Function(linkee_func_id)) => {
+            let expected = base.types.get(base.funcs.get(*func_id).ty());
+            let found = linkee.types.get(linkee.funcs.get(linkee_func_id).ty());
+            if expected.params() == found.params() && expected.results() == found.results() {
+                return Ok(());
+            }
+            (describe_func(expected), describe_func(found))
         }
+        (ImportKind::Table(table_id), ExportItem::Table(linkee_table_id)) => {
+            let expected = base.tables.get(*table_id);
+            let found = linkee.tables.get(linkee_table_id);
+            if expected.element_ty == found.element_ty
+                && limits_match(
+                    (expected.initial, expected.maximum),
+                    (found.initial, found.maximum),
+                )
+            {
+                return Ok(());
+            }
+            (describe_table(expected), describe_table(found))
+        }
+        (ImportKind::Memory(mem_id), ExportItem::Memory(linkee_mem_id)) => {
+            let expected = base.memories.get(*mem_id);
+            let found = linkee.memories.get(linkee_mem_id);
+            let expected_limits = (expected.initial, expected.maximum);
+            let found_limits = (found.initial, found.maximum);
+            if expected.shared == found.shared && limits_match(expected_limits, found_limits) {
+                return Ok(());
+            }
+            (
+                describe_memory(expected.shared, expected_limits),
+                describe_memory(found.shared, found_limits),
+            )
+        }
+        (ImportKind::Global(glob_id), ExportItem::Global(linkee_glob_id)) => {
+            let expected = base.globals.get(*glob_id);
+            let found = linkee.globals.get(linkee_glob_id);
+            if expected.ty == found.ty && expected.mutable == found.mutable {
+                return Ok(());
+            }
+            (
+                describe_global(expected.ty, expected.mutable),
+                describe_global(found.ty, found.mutable),
+            )
+        }
+        (kind, item) => {
+            return Err(LinkError::KindMismatch {
+                module: import.module.clone(),
+                name: import.name.clone(),
+                expected: import_kind_name(kind),
+                found: export_kind_name(item),
+            })
+        }
+    };
+    Err(LinkError::TypeMismatch {
+        module: import.module.clone(),
+        name: import.name.clone(),
+        expected,
+        found,
+    })
+}
+
+/// Returns true if the limits of an export are within the limits of an import.
+fn limits_match(import: (u32, Option<u32>), export: (u32, Option<u32>)) -> bool {
+    let (import_initial, import_maximum) = import;
+    let (export_initial, export_maximum) = export;
+    if export_initial < import_initial {
+        return false;
+    }
+    match (import_maximum, export_maximum) {
+        (None, _) => true,
+        (Some(import_maximum), Some(export_maximum)) => export_maximum <= import_maximum,
+        (Some(_), None) => false,
+    }
+}
+
+fn import_kind_name(kind: &ImportKind) -> &'static str {
+    match kind {
+        ImportKind::Function(_) => "function",
+        ImportKind::Table(_) => "table",
+        ImportKind::Memory(_) => "memory",
+        ImportKind::Global(_) => "global",
+    }
+}
+
+fn export_kind_name(item: ExportItem) -> &'static str {
+    match item {
+        ExportItem::Function(_) => "function",
+        ExportItem::Table(_) => "table",
+        ExportItem::Memory(_) => "memory",
+        ExportItem::Global(_) => "global",
+    }
+}
+
+fn describe_func(ty: &Type) -> String {
+    format!("func {:?} -> {:?}", ty.params(), ty.results())
+}
+
+fn describe_table(table: &Table) -> String {
+    format!(
+        "table of {:?} {}",
+        table.element_ty,
+        describe_limits(table.initial, table.maximum)
+    )
+}
+
+fn describe_memory(shared: bool, (initial, maximum): (u32, Option<u32>)) -> String {
+    let shared = if shared { "shared " } else { "" };
+    format!("{}memory {}", shared, describe_limits(initial, maximum))
+}
+
+fn describe_global(ty: ValType, mutable: bool) -> String {
+    let mutable = if mutable { "mutable " } else { "" };
+    format!("{}global {:?}", mutable, ty)
+}
+
+fn describe_limits(initial: u32, maximum: Option<u32>) -> String {
+    match maximum {
+        Some(maximum) => format!("with limits {}..{}", initial, maximum),
+        None => format!("with limits {}..", initial),
     }
 }
@@ -1,10 +1,10 @@
 // use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process;
 
-use coral_bindgen::link;
+use coral_bindgen::{strip_unused, validate, LinkSession, Linker};
 use walrus::{Module, ModuleConfig};
 
 // —————————————————————————————————— CLI ——————————————————————————————————— //
@@ -22,6 +22,27 @@ struct Args {
     /// Output path
     #[clap(long, short, value_parser)]
     output: Option<String>,
+
+    /// Output format
+    #[clap(long, value_enum, default_value = "wasm")]
+    emit: Emit,
+
+    /// Remove items unreachable from the exports and start function of the base module
+    #[clap(long)]
+    gc: bool,
+
+    /// Write the output without validating it
+    #[clap(long)]
+    no_validate: bool,
+}
+
+/// The format of the output module.
+#[derive(Clone, Copy, ValueEnum)]
+enum Emit {
+    /// Binary format
+    Wasm,
+    /// Text format, for inspection
+    Wat,
 }
 
 fn main() {
@@ -32,32 +53,79 @@ fn main() {
     }
 
     let mut base = parse_base(args.base);
+    let mut session = Linker::session(&mut base);
     for (name, path) in args
         .modules
         .iter()
         .step_by(2)
         .zip(args.modules.iter().skip(1).step_by(2))
     {
-        link_module(&mut base, name, path);
+        link_module(&mut session, name, path);
+    }
+    session.finish();
+    if args.gc {
+        strip_unused(&mut base);
     }
 
     let output_path = match args.output {
         Some(path) => path,
-        None => String::from("out.wasm"),
+        None => match args.emit {
+            Emit::Wasm => String::from("out.wasm"),
+            Emit::Wat => String::from("out.wat"),
+        },
     };
-    base.emit_wasm_file(output_path).unwrap();
+    let wasm = base.emit_wasm();
+    if !args.no_validate {
+        if let Err(err) = validate(&wasm) {
+            println!("Invalid output module: {}", err);
+            process::exit(1);
+        }
+    }
+    match args.emit {
+        Emit::Wasm => fs::write(output_path, wasm).unwrap(),
+        Emit::Wat => match wasmprinter::print_bytes(&wasm) {
+            Ok(wat) => fs::write(output_path, wat).unwrap(),
+            Err(err) => {
+                println!("Failed to print output module: {}", err);
+                process::exit(1);
+            }
+        },
+    }
 }
 
 fn parse_base<P: AsRef<Path>>(path: P) -> Module {
-    let wasm = fs::read(path).unwrap();
+    let wasm = read_module(path);
     let mut config = ModuleConfig::new();
     config.generate_name_section(false);
     config.parse(&wasm).unwrap()
 }
 
-fn link_module<P: AsRef<Path>>(base: &mut Module, name: &str, path: P) {
-    let wasm = fs::read(path).unwrap();
+fn link_module<P: AsRef<Path>>(session: &mut LinkSession, name: &str, path: P) {
+    let wasm = read_module(path);
     let config = ModuleConfig::new();
     let linkee = config.parse(&wasm).unwrap();
-    link(base, &linkee, name);
+    if let Err(err) = session.add(&linkee, name) {
+        println!("Failed to link '{}': {}", name, err);
+        process::exit(1);
+    }
+}
+
+/// Reads a module in either the binary or the text format, the format is detected from the content
+/// of the file.
+fn read_module<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    let path = path.as_ref();
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("Failed to read '{}': {}", path.display(), err);
+            process::exit(1);
+        }
+    };
+    match wat::parse_bytes(&bytes) {
+        Ok(wasm) => wasm.into_owned(),
+        Err(err) => {
+            println!("Failed to parse '{}': {}", path.display(), err);
+            process::exit(1);
+        }
+    }
 }
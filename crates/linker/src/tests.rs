@@ -0,0 +1,349 @@
+use walrus::Module;
+use wasmparser::{Parser, Payload};
+
+use crate::{limits_match, link, validate, LinkError, Linker};
+
+/// Parses a module in the text format.
+fn parse(wat: &str) -> Module {
+    let wasm = wat::parse_str(wat).unwrap();
+    Module::from_buffer(&wasm).unwrap()
+}
+
+/// Links `linkee` into `base` under the name "lib", and checks that the base is left untouched if
+/// linking fails.
+fn try_link(base: &str, linkee: &str) -> Result<Vec<u8>, LinkError> {
+    let mut base = parse(base);
+    let linkee = parse(linkee);
+    let original = base.emit_wasm();
+    match link(&mut base, &linkee, "lib") {
+        Ok(()) => {
+            let wasm = base.emit_wasm();
+            validate(&wasm).unwrap();
+            Ok(wasm)
+        }
+        Err(err) => {
+            assert_eq!(base.emit_wasm(), original);
+            Err(err)
+        }
+    }
+}
+
+#[test]
+fn link_func() {
+    let wasm = try_link(
+        r#"
+        (module
+            (import "lib" "answer" (func $answer (result i32)))
+            (func (export "main") (result i32)
+                call $answer
+            )
+        )
+    "#,
+        r#"
+        (module
+            (func (export "answer") (result i32)
+                i32.const 42
+            )
+        )
+    "#,
+    )
+    .unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    assert_eq!(module.imports.iter().count(), 0);
+}
+
+#[test]
+fn missing_export() {
+    let err = try_link(
+        r#"
+        (module
+            (import "lib" "answer" (func (result i32)))
+        )
+    "#,
+        r#"
+        (module
+            (func (export "question") (result i32)
+                i32.const 42
+            )
+        )
+    "#,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        LinkError::MissingExport { module, name } if module == "lib" && name == "answer"
+    ));
+}
+
+#[test]
+fn kind_mismatch() {
+    let err = try_link(
+        r#"
+        (module
+            (import "lib" "answer" (func (result i32)))
+        )
+    "#,
+        r#"
+        (module
+            (global (export "answer") i32 (i32.const 42))
+        )
+    "#,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        LinkError::KindMismatch {
+            expected: "function",
+            found: "global",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn type_mismatch() {
+    let linkee = r#"
+        (module
+            (func (export "answer") (result i32)
+                i32.const 42
+            )
+            (global (export "counter") (mut i32) (i32.const 0))
+        )
+    "#;
+    let funcs = [
+        "(func (result i64))",
+        "(func (param i32) (result i32))",
+        "(func)",
+    ];
+    for func in funcs {
+        let base = format!(r#"(module (import "lib" "answer" {}))"#, func);
+        let err = try_link(&base, linkee).unwrap_err();
+        assert!(matches!(err, LinkError::TypeMismatch { .. }), "{}", func);
+    }
+
+    // Globals must match both in type and mutability
+    for global in ["(global i32)", "(global (mut i64))"] {
+        let base = format!(r#"(module (import "lib" "counter" {}))"#, global);
+        let err = try_link(&base, linkee).unwrap_err();
+        assert!(matches!(err, LinkError::TypeMismatch { .. }), "{}", global);
+    }
+    try_link(
+        r#"(module (import "lib" "counter" (global (mut i32))))"#,
+        linkee,
+    )
+    .unwrap();
+}
+
+#[test]
+fn limits() {
+    let import = (2, Some(4));
+    assert!(limits_match(import, (2, Some(4))));
+    assert!(limits_match(import, (3, Some(3))));
+    assert!(!limits_match(import, (1, Some(4))));
+    assert!(!limits_match(import, (2, Some(5))));
+    assert!(!limits_match(import, (2, None)));
+    assert!(limits_match((2, None), (3, None)));
+    assert!(limits_match((2, None), (3, Some(3))));
+
+    let linkee = r#"
+        (module
+            (table (export "table") 3 4 funcref)
+            (memory (export "memory") 1 2)
+        )
+    "#;
+    let compatible = [
+        r#"(import "lib" "table" (table 2 funcref))"#,
+        r#"(import "lib" "table" (table 3 4 funcref))"#,
+        r#"(import "lib" "memory" (memory 1))"#,
+        r#"(import "lib" "memory" (memory 0 8))"#,
+    ];
+    for import in compatible {
+        let base = format!("(module {})", import);
+        try_link(&base, linkee).unwrap();
+    }
+    let incompatible = [
+        r#"(import "lib" "table" (table 4 funcref))"#,
+        r#"(import "lib" "table" (table 3 3 funcref))"#,
+        r#"(import "lib" "table" (table 3 externref))"#,
+        r#"(import "lib" "memory" (memory 2))"#,
+        r#"(import "lib" "memory" (memory 1 1))"#,
+    ];
+    for import in incompatible {
+        let base = format!("(module {})", import);
+        let err = try_link(&base, linkee).unwrap_err();
+        assert!(matches!(err, LinkError::TypeMismatch { .. }), "{}", import);
+    }
+}
+
+#[test]
+fn session() {
+    let mut base = parse(
+        r#"
+        (module
+            (import "a" "double" (func $double (param i32) (result i32)))
+            (import "b" "offset" (global $offset i32))
+            (func (export "main") (result i32)
+                global.get $offset
+                call $double
+            )
+        )
+    "#,
+    );
+    // The import of "b" by "a" is introduced by the first linkee, and resolved by the second one.
+    let a = parse(
+        r#"
+        (module
+            (import "b" "add" (func $add (param i32 i32) (result i32)))
+            (func (export "double") (param i32) (result i32)
+                local.get 0
+                local.get 0
+                call $add
+            )
+        )
+    "#,
+    );
+    let b = parse(
+        r#"
+        (module
+            (global (export "offset") i32 (i32.const 21))
+            (func (export "add") (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.add
+            )
+        )
+    "#,
+    );
+
+    let mut session = Linker::session(&mut base);
+    session.add(&a, "a").unwrap();
+    session.add(&b, "b").unwrap();
+    session.finish();
+
+    let wasm = base.emit_wasm();
+    validate(&wasm).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    assert_eq!(module.imports.iter().count(), 0);
+    assert_eq!(module.funcs.iter().count(), 3);
+    assert_eq!(module.globals.iter().count(), 1);
+}
+
+#[test]
+fn session_unresolved_imports() {
+    let mut base = parse(
+        r#"
+        (module
+            (import "a" "double" (func $double (param i32) (result i32)))
+            (func (export "main") (result i32)
+                i32.const 21
+                call $double
+            )
+        )
+    "#,
+    );
+    let a = parse(
+        r#"
+        (module
+            (import "b" "add" (func $add (param i32 i32) (result i32)))
+            (func (export "double") (param i32) (result i32)
+                local.get 0
+                local.get 0
+                call $add
+            )
+        )
+    "#,
+    );
+    let b = parse(
+        r#"
+        (module
+            (func (export "add") (param i64 i64) (result i64)
+                local.get 0
+                local.get 1
+                i64.add
+            )
+        )
+    "#,
+    );
+
+    // Imports introduced by a linkee are checked like the imports of the base, and are left as
+    // imports if no linkee provides them.
+    let mut session = Linker::session(&mut base);
+    session.add(&a, "a").unwrap();
+    assert!(matches!(
+        session.add(&b, "b"),
+        Err(LinkError::TypeMismatch { module, name, .. }) if module == "b" && name == "add"
+    ));
+    session.finish();
+
+    let wasm = base.emit_wasm();
+    validate(&wasm).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let imports: Vec<_> = module
+        .imports
+        .iter()
+        .map(|import| (import.module.as_str(), import.name.as_str()))
+        .collect();
+    assert_eq!(imports, [("b", "add")]);
+}
+
+#[test]
+fn no_duplicate_types() {
+    // The type of the indirect call is shared by the base, the linkee function and the call
+    // itself, it must end up as a single type entry.
+    let wasm = try_link(
+        r#"
+        (module
+            (import "lib" "apply" (func $apply (param i32) (result i32)))
+            (func (export "main") (param i32) (result i32)
+                local.get 0
+                call $apply
+            )
+        )
+    "#,
+        r#"
+        (module
+            (type $unary (func (param i32) (result i32)))
+            (table 1 funcref)
+            (func (export "apply") (type $unary)
+                local.get 0
+                i32.const 0
+                call_indirect (type $unary)
+            )
+        )
+    "#,
+    )
+    .unwrap();
+    // Walrus also stores internal types, count the entries of the type section instead
+    let types = Parser::new(0)
+        .parse_all(&wasm)
+        .find_map(|payload| match payload.unwrap() {
+            Payload::TypeSection(types) => Some(types.get_count()),
+            _ => None,
+        });
+    assert_eq!(types, Some(1));
+}
+
+#[test]
+fn branch_to_func_body() {
+    try_link(
+        r#"
+        (module
+            (import "lib" "clamp" (func (param i32) (result i32)))
+        )
+    "#,
+        r#"
+        (module
+            (func (export "clamp") (param i32) (result i32)
+                i32.const 0
+                local.get 0
+                i32.const 0
+                i32.lt_s
+                br_if 0
+                drop
+                local.get 0
+            )
+        )
+    "#,
+    )
+    .unwrap();
+}
@@ -1,9 +1,71 @@
 //! WebAssembly ABI
 
 use crate::types::ValueType;
+use crate::values::Value;
 use alloc::vec;
 use alloc::vec::Vec;
 
+// ——————————————————————————— Calling Convention ——————————————————————————— //
+
+/// The calling conventions of compiled WebAssembly functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallConv {
+    /// The System V AMD64 calling convention, with the VMContext passed after the arguments and
+    /// the return values beyond the first stored through a return pointer (see `HostReturnAbi`).
+    SystemV,
+}
+
+/// The calling convention of compiled WebAssembly functions on the current target.
+///
+/// The compiler, the native functions exposed to instances (see `as_native_func!`) and the code
+/// calling into instances must all agree on the calling convention. Code relying on a specific
+/// convention (e.g. register roles in inline assembly) should check it at compile time against
+/// this constant, so that adding a target does not silently break it.
+#[cfg(all(target_arch = "x86_64", not(target_os = "windows")))]
+pub const CALL_CONV: CallConv = CallConv::SystemV;
+
+#[cfg(not(all(target_arch = "x86_64", not(target_os = "windows"))))]
+compile_error!("No WebAssembly calling convention is defined for this target");
+
+/// A function called by the code of an instance once preemption has been requested (see
+/// `Instance::request_preemption`), with the VMContext of the instance.
+///
+/// The code clears the preemption flag before calling the handler, which may suspend the
+/// execution before returning.
+pub type PreemptionHandler = unsafe extern "sysv64" fn(vmctx: *mut u8);
+
+// ————————————————————————————— Memory Layout —————————————————————————————— //
+
+/// Size of a WebAssembly page, in bytes, defined by the standard.
+///
+/// Sizes derived from page counts are computed on 64 bits, as 64 bits memories can declare more
+/// pages than a 32 bits size can hold.
+pub const WASM_PAGE_SIZE: u64 = 0x10000; // 64 Ki
+
+/// Returns the width of a VMContext entry, in bytes, on a target whose pointers span the given
+/// number of bytes.
+///
+/// Entries hold pointers, pointer-sized bounds and globals of up to 64 bits, each entry is as wide
+/// as the widest of a pointer and a 64 bits value. The compiler computes the VMContext offsets with
+/// the pointer width of its target, and `VMContext` lays entries out with `VMCTX_ENTRY_WIDTH`.
+pub const fn vmctx_entry_width(pointer_width: usize) -> usize {
+    if pointer_width > core::mem::size_of::<u64>() {
+        pointer_width
+    } else {
+        core::mem::size_of::<u64>()
+    }
+}
+
+/// Width of a VMContext entry on the current target, in bytes.
+pub const VMCTX_ENTRY_WIDTH: usize = vmctx_entry_width(core::mem::size_of::<usize>());
+
+const _: () = assert!(VMCTX_ENTRY_WIDTH >= core::mem::size_of::<*const u8>());
+const _: () = assert!(VMCTX_ENTRY_WIDTH >= core::mem::size_of::<u64>());
+const _: () = assert!(VMCTX_ENTRY_WIDTH % core::mem::align_of::<u64>() == 0);
+const _: () = assert!(WASM_PAGE_SIZE as usize as u64 == WASM_PAGE_SIZE);
+
+// ————————————————————————————————— Types —————————————————————————————————— //
+
 /// A trait for base WebAssembly types.
 ///
 /// SAFETY: This trait is already implemented for all basic types, custom WebAssembly types should
@@ -11,21 +73,35 @@ use alloc::vec::Vec;
 pub unsafe trait WasmBaseType: Send {
     type Abi: Copy;
     const VALUE_TYPE: ValueType;
+
+    /// Returns the tagged value corresponding to an ABI value.
+    fn into_value(val: Self::Abi) -> Value;
 }
 
 macro_rules! impl_wasm_base_type {
-    ($t:ty, $val:expr) => {
+    ($t:ty, $val:expr, $variant:ident as $repr:ty) => {
         unsafe impl WasmBaseType for $t {
             type Abi = $t;
             const VALUE_TYPE: ValueType = $val;
+
+            fn into_value(val: $t) -> Value {
+                Value::$variant(val as $repr)
+            }
         }
     };
 }
 
-impl_wasm_base_type!(i32, ValueType::I32);
-impl_wasm_base_type!(u32, ValueType::I32);
-impl_wasm_base_type!(i64, ValueType::I64);
-impl_wasm_base_type!(u64, ValueType::I64);
+impl_wasm_base_type!(i32, ValueType::I32, I32 as i32);
+impl_wasm_base_type!(u32, ValueType::I32, I32 as i32);
+impl_wasm_base_type!(i64, ValueType::I64, I64 as i64);
+impl_wasm_base_type!(u64, ValueType::I64, I64 as i64);
+
+/// The encoding of null references, of both reference types.
+///
+/// `ref.null` yields an all-zero word, and `ref.is_null` compares against it. Empty table slots,
+/// empty function table entries (whose function pointer is then null) and the null `ExternHandle`
+/// share that encoding, so that a freshly zeroed area holds null references only.
+pub const NULL_REF: u64 = 0;
 
 /// A WebAssembly externref type, ABI compatible with WebAssembly 64 bits references.
 #[derive(Clone, Copy)]
@@ -34,6 +110,10 @@ pub enum ExternRef64 {}
 unsafe impl WasmBaseType for ExternRef64 {
     type Abi = u64;
     const VALUE_TYPE: ValueType = ValueType::ExternRef;
+
+    fn into_value(val: u64) -> Value {
+        Value::ExternRef(val)
+    }
 }
 
 /// A trait representing a value that is ABI compatible with a WebAssembly type and can be passed
@@ -75,6 +155,119 @@ impl_wasm_type!(u32);
 impl_wasm_type!(i64);
 impl_wasm_type!(u64);
 
+unsafe impl WasmType for bool {
+    type Abi = i32;
+
+    fn into_abi(self) -> i32 {
+        self as i32
+    }
+
+    fn from_abi(val: i32) -> Self {
+        val != 0
+    }
+}
+
+unsafe impl WasmType for char {
+    type Abi = u32;
+
+    fn into_abi(self) -> u32 {
+        self as u32
+    }
+
+    /// Invalid code points are replaced by `char::REPLACEMENT_CHARACTER`.
+    fn from_abi(val: u32) -> Self {
+        char::from_u32(val).unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+}
+
+/// Declares a type which can be passed across the WebAssembly/Native boundary.
+///
+/// Two kinds of declarations are supported:
+///
+/// - Fieldless enums, represented by one of the WebAssembly integer types. The enum must name a
+///   fallback variant after its body, which is returned when converting a value that matches no
+///   variant. Discriminants can be any constant expression.
+/// - Newtypes, wrapping a single type which already implements `WasmType`.
+///
+/// The macro derives `Clone` and `Copy`, and checks at compile time that the type has the same
+/// size as its WebAssembly representation.
+///
+/// ```ignore
+/// wasm_type! {
+///     pub enum Color: u32 {
+///         Unknown = 0,
+///         Red = 1,
+///         Blue = 2,
+///     } else Unknown
+/// }
+///
+/// wasm_type! {
+///     pub struct Age(pub u32);
+/// }
+/// ```
+#[macro_export]
+macro_rules! wasm_type {
+    (
+        $(#[$attr:meta])*
+        $vis:vis enum $name:ident: $repr:ident {
+            $($(#[$variant_attr:meta])* $variant:ident = $value:expr),* $(,)?
+        } else $fallback:ident
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy)]
+        #[repr($repr)]
+        $vis enum $name {
+            $($(#[$variant_attr])* $variant = $value,)*
+        }
+
+        const _: [(); core::mem::size_of::<$repr>()] = [(); core::mem::size_of::<$name>()];
+
+        // SAFETY: the enum is represented by a WebAssembly base type, and only valid variants are
+        // created from WebAssembly values.
+        unsafe impl $crate::WasmType for $name {
+            type Abi = $repr;
+
+            fn into_abi(self) -> $repr {
+                self as $repr
+            }
+
+            fn from_abi(val: $repr) -> Self {
+                $(
+                    if val == $name::$variant as $repr {
+                        return $name::$variant;
+                    }
+                )*
+                $name::$fallback
+            }
+        }
+    };
+
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident($inner_vis:vis $inner:ty);
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy)]
+        #[repr(transparent)]
+        $vis struct $name($inner_vis $inner);
+
+        const _: [(); core::mem::size_of::<$inner>()] = [(); core::mem::size_of::<$name>()];
+
+        // SAFETY: the conversions are forwarded to the wrapped type.
+        unsafe impl $crate::WasmType for $name {
+            type Abi = <$inner as $crate::WasmType>::Abi;
+
+            fn into_abi(self) -> <Self::Abi as $crate::WasmBaseType>::Abi {
+                <$inner as $crate::WasmType>::into_abi(self.0)
+            }
+
+            fn from_abi(val: <Self::Abi as $crate::WasmBaseType>::Abi) -> Self {
+                $name(<$inner as $crate::WasmType>::from_abi(val))
+            }
+        }
+    };
+}
+
 /// A trait representing parameters that can be passed to WebAssembly functions.
 ///
 /// SAFETY: This trait must only be implemented for types that are ABI compatible with WebAssembly
@@ -227,3 +420,53 @@ impl_host_return_abi!(Ret6 T1 T2 T3 T4 T5 T6);
 impl_host_return_abi!(Ret7 T1 T2 T3 T4 T5 T6 T7);
 impl_host_return_abi!(Ret8 T1 T2 T3 T4 T5 T6 T7 T8);
 impl_host_return_abi!(Ret9 T1 T2 T3 T4 T5 T6 T7 T8 T9);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    wasm_type! {
+        #[derive(Debug, PartialEq, Eq)]
+        enum Status: i32 {
+            Unknown = -1,
+            Ok = 0,
+            /// Documented variants are supported.
+            Busy = 3,
+        } else Unknown
+    }
+
+    wasm_type! {
+        #[derive(Debug, PartialEq, Eq)]
+        struct Wrapper(Status);
+    }
+
+    #[test]
+    fn wasm_type_macro() {
+        assert_eq!(WasmType::into_abi(Status::Busy), 3);
+        assert_eq!(Status::from_abi(0), Status::Ok);
+        assert_eq!(Status::from_abi(-1), Status::Unknown);
+        assert_eq!(Status::from_abi(42), Status::Unknown);
+        assert_eq!(<Status as WasmType>::ty(), ValueType::I32);
+
+        assert_eq!(WasmType::into_abi(Wrapper(Status::Busy)), 3);
+        assert_eq!(Wrapper::from_abi(0), Wrapper(Status::Ok));
+        assert_eq!(<Wrapper as WasmType>::ty(), ValueType::I32);
+    }
+
+    #[test]
+    fn bool_and_char() {
+        assert_eq!(WasmType::into_abi(true), 1);
+        assert!(bool::from_abi(2));
+        assert!(!bool::from_abi(0));
+        assert_eq!(WasmType::into_abi('a'), 0x61);
+        assert_eq!(char::from_abi(0x61), 'a');
+        assert_eq!(char::from_abi(0xD800), char::REPLACEMENT_CHARACTER);
+    }
+    #[test]
+    fn vmctx_entry_width_per_target() {
+        // Entries hold 64 bits globals even on 32 bits targets
+        assert_eq!(vmctx_entry_width(4), 8);
+        assert_eq!(vmctx_entry_width(8), 8);
+        assert_eq!(vmctx_entry_width(16), 16);
+    }
+}
@@ -0,0 +1,247 @@
+//! Module Bundles
+//!
+//! A bundle groups modules which are instantiated together, such as a program and the libraries it
+//! imports. The imports between the modules of a bundle are resolved ahead of time: each module
+//! lists the import names bound to a previous module of the bundle, so that the embedder can
+//! instantiate the whole bundle in order without looking up the imports by name. The other imports
+//! are resolved by the embedder, as for standalone modules.
+//!
+//! Bundles hold the bytecode of the modules rather than native code, the embedder compiles them.
+//!
+//! All integers are encoded as little endian u32:
+//!
+//! ```text
+//! bundle := magic version nb_modules module*
+//! module := name nb_links (name index)* wasm_size wasm
+//! name   := size utf8_bytes
+//! ```
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+
+/// The first bytes of a bundle.
+pub const BUNDLE_MAGIC: [u8; 4] = *b"\0cbd";
+/// The version of the bundle format.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// The reasons a bundle can be rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleError {
+    /// The bundle does not start with the expected magic and version.
+    InvalidHeader,
+    /// The bundle ends in the middle of an item.
+    UnexpectedEnd,
+    /// A name is not valid UTF-8.
+    InvalidName,
+    /// An import is bound to a module which does not come before the importing module.
+    InvalidLink,
+    /// Trailing bytes follow the last module.
+    TrailingBytes,
+}
+
+/// A parsed bundle, which borrows the bytes it was parsed from.
+#[derive(Debug)]
+pub struct Bundle<'a> {
+    modules: Vec<BundleModule<'a>>,
+}
+
+/// A module of a bundle.
+#[derive(Debug)]
+pub struct BundleModule<'a> {
+    /// The name of the module, under which it is imported by the next modules.
+    pub name: &'a str,
+    /// The import names bound to previous modules, together with the index of those modules.
+    pub links: Vec<(&'a str, usize)>,
+    /// The offset of the bytecode within the bundle.
+    pub offset: usize,
+    /// The bytecode of the module.
+    pub wasm: &'a [u8],
+}
+
+impl<'a> Bundle<'a> {
+    /// Parses a bundle.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, BundleError> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.bytes(BUNDLE_MAGIC.len())? != BUNDLE_MAGIC || reader.u32()? != BUNDLE_VERSION {
+            return Err(BundleError::InvalidHeader);
+        }
+
+        let nb_modules = reader.u32()? as usize;
+        let mut modules = Vec::new();
+        for idx in 0..nb_modules {
+            let name = reader.name()?;
+            let nb_links = reader.u32()? as usize;
+            let mut links = Vec::new();
+            for _ in 0..nb_links {
+                let import = reader.name()?;
+                let module = reader.u32()? as usize;
+                if module >= idx {
+                    return Err(BundleError::InvalidLink);
+                }
+                links.push((import, module));
+            }
+            let size = reader.u32()? as usize;
+            let offset = reader.offset;
+            let wasm = reader.bytes(size)?;
+            modules.push(BundleModule {
+                name,
+                links,
+                offset,
+                wasm,
+            });
+        }
+        if reader.offset != bytes.len() {
+            return Err(BundleError::TrailingBytes);
+        }
+        Ok(Self { modules })
+    }
+
+    /// Returns the modules of the bundle, in instantiation order.
+    pub fn modules(&self) -> &[BundleModule<'a>] {
+        &self.modules
+    }
+}
+
+/// Reads the items of a bundle.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, size: usize) -> Result<&'a [u8], BundleError> {
+        let end = self
+            .offset
+            .checked_add(size)
+            .ok_or(BundleError::UnexpectedEnd)?;
+        let bytes = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or(BundleError::UnexpectedEnd)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, BundleError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self) -> Result<&'a str, BundleError> {
+        let size = self.u32()? as usize;
+        str::from_utf8(self.bytes(size)?).map_err(|_| BundleError::InvalidName)
+    }
+}
+
+// ———————————————————————————— Bundle Builder ————————————————————————————— //
+
+/// Builds the bytes of a bundle.
+pub struct BundleBuilder {
+    nb_modules: u32,
+    bytes: Vec<u8>,
+}
+
+impl BundleBuilder {
+    pub fn new() -> Self {
+        Self {
+            nb_modules: 0,
+            bytes: Vec::new(),
+        }
+    }
+
+    /// Appends a module, whose imports in `links` are bound to previous modules of the bundle
+    /// (identified by their index).
+    pub fn add_module(&mut self, name: &str, links: &[(String, usize)], wasm: &[u8]) -> &mut Self {
+        self.nb_modules += 1;
+        self.push_name(name);
+        self.push_u32(links.len() as u32);
+        for (import, module) in links {
+            self.push_name(import);
+            self.push_u32(*module as u32);
+        }
+        self.push_u32(wasm.len() as u32);
+        self.bytes.extend_from_slice(wasm);
+        self
+    }
+
+    /// Returns the bytes of the bundle.
+    pub fn finish(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.bytes.len() + 12);
+        bytes.extend_from_slice(&BUNDLE_MAGIC);
+        bytes.extend_from_slice(&BUNDLE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.nb_modules.to_le_bytes());
+        bytes.extend_from_slice(&self.bytes);
+        bytes
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_name(&mut self, name: &str) {
+        self.push_u32(name.len() as u32);
+        self.bytes.extend_from_slice(name.as_bytes());
+    }
+}
+
+impl Default for BundleBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn round_trip() {
+        let links = vec![(String::from("lib"), 0)];
+        let bytes = BundleBuilder::new()
+            .add_module("lib", &[], b"lib bytecode")
+            .add_module("main", &links, b"main bytecode")
+            .finish();
+
+        let bundle = Bundle::parse(&bytes).unwrap();
+        let modules = bundle.modules();
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].name, "lib");
+        assert!(modules[0].links.is_empty());
+        assert_eq!(modules[1].name, "main");
+        assert_eq!(modules[1].links, [("lib", 0)]);
+        assert_eq!(modules[1].wasm, b"main bytecode");
+        let offset = modules[1].offset;
+        assert_eq!(
+            &bytes[offset..offset + modules[1].wasm.len()],
+            b"main bytecode"
+        );
+    }
+
+    #[test]
+    fn invalid_bundles() {
+        let bytes = BundleBuilder::new().add_module("a", &[], b"wasm").finish();
+        assert_eq!(
+            Bundle::parse(&bytes[1..]).unwrap_err(),
+            BundleError::InvalidHeader
+        );
+        assert_eq!(
+            Bundle::parse(&bytes[..bytes.len() - 1]).unwrap_err(),
+            BundleError::UnexpectedEnd
+        );
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert_eq!(
+            Bundle::parse(&trailing).unwrap_err(),
+            BundleError::TrailingBytes
+        );
+
+        // Modules can only be bound to previous modules
+        let links = vec![(String::from("a"), 0)];
+        let bytes = BundleBuilder::new()
+            .add_module("a", &links, b"wasm")
+            .finish();
+        assert_eq!(Bundle::parse(&bytes).unwrap_err(), BundleError::InvalidLink);
+    }
+}
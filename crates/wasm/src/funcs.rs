@@ -40,6 +40,37 @@ where
 
 unsafe impl<P, R> Sync for NativeFunc<P, R> {}
 
+/// A native function whose signature has been erased, but whose type is recorded.
+///
+/// This lets native functions of different types be stored together, e.g. in a function table.
+pub struct NativeFuncRef {
+    ptr: *const u8,
+    ty: FuncType,
+}
+
+impl NativeFuncRef {
+    pub fn ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn ty(&self) -> &FuncType {
+        &self.ty
+    }
+}
+
+impl<Params, Results> From<&NativeFunc<Params, Results>> for NativeFuncRef
+where
+    Params: WasmParams,
+    Results: WasmResults,
+{
+    fn from(func: &NativeFunc<Params, Results>) -> Self {
+        Self {
+            ptr: func.ptr(),
+            ty: func.ty(),
+        }
+    }
+}
+
 /// Converts a native Rust function into a function that can be seamelessly called from
 /// WebAssembly.
 ///
@@ -77,6 +108,11 @@ macro_rules! as_native_func {
     // Main body, where we have both arguments types and names
     (inner $func:ident; $static:ident; args_names: $($args_n:ident)*; args_types: $($args_t:ident)*; ret: $ret:tt) => {
         static $static: $crate::NativeFunc<($($args_t,)*), $ret> = {
+            const _: () = ::core::assert!(
+                ::core::matches!($crate::CALL_CONV, $crate::CallConv::SystemV),
+                "Native functions are only implemented for the System V calling convention"
+            );
+
             // NOTE: taking `()` as argument is not FFI-safe, hence the `allow` clause.
             // Here se rely on the fact that `()` arguments are optimized out so that the function
             // matches the Cranlift WasmtimeSysV ABI.
@@ -0,0 +1,228 @@
+//! Handle Tables
+//!
+//! A handle table is an externref table owned by an instance, whose content is managed by the
+//! runtime. This lets the embedder hand out capabilities to a given instance, without sharing a
+//! single namespace across all the instances importing the table.
+//!
+//! Handles are encoded as tagged 64 bits values (see `ExternHandle`), so that the embedder never
+//! relies on the memory layout of its own handle types.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::abi::{ExternRef64, WasmType, NULL_REF};
+
+/// The value of empty slots.
+const EMPTY: u64 = NULL_REF;
+
+// ———————————————————————————— Handle Encoding ————————————————————————————— //
+
+/// An externref handle, encoded as a tagged 64 bits value.
+///
+/// The upper 8 bits hold the tag, which identifies the kind of object, the next 24 bits hold the
+/// generation of the handle and the lower 32 bits hold its index. The tag 0 is reserved for the
+/// null handle, whose bits are all zero. The meaning of tags, indices and generations is left to
+/// the embedder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct ExternHandle(u64);
+
+impl ExternHandle {
+    /// The null handle, which marks empty slots of handle tables.
+    pub const NULL: Self = ExternHandle(EMPTY);
+
+    /// The largest generation a handle can hold.
+    pub const MAX_GENERATION: u32 = (1 << 24) - 1;
+
+    const TAG_SHIFT: u32 = 56;
+    const GENERATION_SHIFT: u32 = 32;
+
+    /// Encodes a handle.
+    ///
+    /// Returns `None` if the tag is 0 or the generation exceeds `MAX_GENERATION`.
+    pub const fn new(tag: u8, index: u32, generation: u32) -> Option<Self> {
+        if tag == 0 || generation > Self::MAX_GENERATION {
+            return None;
+        }
+        let bits = ((tag as u64) << Self::TAG_SHIFT)
+            | ((generation as u64) << Self::GENERATION_SHIFT)
+            | index as u64;
+        Some(ExternHandle(bits))
+    }
+
+    /// Validates raw bits, such as an externref received from WebAssembly.
+    ///
+    /// Returns `None` if the bits have no tag but are not null.
+    pub const fn from_bits(bits: u64) -> Option<Self> {
+        let handle = ExternHandle(bits);
+        if handle.tag() == 0 && bits != EMPTY {
+            None
+        } else {
+            Some(handle)
+        }
+    }
+
+    /// Returns the raw bits of the handle.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_null(self) -> bool {
+        self.0 == EMPTY
+    }
+
+    pub const fn tag(self) -> u8 {
+        (self.0 >> Self::TAG_SHIFT) as u8
+    }
+
+    pub const fn generation(self) -> u32 {
+        ((self.0 >> Self::GENERATION_SHIFT) as u32) & Self::MAX_GENERATION
+    }
+
+    pub const fn index(self) -> u32 {
+        self.0 as u32
+    }
+}
+
+unsafe impl WasmType for ExternHandle {
+    type Abi = ExternRef64;
+
+    fn into_abi(self) -> u64 {
+        self.0
+    }
+
+    /// Invalid bits are decoded as the null handle.
+    fn from_abi(val: u64) -> Self {
+        Self::from_bits(val).unwrap_or(Self::NULL)
+    }
+}
+
+// ————————————————————————————— Handle Tables —————————————————————————————— //
+
+/// A fixed capacity table of externref handles.
+///
+/// The null handle marks an empty slot and can not be inserted.
+pub struct HandleTable {
+    slots: Box<[AtomicU64]>,
+}
+
+impl HandleTable {
+    /// Creates an empty table.
+    pub fn new(capacity: u32) -> Self {
+        let slots = (0..capacity)
+            .map(|_| AtomicU64::new(EMPTY))
+            .collect::<Vec<AtomicU64>>();
+        Self {
+            slots: slots.into_boxed_slice(),
+        }
+    }
+
+    /// Inserts a handle in the first empty slot, returns the index of that slot.
+    ///
+    /// Returns `None` if the table is full or the handle is null.
+    pub fn insert(&self, handle: ExternHandle) -> Option<u32> {
+        if handle.is_null() {
+            return None;
+        }
+        for (idx, slot) in self.slots.iter().enumerate() {
+            if slot
+                .compare_exchange(EMPTY, handle.bits(), Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return Some(idx as u32);
+            }
+        }
+        None
+    }
+
+    /// Returns the handle at the given index, if any.
+    pub fn get(&self, index: u32) -> Option<ExternHandle> {
+        let handle = self.slots.get(index as usize)?.load(Ordering::SeqCst);
+        Self::occupied(handle)
+    }
+
+    /// Removes the handle at the given index and returns it, if any.
+    pub fn remove(&self, index: u32) -> Option<ExternHandle> {
+        let handle = self
+            .slots
+            .get(index as usize)?
+            .swap(EMPTY, Ordering::SeqCst);
+        Self::occupied(handle)
+    }
+
+    /// Returns the handle stored in a slot, `None` if the slot is empty.
+    ///
+    /// Only valid handles are inserted, but the table is writable from WebAssembly.
+    fn occupied(bits: u64) -> Option<ExternHandle> {
+        ExternHandle::from_bits(bits).filter(|handle| !handle.is_null())
+    }
+
+    /// Returns a pointer to the first slot of the table.
+    ///
+    /// The table is accessed directly from WebAssembly through this pointer.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.slots.as_ptr() as *const u8
+    }
+
+    /// Returns the capacity of the table.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Returns true if the table has no slot.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+impl Clone for HandleTable {
+    /// Returns a table holding the same handles.
+    fn clone(&self) -> Self {
+        let slots = self
+            .slots
+            .iter()
+            .map(|slot| AtomicU64::new(slot.load(Ordering::SeqCst)))
+            .collect::<Vec<AtomicU64>>();
+        Self {
+            slots: slots.into_boxed_slice(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_encoding() {
+        let handle = ExternHandle::new(3, 42, 7).unwrap();
+        assert_eq!(handle.bits(), 0x0300_0007_0000_002a);
+        assert_eq!(handle.tag(), 3);
+        assert_eq!(handle.generation(), 7);
+        assert_eq!(handle.index(), 42);
+        assert_eq!(ExternHandle::from_bits(handle.bits()), Some(handle));
+        assert_eq!(ExternHandle::from_abi(handle.into_abi()), handle);
+
+        // The tag 0 is reserved for the null handle
+        assert!(ExternHandle::new(0, 1, 0).is_none());
+        assert!(ExternHandle::new(1, 0, ExternHandle::MAX_GENERATION + 1).is_none());
+        assert!(ExternHandle::from_bits(0).unwrap().is_null());
+        assert!(ExternHandle::from_bits(1).is_none());
+        assert!(ExternHandle::from_abi(1).is_null());
+        assert_eq!(ExternHandle::NULL.bits(), NULL_REF);
+    }
+
+    #[test]
+    fn handle_table() {
+        let table = HandleTable::new(2);
+        let handle = ExternHandle::new(1, 0, 0).unwrap();
+        assert_eq!(table.insert(ExternHandle::NULL), None);
+        assert_eq!(table.insert(handle), Some(0));
+        assert_eq!(table.insert(handle), Some(1));
+        assert_eq!(table.insert(handle), None);
+        assert_eq!(table.remove(0), Some(handle));
+        assert_eq!(table.get(0), None);
+        assert_eq!(table.get(1), Some(handle));
+    }
+}
@@ -1,26 +1,39 @@
 use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
+use crate::abi::{NULL_REF, WASM_PAGE_SIZE};
+use crate::handles::{ExternHandle, HandleTable};
+use crate::tables::{FuncTable, NULL_SIGNATURE};
 use crate::traits::{
-    DataSegment, FuncIndex, FuncInfo, FuncPtr, GlobIndex, GlobInfo, GlobInit, HeapIndex, HeapInfo,
-    ImportIndex, ItemRef, MemoryArea, Module, ModuleError, ModuleResult, Reloc, RelocKind, Runtime,
-    TableIndex, TypeIndex,
+    AllocPolicy, DataSegment, FuncIndex, FuncInfo, FuncPtr, GlobIndex, GlobInfo, GlobInit,
+    HeapIndex, HeapInfo, ImportIndex, ImportKind, ItemRef, MemoryArea, Module, ModuleError,
+    ModuleResult, Reloc, RelocKind, Runtime, TableIndex, TrapCode, TrapSite, TypeIndex,
+    WasmLocation,
 };
-use crate::types::FuncType;
-use crate::vmctx::VMContext;
-use collections::{FrozenMap, HashMap};
+use crate::types::{FuncType, RefType};
+use crate::vmctx::{VMContext, VMContextError};
+use collections::{BTreeMap, EntityRef, FrozenMap, HashMap};
 
-const PAGE_SIZE: usize = 0x10000; // 64 Ki bytes
+/// Size of a WebAssembly page, the `WASM_PAGE_SIZE` assertions guarantee that it fits a `usize`.
+const PAGE_SIZE: usize = WASM_PAGE_SIZE as usize;
 
 type Imports<Area> = FrozenMap<ImportIndex, Arc<Instance<Area>>>;
 
+/// Maps each imported item to the instance it is imported from, and to its index within that
+/// instance.
+type Resolved = HashMap<ItemRef, (ImportIndex, ItemRef)>;
+
 enum Item<'a, Area: MemoryArea> {
     Func(&'a Func),
     Heap(&'a Heap<Area>),
     Table(&'a Table),
 }
 
+/// A heap of the instance, the size of owned heaps is held by their bound (see `heap_bounds`).
 enum Heap<Area> {
     Owned { memory: Area },
     Imported { from: ImportIndex, index: HeapIndex },
@@ -30,12 +43,15 @@ enum Table {
     // Note: for now we use boxed slices, so that we don't have to handle table relocation (but we
     // only support fixed size tables then...)
     Owned(Box<[u64]>),
+    Funcs(FuncTable),
+    Handles(HandleTable),
     Imported {
         from: ImportIndex,
         index: TableIndex,
     },
 }
 
+#[derive(Clone, Copy)]
 enum Func {
     Owned {
         offset: u32,
@@ -62,6 +78,20 @@ impl Func {
     }
 }
 
+/// Execution metrics of an instance.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstanceMetrics {
+    /// The number of calls from the embedder into the instance.
+    pub calls: u64,
+    /// The execution time of those calls, in the unit chosen by the embedder (e.g. CPU cycles).
+    /// The time spent in nested calls (e.g. to imported functions) is included.
+    pub ticks: u64,
+    /// The number of calls to each imported function, if the module was compiled with call
+    /// counters.
+    pub imported_calls: Vec<(FuncIndex, u64)>,
+}
+
+#[derive(Clone, Copy)]
 enum Glob {
     Owned { init: GlobInit },
     Imported { from: ImportIndex, index: GlobIndex },
@@ -69,7 +99,7 @@ enum Glob {
 
 pub struct Instance<Area> {
     /// A map of all exported symbols.
-    items: HashMap<String, ItemRef>,
+    items: BTreeMap<String, ItemRef>,
 
     /// The VM Context, contains pointers to various structures, such as heaps and tables.
     ///
@@ -79,9 +109,26 @@ pub struct Instance<Area> {
     /// The heaps of the instance.
     heaps: FrozenMap<HeapIndex, Heap<Area>>,
 
+    /// The bounds of the heaps owned by the instance in bytes, by heap index (0 for imported
+    /// heaps).
+    ///
+    /// As for tables, compiled code loads the bounds of imported heaps through pointers stored in
+    /// the VMContext. A heap growing in place therefore only has to update its bound for all the
+    /// instances importing it to observe its new size, without walking over the importers.
+    heap_bounds: Box<[AtomicUsize]>,
+
     /// The tables of the instance.
     tables: FrozenMap<TableIndex, Table>,
 
+    /// The bounds of the tables owned by the instance, by table index (0 for imported tables).
+    ///
+    /// Compiled code loads the bounds through pointers stored in the VMContext, so that they can
+    /// change without recompiling, and so that instances importing a table read its actual bound.
+    table_bounds: Box<[AtomicU32]>,
+
+    /// The handle table of the instance, if any.
+    handles: Option<TableIndex>,
+
     /// The functions of the instance.
     funcs: FrozenMap<FuncIndex, Func>,
 
@@ -89,6 +136,10 @@ pub struct Instance<Area> {
     globs: FrozenMap<GlobIndex, Glob>,
 
     /// The imported instances.
+    ///
+    /// Imported instances exist before the importing instance is created and its imports never
+    /// change afterward: the import graph is acyclic by construction, which guarantees that the
+    /// recursive lookups of imported items terminate.
     imports: FrozenMap<ImportIndex, Arc<Instance<Area>>>,
 
     /// The function types used by the instance.
@@ -97,12 +148,30 @@ pub struct Instance<Area> {
     /// The start function, if any.
     start: Option<FuncIndex>,
 
+    /// Whether the start function has already been handed out for execution.
+    started: AtomicBool,
+
+    /// Whether a call from the embedder is executing the instance, see `try_enter`.
+    running: AtomicBool,
+
     /// The memory region containing the code
     code: Area,
+
+    /// The size of the code, in bytes.
+    code_size: usize,
+
+    /// The instructions that might trap, sorted by offset.
+    trap_sites: Box<[TrapSite]>,
+
+    /// The number of calls from the embedder, see `record_call`.
+    calls: AtomicU64,
+
+    /// The execution ticks accumulated by the calls from the embedder.
+    ticks: AtomicU64,
 }
 
 impl<Area: MemoryArea> Instance<Area> {
-    /// Creates an instance from a module.
+    /// Creates an instance from a module, with the default allocation policy.
     pub fn instantiate<Mod, Ctx>(
         module: &Mod,
         import_from: &[(&str, Arc<Instance<Area>>)],
@@ -111,33 +180,196 @@ impl<Area: MemoryArea> Instance<Area> {
     where
         Mod: Module,
     {
-        let mut ctx = runtime.create_context();
+        Self::instantiate_with_policy(module, import_from, runtime, &AllocPolicy::default())
+    }
+
+    /// Creates an instance from a module, the runtime allocates the instance memory according to
+    /// the given policy.
+    pub fn instantiate_with_policy<Mod, Ctx>(
+        module: &Mod,
+        import_from: &[(&str, Arc<Instance<Area>>)],
+        runtime: &impl Runtime<MemoryArea = Area, Context = Ctx>,
+        policy: &AllocPolicy,
+    ) -> ModuleResult<Self>
+    where
+        Mod: Module,
+    {
+        Self::instantiate_with_image(module, None, import_from, runtime, policy)
+    }
+
+    /// Creates an instance from a module and an image of its heaps, the runtime allocates the
+    /// instance memory according to the given policy.
+    ///
+    /// The heaps part of the image are forks of the image heaps (see `Runtime::fork_heap`), the
+    /// others are initialized from the data segments as usual. The image must have been created
+    /// from the same module.
+    pub fn instantiate_from_image<Mod, Ctx>(
+        module: &Mod,
+        image: &ModuleImage<Area>,
+        import_from: &[(&str, Arc<Instance<Area>>)],
+        runtime: &impl Runtime<MemoryArea = Area, Context = Ctx>,
+        policy: &AllocPolicy,
+    ) -> ModuleResult<Self>
+    where
+        Mod: Module,
+    {
+        Self::instantiate_with_image(module, Some(image), import_from, runtime, policy)
+    }
+
+    fn instantiate_with_image<Mod, Ctx>(
+        module: &Mod,
+        image: Option<&ModuleImage<Area>>,
+        import_from: &[(&str, Arc<Instance<Area>>)],
+        runtime: &impl Runtime<MemoryArea = Area, Context = Ctx>,
+        policy: &AllocPolicy,
+    ) -> ModuleResult<Self>
+    where
+        Mod: Module,
+    {
+        let missing_features = module
+            .required_cpu_features()
+            .difference(runtime.cpu_features());
+        if !missing_features.is_empty() {
+            return Err(ModuleError::MissingCpuFeatures(missing_features));
+        }
+
+        let mut ctx = runtime.create_context(policy);
         let items = module.public_items().clone();
         let types = module.types().clone();
 
         let imports = Self::select_imports(module, &import_from)?;
-        let funcs = Self::prepare_funcs(module, &imports, &types)?;
-        let globs = Self::prepare_globs(module, &imports)?;
-        let heaps = Self::allocate_heaps(module, &imports, runtime, &mut ctx)?;
-        let tables = Self::allocate_tables(module, &imports, runtime, &mut ctx)?;
+        let resolved = Self::resolve_imports(module, &imports, &types)?;
+        let funcs = Self::prepare_funcs(module, &resolved)?;
+        Self::check_start(module, &funcs, &types)?;
+        let globs = Self::prepare_globs(module, &resolved)?;
+        let heaps = Self::allocate_heaps(module, image, &resolved, runtime, &mut ctx)?;
+        let tables = Self::allocate_tables(module, &resolved, runtime, &mut ctx)?;
         let code = Self::allocate_code(module, &imports, &funcs, runtime, &mut ctx)?;
+        let handles = tables
+            .iter()
+            .find(|(_, table)| matches!(table, Table::Handles(_)))
+            .map(|(idx, _)| idx);
 
         // Create instance
         let mut instance = Self {
             vmctx: VMContext::empty(module.vmctx_layout()),
             start: module.start(),
+            started: AtomicBool::new(false),
+            running: AtomicBool::new(false),
             imports,
             items,
+            heap_bounds: Self::heap_bounds(module),
             heaps,
+            table_bounds: Self::table_bounds(&tables),
             tables,
+            handles,
             globs,
             funcs,
             types,
             code,
+            code_size: module.code().len(),
+            trap_sites: module.trap_sites().into(),
+            calls: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
         };
 
-        instance.init_tables(module);
-        instance.init_vmctx(); // Set the VMContext to its expected initial values
+        instance.init_tables(module)?;
+        instance.init_vmctx()?; // Set the VMContext to its expected initial values
+        if let Some(handler) = runtime.preemption_handler() {
+            instance.vmctx.set_preemption_handler(handler);
+        }
+        runtime.commit(ctx);
+
+        Ok(instance)
+    }
+
+    /// Creates a copy of the instance, the runtime allocates the memory of the copy according to
+    /// the given policy.
+    ///
+    /// The copy shares the code and the imports of this instance. Its heaps, funcref tables and
+    /// handles are copies of those of this instance, while its globals are reset to their initial
+    /// values and its other externref tables are allocated anew. The runtime is free to copy the
+    /// heaps lazily (e.g. copy-on-write), but the heaps must not be mutated while being forked.
+    pub fn fork<Ctx>(
+        &self,
+        runtime: &impl Runtime<MemoryArea = Area, Context = Ctx>,
+        policy: &AllocPolicy,
+    ) -> ModuleResult<Self>
+    where
+        Area: Clone,
+    {
+        let mut ctx = runtime.create_context(policy);
+        let heaps = self.heaps.try_map_enumerate(|idx, heap| match heap {
+            Heap::Owned { memory } => {
+                let bytes = self.heap_bounds[idx.index()].load(Ordering::SeqCst);
+                Ok(Heap::Owned {
+                    memory: runtime.fork_heap(memory, bytes, &mut ctx)?,
+                })
+            }
+            Heap::Imported { from, index } => Ok(Heap::Imported {
+                from: *from,
+                index: *index,
+            }),
+        })?;
+        let tables = self.tables.try_map(|table| match table {
+            Table::Owned(table) => {
+                let size = table.len() as u32;
+                let table = runtime.alloc_table(size, Some(size), RefType::ExternRef, &mut ctx)?;
+                Ok(Table::Owned(table))
+            }
+            Table::Funcs(table) => {
+                let size = (table.len() * FuncTable::WORDS_PER_ENTRY) as u32;
+                let mut words =
+                    runtime.alloc_table(size, Some(size), RefType::FuncRef, &mut ctx)?;
+                words.copy_from_slice(table.words());
+                Ok(Table::Funcs(FuncTable::from_words(words)))
+            }
+            Table::Handles(handles) => Ok(Table::Handles(handles.clone())),
+            Table::Imported { from, index } => Ok(Table::Imported {
+                from: *from,
+                index: *index,
+            }),
+        })?;
+
+        let mut instance = Self {
+            items: self.items.clone(),
+            vmctx: self.vmctx.empty_like(),
+            heaps,
+            heap_bounds: self
+                .heap_bounds
+                .iter()
+                .map(|bound| AtomicUsize::new(bound.load(Ordering::SeqCst)))
+                .collect(),
+            table_bounds: Self::table_bounds(&tables),
+            tables,
+            handles: self.handles,
+            funcs: self.funcs.clone(),
+            globs: self.globs.clone(),
+            imports: self.imports.clone(),
+            types: self.types.clone(),
+            start: self.start,
+            started: AtomicBool::new(self.started.load(Ordering::SeqCst)),
+            running: AtomicBool::new(false),
+            code: self.code.clone(),
+            code_size: self.code_size,
+            trap_sites: self.trap_sites.clone(),
+            calls: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+        };
+        instance.init_vmctx()?;
+        if let Some(handler) = runtime.preemption_handler() {
+            instance.vmctx.set_preemption_handler(handler);
+        }
+
+        // The copied funcref tables still refer to the functions of this instance
+        let (old_vmctx, new_vmctx) = (self.get_vmctx_ptr(), instance.get_vmctx_ptr());
+        for table in instance.tables.values() {
+            if let Table::Funcs(table) = table {
+                // SAFETY: the new instance is not running yet.
+                unsafe { table.replace_vmctx(old_vmctx as u64, new_vmctx as u64) };
+            }
+        }
+        runtime.commit(ctx);
 
         Ok(instance)
     }
@@ -152,7 +384,7 @@ impl<Area: MemoryArea> Instance<Area> {
     where
         Mod: Module,
     {
-        module.imports().try_map(|module| {
+        module.import_modules().try_map(|module| {
             // Pick the first matching module
             for (name, instance) in import_from {
                 if name == module {
@@ -163,80 +395,133 @@ impl<Area: MemoryArea> Instance<Area> {
         })
     }
 
-    fn prepare_funcs<Mod>(
+    /// Resolves the imported items against the exports of the selected instances, checking their
+    /// kind and type.
+    fn resolve_imports<Mod>(
         module: &Mod,
         imports: &Imports<Area>,
         types: &FrozenMap<TypeIndex, FuncType>,
-    ) -> ModuleResult<FrozenMap<FuncIndex, Func>>
+    ) -> ModuleResult<Resolved>
     where
         Mod: Module,
     {
-        module.funcs().try_map(|func_info| match func_info {
-            FuncInfo::Owned { offset, ty } => Ok(Func::Owned {
-                offset: *offset,
-                ty: *ty,
-            }),
-            FuncInfo::Native { ptr, ty } => Ok(Func::Native { ptr: *ptr, ty: *ty }),
-            FuncInfo::Imported { module, name, ty } => {
-                // Look for the corresponding module
-                let instance = &imports[*module];
-                let func_ref = instance
-                    .items
-                    .get(name)
-                    .ok_or(ModuleError::FailedToInstantiate)?
-                    .as_func()
-                    .ok_or(ModuleError::FailedToInstantiate)?;
-
-                // Typecheck function
-                let my_type = &types[*ty];
-                let other_func = &instance.funcs[func_ref];
-                let other_type = &instance.types[other_func.ty_index()];
-                if !my_type.eq(other_type) {
-                    return Err(ModuleError::TypeError);
+        let mut resolved = HashMap::with_capacity(module.imports().len());
+        for import in module.imports() {
+            let instance = &imports[import.module];
+            let export = *instance
+                .items
+                .get(&import.field)
+                .ok_or(ModuleError::FailedToInstantiate)?;
+            match import.kind {
+                ImportKind::Func { ty, .. } => {
+                    let func_ref = export.as_func().ok_or(ModuleError::FailedToInstantiate)?;
+                    let other_func = &instance.funcs[func_ref];
+                    let other_type = &instance.types[other_func.ty_index()];
+                    if !types[ty].eq(other_type) {
+                        return Err(ModuleError::TypeError);
+                    }
                 }
+                ImportKind::Heap { min_size, .. } => {
+                    let heap_ref = export.as_heap().ok_or(ModuleError::FailedToInstantiate)?;
+                    // The exported heap must be at least as big as the expected minimum size
+                    if instance.memory_size(heap_ref) < min_size {
+                        return Err(ModuleError::FailedToInstantiate);
+                    }
+                }
+                ImportKind::Table { ty, .. } => {
+                    let table_ref = export.as_table().ok_or(ModuleError::FailedToInstantiate)?;
+                    // Indirect calls rely on the layout of funcref tables
+                    if instance.get_table_type(table_ref) != ty {
+                        return Err(ModuleError::TypeError);
+                    }
+                }
+                ImportKind::Glob { .. } => {
+                    // TODO: typecheck glob here
+                    export.as_glob().ok_or(ModuleError::FailedToInstantiate)?;
+                }
+            }
+            resolved.insert(import.item(), (import.module, export));
+        }
+        Ok(resolved)
+    }
 
-                Ok(Func::Imported {
-                    from: *module,
-                    index: func_ref,
-                    ty: *ty,
-                })
+    /// Returns the origin of an imported item, which must have been resolved.
+    fn resolved_origin(resolved: &Resolved, item: ItemRef) -> ModuleResult<(ImportIndex, ItemRef)> {
+        resolved
+            .get(&item)
+            .copied()
+            .ok_or(ModuleError::FailedToInstantiate)
+    }
+
+    /// Checks that the start function, if any, has type `[] -> []`.
+    fn check_start<Mod>(
+        module: &Mod,
+        funcs: &FrozenMap<FuncIndex, Func>,
+        types: &FrozenMap<TypeIndex, FuncType>,
+    ) -> ModuleResult<()>
+    where
+        Mod: Module,
+    {
+        if let Some(start) = module.start() {
+            let ty = &types[funcs[start].ty_index()];
+            if !ty.args().is_empty() || !ty.ret().is_empty() {
+                return Err(ModuleError::TypeError);
             }
-        })
+        }
+        Ok(())
+    }
+
+    fn prepare_funcs<Mod>(
+        module: &Mod,
+        resolved: &Resolved,
+    ) -> ModuleResult<FrozenMap<FuncIndex, Func>>
+    where
+        Mod: Module,
+    {
+        module
+            .funcs()
+            .try_map_enumerate(|func_idx, func_info| match func_info {
+                FuncInfo::Owned { offset, ty, .. } => Ok(Func::Owned {
+                    offset: *offset,
+                    ty: *ty,
+                }),
+                FuncInfo::Native { ptr, ty } => Ok(Func::Native { ptr: *ptr, ty: *ty }),
+                FuncInfo::Imported { ty } => {
+                    let (from, index) = Self::resolved_origin(resolved, ItemRef::Func(func_idx))?;
+                    Ok(Func::Imported {
+                        from,
+                        index: index.as_func().unwrap(),
+                        ty: *ty,
+                    })
+                }
+            })
     }
 
     fn prepare_globs<Mod>(
         module: &Mod,
-        imports: &Imports<Area>,
+        resolved: &Resolved,
     ) -> ModuleResult<FrozenMap<GlobIndex, Glob>>
     where
         Mod: Module,
     {
-        module.globs().try_map(|glob_info| match glob_info {
-            GlobInfo::Owned { init } => Ok(Glob::Owned { init: *init }),
-            GlobInfo::Imported { module, name } => {
-                // Look for the corresponding module
-                let instance = &imports[*module];
-                let glob_ref = instance
-                    .items
-                    .get(name)
-                    .ok_or(ModuleError::FailedToInstantiate)?
-                    .as_glob()
-                    .ok_or(ModuleError::FailedToInstantiate)?;
-
-                // TODO: typecheck glob here
-                let _glob = &instance.globs[glob_ref];
-
-                Ok(Glob::Imported {
-                    from: *module,
-                    index: glob_ref,
-                })
-            }
-        })
+        module
+            .globs()
+            .try_map_enumerate(|glob_idx, glob_info| match glob_info {
+                GlobInfo::Owned { init } => Ok(Glob::Owned { init: *init }),
+                GlobInfo::Imported => {
+                    let (from, index) = Self::resolved_origin(resolved, ItemRef::Glob(glob_idx))?;
+                    Ok(Glob::Imported {
+                        from,
+                        index: index.as_glob().unwrap(),
+                    })
+                }
+            })
     }
 
     fn allocate_heaps<Mod, Ctx>(
         module: &Mod,
-        imports: &Imports<Area>,
+        image: Option<&ModuleImage<Area>>,
+        resolved: &Resolved,
         runtime: &impl Runtime<MemoryArea = Area, Context = Ctx>,
         ctx: &mut Ctx,
     ) -> ModuleResult<FrozenMap<HeapIndex, Heap<Area>>>
@@ -248,21 +533,25 @@ impl<Area: MemoryArea> Instance<Area> {
             .try_map_enumerate(|heap_idx, heap_info| match heap_info {
                 HeapInfo::Owned { min_size, kind } => {
                     let mut initialized = false;
+                    let size = (*min_size as usize) * PAGE_SIZE;
+
+                    // The heap is already initialized in the image
+                    if let Some(heap) = image.and_then(|image| image.get(heap_idx)) {
+                        return Ok(Heap::Owned {
+                            memory: runtime.fork_heap(heap, size, ctx)?,
+                        });
+                    }
+
                     let initialize = |heap: &mut [u8]| {
-                        if heap.len() < *min_size as usize {
+                        if heap.len() < size {
                             return Err(ModuleError::FailedToInstantiate);
                         }
                         initialized = true;
-                        Self::initialize_heap(heap, heap_idx, module.data_segments())
+                        Self::initialize_heap(heap, size, heap_idx, module.data_segments())
                     };
 
                     // Allocate heap
-                    let area = runtime.alloc_heap(
-                        (*min_size as usize) * PAGE_SIZE,
-                        *kind,
-                        initialize,
-                        ctx,
-                    )?;
+                    let area = runtime.alloc_heap(size, *kind, initialize, ctx)?;
 
                     // Check that the heap was initialized
                     if !initialized {
@@ -271,19 +560,11 @@ impl<Area: MemoryArea> Instance<Area> {
 
                     Ok(Heap::Owned { memory: area })
                 }
-                HeapInfo::Imported { module, name } => {
-                    // Look for the corresponding module
-                    let instance = &imports[*module];
-                    let heap_ref = instance
-                        .items
-                        .get(name)
-                        .ok_or(ModuleError::FailedToInstantiate)?
-                        .as_heap()
-                        .ok_or(ModuleError::FailedToInstantiate)?;
-
+                HeapInfo::Imported { .. } => {
+                    let (from, index) = Self::resolved_origin(resolved, ItemRef::Heap(heap_idx))?;
                     Ok(Heap::Imported {
-                        from: *module,
-                        index: heap_ref,
+                        from,
+                        index: index.as_heap().unwrap(),
                     })
                 }
             })
@@ -291,39 +572,60 @@ impl<Area: MemoryArea> Instance<Area> {
 
     fn allocate_tables<Mod, Ctx>(
         module: &Mod,
-        imports: &Imports<Area>,
+        resolved: &Resolved,
         runtime: &impl Runtime<MemoryArea = Area, Context = Ctx>,
         ctx: &mut Ctx,
     ) -> ModuleResult<FrozenMap<TableIndex, Table>>
     where
         Mod: Module,
     {
-        module.tables().try_map(|table_info| match table_info {
-            crate::TableInfo::Owned {
-                min_size,
-                max_size,
-                ty,
-            } => {
-                let table = runtime.alloc_table(*min_size, *max_size, *ty, ctx)?;
-                Ok(Table::Owned(table))
-            }
-            crate::TableInfo::Native { ptr, .. } => Ok(Table::Owned(ptr.clone())),
-            crate::TableInfo::Imported { module, name, .. } => {
-                // Look for the corresponding module
-                let instance = &imports[*module];
-                let table_ref = instance
-                    .items
-                    .get(name)
-                    .ok_or(ModuleError::FailedToInstantiate)?
-                    .as_table()
-                    .ok_or(ModuleError::FailedToInstantiate)?;
-
-                Ok(Table::Imported {
-                    from: *module,
-                    index: table_ref,
-                })
-            }
-        })
+        module
+            .tables()
+            .try_map_enumerate(|table_idx, table_info| match table_info {
+                crate::TableInfo::Owned {
+                    min_size,
+                    max_size,
+                    ty: RefType::FuncRef,
+                } => {
+                    let words = FuncTable::WORDS_PER_ENTRY as u32;
+                    let min_size = min_size
+                        .checked_mul(words)
+                        .ok_or(ModuleError::FailedToInstantiate)?;
+                    let max_size = max_size
+                        .map(|size| {
+                            size.checked_mul(words)
+                                .ok_or(ModuleError::FailedToInstantiate)
+                        })
+                        .transpose()?;
+                    let mut table =
+                        runtime.alloc_table(min_size, max_size, RefType::FuncRef, ctx)?;
+                    table.fill(NULL_REF);
+                    Ok(Table::Funcs(FuncTable::from_words(table)))
+                }
+                crate::TableInfo::Owned {
+                    min_size,
+                    max_size,
+                    ty,
+                } => {
+                    let table = runtime.alloc_table(*min_size, *max_size, *ty, ctx)?;
+                    Ok(Table::Owned(table))
+                }
+                crate::TableInfo::Native {
+                    ptr,
+                    ty: RefType::FuncRef,
+                } => Ok(Table::Funcs(FuncTable::from_words(ptr.clone()))),
+                crate::TableInfo::Native { ptr, .. } => Ok(Table::Owned(ptr.clone())),
+                crate::TableInfo::Handles { capacity } => {
+                    Ok(Table::Handles(HandleTable::new(*capacity)))
+                }
+                crate::TableInfo::Imported { .. } => {
+                    let (from, index) = Self::resolved_origin(resolved, ItemRef::Table(table_idx))?;
+                    Ok(Table::Imported {
+                        from,
+                        index: index.as_table().unwrap(),
+                    })
+                }
+            })
     }
 
     fn allocate_code<Mod, Ctx>(
@@ -358,9 +660,21 @@ impl<Area: MemoryArea> Instance<Area> {
 
     // ———————————————————————————————— Helpers ————————————————————————————————— //
 
-    /// Returns the index of the start function, if any.
-    pub fn get_start(&self) -> Option<FuncIndex> {
-        self.start.clone()
+    /// Returns the index of the start function the first time it is called, `None` afterward or
+    /// if the instance has no start function.
+    ///
+    /// The embedder is responsible for running the start function, which must be run exactly once
+    /// and before any other function of the instance. The memories and tables are initialized
+    /// during instantiation, the start function can therefore be run as soon as the instance is
+    /// created. If the start function traps the instance must be discarded, and
+    /// `ModuleError::StartTrapped` reported.
+    pub fn take_start(&self) -> Option<FuncIndex> {
+        let start = self.start?;
+        if self.started.swap(true, Ordering::SeqCst) {
+            None
+        } else {
+            Some(start)
+        }
     }
 
     /// Returns the address of the given function.
@@ -391,6 +705,11 @@ impl<Area: MemoryArea> Instance<Area> {
         &self.types[*ty]
     }
 
+    /// Returns the items exported by the instance, by name.
+    pub fn public_items(&self) -> &BTreeMap<String, ItemRef> {
+        &self.items
+    }
+
     /// Returns the index of a function exported by the instance.
     pub fn get_func_index_by_name<'a, 'b>(&'a self, name: &'b str) -> Option<FuncIndex> {
         match self.items.get(name) {
@@ -418,47 +737,252 @@ impl<Area: MemoryArea> Instance<Area> {
     }
 
     /// Returns a table exported by the instance from it's exported name.
-    pub fn get_table_by_name<'a, 'b>(&'a self, name: &'b str) -> Option<&Box<[u64]>> {
+    ///
+    /// Handle tables are managed by the runtime and can't be accessed that way.
+    /// For funcref tables, only the function pointers are returned.
+    pub fn get_table_by_name<'a, 'b>(&'a self, name: &'b str) -> Option<&[u64]> {
         let index = match self.items.get(name)? {
             ItemRef::Table(idx) => *idx,
             _ => return None,
         };
-        Some(self.get_table(index))
+        self.get_table(index)
+    }
+
+    /// Inserts a handle into the instance handle table, returns the index of the handle.
+    ///
+    /// Returns `None` if the instance has no handle table, if the table is full or if the handle is
+    /// null.
+    pub fn insert_handle(&self, handle: ExternHandle) -> Option<u32> {
+        self.get_handle_table()?.insert(handle)
+    }
+
+    /// Returns the handle at the given index of the instance handle table, if any.
+    pub fn get_handle(&self, index: u32) -> Option<ExternHandle> {
+        self.get_handle_table()?.get(index)
+    }
+
+    /// Removes the handle at the given index of the instance handle table and returns it, if any.
+    pub fn remove_handle(&self, index: u32) -> Option<ExternHandle> {
+        self.get_handle_table()?.remove(index)
+    }
+
+    /// Returns the current size of a heap, in pages.
+    /// Imported heaps are resolved through recursive lookups.
+    pub fn memory_size(&self, index: HeapIndex) -> u32 {
+        match &self.heaps[index] {
+            Heap::Owned { .. } => {
+                (self.heap_bounds[index.index()].load(Ordering::SeqCst) / PAGE_SIZE) as u32
+            }
+            Heap::Imported { from, index } => {
+                let instance = &self.imports[*from];
+                instance.memory_size(*index)
+            }
+        }
+    }
+
+    /// Sets the current size of a heap, in pages.
+    /// Imported heaps are resolved through recursive lookups.
+    ///
+    /// Until `memory.grow` is supported this is how the embedder grows a heap, the new size is
+    /// observed by all the instances importing it.
+    ///
+    /// # Safety
+    ///
+    /// The memory area of the heap must span at least `pages` pages.
+    pub unsafe fn set_memory_size(&self, index: HeapIndex, pages: u32) {
+        match &self.heaps[index] {
+            Heap::Owned { .. } => {
+                let bytes = pages as usize * PAGE_SIZE;
+                self.heap_bounds[index.index()].store(bytes, Ordering::SeqCst);
+            }
+            Heap::Imported { from, index } => {
+                let instance = &self.imports[*from];
+                instance.set_memory_size(*index, pages);
+            }
+        }
+    }
+
+    /// Returns the number of bytes of memory owned by the instance: its own heaps and its code.
+    ///
+    /// Imported heaps are not counted, and the code is counted once per instance even if it is
+    /// shared (e.g. with forks).
+    pub fn memory_footprint(&self) -> usize {
+        // The bounds of imported heaps are 0
+        let heaps: usize = self
+            .heap_bounds
+            .iter()
+            .map(|bound| bound.load(Ordering::SeqCst))
+            .sum();
+        heaps + self.code_size
+    }
+
+    /// Returns the memory area of a heap, or `None` if the heap does not exist.
+    /// Imported heaps are resolved through recursive lookups.
+    pub fn get_heap_area(&self, index: HeapIndex) -> Option<&Area> {
+        match self.heaps.get(index)? {
+            Heap::Owned { memory, .. } => Some(memory),
+            Heap::Imported { from, index } => self.imports[*from].get_heap_area(*index),
+        }
+    }
+
+    /// Returns the address and current size (in bytes) of a heap, or `None` if the heap does not
+    /// exist.
+    /// Imported heaps are resolved through recursive lookups.
+    pub fn get_heap_ptr_and_size(&self, index: HeapIndex) -> Option<(*mut u8, usize)> {
+        self.heaps.get(index)?;
+        let ptr = self.get_heap_ptr(index) as *mut u8;
+        let size = self.memory_size(index) as usize * PAGE_SIZE;
+        Some((ptr, size))
+    }
+
+    /// Records a call from the embedder into the instance, which executed for `ticks`.
+    pub fn record_call(&self, ticks: u64) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.ticks.fetch_add(ticks, Ordering::Relaxed);
+    }
+
+    /// Marks the instance as running a call from the embedder, returns false if it already is.
+    ///
+    /// Embedders must not call into an instance which is running, for instance from one of its
+    /// imports: guest code is not expected to be re-entered.
+    pub fn try_enter(&self) -> bool {
+        !self.running.swap(true, Ordering::SeqCst)
+    }
+
+    /// Marks the call from the embedder as completed, see `try_enter`.
+    pub fn leave(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns true if a call from the embedder is executing the instance.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Requests the code of the instance to call the preemption handler of the runtime, at the
+    /// next loop header or call return. Returns false if the code does not check for preemption
+    /// requests (see `VMContextLayout::preemption_checks`) or the runtime has no handler.
+    ///
+    /// The request is lock-free, and can be issued from an interrupt handler.
+    pub fn request_preemption(&self) -> bool {
+        self.vmctx.set_preemption_flag(true)
+    }
+
+    /// Withdraws a pending preemption request, if any.
+    pub fn clear_preemption(&self) {
+        self.vmctx.set_preemption_flag(false);
+    }
+
+    /// Returns the execution metrics of the instance.
+    pub fn metrics(&self) -> InstanceMetrics {
+        let imported_calls = self
+            .funcs
+            .iter()
+            .filter(|(_, func)| matches!(func, Func::Imported { .. }))
+            .filter_map(|(idx, _)| Some((idx, self.vmctx.read_counter(idx).ok()?)))
+            .collect();
+        InstanceMetrics {
+            calls: self.calls.load(Ordering::Relaxed),
+            ticks: self.ticks.load(Ordering::Relaxed),
+            imported_calls,
+        }
+    }
+
+    /// Returns true if the address is within the code of the instance or of the instances it
+    /// imports, transitively, that is the code a call into the instance may execute.
+    pub fn reaches_code(&self, addr: usize) -> bool {
+        let code = self.code.as_ptr() as usize;
+        if addr >= code && addr < code + self.code_size {
+            return true;
+        }
+        self.imports
+            .iter()
+            .any(|(_, instance)| instance.reaches_code(addr))
+    }
+
+    /// Returns the cause and location of a trap raised by the instruction at the given address, or
+    /// `None` if the address is not within the code of the instance.
+    ///
+    /// The cause is `TrapCode::Unknown` if the instruction is not a known trap site, such as an
+    /// access to an unmapped page outside of any heap.
+    pub fn locate_trap(&self, addr: usize) -> Option<(TrapCode, WasmLocation)> {
+        let code = self.code.as_ptr() as usize;
+        if addr < code || addr >= code + self.code_size {
+            return None;
+        }
+        let offset = (addr - code) as u32;
+
+        if let Ok(idx) = self
+            .trap_sites
+            .binary_search_by_key(&offset, |site| site.offset)
+        {
+            let site = &self.trap_sites[idx];
+            return Some((site.code, site.location));
+        }
+
+        // Not a trap site, look for the function containing the instruction
+        let func = self
+            .funcs
+            .iter()
+            .filter_map(|(idx, func)| match func {
+                Func::Owned { offset: start, .. } if *start <= offset => Some((*start, idx)),
+                _ => None,
+            })
+            .max_by_key(|(start, _)| *start)?
+            .1;
+        Some((TrapCode::Unknown, WasmLocation { func, offset: None }))
     }
 
     pub fn get_vmctx_ptr(&self) -> *const u8 {
         self.vmctx.as_ptr()
     }
 
+    /// Writes the content of the VMContext, for diagnostic purposes.
+    pub fn dump_vmctx(&self, writer: &mut impl core::fmt::Write) -> core::fmt::Result {
+        self.vmctx.dump(writer)
+    }
+
+    /// Applies the data segments to a heap, which is already zeroed by the runtime (see
+    /// `Runtime::alloc_heap`).
+    ///
+    /// Segments are checked against `size`, the size of the heap as observed by the guest, before
+    /// any of them is applied: a segment which does not fit fails the instantiation.
     fn initialize_heap(
         heap: &mut [u8],
+        size: usize,
         idx: HeapIndex,
         segments: &[DataSegment],
     ) -> ModuleResult<()> {
-        // Zero out the memory
-        heap.fill(0);
+        // Check all the segments before applying any of them
+        let segments = segments.iter().filter(|segment| segment.heap_index == idx);
+        for segment in segments.clone() {
+            Self::segment_range(segment, size)?;
+        }
 
         // Initialize with data segments
         for segment in segments {
-            // Skip segments for other heaps
-            if segment.heap_index != idx {
-                continue;
-            }
-
-            // Copy data
-            let start = if let Some(_glob_idx) = segment.base {
-                // TODO: handle globals
-                todo!("Base are not yet supported for data segments");
-            } else {
-                segment.offset as usize
-            };
-            let end = start + segment.data.len();
-            heap[start..end].copy_from_slice(&segment.data);
+            let range = Self::segment_range(segment, size)?;
+            heap[range].copy_from_slice(&segment.data);
         }
 
         Ok(())
     }
 
+    /// Returns the range covered by a data segment within a heap of `size` bytes.
+    fn segment_range(segment: &DataSegment, size: usize) -> ModuleResult<Range<usize>> {
+        let start = if let Some(_glob_idx) = segment.base {
+            // TODO: handle globals
+            return Err(ModuleError::Unsupported);
+        } else {
+            usize::try_from(segment.offset).map_err(|_| ModuleError::DataSegmentOutOfBounds)?
+        };
+        let end = start
+            .checked_add(segment.data.len())
+            .filter(|end| *end <= size)
+            .ok_or(ModuleError::DataSegmentOutOfBounds)?;
+        Ok(start..end)
+    }
+
     fn relocate(
         code: &mut [u8],
         relocs: &[Reloc],
@@ -481,26 +1005,33 @@ impl<Area: MemoryArea> Instance<Area> {
             let value = base + reloc.addend;
 
             let offset = reloc.offset as usize;
+            let pc = code.as_ptr().wrapping_add(offset) as i64;
             match reloc.kind {
-                RelocKind::Abs4 => todo!(),
+                RelocKind::Abs4 => {
+                    let value =
+                        u32::try_from(value).map_err(|_| ModuleError::FailedToInstantiate)?;
+                    code[offset..][..4].copy_from_slice(&value.to_le_bytes());
+                }
                 RelocKind::Abs8 => {
                     code[offset..][..8].copy_from_slice(&value.to_le_bytes());
                 }
-                RelocKind::X86PCRel4 => todo!(),
-                RelocKind::X86CallPCRel4 => {
-                    let pc = code.as_ptr().wrapping_add(reloc.offset as usize) as i64;
-                    let pc_relative = (value - pc) as i32;
+                // There is no procedure linkage table, PLT calls target the function directly.
+                RelocKind::X86PCRel4 | RelocKind::X86CallPCRel4 | RelocKind::X86CallPLTRel4 => {
+                    let pc_relative =
+                        i32::try_from(value - pc).map_err(|_| ModuleError::FailedToInstantiate)?;
                     code[offset..][..4].copy_from_slice(&pc_relative.to_le_bytes());
                 }
-                RelocKind::X86CallPLTRel4 => todo!(),
-                RelocKind::X86GOTPCRel4 => todo!(),
-                RelocKind::Arm32Call => todo!(),
-                RelocKind::Arm64Call => todo!(),
-                RelocKind::S390xPCRel32Dbl => todo!(),
-                RelocKind::ElfX86_64TlsGd => todo!(),
-                RelocKind::MachOX86_64Tlv => todo!(),
-                RelocKind::Aarch64TlsGdAdrPage21 => todo!(),
-                RelocKind::Aarch64TlsGdAddLo12Nc => todo!(),
+                // The global offset table is built by the compiler, which replaces GOT relocations
+                // by relocations of the table entries.
+                RelocKind::X86GOTPCRel4 => return Err(ModuleError::FailedToInstantiate),
+                // Other architectures and thread local storage are not supported.
+                RelocKind::Arm32Call
+                | RelocKind::Arm64Call
+                | RelocKind::S390xPCRel32Dbl
+                | RelocKind::ElfX86_64TlsGd
+                | RelocKind::MachOX86_64Tlv
+                | RelocKind::Aarch64TlsGdAdrPage21
+                | RelocKind::Aarch64TlsGdAddLo12Nc => return Err(ModuleError::FailedToInstantiate),
             }
         }
 
@@ -520,11 +1051,28 @@ impl<Area: MemoryArea> Instance<Area> {
         }
     }
 
+    /// Returns the address, signature identifier and VMContext of a function, as stored in
+    /// function tables.
+    /// Imported functions are resolved through recursive lookups.
+    fn get_func_ref(&self, func: FuncIndex) -> (*const u8, u64, *const u8) {
+        match &self.funcs[func] {
+            Func::Imported { from, index, .. } => {
+                let instance = &self.imports[*from];
+                instance.get_func_ref(*index)
+            }
+            owned_or_native => {
+                let ty = &self.types[owned_or_native.ty_index()];
+                let signature = ty.signature_id().unwrap_or(NULL_SIGNATURE);
+                (self.get_func_ptr(func), signature, self.vmctx.as_ptr())
+            }
+        }
+    }
+
     /// Returns the address of a heap.
     /// Imported heaps are resolved through recursive lookups.
     fn get_heap_ptr(&self, heap: HeapIndex) -> *const u8 {
         match &self.heaps[heap] {
-            Heap::Owned { memory } => memory.as_ptr(),
+            Heap::Owned { memory, .. } => memory.as_ptr(),
             Heap::Imported { from, index } => {
                 let instance = &self.imports[*from];
                 instance.get_heap_ptr(*index)
@@ -532,11 +1080,13 @@ impl<Area: MemoryArea> Instance<Area> {
         }
     }
 
-    /// Returns a table.
+    /// Returns a table, or `None` for handle tables.
     /// Imported tables are resolved through recursive lookups.
-    fn get_table(&self, table: TableIndex) -> &Box<[u64]> {
+    fn get_table(&self, table: TableIndex) -> Option<&[u64]> {
         match &self.tables[table] {
-            Table::Owned(table) => table,
+            Table::Owned(table) => Some(table),
+            Table::Funcs(table) => Some(table.entries()),
+            Table::Handles(_) => None,
             Table::Imported { from, index } => {
                 let instance = &self.imports[*from];
                 instance.get_table(*index)
@@ -544,14 +1094,98 @@ impl<Area: MemoryArea> Instance<Area> {
         }
     }
 
-    /// Returns the address of a table.
+    /// Returns a funcref table, or `None` for externref tables.
     /// Imported tables are resolved through recursive lookups.
-    ///
-    /// TODO: for now we only support static bounds, i.e. tables can't be resized. Ideally, the
-    /// bound should be a pointer to the location to which the bound is actually stored.
-    fn get_table_ptr_and_bound(&self, table: TableIndex) -> (*const u8, usize) {
-        let table = self.get_table(table);
-        (table.as_ptr() as *const u8, table.len())
+    fn get_func_table(&self, table: TableIndex) -> Option<&FuncTable> {
+        match &self.tables[table] {
+            Table::Funcs(table) => Some(table),
+            Table::Owned(_) | Table::Handles(_) => None,
+            Table::Imported { from, index } => {
+                let instance = &self.imports[*from];
+                instance.get_func_table(*index)
+            }
+        }
+    }
+
+    /// Returns the type of the elements of a table.
+    /// Imported tables are resolved through recursive lookups.
+    fn get_table_type(&self, table: TableIndex) -> RefType {
+        match &self.tables[table] {
+            Table::Funcs(_) => RefType::FuncRef,
+            Table::Owned(_) | Table::Handles(_) => RefType::ExternRef,
+            Table::Imported { from, index } => {
+                let instance = &self.imports[*from];
+                instance.get_table_type(*index)
+            }
+        }
+    }
+
+    /// Returns the handle table of the instance, if any.
+    fn get_handle_table(&self) -> Option<&HandleTable> {
+        match &self.tables[self.handles?] {
+            Table::Handles(handles) => Some(handles),
+            _ => None,
+        }
+    }
+
+    /// Returns the initial bound of each heap, in bytes.
+    fn heap_bounds<Mod: Module>(module: &Mod) -> Box<[AtomicUsize]> {
+        module
+            .heaps()
+            .values()
+            .map(|heap| match heap {
+                HeapInfo::Owned { min_size, .. } => *min_size as usize * PAGE_SIZE,
+                HeapInfo::Imported { .. } => 0,
+            })
+            .map(AtomicUsize::new)
+            .collect()
+    }
+
+    /// Returns the address of a heap's bound.
+    /// Imported heaps are resolved through recursive lookups.
+    fn get_heap_bound_ptr(&self, heap: HeapIndex) -> *const usize {
+        match &self.heaps[heap] {
+            // Atomics have the same in-memory representation as the underlying integer.
+            Heap::Owned { .. } => {
+                &self.heap_bounds[heap.index()] as *const AtomicUsize as *const usize
+            }
+            Heap::Imported { from, index } => {
+                let instance = &self.imports[*from];
+                instance.get_heap_bound_ptr(*index)
+            }
+        }
+    }
+
+    /// Returns the initial bound of each table, in number of elements.
+    fn table_bounds(tables: &FrozenMap<TableIndex, Table>) -> Box<[AtomicU32]> {
+        tables
+            .values()
+            .map(|table| {
+                let len = match table {
+                    Table::Owned(table) => table.len(),
+                    Table::Funcs(table) => table.len(),
+                    Table::Handles(handles) => handles.len(),
+                    Table::Imported { .. } => 0,
+                };
+                AtomicU32::new(len as u32)
+            })
+            .collect()
+    }
+
+    /// Returns the address of a table and the address of its bound.
+    /// Imported tables are resolved through recursive lookups.
+    fn get_table_ptr_and_bound_ptr(&self, table: TableIndex) -> (*const u8, *const u32) {
+        // Atomics have the same in-memory representation as the underlying integer.
+        let bound_ptr = &self.table_bounds[table.index()] as *const AtomicU32 as *const u32;
+        match &self.tables[table] {
+            Table::Owned(table) => (table.as_ptr() as *const u8, bound_ptr),
+            Table::Funcs(table) => (table.as_ptr(), bound_ptr),
+            Table::Handles(handles) => (handles.as_ptr(), bound_ptr),
+            Table::Imported { from, index } => {
+                let instance = &self.imports[*from];
+                instance.get_table_ptr_and_bound_ptr(*index)
+            }
+        }
     }
 
     /// Returns the address of a global.
@@ -569,50 +1203,98 @@ impl<Area: MemoryArea> Instance<Area> {
     /// Initialize the VMContext struct.
     /// This function **must** be called before runing any code within the instance, otherwise the
     /// execution leads to undefined behavior.
-    fn init_vmctx(&mut self) {
+    ///
+    /// Fails if the VMContext layout of the module does not match its items.
+    fn init_vmctx(&mut self) -> ModuleResult<()> {
+        self.try_init_vmctx()
+            .map_err(|_| ModuleError::FailedToInstantiate)
+    }
+
+    fn try_init_vmctx(&mut self) -> Result<(), VMContextError> {
         for idx in self.heaps.keys() {
             let ptr = self.get_heap_ptr(idx);
-            self.vmctx.set_heap(ptr, idx);
+            let bound_ptr = self.get_heap_bound_ptr(idx);
+            self.vmctx.try_set_heap(ptr, bound_ptr, idx)?;
         }
         for idx in self.tables.keys() {
-            let (ptr, bound) = self.get_table_ptr_and_bound(idx);
-            self.vmctx.set_table(ptr, bound, idx);
+            let (ptr, bound_ptr) = self.get_table_ptr_and_bound_ptr(idx);
+            self.vmctx.try_set_table(ptr, bound_ptr, idx)?;
         }
-        for idx in self.funcs.keys() {
+        for (idx, func) in self.funcs.iter() {
+            // Only imported and native functions have a slot in the VMContext
+            if let Func::Owned { .. } = func {
+                continue;
+            }
             let ptr = self.get_func_ptr(idx);
-            self.vmctx.set_func(ptr, idx);
+            self.vmctx.try_set_func(ptr, idx)?;
         }
         for (idx, import) in self.imports.iter_mut() {
             let ptr = import.vmctx.as_ptr();
-            self.vmctx.set_import(ptr, idx);
+            self.vmctx.try_set_import(ptr, idx)?;
         }
         for (idx, glob) in self.globs.iter() {
             match glob {
-                Glob::Owned { init } => self.vmctx.set_glob_value(*init, idx),
-                Glob::Imported { .. } => self.vmctx.set_glob_ptr(self.get_glob_ptr(idx), idx),
+                Glob::Owned { init } => self.vmctx.try_set_glob_value(*init, idx)?,
+                Glob::Imported { .. } => {
+                    self.vmctx.try_set_glob_ptr(self.get_glob_ptr(idx), idx)?
+                }
             }
         }
+        Ok(())
     }
 
-    fn init_tables<Mod>(&mut self, module: &Mod)
+    /// Apply the table segments to the tables owned by the instance.
+    ///
+    /// Segments are applied in order, if a segment does not fit in its table the instantiation
+    /// fails. Segments targeting imported tables are rejected with
+    /// `ModuleError::ImportedTableSegment`.
+    fn init_tables<Mod>(&self, module: &Mod) -> ModuleResult<()>
     where
         Mod: Module,
     {
         for segment in module.table_segments() {
             let start = if let Some(_glob_idx) = segment.base {
                 // TODO: handle globals
-                todo!("Base are not yet supported for table segments");
+                return Err(ModuleError::Unsupported);
             } else {
                 segment.offset as usize
             };
+
+            // Segments only contain function references
+            let ty = match &module.tables()[segment.table_index] {
+                crate::TableInfo::Owned { ty, .. }
+                | crate::TableInfo::Imported { ty, .. }
+                | crate::TableInfo::Native { ty, .. } => *ty,
+                crate::TableInfo::Handles { .. } => RefType::ExternRef,
+            };
+            if ty != RefType::FuncRef {
+                return Err(ModuleError::TypeError);
+            }
+            // The exporter of the table could outlive this instance
+            if let Table::Imported { .. } = self.tables[segment.table_index] {
+                return Err(ModuleError::ImportedTableSegment);
+            }
+            let table = self
+                .get_func_table(segment.table_index)
+                .ok_or(ModuleError::TypeError)?;
+            let end = start
+                .checked_add(segment.elements.len())
+                .ok_or(ModuleError::FailedToInstantiate)?;
+            if end > table.len() {
+                return Err(ModuleError::FailedToInstantiate);
+            }
+
             for (entry_idx, func_idx) in (start..).zip(segment.elements.iter()) {
-                let ptr = self.get_func_ptr(*func_idx);
-                match &mut self.tables[segment.table_index] {
-                    Table::Owned(table) => table[entry_idx] = ptr as u64,
-                    Table::Imported { .. } => panic!("Can't initialize imported tables"),
+                let (ptr, signature, vmctx) = match func_idx {
+                    Some(func_idx) => self.get_func_ref(*func_idx),
+                    None => (core::ptr::null(), NULL_SIGNATURE, core::ptr::null()),
                 };
+                // SAFETY: the entry is within the bounds checked above, and the table is owned by
+                // this instance which is not running yet.
+                unsafe { table.write(entry_idx, ptr as u64, signature, vmctx as u64) };
             }
         }
+        Ok(())
     }
 
     /// Returns a function corresponding to the item reference, if that item is a function.
@@ -635,3 +1317,214 @@ impl<Area: MemoryArea> Instance<Area> {
         }
     }
 }
+
+// ————————————————————————————— Module Images —————————————————————————————— //
+
+/// The minimum size of the data segments of a heap for the heap to be part of a module image.
+///
+/// Smaller segments are cheaper to copy than to share copy-on-write.
+pub const MODULE_IMAGE_THRESHOLD: usize = 64 * 1024;
+
+/// The initialized heaps of a module, shared by the instances of that module.
+///
+/// Heaps with large data segments (e.g. embedded assets) are initialized once, and the instances
+/// created from the image get a fork of these heaps rather than a copy of the segments (see
+/// `Runtime::fork_heap`). With a copy-on-write runtime the pages are shared by the instances until
+/// written to. The heaps of the image are never exposed, and therefore never mutated.
+pub struct ModuleImage<Area> {
+    heaps: Vec<(HeapIndex, Area)>,
+}
+
+impl<Area: MemoryArea> ModuleImage<Area> {
+    /// Creates the image of a module, the heaps are allocated with the default allocation policy.
+    pub fn new<Mod, Ctx>(
+        module: &Mod,
+        runtime: &impl Runtime<MemoryArea = Area, Context = Ctx>,
+    ) -> ModuleResult<Self>
+    where
+        Mod: Module,
+    {
+        let mut ctx = runtime.create_context(&AllocPolicy::default());
+        let mut heaps = Vec::new();
+        for (heap_idx, heap_info) in module.heaps().iter() {
+            let (min_size, kind) = match heap_info {
+                HeapInfo::Owned { min_size, kind } => (*min_size, *kind),
+                HeapInfo::Imported { .. } => continue,
+            };
+            let segments_size: usize = module
+                .data_segments()
+                .iter()
+                .filter(|segment| segment.heap_index == heap_idx)
+                .map(|segment| segment.data.len())
+                .sum();
+            if segments_size < MODULE_IMAGE_THRESHOLD {
+                continue;
+            }
+
+            let size = (min_size as usize) * PAGE_SIZE;
+            let mut initialized = false;
+            let initialize = |heap: &mut [u8]| {
+                if heap.len() < size {
+                    return Err(ModuleError::FailedToInstantiate);
+                }
+                initialized = true;
+                Instance::<Area>::initialize_heap(heap, size, heap_idx, module.data_segments())
+            };
+            let area = runtime.alloc_heap(size, kind, initialize, &mut ctx)?;
+            if !initialized {
+                return Err(ModuleError::FailedToInstantiate);
+            }
+            heaps.push((heap_idx, area));
+        }
+        runtime.commit(ctx);
+        Ok(Self { heaps })
+    }
+
+    /// Returns true if the image holds no heap, instantiating from an empty image is equivalent to
+    /// a regular instantiation.
+    pub fn is_empty(&self) -> bool {
+        self.heaps.is_empty()
+    }
+
+    /// Returns the initialized heap, if it is part of the image.
+    fn get(&self, heap_idx: HeapIndex) -> Option<&Area> {
+        self.heaps
+            .iter()
+            .find(|(idx, _)| *idx == heap_idx)
+            .map(|(_, area)| area)
+    }
+}
+
+// ——————————————————————————————— Snapshots ———————————————————————————————— //
+
+/// The shortest run of zeroes splitting the content of a heap into distinct data segments.
+///
+/// Each segment has a fixed cost, shorter runs of zeroes are kept within the segments.
+const SNAPSHOT_SEGMENT_GAP: usize = 64;
+
+/// The state of the heaps and globals of an instance, from which a pre-initialized module can be
+/// created (see `WasmModule::pre_initialized`).
+///
+/// Only the items owned by the instance are captured: imported items, tables and handles are not
+/// part of the snapshot.
+pub struct InstanceSnapshot {
+    pub(crate) heaps: Vec<HeapSnapshot>,
+    pub(crate) globs: Vec<(GlobIndex, GlobInit)>,
+    /// Whether the start function was handed out for execution before the snapshot.
+    pub(crate) started: bool,
+}
+
+/// The content of a heap, as data segments.
+pub(crate) struct HeapSnapshot {
+    pub(crate) index: HeapIndex,
+    /// The size of the heap, in pages.
+    pub(crate) size: u32,
+    pub(crate) segments: Vec<DataSegment>,
+}
+
+impl InstanceSnapshot {
+    /// Returns the total size of the data segments of the snapshot, in bytes.
+    pub fn data_size(&self) -> usize {
+        self.heaps
+            .iter()
+            .flat_map(|heap| heap.segments.iter())
+            .map(|segment| segment.data.len())
+            .sum()
+    }
+}
+
+impl<Area: MemoryArea> Instance<Area> {
+    /// Captures the content of the heaps and the value of the globals owned by the instance,
+    /// typically once its initialization function returned.
+    ///
+    /// The instance must not be executing.
+    pub fn snapshot(&self) -> ModuleResult<InstanceSnapshot> {
+        if self.is_running() {
+            return Err(ModuleError::RuntimeError);
+        }
+
+        let mut heaps = Vec::new();
+        for (index, heap) in self.heaps.iter() {
+            if let Heap::Owned { memory } = heap {
+                let size = self.memory_size(index);
+                let len = size as usize * PAGE_SIZE;
+                // SAFETY: the heap is `size` pages long, and the instance is not executing.
+                let bytes = unsafe { core::slice::from_raw_parts(memory.as_ptr(), len) };
+                heaps.push(HeapSnapshot {
+                    index,
+                    size,
+                    segments: snapshot_segments(index, bytes),
+                });
+            }
+        }
+        let globs = self
+            .globs
+            .iter()
+            .filter_map(|(idx, glob)| match glob {
+                Glob::Owned { init } => Some((idx, self.vmctx.read_glob_value(*init, idx))),
+                Glob::Imported { .. } => None,
+            })
+            .collect();
+
+        Ok(InstanceSnapshot {
+            heaps,
+            globs,
+            started: self.started.load(Ordering::SeqCst),
+        })
+    }
+}
+
+/// Splits the content of a heap into data segments, leaving out the long runs of zeroes.
+fn snapshot_segments(heap_index: HeapIndex, bytes: &[u8]) -> Vec<DataSegment> {
+    let mut segments = Vec::new();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == 0 {
+            idx += 1;
+            continue;
+        }
+
+        // Extend the segment until the next long enough run of zeroes
+        let start = idx;
+        let mut end = idx;
+        while idx < bytes.len() && idx - end < SNAPSHOT_SEGMENT_GAP {
+            if bytes[idx] != 0 {
+                end = idx + 1;
+            }
+            idx += 1;
+        }
+        segments.push(DataSegment {
+            heap_index,
+            base: None,
+            offset: start as u64,
+            data: bytes[start..end].to_vec(),
+        });
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn snapshot_segments_skip_zeroes() {
+        let heap = HeapIndex::from_u32(0);
+        let mut bytes = vec![0; 1024];
+        bytes[10] = 1;
+        bytes[12] = 2;
+        bytes[10 + SNAPSHOT_SEGMENT_GAP] = 3;
+        bytes[1000] = 4;
+
+        let segments = snapshot_segments(heap, &bytes);
+        let layout: Vec<(u64, usize)> = segments
+            .iter()
+            .map(|segment| (segment.offset, segment.data.len()))
+            .collect();
+        assert_eq!(layout, [(10, SNAPSHOT_SEGMENT_GAP + 1), (1000, 1)]);
+        assert_eq!(segments[0].data[2], 2);
+        assert_eq!(segments[1].data, [4]);
+        assert!(snapshot_segments(heap, &[0; 256]).is_empty());
+    }
+}
@@ -3,6 +3,7 @@
 
 extern crate alloc;
 
+mod bundle;
 mod instances;
 mod modules;
 mod traits;
@@ -10,10 +11,18 @@ mod vmctx;
 mod types;
 mod funcs;
 mod abi;
+mod handles;
+mod tables;
+mod values;
 
+pub use bundle::*;
 pub use instances::*;
 pub use modules::*;
 pub use traits::*;
 pub use types::*;
 pub use funcs::*;
 pub use abi::*;
+pub use handles::*;
+pub use tables::*;
+pub use values::*;
+pub use vmctx::{VMContext, VMContextError, VMContextField};
@@ -1,15 +1,19 @@
 use crate::alloc::string::{String, ToString};
+use crate::alloc::vec;
 use crate::alloc::vec::Vec;
 
 use crate::abi::{ExternRef64, WasmParams, WasmResults, WasmType};
-use crate::funcs::NativeFunc;
+use crate::funcs::{NativeFunc, NativeFuncRef};
+use crate::instances::InstanceSnapshot;
+use crate::tables::{FuncTable, NULL_SIGNATURE};
 use crate::traits::{
-    DataSegment, FuncIndex, FuncInfo, FuncPtr, GlobIndex, GlobInfo, HeapIndex, HeapInfo,
-    ImportIndex, Reloc, TableIndex, TableInfo, TableSegment,
+    CpuFeatures, DataSegment, ExceptionHandler, FuncIndex, FuncInfo, FuncPtr, GlobIndex, GlobInfo,
+    HeapIndex, HeapInfo, Import, ImportIndex, ModuleError, ModuleResult, Reloc, StackMap,
+    TableIndex, TableInfo, TableSegment, TrapSite,
 };
 use crate::traits::{ItemRef, Module, VMContextLayout};
 use crate::{FuncType, RefType, TypeIndex};
-use collections::{FrozenMap, HashMap, PrimaryMap};
+use collections::{BTreeMap, FrozenMap, HashMap, PrimaryMap};
 
 // —————————————————————————————————— VMCS —————————————————————————————————— //
 
@@ -20,6 +24,8 @@ pub struct SimpleVMContextLayout {
     tables: Vec<TableIndex>,
     globs: Vec<GlobIndex>,
     imports: Vec<ImportIndex>,
+    counters: Vec<FuncIndex>,
+    preemption_checks: bool,
 }
 
 impl SimpleVMContextLayout {
@@ -36,8 +42,22 @@ impl SimpleVMContextLayout {
             tables,
             globs,
             imports,
+            counters: Vec::new(),
+            preemption_checks: false,
         }
     }
+
+    /// Adds a call counter for each of the given imported functions.
+    pub fn with_counters(mut self, counters: Vec<FuncIndex>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// Reserves the preemption flag and handler, for code checking for preemption requests.
+    pub fn with_preemption_checks(mut self, preemption_checks: bool) -> Self {
+        self.preemption_checks = preemption_checks;
+        self
+    }
 }
 
 impl VMContextLayout for SimpleVMContextLayout {
@@ -60,21 +80,93 @@ impl VMContextLayout for SimpleVMContextLayout {
     fn imports(&self) -> &[ImportIndex] {
         &self.imports
     }
+
+    fn counters(&self) -> &[FuncIndex] {
+        &self.counters
+    }
+
+    fn preemption_checks(&self) -> bool {
+        self.preemption_checks
+    }
 }
 
 // —————————————————————————————— Wasm Module ——————————————————————————————— //
 
+/// Descriptive metadata of a module, which does not affect its execution.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleMetadata {
+    /// The name of the module, from the `name` custom section.
+    pub name: Option<String>,
+    /// The tools which produced the module, as `name version`, from the `producers` custom
+    /// section.
+    pub producers: Vec<String>,
+    /// The hash of the module bytecode, see `content_hash`.
+    pub hash: u64,
+    /// The items exported under several names, ordered by their first name.
+    pub aliases: Vec<ExportAliases>,
+}
+
+/// The names of an item exported under several names, in lexicographic order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportAliases {
+    pub item: ItemRef,
+    pub names: Vec<String>,
+}
+
+impl ExportAliases {
+    /// Groups the exported names by item, and returns the items with more than one name.
+    fn collect(exported_names: &BTreeMap<String, ItemRef>) -> Vec<Self> {
+        let mut aliases: Vec<Self> = Vec::new();
+        let mut positions: HashMap<ItemRef, usize> = HashMap::new();
+        for (name, item) in exported_names {
+            match positions.get(item) {
+                Some(&idx) => aliases[idx].names.push(name.clone()),
+                None => {
+                    positions.insert(*item, aliases.len());
+                    aliases.push(Self {
+                        item: *item,
+                        names: vec![name.clone()],
+                    });
+                }
+            }
+        }
+        aliases.retain(|aliases| aliases.names.len() > 1);
+        aliases
+    }
+}
+
+impl ModuleMetadata {
+    /// Returns the FNV-1a hash of a module bytecode.
+    ///
+    /// The hash identifies a module for display purposes, it is not resistant to collisions.
+    pub fn content_hash(bytecode: &[u8]) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x100_0000_01b3;
+
+        bytecode.iter().fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(PRIME)
+        })
+    }
+}
+
 pub struct ModuleInfo {
-    exported_items: HashMap<String, ItemRef>,
+    exported_items: BTreeMap<String, ItemRef>,
     funcs: FrozenMap<FuncIndex, FuncInfo>,
     types: FrozenMap<TypeIndex, FuncType>,
     heaps: FrozenMap<HeapIndex, HeapInfo>,
     tables: FrozenMap<TableIndex, TableInfo>,
     globs: FrozenMap<GlobIndex, GlobInfo>,
-    imports: FrozenMap<ImportIndex, String>,
+    import_modules: FrozenMap<ImportIndex, String>,
+    imports: Vec<Import>,
     segments: Vec<DataSegment>,
     elements: Vec<TableSegment>,
     start: Option<FuncIndex>,
+    cpu_features: CpuFeatures,
+    metadata: ModuleMetadata,
+    /// Whether the code counts the calls to imported functions.
+    call_counters: bool,
+    /// Whether the code checks for preemption requests.
+    preemption_checks: bool,
 }
 
 impl ModuleInfo {
@@ -84,25 +176,52 @@ impl ModuleInfo {
         heaps: FrozenMap<HeapIndex, HeapInfo>,
         tables: FrozenMap<TableIndex, TableInfo>,
         globs: FrozenMap<GlobIndex, GlobInfo>,
-        imports: FrozenMap<ImportIndex, String>,
+        import_modules: FrozenMap<ImportIndex, String>,
+        imports: Vec<Import>,
         segments: Vec<DataSegment>,
         elements: Vec<TableSegment>,
         start: Option<FuncIndex>,
     ) -> Self {
         Self {
-            exported_items: HashMap::new(),
+            exported_items: BTreeMap::new(),
             funcs,
             types,
             heaps,
             tables,
             globs,
+            import_modules,
             imports,
             segments,
             elements,
             start,
+            cpu_features: CpuFeatures::empty(),
+            metadata: ModuleMetadata::default(),
+            call_counters: false,
+            preemption_checks: false,
         }
     }
 
+    /// Sets the metadata of the module.
+    pub fn set_metadata(&mut self, metadata: ModuleMetadata) {
+        self.metadata = metadata;
+    }
+
+    /// Records that the code counts the calls to imported functions, in the VMContext.
+    pub fn enable_call_counters(&mut self) {
+        self.call_counters = true;
+    }
+
+    /// Records that the code checks the preemption flag of the VMContext at loop headers and
+    /// after calls.
+    pub fn enable_preemption_checks(&mut self) {
+        self.preemption_checks = true;
+    }
+
+    /// Records CPU features the code of the module may use.
+    pub fn require_cpu_features(&mut self, features: CpuFeatures) {
+        self.cpu_features = self.cpu_features.union(features);
+    }
+
     /// Update the offset of a Wasm function.
     ///
     /// This is intended for use by the compiler, as the functions might be defined before the
@@ -119,6 +238,20 @@ impl ModuleInfo {
         }
     }
 
+    /// Update the code size of a Wasm function.
+    ///
+    /// As for offsets, the size is only known once the function has been compiled.
+    pub fn update_func_size(&mut self, func_idx: FuncIndex, size: u32) {
+        match &mut self.funcs[func_idx] {
+            FuncInfo::Owned {
+                size: previous_size,
+                ..
+            } => *previous_size = size,
+            FuncInfo::Imported { .. } => panic!("Tried to set size of imported function"),
+            FuncInfo::Native { .. } => panic!("Tried to set size of a native function"),
+        }
+    }
+
     /// Marks a function as exported under the given list of names.
     pub fn export_func(&mut self, func_idx: FuncIndex, exported_names: &[String]) {
         for exported_name in exported_names {
@@ -154,23 +287,38 @@ impl ModuleInfo {
 
 /// A WebAssembly module.
 pub struct WasmModule {
-    exported_names: HashMap<String, ItemRef>,
+    exported_names: BTreeMap<String, ItemRef>,
     funcs: FrozenMap<FuncIndex, FuncInfo>,
     types: FrozenMap<TypeIndex, FuncType>,
     heaps: FrozenMap<HeapIndex, HeapInfo>,
     tables: FrozenMap<TableIndex, TableInfo>,
     globs: FrozenMap<GlobIndex, GlobInfo>,
-    imports: FrozenMap<ImportIndex, String>,
+    import_modules: FrozenMap<ImportIndex, String>,
+    imports: Vec<Import>,
     segments: Vec<DataSegment>,
     elements: Vec<TableSegment>,
     start: Option<FuncIndex>,
     code: Vec<u8>,
     relocs: Vec<Reloc>,
+    stack_maps: Vec<StackMap>,
+    trap_sites: Vec<TrapSite>,
+    exception_handlers: Vec<ExceptionHandler>,
     vmctx_layout: SimpleVMContextLayout,
+    cpu_features: CpuFeatures,
+    metadata: ModuleMetadata,
 }
 
 impl WasmModule {
-    pub fn new(info: ModuleInfo, code: Vec<u8>, relocs: Vec<Reloc>) -> Self {
+    /// Creates a new module.
+    ///
+    /// The stack maps and trap sites must be sorted by offset.
+    pub fn new(
+        info: ModuleInfo,
+        code: Vec<u8>,
+        relocs: Vec<Reloc>,
+        stack_maps: Vec<StackMap>,
+        trap_sites: Vec<TrapSite>,
+    ) -> Self {
         // Compute the VMContext layout
         let nb_imported_funcs = info
             .funcs
@@ -181,7 +329,7 @@ impl WasmModule {
         let mut heaps = Vec::with_capacity(info.heaps.len());
         let mut tables = Vec::with_capacity(info.tables.len());
         let mut globs = Vec::with_capacity(info.globs.len());
-        let mut imports = Vec::with_capacity(info.imports.len());
+        let mut imports = Vec::with_capacity(info.import_modules.len());
 
         for (func_idx, func) in info.funcs.iter() {
             if func.is_imported() {
@@ -195,14 +343,24 @@ impl WasmModule {
         for table_idx in info.tables.keys() {
             tables.push(table_idx);
         }
-        for import_idx in info.imports.keys() {
+        for import_idx in info.import_modules.keys() {
             imports.push(import_idx);
         }
         for glob_idx in info.globs.keys() {
             globs.push(glob_idx);
         }
 
-        let vmctx_layout = SimpleVMContextLayout::new(funcs, heaps, tables, globs, imports);
+        let counters = if info.call_counters {
+            funcs.clone()
+        } else {
+            Vec::new()
+        };
+        let vmctx_layout = SimpleVMContextLayout::new(funcs, heaps, tables, globs, imports)
+            .with_counters(counters)
+            .with_preemption_checks(info.preemption_checks);
+
+        let mut metadata = info.metadata;
+        metadata.aliases = ExportAliases::collect(&info.exported_items);
 
         Self {
             exported_names: info.exported_items,
@@ -211,15 +369,81 @@ impl WasmModule {
             heaps: info.heaps,
             tables: info.tables,
             globs: info.globs,
+            import_modules: info.import_modules,
             imports: info.imports,
             segments: info.segments,
             elements: info.elements,
             start: info.start,
             code,
             relocs,
+            stack_maps,
+            trap_sites,
+            // The compiler does not emit exception handlers yet
+            exception_handlers: Vec::new(),
             vmctx_layout,
+            cpu_features: info.cpu_features,
+            metadata,
         }
     }
+
+    /// Returns the name, producers, hash and export aliases of the module.
+    pub fn metadata(&self) -> &ModuleMetadata {
+        &self.metadata
+    }
+
+    /// Returns the names under which an item is exported, in lexicographic order.
+    pub fn export_names(&self, item: ItemRef) -> impl Iterator<Item = &str> {
+        self.exported_names
+            .iter()
+            .filter(move |(_, exported)| **exported == item)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Returns a pre-initialized module, whose instances start with the heaps and globals captured
+    /// by the snapshot of an instance of this module.
+    ///
+    /// The data segments of the snapshotted heaps are replaced by the content of the heaps, and the
+    /// initializers of the owned globals by their captured values. The start function is dropped
+    /// if it was executed before the snapshot. The code and the metadata are left untouched, the
+    /// snapshot must have been taken from an instance of this module.
+    pub fn pre_initialized(mut self, snapshot: InstanceSnapshot) -> ModuleResult<Self> {
+        for heap in &snapshot.heaps {
+            match self.heaps.get_mut(heap.index) {
+                Some(HeapInfo::Owned { min_size, .. }) => *min_size = heap.size,
+                _ => return Err(ModuleError::TypeError),
+            }
+        }
+        for (glob_idx, value) in &snapshot.globs {
+            match self.globs.get_mut(*glob_idx) {
+                Some(GlobInfo::Owned { init }) => *init = *value,
+                _ => return Err(ModuleError::TypeError),
+            }
+        }
+
+        self.segments.retain(|segment| {
+            !snapshot
+                .heaps
+                .iter()
+                .any(|heap| heap.index == segment.heap_index)
+        });
+        for heap in snapshot.heaps {
+            self.segments.extend(heap.segments);
+        }
+        if snapshot.started {
+            self.start = None;
+        }
+        Ok(self)
+    }
+
+    /// Returns the stack map of the frame whose return address is at the given offset, relative to
+    /// the module's code address.
+    pub fn get_stack_map(&self, return_offset: u32) -> Option<&StackMap> {
+        let idx = self
+            .stack_maps
+            .binary_search_by_key(&return_offset, |stack_map| stack_map.offset_end)
+            .ok()?;
+        Some(&self.stack_maps[idx])
+    }
 }
 
 impl Module for WasmModule {
@@ -253,7 +477,11 @@ impl Module for WasmModule {
         &self.globs
     }
 
-    fn imports(&self) -> &FrozenMap<ImportIndex, String> {
+    fn import_modules(&self) -> &FrozenMap<ImportIndex, String> {
+        &self.import_modules
+    }
+
+    fn imports(&self) -> &[Import] {
         &self.imports
     }
 
@@ -269,28 +497,42 @@ impl Module for WasmModule {
         &self.relocs
     }
 
-    fn public_items(&self) -> &HashMap<String, ItemRef> {
+    fn stack_maps(&self) -> &[StackMap] {
+        &self.stack_maps
+    }
+
+    fn trap_sites(&self) -> &[TrapSite] {
+        &self.trap_sites
+    }
+
+    fn exception_handlers(&self) -> &[ExceptionHandler] {
+        &self.exception_handlers
+    }
+
+    fn public_items(&self) -> &BTreeMap<String, ItemRef> {
         &self.exported_names
     }
 
     fn vmctx_layout(&self) -> &Self::VMContext {
         &self.vmctx_layout
     }
+
+    fn required_cpu_features(&self) -> CpuFeatures {
+        self.cpu_features
+    }
 }
 
 // ————————————————————————————— Native Module —————————————————————————————— //
 
 static EMPTY_CODE: [u8; 0] = [];
-static EMPTY_SEGMENT: [DataSegment; 0] = [];
-static EMPTY_ELEMENTS: [TableSegment; 0] = [];
 static EMPTY_HEAPS: FrozenMap<HeapIndex, HeapInfo> = FrozenMap::empty();
 static EMPTY_GLOBS: FrozenMap<GlobIndex, GlobInfo> = FrozenMap::empty();
-static EMPTY_IMPORTS: FrozenMap<ImportIndex, String> = FrozenMap::empty();
-static EMPTY_RELOCS: [Reloc; 0] = [];
+static EMPTY_IMPORT_MODULES: FrozenMap<ImportIndex, String> = FrozenMap::empty();
+static EMPTY_IMPORTS: [Import; 0] = [];
 
 /// A builder for native modules.
 pub struct NativeModuleBuilder {
-    exported_names: HashMap<String, ItemRef>,
+    exported_names: BTreeMap<String, ItemRef>,
     funcs: PrimaryMap<FuncIndex, FuncInfo>,
     types: PrimaryMap<TypeIndex, FuncType>,
     tables: PrimaryMap<TableIndex, TableInfo>,
@@ -300,7 +542,7 @@ impl NativeModuleBuilder {
     /// Creates a fresh native module builder.
     pub fn new() -> Self {
         Self {
-            exported_names: HashMap::new(),
+            exported_names: BTreeMap::new(),
             funcs: PrimaryMap::new(),
             types: PrimaryMap::new(),
             tables: PrimaryMap::new(),
@@ -356,11 +598,41 @@ impl NativeModuleBuilder {
         self.exported_names.insert(name, ItemRef::Table(idx));
         self
     }
+
+    /// Add a native function table to the module.
+    ///
+    /// The types of the functions are recorded, so that indirect calls through the table are
+    /// typechecked: calling an entry with an unexpected type traps.
+    pub fn add_func_table(mut self, name: String, funcs: Vec<NativeFuncRef>) -> Self {
+        let funcs = funcs
+            .iter()
+            .map(|func| {
+                let signature = func.ty().signature_id().unwrap_or(NULL_SIGNATURE);
+                (func.ptr() as u64, signature)
+            })
+            .collect::<Vec<(u64, u64)>>();
+        let idx = self.tables.push(TableInfo::Native {
+            ptr: FuncTable::native_words(&funcs),
+            ty: RefType::FuncRef,
+        });
+        self.exported_names.insert(name, ItemRef::Table(idx));
+        self
+    }
+
+    /// Add a handle table to the module.
+    ///
+    /// Contrary to other tables, each instance of the module gets its own empty handle table, which
+    /// can then be populated by the runtime.
+    pub fn add_handle_table(mut self, name: String, capacity: u32) -> Self {
+        let idx = self.tables.push(TableInfo::Handles { capacity });
+        self.exported_names.insert(name, ItemRef::Table(idx));
+        self
+    }
 }
 
 /// A module exposing native (Rust) functions and items.
 pub struct NativeModule {
-    exported_names: HashMap<String, ItemRef>,
+    exported_names: BTreeMap<String, ItemRef>,
     funcs: FrozenMap<FuncIndex, FuncInfo>,
     types: FrozenMap<TypeIndex, FuncType>,
     tables: FrozenMap<TableIndex, TableInfo>,
@@ -398,27 +670,24 @@ impl Module for NativeModule {
         &EMPTY_GLOBS
     }
 
-    fn imports(&self) -> &FrozenMap<ImportIndex, String> {
-        &EMPTY_IMPORTS
-    }
-
-    fn data_segments(&self) -> &[DataSegment] {
-        &EMPTY_SEGMENT
+    fn import_modules(&self) -> &FrozenMap<ImportIndex, String> {
+        &EMPTY_IMPORT_MODULES
     }
 
-    fn table_segments(&self) -> &[TableSegment] {
-        &EMPTY_ELEMENTS
-    }
-
-    fn relocs(&self) -> &[Reloc] {
-        &EMPTY_RELOCS
+    fn imports(&self) -> &[Import] {
+        &EMPTY_IMPORTS
     }
 
-    fn public_items(&self) -> &HashMap<String, ItemRef> {
+    fn public_items(&self) -> &BTreeMap<String, ItemRef> {
         &self.exported_names
     }
 
     fn vmctx_layout(&self) -> &Self::VMContext {
         &self.vmctx_layout
     }
+
+    fn required_cpu_features(&self) -> CpuFeatures {
+        // Native functions are compiled with the kernel
+        CpuFeatures::empty()
+    }
 }
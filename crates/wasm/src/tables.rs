@@ -0,0 +1,120 @@
+//! Function Tables
+//!
+//! A function table is a funcref table, whose entries can be called from WebAssembly with
+//! `call_indirect`. Each entry is made of three words: the address of the function, the signature
+//! identifier of its type (see `FuncType::signature_id`) and the VMContext the function expects.
+//!
+//! The words are stored in three consecutive arrays of the same length, so that the code can
+//! access the signature and VMContext of an entry given the address of the entry and the length
+//! of the table:
+//!
+//! ```text
+//! [ func_ptr; len ] [ signature; len ] [ vmctx; len ]
+//! ```
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+use crate::abi::NULL_REF;
+
+/// The signature of empty entries, which never matches the signature of a function.
+pub const NULL_SIGNATURE: u64 = 0;
+
+/// A fixed size table of function references.
+pub struct FuncTable {
+    words: Box<[u64]>,
+}
+
+impl FuncTable {
+    /// The number of words used by each entry.
+    pub const WORDS_PER_ENTRY: usize = 3;
+
+    /// Creates an empty table, with the given number of entries.
+    pub fn new(len: usize) -> Self {
+        Self::from_words(vec![NULL_REF; len * Self::WORDS_PER_ENTRY].into_boxed_slice())
+    }
+
+    /// Creates a table from its raw words, which must follow the layout of function tables.
+    ///
+    /// Panics if the number of words is not a multiple of `WORDS_PER_ENTRY`.
+    pub fn from_words(words: Box<[u64]>) -> Self {
+        assert_eq!(words.len() % Self::WORDS_PER_ENTRY, 0);
+        Self { words }
+    }
+
+    /// Creates the raw words of a table holding the given native functions.
+    ///
+    /// Native functions do not use their VMContext, which is therefore left null.
+    pub fn native_words(funcs: &[(u64, u64)]) -> Box<[u64]> {
+        let len = funcs.len();
+        let mut words = vec![NULL_REF; len * Self::WORDS_PER_ENTRY].into_boxed_slice();
+        for (idx, (ptr, signature)) in funcs.iter().enumerate() {
+            words[idx] = *ptr;
+            words[len + idx] = *signature;
+        }
+        words
+    }
+
+    /// Returns the function pointers of the table.
+    pub fn entries(&self) -> &[u64] {
+        &self.words[..self.len()]
+    }
+
+    /// Returns the signature of the entry at the given index, if any.
+    pub fn signature(&self, index: usize) -> Option<u64> {
+        if index < self.len() {
+            Some(self.words[self.len() + index])
+        } else {
+            None
+        }
+    }
+
+    /// Writes an entry of the table.
+    ///
+    /// SAFETY: the table must not be accessed concurrently. Tables are mutated in place through
+    /// raw pointers, as is done by the instances' code.
+    pub(crate) unsafe fn write(&self, index: usize, ptr: u64, signature: u64, vmctx: u64) {
+        assert!(index < self.len());
+        let len = self.len();
+        let words = self.words.as_ptr() as *mut u64;
+        words.add(index).write(ptr);
+        words.add(len + index).write(signature);
+        words.add(2 * len + index).write(vmctx);
+    }
+
+    /// Returns the raw words of the table.
+    pub(crate) fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Replaces the VMContext of all the entries expecting `old` by `new`.
+    ///
+    /// SAFETY: the table must not be accessed concurrently, see `write`.
+    pub(crate) unsafe fn replace_vmctx(&self, old: u64, new: u64) {
+        let len = self.len();
+        let words = self.words.as_ptr() as *mut u64;
+        for index in 0..len {
+            let vmctx = words.add(2 * len + index);
+            if vmctx.read() == old {
+                vmctx.write(new);
+            }
+        }
+    }
+
+    /// Returns a pointer to the first entry of the table.
+    ///
+    /// The table is accessed directly from WebAssembly through this pointer.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.words.as_ptr() as *const u8
+    }
+
+    /// Returns the number of entries of the table.
+    pub fn len(&self) -> usize {
+        self.words.len() / Self::WORDS_PER_ENTRY
+    }
+
+    /// Returns true if the table has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+}
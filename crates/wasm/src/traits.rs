@@ -4,9 +4,11 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::ops::Deref;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use collections::{entity_impl, FrozenMap, HashMap};
+use collections::{entity_impl, BTreeMap, FrozenMap};
 
+use crate::abi::PreemptionHandler;
 use crate::funcs::NativeFunc;
 use crate::types::{FuncType, RefType};
 
@@ -126,8 +128,9 @@ impl FuncPtr {
     /// Creates a raw function pointer.
     ///
     /// SAFETY: Note that the pointer might be used to call the function from Wasm Instances, and
-    /// therefore the function must respect the adequate calling convention. At the time of
-    /// writing, this means SystemV calling convention with a `vmctx: u64` as last argument.
+    /// therefore the function must respect the adequate calling convention, see `CALL_CONV`. At
+    /// the time of writing, this means SystemV calling convention with a `vmctx: u64` as last
+    /// argument.
     /// Note that the calling convention might be subject to change, there are no stability
     /// guarantees yet!
     ///
@@ -153,11 +156,12 @@ impl FuncPtr {
 pub enum FuncInfo {
     Owned {
         offset: u32,
+        /// The size of the code of the function, in bytes.
+        size: u32,
         ty: TypeIndex,
     },
+    /// An imported function, see `Module::imports` for the origin of the function.
     Imported {
-        module: ImportIndex,
-        name: String,
         ty: TypeIndex,
     },
     Native {
@@ -178,7 +182,7 @@ impl FuncInfo {
 
 pub enum HeapInfo {
     Owned { min_size: u32, kind: HeapKind },
-    Imported { module: ImportIndex, name: String },
+    Imported { min_size: u32 },
 }
 
 pub enum TableInfo {
@@ -188,14 +192,17 @@ pub enum TableInfo {
         ty: RefType,
     },
     Imported {
-        module: ImportIndex,
-        name: String,
         ty: RefType,
     },
+    /// A table provided by the embedder, funcref tables follow the layout of `FuncTable`.
     Native {
         ptr: Box<[u64]>,
         ty: RefType,
     },
+    /// An externref table managed by the runtime, each instance gets its own table.
+    Handles {
+        capacity: u32,
+    },
 }
 
 /// Possible initial values for a global variable.
@@ -205,12 +212,46 @@ pub enum GlobInit {
     I64(i64),
     F32(u32),
     F64(u64),
+    /// A reference, modules can only initialize reference globals with `NULL_REF`.
+    Ref(u64),
 }
 
 pub enum GlobInfo {
     // TODO: add type
     Owned { init: GlobInit },
-    Imported { module: ImportIndex, name: String },
+    Imported,
+}
+
+/// The kind of an imported item, with its index within the importing module and its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportKind {
+    Func { index: FuncIndex, ty: TypeIndex },
+    Heap { index: HeapIndex, min_size: u32 },
+    Table { index: TableIndex, ty: RefType },
+    // TODO: add type
+    Glob { index: GlobIndex },
+}
+
+/// An item imported by a module.
+#[derive(Debug, Clone)]
+pub struct Import {
+    /// The module the item is imported from.
+    pub module: ImportIndex,
+    /// The name of the item within the module it is imported from.
+    pub field: String,
+    pub kind: ImportKind,
+}
+
+impl Import {
+    /// Returns the imported item, within the importing module.
+    pub fn item(&self) -> ItemRef {
+        match self.kind {
+            ImportKind::Func { index, .. } => ItemRef::Func(index),
+            ImportKind::Heap { index, .. } => ItemRef::Heap(index),
+            ImportKind::Table { index, .. } => ItemRef::Table(index),
+            ImportKind::Glob { index } => ItemRef::Glob(index),
+        }
+    }
 }
 
 /// A data segment used to initialize memory.
@@ -235,8 +276,8 @@ pub struct TableSegment {
     pub base: Option<GlobIndex>,
     /// Offset, relative to the base if any, to 0 otherwise.
     pub offset: u32,
-    /// The actual elements
-    pub elements: Box<[FuncIndex]>,
+    /// The actual elements, `None` for null references.
+    pub elements: Box<[Option<FuncIndex>]>,
 }
 
 pub trait VMContextLayout {
@@ -245,9 +286,16 @@ pub trait VMContextLayout {
     fn funcs(&self) -> &[FuncIndex];
     fn globs(&self) -> &[GlobIndex];
     fn imports(&self) -> &[ImportIndex];
+    /// The imported functions whose calls are counted, empty unless the module was compiled with
+    /// call counters.
+    fn counters(&self) -> &[FuncIndex];
+    /// Whether the code checks for preemption requests, in which case the VMContext holds a
+    /// preemption flag and the address of the preemption handler.
+    fn preemption_checks(&self) -> bool;
 }
 
 /// One to one mapping to Cranelift `Reloc`. See Cranelift for details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RelocKind {
     Abs4,
     Abs8,
@@ -267,6 +315,7 @@ pub enum RelocKind {
 /// Addend to add to the symbol value.
 pub type Addend = i64;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Reloc {
     /// Offset of the relocation, relative to the module's code address.
     pub offset: u32,
@@ -282,12 +331,145 @@ pub struct Reloc {
     pub addend: Addend,
 }
 
+/// A stack map, describing which stack slots hold live references at a given safepoint.
+///
+/// Safepoints are call sites: when walking the stack, the return address of a frame corresponds to
+/// the `offset_end` of the call instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackMap {
+    /// Offset of the safepoint instruction, relative to the module's code address.
+    pub offset: u32,
+
+    /// Offset of the first byte after the safepoint instruction, relative to the module's code
+    /// address.
+    pub offset_end: u32,
+
+    /// Number of stack slots (8 bytes words) covered by the map, starting from the stack pointer.
+    pub mapped_words: u32,
+
+    /// A bitmap of the stack slots holding a live reference, one bit per slot.
+    pub bitmap: Box<[u32]>,
+}
+
+impl StackMap {
+    /// Returns the indexes of the stack slots holding live references, relative to the stack
+    /// pointer at the safepoint.
+    pub fn live_slots(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.mapped_words).filter(|slot| {
+            let word = self.bitmap[(slot / 32) as usize];
+            word & (1 << (slot % 32)) != 0
+        })
+    }
+}
+
+// ————————————————————————————————— Traps —————————————————————————————————— //
+
+/// The cause of a trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TrapCode {
+    /// The cause of the trap is not known, e.g. because the faulting instruction is not a trap
+    /// site.
+    Unknown = 0,
+    StackOverflow = 1,
+    HeapOutOfBounds = 2,
+    HeapMisaligned = 3,
+    TableOutOfBounds = 4,
+    IndirectCallToNull = 5,
+    BadSignature = 6,
+    IntegerOverflow = 7,
+    IntegerDivisionByZero = 8,
+    BadConversionToInteger = 9,
+    UnreachableCodeReached = 10,
+    Interrupt = 11,
+    /// An exception was thrown and no handler caught it.
+    ///
+    /// NOTE: exceptions are not supported yet, this code is reserved for that purpose.
+    UncaughtException = 12,
+}
+
+/// An instruction that might trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapSite {
+    /// Offset of the instruction, relative to the module's code address.
+    pub offset: u32,
+    /// The cause of the trap, if the instruction traps.
+    pub code: TrapCode,
+    /// The corresponding location in the WebAssembly module.
+    pub location: WasmLocation,
+}
+
+/// A range of code whose exceptions are caught by a handler.
+///
+/// Exceptions are meant to be raised as traps: on a trap within the range the runtime unwinds the
+/// stack up to the frame of the function containing the range, then resumes at the landing pad.
+/// Traps outside of any range terminate the execution with `TrapCode::UncaughtException`.
+///
+/// NOTE: exceptions are not supported yet, and the compiler rejects modules using them. Modules
+/// therefore have no handlers for now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionHandler {
+    /// Offset of the first instruction covered by the handler, relative to the module's code
+    /// address.
+    pub start: u32,
+    /// Offset of the end of the range, exclusive.
+    pub end: u32,
+    /// Offset of the code handling the exception.
+    pub landing_pad: u32,
+    /// The tag of the exceptions caught by the handler, `None` if it catches all exceptions.
+    pub tag: Option<u32>,
+}
+
+/// A location within a WebAssembly module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WasmLocation {
+    /// The function containing the location.
+    pub func: FuncIndex,
+    /// Offset within the module bytecode, if known.
+    pub offset: Option<u32>,
+}
+
+/// The outcome of the execution of a function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The function returned the given values.
+    Returned(Vec<u64>),
+    /// The function trapped, the location is `None` if the trap was raised outside of the
+    /// instance's code (e.g. while executing a native function).
+    Trapped {
+        code: TrapCode,
+        location: Option<WasmLocation>,
+    },
+    /// The execution was stopped by the embedder.
+    ///
+    /// NOTE: executions can not be interrupted yet, embedders report this status for executions
+    /// which were not started because the instance was discarded (e.g. by an out of memory killer)
+    /// or because there was not enough memory to start them.
+    Killed,
+}
+
 /// The error that might occur during module instantiation.
 #[derive(Debug)]
 pub enum ModuleError {
     FailedToInstantiate,
     TypeError,
     RuntimeError,
+    /// The start function trapped, the instance must not be used.
+    StartTrapped,
+    /// The allocation policy quota would be exceeded.
+    QuotaExceeded,
+    /// An active data segment does not fit within the initial size of its heap.
+    DataSegmentOutOfBounds,
+    /// An active element segment targets an imported table. This is not supported: the table
+    /// would refer to the functions of the instance without keeping it alive.
+    ImportedTableSegment,
+    /// The code of the module uses CPU features which are not supported by the runtime.
+    MissingCpuFeatures(CpuFeatures),
+    /// The runtime is running low on memory, and does not allocate new areas.
+    OutOfMemory,
+    /// The module relies on a feature the runtime does not support yet, such as segment offsets
+    /// read from a global.
+    Unsupported,
 }
 
 pub type ModuleResult<T> = Result<T, ModuleError>;
@@ -303,12 +485,224 @@ pub trait Module {
     fn funcs(&self) -> &FrozenMap<FuncIndex, FuncInfo>;
     fn types(&self) -> &FrozenMap<TypeIndex, FuncType>;
     fn globs(&self) -> &FrozenMap<GlobIndex, GlobInfo>;
-    fn imports(&self) -> &FrozenMap<ImportIndex, String>;
-    fn data_segments(&self) -> &[DataSegment];
-    fn table_segments(&self) -> &[TableSegment];
-    fn relocs(&self) -> &[Reloc];
-    fn public_items(&self) -> &HashMap<String, ItemRef>;
+    /// The names of the modules the items are imported from.
+    fn import_modules(&self) -> &FrozenMap<ImportIndex, String>;
+    /// The imported items, in the order of the import section.
+    fn imports(&self) -> &[Import];
+
+    /// The active data segments, applied in order to the owned heaps on instantiation.
+    ///
+    /// The segments belong to the module: instances copy them into their heaps, and never keep a
+    /// reference to them. Passive segments are not supported.
+    fn data_segments(&self) -> &[DataSegment] {
+        &[]
+    }
+
+    /// The active table segments, applied in order to the tables on instantiation.
+    ///
+    /// As for data segments, the elements are copied into the tables. Passive and declared
+    /// segments are not supported.
+    fn table_segments(&self) -> &[TableSegment] {
+        &[]
+    }
+
+    /// The relocations to apply to the code, none if the code is position independent.
+    fn relocs(&self) -> &[Reloc] {
+        &[]
+    }
+
+    fn stack_maps(&self) -> &[StackMap] {
+        &[]
+    }
+
+    fn trap_sites(&self) -> &[TrapSite] {
+        &[]
+    }
+
+    /// The exception handlers, sorted by start offset. Nested ranges come after the ranges
+    /// enclosing them.
+    fn exception_handlers(&self) -> &[ExceptionHandler] {
+        &[]
+    }
+
+    /// The exported items, ordered by name.
+    fn public_items(&self) -> &BTreeMap<String, ItemRef>;
     fn vmctx_layout(&self) -> &Self::VMContext;
+    /// The CPU features the code of the module may use.
+    fn required_cpu_features(&self) -> CpuFeatures;
+}
+
+// ———————————————————————————— Allocation Policy ————————————————————————————— //
+
+/// Constraints on the areas allocated by a runtime during instantiation.
+#[derive(Debug, Clone)]
+pub struct AllocPolicy {
+    /// Minimum alignment of heaps and code areas, in bytes. Must be a power of two.
+    pub alignment: usize,
+    /// Where to place heaps and code areas in the address space.
+    pub placement: Placement,
+    /// The quota charged for all allocations, if any.
+    pub quota: Option<Arc<Quota>>,
+}
+
+/// Where a runtime places heaps and code areas in the address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Anywhere, at the discretion of the runtime.
+    Anywhere,
+    /// Next to the other areas of the same group, in a region of the address space dedicated to
+    /// that group. Groups are identified by an opaque ID, typically one per component.
+    Grouped(u64),
+}
+
+/// A memory quota, which can be shared by multiple instances (e.g. all the instances of a
+/// component).
+///
+/// NOTE: charges are never released, even if the runtime frees the areas later on.
+#[derive(Debug)]
+pub struct Quota {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl AllocPolicy {
+    /// Charges an allocation to the quota, if any.
+    pub fn charge(&self, size: usize) -> ModuleResult<()> {
+        match &self.quota {
+            Some(quota) => quota.charge(size),
+            None => Ok(()),
+        }
+    }
+
+    /// Rounds a size up to the policy alignment.
+    pub fn align(&self, size: usize) -> usize {
+        debug_assert!(self.alignment.is_power_of_two());
+        (size + self.alignment - 1) & !(self.alignment - 1)
+    }
+}
+
+impl Default for AllocPolicy {
+    fn default() -> Self {
+        Self {
+            alignment: 0x1000,
+            placement: Placement::Anywhere,
+            quota: None,
+        }
+    }
+}
+
+impl Quota {
+    /// Creates a quota of `limit` bytes.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns the number of bytes charged so far.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Charges `size` bytes, or returns `ModuleError::QuotaExceeded` without charging anything if
+    /// that would exceed the limit.
+    pub fn charge(&self, size: usize) -> ModuleResult<()> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(size).filter(|total| *total <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|_| ModuleError::QuotaExceeded)
+    }
+}
+
+// —————————————————————————————— CPU Features —————————————————————————————— //
+
+/// A set of x86_64 CPU features.
+///
+/// Code generated for a set of features may execute instructions from any of them, and therefore
+/// fault on CPUs that do not support all of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CpuFeatures(u32);
+
+impl CpuFeatures {
+    pub const SSE3: Self = Self(1 << 0);
+    pub const SSSE3: Self = Self(1 << 1);
+    pub const SSE41: Self = Self(1 << 2);
+    pub const SSE42: Self = Self(1 << 3);
+    pub const POPCNT: Self = Self(1 << 4);
+    pub const AVX: Self = Self(1 << 5);
+    pub const AVX2: Self = Self(1 << 6);
+    pub const BMI1: Self = Self(1 << 7);
+    pub const BMI2: Self = Self(1 << 8);
+    pub const LZCNT: Self = Self(1 << 9);
+
+    /// The features assumed by default, supported by virtually all x86_64 CPUs still in use.
+    pub const BASELINE: Self = Self::SSE3
+        .union(Self::SSSE3)
+        .union(Self::SSE41)
+        .union(Self::SSE42);
+
+    /// All the features, with their names.
+    pub const NAMED: [(Self, &'static str); 10] = [
+        (Self::SSE3, "sse3"),
+        (Self::SSSE3, "ssse3"),
+        (Self::SSE41, "sse4.1"),
+        (Self::SSE42, "sse4.2"),
+        (Self::POPCNT, "popcnt"),
+        (Self::AVX, "avx"),
+        (Self::AVX2, "avx2"),
+        (Self::BMI1, "bmi1"),
+        (Self::BMI2, "bmi2"),
+        (Self::LZCNT, "lzcnt"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Returns the features of `self` that are not in `other`.
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Adds or removes the given features.
+    pub fn set(&mut self, features: Self, enabled: bool) {
+        if enabled {
+            *self = self.union(features);
+        } else {
+            *self = self.difference(features);
+        }
+    }
+
+    /// Returns the names of the features in the set.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMED
+            .into_iter()
+            .filter(move |(feature, _)| self.contains(*feature))
+            .map(|(_, name)| name)
+    }
 }
 
 // ———————————————————————————————— Runtime ————————————————————————————————— //
@@ -319,21 +713,27 @@ pub trait Module {
 /// - the `alloc_code` method which might cause arbitrary code execution if the runtime modifies
 /// the code area once the code has been written.
 /// - The `alloc_heap` method might cause arbitrary code execution within the instance in case of
-/// improper initialization (i.e. in most case memory must be zeroed), which might result in
-/// arbitrary bad things depending on the instance's capabilities.
+/// improper initialization (i.e. memory must be zeroed), which might result in arbitrary bad
+/// things depending on the instance's capabilities.
 pub unsafe trait Runtime {
     type MemoryArea;
     type Context;
 
+    /// The CPU features available to the code of instances, modules requiring other features are
+    /// rejected at instantiation.
+    fn cpu_features(&self) -> CpuFeatures;
+
     /// Creates a new context.
     ///
     /// The same context is guaranteed to be passed to all methods during instantation of a module.
-    fn create_context(&self) -> Self::Context;
+    /// All the allocations made with that context must respect the allocation policy.
+    fn create_context(&self, policy: &AllocPolicy) -> Self::Context;
 
     /// Allocates a heap.
     ///
-    /// SAFETY: Initial memory must always be initialized to 0 by calling the `initialize` callback
-    /// on the memory.
+    /// SAFETY: The memory passed to the `initialize` callback, which applies the data segments,
+    /// must be zeroed. The runtime is free to zero it on demand (e.g. by mapping a shared zero
+    /// page copy-on-write), but must not skip the callback.
     fn alloc_heap<F>(
         &self,
         min_size: usize,
@@ -344,7 +744,23 @@ pub unsafe trait Runtime {
     where
         F: FnOnce(&mut [u8]) -> Result<(), ModuleError>;
 
+    /// Allocates a heap holding a copy of the first `size` bytes of `heap`, used when forking an
+    /// instance.
+    ///
+    /// The copy can be made lazily, but writes to either heap must not be visible in the other.
+    fn fork_heap(
+        &self,
+        heap: &Self::MemoryArea,
+        size: usize,
+        ctx: &mut Self::Context,
+    ) -> Result<Self::MemoryArea, ModuleError>;
+
     /// Allocates a table.
+    ///
+    /// The sizes are expressed in words, funcref tables use several words per entry (see
+    /// `FuncTable`). Funcref tables are cleared by the instance, the slots of externref tables
+    /// that the runtime does not fill with its own references must hold null references (see
+    /// `NULL_REF`).
     fn alloc_table(
         &self,
         min_size: u32,
@@ -365,4 +781,16 @@ pub unsafe trait Runtime {
     ) -> Result<Self::MemoryArea, ModuleError>
     where
         F: FnOnce(&mut [u8]) -> Result<(), ModuleError>;
+
+    /// The function called by instances compiled with preemption checks once preemption has been
+    /// requested, `None` if the runtime never requests preemption.
+    fn preemption_handler(&self) -> Option<PreemptionHandler> {
+        None
+    }
+
+    /// Called once an instantiation succeeded, with the context used for its allocations.
+    ///
+    /// Contexts dropped without being committed belong to failed instantiations, the runtime can
+    /// then release the areas allocated so far.
+    fn commit(&self, _ctx: Self::Context) {}
 }
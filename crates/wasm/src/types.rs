@@ -25,6 +25,29 @@ impl FuncType {
     pub fn ret(&self) -> &[ValueType] {
         &self.ret
     }
+
+    /// Returns an identifier of the type, two types are equal if and only if their identifiers
+    /// are equal. Identifiers are never null.
+    ///
+    /// The identifiers are used to typecheck indirect calls, they are computed without any
+    /// registry so that they can be compared across modules. Returns `None` if the type has too
+    /// many arguments and results for its identifier to fit in 64 bits.
+    pub fn signature_id(&self) -> Option<u64> {
+        // Each value type is encoded on 3 bits, the arguments and results are separated by a 0.
+        // The leading 1 marks the length of the encoding.
+        if self.args.len() + self.ret.len() + 1 > 21 {
+            return None;
+        }
+        let mut id = 1;
+        for ty in &self.args {
+            id = (id << 3) | ty.code();
+        }
+        id <<= 3;
+        for ty in &self.ret {
+            id = (id << 3) | ty.code();
+        }
+        Some(id)
+    }
 }
 
 /// A WebAssembly value type.
@@ -38,6 +61,20 @@ pub enum ValueType {
     FuncRef,
 }
 
+impl ValueType {
+    /// Returns a non-null 3 bits code identifying the type.
+    fn code(self) -> u64 {
+        match self {
+            ValueType::I32 => 1,
+            ValueType::I64 => 2,
+            ValueType::F32 => 3,
+            ValueType::F64 => 4,
+            ValueType::ExternRef => 5,
+            ValueType::FuncRef => 6,
+        }
+    }
+}
+
 /// A WebAssembly numeric type.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum NumType {
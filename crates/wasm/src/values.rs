@@ -0,0 +1,171 @@
+//! Typed Values
+//!
+//! The values passed by the embedder when calling into WebAssembly. Values are tagged with their
+//! type, so that the arguments of a call can be checked against the signature of the callee
+//! before entering guest code.
+
+use crate::abi::{WasmBaseType, WasmType};
+use crate::types::{FuncType, ValueType};
+
+/// The maximum number of arguments of a call from the embedder.
+pub const MAX_ARGS: usize = 5;
+
+/// A WebAssembly value, tagged with its type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    /// A reference, in its 64 bits ABI representation.
+    ExternRef(u64),
+}
+
+impl Value {
+    /// Creates a value from a type which can be passed across the WebAssembly/Native boundary.
+    pub fn from_wasm<T: WasmType>(val: T) -> Self {
+        T::Abi::into_value(val.into_abi())
+    }
+
+    pub fn ty(&self) -> ValueType {
+        match self {
+            Value::I32(_) => ValueType::I32,
+            Value::I64(_) => ValueType::I64,
+            Value::F32(_) => ValueType::F32,
+            Value::F64(_) => ValueType::F64,
+            Value::ExternRef(_) => ValueType::ExternRef,
+        }
+    }
+
+    /// Returns the value as held in a 64 bits register, 32 bits values are zero-extended.
+    pub fn to_bits(&self) -> u64 {
+        match *self {
+            Value::I32(val) => val as u32 as u64,
+            Value::I64(val) => val as u64,
+            Value::F32(val) => val.to_bits() as u64,
+            Value::F64(val) => val.to_bits(),
+            Value::ExternRef(val) => val,
+        }
+    }
+
+    /// Creates a value of the given type from its representation in a 64 bits register.
+    ///
+    /// Returns `None` for function references, which can not be held by the embedder.
+    pub fn from_bits(ty: ValueType, bits: u64) -> Option<Self> {
+        let value = match ty {
+            ValueType::I32 => Value::I32(bits as u32 as i32),
+            ValueType::I64 => Value::I64(bits as i64),
+            ValueType::F32 => Value::F32(f32::from_bits(bits as u32)),
+            ValueType::F64 => Value::F64(f64::from_bits(bits)),
+            ValueType::ExternRef => Value::ExternRef(bits),
+            ValueType::FuncRef => return None,
+        };
+        Some(value)
+    }
+
+    /// Returns true for floating point values, which are passed in SSE registers.
+    pub fn is_float(&self) -> bool {
+        matches!(self, Value::F32(_) | Value::F64(_))
+    }
+}
+
+/// The arguments of a call into WebAssembly.
+///
+/// Arguments are stored inline, so that they can be built without allocating (e.g. from an
+/// interrupt handler).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Args {
+    values: [Value; MAX_ARGS],
+    len: usize,
+}
+
+impl Args {
+    /// Creates an empty list of arguments.
+    pub const fn new() -> Self {
+        Self {
+            values: [Value::I32(0); MAX_ARGS],
+            len: 0,
+        }
+    }
+
+    /// Adds an argument.
+    ///
+    /// Panics if the list already holds `MAX_ARGS` arguments.
+    pub fn push<T: WasmType>(self, arg: T) -> Self {
+        self.push_value(Value::from_wasm(arg))
+    }
+
+    /// Adds an argument, given as a tagged value.
+    ///
+    /// Panics if the list already holds `MAX_ARGS` arguments.
+    pub fn push_value(mut self, value: Value) -> Self {
+        if self.len >= MAX_ARGS {
+            panic!("Too many arguments, we support at most {}", MAX_ARGS);
+        }
+        self.values[self.len] = value;
+        self.len += 1;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[Value] {
+        &self.values[..self.len]
+    }
+
+    /// Returns true if the arguments have the types expected by a function.
+    pub fn matches(&self, ty: &FuncType) -> bool {
+        let expected = ty.args();
+        expected.len() == self.len
+            && self
+                .as_slice()
+                .iter()
+                .zip(expected)
+                .all(|(value, ty)| value.ty() == *ty)
+    }
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn values() {
+        assert_eq!(Value::from_wasm(-1i32), Value::I32(-1));
+        assert_eq!(Value::from_wasm(true), Value::I32(1));
+        assert_eq!(Value::from_wasm(3u64), Value::I64(3));
+        assert_eq!(Value::I32(-1).to_bits(), 0xffff_ffff);
+        assert_eq!(Value::F32(1.5).to_bits(), 1.5f32.to_bits() as u64);
+        for value in [Value::I32(-2), Value::F32(-0.5), Value::F64(2.5)] {
+            assert_eq!(Value::from_bits(value.ty(), value.to_bits()), Some(value));
+        }
+        assert_eq!(Value::from_bits(ValueType::FuncRef, 0), None);
+    }
+
+    #[test]
+    fn args() {
+        let args = Args::new()
+            .push(1u32)
+            .push(2u64)
+            .push_value(Value::F64(0.5));
+        assert_eq!(args.len(), 3);
+        let ty = FuncType::new(vec![ValueType::I32, ValueType::I64, ValueType::F64], vec![]);
+        assert!(args.matches(&ty));
+        let swapped = FuncType::new(vec![ValueType::I64, ValueType::I32, ValueType::F64], vec![]);
+        assert!(!args.matches(&swapped));
+        assert!(!Args::new().push(1u32).matches(&ty));
+    }
+}
@@ -1,24 +1,65 @@
+use crate::abi::{PreemptionHandler, VMCTX_ENTRY_WIDTH};
 use crate::traits::{FuncIndex, GlobInit, HeapIndex, ImportIndex, TableIndex};
 use crate::traits::{GlobIndex, VMContextLayout};
 use collections::EntityRef;
 
-use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::fmt;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-/// Size of a pointer, in bytes.
-const PTR_SIZE: usize = core::mem::size_of::<*const u8>();
 /// 8 bytes aligment.
 const ALIGN_8: usize = core::mem::align_of::<u64>();
 /// The width of items in the VMContext.
-const ITEM_WIDTH: usize = 8;
+const ITEM_WIDTH: usize = VMCTX_ENTRY_WIDTH;
 
+/// The kinds of fields stored in the VMContext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VMContextField {
+    Heap,
+    Table,
+    Func,
+    Import,
+    Glob,
+    /// The number of calls to an imported function.
+    Counter,
+    /// The preemption flag, followed by the address of the preemption handler.
+    Preemption,
+}
+
+/// An access to a field that is not part of the VMContext layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VMContextError {
+    pub field: VMContextField,
+    pub index: usize,
+    /// The number of fields of that kind in the layout.
+    pub len: usize,
+}
+
+/// A contiguous region of the VMContext, holding all the fields of a given kind.
+#[derive(Clone, Copy)]
+struct Region {
+    offset: usize,
+    len: usize,
+    /// Number of slots per field.
+    slots: usize,
+}
+
+/// The VMContext, holding the addresses and values needed by the code of an instance.
+///
+/// Each field occupies one or more slots of `ITEM_WIDTH` bytes regardless of the architecture,
+/// values narrower than a slot are stored at the start of the slot and the rest of the slot is
+/// zeroed. This matches the offsets computed by the compiler for the same layout.
 pub struct VMContext {
     ptr: NonNull<u8>,
     layout: Layout,
-    table_offset: usize,
-    func_offset: usize,
-    import_offset: usize,
-    glob_offset: usize,
+    heaps: Region,
+    tables: Region,
+    funcs: Region,
+    imports: Region,
+    globs: Region,
+    counters: Region,
+    preemption: Region,
 }
 
 // SAFETY: Send is not implemented because of NonNull for the VMContext pointer. As the VMContext
@@ -26,104 +67,378 @@ pub struct VMContext {
 unsafe impl Send for VMContext {}
 unsafe impl Sync for VMContext {}
 
+// The pointers received by the setters are stored in the VMContext, never dereferenced.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 impl VMContext {
     /// Initialize an empty VMContext.
     ///
     /// WARNING: The VMContext **must** be initialized (with the various methods to set its field)
     /// before being used to execute any code. Failing to do so will result in undefined behavior.
     pub fn empty(layout: &impl VMContextLayout) -> Self {
-        // For now each slot takes 8 bytes, in the future we will have to support other sizes (e.g.
-        // for 128 bits globals), but this should be good enough to start with.
-        let table_offset = layout.heaps().len() * ITEM_WIDTH;
-        let func_offset = table_offset + layout.tables().len() * 2 * ITEM_WIDTH; // Tables occupate 2 slots (pointer + bound)
-        let import_offset = func_offset + layout.funcs().len() * ITEM_WIDTH;
-        let glob_offset = import_offset + layout.imports().len() * ITEM_WIDTH;
-        let capacity = glob_offset + layout.globs().len() * ITEM_WIDTH;
-
-        let alloc_layout = Layout::from_size_align(capacity, ALIGN_8).unwrap();
-        let ptr = unsafe { alloc(alloc_layout) };
+        // Each slot takes `ITEM_WIDTH` bytes, in the future we will have to support other sizes
+        // (e.g. for 128 bits globals), but this should be good enough to start with.
+        let heaps = Region::new(0, layout.heaps().len(), 2); // Pointer + bound pointer
+        let tables = Region::new(heaps.end(), layout.tables().len(), 2); // Pointer + bound pointer
+        let funcs = Region::new(tables.end(), layout.funcs().len(), 1);
+        let imports = Region::new(funcs.end(), layout.imports().len(), 1);
+        let globs = Region::new(imports.end(), layout.globs().len(), 1);
+        let counters = Region::new(globs.end(), layout.counters().len(), 1);
+        let preemption = Region::new(counters.end(), layout.preemption_checks() as usize, 2); // Flag + handler
+        let capacity = preemption.end();
+
+        // Zeroed, so that the padding of values narrower than a slot is well defined
+        // Zero-sized allocations are not allowed
+        let alloc_layout = Layout::from_size_align(capacity.max(ITEM_WIDTH), ALIGN_8).unwrap();
+        let ptr = unsafe { alloc_zeroed(alloc_layout) };
         let ptr = NonNull::new(ptr).unwrap(); // TODO: handle allocation errors
 
         Self {
             ptr,
             layout: alloc_layout,
-            table_offset,
-            func_offset,
-            import_offset,
-            glob_offset,
+            heaps,
+            tables,
+            funcs,
+            imports,
+            globs,
+            counters,
+            preemption,
         }
     }
 
-    pub fn set_heap(&mut self, heap_ptr: *const u8, idx: HeapIndex) {
-        unsafe {
-            let offset = idx.index() * PTR_SIZE;
-            self.wirte_ptr_at(heap_ptr, offset);
+    /// Initialize an empty VMContext, with the same layout as this one.
+    ///
+    /// WARNING: as for `VMContext::empty`, the VMContext **must** be initialized before use.
+    pub fn empty_like(&self) -> Self {
+        let ptr = unsafe { alloc_zeroed(self.layout) };
+        let ptr = NonNull::new(ptr).unwrap(); // TODO: handle allocation errors
+
+        Self {
+            ptr,
+            layout: self.layout,
+            heaps: self.heaps,
+            tables: self.tables,
+            funcs: self.funcs,
+            imports: self.imports,
+            globs: self.globs,
+            counters: self.counters,
+            preemption: self.preemption,
         }
     }
 
-    pub fn set_table(&mut self, table_ptr: *const u8, bound: usize, idx: TableIndex) {
-        unsafe {
-            let offset = self.table_offset + idx.index() * 2 * PTR_SIZE;
-            self.wirte_ptr_at(table_ptr, offset);
-            self.write_bound_at(bound, offset + PTR_SIZE);
-        }
+    pub fn set_heap(&mut self, heap_ptr: *const u8, bound_ptr: *const usize, idx: HeapIndex) {
+        let offset = self.offset(VMContextField::Heap, idx.index());
+        unsafe { self.write_heap_at(heap_ptr, bound_ptr, offset) };
+    }
+
+    pub fn set_table(&mut self, table_ptr: *const u8, bound_ptr: *const u32, idx: TableIndex) {
+        let offset = self.offset(VMContextField::Table, idx.index());
+        unsafe { self.write_table_at(table_ptr, bound_ptr, offset) };
     }
 
     pub fn set_func(&mut self, func_ptr: *const u8, idx: FuncIndex) {
-        unsafe {
-            let offset = self.func_offset + idx.index() * PTR_SIZE;
-            self.wirte_ptr_at(func_ptr, offset);
-        }
+        let offset = self.offset(VMContextField::Func, idx.index());
+        unsafe { self.write_ptr_at(func_ptr, offset) };
     }
 
     pub fn set_import(&mut self, vmctx_ptr: *const u8, idx: ImportIndex) {
-        unsafe {
-            let offset = self.import_offset + idx.index() * PTR_SIZE;
-            self.wirte_ptr_at(vmctx_ptr, offset);
-        }
+        let offset = self.offset(VMContextField::Import, idx.index());
+        unsafe { self.write_ptr_at(vmctx_ptr, offset) };
     }
 
     pub fn set_glob_ptr(&mut self, glob_ptr: *const u8, idx: GlobIndex) {
-        unsafe {
-            let offset = self.glob_offset + idx.index() * PTR_SIZE;
-            self.wirte_ptr_at(glob_ptr, offset);
-        }
+        let offset = self.offset(VMContextField::Glob, idx.index());
+        unsafe { self.write_ptr_at(glob_ptr, offset) };
     }
 
     pub fn set_glob_value(&mut self, value: GlobInit, idx: GlobIndex) {
-        unsafe {
-            let offset = self.glob_offset + idx.index() * PTR_SIZE;
-            let ptr = self.ptr.as_ptr().add(offset);
-            match value {
-                GlobInit::I32(x) => ptr.cast::<i32>().write(x),
-                GlobInit::I64(x) => ptr.cast::<i64>().write(x),
-                GlobInit::F32(x) => ptr.cast::<u32>().write(x),
-                GlobInit::F64(x) => ptr.cast::<u64>().write(x),
+        let offset = self.offset(VMContextField::Glob, idx.index());
+        unsafe { self.write_glob_at(value, offset) };
+    }
+
+    // Checked variants, returning an error if the index is not part of the layout.
+
+    pub fn try_set_heap(
+        &mut self,
+        heap_ptr: *const u8,
+        bound_ptr: *const usize,
+        idx: HeapIndex,
+    ) -> Result<(), VMContextError> {
+        let offset = self.checked_offset(VMContextField::Heap, idx.index())?;
+        unsafe { self.write_heap_at(heap_ptr, bound_ptr, offset) };
+        Ok(())
+    }
+
+    pub fn try_set_table(
+        &mut self,
+        table_ptr: *const u8,
+        bound_ptr: *const u32,
+        idx: TableIndex,
+    ) -> Result<(), VMContextError> {
+        let offset = self.checked_offset(VMContextField::Table, idx.index())?;
+        unsafe { self.write_table_at(table_ptr, bound_ptr, offset) };
+        Ok(())
+    }
+
+    pub fn try_set_func(
+        &mut self,
+        func_ptr: *const u8,
+        idx: FuncIndex,
+    ) -> Result<(), VMContextError> {
+        let offset = self.checked_offset(VMContextField::Func, idx.index())?;
+        unsafe { self.write_ptr_at(func_ptr, offset) };
+        Ok(())
+    }
+
+    pub fn try_set_import(
+        &mut self,
+        vmctx_ptr: *const u8,
+        idx: ImportIndex,
+    ) -> Result<(), VMContextError> {
+        let offset = self.checked_offset(VMContextField::Import, idx.index())?;
+        unsafe { self.write_ptr_at(vmctx_ptr, offset) };
+        Ok(())
+    }
+
+    pub fn try_set_glob_ptr(
+        &mut self,
+        glob_ptr: *const u8,
+        idx: GlobIndex,
+    ) -> Result<(), VMContextError> {
+        let offset = self.checked_offset(VMContextField::Glob, idx.index())?;
+        unsafe { self.write_ptr_at(glob_ptr, offset) };
+        Ok(())
+    }
+
+    pub fn try_set_glob_value(
+        &mut self,
+        value: GlobInit,
+        idx: GlobIndex,
+    ) -> Result<(), VMContextError> {
+        let offset = self.checked_offset(VMContextField::Glob, idx.index())?;
+        unsafe { self.write_glob_at(value, offset) };
+        Ok(())
+    }
+
+    /// Returns the current value of a global stored in the VMContext, the value has the same type
+    /// as `like`.
+    pub fn read_glob_value(&self, like: GlobInit, idx: GlobIndex) -> GlobInit {
+        let offset = self.offset(VMContextField::Glob, idx.index());
+        // SAFETY: the slot is within the VMContext, and holds a value of the type of the global.
+        unsafe { self.read_glob_at(like, offset) }
+    }
+
+    pub fn get_global_ptr(&self, idx: GlobIndex) -> *const u8 {
+        let offset = self.offset(VMContextField::Glob, idx.index());
+        unsafe { self.ptr.as_ptr().add(offset) }
+    }
+
+    /// Returns the number of calls to an imported function, or an error if the function has no
+    /// call counter.
+    pub fn read_counter(&self, idx: FuncIndex) -> Result<u64, VMContextError> {
+        let offset = self.checked_offset(VMContextField::Counter, idx.index())?;
+        // SAFETY: the counter is within the VMContext, and is updated by the code of the instance.
+        Ok(unsafe { self.ptr.as_ptr().add(offset).cast::<u64>().read_volatile() })
+    }
+
+    /// Sets the function called by the code once preemption has been requested, does nothing if
+    /// the code does not check for preemption requests.
+    pub fn set_preemption_handler(&mut self, handler: PreemptionHandler) {
+        if let Some(offset) = self.preemption_flag_offset() {
+            // SAFETY: the handler slot follows the flag, within the VMContext.
+            unsafe {
+                let slot = self.ptr.as_ptr().add(offset + ITEM_WIDTH);
+                slot.cast::<u64>().write(handler as usize as u64);
             }
         }
     }
 
-    pub fn get_global_ptr(&self, idx: GlobIndex) -> *const u8 {
+    /// Returns the offset of the preemption flag, or `None` if the code does not check for
+    /// preemption requests.
+    ///
+    /// The flag is a 64 bits value, preemption is requested when it is non-zero.
+    pub fn preemption_flag_offset(&self) -> Option<usize> {
+        self.checked_offset(VMContextField::Preemption, 0).ok()
+    }
+
+    /// Sets or clears the preemption flag, returns false if the code does not check for
+    /// preemption requests or no handler has been set.
+    ///
+    /// The flag can be updated while the code of the instance is executing, including from an
+    /// interrupt handler.
+    pub fn set_preemption_flag(&self, requested: bool) -> bool {
+        let offset = match self.preemption_flag_offset() {
+            Some(offset) => offset,
+            None => return false,
+        };
+        // SAFETY: the flag and the handler are within the VMContext, and 8 bytes aligned.
         unsafe {
-            let offset = self.glob_offset + idx.index() * PTR_SIZE;
-            self.ptr.as_ptr().add(offset)
+            let flag = self.ptr.as_ptr().add(offset);
+            let handler = flag.add(ITEM_WIDTH).cast::<u64>().read_volatile();
+            if handler == 0 {
+                return false;
+            }
+            (*flag.cast::<AtomicU64>()).store(requested as u64, Ordering::SeqCst);
         }
+        true
     }
 
     pub fn as_ptr(&self) -> *const u8 {
         self.ptr.as_ptr()
     }
 
+    /// Writes the laid out fields, one slot per line, for diagnostic purposes.
+    pub fn dump(&self, writer: &mut impl fmt::Write) -> fmt::Result {
+        let regions = [
+            (VMContextField::Heap, self.heaps),
+            (VMContextField::Table, self.tables),
+            (VMContextField::Func, self.funcs),
+            (VMContextField::Import, self.imports),
+            (VMContextField::Glob, self.globs),
+            (VMContextField::Counter, self.counters),
+            (VMContextField::Preemption, self.preemption),
+        ];
+        writeln!(
+            writer,
+            "VMContext at {:p}, {} bytes",
+            self.ptr,
+            self.layout.size()
+        )?;
+        for (field, region) in regions {
+            for index in 0..region.len {
+                for slot in 0..region.slots {
+                    let offset = region.offset + (index * region.slots + slot) * ITEM_WIDTH;
+                    let value = unsafe { self.ptr.as_ptr().add(offset).cast::<u64>().read() };
+                    let name = match (field, slot) {
+                        (VMContextField::Heap | VMContextField::Table, 1) => "bound_ptr",
+                        (VMContextField::Heap | VMContextField::Table, _) => "ptr",
+                        (VMContextField::Glob, _) => "value",
+                        (VMContextField::Counter, _) => "count",
+                        (VMContextField::Preemption, 0) => "flag",
+                        (VMContextField::Preemption, _) => "handler",
+                        _ => "ptr",
+                    };
+                    writeln!(
+                        writer,
+                        "  0x{:04x} {:?}[{}].{} = 0x{:016x}",
+                        offset, field, index, name, value
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the region holding the given kind of fields.
+    fn region(&self, field: VMContextField) -> Region {
+        match field {
+            VMContextField::Heap => self.heaps,
+            VMContextField::Table => self.tables,
+            VMContextField::Func => self.funcs,
+            VMContextField::Import => self.imports,
+            VMContextField::Glob => self.globs,
+            VMContextField::Counter => self.counters,
+            VMContextField::Preemption => self.preemption,
+        }
+    }
+
+    /// Returns the offset of a field, the index is only validated in debug builds.
+    fn offset(&self, field: VMContextField, index: usize) -> usize {
+        let region = self.region(field);
+        debug_assert!(
+            index < region.len,
+            "VMContext {:?} index {} out of bounds ({} in layout)",
+            field,
+            index,
+            region.len
+        );
+        region.offset_of(index)
+    }
+
+    /// Returns the offset of a field, or an error if the field is not part of the layout.
+    fn checked_offset(&self, field: VMContextField, index: usize) -> Result<usize, VMContextError> {
+        let region = self.region(field);
+        if index < region.len {
+            Ok(region.offset_of(index))
+        } else {
+            Err(VMContextError {
+                field,
+                index,
+                len: region.len,
+            })
+        }
+    }
+
+    /// Writes a heap address and the address of its pointer-sized bound, in bytes.
+    ///
+    /// As for tables, the bound is stored outside of the VMContext so that the instances
+    /// importing a heap observe its growth.
+    unsafe fn write_heap_at(
+        &mut self,
+        heap_ptr: *const u8,
+        bound_ptr: *const usize,
+        offset: usize,
+    ) {
+        self.write_ptr_at(heap_ptr, offset);
+        self.write_ptr_at(bound_ptr.cast(), offset + ITEM_WIDTH);
+    }
+
+    /// Writes a table address and the address of its 32 bits bound.
+    ///
+    /// The bound is stored outside of the VMContext, so that it can be updated without touching
+    /// the VMContext of the instances sharing the table.
+    unsafe fn write_table_at(
+        &mut self,
+        table_ptr: *const u8,
+        bound_ptr: *const u32,
+        offset: usize,
+    ) {
+        self.write_ptr_at(table_ptr, offset);
+        self.write_ptr_at(bound_ptr.cast(), offset + ITEM_WIDTH);
+    }
+
+    /// Writes the value of a global, at the start of its slot.
+    unsafe fn write_glob_at(&mut self, value: GlobInit, offset: usize) {
+        let ptr = self.ptr.as_ptr().add(offset);
+        match value {
+            GlobInit::I32(x) => ptr.cast::<i32>().write(x),
+            GlobInit::I64(x) => ptr.cast::<i64>().write(x),
+            GlobInit::F32(x) => ptr.cast::<u32>().write(x),
+            GlobInit::F64(x) => ptr.cast::<u64>().write(x),
+            GlobInit::Ref(x) => ptr.cast::<u64>().write(x),
+        }
+    }
+
+    /// Reads the value of a global, at the start of its slot.
+    unsafe fn read_glob_at(&self, like: GlobInit, offset: usize) -> GlobInit {
+        let ptr = self.ptr.as_ptr().add(offset);
+        match like {
+            GlobInit::I32(_) => GlobInit::I32(ptr.cast::<i32>().read()),
+            GlobInit::I64(_) => GlobInit::I64(ptr.cast::<i64>().read()),
+            GlobInit::F32(_) => GlobInit::F32(ptr.cast::<u32>().read()),
+            GlobInit::F64(_) => GlobInit::F64(ptr.cast::<u64>().read()),
+            GlobInit::Ref(_) => GlobInit::Ref(ptr.cast::<u64>().read()),
+        }
+    }
+
     /// Writes a pointer to the VmContext.
-    unsafe fn wirte_ptr_at(&mut self, ptr: *const u8, offset: usize) {
+    unsafe fn write_ptr_at(&mut self, ptr: *const u8, offset: usize) {
         let target = self.ptr.as_ptr().add(offset).cast::<*const u8>();
         target.write(ptr);
     }
+}
+
+impl Region {
+    fn new(offset: usize, len: usize, slots: usize) -> Self {
+        Self { offset, len, slots }
+    }
+
+    /// The offset of the first byte following the region.
+    fn end(&self) -> usize {
+        self.offset_of(self.len)
+    }
 
-    /// Writes a bound to the VmContext (used by tables).
-    unsafe fn write_bound_at(&mut self, bound: usize, offset: usize) {
-        let target = self.ptr.as_ptr().add(offset).cast::<u32>();
-        target.write(bound as u32);
+    fn offset_of(&self, index: usize) -> usize {
+        self.offset + index * self.slots * ITEM_WIDTH
     }
 }
 
@@ -134,3 +449,99 @@ impl Drop for VMContext {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleVMContextLayout;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec;
+
+    #[test]
+    fn checked_accessors() {
+        let layout = SimpleVMContextLayout::new(
+            vec![FuncIndex::from_u32(0), FuncIndex::from_u32(1)],
+            vec![HeapIndex::from_u32(0)],
+            vec![],
+            vec![GlobIndex::from_u32(0)],
+            vec![],
+        );
+        let mut vmctx = VMContext::empty(&layout);
+
+        assert!(vmctx
+            .try_set_func(0x1000 as *const u8, FuncIndex::from_u32(1))
+            .is_ok());
+        assert!(vmctx
+            .try_set_glob_value(GlobInit::I32(-1), GlobIndex::from_u32(0))
+            .is_ok());
+        assert!(vmctx
+            .try_set_heap(
+                0x4000 as *const u8,
+                0x5000 as *const usize,
+                HeapIndex::from_u32(0)
+            )
+            .is_ok());
+        assert_eq!(
+            vmctx.try_set_table(
+                0x2000 as *const u8,
+                0x3000 as *const u32,
+                TableIndex::from_u32(0)
+            ),
+            Err(VMContextError {
+                field: VMContextField::Table,
+                index: 0,
+                len: 0
+            })
+        );
+        assert_eq!(
+            vmctx.try_set_func(0x1000 as *const u8, FuncIndex::from_u32(2)),
+            Err(VMContextError {
+                field: VMContextField::Func,
+                index: 2,
+                len: 2
+            })
+        );
+
+        let mut dump = String::new();
+        vmctx.dump(&mut dump).unwrap();
+        assert!(dump.contains("0x0008 Heap[0].bound_ptr = 0x0000000000005000"));
+        assert!(dump.contains("0x0018 Func[1].ptr = 0x0000000000001000"));
+        assert!(dump.contains("0x0020 Glob[0].value = 0x00000000ffffffff"));
+    }
+
+    #[test]
+    fn preemption_flag() {
+        unsafe extern "sysv64" fn handler(_vmctx: *mut u8) {}
+
+        let layout = SimpleVMContextLayout::new(vec![], vec![], vec![], vec![], vec![]);
+        let mut vmctx = VMContext::empty(&layout);
+        vmctx.set_preemption_handler(handler);
+        assert_eq!(vmctx.preemption_flag_offset(), None);
+        assert!(!vmctx.set_preemption_flag(true));
+
+        // The flag comes after the call counters
+        let layout = SimpleVMContextLayout::new(
+            vec![FuncIndex::from_u32(0)],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        )
+        .with_counters(vec![FuncIndex::from_u32(0)])
+        .with_preemption_checks(true);
+        let mut vmctx = VMContext::empty(&layout);
+        assert_eq!(vmctx.preemption_flag_offset(), Some(2 * ITEM_WIDTH));
+        assert!(!vmctx.set_preemption_flag(true), "no handler");
+        vmctx.set_preemption_handler(handler);
+        assert!(vmctx.set_preemption_flag(true));
+
+        let mut dump = String::new();
+        vmctx.dump(&mut dump).unwrap();
+        assert!(dump.contains("0x0010 Preemption[0].flag = 0x0000000000000001"));
+        assert!(dump.contains(&format!(
+            "0x0018 Preemption[0].handler = 0x{:016x}",
+            handler as usize
+        )));
+    }
+}
@@ -0,0 +1,26 @@
+//! Compiles both arbitrary bytes and generated modules.
+//!
+//! The compiler runs within the kernel, where a panic takes the whole system down: invalid or
+//! unsupported modules must be rejected with an error.
+
+#![no_main]
+
+use compiler::{Compiler, X86_64Compiler};
+use libfuzzer_sys::arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    compile(data);
+
+    let mut u = Unstructured::new(data);
+    if let Ok(module) = coral_fuzz::module(&mut u) {
+        compile(&module.to_bytes());
+    }
+});
+
+fn compile(wasm: &[u8]) {
+    let mut compiler = X86_64Compiler::new();
+    if compiler.parse(wasm).is_ok() {
+        let _ = compiler.compile();
+    }
+}
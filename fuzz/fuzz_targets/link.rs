@@ -0,0 +1,37 @@
+//! Links pairs of generated modules.
+//!
+//! The linked module may be invalid (see `linker::validate`), but linking must not panic: imports
+//! the linkee can not resolve are rejected with a `LinkError`, leaving the base untouched.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+use walrus::Module;
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let (base, linkee) = match (coral_fuzz::module(&mut u), coral_fuzz::linkee(&mut u)) {
+        (Ok(base), Ok(linkee)) => (base, linkee),
+        _ => return,
+    };
+    // Walrus does not support all the proposals generated by wasm-smith
+    let (mut base, linkee) = match (
+        Module::from_buffer(&base.to_bytes()),
+        Module::from_buffer(&linkee.to_bytes()),
+    ) {
+        (Ok(base), Ok(linkee)) => (base, linkee),
+        _ => return,
+    };
+
+    // Resolve the imports of the first imported module, if any
+    let name = match base.imports.iter().next() {
+        Some(import) => import.module.clone(),
+        None => String::from("linkee"),
+    };
+    if linker::link(&mut base, &linkee, &name).is_err() {
+        return;
+    }
+    let wasm = base.emit_wasm();
+    let _ = linker::validate(&wasm);
+});
@@ -0,0 +1,32 @@
+//! Fuzzing Helpers
+//!
+//! Generates valid modules for the fuzz targets, restricted to the proposals supported by Coral.
+
+use libfuzzer_sys::arbitrary::{Result, Unstructured};
+use wasm_smith::{Module, SwarmConfig};
+
+/// Generates an arbitrary module.
+pub fn module(u: &mut Unstructured) -> Result<Module> {
+    let config = config(u)?;
+    Module::new(config, u)
+}
+
+/// Generates an arbitrary module which can be linked into another one.
+///
+/// The linker does not support data and element segments yet.
+pub fn linkee(u: &mut Unstructured) -> Result<Module> {
+    let mut config = config(u)?;
+    config.max_data_segments = 0;
+    config.max_element_segments = 0;
+    Module::new(config, u)
+}
+
+fn config(u: &mut Unstructured) -> Result<SwarmConfig> {
+    let mut config: SwarmConfig = u.arbitrary()?;
+    config.simd_enabled = false;
+    config.memory64_enabled = false;
+    config.threads_enabled = false;
+    config.exceptions_enabled = false;
+    config.max_memories = config.max_memories.min(1);
+    Ok(config)
+}
@@ -1,12 +1,21 @@
 //! # Heap Allocator
+//!
+//! The heap is shared with the interrupt handlers: the heap lock is taken with interrupts
+//! disabled, so that a handler freeing memory can not deadlock on a lock held by the code it
+//! interrupted.
+//!
+//! Handlers must not allocate though, as the heap might be exhausted or fragmented at any point.
+//! They run in an alloc-free context (see `alloc_free`), in which allocating is a bug caught by a
+//! debug assertion.
 
+use x86_64::instructions::interrupts;
 use x86_64::structures::paging::mapper::{MapToError, Mapper};
 use x86_64::structures::paging::{Page, PageTableFlags};
 use x86_64::VirtAddr;
 
 use crate::memory::{FrameAllocator, Size4KiB};
 use alloc::alloc::GlobalAlloc;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 mod fallback;
 mod global;
@@ -19,6 +28,54 @@ pub const HEAP_SIZE: usize = 10_000 * 0x1000; // 40 Mb
 
 static IS_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
+/// Number of bytes currently allocated on the kernel heap.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of bytes of the kernel heap which are not currently allocated.
+///
+/// Freed blocks kept by the size-class lists count as free, even though they can only be reused
+/// for allocations of the same class.
+pub fn heap_free() -> usize {
+    HEAP_SIZE.saturating_sub(ALLOCATED.load(Ordering::Relaxed))
+}
+
+/// Depth of the nested alloc-free contexts.
+static ALLOC_FREE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Executes `f` in an alloc-free context, in which allocating from the kernel heap is forbidden.
+/// Freeing memory is allowed.
+///
+/// The context is only enforced by a debug assertion, it documents code paths which must not
+/// allocate, such as interrupt handlers and the syscall fast paths. `f` must not suspend the
+/// current execution.
+pub fn alloc_free<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    ALLOC_FREE_DEPTH.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    ALLOC_FREE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// Returns true if executing within an alloc-free context.
+pub fn is_alloc_free() -> bool {
+    ALLOC_FREE_DEPTH.load(Ordering::Relaxed) > 0
+}
+
+/// Returns the depth of the nested alloc-free contexts, see `restore_alloc_free_depth`.
+pub fn alloc_free_depth() -> usize {
+    ALLOC_FREE_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Restores the depth of the nested alloc-free contexts to a previous value.
+///
+/// Recovering from a trap skips the end of the contexts entered since the call into guest code,
+/// which would otherwise stay active forever.
+pub fn restore_alloc_free_depth(depth: usize) {
+    ALLOC_FREE_DEPTH.store(depth, Ordering::Relaxed);
+}
+
 /// Initializes the kernel heap.
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -62,10 +119,16 @@ static GLOBAL_ALLOC: utils::Locked<global::GlobalAllocator> =
 
 unsafe impl GlobalAlloc for utils::Locked<global::GlobalAllocator> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        self.lock().alloc(layout)
+        debug_assert!(!is_alloc_free(), "allocation in alloc-free context");
+        let ptr = interrupts::without_interrupts(|| self.lock().alloc(layout));
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        self.lock().dealloc(ptr, layout)
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        interrupts::without_interrupts(|| self.lock().dealloc(ptr, layout))
     }
 }
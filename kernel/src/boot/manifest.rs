@@ -0,0 +1,490 @@
+//! Boot Manifest
+//!
+//! The manifest is written in a subset of TOML: arrays of tables (`[[component]]`, `[[instance]]`
+//! and `[[subscription]]`) whose keys hold strings, integers, booleans or arrays of strings. Each
+//! value must fit on a single line, and comments start with `#`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::events::Encoding;
+use crate::services::ServicePermissions;
+use crate::supervisor::RestartPolicy;
+
+/// The userspace to build at boot time.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub components: Vec<ComponentDecl>,
+    /// The instances, in instantiation order.
+    pub instances: Vec<InstanceDecl>,
+    pub subscriptions: Vec<SubscriptionDecl>,
+}
+
+/// A component, whose instances are declared separately.
+#[derive(Debug)]
+pub struct ComponentDecl {
+    pub name: String,
+    /// The restart policy of the init function of the component, if any.
+    pub policy: RestartPolicy,
+    /// The services the component can register and lookup, see `ServicePermissions`.
+    pub services: ServicePermissions,
+}
+
+/// An instance of a module within a component.
+#[derive(Debug)]
+pub struct InstanceDecl {
+    pub component: String,
+    pub name: String,
+    pub module: String,
+    /// The handles to insert into the handle table of the instance, in order.
+    pub handles: Vec<Handle>,
+    /// Whether the next instances of the component can import this instance, under its name.
+    pub expose: bool,
+    /// The function to run, under supervision, once the userspace is built.
+    pub init: Option<String>,
+}
+
+/// A kernel object given to an instance at boot time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handle {
+    /// A full screen surface of the compositor, drawn to as a VGA text buffer.
+    Vga,
+    /// The component of the instance.
+    Component,
+    /// The capability to shutdown or reboot the system.
+    Power,
+    /// The capability to read the syscall trace.
+    Trace,
+}
+
+/// A function called on each event of a kind.
+#[derive(Debug)]
+pub struct SubscriptionDecl {
+    pub event: EventSource,
+    pub component: String,
+    pub instance: String,
+    pub handler: String,
+    pub encoding: Encoding,
+}
+
+/// The event sources instances can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventSource {
+    Keyboard,
+    Timer,
+    Pointer,
+    Module,
+    Vma,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestError {
+    /// The line is not a table header nor a key/value pair.
+    Syntax { line: usize },
+    /// The table is not part of the manifest.
+    UnknownTable { line: usize, name: String },
+    /// The key is not expected in its table, or appears outside of any table.
+    UnknownKey { line: usize, key: String },
+    /// A required key is missing from the table starting at the given line.
+    MissingKey { line: usize, key: &'static str },
+    /// The value does not have the expected type, or is not one of the expected names.
+    InvalidValue { line: usize, key: String },
+}
+
+impl Manifest {
+    /// Parses a manifest.
+    pub fn parse(source: &str) -> Result<Self, ManifestError> {
+        let mut manifest = Manifest::default();
+        for table in parse_tables(source)? {
+            match table.name.as_str() {
+                "component" => manifest.components.push(table.component()?),
+                "instance" => manifest.instances.push(table.instance()?),
+                "subscription" => manifest.subscriptions.push(table.subscription()?),
+                _ => {
+                    return Err(ManifestError::UnknownTable {
+                        line: table.line,
+                        name: table.name,
+                    })
+                }
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+// ————————————————————————————————— Tables ————————————————————————————————— //
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(u64),
+    Bool(bool),
+    Array(Vec<String>),
+}
+
+/// An entry of an array of tables.
+struct Table {
+    name: String,
+    /// The line of the header of the table.
+    line: usize,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    key: String,
+    value: Value,
+    line: usize,
+    /// Whether the entry has been consumed, remaining entries are unknown keys.
+    used: bool,
+}
+
+impl Table {
+    fn component(mut self) -> Result<ComponentDecl, ManifestError> {
+        let defaults = RestartPolicy::default();
+        let max_restarts = match self.integer("max_restarts")? {
+            Some((restarts, line)) if restarts > u32::MAX as u64 => {
+                return Err(invalid(line, "max_restarts"))
+            }
+            Some((restarts, _)) => restarts as u32,
+            None => defaults.max_restarts,
+        };
+        let delay_ms = match self.integer("restart_delay_ms")? {
+            Some((delay, _)) => delay,
+            None => defaults.delay_ms,
+        };
+        let component = ComponentDecl {
+            name: self.required_string("name")?,
+            policy: RestartPolicy {
+                max_restarts,
+                delay_ms,
+            },
+            services: ServicePermissions::new(
+                self.strings("register_services")?,
+                self.strings("lookup_services")?,
+            ),
+        };
+        self.finish()?;
+        Ok(component)
+    }
+
+    fn instance(mut self) -> Result<InstanceDecl, ManifestError> {
+        let handles = match self.take("handles") {
+            Some((Value::Array(names), line)) => names
+                .iter()
+                .map(|name| match name.as_str() {
+                    "vga" => Ok(Handle::Vga),
+                    "component" => Ok(Handle::Component),
+                    "power" => Ok(Handle::Power),
+                    "trace" => Ok(Handle::Trace),
+                    _ => Err(invalid(line, "handles")),
+                })
+                .collect::<Result<Vec<Handle>, ManifestError>>()?,
+            Some((_, line)) => return Err(invalid(line, "handles")),
+            None => Vec::new(),
+        };
+        let expose = match self.take("expose") {
+            Some((Value::Bool(expose), _)) => expose,
+            Some((_, line)) => return Err(invalid(line, "expose")),
+            None => false,
+        };
+        let instance = InstanceDecl {
+            component: self.required_string("component")?,
+            name: self.required_string("name")?,
+            module: self.required_string("module")?,
+            handles,
+            expose,
+            init: self.string("init")?.map(|(init, _)| init),
+        };
+        self.finish()?;
+        Ok(instance)
+    }
+
+    fn subscription(mut self) -> Result<SubscriptionDecl, ManifestError> {
+        let (event, line) = self.required_string_at("event")?;
+        let event = match event.as_str() {
+            "keyboard" => EventSource::Keyboard,
+            "timer" => EventSource::Timer,
+            "pointer" => EventSource::Pointer,
+            "module" => EventSource::Module,
+            "vma" => EventSource::Vma,
+            _ => return Err(invalid(line, "event")),
+        };
+        let encoding = match self.string("encoding")? {
+            Some((encoding, line)) => match encoding.as_str() {
+                "scalars" => Encoding::Scalars,
+                "tagged" => Encoding::Tagged,
+                _ => return Err(invalid(line, "encoding")),
+            },
+            None => Encoding::Scalars,
+        };
+        let subscription = SubscriptionDecl {
+            event,
+            component: self.required_string("component")?,
+            instance: self.required_string("instance")?,
+            handler: self.required_string("handler")?,
+            encoding,
+        };
+        self.finish()?;
+        Ok(subscription)
+    }
+
+    /// Consumes the value of a key, with the line it appears on.
+    fn take(&mut self, key: &str) -> Option<(Value, usize)> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| !entry.used && entry.key == key)?;
+        entry.used = true;
+        Some((entry.value.clone(), entry.line))
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<(String, usize)>, ManifestError> {
+        match self.take(key) {
+            Some((Value::String(value), line)) => Ok(Some((value, line))),
+            Some((_, line)) => Err(invalid(line, key)),
+            None => Ok(None),
+        }
+    }
+
+    fn integer(&mut self, key: &str) -> Result<Option<(u64, usize)>, ManifestError> {
+        match self.take(key) {
+            Some((Value::Integer(value), line)) => Ok(Some((value, line))),
+            Some((_, line)) => Err(invalid(line, key)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the array of strings of a key, empty if the key is missing.
+    fn strings(&mut self, key: &str) -> Result<Vec<String>, ManifestError> {
+        match self.take(key) {
+            Some((Value::Array(values), _)) => Ok(values),
+            Some((_, line)) => Err(invalid(line, key)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn required_string_at(&mut self, key: &'static str) -> Result<(String, usize), ManifestError> {
+        self.string(key)?.ok_or(ManifestError::MissingKey {
+            line: self.line,
+            key,
+        })
+    }
+
+    fn required_string(&mut self, key: &'static str) -> Result<String, ManifestError> {
+        self.required_string_at(key).map(|(value, _)| value)
+    }
+
+    /// Checks that all the entries have been consumed.
+    fn finish(self) -> Result<(), ManifestError> {
+        match self.entries.into_iter().find(|entry| !entry.used) {
+            Some(entry) => Err(ManifestError::UnknownKey {
+                line: entry.line,
+                key: entry.key,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+fn invalid(line: usize, key: &str) -> ManifestError {
+    ManifestError::InvalidValue {
+        line,
+        key: String::from(key),
+    }
+}
+
+// ————————————————————————————————— Parser ————————————————————————————————— //
+
+/// Splits the source into its tables, line numbers start at 1.
+fn parse_tables(source: &str) -> Result<Vec<Table>, ManifestError> {
+    let mut tables: Vec<Table> = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        let line_nb = idx + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line
+            .strip_prefix("[[")
+            .and_then(|line| line.strip_suffix("]]"))
+        {
+            let name = name.trim();
+            if !is_bare_key(name) {
+                return Err(ManifestError::Syntax { line: line_nb });
+            }
+            tables.push(Table {
+                name: String::from(name),
+                line: line_nb,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or(ManifestError::Syntax { line: line_nb })?;
+        let key = key.trim();
+        if !is_bare_key(key) {
+            return Err(ManifestError::Syntax { line: line_nb });
+        }
+        let value = parse_value(value.trim()).ok_or(ManifestError::Syntax { line: line_nb })?;
+        let table = tables.last_mut().ok_or(ManifestError::UnknownKey {
+            line: line_nb,
+            key: String::from(key),
+        })?;
+        if table.entries.iter().any(|entry| entry.key == key) {
+            return Err(invalid(line_nb, key));
+        }
+        table.entries.push(Entry {
+            key: String::from(key),
+            value,
+            line: line_nb,
+            used: false,
+        });
+    }
+    Ok(tables)
+}
+
+/// Removes the comment at the end of a line, if any.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..idx],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    match value {
+        "true" => return Some(Value::Bool(true)),
+        "false" => return Some(Value::Bool(false)),
+        _ => (),
+    }
+    if let Some(items) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        let mut strings = Vec::new();
+        let mut rest = items.trim();
+        while !rest.is_empty() {
+            let (string, tail) = parse_string(rest)?;
+            strings.push(string);
+            rest = tail.trim_start();
+            // Items are separated by commas, a trailing comma is allowed
+            match rest.strip_prefix(',') {
+                Some(tail) => rest = tail.trim_start(),
+                None if rest.is_empty() => (),
+                None => return None,
+            }
+        }
+        return Some(Value::Array(strings));
+    }
+    if value.starts_with('"') {
+        return match parse_string(value)? {
+            (string, "") => Some(Value::String(string)),
+            _ => None,
+        };
+    }
+    let digits = value.replace('_', "");
+    digits.parse().ok().map(Value::Integer)
+}
+
+/// Parses a basic string at the start of `value`, and returns it with the remaining input.
+fn parse_string(value: &str) -> Option<(String, &str)> {
+    let mut chars = value.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &value[idx + 2..])),
+            '\\' => match chars.next()?.1 {
+                '"' => string.push('"'),
+                '\\' => string.push('\\'),
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                _ => return None,
+            },
+            _ => string.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn boot_manifest() {
+        let manifest = Manifest::parse(include_str!("../../boot.toml")).unwrap();
+        assert_eq!(manifest.components.len(), 1);
+        assert_eq!(manifest.components[0].name, "boot");
+        assert!(manifest.components[0].services.can_register("vga"));
+        let coral = &manifest.instances[0];
+        assert_eq!(coral.module, "coral");
+        assert_eq!(
+            coral.handles,
+            [Handle::Vga, Handle::Component, Handle::Power, Handle::Trace]
+        );
+        assert!(coral.expose);
+        let userboot = manifest.instances.last().unwrap();
+        assert_eq!(userboot.init.as_deref(), Some("init"));
+        assert_eq!(manifest.subscriptions[0].event, EventSource::Keyboard);
+        assert_eq!(manifest.subscriptions[0].encoding, Encoding::Scalars);
+    }
+
+    #[test_case]
+    fn manifest_errors() {
+        let parse = |source: &str| Manifest::parse(source).unwrap_err();
+        assert_eq!(
+            parse("[[component]]\nname"),
+            ManifestError::Syntax { line: 2 }
+        );
+        assert_eq!(
+            parse("# Comment\n[[task]]"),
+            ManifestError::UnknownTable {
+                line: 2,
+                name: String::from("task")
+            }
+        );
+        assert_eq!(
+            parse("[[component]]\nname = \"a\"\ncolor = \"blue\""),
+            ManifestError::UnknownKey {
+                line: 3,
+                key: String::from("color")
+            }
+        );
+        assert_eq!(
+            parse("[[component]]\nmax_restarts = 1"),
+            ManifestError::MissingKey {
+                line: 1,
+                key: "name"
+            }
+        );
+        assert_eq!(
+            parse("[[component]]\nname = \"a\"\nlookup_services = \"vga\""),
+            ManifestError::InvalidValue {
+                line: 3,
+                key: String::from("lookup_services")
+            }
+        );
+        assert_eq!(
+            parse("[[component]]\nname = 42"),
+            ManifestError::InvalidValue {
+                line: 2,
+                key: String::from("name")
+            }
+        );
+    }
+}
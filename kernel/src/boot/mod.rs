@@ -0,0 +1,307 @@
+//! Boot Userspace
+//!
+//! The userspace started at boot time is described by a manifest: the components to create, the
+//! modules to instantiate within them, how instances import each other and which events they
+//! subscribe to. The kernel builds that userspace and schedules the init function of each
+//! component, under supervision.
+
+pub mod manifest;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use manifest::{EventSource, Handle, InstanceDecl};
+pub use manifest::{Manifest, ManifestError};
+
+use crate::compositor::{Geometry, Surface};
+use crate::events::{self, EventDispatcher};
+use crate::runtime::{self, get_runtime, KoIndex, ACTIVE_COMPONENTS, ACTIVE_SURFACES, ACTIVE_VMA};
+use crate::scheduler::Scheduler;
+use crate::services::ServicePermissions;
+use crate::supervisor::{RestartPolicy, Supervisor};
+use crate::syscalls::{self, ExternRef};
+use crate::wasm::{Component, ComponentFunc, InstanceIndex};
+use wasm::{ModuleError, WasmModule};
+
+/// The name of the native module exposing the syscalls.
+pub const SYSCALL_MODULE: &str = "coral";
+
+/// Capacity of the queue of each event dispatcher.
+const EVENT_QUEUE_CAPACITY: usize = 128;
+
+#[derive(Debug)]
+pub enum BootError {
+    /// Two components have the same name.
+    DuplicateComponent(String),
+    UnknownComponent(String),
+    /// The instance is not declared within the component, or is declared after its use.
+    UnknownInstance(String),
+    /// The module is neither embedded in the kernel nor the syscall module.
+    UnknownModule(String),
+    CompilationFailed(String),
+    InstantiationFailed {
+        instance: String,
+        error: ModuleError,
+    },
+    /// The instance has no handle table, or the table is full.
+    HandleTableFull(String),
+    MissingExport {
+        instance: String,
+        func: String,
+    },
+    /// A component has more than one init function.
+    DuplicateInit(String),
+    /// The screen surface of the instance could not be allocated.
+    SurfaceFailed(String),
+}
+
+/// Builds the userspace described by a manifest, and schedules its tasks on the scheduler.
+///
+/// `modules` holds the bytecode of the modules embedded in the kernel, by name. The event
+/// dispatchers and the compilation task are scheduled as well, so that instances can subscribe to
+/// events through syscalls.
+///
+/// The runtime must be initialized, and a compiler registered, before booting.
+pub fn boot(
+    manifest: &Manifest,
+    modules: &[(&str, &[u8])],
+    scheduler: &Scheduler,
+) -> Result<(), BootError> {
+    let mut components: Vec<BootComponent> = Vec::new();
+    for decl in &manifest.components {
+        if components.iter().any(|c| c.name == decl.name) {
+            return Err(BootError::DuplicateComponent(decl.name.clone()));
+        }
+        components.push(BootComponent::new(
+            decl.name.clone(),
+            decl.policy,
+            decl.services.clone(),
+        ));
+    }
+
+    let mut builder = Builder {
+        modules,
+        compiled: Vec::new(),
+    };
+    for decl in &manifest.instances {
+        let component = find_component(&mut components, &decl.component)?;
+        builder.add_instance(component, decl)?;
+    }
+
+    let dispatchers = Dispatchers::new();
+    for decl in &manifest.subscriptions {
+        let component = find_component(&mut components, &decl.component)?;
+        let handler = component.get_func(&decl.instance, &decl.handler)?;
+        dispatchers.get(decl.event).add_listener(
+            component.component.clone(),
+            handler,
+            decl.encoding,
+        );
+    }
+    dispatchers.schedule(scheduler);
+    scheduler.schedule(runtime::compilation::compilation_task());
+
+    for component in components {
+        if let Some(init) = component.init {
+            let supervisor = Supervisor::new(component.component, init, component.policy);
+            scheduler.schedule(supervisor.run());
+        }
+    }
+    Ok(())
+}
+
+fn find_component<'a>(
+    components: &'a mut [BootComponent],
+    name: &str,
+) -> Result<&'a mut BootComponent, BootError> {
+    components
+        .iter_mut()
+        .find(|component| component.name == name)
+        .ok_or_else(|| BootError::UnknownComponent(String::from(name)))
+}
+
+// ——————————————————————————————— Components ——————————————————————————————— //
+
+/// A component being built.
+struct BootComponent {
+    name: String,
+    component: Arc<Component>,
+    /// The instances of the component, by name.
+    instances: Vec<(String, InstanceIndex)>,
+    init: Option<ComponentFunc>,
+    policy: RestartPolicy,
+}
+
+impl BootComponent {
+    fn new(name: String, policy: RestartPolicy, services: ServicePermissions) -> Self {
+        Self {
+            name,
+            component: Arc::new(Component::new().privileged().with_services(services)),
+            instances: Vec::new(),
+            init: None,
+            policy,
+        }
+    }
+
+    fn get_func(&self, instance: &str, func: &str) -> Result<ComponentFunc, BootError> {
+        let idx = self
+            .instances
+            .iter()
+            .find(|(name, _)| name == instance)
+            .map(|(_, idx)| *idx)
+            .ok_or_else(|| BootError::UnknownInstance(String::from(instance)))?;
+        self.component
+            .get_func(func, idx)
+            .ok_or_else(|| BootError::MissingExport {
+                instance: String::from(instance),
+                func: String::from(func),
+            })
+    }
+}
+
+/// Creates the instances, compiling each embedded module at most once.
+struct Builder<'a> {
+    modules: &'a [(&'a str, &'a [u8])],
+    compiled: Vec<(&'a str, WasmModule)>,
+}
+
+impl<'a> Builder<'a> {
+    fn add_instance(
+        &mut self,
+        component: &mut BootComponent,
+        decl: &InstanceDecl,
+    ) -> Result<(), BootError> {
+        let instantiated = if decl.module == SYSCALL_MODULE {
+            component
+                .component
+                .add_instance(&syscalls::build_syscall_module())
+        } else {
+            let module = self.compile(&decl.module)?;
+            component.component.add_instance(module)
+        };
+        let idx = instantiated.map_err(|error| BootError::InstantiationFailed {
+            instance: decl.name.clone(),
+            error,
+        })?;
+        component.instances.push((decl.name.clone(), idx));
+
+        let instance = component.component.get_instance(idx);
+        for handle in &decl.handles {
+            let handle = match handle {
+                Handle::Vga => Self::screen(&decl.name)?,
+                Handle::Component => ACTIVE_COMPONENTS
+                    .insert(component.component.clone())
+                    .into_externref(),
+                Handle::Power => ExternRef::Power,
+                Handle::Trace => ExternRef::Trace,
+            };
+            instance
+                .insert_handle(handle.into_handle())
+                .ok_or_else(|| BootError::HandleTableFull(decl.name.clone()))?;
+        }
+
+        if decl.expose {
+            component.component.push_import(decl.name.clone(), idx);
+        }
+        if let Some(init) = &decl.init {
+            if component.init.is_some() {
+                return Err(BootError::DuplicateInit(component.name.clone()));
+            }
+            component.init = Some(component.get_func(&decl.name, init)?);
+        }
+        Ok(())
+    }
+
+    /// Returns the compiled module with the given name.
+    fn compile(&mut self, name: &str) -> Result<&WasmModule, BootError> {
+        let idx = match self.compiled.iter().position(|(n, _)| *n == name) {
+            Some(idx) => idx,
+            None => {
+                let (name, bytecode) = self
+                    .modules
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .ok_or_else(|| BootError::UnknownModule(String::from(name)))?;
+                let module = runtime::compile(bytecode)
+                    .map_err(|_| BootError::CompilationFailed(String::from(*name)))?;
+                self.compiled.push((name, module));
+                self.compiled.len() - 1
+            }
+        };
+        Ok(&self.compiled[idx].1)
+    }
+
+    /// Creates and presents a full screen surface for an instance, and returns a handle to its
+    /// cells. Each instance gets its own surface, the surfaces are stacked in declaration order.
+    fn screen(instance: &str) -> Result<ExternRef, BootError> {
+        let surface = Surface::new(Geometry::FULL_SCREEN, get_runtime().vma_allocator())
+            .map_err(|_| BootError::SurfaceFailed(String::from(instance)))?;
+        surface.present();
+        let vma = ACTIVE_VMA.insert(Arc::clone(surface.vma()));
+        // The instance only draws through its VMA handle, the surface is kept alive by the kernel
+        ACTIVE_SURFACES.insert(surface);
+        Ok(vma.into_externref())
+    }
+}
+
+// ————————————————————————————————— Events ————————————————————————————————— //
+
+/// The dispatchers of the event sources.
+struct Dispatchers {
+    keyboard: Arc<EventDispatcher>,
+    timer: Arc<EventDispatcher>,
+    pointer: Arc<EventDispatcher>,
+    module: Arc<EventDispatcher>,
+    vma: Arc<EventDispatcher>,
+}
+
+impl Dispatchers {
+    /// Creates the dispatchers and connects them to the event sources.
+    ///
+    /// Components can also subscribe to pointer, module and VMA events through syscalls.
+    fn new() -> Self {
+        let new = || Arc::new(EventDispatcher::new(EVENT_QUEUE_CAPACITY));
+        let dispatchers = Self {
+            keyboard: new(),
+            timer: new(),
+            pointer: new(),
+            module: new(),
+            vma: new(),
+        };
+
+        let d = &dispatchers;
+        events::KEYBOARD_EVENTS.initialize(d.keyboard.source().clone());
+        events::TIMER_EVENTS.initialize(d.timer.source().clone());
+        events::POINTER_EVENTS.initialize(d.pointer.source().clone());
+        events::POINTER_DISPATCHER.init_once(|| d.pointer.clone());
+        events::MODULE_EVENTS.initialize(d.module.source().clone());
+        events::MODULE_DISPATCHER.init_once(|| d.module.clone());
+        events::VMA_EVENTS.initialize(d.vma.source().clone());
+        events::VMA_DISPATCHER.init_once(|| d.vma.clone());
+        dispatchers
+    }
+
+    fn get(&self, source: EventSource) -> &EventDispatcher {
+        match source {
+            EventSource::Keyboard => &self.keyboard,
+            EventSource::Timer => &self.timer,
+            EventSource::Pointer => &self.pointer,
+            EventSource::Module => &self.module,
+            EventSource::Vma => &self.vma,
+        }
+    }
+
+    /// Schedules the dispatch tasks.
+    fn schedule(self, scheduler: &Scheduler) {
+        for dispatcher in [
+            self.keyboard,
+            self.timer,
+            self.pointer,
+            self.module,
+            self.vma,
+        ] {
+            scheduler.schedule(dispatcher.dispatch());
+        }
+    }
+}
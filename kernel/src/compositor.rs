@@ -0,0 +1,268 @@
+//! Text Mode Compositor
+//!
+//! Components never write to the VGA text buffer directly, otherwise they would clobber each
+//! other's output. Instead each component draws into surfaces: rectangles of text cells backed by
+//! a VMA, laid out row by row. The compositor stacks the presented surfaces into a back buffer,
+//! which is then copied to the VGA buffer in one go. Presenting a surface brings it on top of the
+//! others.
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::memory::{Vma, VmaAllocator};
+
+/// Width of the screen, in cells.
+pub const SCREEN_WIDTH: u32 = 80;
+/// Height of the screen, in cells.
+pub const SCREEN_HEIGHT: u32 = 25;
+/// Number of cells of the screen.
+const SCREEN_CELLS: usize = (SCREEN_WIDTH * SCREEN_HEIGHT) as usize;
+/// Size of a cell: an ASCII character followed by its color code.
+const CELL_SIZE: usize = 2;
+/// Address of the VGA text buffer, which is identity mapped.
+const VGA_BUFFER: usize = 0xb8000;
+/// The cell displayed where no surface is visible: a black space.
+const BLANK: u16 = 0;
+
+static COMPOSITOR: Mutex<Compositor> = Mutex::new(Compositor::new());
+
+// ———————————————————————————————— Surfaces ———————————————————————————————— //
+
+/// The position and size of a surface on the screen, in cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Geometry {
+    /// A surface covering the whole screen.
+    pub const FULL_SCREEN: Self = Self {
+        x: 0,
+        y: 0,
+        width: SCREEN_WIDTH,
+        height: SCREEN_HEIGHT,
+    };
+
+    /// Returns true if the surface fits within the screen.
+    fn fits_screen(&self) -> bool {
+        let fits = |start: u32, len: u32, max: u32| matches!(start.checked_add(len), Some(end) if end <= max);
+        fits(self.x, self.width, SCREEN_WIDTH) && fits(self.y, self.height, SCREEN_HEIGHT)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurfaceError {
+    /// The surface does not fit within the screen.
+    OutOfBounds,
+    /// The cells of the surface could not be allocated.
+    OutOfMemory,
+}
+
+/// A rectangle of text cells drawn by a component.
+///
+/// The backing VMA can hold a whole screen, so that resizing a surface never reallocates it.
+pub struct Surface {
+    vma: Arc<Vma>,
+    geometry: Mutex<Geometry>,
+}
+
+impl Surface {
+    /// Creates a hidden surface, whose cells are blank.
+    pub fn new(geometry: Geometry, alloc: &VmaAllocator) -> Result<Arc<Self>, SurfaceError> {
+        if !geometry.fits_screen() {
+            return Err(SurfaceError::OutOfBounds);
+        }
+        let mut vma = alloc
+            .with_capacity(SCREEN_CELLS * CELL_SIZE)
+            .map_err(|_| SurfaceError::OutOfMemory)?;
+        vma.zeroed();
+        Ok(Arc::new(Self {
+            vma: Arc::new(vma),
+            geometry: Mutex::new(geometry),
+        }))
+    }
+
+    /// Returns the VMA holding the cells of the surface.
+    pub fn vma(&self) -> &Arc<Vma> {
+        &self.vma
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        *self.geometry.lock()
+    }
+
+    /// Changes the size of the surface, the cells are not moved: they are laid out with the new
+    /// width.
+    pub fn resize(&self, width: u32, height: u32) -> Result<(), SurfaceError> {
+        self.update(|geometry| Geometry {
+            width,
+            height,
+            ..geometry
+        })
+    }
+
+    /// Moves the surface to a new position on the screen.
+    pub fn move_to(&self, x: u32, y: u32) -> Result<(), SurfaceError> {
+        self.update(|geometry| Geometry { x, y, ..geometry })
+    }
+
+    /// Brings the surface on top of the others and displays it.
+    pub fn present(self: &Arc<Self>) {
+        let mut compositor = COMPOSITOR.lock();
+        compositor.raise(self);
+        compositor.flush();
+    }
+
+    /// Updates the geometry of the surface, and refreshes the screen if the surface is visible.
+    fn update<F>(&self, f: F) -> Result<(), SurfaceError>
+    where
+        F: FnOnce(Geometry) -> Geometry,
+    {
+        let mut compositor = COMPOSITOR.lock();
+        {
+            let mut geometry = self.geometry.lock();
+            let updated = f(*geometry);
+            if !updated.fits_screen() {
+                return Err(SurfaceError::OutOfBounds);
+            }
+            *geometry = updated;
+        }
+        if compositor.is_presented(&self.vma) {
+            compositor.flush();
+        }
+        Ok(())
+    }
+}
+
+/// Refreshes the screen if a presented surface is backed by the VMA, to be called after the
+/// kernel wrote to a VMA on behalf of a component.
+///
+/// The screen is composed in place: this function does not allocate.
+pub fn damage(vma: &Vma) {
+    let mut compositor = COMPOSITOR.lock();
+    if compositor.is_presented(vma) {
+        compositor.flush();
+    }
+}
+
+// ——————————————————————————————— Compositor ——————————————————————————————— //
+
+struct Compositor {
+    /// The presented surfaces, from the bottom to the top of the stack.
+    ///
+    /// Surfaces are dropped once their last handle is revoked, and then disappear from the screen
+    /// at the next refresh.
+    stack: Vec<Weak<Surface>>,
+    /// The screen is composed in the back buffer, then copied to the VGA buffer.
+    back: [u16; SCREEN_CELLS],
+}
+
+impl Compositor {
+    const fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            back: [BLANK; SCREEN_CELLS],
+        }
+    }
+
+    /// Moves a surface to the top of the stack, dropped surfaces are removed along the way.
+    fn raise(&mut self, surface: &Arc<Surface>) {
+        self.stack.retain(|presented| {
+            presented.strong_count() > 0 && !Weak::ptr_eq(presented, &Arc::downgrade(surface))
+        });
+        self.stack.push(Arc::downgrade(surface));
+    }
+
+    /// Returns true if a presented surface is backed by the VMA.
+    fn is_presented(&self, vma: &Vma) -> bool {
+        self.stack
+            .iter()
+            .any(|presented| match presented.upgrade() {
+                Some(surface) => core::ptr::eq(Arc::as_ptr(&surface.vma), vma),
+                None => false,
+            })
+    }
+
+    /// Composes the presented surfaces into the back buffer, and copies it to the screen.
+    fn flush(&mut self) {
+        self.back.fill(BLANK);
+        for surface in self.stack.iter().filter_map(Weak::upgrade) {
+            // The cells are read without borrowing the VMA, the worst a concurrent write can do
+            // is tear the displayed text.
+            draw(&mut self.back, surface.geometry(), surface.vma.as_bytes());
+        }
+
+        let vga = VGA_BUFFER as *mut u16;
+        for (idx, cell) in self.back.iter().enumerate() {
+            // SAFETY: the VGA buffer is identity mapped, holds `SCREEN_CELLS` cells and is only
+            // written by the compositor.
+            unsafe { vga.add(idx).write_volatile(*cell) };
+        }
+    }
+}
+
+/// Draws the cells of a surface onto the screen, the geometry must fit the screen.
+fn draw(screen: &mut [u16; SCREEN_CELLS], geometry: Geometry, cells: &[u8]) {
+    let width = geometry.width as usize;
+    for row in 0..geometry.height as usize {
+        let start = (geometry.y as usize + row) * SCREEN_WIDTH as usize + geometry.x as usize;
+        let line = &cells[row * width * CELL_SIZE..(row + 1) * width * CELL_SIZE];
+        for (cell, bytes) in screen[start..start + width]
+            .iter_mut()
+            .zip(line.chunks_exact(CELL_SIZE))
+        {
+            *cell = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn geometry_bounds() {
+        assert!(Geometry::FULL_SCREEN.fits_screen());
+        let geometry = |x, y, width, height| Geometry {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert!(geometry(70, 20, 10, 5).fits_screen());
+        assert!(!geometry(71, 20, 10, 5).fits_screen());
+        assert!(!geometry(0, 0, 10, SCREEN_HEIGHT + 1).fits_screen());
+        assert!(!geometry(u32::MAX, 0, 1, 1).fits_screen());
+    }
+
+    #[test_case]
+    fn draw_stacks_surfaces() {
+        let mut screen = [BLANK; SCREEN_CELLS];
+        let bottom = vec![0x11; SCREEN_CELLS * CELL_SIZE];
+        let top = vec![0x22; 2 * 2 * CELL_SIZE];
+        draw(&mut screen, Geometry::FULL_SCREEN, &bottom);
+        draw(
+            &mut screen,
+            Geometry {
+                x: 1,
+                y: 1,
+                width: 2,
+                height: 2,
+            },
+            &top,
+        );
+
+        let at = |x: usize, y: usize| screen[y * SCREEN_WIDTH as usize + x];
+        assert_eq!(at(0, 0), 0x1111);
+        assert_eq!(at(1, 1), 0x2222);
+        assert_eq!(at(2, 2), 0x2222);
+        assert_eq!(at(3, 1), 0x1111);
+        assert_eq!(at(1, 3), 0x1111);
+    }
+}
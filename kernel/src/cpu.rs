@@ -0,0 +1,100 @@
+//! CPU Features
+//!
+//! The features of the CPU are detected at boot with CPUID, the compiler then generates code for
+//! the features available on this machine rather than for a fixed baseline.
+
+use core::arch::x86_64::{__cpuid_count, __get_cpuid_max, CpuidResult};
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use wasm::CpuFeatures;
+
+// CPUID.01H:ECX
+const ECX_SSE3: u32 = 1 << 0;
+const ECX_SSSE3: u32 = 1 << 9;
+const ECX_SSE41: u32 = 1 << 19;
+const ECX_SSE42: u32 = 1 << 20;
+const ECX_POPCNT: u32 = 1 << 23;
+const ECX_XSAVE: u32 = 1 << 26;
+const ECX_AVX: u32 = 1 << 28;
+// CPUID.(EAX=07H, ECX=0H):EBX
+const EBX_BMI1: u32 = 1 << 3;
+const EBX_AVX2: u32 = 1 << 5;
+const EBX_BMI2: u32 = 1 << 8;
+// CPUID.80000001H:ECX
+const ECX_LZCNT: u32 = 1 << 5;
+
+/// Enables the processor extended states needed by the detected features.
+///
+/// AVX instructions fault unless the OS enabled XSAVE and the AVX state, this must therefore be
+/// called before executing code using AVX.
+pub fn init() {
+    let ecx = cpuid(1).ecx;
+    if ecx & ECX_XSAVE == 0 {
+        return;
+    }
+
+    // SAFETY: XSAVE is supported, and the extended states are not used by the kernel itself.
+    unsafe {
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+        if ecx & ECX_AVX != 0 {
+            XCr0::write(XCr0::read() | XCr0Flags::X87 | XCr0Flags::SSE | XCr0Flags::AVX);
+        }
+    }
+}
+
+/// Returns the features supported by the CPU, and enabled by the kernel.
+pub fn features() -> CpuFeatures {
+    let mut features = CpuFeatures::empty();
+
+    let (max_leaf, _) = cpuid_max(0);
+    let ecx = cpuid(1).ecx;
+    features.set(CpuFeatures::SSE3, ecx & ECX_SSE3 != 0);
+    features.set(CpuFeatures::SSSE3, ecx & ECX_SSSE3 != 0);
+    features.set(CpuFeatures::SSE41, ecx & ECX_SSE41 != 0);
+    features.set(CpuFeatures::SSE42, ecx & ECX_SSE42 != 0);
+    features.set(CpuFeatures::POPCNT, ecx & ECX_POPCNT != 0);
+    features.set(CpuFeatures::AVX, ecx & ECX_AVX != 0 && avx_enabled());
+
+    if max_leaf >= 7 {
+        let ebx = cpuid_count(7, 0).ebx;
+        features.set(CpuFeatures::BMI1, ebx & EBX_BMI1 != 0);
+        features.set(CpuFeatures::BMI2, ebx & EBX_BMI2 != 0);
+        features.set(
+            CpuFeatures::AVX2,
+            ebx & EBX_AVX2 != 0 && features.contains(CpuFeatures::AVX),
+        );
+    }
+
+    let (max_extended_leaf, _) = cpuid_max(0x8000_0000);
+    if max_extended_leaf >= 0x8000_0001 {
+        let ecx = cpuid(0x8000_0001).ecx;
+        features.set(CpuFeatures::LZCNT, ecx & ECX_LZCNT != 0);
+    }
+
+    features
+}
+
+/// Returns true if the AVX state has been enabled by `init`.
+fn avx_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::OSXSAVE) && XCr0::read().contains(XCr0Flags::AVX)
+}
+
+fn cpuid(leaf: u32) -> CpuidResult {
+    cpuid_count(leaf, 0)
+}
+
+// The CPUID intrinsics are only marked as safe by recent toolchains.
+#[allow(unused_unsafe)]
+fn cpuid_count(leaf: u32, sub_leaf: u32) -> CpuidResult {
+    // SAFETY: CPUID is available on all x86_64 CPUs, and the callers only query supported leaves.
+    unsafe { __cpuid_count(leaf, sub_leaf) }
+}
+
+/// Returns the highest supported leaf and sub-leaf, for the range starting at `leaf`.
+#[allow(unused_unsafe)]
+fn cpuid_max(leaf: u32) -> (u32, u32) {
+    // SAFETY: CPUID is available on all x86_64 CPUs.
+    unsafe { __get_cpuid_max(leaf) }
+}
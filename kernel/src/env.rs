@@ -0,0 +1,117 @@
+//! Component Environments
+//!
+//! Each component owns a small key-value store, akin to environment variables, which is used to
+//! configure programs. A component created while another one is running starts with a copy of the
+//! environment of its creator.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Maximum number of variables per environment.
+pub const MAX_ENV_VARS: usize = 64;
+/// Maximum size of a key, in bytes.
+pub const MAX_KEY_SIZE: usize = 256;
+/// Maximum size of a value, in bytes.
+pub const MAX_VALUE_SIZE: usize = 4096;
+
+/// Separator between keys when listing an environment.
+pub const KEY_SEPARATOR: u8 = b'\0';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvError {
+    /// The key is empty, too large or contains the separator.
+    InvalidKey,
+    /// The value is too large.
+    InvalidValue,
+    /// The environment already holds the maximum number of variables.
+    Full,
+}
+
+/// A set of variables.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    vars: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Environment {
+    pub const fn new() -> Self {
+        Self {
+            vars: BTreeMap::new(),
+        }
+    }
+
+    /// Sets a variable, replacing the previous value if any.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), EnvError> {
+        if key.is_empty() || key.len() > MAX_KEY_SIZE || key.contains(&KEY_SEPARATOR) {
+            return Err(EnvError::InvalidKey);
+        }
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(EnvError::InvalidValue);
+        }
+
+        if let Some(previous) = self.vars.get_mut(key) {
+            previous.clear();
+            previous.extend_from_slice(value);
+            return Ok(());
+        }
+        if self.vars.len() >= MAX_ENV_VARS {
+            return Err(EnvError::Full);
+        }
+        self.vars.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    /// Returns the value of a variable, if any.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.vars.get(key).map(|value| value.as_slice())
+    }
+
+    /// Returns the size of the list of keys, as written by `write_keys`.
+    pub fn keys_size(&self) -> usize {
+        self.vars.keys().map(|key| key.len() + 1).sum()
+    }
+
+    /// Writes the keys in order, each followed by a separator.
+    ///
+    /// The buffer must be at least `keys_size` bytes long.
+    pub fn write_keys(&self, buf: &mut [u8]) {
+        let mut pos = 0;
+        for key in self.vars.keys() {
+            buf[pos..][..key.len()].copy_from_slice(key);
+            buf[pos + key.len()] = KEY_SEPARATOR;
+            pos += key.len() + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn set_and_get() {
+        let mut env = Environment::new();
+        assert_eq!(env.get(b"PATH"), None);
+        env.set(b"PATH", b"/bin").unwrap();
+        env.set(b"TERM", b"vga").unwrap();
+        env.set(b"PATH", b"/usr/bin").unwrap();
+        assert_eq!(env.get(b"PATH"), Some(b"/usr/bin".as_slice()));
+
+        // Keys are listed in order
+        let mut buf = [0xff; 10];
+        assert_eq!(env.keys_size(), 10);
+        env.write_keys(&mut buf);
+        assert_eq!(&buf, b"PATH\0TERM\0");
+    }
+
+    #[test_case]
+    fn invalid_vars() {
+        let mut env = Environment::new();
+        assert_eq!(env.set(b"", b"value"), Err(EnvError::InvalidKey));
+        assert_eq!(env.set(b"A\0B", b"value"), Err(EnvError::InvalidKey));
+        assert_eq!(
+            env.set(b"KEY", &[0; MAX_VALUE_SIZE + 1]),
+            Err(EnvError::InvalidValue)
+        );
+    }
+}
@@ -3,8 +3,10 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::future::Future;
 use core::pin::Pin;
-use core::task::{Context, Poll};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
 
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
@@ -12,24 +14,185 @@ use futures::stream::Stream;
 use futures::task::AtomicWaker;
 use futures::StreamExt;
 use spin::Mutex;
+use x86_64::instructions::interrupts;
 
-use crate::scheduler::{Scheduler, Task};
-use crate::wasm::{AsArgs, Component, ComponentFunc};
+use crate::kprintln;
+use crate::runtime::compilation::ModuleStatus;
+use crate::sched_trace::{self, SchedEvent};
+use crate::scheduler::{self, Sleep, Task};
+use crate::syscalls::ExternRef;
+use crate::wasm::{Component, ComponentFunc};
+use wasm::{Args, FuncType, Value, WasmType};
 
 // —————————————————————————————— Known Events —————————————————————————————— //
 
-pub static KEYBOARD_EVENTS: StaticEventSource<u8> = StaticEventSource::new();
-pub static TIMER_EVENTS: StaticEventSource<()> = StaticEventSource::new();
+pub static KEYBOARD_EVENTS: StaticEventSource<Event> = StaticEventSource::new();
+pub static TIMER_EVENTS: StaticEventSource<Event> = StaticEventSource::new();
+pub static POINTER_EVENTS: StaticEventSource<Event> = StaticEventSource::new();
+pub static MODULE_EVENTS: StaticEventSource<Event> = StaticEventSource::new();
+pub static VMA_EVENTS: StaticEventSource<Event> = StaticEventSource::new();
+
+/// The dispatcher of pointer events, components subscribe to it through a syscall.
+pub static POINTER_DISPATCHER: OnceCell<Arc<EventDispatcher>> = OnceCell::uninit();
+
+/// The dispatcher of module events, components subscribe to it through a syscall.
+pub static MODULE_DISPATCHER: OnceCell<Arc<EventDispatcher>> = OnceCell::uninit();
+
+/// The dispatcher of VMA events, components subscribe to it through a syscall.
+pub static VMA_DISPATCHER: OnceCell<Arc<EventDispatcher>> = OnceCell::uninit();
 
 pub(crate) fn push_keyboard_event(scancode: u8) {
     if let Some(queue) = KEYBOARD_EVENTS.try_get() {
-        queue.dispatch(scancode);
+        queue.dispatch(Event::new(EventKind::Keyboard).with(scancode as u32));
+        trace_queued(EventKind::Keyboard, queue, false);
     }
 }
 
 pub(crate) fn push_timer_event() {
     if let Some(queue) = TIMER_EVENTS.try_get() {
-        queue.dispatch(());
+        queue.dispatch(Event::new(EventKind::Timer));
+        trace_queued(EventKind::Timer, queue, false);
+    }
+}
+
+pub(crate) fn push_pointer_event(dx: i16, dy: i16, buttons: u8) {
+    if let Some(queue) = POINTER_EVENTS.try_get() {
+        let event = Event::new(EventKind::Pointer)
+            .with(dx as i32)
+            .with(dy as i32)
+            .with(buttons as u32);
+        // The mouse sends bursts of packets, drop them if the listeners can't keep up.
+        let dropped = queue.try_dispatch(event).is_err();
+        trace_queued(EventKind::Pointer, queue, dropped);
+    }
+}
+
+pub(crate) fn push_module_event(module: ExternRef, status: ModuleStatus) {
+    notify_ready();
+    if let Some(queue) = MODULE_EVENTS.try_get() {
+        let (compiled, total) = match status {
+            ModuleStatus::Compiling { compiled, total } => (compiled, total),
+            _ => (0, 0),
+        };
+        let event = Event::new(EventKind::Module)
+            .with(module)
+            .with(status.code())
+            .with(compiled)
+            .with(total);
+        // The status can also be queried with a syscall, drop the event if the listeners can't
+        // keep up.
+        let dropped = queue.try_dispatch(event).is_err();
+        trace_queued(EventKind::Module, queue, dropped);
+    }
+}
+
+pub(crate) fn push_vma_event(vma: ExternRef, offset: u64, size: u64) {
+    if let Some(queue) = VMA_EVENTS.try_get() {
+        let event = Event::new(EventKind::Vma).with(vma).with(offset).with(size);
+        // Writes are not lost if the event is dropped, the listeners only miss the notification.
+        let dropped = queue.try_dispatch(event).is_err();
+        trace_queued(EventKind::Vma, queue, dropped);
+    }
+}
+
+/// Records an event pushed to one of the known sources into the scheduler trace.
+fn trace_queued(kind: EventKind, source: &EventSource<Event>, dropped: bool) {
+    sched_trace::record(SchedEvent::EventQueued {
+        kind,
+        queue_depth: source.queue.len(),
+        dropped,
+    });
+}
+
+// ————————————————————————————————— Events ————————————————————————————————— //
+
+/// The maximum number of scalars carried by an event.
+///
+/// Listeners are called with at most `wasm::MAX_ARGS` arguments, one of which might be the kind.
+pub const MAX_EVENT_SCALARS: usize = 4;
+
+/// The kind of an event, used as a type tag by listeners of multiple event sources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum EventKind {
+    Timer = 1,
+    Keyboard = 2,
+    /// Pointer motion and buttons, carries `dx`, `dy` and the buttons state.
+    Pointer = 3,
+    /// Compilation progress of a module, carries the module, its status and the number of
+    /// compiled and total functions.
+    Module = 4,
+    /// A watched VMA was written, carries the VMA and the offset and size of the write.
+    Vma = 5,
+}
+
+/// An event, made of a kind and a small payload of scalars.
+///
+/// Events are pushed from interrupt handlers and therefore must not allocate. Payloads which do
+/// not fit in `MAX_EVENT_SCALARS` scalars are passed by reference, as a VMA handle followed by an
+/// offset and a size (see `EventKind::Vma`). Writing payloads into a ring in guest memory is not
+/// supported yet: no event source needs it so far.
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    kind: EventKind,
+    scalars: [Value; MAX_EVENT_SCALARS],
+    len: usize,
+}
+
+/// How an event is marshalled into the arguments of a listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// The listener receives the scalars of the payload.
+    Scalars,
+    /// The listener receives the kind of the event, followed by the scalars of the payload.
+    Tagged,
+}
+
+impl Event {
+    /// Creates an event with an empty payload.
+    pub const fn new(kind: EventKind) -> Self {
+        Self {
+            kind,
+            scalars: [Value::I32(0); MAX_EVENT_SCALARS],
+            len: 0,
+        }
+    }
+
+    /// Appends a scalar to the payload.
+    ///
+    /// Panics if the payload already holds `MAX_EVENT_SCALARS` scalars.
+    pub fn with<T>(mut self, scalar: T) -> Self
+    where
+        T: WasmType,
+    {
+        if self.len >= MAX_EVENT_SCALARS {
+            panic!(
+                "Too many scalars, events carry at most {}",
+                MAX_EVENT_SCALARS
+            );
+        }
+        self.scalars[self.len] = Value::from_wasm(scalar);
+        self.len += 1;
+        self
+    }
+
+    pub fn kind(&self) -> EventKind {
+        self.kind
+    }
+
+    pub fn scalars(&self) -> &[Value] {
+        &self.scalars[..self.len]
+    }
+
+    /// Marshals the event into the arguments of a listener.
+    pub fn marshal(&self, encoding: Encoding) -> Args {
+        let args = match encoding {
+            Encoding::Scalars => Args::new(),
+            Encoding::Tagged => Args::new().push(self.kind as u32),
+        };
+        self.scalars()
+            .iter()
+            .fold(args, |args, scalar| args.push_value(*scalar))
     }
 }
 
@@ -84,14 +247,23 @@ impl<T> EventSource<T> {
             .expect("Can't dispatch event: queue is full");
         self.waker.wake();
     }
+
+    /// Pushes an event to the queue and wake the corresponding event source, the event is handed
+    /// back if the queue is full.
+    pub fn try_dispatch(&self, item: T) -> Result<(), T> {
+        self.queue.push(item)?;
+        self.waker.wake();
+        Ok(())
+    }
 }
 
-struct SourceStream<T> {
+/// A stream of the events received by a source.
+pub(crate) struct SourceStream<T> {
     source: Arc<EventSource<T>>,
 }
 
 impl<T> SourceStream<T> {
-    fn new(source: Arc<EventSource<T>>) -> Pin<Box<Self>> {
+    pub(crate) fn new(source: Arc<EventSource<T>>) -> Pin<Box<Self>> {
         Box::pin(Self { source })
     }
 }
@@ -119,15 +291,21 @@ impl<T> Stream for SourceStream<T> {
 ///
 /// A dispatcher is connected to an event source, and can be scheduled to asyncronously wait on new
 /// events and dispatch them to listeners.
-pub struct EventDispatcher<T> {
-    listeners: Mutex<Vec<(Arc<Component>, ComponentFunc)>>,
-    source: Arc<EventSource<T>>,
+pub struct EventDispatcher {
+    listeners: Mutex<Vec<Listener>>,
+    source: Arc<EventSource<Event>>,
+}
+
+/// A guest function called on each event.
+struct Listener {
+    component: Arc<Component>,
+    handler: ComponentFunc,
+    encoding: Encoding,
+    /// The type of the handler, events are checked against it before each call.
+    ty: FuncType,
 }
 
-impl<T> EventDispatcher<T>
-where
-    T: AsArgs,
-{
+impl EventDispatcher {
     /// Creates a new event dispatcher with the given capacity.
     pub fn new(capacity: usize) -> Self {
         let queue = ArrayQueue::new(capacity);
@@ -138,37 +316,154 @@ where
         }
     }
 
-    pub fn source(&self) -> &Arc<EventSource<T>> {
+    pub fn source(&self) -> &Arc<EventSource<Event>> {
         &self.source
     }
 
     /// Registers a new listener for this event dispatcher.
-    pub fn add_listener(&self, component: Arc<Component>, handler: ComponentFunc) {
+    ///
+    /// The encoding selects how events are marshalled into the arguments of the handler.
+    pub fn add_listener(
+        &self,
+        component: Arc<Component>,
+        handler: ComponentFunc,
+        encoding: Encoding,
+    ) {
+        // The type is looked up once, without holding the lock of the listeners
+        let ty = component.get_func_type(handler);
         let mut listeners = self.listeners.lock();
-        listeners.push((component, handler));
+        listeners.push(Listener {
+            component,
+            handler,
+            encoding,
+            ty,
+        });
     }
 
     /// Creates a dispatch task.
     ///
-    /// The task asynchronously wait for event and dispatch them to the listeners.
-    pub fn dispatch(self: Arc<Self>, scheduler: Arc<Scheduler>) -> Task
-    where
-        T: 'static + Send,
-    {
+    /// The task asynchronously wait for event and dispatch them to the listeners, each handler
+    /// runs in its own task on the current scheduler.
+    pub fn dispatch(self: Arc<Self>) -> Task {
         let stream = SourceStream::new(self.source.clone());
-        Task::new(self.clone().as_promise(stream, scheduler))
+        Task::new(self.clone().as_promise(stream))
     }
 
-    async fn as_promise(
-        self: Arc<Self>,
-        mut stream: Pin<Box<SourceStream<T>>>,
-        scheduler: Arc<Scheduler>,
-    ) {
-        while let Some(item) = stream.next().await {
+    async fn as_promise(self: Arc<Self>, mut stream: Pin<Box<SourceStream<Event>>>) {
+        while let Some(event) = stream.next().await {
             let listeners = self.listeners.lock();
-            for (component, handler) in listeners.iter() {
-                scheduler.schedule(component.clone().run(*handler, item.as_args()));
+            sched_trace::record(SchedEvent::EventDispatched {
+                kind: event.kind(),
+                listeners: listeners.len(),
+            });
+            for listener in listeners.iter() {
+                // Killed components have no instances left to handle the event
+                if listener.component.is_killed() {
+                    continue;
+                }
+                let args = event.marshal(listener.encoding);
+                if !args.matches(&listener.ty) {
+                    kprintln!(
+                        "WARNING: can't dispatch {:?} event, handler expects {:?}",
+                        event.kind(),
+                        listener.ty.args()
+                    );
+                    continue;
+                }
+                let component = listener.component.clone();
+                scheduler::spawn(component.run_promise(listener.handler, args));
+            }
+        }
+    }
+}
+
+// ——————————————————————————————— Readiness ———————————————————————————————— //
+
+/// Incremented each time a kernel object might have become ready.
+static READY_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The tasks waiting for a kernel object to become ready.
+static READY_WAITERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+/// Wakes up the tasks waiting for kernel objects, which then check whether the objects they wait
+/// on are ready (e.g. a module whose compilation completed, or a component which became idle).
+pub(crate) fn notify_ready() {
+    // Interrupts are disabled to prevent deadlocks with notifications from interrupt handlers.
+    interrupts::without_interrupts(|| {
+        READY_GENERATION.fetch_add(1, Ordering::SeqCst);
+        for waker in READY_WAITERS.lock().drain(..) {
+            waker.wake();
+        }
+    });
+}
+
+/// Returns a future which completes on the next readiness notification, or once the number of
+/// timer interrupts reaches the deadline.
+///
+/// Completion does not imply that a given object is ready, waiters must check again.
+pub fn wait_ready(deadline: u64) -> WaitReady {
+    WaitReady {
+        generation: READY_GENERATION.load(Ordering::SeqCst),
+        timeout: scheduler::sleep_until(deadline),
+        registered: false,
+    }
+}
+
+pub struct WaitReady {
+    /// The readiness generation when the future was created.
+    generation: u64,
+    timeout: Sleep,
+    /// Whether the waker of the task has been registered.
+    registered: bool,
+}
+
+impl WaitReady {
+    fn notified(&self) -> bool {
+        READY_GENERATION.load(Ordering::SeqCst) != self.generation
+    }
+}
+
+impl Future for WaitReady {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        if self.notified() || Pin::new(&mut self.timeout).poll(ctx).is_ready() {
+            return Poll::Ready(());
+        }
+
+        // The waker is registered once: the future is also polled on each timer interrupt, and
+        // all the wakers of a task wake the same task.
+        if !self.registered {
+            self.registered = true;
+            interrupts::without_interrupts(|| READY_WAITERS.lock().push(ctx.waker().clone()));
+            // Check again in case of a notification before the registration
+            if self.notified() {
+                return Poll::Ready(());
             }
         }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test_case]
+    fn wait_ready_completes_on_notification() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut wait = wait_ready(u64::MAX);
+        assert!(Pin::new(&mut wait).poll(&mut ctx).is_pending());
+        assert!(Pin::new(&mut wait).poll(&mut ctx).is_pending());
+        notify_ready();
+        assert!(Pin::new(&mut wait).poll(&mut ctx).is_ready());
+    }
+
+    #[test_case]
+    fn wait_ready_completes_on_deadline() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut wait = wait_ready(scheduler::ticks());
+        assert!(Pin::new(&mut wait).poll(&mut ctx).is_ready());
     }
 }
@@ -0,0 +1,225 @@
+//! Fibers
+//!
+//! A fiber executes a function on its own stack. The function can suspend the fiber at any point,
+//! giving control back to the code which resumed it, and continues where it left off when the
+//! fiber is resumed again. Fibers let the kernel suspend guest executions, which would otherwise
+//! run to completion on the stack of the scheduler.
+
+use alloc::boxed::Box;
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::memory::{PageSize, Vma, VmaAllocator, PAGE_SIZE};
+use wasm::MemoryArea;
+
+/// Size of the stack of each fiber.
+///
+/// Stacks are preceded by an unmapped guard page: overflowing a fiber stack raises a page fault,
+/// which is reported as a trap if it was caused by guest code.
+pub const FIBER_STACK_SIZE: usize = 0x10000;
+
+/// The reason why a fiber suspended itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suspend {
+    /// The fiber can be resumed as soon as possible.
+    Yield,
+    /// The fiber must not be resumed before the given number of milliseconds.
+    Sleep(u64),
+    /// The fiber waits for a kernel object to become ready (see `events::notify_ready`), and must
+    /// be resumed at the latest once the number of timer interrupts reaches the given deadline.
+    Wait(u64),
+}
+
+/// The state of a fiber, after it gave control back.
+pub enum FiberState<T> {
+    /// The fiber suspended itself.
+    Suspended(Suspend),
+    /// The function of the fiber returned the given value.
+    Done(T),
+}
+
+/// A function executing on its own stack.
+///
+/// NOTE: dropping a suspended fiber frees its stack without unwinding it, the values owned by the
+/// suspended frames are leaked.
+pub struct Fiber<T> {
+    // The inner fiber is boxed so that it does not move while the fiber is alive.
+    inner: Box<Inner<T>>,
+}
+
+struct Inner<T> {
+    context: Context,
+    entry: Option<Box<dyn FnOnce() -> T + Send>>,
+    output: Option<T>,
+    /// Whether the function of the fiber returned.
+    done: bool,
+    stack: Vma,
+}
+
+/// The saved state of a fiber and of the code which resumed it.
+struct Context {
+    /// The stack pointer of the fiber, while it is suspended.
+    rsp: u64,
+    /// The stack pointer of the code which resumed the fiber, while the fiber is executing.
+    resumer_rsp: u64,
+    /// The reason of the last suspension.
+    suspend: Option<Suspend>,
+}
+
+/// The context of the fiber currently executing, null if no fiber is executing.
+static CURRENT_FIBER: AtomicPtr<Context> = AtomicPtr::new(ptr::null_mut());
+
+impl<T> Fiber<T> {
+    /// Creates a fiber, `f` starts executing the first time the fiber is resumed.
+    ///
+    /// The stack of the fiber is allocated from `alloc`, this fails if there is not enough free
+    /// memory.
+    pub fn new<F>(alloc: &VmaAllocator, f: F) -> Result<Self, ()>
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        // The first page of the reserved range is left unmapped and serves as guard page
+        let guard_page = alloc.reserve(PAGE_SIZE + FIBER_STACK_SIZE, PAGE_SIZE)?;
+        let stack =
+            alloc.with_capacity_at(FIBER_STACK_SIZE, guard_page + PAGE_SIZE, PageSize::Small)?;
+        let mut inner = Box::new(Inner {
+            context: Context {
+                rsp: 0,
+                resumer_rsp: 0,
+                suspend: None,
+            },
+            entry: Some(Box::new(f)),
+            output: None,
+            done: false,
+            stack,
+        });
+
+        // Prepare the stack so that the first switch to the fiber returns into the trampoline,
+        // which calls `fiber_main` with the inner fiber as argument.
+        let inner_ptr = &mut *inner as *mut Inner<T>;
+        let top = (inner.stack.as_mut_ptr() as usize + FIBER_STACK_SIZE) & !0xf;
+        let initial_frame: [u64; 7] = [
+            0,                                          // r15
+            0,                                          // r14
+            fiber_main::<T> as *const () as u64,        // r13
+            inner_ptr as u64,                           // r12
+            0,                                          // rbx
+            0,                                          // rbp
+            coral_fiber_trampoline as *const () as u64, // return address
+        ];
+        let rsp = top - initial_frame.len() * 8;
+        // SAFETY: the frame is written at the top of the fiber stack, which is large enough.
+        unsafe {
+            ptr::copy_nonoverlapping(initial_frame.as_ptr(), rsp as *mut u64, initial_frame.len())
+        };
+        inner.context.rsp = rsp as u64;
+
+        Ok(Self { inner })
+    }
+
+    /// Executes the fiber until it suspends itself or returns.
+    ///
+    /// Fibers can not be nested, and must not be resumed once they returned.
+    pub fn resume(&mut self) -> FiberState<T> {
+        assert!(!self.inner.done, "Can't resume a fiber that returned");
+
+        let context = &mut self.inner.context as *mut Context;
+        let previous = CURRENT_FIBER.swap(context, Ordering::SeqCst);
+        assert!(previous.is_null(), "Fibers can not be nested");
+
+        // SAFETY: the fiber stack is either the initial frame, or the state saved by the last
+        // suspension.
+        unsafe { coral_fiber_switch(&mut (*context).resumer_rsp, (*context).rsp) };
+        CURRENT_FIBER.store(ptr::null_mut(), Ordering::SeqCst);
+
+        match self.inner.output.take() {
+            Some(output) => {
+                self.inner.done = true;
+                FiberState::Done(output)
+            }
+            None => {
+                let suspend = self.inner.context.suspend.take();
+                FiberState::Suspended(suspend.expect("Fiber gave control back without reason"))
+            }
+        }
+    }
+}
+
+/// Suspends the fiber currently executing, which returns from `suspend` when resumed.
+///
+/// Returns false, without suspending anything, if no fiber is executing.
+pub fn suspend(reason: Suspend) -> bool {
+    let context = CURRENT_FIBER.load(Ordering::SeqCst);
+    if context.is_null() {
+        return false;
+    }
+
+    // SAFETY: the context is valid while the fiber is executing, and we are executing on the
+    // stack of the fiber.
+    unsafe {
+        (*context).suspend = Some(reason);
+        coral_fiber_switch(&mut (*context).rsp, (*context).resumer_rsp);
+    }
+    true
+}
+
+/// The first function executed by a fiber, runs the fiber function and gives control back.
+extern "C" fn fiber_main<T>(inner: *mut Inner<T>) -> ! {
+    // SAFETY: the inner fiber outlives the execution of the fiber, and is not accessed by the
+    // resumer until we give control back.
+    unsafe {
+        let entry = (*inner).entry.take().expect("Fiber started twice");
+        let output = entry();
+        (*inner).output = Some(output);
+        coral_fiber_switch(&mut (*inner).context.rsp, (*inner).context.resumer_rsp);
+    }
+    unreachable!("Fiber resumed after returning");
+}
+
+extern "C" {
+    /// Saves the callee-saved registers on the current stack, stores the stack pointer into
+    /// `save_rsp`, then switches to the stack `rsp` and restores the registers saved there.
+    fn coral_fiber_switch(save_rsp: *mut u64, rsp: u64);
+
+    /// Calls the function in r13 with r12 as argument, the function must not return.
+    fn coral_fiber_trampoline();
+}
+
+global_asm!(
+    ".global coral_fiber_switch",
+    "coral_fiber_switch:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global coral_fiber_trampoline",
+    "coral_fiber_trampoline:",
+    "mov rdi, r12",
+    "call r13",
+    "ud2",
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fibers need a VMA allocator for their stack, see the heap_allocation integration tests.
+
+    #[test_case]
+    fn suspend_outside_of_fiber() {
+        assert!(!suspend(Suspend::Yield));
+    }
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use lazy_static::lazy_static;
 use x86_64::instructions::segmentation::{Segment, CS};
 use x86_64::instructions::tables::load_tss;
@@ -6,32 +8,54 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const INVALID_OPCODE_IST_INDEX: u16 = 2;
+pub const DIVIDE_ERROR_IST_INDEX: u16 = 3;
+
+/// Size of each interrupt stack.
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+/// Number of nested page faults with their own interrupt stack, see `with_nested_page_fault_stack`.
+const PAGE_FAULT_NESTING: usize = 3;
+
+/// The page fault interrupt stacks, one per nesting level.
+static mut PAGE_FAULT_STACKS: [[u8; IST_STACK_SIZE]; PAGE_FAULT_NESTING] =
+    [[0; IST_STACK_SIZE]; PAGE_FAULT_NESTING];
+
+/// The number of page faults being handled.
+static PAGE_FAULT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocates a new static interrupt stack and returns its top address.
+macro_rules! ist_stack {
+    () => {{
+        static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + IST_STACK_SIZE;
+        stack_end
+    }};
+}
 
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+/// The Task State Segment.
+///
+/// The faults that can be raised by guest code (and translated into wasm traps) run on their own
+/// stack, so that their handlers never rely on the stack in use when the fault occurred.
+///
+/// The interrupt stacks are set by `init`, the page fault stack is then moved by the page fault
+/// handler while it runs.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // SAFETY: the TSS is only modified with interrupts disabled, see `set_page_fault_stack`.
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
         (
             gdt,
             Selectors {
@@ -43,9 +67,51 @@ lazy_static! {
 }
 
 pub fn init() {
+    let double_fault_stack = ist_stack!();
+    let invalid_opcode_stack = ist_stack!();
+    let divide_error_stack = ist_stack!();
+    // SAFETY: the TSS is not loaded yet.
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+        TSS.interrupt_stack_table[INVALID_OPCODE_IST_INDEX as usize] = invalid_opcode_stack;
+        TSS.interrupt_stack_table[DIVIDE_ERROR_IST_INDEX as usize] = divide_error_stack;
+    }
+    set_page_fault_stack(0);
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Runs the handler of a page fault with the page fault interrupt stack moved to the next nesting
+/// level, so that a page fault raised by `handler` does not overwrite the frame of the one being
+/// handled.
+///
+/// Must be called with interrupts disabled, panics if page faults are nested too deeply.
+pub fn with_nested_page_fault_stack<F, R>(handler: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let depth = PAGE_FAULT_DEPTH.fetch_add(1, Ordering::SeqCst) + 1;
+    if depth >= PAGE_FAULT_NESTING {
+        // There is no stack left for the next page fault
+        panic!("EXCEPTION: too many nested page faults");
+    }
+    set_page_fault_stack(depth);
+    let result = handler();
+    set_page_fault_stack(depth - 1);
+    PAGE_FAULT_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+/// Points the page fault entry of the interrupt stack table to the stack of the given nesting
+/// level.
+fn set_page_fault_stack(level: usize) {
+    // SAFETY: the CPU only reads the TSS when an interrupt is raised, and the page fault stack is
+    // only moved with interrupts disabled.
+    unsafe {
+        let stack_start = VirtAddr::from_ptr(&PAGE_FAULT_STACKS[level]);
+        TSS.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = stack_start + IST_STACK_SIZE;
+    }
+}
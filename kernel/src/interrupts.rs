@@ -1,11 +1,15 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
 
 use crate::events::{push_keyboard_event, push_timer_event};
-use crate::{gdt, kprintln};
+use crate::{allocator, early_println, gdt, kprintln, mouse, profiler, runtime, scheduler, wasm};
 
 pub const PORT_SCANCODE: u16 = 0x60;
 
@@ -20,8 +24,24 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 1,
+    Mouse = PIC_2_OFFSET + 4,
 }
 
+/// Vectors of the CPU exceptions with a dedicated handler.
+const DIVIDE_ERROR_VECTOR: u8 = 0;
+const BREAKPOINT_VECTOR: u8 = 3;
+const INVALID_OPCODE_VECTOR: u8 = 6;
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+const PAGE_FAULT_VECTOR: u8 = 14;
+
+/// Number of interrupts received since boot, by vector.
+static INTERRUPT_COUNTS: [AtomicU64; 256] = {
+    // A constant is needed to repeat a non-Copy value
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
@@ -29,14 +49,23 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+            idt.invalid_opcode
+                .set_handler_fn(invalid_opcode_handler)
+                .set_stack_index(gdt::INVALID_OPCODE_IST_INDEX);
+            idt.divide_error
+                .set_handler_fn(divide_error_handler)
+                .set_stack_index(gdt::DIVIDE_ERROR_IST_INDEX);
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
         idt
     };
 }
@@ -46,29 +75,77 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Records an interrupt, called first thing by each handler.
+fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Calls `f` with the vector and the number of occurrences of each interrupt received since boot,
+/// by increasing vector.
+pub fn for_each_interrupt_count<F>(mut f: F)
+where
+    F: FnMut(u8, u64),
+{
+    for (vector, count) in INTERRUPT_COUNTS.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        if count > 0 {
+            f(vector as u8, count);
+        }
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    count_interrupt(BREAKPOINT_VECTOR);
     kprintln!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    panic!(
-        "EXCEPTION: PAGE FAULT {:#?}\n{:#?}",
-        error_code, stack_frame
-    );
+    count_interrupt(PAGE_FAULT_VECTOR);
+    let address = Cr2::read();
+
+    // A page fault raised while handling this one gets a stack of its own
+    gdt::with_nested_page_fault_stack(|| {
+        // Writes to copy-on-write pages are resolved by copying the page, then retried
+        let cow_fault =
+            PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+        if error_code.contains(cow_fault) && runtime::resolve_copy_on_write(address) {
+            return;
+        }
+        handle_fault(Fault::PageFault(address, error_code), &mut stack_frame);
+    });
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(mut stack_frame: InterruptStackFrame) {
+    count_interrupt(INVALID_OPCODE_VECTOR);
+    handle_fault(Fault::InvalidOpcode, &mut stack_frame);
+}
+
+extern "x86-interrupt" fn divide_error_handler(mut stack_frame: InterruptStackFrame) {
+    count_interrupt(DIVIDE_ERROR_VECTOR);
+    handle_fault(Fault::DivideError, &mut stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    count_interrupt(DOUBLE_FAULT_VECTOR);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    push_timer_event();
+// NOTE: the hardware interrupt handlers run in an alloc-free context, see `allocator`.
+
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::Timer.as_u8());
+    allocator::alloc_free(|| {
+        profiler::sample(stack_frame.instruction_pointer.as_u64());
+        scheduler::tick();
+        wasm::request_preemption();
+        push_timer_event();
+    });
 
     unsafe {
         PICS.lock()
@@ -77,9 +154,12 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let mut port = Port::new(PORT_SCANCODE);
-    let scancode: u8 = unsafe { port.read() };
-    push_keyboard_event(scancode);
+    count_interrupt(InterruptIndex::Keyboard.as_u8());
+    allocator::alloc_free(|| {
+        let mut port = Port::new(PORT_SCANCODE);
+        let scancode: u8 = unsafe { port.read() };
+        push_keyboard_event(scancode);
+    });
 
     unsafe {
         PICS.lock()
@@ -87,6 +167,46 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::Mouse.as_u8());
+    allocator::alloc_free(mouse::handle_interrupt);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    }
+}
+
+// ————————————————————————————————— Faults ————————————————————————————————— //
+
+/// A fault that can be raised by guest code.
+///
+/// Wasm traps are implemented with hardware faults: out of bounds accesses hit unmapped pages,
+/// explicit traps are `ud2` instructions and integer divisions by zero raise divide errors.
+#[derive(Debug)]
+#[allow(dead_code)] // Fields are only read through the `Debug` implementation
+enum Fault {
+    PageFault(VirtAddr, PageFaultErrorCode),
+    InvalidOpcode,
+    DivideError,
+}
+
+/// Entry point of the fault-to-trap translation.
+///
+/// This runs on a dedicated interrupt stack (see `gdt`), it must not access the stack in use when
+/// the fault occurred as it is controlled by the guest. Faults can be raised with any lock held,
+/// traps are therefore logged through the lock-free early console.
+fn handle_fault(fault: Fault, stack_frame: &mut InterruptStackFrame) {
+    let faulting_ip = stack_frame.instruction_pointer;
+
+    // SAFETY: we are in a fault handler, with the stack frame of that fault.
+    if unsafe { wasm::recover_from_trap(stack_frame) } {
+        early_println!("Trap: {:?} at {:?}", fault, faulting_ip);
+        return;
+    }
+    panic!("EXCEPTION: {:?}\n{:#?}", fault, stack_frame);
+}
+
 impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
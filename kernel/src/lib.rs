@@ -20,16 +20,30 @@ use bootloader::BootInfo;
 use core::panic::PanicInfo;
 
 pub mod allocator;
+pub mod boot;
+pub mod compositor;
+pub mod cpu;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod mouse;
+pub mod oom;
+pub mod power;
+pub mod profiler;
 pub mod qemu;
 pub mod serial;
+pub mod test_device;
 pub mod syscalls;
 pub mod runtime;
 pub mod scheduler;
+pub mod selftest;
+pub mod sched_trace;
+pub mod services;
 pub mod wasm;
 pub mod events;
+pub mod env;
+pub mod fiber;
+pub mod supervisor;
 
 pub use memory::init as init_memory;
 
@@ -39,6 +53,7 @@ entry_point!(test_kernel_main);
 // Entry point for `cargo test`
 #[cfg(test)]
 fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+    test_device::init();
     init();
     test_main();
 
@@ -50,9 +65,11 @@ pub fn init() {
     // Initialize description tables
     gdt::init();
     interrupts::init_idt();
+    cpu::init();
 
     // Initialize hardware interrupt
     unsafe { interrupts::PICS.lock().initialize() };
+    mouse::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -92,7 +109,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
         test.run();
     }
 
-    qemu::exit(qemu::ExitCode::Success);
+    test_device::device().exit(qemu::ExitCode::Success);
 }
 
 #[cfg(test)]
@@ -105,7 +122,7 @@ fn panic(info: &PanicInfo) -> ! {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     debug_println!("[failed]\n");
     debug_println!("Error: {}\n", info);
-    qemu::exit(qemu::ExitCode::Failed);
+    test_device::device().exit(qemu::ExitCode::Failed);
     hlt_loop();
 }
 
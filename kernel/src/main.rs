@@ -5,24 +5,28 @@
 
 extern crate alloc;
 
-use alloc::string::String;
 use alloc::sync::Arc;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use core::ptr::NonNull;
 
-use compiler::{Compiler, X86_64Compiler};
-use kernel::kprintln;
-use kernel::memory::Vma;
-use kernel::runtime::{KoIndex, ACTIVE_VMA};
-use kernel::wasm::Args;
+use compiler::{Compilation, Compiler, CompilerOptions, X86_64Compiler};
+use kernel::boot::Manifest;
+use kernel::runtime::{ModuleCompilation, PageSizes};
+use kernel::{early_println, kprint, kprintln};
+use wasm::WasmModule;
 
 /// The first user program to run, expected to boostrap userspace.
 const WASM_USERBOOT: &'static [u8] = std::include_bytes!("../wasm/userboot.wasm");
+/// The modules the boot manifest can instantiate, by name.
+const BOOT_MODULES: &[(&str, &[u8])] = &[("userboot", WASM_USERBOOT)];
+/// Describes the userspace to build at boot time.
+const BOOT_MANIFEST: &str = std::include_str!("../boot.toml");
 
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    // Output goes through the early console until memory is initialized
+    kernel::serial::init_early();
     kprintln!("Hello, {}!", "World");
 
     kernel::init();
@@ -33,79 +37,70 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
+    // Generate code for the features of this CPU
+    let cpu_features = kernel::cpu::features();
+    kprint!("CPU features:");
+    for name in cpu_features.names() {
+        kprint!(" {}", name);
+    }
+    kprint!("\n");
+    let options = CompilerOptions {
+        cpu_features: Some(cpu_features),
+        optimize: true,
+        call_counters: true,
+        // Guests yield to the other tasks at each timer interrupt, see `wasm::request_preemption`
+        preemption_checks: true,
+        // The code is copied for each instance, bounds check traps are reported per function
+        shared_traps: true,
+        ..Default::default()
+    };
+
     // Register runtime compiler
-    let compiler = Box::new(|wasm: &[u8]| {
-        let mut compiler = X86_64Compiler::new();
+    let compiler = Box::new(move |wasm: &[u8]| {
+        let mut compiler = X86_64Compiler::with_options(options);
         compiler
             .parse(wasm)
-            .map_err(|err| kprintln!("Failed to parse: {:?}", err))?;
-        compiler
-            .compile()
-            .map_err(|err| kprintln!("Failed to compule: {:?}", err))
+            .map_err(|err| kprintln!("Compilation error: {}", err))?;
+        let compilation = KernelCompilation(compiler.start_compilation());
+        Ok(Box::new(compilation) as Box<dyn ModuleCompilation>)
     });
-    kernel::runtime::init(allocator);
+    kernel::runtime::init(allocator, PageSizes::default());
     kernel::runtime::register_compiler(compiler);
 
-    // Compile & initialize userboot
-    let mut compiler = X86_64Compiler::new();
-    compiler
-        .parse(WASM_USERBOOT)
-        .expect("Failed parsing userboot");
-    let user_module = compiler.compile().expect("Failed compiling userboot");
-
-    // Creates coral module
-    let vga_buffer =
-        unsafe { Vma::from_raw(NonNull::new(0xb8000 as *mut u8).unwrap(), 80 * 25 * 2) };
-    let vga_idx = ACTIVE_VMA.insert(Arc::new(vga_buffer)).into_externref();
-    let coral_handles_table = vec![vga_idx];
-    let coral_module = kernel::syscalls::build_syscall_module(coral_handles_table);
-
-    // Initialize the Coral native module
-    let component = kernel::wasm::Component::new();
-    let coral_idx = component
-        .add_instance(&coral_module)
-        .expect("Failed to instantiate Coral module");
-    component.push_import(String::from("coral"), coral_idx);
-    let userboot_idx = component
-        .add_instance(&user_module)
-        .expect("Failed to instantiate coral syscalls module");
-    let userboot_init = component
-        .get_func("init", userboot_idx)
-        .expect("Failed to retrieve 'init' from userboot instance");
-    let userboot_tick = component
-        .get_func("tick", userboot_idx)
-        .expect("Failed to retrieve 'tick' from userboot instance");
-    let userboot_key = component
-        .get_func("press_key", userboot_idx)
-        .expect("Failes to retrieve 'press_key' from userboot instance");
-    let component = Arc::new(component);
-
-    // Schaduler and events
+    // Build the boot userspace
     let scheduler = Arc::new(kernel::scheduler::Scheduler::new());
-
-    // Keyboard events
-    let keyboard_dispatcher = Arc::new(kernel::events::EventDispatcher::new(128));
-    let keyboard_source = keyboard_dispatcher.source().clone();
-    kernel::events::KEYBOARD_EVENTS.initialize(keyboard_source);
-    keyboard_dispatcher.add_listener(component.clone(), userboot_key);
-    scheduler.schedule(keyboard_dispatcher.dispatch(scheduler.clone()));
-
-    // Timer events
-    let timer_dispatcher = Arc::new(kernel::events::EventDispatcher::new(128));
-    let timer_source = timer_dispatcher.source().clone();
-    kernel::events::TIMER_EVENTS.initialize(timer_source);
-    timer_dispatcher.add_listener(component.clone(), userboot_tick);
-    scheduler.schedule(timer_dispatcher.dispatch(scheduler.clone()));
-
-    // Schedule userboot
-    scheduler.schedule(component.run(userboot_init, Args::new()));
+    let manifest = Manifest::parse(BOOT_MANIFEST).expect("Invalid boot manifest");
+    kernel::boot::boot(&manifest, BOOT_MODULES, &scheduler).expect("Failed to boot userspace");
     scheduler.run();
 }
 
+/// Exposes the compilations of the compiler crate to the kernel.
+struct KernelCompilation(Compilation);
+
+impl ModuleCompilation for KernelCompilation {
+    fn progress(&self) -> (usize, usize) {
+        self.0.progress()
+    }
+
+    fn compile_next(&mut self) -> Result<bool, ()> {
+        self.0
+            .compile_next()
+            .map_err(|err| kprintln!("Compilation error: {}", err))
+    }
+
+    fn finish(self: Box<Self>) -> Result<WasmModule, ()> {
+        self.0
+            .finish()
+            .map(|(module, _stats)| module)
+            .map_err(|err| kprintln!("Compilation error: {}", err))
+    }
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    kprintln!("{}", info);
+    // The serial lock might be held by the panicking code
+    early_println!("{}", info);
 
     kernel::hlt_loop();
 }
@@ -1,25 +1,88 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::marker::PhantomData;
-use core::ops::DerefMut;
+use core::ops::{Deref, DerefMut};
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use bootloader::BootInfo;
 use spin::{Mutex, MutexGuard};
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::frame::PhysFrame;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
 use x86_64::structures::paging::page::Page;
 use x86_64::structures::paging::page_table::{PageTable, PageTableFlags};
-use x86_64::structures::paging::{Mapper, OffsetPageTable};
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Size2MiB, Translate};
 use x86_64::{PhysAddr, VirtAddr};
 
-use crate::allocator;
+use crate::{allocator, serial};
 use wasm::MemoryArea;
 
 // TODO: Be generic over page sizes.
-const PAGE_SIZE: usize = 0x1000;
+pub const PAGE_SIZE: usize = 0x1000;
+/// Size of large pages, which are mapped by a single level 2 entry.
+pub const HUGE_PAGE_SIZE: usize = 0x20_0000;
 const NB_PTE_ENTRIES: usize = 512;
 
+/// Marks copy-on-write pages, using one of the page table bits available to the OS.
+///
+/// Copy-on-write pages are mapped read-only, writing to them raises a page fault which is resolved
+/// by copying the page (see `VmaAllocator::resolve_copy_on_write`).
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Number of frames kept for the kernel: page tables and copy-on-write faults can still be served
+/// once areas can no longer be allocated.
+pub const EMERGENCY_FRAMES: usize = 256;
+/// Memory is considered low once fewer frames remain outside of the emergency pool.
+pub const LOW_MEMORY_FRAMES: usize = 2048;
+
+/// The size of the pages backing a VMA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4 KiB pages only.
+    Small,
+    /// 2 MiB pages for each aligned 2 MiB range within the area, 4 KiB pages for the remainder or
+    /// when no physically contiguous 2 MiB frame is available.
+    Huge,
+}
+
+impl PageSize {
+    /// Returns the alignment at which an area of the given size should be reserved, so that it
+    /// can be backed by pages of that size.
+    pub fn alignment(self, size: usize) -> usize {
+        match self {
+            PageSize::Huge if size >= HUGE_PAGE_SIZE => HUGE_PAGE_SIZE,
+            _ => PAGE_SIZE,
+        }
+    }
+}
+
+/// How much physical memory remains available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPressure {
+    Normal,
+    /// Fewer than `LOW_MEMORY_FRAMES` frames remain outside of the emergency pool.
+    Low,
+    /// The emergency pool is in use.
+    Critical,
+}
+
+impl MemoryPressure {
+    /// Returns the memory pressure given the number of free frames.
+    pub fn from_free_frames(free_frames: usize) -> Self {
+        if free_frames < EMERGENCY_FRAMES {
+            MemoryPressure::Critical
+        } else if free_frames < EMERGENCY_FRAMES + LOW_MEMORY_FRAMES {
+            MemoryPressure::Low
+        } else {
+            MemoryPressure::Normal
+        }
+    }
+}
+
 // ————————————————————————— Re-export definitions —————————————————————————— //
 
 pub use x86_64::structures::paging::page::Size4KiB;
@@ -47,15 +110,12 @@ pub unsafe fn init(boot_info: &'static BootInfo) -> Result<VmaAllocator, ()> {
 
     // Initialize the heap.
     allocator::init_heap(&mut mapper, &mut frame_allocator).map_err(|_| ())?;
+    serial::end_early_boot();
 
     // Create a memory map once the heap has been allocated.
     let memory_map = VirtualMemoryMap::new_from_mapping(mapper.level_4_table());
 
-    Ok(VmaAllocator::new(
-        mapper,
-        memory_map,
-        frame_allocator,
-    ))
+    Ok(VmaAllocator::new(mapper, memory_map, frame_allocator))
 }
 
 /// This function is unsafe because the caller must guarantee that the
@@ -73,9 +133,9 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 }
 
 // ———————————————————————————— Frame Allocator ————————————————————————————— //
-// NOTE: this implementation comes from [1], it is simple but don't allow     //
-// frame reuse and has an allocation inf O(n) where n is the number of        //
-// already allocated framed.                                                  //
+// NOTE: this implementation comes from [1], it is simple but has an          //
+// allocation in O(n) where n is the number of already allocated frames.      //
+// Released frames are kept aside and handed out first.                       //
 //                                                                            //
 // [1]: https://os.phil-opp.com/paging-implementation/                        //
 // —————————————————————————————————————————————————————————————————————————— //
@@ -84,6 +144,18 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// Frames skipped to reach the start of a huge frame, handed out before the next ones.
+    skipped: Vec<PhysFrame>,
+    /// Frames released after use, handed out before any other.
+    released: Vec<PhysFrame>,
+    /// The number of mappings of the frames mapped more than once, such as the frames shared by
+    /// copy-on-write copies. Other allocated frames have a single mapping.
+    shared: BTreeMap<PhysFrame, usize>,
+    /// The total number of usable frames.
+    nb_usable: usize,
+    /// The number of frames set aside for the pages mapping the zero frame, which get their own
+    /// frame on their first write.
+    reserved: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -93,9 +165,74 @@ impl BootInfoFrameAllocator {
     /// memory map is valid. The main requirement is that all frames that are marked
     /// as `USABLE` in it are really unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let mut allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            skipped: Vec::new(),
+            released: Vec::new(),
+            shared: BTreeMap::new(),
+            nb_usable: 0,
+            reserved: 0,
+        };
+        allocator.nb_usable = allocator.usable_frames().count();
+        allocator
+    }
+
+    /// Returns the number of frames which can still be allocated, reserved frames excluded.
+    pub fn nb_free_frames(&self) -> usize {
+        let nb_free =
+            self.nb_usable.saturating_sub(self.next) + self.skipped.len() + self.released.len();
+        nb_free.saturating_sub(self.reserved)
+    }
+
+    /// Sets a frame aside for a new mapping of the zero frame, fails if no frame is available.
+    fn reserve_frame(&mut self) -> Result<(), ()> {
+        if self.nb_free_frames() == 0 {
+            return Err(());
+        }
+        self.reserved += 1;
+        Ok(())
+    }
+
+    /// Gives back the frame reserved for a mapping of the zero frame which has been removed.
+    fn unreserve_frame(&mut self) {
+        debug_assert!(self.reserved > 0);
+        self.reserved = self.reserved.saturating_sub(1);
+    }
+
+    /// Allocates the frame reserved for a mapping of the zero frame, which is removed.
+    fn allocate_reserved_frame(&mut self) -> Option<PhysFrame> {
+        self.unreserve_frame();
+        self.take_frame()
+    }
+
+    /// Makes a frame available again.
+    ///
+    /// SAFETY: the frame must not be used anymore, in particular it must no longer be mapped.
+    unsafe fn release_frame(&mut self, frame: PhysFrame) {
+        self.released.push(frame);
+    }
+
+    /// Records an additional mapping of an allocated frame.
+    fn share_frame(&mut self, frame: PhysFrame) {
+        *self.shared.entry(frame).or_insert(1) += 1;
+    }
+
+    /// Returns true if the frame has more than one mapping.
+    fn is_shared(&self, frame: PhysFrame) -> bool {
+        self.shared.contains_key(&frame)
+    }
+
+    /// Records the removal of a mapping of a frame, which is released with its last mapping.
+    ///
+    /// SAFETY: the mapping must have been removed.
+    unsafe fn unmap_frame(&mut self, frame: PhysFrame) {
+        match self.shared.get_mut(&frame) {
+            Some(mappings) if *mappings > 2 => *mappings -= 1,
+            Some(_) => {
+                self.shared.remove(&frame);
+            }
+            None => self.release_frame(frame),
         }
     }
 
@@ -112,11 +249,62 @@ impl BootInfoFrameAllocator {
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 
+    /// Allocates a frame which is not reserved.
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.nb_free_frames() == 0 {
+            return None;
+        }
+        self.take_frame()
+    }
+
+    /// Allocates a frame, including reserved ones.
+    fn take_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.released.pop() {
+            return Some(frame);
+        }
+        if let Some(frame) = self.skipped.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
+
+    /// Allocates a 2 MiB frame, made of physically contiguous usable frames.
+    ///
+    /// The frames skipped to reach a 2 MiB boundary are kept for later allocations. Returns `None`
+    /// if no such frame remains, in which case no frame is consumed.
+    fn allocate_huge_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frames_per_huge_frame = HUGE_PAGE_SIZE / PAGE_SIZE;
+        if self.nb_free_frames() < frames_per_huge_frame {
+            return None;
+        }
+        let mut previous: Option<PhysFrame> = None;
+        let mut run_start: Option<(usize, PhysFrame)> = None;
+        let mut found = None;
+        for (idx, frame) in self.usable_frames().enumerate().skip(self.next) {
+            let is_contiguous = matches!(previous, Some(previous) if previous + 1 == frame);
+            previous = Some(frame);
+            if !is_contiguous {
+                run_start = None;
+            }
+            if run_start.is_none() && frame.start_address().is_aligned(HUGE_PAGE_SIZE as u64) {
+                run_start = Some((idx, frame));
+            }
+            if let Some((start_idx, start)) = run_start {
+                if idx + 1 - start_idx == frames_per_huge_frame {
+                    found = Some((start_idx, idx + 1, start));
+                    break;
+                }
+            }
+        }
+
+        let (start_idx, end_idx, start) = found?;
+        let skipped = self.usable_frames().skip(self.next);
+        self.skipped.extend(skipped.take(start_idx - self.next));
+        self.next = end_idx;
+        PhysFrame::from_start_address(start.start_address()).ok()
+    }
 }
 
 unsafe impl x86_64::structures::paging::FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -172,7 +360,13 @@ impl VirtualMemoryMap {
     /// No frames are allocated, but the area is marked as reserved, preventing future collisions
     /// with other areas.
     pub fn reserve_area(&mut self, size: usize) -> Result<VirtAddr, ()> {
-        let start_of_area = self.cursor;
+        self.reserve_aligned_area(size, PAGE_SIZE)
+    }
+
+    /// Reserves an area in the virtual address space, starting at a multiple of `align` (which
+    /// must be a power of two, at least a page).
+    pub fn reserve_aligned_area(&mut self, size: usize, align: usize) -> Result<VirtAddr, ()> {
+        let start_of_area = self.cursor.align_up(align as u64);
         let end_of_area = (start_of_area + size).align_up(PAGE_SIZE as u64);
         if end_of_area > self.end_at {
             return Err(());
@@ -185,7 +379,9 @@ impl VirtualMemoryMap {
 // —————————————————————————— Virtual Memory Area ——————————————————————————— //
 
 /// A Virtual Memory Area.
-// TODO: Free the area on drop.
+///
+/// The frames of an area are released when it is dropped. Frames shared with copy-on-write copies
+/// are released once their last mapping goes away.
 pub struct Vma {
     ptr: NonNull<u8>,
     nb_pages: usize,
@@ -193,6 +389,9 @@ pub struct Vma {
     #[allow(unused)]
     kind: VmaKind,
     vma_allocator: Option<VmaAllocator>,
+    state: Mutex<VmaState>,
+    /// Whether writes through the kernel emit VMA events.
+    watched: AtomicBool,
     marker: PhantomData<u8>,
 }
 
@@ -225,10 +424,38 @@ impl Vma {
         // The assumption is not necessary for correctness here, but should still hold.
         debug_assert!(virt_addr.is_aligned(PAGE_SIZE as u64));
 
-        for _ in 0..self.nb_pages {
+        let end = virt_addr + self.nb_pages * PAGE_SIZE;
+        while virt_addr < end {
+            // Huge pages are always fully contained within the area
+            if let TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } = mapper.translate(virt_addr)
+            {
+                let page = Page::<Size2MiB>::containing_address(virt_addr);
+                unsafe {
+                    mapper.update_flags(page, flags).map_err(|_| ())?.flush();
+                }
+                virt_addr += HUGE_PAGE_SIZE;
+                continue;
+            }
+
+            // Copy-on-write pages only become writable once copied
+            let page_flags = match mapper.translate(virt_addr) {
+                TranslateResult::Mapped { flags: current, .. }
+                    if current.contains(COPY_ON_WRITE)
+                        && flags.contains(PageTableFlags::WRITABLE) =>
+                {
+                    (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+                }
+                _ => flags,
+            };
             let page = Page::<Size4KiB>::containing_address(virt_addr);
             unsafe {
-                mapper.update_flags(page, flags).map_err(|_| ())?.flush();
+                mapper
+                    .update_flags(page, page_flags)
+                    .map_err(|_| ())?
+                    .flush();
             }
             virt_addr += PAGE_SIZE;
         }
@@ -254,6 +481,8 @@ impl Vma {
             size,
             kind: VmaKind::Static,
             vma_allocator: None,
+            state: Mutex::new(VmaState::Exclusive),
+            watched: AtomicBool::new(false),
             marker: PhantomData,
         }
     }
@@ -318,6 +547,155 @@ impl Vma {
     pub fn size(&self) -> usize {
         self.size
     }
+
+    /// Watches the VMA: writes through the kernel (e.g. `vma_write`) emit VMA events from now on.
+    ///
+    /// NOTE: writes by the owner of the VMA, such as an instance using it as heap, are not
+    /// detected.
+    pub fn watch(&self) {
+        self.watched.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if the VMA is watched.
+    pub fn is_watched(&self) -> bool {
+        self.watched.load(Ordering::SeqCst)
+    }
+}
+
+// ————————————————————————————— VMA Ownership —————————————————————————————— //
+
+/// The ownership state of a VMA, which restricts how its content can be accessed.
+///
+/// A VMA starts exclusive: it is owned by a single party (e.g. the instance using it as heap),
+/// which can mutate it at any time. The kernel borrows the VMA in shared mode while reading from
+/// it, and refuses to write to it during that time. Once sealed, the content of the VMA never
+/// changes again and can be read without copying it first (e.g. to compile a module).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaState {
+    /// The VMA can be read and written by its owner.
+    Exclusive,
+    /// The kernel is reading the VMA, with the given number of active borrows.
+    Shared(u32),
+    /// The VMA is read-only, forever.
+    Sealed,
+}
+
+/// An invalid VMA state transition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaStateError {
+    /// The VMA is currently borrowed by the kernel.
+    Borrowed,
+    /// The VMA is sealed and can not be written.
+    Sealed,
+    /// The pages of the VMA could not be re-mapped.
+    Mapping,
+}
+
+impl Vma {
+    /// Returns the current ownership state of the VMA.
+    pub fn state(&self) -> VmaState {
+        *self.state.lock()
+    }
+
+    /// Seals the VMA, its content can not be modified afterward. Sealing is idempotent.
+    ///
+    /// The pages of the area are re-mapped read-only, so that the owner of the VMA traps if it
+    /// tries to write to it. Static areas are not re-mapped, as they can only be written through
+    /// the kernel.
+    pub fn seal(&self) -> Result<(), VmaStateError> {
+        let mut state = self.state.lock();
+        match *state {
+            VmaState::Sealed => Ok(()),
+            VmaState::Shared(_) => Err(VmaStateError::Borrowed),
+            VmaState::Exclusive => {
+                if self.vma_allocator.is_some() {
+                    let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+                    self.update_flags(flags)
+                        .map_err(|_| VmaStateError::Mapping)?;
+                }
+                *state = VmaState::Sealed;
+                Ok(())
+            }
+        }
+    }
+
+    /// Borrows the VMA for reading, writes through the kernel are rejected until the returned
+    /// borrow is dropped.
+    ///
+    /// NOTE: the owner of a VMA which is not sealed can still mutate it concurrently.
+    pub fn borrow_shared(&self) -> SharedVma<'_> {
+        let mut state = self.state.lock();
+        *state = match *state {
+            VmaState::Exclusive => VmaState::Shared(1),
+            VmaState::Shared(n) => VmaState::Shared(n + 1),
+            VmaState::Sealed => VmaState::Sealed,
+        };
+        SharedVma { vma: self }
+    }
+
+    /// Calls `f` with a mutable view of the area, if the VMA is neither borrowed nor sealed.
+    ///
+    /// The state of the VMA can not change while `f` executes.
+    pub fn with_exclusive<F, T>(&self, f: F) -> Result<T, VmaStateError>
+    where
+        F: FnOnce(&mut [u8]) -> T,
+    {
+        let state = self.state.lock();
+        match *state {
+            VmaState::Exclusive => {
+                // SAFETY: the VMA is not borrowed by the kernel, and the state lock prevents
+                // other kernel accesses until we return.
+                Ok(f(unsafe { self.unsafe_as_bytes_mut() }))
+            }
+            VmaState::Shared(_) => Err(VmaStateError::Borrowed),
+            VmaState::Sealed => Err(VmaStateError::Sealed),
+        }
+    }
+}
+
+/// A shared borrow of a VMA, released on drop.
+pub struct SharedVma<'vma> {
+    vma: &'vma Vma,
+}
+
+impl<'vma> Deref for SharedVma<'vma> {
+    type Target = Vma;
+
+    fn deref(&self) -> &Self::Target {
+        self.vma
+    }
+}
+
+impl<'vma> Drop for SharedVma<'vma> {
+    fn drop(&mut self) {
+        let mut state = self.vma.state.lock();
+        *state = match *state {
+            VmaState::Shared(1) => VmaState::Exclusive,
+            VmaState::Shared(n) => VmaState::Shared(n - 1),
+            state => state,
+        };
+    }
+}
+
+impl Drop for Vma {
+    fn drop(&mut self) {
+        // Static areas are not mapped by the allocator
+        let vma_allocator = match &self.vma_allocator {
+            Some(vma_allocator) => vma_allocator,
+            None => return,
+        };
+        let start = VirtAddr::from_ptr(self.ptr.as_ptr());
+        let mut inner = vma_allocator.lock();
+        let inner = inner.deref_mut();
+        let end = start + self.nb_pages * PAGE_SIZE;
+        release_pages(
+            &mut inner.mapper,
+            &mut inner.frame_allocator,
+            inner.zero_frame,
+            start,
+            end,
+        );
+    }
 }
 
 impl MemoryArea for Vma {
@@ -330,6 +708,52 @@ impl MemoryArea for Vma {
     }
 }
 
+// ————————————————————————————————— Blobs —————————————————————————————————— //
+
+/// An immutable blob of bytes.
+///
+/// Contrary to VMAs, the content of a blob never changes. It can therefore be read (e.g. to compile
+/// a module) without copying it first.
+pub struct Blob {
+    bytes: BlobBytes,
+}
+
+enum BlobBytes {
+    /// Bytes embedded in the kernel image.
+    Static(&'static [u8]),
+    /// Bytes owned by the blob.
+    Owned(Box<[u8]>),
+}
+
+impl Blob {
+    /// Creates a blob from bytes living for the whole lifetime of the kernel, without copying them.
+    pub const fn from_static(bytes: &'static [u8]) -> Self {
+        Self {
+            bytes: BlobBytes::Static(bytes),
+        }
+    }
+
+    /// Creates a blob from a copy of the given bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            bytes: BlobBytes::Owned(Box::from(bytes)),
+        }
+    }
+
+    /// Returns a view of the blob.
+    pub fn as_bytes(&self) -> &[u8] {
+        match &self.bytes {
+            BlobBytes::Static(bytes) => bytes,
+            BlobBytes::Owned(bytes) => bytes,
+        }
+    }
+
+    /// Returns the size of the blob.
+    pub fn size(&self) -> usize {
+        self.as_bytes().len()
+    }
+}
+
 // ————————————————————— Virtual Memory Area Allocator —————————————————————— //
 
 /// The Virtual Memory Area Allocator, responsible for allocating and managing virtual memory
@@ -353,6 +777,24 @@ struct LockedVmaAllocator {
     mapper: OffsetPageTable<'static>,
     memory_map: VirtualMemoryMap,
     frame_allocator: BootInfoFrameAllocator,
+    /// The frame backing the pages of zero-on-demand areas, allocated on first use.
+    zero_frame: Option<PhysFrame>,
+}
+
+impl LockedVmaAllocator {
+    /// Returns the zero frame, allocating it if needed.
+    ///
+    /// The zero frame is only ever mapped copy-on-write, and is never released.
+    fn zero_frame(&mut self) -> Result<PhysFrame, ()> {
+        if let Some(frame) = self.zero_frame {
+            return Ok(frame);
+        }
+        let frame = self.frame_allocator.allocate_frame().ok_or(())?;
+        let addr = self.mapper.phys_offset() + frame.start_address().as_u64();
+        unsafe { core::ptr::write_bytes(addr.as_mut_ptr::<u8>(), 0, PAGE_SIZE) };
+        self.zero_frame = Some(frame);
+        Ok(frame)
+    }
 }
 
 impl VmaAllocator {
@@ -365,32 +807,71 @@ impl VmaAllocator {
             mapper,
             memory_map,
             frame_allocator,
+            zero_frame: None,
         }));
         Self(inner)
     }
 
+    /// Returns the number of frames which can still be allocated, including the emergency pool but
+    /// not the frames reserved for zero-on-demand pages.
+    pub fn free_frames(&self) -> usize {
+        self.lock().frame_allocator.nb_free_frames()
+    }
+
+    /// Returns the current memory pressure.
+    pub fn pressure(&self) -> MemoryPressure {
+        MemoryPressure::from_free_frames(self.free_frames())
+    }
+
     /// Allocates a new virtual memory area with the given capacity.
-    // TODO: Free allocated pages on failure.
     pub fn with_capacity(&self, capacity: usize) -> Result<Vma, ()> {
+        self.with_aligned_capacity(capacity, PAGE_SIZE)
+    }
+
+    /// Allocates a new virtual memory area with the given capacity, starting at a multiple of
+    /// `align`.
+    pub fn with_aligned_capacity(&self, capacity: usize, align: usize) -> Result<Vma, ()> {
+        let virt_addr = self.reserve(capacity, align)?;
+        self.with_capacity_at(capacity, virt_addr, PageSize::Small)
+    }
+
+    /// Reserves a range of the virtual address space, without mapping it.
+    ///
+    /// Areas can then be allocated within that range using `with_capacity_at`.
+    pub fn reserve(&self, size: usize, align: usize) -> Result<VirtAddr, ()> {
+        let align = align.max(PAGE_SIZE);
+        self.lock().memory_map.reserve_aligned_area(size, align)
+    }
+
+    /// Allocates a new virtual memory area with the given capacity at `virt_addr`, which must be
+    /// page aligned and within a range returned by `reserve` that is not yet mapped.
+    ///
+    /// With `PageSize::Huge`, the area benefits from 2 MiB pages only if `virt_addr` is aligned
+    /// accordingly (see `PageSize::alignment`).
+    ///
+    /// Areas are never backed by the emergency pool, the allocation fails if the area does not fit
+    /// within the other free frames. On failure, the frames allocated so far are released.
+    pub fn with_capacity_at(
+        &self,
+        capacity: usize,
+        virt_addr: VirtAddr,
+        page_size: PageSize,
+    ) -> Result<Vma, ()> {
         let nb_pages = Vma::bytes_to_pages(capacity);
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         let mut inner = self.0.lock();
         let inner = inner.deref_mut();
         let mapper = &mut inner.mapper;
         let frame_allocator = &mut inner.frame_allocator;
-        let mut virt_addr = inner.memory_map.reserve_area(capacity)?;
-        let ptr = NonNull::new(virt_addr.as_mut_ptr()).unwrap();
+        let ptr = NonNull::new(virt_addr.as_mut_ptr()).ok_or(())?;
+        if frame_allocator.nb_free_frames() < nb_pages + EMERGENCY_FRAMES {
+            return Err(());
+        }
 
-        for _ in 0..nb_pages {
-            unsafe {
-                let frame = frame_allocator.allocate_frame().ok_or(())?;
-                let page = Page::containing_address(virt_addr);
-                mapper
-                    .map_to(page, frame, flags, frame_allocator)
-                    .map_err(|_| ())?
-                    .flush();
-                virt_addr += PAGE_SIZE;
-            }
+        let end = virt_addr + nb_pages * PAGE_SIZE;
+        if map_pages(mapper, frame_allocator, virt_addr, end, page_size).is_err() {
+            // The range was not mapped before, only the new pages are released
+            release_pages(mapper, frame_allocator, inner.zero_frame, virt_addr, end);
+            return Err(());
         }
 
         Ok(Vma {
@@ -399,9 +880,436 @@ impl VmaAllocator {
             size: capacity,
             kind: VmaKind::Static, // TODO: We don't support resizing for now.
             vma_allocator: Some(self.clone()),
+            state: Mutex::new(VmaState::Exclusive),
+            watched: AtomicBool::new(false),
+            marker: PhantomData,
+        })
+    }
+
+    /// Allocates a new virtual memory area whose content is zeroed, with the same requirements as
+    /// `with_capacity_at`.
+    ///
+    /// With `PageSize::Small` the area is zeroed on demand: all its pages map a shared zero frame
+    /// copy-on-write, and get their own frame on the first write (see `resolve_copy_on_write`).
+    /// A frame is reserved for each page, so that writes to the area never run out of memory.
+    /// Sharing the zero frame would split huge pages on the first write, with `PageSize::Huge`
+    /// the frames are therefore allocated and zeroed upfront.
+    pub fn with_zeroed_capacity_at(
+        &self,
+        capacity: usize,
+        virt_addr: VirtAddr,
+        page_size: PageSize,
+    ) -> Result<Vma, ()> {
+        if page_size == PageSize::Huge {
+            let mut vma = self.with_capacity_at(capacity, virt_addr, page_size)?;
+            vma.zeroed();
+            return Ok(vma);
+        }
+
+        let nb_pages = Vma::bytes_to_pages(capacity);
+        let mut inner = self.0.lock();
+        let inner = inner.deref_mut();
+        let ptr = NonNull::new(virt_addr.as_mut_ptr()).ok_or(())?;
+        // The frames are allocated on write, but are reserved upfront
+        if inner.frame_allocator.nb_free_frames() < nb_pages + EMERGENCY_FRAMES {
+            return Err(());
+        }
+        let zero_frame = inner.zero_frame()?;
+        let mapper = &mut inner.mapper;
+        let frame_allocator = &mut inner.frame_allocator;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE | COPY_ON_WRITE;
+        let end = virt_addr + nb_pages * PAGE_SIZE;
+        let mut page_addr = virt_addr;
+        while page_addr < end {
+            let page = Page::<Size4KiB>::containing_address(page_addr);
+            match unsafe { mapper.map_to(page, zero_frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    // Releasing the pages mapped so far also gives back their reserved frames
+                    release_pages(mapper, frame_allocator, Some(zero_frame), virt_addr, end);
+                    return Err(());
+                }
+            }
+            // Enough frames are free for the whole area, checked above
+            frame_allocator.reserved += 1;
+            page_addr += PAGE_SIZE;
+        }
+
+        Ok(Vma {
+            ptr,
+            nb_pages,
+            size: capacity,
+            kind: VmaKind::Static,
+            vma_allocator: Some(self.clone()),
+            state: Mutex::new(VmaState::Exclusive),
+            watched: AtomicBool::new(false),
             marker: PhantomData,
         })
     }
+
+    /// Creates a copy-on-write copy of a VMA at `virt_addr`, which must be page aligned and within
+    /// a range returned by `reserve` that is not yet mapped.
+    ///
+    /// Both VMAs share the frames of `vma` until they are written to. The writable pages of `vma`
+    /// are re-mapped copy-on-write, while its read-only pages (e.g. if it is sealed) stay
+    /// read-only. The copy is always copy-on-write, and starts in the exclusive state.
+    ///
+    /// The copy is mapped with 4 KiB pages only, huge pages of `vma` are shared one 4 KiB page at
+    /// a time.
+    pub fn fork_at(&self, vma: &Vma, virt_addr: VirtAddr) -> Result<Vma, ()> {
+        // Static areas are not mapped by the allocator
+        if vma.vma_allocator.is_none() {
+            return Err(());
+        }
+
+        let mut inner = self.0.lock();
+        let inner = inner.deref_mut();
+        let mapper = &mut inner.mapper;
+        let frame_allocator = &mut inner.frame_allocator;
+        let ptr = NonNull::new(virt_addr.as_mut_ptr()).ok_or(())?;
+        let source_addr = VirtAddr::from_ptr(vma.ptr.as_ptr());
+        let size = vma.nb_pages * PAGE_SIZE;
+        let mut offset = 0;
+        let shared = share_pages(
+            mapper,
+            frame_allocator,
+            inner.zero_frame,
+            source_addr,
+            virt_addr,
+            size,
+            &mut offset,
+        );
+        if shared.is_err() {
+            // The pages of `vma` stay copy-on-write, they are made writable again on write
+            release_pages(
+                mapper,
+                frame_allocator,
+                inner.zero_frame,
+                virt_addr,
+                virt_addr + offset,
+            );
+            return Err(());
+        }
+
+        Ok(Vma {
+            ptr,
+            nb_pages: vma.nb_pages,
+            size: vma.size,
+            kind: VmaKind::Static,
+            vma_allocator: Some(self.clone()),
+            state: Mutex::new(VmaState::Exclusive),
+            watched: AtomicBool::new(false),
+            marker: PhantomData,
+        })
+    }
+
+    /// Resolves a write to a copy-on-write page by mapping a copy of the page in place, returns
+    /// false if the address is not within a copy-on-write page. Pages mapping the zero frame get
+    /// a freshly zeroed frame.
+    ///
+    /// The last mapping of a shared frame is made writable in place rather than copied, other
+    /// mappings drop their share of the frame. Copy-on-write huge pages are first split into 4 KiB
+    /// pages.
+    ///
+    /// This is called from the page fault handler, and therefore gives up if the allocator is
+    /// already locked rather than waiting for it.
+    pub fn resolve_copy_on_write(&self, addr: VirtAddr) -> bool {
+        let mut inner = match self.0.try_lock() {
+            Some(inner) => inner,
+            None => return false,
+        };
+        let inner = inner.deref_mut();
+        let zero_frame = inner.zero_frame;
+        let mapper = &mut inner.mapper;
+        let frame_allocator = &mut inner.frame_allocator;
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let mut translation = mapper.translate(page.start_address());
+        if let TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(frame),
+            flags,
+            ..
+        } = translation
+        {
+            // Huge pages are split, so that only the written 4 KiB page gets copied
+            if !flags.contains(COPY_ON_WRITE)
+                || split_huge_page(mapper, frame_allocator, addr, frame, flags).is_err()
+            {
+                return false;
+            }
+            translation = mapper.translate(page.start_address());
+        }
+        let (frame, flags) = match translation {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => (frame, flags),
+            _ => return false,
+        };
+        if !flags.contains(COPY_ON_WRITE) {
+            return false;
+        }
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if Some(frame) != zero_frame && !frame_allocator.is_shared(frame) {
+            return match unsafe { mapper.update_flags(page, flags) } {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => false,
+            };
+        }
+        // Pages mapping the zero frame had a frame reserved when they were mapped
+        let copy = if Some(frame) == zero_frame {
+            frame_allocator.allocate_reserved_frame()
+        } else {
+            frame_allocator.allocate_frame()
+        };
+        let copy = match copy {
+            Some(copy) => copy,
+            None => return false,
+        };
+
+        // Frames are accessed through the mapping of the physical memory
+        let phys_offset = mapper.phys_offset();
+        let source = phys_offset + frame.start_address().as_u64();
+        let target = phys_offset + copy.start_address().as_u64();
+        unsafe {
+            // Pages of zero-on-demand areas don't need to read the zero frame
+            if Some(frame) == zero_frame {
+                core::ptr::write_bytes(target.as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+            } else {
+                core::ptr::copy_nonoverlapping(
+                    source.as_ptr::<u8>(),
+                    target.as_mut_ptr::<u8>(),
+                    PAGE_SIZE,
+                );
+            }
+            match mapper.unmap(page) {
+                Ok((_, flush)) => flush.ignore(),
+                Err(_) => return false,
+            }
+            match mapper.map_to(page, copy, flags, frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(_) => return false,
+            }
+            if Some(frame) != zero_frame {
+                frame_allocator.unmap_frame(frame);
+            }
+        }
+        true
+    }
+}
+
+/// Maps the pages from `virt_addr` to `end` to newly allocated frames, with 2 MiB pages where
+/// possible if `page_size` is `PageSize::Huge`.
+fn map_pages(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    mut virt_addr: VirtAddr,
+    end: VirtAddr,
+    page_size: PageSize,
+) -> Result<(), ()> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut use_huge_pages = page_size == PageSize::Huge;
+    while virt_addr < end {
+        let fits_huge_page =
+            virt_addr.is_aligned(HUGE_PAGE_SIZE as u64) && end - virt_addr >= HUGE_PAGE_SIZE as u64;
+        if use_huge_pages && fits_huge_page {
+            // Fall back to small pages for the rest of the area once memory is too fragmented
+            match frame_allocator.allocate_huge_frame() {
+                Some(frame) => unsafe {
+                    let page = Page::<Size2MiB>::containing_address(virt_addr);
+                    match mapper.map_to(page, frame, flags, frame_allocator) {
+                        Ok(flush) => flush.flush(),
+                        Err(_) => {
+                            release_huge_frame(frame_allocator, frame);
+                            return Err(());
+                        }
+                    }
+                    virt_addr += HUGE_PAGE_SIZE;
+                    continue;
+                },
+                None => use_huge_pages = false,
+            }
+        }
+
+        unsafe {
+            let frame = frame_allocator.allocate_frame().ok_or(())?;
+            let page = Page::<Size4KiB>::containing_address(virt_addr);
+            match mapper.map_to(page, frame, flags, frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    frame_allocator.release_frame(frame);
+                    return Err(());
+                }
+            }
+            virt_addr += PAGE_SIZE;
+        }
+    }
+    Ok(())
+}
+
+/// Maps the frames of the `size` bytes starting at `source` a second time starting at `target`,
+/// copy-on-write in both mappings. Read-only pages of the source stay read-only.
+///
+/// The target is mapped with 4 KiB pages only, and `offset` is the size of the target mapped so
+/// far, including if an error is returned.
+fn share_pages(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    zero_frame: Option<PhysFrame>,
+    source: VirtAddr,
+    target: VirtAddr,
+    size: usize,
+    offset: &mut usize,
+) -> Result<(), ()> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE | COPY_ON_WRITE;
+    while *offset < size {
+        let source = source + *offset;
+        let frames = match mapper.translate(source) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => {
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    let source = Page::<Size4KiB>::containing_address(source);
+                    let flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    unsafe { mapper.update_flags(source, flags).map_err(|_| ())?.flush() };
+                }
+                PhysFrame::range(frame, frame + 1)
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(frame),
+                flags,
+                ..
+            } => {
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    let source = Page::<Size2MiB>::containing_address(source);
+                    let flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    unsafe { mapper.update_flags(source, flags).map_err(|_| ())?.flush() };
+                }
+                let first = PhysFrame::containing_address(frame.start_address());
+                let nb_frames = (HUGE_PAGE_SIZE / PAGE_SIZE) as u64;
+                PhysFrame::range(first, first + nb_frames)
+            }
+            _ => return Err(()),
+        };
+        for frame in frames {
+            let page = Page::<Size4KiB>::containing_address(target + *offset);
+            // Mappings of the zero frame get a frame reserved, as in zero-on-demand areas
+            let is_zero_frame = Some(frame) == zero_frame;
+            if is_zero_frame {
+                frame_allocator.reserve_frame()?;
+            }
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    if is_zero_frame {
+                        frame_allocator.unreserve_frame();
+                    }
+                    return Err(());
+                }
+            }
+            // The zero frame is never released, its mappings are not counted
+            if !is_zero_frame {
+                frame_allocator.share_frame(frame);
+            }
+            *offset += PAGE_SIZE;
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps the pages from `virt_addr` to `end` and releases the frames which are not mapped anymore,
+/// pages which are not mapped are skipped.
+///
+/// The zero frame is never released, it is still mapped by sealed zero-on-demand areas once their
+/// pages lost the copy-on-write marker. Each of its mappings gives back its reserved frame instead.
+fn release_pages(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    zero_frame: Option<PhysFrame>,
+    mut virt_addr: VirtAddr,
+    end: VirtAddr,
+) {
+    while virt_addr < end {
+        match mapper.translate(virt_addr) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                let page = Page::<Size2MiB>::containing_address(virt_addr);
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    // Copies share the frames of huge pages one 4 KiB frame at a time
+                    let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+                    let nb_frames = (HUGE_PAGE_SIZE / PAGE_SIZE) as u64;
+                    for frame in PhysFrame::range(first, first + nb_frames) {
+                        unsafe { frame_allocator.unmap_frame(frame) };
+                    }
+                }
+                virt_addr += HUGE_PAGE_SIZE;
+            }
+            TranslateResult::Mapped { .. } => {
+                let page = Page::<Size4KiB>::containing_address(virt_addr);
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    if Some(frame) == zero_frame {
+                        frame_allocator.unreserve_frame();
+                    } else {
+                        unsafe { frame_allocator.unmap_frame(frame) };
+                    }
+                }
+                virt_addr += PAGE_SIZE;
+            }
+            _ => virt_addr += PAGE_SIZE,
+        }
+    }
+}
+
+/// Releases the 4 KiB frames making up a 2 MiB frame.
+///
+/// SAFETY: the frame must not be used anymore, in particular it must no longer be mapped.
+unsafe fn release_huge_frame(
+    frame_allocator: &mut BootInfoFrameAllocator,
+    frame: PhysFrame<Size2MiB>,
+) {
+    let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+    let nb_frames = (HUGE_PAGE_SIZE / PAGE_SIZE) as u64;
+    for frame in PhysFrame::range(first, first + nb_frames) {
+        frame_allocator.release_frame(frame);
+    }
+}
+
+/// Replaces the huge page containing `addr` by 4 KiB pages mapping the same frames, with the same
+/// flags.
+fn split_huge_page(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    addr: VirtAddr,
+    frame: PhysFrame<Size2MiB>,
+    flags: PageTableFlags,
+) -> Result<(), ()> {
+    let huge_page = Page::<Size2MiB>::containing_address(addr);
+    let flags = flags - PageTableFlags::HUGE_PAGE;
+    let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+    let first_page = Page::<Size4KiB>::containing_address(huge_page.start_address());
+    let nb_frames = (HUGE_PAGE_SIZE / PAGE_SIZE) as u64;
+    unsafe {
+        let (_, flush) = mapper.unmap(huge_page).map_err(|_| ())?;
+        flush.flush();
+        for idx in 0..nb_frames {
+            mapper
+                .map_to(first_page + idx, first + idx, flags, frame_allocator)
+                .map_err(|_| ())?
+                .flush();
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -418,4 +1326,53 @@ mod tests {
         assert_eq!(VMA::bytes_to_pages(PAGE_SIZE), 1);
         assert_eq!(VMA::bytes_to_pages(PAGE_SIZE + 1), 2);
     }
+
+    #[test_case]
+    fn page_size_alignment() {
+        assert_eq!(PageSize::Small.alignment(4 * HUGE_PAGE_SIZE), PAGE_SIZE);
+        assert_eq!(PageSize::Huge.alignment(HUGE_PAGE_SIZE - 1), PAGE_SIZE);
+        assert_eq!(PageSize::Huge.alignment(HUGE_PAGE_SIZE), HUGE_PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn memory_pressure() {
+        let pressure = MemoryPressure::from_free_frames;
+        assert_eq!(pressure(0), MemoryPressure::Critical);
+        assert_eq!(pressure(EMERGENCY_FRAMES - 1), MemoryPressure::Critical);
+        assert_eq!(pressure(EMERGENCY_FRAMES), MemoryPressure::Low);
+        assert_eq!(
+            pressure(EMERGENCY_FRAMES + LOW_MEMORY_FRAMES - 1),
+            MemoryPressure::Low
+        );
+        assert_eq!(
+            pressure(EMERGENCY_FRAMES + LOW_MEMORY_FRAMES),
+            MemoryPressure::Normal
+        );
+    }
+
+    #[test_case]
+    fn vma_ownership() {
+        let area = Box::leak(Box::new([0u8; 16]));
+        let vma = unsafe { VMA::from_raw(NonNull::new(area.as_mut_ptr()).unwrap(), area.len()) };
+        assert_eq!(vma.state(), VmaState::Exclusive);
+        assert_eq!(vma.with_exclusive(|bytes| bytes[0] = 42), Ok(()));
+
+        // Writes are rejected while the VMA is borrowed
+        let first = vma.borrow_shared();
+        let second = vma.borrow_shared();
+        assert_eq!(vma.state(), VmaState::Shared(2));
+        assert_eq!(vma.with_exclusive(|_| ()), Err(VmaStateError::Borrowed));
+        assert_eq!(vma.seal(), Err(VmaStateError::Borrowed));
+        drop(first);
+        drop(second);
+        assert_eq!(vma.state(), VmaState::Exclusive);
+
+        // Sealing is final
+        assert_eq!(vma.seal(), Ok(()));
+        assert_eq!(vma.seal(), Ok(()));
+        drop(vma.borrow_shared());
+        assert_eq!(vma.state(), VmaState::Sealed);
+        assert_eq!(vma.with_exclusive(|_| ()), Err(VmaStateError::Sealed));
+        assert_eq!(vma.as_bytes()[0], 42);
+    }
 }
@@ -0,0 +1,252 @@
+//! PS/2 Mouse Driver
+//!
+//! The mouse is connected to the auxiliary port of the PS/2 controller, and raises IRQ 12 for
+//! each byte it sends. Bytes are grouped into 3-bytes packets, which are decoded into pointer
+//! events.
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::events::push_pointer_event;
+use crate::interrupts::PICS;
+
+/// Data port of the PS/2 controller, shared between the keyboard and the mouse.
+const PORT_DATA: u16 = 0x60;
+/// Status (read) and command (write) port of the PS/2 controller.
+const PORT_COMMAND: u16 = 0x64;
+
+// Controller commands
+const CMD_ENABLE_AUX: u8 = 0xA8;
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_WRITE_AUX: u8 = 0xD4;
+
+// Mouse commands
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+
+// Status bits
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// Configuration bits
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+/// Number of polling iterations before giving up on the controller.
+const TIMEOUT: usize = 100_000;
+
+/// The IRQ line of the mouse, on the secondary PIC.
+const MOUSE_IRQ: u8 = 12;
+/// The IRQ line through which the secondary PIC is cascaded to the primary one.
+const CASCADE_IRQ: u8 = 2;
+
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new());
+
+/// Enables the mouse and its interrupt.
+///
+/// Must be called after the PICs are initialized, and before interrupts are enabled.
+pub fn init() {
+    let mut controller = Controller::new();
+    let result = controller
+        .command(CMD_ENABLE_AUX)
+        .and_then(|_| controller.command(CMD_READ_CONFIG))
+        .and_then(|_| controller.read())
+        .and_then(|config| {
+            let config = (config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED;
+            controller.command(CMD_WRITE_CONFIG)?;
+            controller.write(config)
+        })
+        .and_then(|_| controller.write_mouse(MOUSE_SET_DEFAULTS))
+        .and_then(|_| controller.write_mouse(MOUSE_ENABLE_REPORTING));
+    if result.is_err() {
+        crate::kprintln!("WARNING: PS/2 mouse not responding, pointer events are disabled");
+        return;
+    }
+
+    // Unmask the mouse interrupt, which goes through the cascade line of the primary PIC
+    unsafe {
+        let mut pics = PICS.lock();
+        let [primary, secondary] = pics.read_masks();
+        pics.write_masks(
+            primary & !(1 << CASCADE_IRQ),
+            secondary & !(1 << (MOUSE_IRQ - 8)),
+        );
+    }
+}
+
+/// Handles a mouse interrupt, the controller holds one byte of a packet.
+pub(crate) fn handle_interrupt() {
+    let mut port = Port::new(PORT_DATA);
+    let byte: u8 = unsafe { port.read() };
+    if let Some(packet) = DECODER.lock().push(byte) {
+        push_pointer_event(packet.dx, packet.dy, packet.buttons);
+    }
+}
+
+// ——————————————————————————————— Controller ——————————————————————————————— //
+
+/// The PS/2 controller, driven through polling.
+struct Controller {
+    data: Port<u8>,
+    command: Port<u8>,
+}
+
+impl Controller {
+    fn new() -> Self {
+        Self {
+            data: Port::new(PORT_DATA),
+            command: Port::new(PORT_COMMAND),
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.command.read() }
+    }
+
+    /// Waits until the controller is ready to receive a byte.
+    fn wait_input(&mut self) -> Result<(), ()> {
+        for _ in 0..TIMEOUT {
+            if self.status() & STATUS_INPUT_FULL == 0 {
+                return Ok(());
+            }
+        }
+        Err(())
+    }
+
+    /// Waits until the controller has a byte for us.
+    fn wait_output(&mut self) -> Result<(), ()> {
+        for _ in 0..TIMEOUT {
+            if self.status() & STATUS_OUTPUT_FULL != 0 {
+                return Ok(());
+            }
+        }
+        Err(())
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), ()> {
+        self.wait_input()?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), ()> {
+        self.wait_input()?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<u8, ()> {
+        self.wait_output()?;
+        Ok(unsafe { self.data.read() })
+    }
+
+    /// Sends a command to the mouse and consumes its acknowledgment.
+    fn write_mouse(&mut self, command: u8) -> Result<(), ()> {
+        self.command(CMD_WRITE_AUX)?;
+        self.write(command)?;
+        self.read().map(|_ack| ())
+    }
+}
+
+// ————————————————————————————————— Packets ———————————————————————————————— //
+
+/// A decoded mouse packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    /// Horizontal motion, positive to the right.
+    pub dx: i16,
+    /// Vertical motion, positive upward.
+    pub dy: i16,
+    /// Button state: bit 0 is left, bit 1 is right and bit 2 is middle.
+    pub buttons: u8,
+}
+
+/// Reassembles and decodes 3-bytes packets.
+pub struct PacketDecoder {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl PacketDecoder {
+    // Flags of the first byte of a packet
+    const BUTTONS: u8 = 0b111;
+    const ALWAYS_ONE: u8 = 1 << 3;
+    const X_SIGN: u8 = 1 << 4;
+    const Y_SIGN: u8 = 1 << 5;
+    const X_OVERFLOW: u8 = 1 << 6;
+    const Y_OVERFLOW: u8 = 1 << 7;
+
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; 3],
+            len: 0,
+        }
+    }
+
+    /// Pushes a byte, returns a packet once complete.
+    ///
+    /// Packets with an overflow are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<Packet> {
+        // Re-synchronize if we lost track of packet boundaries
+        if self.len == 0 && byte & Self::ALWAYS_ONE == 0 {
+            return None;
+        }
+
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.bytes.len() {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y] = self.bytes;
+        if flags & (Self::X_OVERFLOW | Self::Y_OVERFLOW) != 0 {
+            return None;
+        }
+        Some(Packet {
+            dx: Self::motion(x, flags & Self::X_SIGN != 0),
+            dy: Self::motion(y, flags & Self::Y_SIGN != 0),
+            buttons: flags & Self::BUTTONS,
+        })
+    }
+
+    /// Motions are 9 bits two's complement values, with the sign bit in the first byte.
+    fn motion(value: u8, negative: bool) -> i16 {
+        if negative {
+            value as i16 - 0x100
+        } else {
+            value as i16
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn decode_packets() {
+        let mut decoder = PacketDecoder::new();
+
+        // A stray byte, not the start of a packet
+        assert_eq!(decoder.push(0x00), None);
+
+        // Left button, moving right and down
+        assert_eq!(decoder.push(0b0010_1001), None);
+        assert_eq!(decoder.push(5), None);
+        assert_eq!(
+            decoder.push(0xfe),
+            Some(Packet {
+                dx: 5,
+                dy: -2,
+                buttons: 1
+            })
+        );
+
+        // Overflowing packets are dropped
+        decoder.push(0b0100_1000);
+        decoder.push(0xff);
+        assert_eq!(decoder.push(0xff), None);
+    }
+}
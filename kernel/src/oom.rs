@@ -0,0 +1,45 @@
+//! Out of Memory Policy
+//!
+//! The VMA allocator keeps an emergency pool of frames for the kernel, so that page tables and
+//! copy-on-write faults can still be served once areas can no longer be allocated (see
+//! `memory::MemoryPressure`). The runtime applies the following policy before allocating areas:
+//!
+//! - While memory is low, new areas are rejected: instantiations and forks fail with
+//!   `ModuleError::OutOfMemory`, while running instances are not affected.
+//! - Once the emergency pool is in use, the largest non-privileged component is killed to reclaim
+//!   its memory. Components created at boot are privileged.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::kprintln;
+use crate::runtime::ACTIVE_COMPONENTS;
+use crate::wasm::Component;
+
+/// Kills the non-privileged component owning the most memory, returns false if no component could
+/// be killed.
+///
+/// Only the components reachable through a handle are considered, and components whose instances
+/// are locked are skipped. The memory of a killed component is released once its executions in
+/// progress, if any, complete.
+pub fn kill_largest_component() -> bool {
+    let mut candidates: Vec<(usize, Arc<Component>)> = ACTIVE_COMPONENTS
+        .objects()
+        .into_iter()
+        .filter(|component| !component.is_privileged() && !component.is_killed())
+        .filter_map(|component| Some((component.try_memory_footprint()?, component)))
+        .collect();
+    candidates.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    for (footprint, component) in candidates {
+        if component.try_kill() {
+            kprintln!(
+                "WARNING: out of memory, killed a component owning {} KiB",
+                footprint / 1024
+            );
+            return true;
+        }
+    }
+    kprintln!("WARNING: out of memory, no component can be killed");
+    false
+}
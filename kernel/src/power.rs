@@ -0,0 +1,74 @@
+//! Power Management
+//!
+//! Orderly shutdown and reboot of the machine. Before powering off or resetting, the kernel stops
+//! scheduling new work and flushes its logs.
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::{hlt_loop, kprintln, scheduler, serial};
+
+/// ACPI PM1a control ports and sleep values used to enter the S5 (soft off) state, for the
+/// virtual machines we support.
+///
+/// NOTE: Properly entering S5 requires parsing the ACPI tables (the FADT for the PM1a control block
+/// and the DSDT for the `\_S5` sleep type), which is not supported yet. Instead we rely on the
+/// fixed values used by the common emulators.
+const ACPI_POWEROFF: [(u16, u16); 3] = [
+    (0x604, 0x2000),  // QEMU
+    (0xb004, 0x2000), // Bochs and older versions of QEMU
+    (0x4004, 0x3400), // VirtualBox
+];
+
+/// Keyboard controller status and command port.
+const PORT_KBD_CONTROLLER: u16 = 0x64;
+/// Set in the status register while the controller input buffer is full.
+const KBD_INPUT_FULL: u8 = 0b10;
+/// Pulse the CPU reset line.
+const KBD_CMD_RESET: u8 = 0xfe;
+
+/// Powers off the machine.
+pub fn shutdown() -> ! {
+    teardown();
+    kprintln!("Shutting down");
+    serial::flush();
+
+    for (port, value) in ACPI_POWEROFF {
+        unsafe { Port::new(port).write(value) };
+    }
+
+    // We are still running, ACPI poweroff is not supported
+    kprintln!("Shutdown failed, halting");
+    serial::flush();
+    hlt_loop();
+}
+
+/// Reboots the machine.
+pub fn reboot() -> ! {
+    teardown();
+    kprintln!("Rebooting");
+    serial::flush();
+
+    // Ask the keyboard controller to reset the CPU
+    unsafe {
+        let mut controller: Port<u8> = Port::new(PORT_KBD_CONTROLLER);
+        while controller.read() & KBD_INPUT_FULL != 0 {
+            core::hint::spin_loop();
+        }
+        controller.write(KBD_CMD_RESET);
+    }
+
+    // We are still running, the reset failed
+    kprintln!("Reboot failed, halting");
+    serial::flush();
+    hlt_loop();
+}
+
+/// Stops the scheduler.
+///
+/// No task is queued or polled anymore, and with interrupts disabled no more events are produced.
+/// The calling task is the last one to run: both `shutdown` and `reboot` diverge.
+fn teardown() {
+    scheduler::stop();
+    interrupts::disable();
+}
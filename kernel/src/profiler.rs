@@ -0,0 +1,290 @@
+//! Sampling Profiler
+//!
+//! When profiling is enabled, the timer interrupt samples the interrupted instruction. Samples
+//! within guest code are attributed to the (component, instance, function) they belong to, and
+//! aggregated into a fixed-size kernel buffer, so that sampling never allocates.
+//!
+//! Components are identified by their order of first appearance within the profile, so that the
+//! profile never leaks kernel addresses.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::wasm::{with_current_component, InstanceIndex};
+use wasm::FuncIndex;
+
+/// Number of distinct functions tracked by the profile, samples of other functions are dropped.
+const PROFILE_CAPACITY: usize = 128;
+/// Number of distinct components tracked by the profile.
+const MAX_PROFILED_COMPONENTS: usize = 16;
+
+/// The global profile.
+static PROFILE: Mutex<Profile> = Mutex::new(Profile::new());
+
+/// Whether the timer interrupt records samples.
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Number of samples dropped because the profile was locked (e.g. while being read).
+static LOCKED_SAMPLES: AtomicU64 = AtomicU64::new(0);
+
+// ———————————————————————————————— Sampling ———————————————————————————————— //
+
+/// Enables or disables profiling, the profile is reset when profiling gets enabled.
+pub fn set_enabled(enabled: bool) {
+    if enabled && !IS_ENABLED.load(Ordering::SeqCst) {
+        *PROFILE.lock() = Profile::new();
+        LOCKED_SAMPLES.store(0, Ordering::SeqCst);
+    }
+    IS_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Records a sample of the instruction at `ip`, if profiling is enabled.
+///
+/// This is called from the timer interrupt handler with the interrupted instruction pointer.
+pub fn sample(ip: u64) {
+    if !IS_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let sample = with_current_component(|component| match component.locate_func(ip) {
+        Some((instance, func)) => Sample::Guest {
+            component: component as *const _ as usize,
+            instance,
+            func,
+        },
+        None => Sample::Component,
+    })
+    .unwrap_or(Sample::Kernel);
+
+    // The profile might be held by the interrupted code, we must not wait for it.
+    match PROFILE.try_lock() {
+        Some(mut profile) => profile.record(sample),
+        None => {
+            LOCKED_SAMPLES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Calls `f` on each line of the profile: a summary followed by the sample count of each
+/// function, stops on the first error.
+pub fn for_each_line<F>(mut f: F)
+where
+    F: FnMut(&dyn fmt::Display) -> Result<(), ()>,
+{
+    let profile = PROFILE.lock();
+    let summary = Summary {
+        profile: &profile,
+        locked: LOCKED_SAMPLES.load(Ordering::SeqCst),
+    };
+    if f(&summary).is_err() {
+        return;
+    }
+    for entry in profile.entries() {
+        if f(entry).is_err() {
+            return;
+        }
+    }
+}
+
+// ————————————————————————————————— Profile ———————————————————————————————— //
+
+/// The location of a sampled instruction.
+#[derive(Clone, Copy)]
+enum Sample {
+    /// No component was executing.
+    Kernel,
+    /// A component was executing, but outside of its guest code (e.g. in a syscall), or its
+    /// instances were locked.
+    Component,
+    /// A function of a component.
+    Guest {
+        /// The address of the component, only used as an identifier.
+        component: usize,
+        instance: InstanceIndex,
+        func: FuncIndex,
+    },
+}
+
+/// The number of samples of a function.
+#[derive(Clone, Copy)]
+struct ProfileEntry {
+    /// The index of the component within the profile.
+    component: u32,
+    instance: InstanceIndex,
+    func: FuncIndex,
+    count: u64,
+}
+
+/// Aggregated sample counts.
+struct Profile {
+    entries: [Option<ProfileEntry>; PROFILE_CAPACITY],
+    /// The addresses of the profiled components, in order of first appearance.
+    ///
+    /// NOTE: a component freed while profiling could be confused with a component later allocated
+    /// at the same address.
+    components: [usize; MAX_PROFILED_COMPONENTS],
+    nb_components: usize,
+    kernel: u64,
+    component: u64,
+    /// Samples dropped because the profile was full.
+    dropped: u64,
+}
+
+impl Profile {
+    const fn new() -> Self {
+        Self {
+            entries: [None; PROFILE_CAPACITY],
+            components: [0; MAX_PROFILED_COMPONENTS],
+            nb_components: 0,
+            kernel: 0,
+            component: 0,
+            dropped: 0,
+        }
+    }
+
+    fn record(&mut self, sample: Sample) {
+        let (component, instance, func) = match sample {
+            Sample::Kernel => {
+                self.kernel += 1;
+                return;
+            }
+            Sample::Component => {
+                self.component += 1;
+                return;
+            }
+            Sample::Guest {
+                component,
+                instance,
+                func,
+            } => (component, instance, func),
+        };
+        let component = match self.component_index(component) {
+            Some(component) => component,
+            None => {
+                self.dropped += 1;
+                return;
+            }
+        };
+
+        for slot in self.entries.iter_mut() {
+            match slot {
+                Some(entry)
+                    if entry.component == component
+                        && entry.instance == instance
+                        && entry.func == func =>
+                {
+                    entry.count += 1;
+                    return;
+                }
+                Some(_) => {}
+                None => {
+                    *slot = Some(ProfileEntry {
+                        component,
+                        instance,
+                        func,
+                        count: 1,
+                    });
+                    return;
+                }
+            }
+        }
+        self.dropped += 1;
+    }
+
+    /// Returns the index of a component within the profile, allocating one if needed.
+    fn component_index(&mut self, component: usize) -> Option<u32> {
+        let known = &self.components[..self.nb_components];
+        if let Some(idx) = known.iter().position(|c| *c == component) {
+            return Some(idx as u32);
+        }
+        if self.nb_components >= MAX_PROFILED_COMPONENTS {
+            return None;
+        }
+        self.components[self.nb_components] = component;
+        self.nb_components += 1;
+        Some(self.nb_components as u32 - 1)
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &ProfileEntry> {
+        self.entries.iter().flatten()
+    }
+
+    fn total(&self) -> u64 {
+        let guest: u64 = self.entries().map(|entry| entry.count).sum();
+        guest + self.kernel + self.component + self.dropped
+    }
+}
+
+/// The first line of the profile.
+struct Summary<'a> {
+    profile: &'a Profile,
+    locked: u64,
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let profile = self.profile;
+        write!(
+            f,
+            "samples: {} (kernel: {}, component: {}, dropped: {})",
+            profile.total() + self.locked,
+            profile.kernel,
+            profile.component,
+            profile.dropped + self.locked
+        )
+    }
+}
+
+impl fmt::Display for ProfileEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "component#{} instance#{} func#{}: {}",
+            self.component,
+            self.instance.as_u32(),
+            self.func.as_u32(),
+            self.count
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use collections::EntityRef;
+
+    fn guest(component: usize, func: usize) -> Sample {
+        Sample::Guest {
+            component,
+            instance: InstanceIndex::new(0),
+            func: FuncIndex::new(func),
+        }
+    }
+
+    #[test_case]
+    fn aggregation() {
+        let mut profile = Profile::new();
+        profile.record(Sample::Kernel);
+        profile.record(Sample::Component);
+        profile.record(guest(0x1000, 3));
+        profile.record(guest(0x1000, 3));
+        profile.record(guest(0x2000, 3));
+
+        let counts: [(u32, u64); 2] = [(0, 2), (1, 1)];
+        assert_eq!(profile.entries().count(), counts.len());
+        for (entry, (component, count)) in profile.entries().zip(counts) {
+            assert_eq!(entry.component, component);
+            assert_eq!(entry.count, count);
+        }
+        assert_eq!(profile.total(), 5);
+
+        // Samples of new functions are dropped once the profile is full
+        for func in 0..PROFILE_CAPACITY {
+            profile.record(guest(0x1000, func + 4));
+        }
+        assert_eq!(profile.dropped, 2);
+        assert_eq!(profile.total(), 5 + PROFILE_CAPACITY as u64);
+    }
+}
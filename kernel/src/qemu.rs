@@ -1,5 +1,7 @@
 use x86_64::instructions::port::Port;
 
+use crate::test_device::TestDevice;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u32)]
 pub enum ExitCode {
@@ -7,6 +9,15 @@ pub enum ExitCode {
     Failed = 0x11,
 }
 
+/// QEMU's `isa-debug-exit` device.
+pub struct Qemu;
+
+impl TestDevice for Qemu {
+    fn exit(&self, exit_code: ExitCode) {
+        exit(exit_code);
+    }
+}
+
 pub fn exit(exit_code: ExitCode) {
     unsafe {
         // Port defined in Cargo.toml under `package.metadata.bootimage.tast-args`
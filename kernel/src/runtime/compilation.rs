@@ -0,0 +1,403 @@
+//! Deferred Compilation
+//!
+//! Modules are compiled by a kernel task, which consumes a queue of compilation jobs. The task
+//! compiles one function at a time and yields to the scheduler in between, so that compiling a big
+//! module does not freeze the system. The progress of each module is tracked by its status, and
+//! reported through module events.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use conquer_once::spin::OnceCell;
+use futures::task::AtomicWaker;
+use spin::Mutex;
+
+use super::get_runtime;
+use super::kernel_objects::{BundleIndex, KoIndex, ModuleIndex, ACTIVE_BUNDLES, ACTIVE_MODULES};
+use crate::events;
+use crate::memory::{Blob, Vma};
+use crate::scheduler::{self, Task};
+use wasm::{Bundle, BundleError, ModuleImage, WasmModule};
+
+/// The jobs waiting for the compilation task, in order of submission.
+static QUEUE: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+
+/// Wakes up the compilation task when a job is submitted.
+static QUEUE_WAKER: AtomicWaker = AtomicWaker::new();
+
+// ————————————————————————————— Module Status —————————————————————————————— //
+
+/// The compilation status of a module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleStatus {
+    /// Waiting in the compilation queue.
+    Pending,
+    /// Being compiled, some functions might not be compiled yet.
+    Compiling { compiled: u32, total: u32 },
+    /// Compiled, the module can be instantiated.
+    Ready,
+    /// The module could not be parsed or compiled.
+    Failed,
+}
+
+impl ModuleStatus {
+    /// Returns the code of the status, as exposed to user space.
+    pub fn code(self) -> u32 {
+        match self {
+            ModuleStatus::Pending => 0,
+            ModuleStatus::Compiling { .. } => 1,
+            ModuleStatus::Ready => 2,
+            ModuleStatus::Failed => 3,
+        }
+    }
+
+    /// Returns true once the compilation completed, whether it succeeded or not.
+    pub fn is_complete(self) -> bool {
+        matches!(self, ModuleStatus::Ready | ModuleStatus::Failed)
+    }
+
+    /// Returns the percentage of compiled functions.
+    pub fn percent(self) -> u32 {
+        match self {
+            ModuleStatus::Pending | ModuleStatus::Failed => 0,
+            ModuleStatus::Compiling { total: 0, .. } | ModuleStatus::Ready => 100,
+            ModuleStatus::Compiling { compiled, total } => {
+                (compiled as u64 * 100 / total as u64) as u32
+            }
+        }
+    }
+}
+
+/// A WebAssembly module, which might still be waiting for its compilation.
+pub struct KernelModule {
+    status: Mutex<ModuleStatus>,
+    module: OnceCell<Arc<WasmModule>>,
+    /// The image of the module heaps, created on the first instantiation. `None` if the module has
+    /// no large data segment or the image could not be created.
+    image: OnceCell<Option<ModuleImage<Arc<Vma>>>>,
+}
+
+impl KernelModule {
+    /// Creates a module which is ready to be instantiated.
+    pub fn ready(module: WasmModule) -> Self {
+        let kernel_module = Self::pending();
+        kernel_module.complete(Ok(module));
+        kernel_module
+    }
+
+    fn pending() -> Self {
+        Self {
+            status: Mutex::new(ModuleStatus::Pending),
+            module: OnceCell::uninit(),
+            image: OnceCell::uninit(),
+        }
+    }
+
+    pub fn status(&self) -> ModuleStatus {
+        *self.status.lock()
+    }
+
+    /// Returns the compiled module, or `None` if the module is not ready.
+    pub fn get(&self) -> Option<Arc<WasmModule>> {
+        self.module.try_get().ok().cloned()
+    }
+
+    /// Returns the image of the module heaps, shared by its instances, or `None` if the module is
+    /// not ready or has no large data segment.
+    pub fn image(&self) -> Option<&ModuleImage<Arc<Vma>>> {
+        let module = self.get()?;
+        self.image
+            .get_or_init(|| match ModuleImage::new(module.as_ref(), get_runtime()) {
+                Ok(image) if !image.is_empty() => Some(image),
+                Ok(_) => None,
+                Err(err) => {
+                    crate::kprintln!("WARNING: failed to create module image: {:?}", err);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    fn set_status(&self, status: ModuleStatus) {
+        *self.status.lock() = status;
+    }
+
+    /// Records the outcome of the compilation.
+    fn complete(&self, module: Result<WasmModule, ()>) {
+        match module {
+            Ok(module) => {
+                self.module.init_once(|| Arc::new(module));
+                self.set_status(ModuleStatus::Ready);
+            }
+            Err(()) => self.set_status(ModuleStatus::Failed),
+        }
+    }
+}
+
+/// A bundle of modules, instantiated together (see `wasm::Bundle`).
+pub struct KernelBundle {
+    modules: Vec<BundledModule>,
+}
+
+/// A module of a bundle.
+pub struct BundledModule {
+    pub name: String,
+    pub module: Arc<KernelModule>,
+    /// The imports bound to previous modules of the bundle, identified by their index.
+    pub links: Vec<(String, usize)>,
+}
+
+impl KernelBundle {
+    /// Returns the modules of the bundle, in instantiation order.
+    pub fn modules(&self) -> &[BundledModule] {
+        &self.modules
+    }
+
+    /// Returns the compilation status of the bundle: failed if any module failed, ready once all
+    /// the modules are ready, and compiling otherwise. The progress of a compiling bundle is the sum
+    /// of the percentages of its modules, out of 100 per module.
+    pub fn status(&self) -> ModuleStatus {
+        let mut ready = true;
+        let mut pending = true;
+        let mut compiled = 0;
+        for module in &self.modules {
+            let status = module.module.status();
+            match status {
+                ModuleStatus::Failed => return ModuleStatus::Failed,
+                ModuleStatus::Ready => pending = false,
+                ModuleStatus::Pending => ready = false,
+                ModuleStatus::Compiling { .. } => {
+                    ready = false;
+                    pending = false;
+                }
+            }
+            compiled += status.percent();
+        }
+        if ready {
+            ModuleStatus::Ready
+        } else if pending {
+            ModuleStatus::Pending
+        } else {
+            ModuleStatus::Compiling {
+                compiled,
+                total: 100 * self.modules.len() as u32,
+            }
+        }
+    }
+}
+
+// ————————————————————————————— Compilation Jobs ————————————————————————————— //
+
+/// The bytes to compile, kept alive until the compilation starts.
+///
+/// The source can not change during compilation: blobs are immutable, and VMAs must be sealed.
+#[derive(Clone)]
+pub enum Source {
+    Blob(Arc<Blob>),
+    Vma(Arc<Vma>),
+}
+
+impl Source {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Source::Blob(blob) => blob.as_bytes(),
+            Source::Vma(vma) => vma.as_bytes(),
+        }
+    }
+}
+
+struct Job {
+    module: Arc<KernelModule>,
+    index: ModuleIndex,
+    source: Source,
+    offset: usize,
+    size: usize,
+}
+
+/// Submits a module for compilation and returns its index, the module is pending until the
+/// compilation task compiles it.
+///
+/// The range must be within the bounds of the source.
+pub fn submit(source: Source, offset: usize, size: usize) -> ModuleIndex {
+    submit_module(source, offset, size).0
+}
+
+fn submit_module(source: Source, offset: usize, size: usize) -> (ModuleIndex, Arc<KernelModule>) {
+    let module = Arc::new(KernelModule::pending());
+    let index = ACTIVE_MODULES.insert(module.clone());
+    QUEUE.lock().push(Job {
+        module: module.clone(),
+        index,
+        source,
+        offset,
+        size,
+    });
+    QUEUE_WAKER.wake();
+    (index, module)
+}
+
+/// Submits the modules of a bundle for compilation and returns the index of the bundle, see
+/// `wasm::Bundle` for the format.
+///
+/// The range must be within the bounds of the source.
+pub fn submit_bundle(
+    source: Source,
+    offset: usize,
+    size: usize,
+) -> Result<BundleIndex, BundleError> {
+    let bytes = &source.as_bytes()[offset..(offset + size)];
+    let bundle = Bundle::parse(bytes)?;
+    let modules = bundle
+        .modules()
+        .iter()
+        .map(|module| {
+            let (_, kernel_module) =
+                submit_module(source.clone(), offset + module.offset, module.wasm.len());
+            BundledModule {
+                name: String::from(module.name),
+                module: kernel_module,
+                links: module
+                    .links
+                    .iter()
+                    .map(|(import, idx)| (String::from(*import), *idx))
+                    .collect(),
+            }
+        })
+        .collect();
+    Ok(ACTIVE_BUNDLES.insert(Arc::new(KernelBundle { modules })))
+}
+
+/// Creates the compilation task, which compiles the submitted modules one at a time.
+///
+/// Must be scheduled only once.
+pub fn compilation_task() -> Task {
+    Task::new(async {
+        loop {
+            let job = NextJob.await;
+            job.run().await;
+        }
+    })
+}
+
+impl Job {
+    async fn run(self) {
+        let source = &self.source.as_bytes()[self.offset..(self.offset + self.size)];
+        let mut compilation = match super::start_compilation(source) {
+            Ok(compilation) => compilation,
+            Err(()) => return self.complete(Err(())),
+        };
+
+        loop {
+            let (compiled, total) = compilation.progress();
+            self.set_status(ModuleStatus::Compiling {
+                compiled: compiled as u32,
+                total: total as u32,
+            });
+            match compilation.compile_next() {
+                Ok(true) => scheduler::yield_now().await,
+                Ok(false) => break,
+                Err(()) => return self.complete(Err(())),
+            }
+        }
+        self.complete(compilation.finish());
+    }
+
+    fn set_status(&self, status: ModuleStatus) {
+        self.module.set_status(status);
+        events::push_module_event(self.index.into_externref(), status);
+    }
+
+    fn complete(&self, module: Result<WasmModule, ()>) {
+        self.module.complete(module);
+        events::push_module_event(self.index.into_externref(), self.module.status());
+    }
+}
+
+/// A future which completes with the next job of the queue.
+struct NextJob;
+
+impl Future for NextJob {
+    type Output = Job;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Job> {
+        if let Some(job) = pop_job() {
+            return Poll::Ready(job);
+        }
+
+        QUEUE_WAKER.register(ctx.waker());
+        // Check again in case a job was submitted concurrently
+        match pop_job() {
+            Some(job) => Poll::Ready(job),
+            None => Poll::Pending,
+        }
+    }
+}
+
+fn pop_job() -> Option<Job> {
+    let mut queue = QUEUE.lock();
+    if queue.is_empty() {
+        None
+    } else {
+        Some(queue.remove(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn module_status() {
+        assert_eq!(ModuleStatus::Pending.percent(), 0);
+        let compiling = ModuleStatus::Compiling {
+            compiled: 1,
+            total: 3,
+        };
+        assert_eq!(compiling.code(), 1);
+        assert_eq!(compiling.percent(), 33);
+        let empty = ModuleStatus::Compiling {
+            compiled: 0,
+            total: 0,
+        };
+        assert_eq!(empty.percent(), 100);
+        assert_eq!(ModuleStatus::Ready.code(), 2);
+        assert_eq!(ModuleStatus::Failed.code(), 3);
+    }
+
+    #[test_case]
+    fn bundle_status() {
+        let member = |status| {
+            let module = KernelModule::pending();
+            module.set_status(status);
+            BundledModule {
+                name: String::new(),
+                module: Arc::new(module),
+                links: Vec::new(),
+            }
+        };
+        let bundle = |statuses: &[ModuleStatus]| KernelBundle {
+            modules: statuses.iter().map(|status| member(*status)).collect(),
+        };
+        let half = ModuleStatus::Compiling {
+            compiled: 1,
+            total: 2,
+        };
+
+        assert_eq!(bundle(&[]).status(), ModuleStatus::Ready);
+        assert_eq!(
+            bundle(&[ModuleStatus::Pending, ModuleStatus::Pending]).status(),
+            ModuleStatus::Pending
+        );
+        let compiling = bundle(&[ModuleStatus::Ready, half, ModuleStatus::Pending]).status();
+        assert_eq!(compiling.code(), 1);
+        assert_eq!(compiling.percent(), 50);
+        assert_eq!(
+            bundle(&[ModuleStatus::Ready, ModuleStatus::Failed]).status(),
+            ModuleStatus::Failed
+        );
+    }
+}
@@ -2,23 +2,42 @@
 //!
 //! Coral is an object-based kernel, in the sense that user-land interacts through the kernel via
 //! handles to kernel-land objects. Kernel objects are reference counted.
+//!
+//! Handles are capabilities, which can be revoked. A handle can be derived from another one, so
+//! that the owner of an object can share it and later withdraw the access: revoking a handle
+//! invalidates all its copies and all the handles derived from it, without affecting the handle it
+//! has been derived from. Revoked slots are reused, but each revocation bumps the generation of
+//! the slot so that a revoked handle stays invalid.
 
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::marker::PhantomData;
 
-use crate::memory::Vma;
+use super::compilation::{KernelBundle, KernelModule};
+use crate::compositor::Surface;
+use crate::memory::{Blob, Vma};
 use crate::syscalls::ExternRef;
 use crate::wasm::Component;
-use wasm::WasmModule;
 
 use spin::Mutex;
+use wasm::ExternHandle;
 
 /// The currently active Virtual Memory Areas.
 pub static ACTIVE_VMA: KernelObjectCollection<Vma, VmaIndex> = KernelObjectCollection::new();
 
-/// The currently active WebAssembly modules.
-pub static ACTIVE_MODULES: KernelObjectCollection<WasmModule, ModuleIndex> =
+/// The currently active blobs.
+pub static ACTIVE_BLOBS: KernelObjectCollection<Blob, BlobIndex> = KernelObjectCollection::new();
+
+/// The currently active WebAssembly modules, including those waiting for their compilation.
+pub static ACTIVE_MODULES: KernelObjectCollection<KernelModule, ModuleIndex> =
+    KernelObjectCollection::new();
+
+/// The currently active module bundles.
+pub static ACTIVE_BUNDLES: KernelObjectCollection<KernelBundle, BundleIndex> =
+    KernelObjectCollection::new();
+
+/// The currently active compositor surfaces.
+pub static ACTIVE_SURFACES: KernelObjectCollection<Surface, SurfaceIndex> =
     KernelObjectCollection::new();
 
 /// The currently active components.
@@ -27,17 +46,39 @@ pub static ACTIVE_COMPONENTS: KernelObjectCollection<Component, ComponentIndex>
 
 /// A collection of kernel objects.
 pub struct KernelObjectCollection<Obj, Idx> {
-    collection: Mutex<Vec<Arc<Obj>>>,
+    collection: Mutex<Slots<Obj>>,
     _idx: PhantomData<Idx>,
 }
 
+/// The slots of a collection, revoked slots are reused by later insertions and derivations.
+struct Slots<Obj> {
+    slots: Vec<Slot<Obj>>,
+    /// The revoked slots available for reuse.
+    free: Vec<usize>,
+    /// The number of objects inserted since the creation of the collection.
+    inserted: usize,
+}
+
+/// An entry of a kernel object collection, each handle refers to a slot.
+struct Slot<Obj> {
+    /// The object, `None` once the slot has been revoked.
+    object: Option<Arc<Obj>>,
+    /// Incremented on revocation, handles to previous generations are invalid.
+    generation: u32,
+    /// The slot this slot has been derived from, if any.
+    parent: Option<usize>,
+    /// The slots derived from this slot.
+    derived: Vec<usize>,
+}
+
 /// Kernel Object Index.
 ///
 /// A trait that represents a kernel object index, can be used to retrieve an object from a global
 /// collection.
 pub trait KoIndex {
-    fn from(index: usize) -> Self;
+    fn new(index: usize, generation: u32) -> Self;
     fn into_usize(self) -> usize;
+    fn generation(self) -> u32;
     fn into_externref(self) -> ExternRef;
 }
 
@@ -45,56 +86,188 @@ impl<Obj, Idx> KernelObjectCollection<Obj, Idx> {
     /// Creates an empty collection.
     const fn new() -> Self {
         Self {
-            collection: Mutex::new(Vec::new()),
+            collection: Mutex::new(Slots {
+                slots: Vec::new(),
+                free: Vec::new(),
+                inserted: 0,
+            }),
             _idx: PhantomData,
         }
     }
 }
 
+impl<Obj> Slots<Obj> {
+    /// Returns the object of a slot, `None` if the slot has been revoked since that generation.
+    fn get(&self, idx: usize, generation: u32) -> Option<&Arc<Obj>> {
+        let slot = self.slots.get(idx)?;
+        if slot.generation != generation {
+            return None;
+        }
+        slot.object.as_ref()
+    }
+
+    /// Stores an object in a free slot, or in a new one if none is free.
+    fn allocate(&mut self, object: Arc<Obj>, parent: Option<usize>) -> (usize, u32) {
+        if let Some(idx) = self.free.pop() {
+            let slot = &mut self.slots[idx];
+            slot.object = Some(object);
+            slot.parent = parent;
+            return (idx, slot.generation);
+        }
+        self.slots.push(Slot {
+            object: Some(object),
+            generation: 0,
+            parent,
+            derived: Vec::new(),
+        });
+        (self.slots.len() - 1, 0)
+    }
+
+    /// Revokes a slot, which becomes available for reuse unless its generations are exhausted.
+    fn release(&mut self, idx: usize) {
+        let slot = &mut self.slots[idx];
+        slot.object = None;
+        slot.parent = None;
+        slot.generation += 1;
+        if slot.generation <= ExternHandle::MAX_GENERATION {
+            self.free.push(idx);
+        }
+    }
+}
+
 impl<Obj, Idx> KernelObjectCollection<Obj, Idx>
 where
-    Idx: KoIndex,
+    Idx: KoIndex + Copy,
 {
     /// Inserts a new object into the collection. The corresponding index is returned.
     pub fn insert(&self, object: Arc<Obj>) -> Idx {
         let mut collection = self.collection.lock();
-        let idx = collection.len();
-        collection.push(object);
-        Idx::from(idx)
+        collection.inserted += 1;
+        let (idx, generation) = collection.allocate(object, None);
+        Idx::new(idx, generation)
     }
 
-    /// Retrieves an object from the collection.
+    /// Retrieves an object from the collection, returns `None` if the index has been revoked.
     pub fn get(&self, index: Idx) -> Option<Arc<Obj>> {
         let collection = self.collection.lock();
-        collection.get(index.into_usize()).cloned()
+        collection
+            .get(index.into_usize(), index.generation())
+            .cloned()
+    }
+
+    /// Derives a new index from an existing one, referring to the same object but revocable
+    /// independently.
+    ///
+    /// Returns `None` if the index is invalid or has been revoked.
+    pub fn derive(&self, index: Idx) -> Option<Idx> {
+        let mut collection = self.collection.lock();
+        let parent = index.into_usize();
+        let object = collection.get(parent, index.generation())?.clone();
+        let (idx, generation) = collection.allocate(object, Some(parent));
+        collection.slots[parent].derived.push(idx);
+        Some(Idx::new(idx, generation))
+    }
+
+    /// Revokes an index, together with all the indices derived from it.
+    ///
+    /// Returns false if the index is invalid or was already revoked.
+    pub fn revoke(&self, index: Idx) -> bool {
+        let mut collection = self.collection.lock();
+        let idx = index.into_usize();
+        if collection.get(idx, index.generation()).is_none() {
+            return false;
+        }
+        if let Some(parent) = collection.slots[idx].parent {
+            collection.slots[parent]
+                .derived
+                .retain(|&derived| derived != idx);
+        }
+
+        // Only the subtree rooted at the revoked slot is visited
+        let mut to_revoke = alloc::vec![idx];
+        while let Some(idx) = to_revoke.pop() {
+            to_revoke.append(&mut collection.slots[idx].derived);
+            collection.release(idx);
+        }
+        true
+    }
+
+    /// Returns the objects referred to by valid indices, an object is returned once per index.
+    pub fn objects(&self) -> Vec<Arc<Obj>> {
+        let collection = self.collection.lock();
+        collection
+            .slots
+            .iter()
+            .filter_map(|slot| slot.object.clone())
+            .collect()
+    }
+
+    /// Returns the number of objects inserted into the collection, derived indices excluded.
+    pub fn len(&self) -> usize {
+        self.collection.lock().inserted
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 }
 
 /// An index representing a virtual memory area.
-#[repr(transparent)]
 #[derive(Debug, Clone, Copy)]
-pub struct VmaIndex(u32);
+pub struct VmaIndex {
+    index: u32,
+    generation: u32,
+}
+
+/// An index representing a blob.
+#[derive(Debug, Clone, Copy)]
+pub struct BlobIndex {
+    index: u32,
+    generation: u32,
+}
 
 /// An index representing a WebAssembly module.
-#[repr(transparent)]
 #[derive(Debug, Clone, Copy)]
-pub struct ModuleIndex(u32);
+pub struct ModuleIndex {
+    index: u32,
+    generation: u32,
+}
+
+/// An index representing a bundle of WebAssembly modules.
+#[derive(Debug, Clone, Copy)]
+pub struct BundleIndex {
+    index: u32,
+    generation: u32,
+}
 
 /// An index representing a component.
-#[repr(transparent)]
 #[derive(Debug, Clone, Copy)]
-pub struct ComponentIndex(u32);
+pub struct ComponentIndex {
+    index: u32,
+    generation: u32,
+}
+
+/// An index representing a compositor surface.
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceIndex {
+    index: u32,
+    generation: u32,
+}
 
 macro_rules! impl_ko_index {
     ($index:ident, $handle:tt, $error:expr) => {
         impl KoIndex for $index {
-            fn from(index: usize) -> Self {
-                let idx = u32::try_from(index).expect($error);
-                Self(idx)
+            fn new(index: usize, generation: u32) -> Self {
+                let index = u32::try_from(index).expect($error);
+                Self { index, generation }
             }
 
             fn into_usize(self) -> usize {
-                self.0 as usize
+                self.index as usize
+            }
+
+            fn generation(self) -> u32 {
+                self.generation
             }
 
             fn into_externref(self) -> ExternRef {
@@ -105,5 +278,52 @@ macro_rules! impl_ko_index {
 }
 
 impl_ko_index!(VmaIndex, Vma, "Invalid VMA index");
+impl_ko_index!(BlobIndex, Blob, "Invalid blob index");
 impl_ko_index!(ModuleIndex, Module, "Invalid module index");
+impl_ko_index!(BundleIndex, Bundle, "Invalid bundle index");
 impl_ko_index!(ComponentIndex, Component, "Invalid component index");
+impl_ko_index!(SurfaceIndex, Surface, "Invalid surface index");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn revocation() {
+        let collection: KernelObjectCollection<u32, VmaIndex> = KernelObjectCollection::new();
+        let root = collection.insert(Arc::new(42));
+        let shared = collection.derive(root).unwrap();
+        let nested = collection.derive(shared).unwrap();
+        let sibling = collection.derive(root).unwrap();
+        assert_eq!(collection.get(nested).as_deref(), Some(&42));
+
+        // Revoking a handle revokes the handles derived from it, and only those
+        assert!(collection.revoke(shared));
+        assert!(collection.get(shared).is_none());
+        assert!(collection.get(nested).is_none());
+        assert_eq!(collection.get(root).as_deref(), Some(&42));
+        assert_eq!(collection.get(sibling).as_deref(), Some(&42));
+
+        // Revoked handles can neither be revoked again nor derived
+        assert!(!collection.revoke(nested));
+        assert!(collection.derive(shared).is_none());
+    }
+
+    #[test_case]
+    fn slot_reuse() {
+        let collection: KernelObjectCollection<u32, VmaIndex> = KernelObjectCollection::new();
+        let root = collection.insert(Arc::new(1));
+        let shared = collection.derive(root).unwrap();
+        assert!(collection.revoke(root));
+
+        // Revoked slots are reused, handles to the previous generation stay invalid
+        let other = collection.insert(Arc::new(2));
+        let derived = collection.derive(other).unwrap();
+        assert_eq!(collection.objects().len(), 2);
+        assert!(collection.get(root).is_none());
+        assert!(collection.get(shared).is_none());
+        assert!(!collection.revoke(shared));
+        assert_eq!(collection.get(derived).as_deref(), Some(&2));
+        assert_eq!(collection.len(), 2);
+    }
+}
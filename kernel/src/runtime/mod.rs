@@ -3,17 +3,20 @@
 //! This module provides the necessary runtime support for proper instantiation and execution of
 //! userspace modules, as well as support for managing kernel objects.
 
+pub mod compilation;
 mod kernel_objects;
 mod runtime;
 
 use crate::memory::VmaAllocator;
 pub use kernel_objects::{
-    ComponentIndex, KoIndex, ModuleIndex, VmaIndex, ACTIVE_COMPONENTS, ACTIVE_MODULES, ACTIVE_VMA,
+    BlobIndex, BundleIndex, ComponentIndex, KoIndex, ModuleIndex, SurfaceIndex, VmaIndex,
+    ACTIVE_BLOBS, ACTIVE_BUNDLES, ACTIVE_COMPONENTS, ACTIVE_MODULES, ACTIVE_SURFACES, ACTIVE_VMA,
 };
-pub use runtime::Runtime;
+pub use runtime::{PageSizes, Runtime};
 
 use alloc::boxed::Box;
 use conquer_once::OnceCell;
+use x86_64::VirtAddr;
 
 use wasm::WasmModule;
 
@@ -24,9 +27,9 @@ static RUNTIME: OnceCell<Runtime> = OnceCell::uninit();
 /// Initializes the global runtime.
 ///
 /// This is required before instantiating and running WebAssembly instances.
-pub fn init(alloc: VmaAllocator) {
+pub fn init(alloc: VmaAllocator, page_sizes: PageSizes) {
     RUNTIME
-        .try_init_once(|| Runtime::new(alloc))
+        .try_init_once(|| Runtime::new(alloc, page_sizes))
         .expect("The runtime must be initialized only once");
 }
 
@@ -42,19 +45,44 @@ pub fn get_runtime() -> &'static Runtime {
     }
 }
 
+/// Resolves a write to a copy-on-write page, returns false if the address is not within a
+/// copy-on-write page or if the runtime is not yet initialized.
+///
+/// Called from the page fault handler, see `Runtime::resolve_copy_on_write`.
+pub fn resolve_copy_on_write(addr: VirtAddr) -> bool {
+    match RUNTIME.try_get() {
+        Ok(runtime) => runtime.resolve_copy_on_write(addr),
+        Err(_) => false,
+    }
+}
+
 // ——————————————————————— Optionnal Compiler Support ——————————————————————— //
 
-type CompilerClosure = Box<dyn Fn(&[u8]) -> Result<WasmModule, ()> + Send + Sync>;
+/// A compilation in progress, whose functions are compiled one at a time.
+pub trait ModuleCompilation: Send {
+    /// Returns the number of functions compiled so far, and the total number of functions.
+    fn progress(&self) -> (usize, usize);
+
+    /// Compiles the next function, returns false once all the functions have been compiled.
+    fn compile_next(&mut self) -> Result<bool, ()>;
+
+    /// Compiles the remaining functions, if any, and returns the module.
+    fn finish(self: Box<Self>) -> Result<WasmModule, ()>;
+}
+
+type CompilerClosure = Box<dyn Fn(&[u8]) -> Result<Box<dyn ModuleCompilation>, ()> + Send + Sync>;
 
 static COMPILER: OnceCell<CompilerClosure> = OnceCell::uninit();
 
+/// Registers the compiler, the closure parses a module and prepares its compilation.
 pub fn register_compiler(closure: CompilerClosure) {
     COMPILER
         .try_init_once(|| closure)
         .expect("The compiler must be registered only once");
 }
 
-pub fn compile(wasm: &[u8]) -> Result<WasmModule, ()> {
+/// Parses a module and prepares its compilation, see `compilation` for deferred compilation.
+pub fn start_compilation(wasm: &[u8]) -> Result<Box<dyn ModuleCompilation>, ()> {
     let compiler = match COMPILER.try_get() {
         Ok(compiler) => compiler,
         Err(_) => {
@@ -64,3 +92,7 @@ pub fn compile(wasm: &[u8]) -> Result<WasmModule, ()> {
     };
     compiler(wasm)
 }
+
+pub fn compile(wasm: &[u8]) -> Result<WasmModule, ()> {
+    start_compilation(wasm)?.finish()
+}
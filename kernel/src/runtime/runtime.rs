@@ -7,35 +7,188 @@ use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 
-use crate::memory::{Vma, VmaAllocator};
+use collections::HashMap;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::memory::{MemoryPressure, PageSize, Vma, VmaAllocator, PAGE_SIZE};
 use crate::runtime::{VmaIndex, ACTIVE_VMA};
 use crate::syscalls::ExternRef;
-use wasm::{HeapKind, ModuleError, RefType, WasmType};
+use crate::{cpu, oom};
+use wasm::{
+    AllocPolicy, CpuFeatures, HeapKind, ModuleError, Placement, PreemptionHandler, RefType,
+    WasmType, NULL_REF,
+};
 
 use super::KoIndex;
 
 type Area = Arc<Vma>;
 
+/// Size of the virtual address space range reserved for each placement group.
+const GROUP_REGION_SIZE: usize = 1 << 32;
+
 // ———————————————————————————— Runtime Context ————————————————————————————— //
 
 /// A context passed to runtime methods during module instantiation.
 pub struct InstantiationCtx {
+    /// The allocation policy of the instance.
+    policy: AllocPolicy,
     /// The owned heaps.
     heaps: Vec<VmaIndex>,
     /// The first externref table is filled with references to owned objects.
     is_first_externref_table: bool,
+    /// Whether the instantiation succeeded, otherwise the heaps are released on drop.
+    committed: bool,
+}
+
+impl Drop for InstantiationCtx {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        // Roll back a failed instantiation: once the partial instance is dropped the heaps are
+        // only referenced by their handles, revoking the handles frees the heaps.
+        for vma_idx in self.heaps.drain(..) {
+            ACTIVE_VMA.revoke(vma_idx);
+        }
+    }
 }
 
 // ———————————————————————————————— Runtime ————————————————————————————————— //
 
+/// The size of the pages backing each kind of area.
+#[derive(Debug, Clone, Copy)]
+pub struct PageSizes {
+    pub heap: PageSize,
+    pub code: PageSize,
+}
+
+impl Default for PageSizes {
+    fn default() -> Self {
+        Self {
+            heap: PageSize::Huge,
+            code: PageSize::Huge,
+        }
+    }
+}
+
 /// The wasm runtime, responsible for allocating code and memory areas.
 pub struct Runtime {
     alloc: VmaAllocator,
+    /// The virtual address space ranges reserved for each placement group.
+    groups: Mutex<HashMap<u64, GroupRegion>>,
+    /// The CPU features detected at boot.
+    cpu_features: CpuFeatures,
+    page_sizes: PageSizes,
+}
+
+/// A range of the virtual address space reserved for a placement group.
+struct GroupRegion {
+    /// Next available address.
+    cursor: VirtAddr,
+    /// End of the range.
+    end: VirtAddr,
 }
 
 impl Runtime {
-    pub fn new(alloc: VmaAllocator) -> Self {
-        Self { alloc }
+    pub fn new(alloc: VmaAllocator, page_sizes: PageSizes) -> Self {
+        Self {
+            alloc,
+            groups: Mutex::new(HashMap::new()),
+            cpu_features: cpu::features(),
+            page_sizes,
+        }
+    }
+
+    /// Allocates a VMA according to the allocation policy.
+    fn alloc_vma(
+        &self,
+        size: usize,
+        policy: &AllocPolicy,
+        page_size: PageSize,
+    ) -> Result<Vma, ModuleError> {
+        let virt_addr = self.reserve_vma(size, policy, page_size)?;
+        self.alloc
+            .with_capacity_at(size, virt_addr, page_size)
+            .map_err(|_| ModuleError::OutOfMemory)
+    }
+
+    /// Applies the out of memory policy before allocating an area (see `oom`): areas are rejected
+    /// while memory is low, and the largest non-privileged component is killed once the emergency
+    /// pool is in use.
+    fn check_memory_pressure(&self) -> Result<(), ModuleError> {
+        match self.alloc.pressure() {
+            MemoryPressure::Normal => Ok(()),
+            MemoryPressure::Low => Err(ModuleError::OutOfMemory),
+            MemoryPressure::Critical => {
+                oom::kill_largest_component();
+                Err(ModuleError::OutOfMemory)
+            }
+        }
+    }
+
+    /// Charges a VMA to the policy quota, and returns the address it must be mapped at.
+    ///
+    /// The area is aligned so that it can be backed by pages of the given size.
+    fn reserve_vma(
+        &self,
+        size: usize,
+        policy: &AllocPolicy,
+        page_size: PageSize,
+    ) -> Result<VirtAddr, ModuleError> {
+        self.check_memory_pressure()?;
+
+        // Whole pages are mapped, charge them all
+        let nb_pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        policy.charge(nb_pages * PAGE_SIZE)?;
+        let align = policy.alignment.max(page_size.alignment(size));
+        match policy.placement {
+            Placement::Anywhere => self
+                .alloc
+                .reserve(size, align)
+                .map_err(|_| ModuleError::FailedToInstantiate),
+            Placement::Grouped(group) => self.reserve_in_group(group, size, align),
+        }
+    }
+
+    /// Resolves a write to a copy-on-write page, returns false if the address is not within a
+    /// copy-on-write page.
+    pub fn resolve_copy_on_write(&self, addr: VirtAddr) -> bool {
+        self.alloc.resolve_copy_on_write(addr)
+    }
+
+    /// Returns the allocator backing the areas of the runtime.
+    pub fn vma_allocator(&self) -> &VmaAllocator {
+        &self.alloc
+    }
+
+    /// Returns the address of the next area of a group, reserving the group range if needed.
+    fn reserve_in_group(
+        &self,
+        group: u64,
+        size: usize,
+        align: usize,
+    ) -> Result<VirtAddr, ModuleError> {
+        let mut groups = self.groups.lock();
+        if !groups.contains_key(&group) {
+            let start = self
+                .alloc
+                .reserve(GROUP_REGION_SIZE, PAGE_SIZE)
+                .map_err(|_| ModuleError::FailedToInstantiate)?;
+            let end = start + GROUP_REGION_SIZE;
+            groups.insert(group, GroupRegion { cursor: start, end });
+        }
+
+        // Areas are page aligned, so that they never share a page
+        let region = groups.get_mut(&group).unwrap();
+        let align = align.max(PAGE_SIZE) as u64;
+        let virt_addr = region.cursor.align_up(align);
+        let end_of_area = (virt_addr + size).align_up(PAGE_SIZE as u64);
+        if end_of_area > region.end {
+            return Err(ModuleError::FailedToInstantiate);
+        }
+        region.cursor = end_of_area;
+        Ok(virt_addr)
     }
 }
 
@@ -43,13 +196,27 @@ unsafe impl wasm::Runtime for Runtime {
     type MemoryArea = Area;
     type Context = InstantiationCtx;
 
-    fn create_context(&self) -> Self::Context {
+    fn cpu_features(&self) -> CpuFeatures {
+        self.cpu_features
+    }
+
+    fn preemption_handler(&self) -> Option<PreemptionHandler> {
+        Some(crate::wasm::preempt)
+    }
+
+    fn create_context(&self, policy: &AllocPolicy) -> Self::Context {
         InstantiationCtx {
+            policy: policy.clone(),
             heaps: Vec::new(),
             is_first_externref_table: true,
+            committed: false,
         }
     }
 
+    fn commit(&self, mut ctx: Self::Context) {
+        ctx.committed = true;
+    }
+
     fn alloc_heap<F>(
         &self,
         min_size: usize,
@@ -60,10 +227,13 @@ unsafe impl wasm::Runtime for Runtime {
     where
         F: FnOnce(&mut [u8]) -> Result<(), ModuleError>,
     {
+        // Heaps are zeroed by the allocator, on demand unless they are backed by huge pages
+        let page_size = self.page_sizes.heap;
+        let virt_addr = self.reserve_vma(min_size, &ctx.policy, page_size)?;
         let mut vma = self
             .alloc
-            .with_capacity(min_size as usize)
-            .map_err(|_| ModuleError::FailedToInstantiate)?;
+            .with_zeroed_capacity_at(min_size, virt_addr, page_size)
+            .map_err(|_| ModuleError::OutOfMemory)?;
         initialize(vma.as_bytes_mut())?;
         let vma = Arc::new(vma);
         let vma_idx = ACTIVE_VMA.insert(Arc::clone(&vma));
@@ -71,6 +241,25 @@ unsafe impl wasm::Runtime for Runtime {
         Ok(vma)
     }
 
+    fn fork_heap(
+        &self,
+        heap: &Self::MemoryArea,
+        _size: usize,
+        ctx: &mut Self::Context,
+    ) -> Result<Self::MemoryArea, ModuleError> {
+        // The whole area is forked copy-on-write, pages are charged as if they were copied
+        // The copy is mapped with small pages, see `VmaAllocator::fork_at`
+        let virt_addr = self.reserve_vma(heap.size(), &ctx.policy, PageSize::Small)?;
+        let vma = self
+            .alloc
+            .fork_at(heap, virt_addr)
+            .map_err(|_| ModuleError::FailedToInstantiate)?;
+        let vma = Arc::new(vma);
+        let vma_idx = ACTIVE_VMA.insert(Arc::clone(&vma));
+        ctx.heaps.push(vma_idx);
+        Ok(vma)
+    }
+
     fn alloc_table(
         &self,
         min_size: u32,
@@ -83,12 +272,13 @@ unsafe impl wasm::Runtime for Runtime {
         } else {
             min_size
         } as usize;
-        let mut table = vec![ExternRef::Invalid.into_abi(); size].into_boxed_slice();
+        ctx.policy.charge(size * core::mem::size_of::<u64>())?;
+        let mut table = vec![NULL_REF; size].into_boxed_slice();
 
         if ctx.is_first_externref_table && ty == RefType::ExternRef {
             ctx.is_first_externref_table = false;
 
-            // Fill the first table with heap references
+            // Fill the first table with heap references, the remaining slots stay null
             for (idx, vma) in ctx.heaps.iter().enumerate() {
                 if idx >= table.len() {
                     break;
@@ -104,15 +294,12 @@ unsafe impl wasm::Runtime for Runtime {
         &self,
         size: usize,
         write_code: F,
-        _ctx: &mut Self::Context,
+        ctx: &mut Self::Context,
     ) -> Result<Self::MemoryArea, ModuleError>
     where
         F: FnOnce(&mut [u8]) -> Result<(), ModuleError>,
     {
-        let mut vma = self
-            .alloc
-            .with_capacity(size)
-            .map_err(|_| ModuleError::FailedToInstantiate)?;
+        let mut vma = self.alloc_vma(size, &ctx.policy, self.page_sizes.code)?;
         write_code(vma.as_bytes_mut())?;
         vma.set_executable();
         Ok(Arc::new(vma))
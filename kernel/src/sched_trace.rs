@@ -0,0 +1,212 @@
+//! Scheduler Tracing
+//!
+//! When scheduler tracing is enabled, the scheduler and the event sources record what they do
+//! (tasks spawned, woken up and polled, events queued and dispatched) into a global ring buffer.
+//! Records are timestamped with the TSC, so that the latency between an interrupt and the tasks it
+//! triggers (e.g. from a key press to the screen update) can be reconstructed from the trace.
+//!
+//! Records are pushed from interrupt handlers too, the buffer is therefore only locked with
+//! interrupts disabled.
+
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::events::EventKind;
+
+/// Number of records kept in the trace buffer, older records are overwritten.
+const SCHED_TRACE_CAPACITY: usize = 128;
+
+/// The global scheduler trace.
+static SCHED_TRACE: Mutex<SchedTrace> = Mutex::new(SchedTrace::new());
+
+/// Whether the scheduler and event sources record their activity.
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// ———————————————————————————————— Tracing ————————————————————————————————— //
+
+/// Enables or disables scheduler tracing, the trace is reset when tracing gets enabled.
+pub fn set_enabled(enabled: bool) {
+    if enabled && !IS_ENABLED.load(Ordering::SeqCst) {
+        interrupts::without_interrupts(|| *SCHED_TRACE.lock() = SchedTrace::new());
+    }
+    IS_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns true if scheduler activity should be recorded.
+pub fn is_enabled() -> bool {
+    IS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the current timestamp, in CPU cycles.
+pub fn now() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Records an event which happened at the current time, if tracing is enabled.
+pub fn record(event: SchedEvent) {
+    record_at(now(), event);
+}
+
+/// Records an event which happened at the given timestamp, if tracing is enabled.
+pub fn record_at(timestamp: u64, event: SchedEvent) {
+    if !is_enabled() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        SCHED_TRACE.lock().push(SchedRecord { timestamp, event });
+    });
+}
+
+/// Calls `f` on each record of the trace, from the oldest to the most recent, stops on the first
+/// error.
+pub fn for_each_record<F>(f: F)
+where
+    F: FnMut(&SchedRecord) -> Result<(), ()>,
+{
+    // Copy the trace, so that interrupts are not disabled while formatting the records.
+    let trace = interrupts::without_interrupts(|| SCHED_TRACE.lock().clone());
+    trace.for_each(f);
+}
+
+// ————————————————————————————— Trace Records —————————————————————————————— //
+
+/// A timestamped scheduler event.
+#[derive(Clone, Copy)]
+pub struct SchedRecord {
+    /// The TSC value when the event happened.
+    timestamp: u64,
+    event: SchedEvent,
+}
+
+/// An action of the scheduler or of an event source.
+#[derive(Clone, Copy)]
+pub enum SchedEvent {
+    /// A task has been added to the ready queue.
+    Spawn { task: u64, queue_depth: usize },
+    /// A task has been woken up and queued again.
+    Wake { task: u64, queue_depth: usize },
+    /// A task has been polled (i.e. switched to) for the given number of cycles.
+    Poll { task: u64, cycles: u64, done: bool },
+    /// The core halted for the given number of cycles, as no task was ready.
+    Idle { cycles: u64 },
+    /// An event has been pushed to its source, or dropped if the queue was full.
+    EventQueued {
+        kind: EventKind,
+        queue_depth: usize,
+        dropped: bool,
+    },
+    /// An event has been handed to the listeners of its dispatcher.
+    EventDispatched { kind: EventKind, listeners: usize },
+}
+
+impl fmt::Display for SchedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedEvent::Spawn { task, queue_depth } => {
+                write!(f, "spawn task={} queue={}", task, queue_depth)
+            }
+            SchedEvent::Wake { task, queue_depth } => {
+                write!(f, "wake task={} queue={}", task, queue_depth)
+            }
+            SchedEvent::Poll { task, cycles, done } => {
+                write!(f, "poll task={} cycles={}", task, cycles)?;
+                if *done {
+                    write!(f, " done")?;
+                }
+                Ok(())
+            }
+            SchedEvent::Idle { cycles } => write!(f, "idle cycles={}", cycles),
+            SchedEvent::EventQueued {
+                kind,
+                queue_depth,
+                dropped,
+            } => {
+                write!(f, "queue {:?} queue={}", kind, queue_depth)?;
+                if *dropped {
+                    write!(f, " dropped")?;
+                }
+                Ok(())
+            }
+            SchedEvent::EventDispatched { kind, listeners } => {
+                write!(f, "dispatch {:?} listeners={}", kind, listeners)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SchedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.timestamp, self.event)
+    }
+}
+
+// —————————————————————————————— Ring Buffer ——————————————————————————————— //
+
+/// A fixed capacity ring buffer of scheduler records.
+#[derive(Clone)]
+struct SchedTrace {
+    records: [Option<SchedRecord>; SCHED_TRACE_CAPACITY],
+    /// Index of the next record to write.
+    next: usize,
+}
+
+impl SchedTrace {
+    const fn new() -> Self {
+        Self {
+            records: [None; SCHED_TRACE_CAPACITY],
+            next: 0,
+        }
+    }
+
+    /// Pushes a record, overwriting the oldest one if the buffer is full.
+    fn push(&mut self, record: SchedRecord) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % SCHED_TRACE_CAPACITY;
+    }
+
+    /// Iterates over the records from the oldest to the most recent, stops on the first error.
+    fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&SchedRecord) -> Result<(), ()>,
+    {
+        let (recent, old) = self.records.split_at(self.next);
+        for record in old.iter().chain(recent.iter()).flatten() {
+            if f(record).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: u64) -> SchedRecord {
+        SchedRecord {
+            timestamp,
+            event: SchedEvent::Idle { cycles: 0 },
+        }
+    }
+
+    #[test_case]
+    fn ring_buffer() {
+        let mut trace = SchedTrace::new();
+        for timestamp in 0..(SCHED_TRACE_CAPACITY + 3) as u64 {
+            trace.push(record(timestamp));
+        }
+
+        // The three oldest records must have been overwritten
+        let mut expected = 3;
+        trace.for_each(|record| {
+            assert_eq!(record.timestamp, expected);
+            expected += 1;
+            Ok(())
+        });
+        assert_eq!(expected, (SCHED_TRACE_CAPACITY + 3) as u64);
+    }
+}
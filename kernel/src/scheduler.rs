@@ -1,18 +1,34 @@
 //! Scheduler
+//!
+//! The scheduler is an executor for kernel tasks, which are plain futures: drivers, event
+//! dispatchers and guest executions are written as async functions, and woken up through wakers.
 
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::task::Wake;
+use alloc::vec::Vec;
 use core::future::Future;
 use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::task::{Context, Poll, Waker};
 
+use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use spin::Mutex;
 use x86_64::instructions::interrupts;
 
-type SharedTask = Arc<Mutex<Task>>;
-type TaskQueue = Arc<ArrayQueue<SharedTask>>;
+use crate::sched_trace::{self, SchedEvent};
+
+type TaskQueue = Arc<ArrayQueue<Arc<TaskCell>>>;
+
+/// The scheduler running on this core, once started.
+static CURRENT_SCHEDULER: OnceCell<Arc<Scheduler>> = OnceCell::uninit();
+
+/// The identifier of the next task, only used to correlate scheduler trace records.
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the scheduler has been stopped, see `stop`.
+static STOPPED: AtomicBool = AtomicBool::new(false);
 
 pub struct Task {
     future: Pin<Box<dyn Future<Output = ()> + Send>>,
@@ -30,6 +46,16 @@ impl Task {
     }
 }
 
+/// A task owned by the scheduler.
+struct TaskCell {
+    /// The task, `None` once completed.
+    task: Mutex<Option<Task>>,
+    /// Whether the task is in the ready queue, so that it is never queued twice.
+    queued: AtomicBool,
+    /// Identifies the task in the scheduler trace.
+    id: u64,
+}
+
 pub struct Scheduler {
     task_queue: TaskQueue,
 }
@@ -41,17 +67,47 @@ impl Scheduler {
         }
     }
 
+    /// Queues a task, which is dropped if the scheduler has been stopped.
     pub fn schedule(&self, task: Task) {
-        let task = Arc::new(Mutex::new(task));
+        if is_stopped() {
+            return;
+        }
+        let task = Arc::new(TaskCell {
+            task: Mutex::new(Some(task)),
+            queued: AtomicBool::new(true),
+            id: NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed),
+        });
+        let id = task.id;
         self.task_queue.push(task).ok().expect("Task queue is full");
+        sched_trace::record(SchedEvent::Spawn {
+            task: id,
+            queue_depth: self.task_queue.len(),
+        });
+    }
+
+    /// Schedules a future, its output is discarded.
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future + Send + 'static,
+    {
+        self.schedule(Task::new(async move {
+            future.await;
+        }));
     }
 
     /// Starts execution of component on this CPU core.
-    pub fn run(&self) -> ! {
-        loop {
+    ///
+    /// The scheduler becomes the current scheduler, to which `spawn` hands the new tasks. Once
+    /// stopped, the core is halted.
+    pub fn run(self: &Arc<Self>) -> ! {
+        CURRENT_SCHEDULER
+            .try_init_once(|| Arc::clone(self))
+            .expect("A scheduler is already running");
+        while !is_stopped() {
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
+        crate::hlt_loop();
     }
 
     /// Halt the current core if the task queue is empty.
@@ -59,45 +115,111 @@ impl Scheduler {
         // Deactivate interrupts to prevent race conditions
         interrupts::disable();
         if self.task_queue.is_empty() {
+            let start = sched_trace::now();
             interrupts::enable_and_hlt();
+            sched_trace::record_at(
+                start,
+                SchedEvent::Idle {
+                    cycles: sched_trace::now() - start,
+                },
+            );
         } else {
             interrupts::enable();
         }
     }
 
     fn run_ready_tasks(&self) {
-        while let Some(task) = self.task_queue.pop() {
+        while let Some(cell) = self.task_queue.pop() {
+            if is_stopped() {
+                return;
+            }
+            // The task can be queued again as soon as it is polled
+            cell.queued.store(false, Ordering::SeqCst);
+
             // TODO: optimize waker? (remove clone and from_waker)
-            let waker = TaskWaker::new(task.clone(), self.task_queue.clone());
+            let waker = TaskWaker::new(cell.clone(), self.task_queue.clone());
             let mut ctx = Context::from_waker(&waker);
-            let mut task = task.lock();
-            match task.poll(&mut ctx) {
-                Poll::Ready(()) => {
-                    // Task done
-                }
-                Poll::Pending => {
-                    // Task pending...
-                }
+            let mut task = cell.task.lock();
+            let start = sched_trace::now();
+            let done = matches!(
+                task.as_mut().map(|task| task.poll(&mut ctx)),
+                Some(Poll::Ready(()))
+            );
+            sched_trace::record_at(
+                start,
+                SchedEvent::Poll {
+                    task: cell.id,
+                    cycles: sched_trace::now() - start,
+                    done,
+                },
+            );
+            if done {
+                // Task done, the future must not be polled again
+                *task = None;
             }
         }
     }
 }
 
+/// Schedules a future on the current scheduler, its output is discarded.
+///
+/// Panics if no scheduler is running.
+pub fn spawn<F>(future: F)
+where
+    F: Future + Send + 'static,
+{
+    CURRENT_SCHEDULER
+        .try_get()
+        .expect("No scheduler is running")
+        .spawn(future);
+}
+
+/// Stops the scheduler: no task is polled or queued anymore, including the tasks woken up later.
+///
+/// Stopping is definitive, the task calling `stop` runs to completion or diverges.
+pub fn stop() {
+    STOPPED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if the scheduler has been stopped.
+pub fn is_stopped() -> bool {
+    STOPPED.load(Ordering::SeqCst)
+}
+
+/// Returns the number of tasks ready to run on the current scheduler, 0 if none is running.
+pub fn run_queue_len() -> usize {
+    match CURRENT_SCHEDULER.try_get() {
+        Ok(scheduler) => scheduler.task_queue.len(),
+        Err(_) => 0,
+    }
+}
+
 pub struct TaskWaker {
-    task: SharedTask,
+    task: Arc<TaskCell>,
     queue: TaskQueue,
 }
 
 impl TaskWaker {
-    fn new(task: SharedTask, queue: TaskQueue) -> Waker {
+    fn new(task: Arc<TaskCell>, queue: TaskQueue) -> Waker {
         Waker::from(Arc::new(TaskWaker { task, queue }))
     }
 
     fn wake_task(&self) {
+        if is_stopped() {
+            return;
+        }
+        if self.task.queued.swap(true, Ordering::SeqCst) {
+            // Already queued
+            return;
+        }
         self.queue
             .push(self.task.clone())
             .ok()
             .expect("Can't wake task: task queue is full");
+        sched_trace::record(SchedEvent::Wake {
+            task: self.task.id,
+            queue_depth: self.queue.len(),
+        });
     }
 }
 
@@ -110,3 +232,147 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+// ————————————————————————————————— Timers ————————————————————————————————— //
+
+/// Frequency of the programmable interval timer, which raises the timer interrupt, in Hz.
+const PIT_FREQUENCY: u128 = 1_193_182;
+/// The PIT divider, left to its default value.
+const PIT_DIVIDER: u128 = 65_536;
+
+/// Number of timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The tasks waiting for the next timer interrupt.
+static SLEEPERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+/// Records a timer interrupt and wakes up the sleeping tasks.
+///
+/// Must be called from the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    for waker in SLEEPERS.lock().drain(..) {
+        waker.wake();
+    }
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Converts a duration in milliseconds into a number of timer interrupts, rounded up.
+///
+/// The computation can not overflow: there are less than one tick per millisecond.
+fn ms_to_ticks(ms: u64) -> u64 {
+    let divider = PIT_DIVIDER * 1000;
+    let ticks = (ms as u128 * PIT_FREQUENCY + divider - 1) / divider;
+    ticks as u64
+}
+
+/// Returns a future which completes after at least the given number of milliseconds.
+///
+/// The precision is limited by the timer frequency, about 55 milliseconds.
+pub fn sleep_ms(ms: u64) -> Sleep {
+    sleep_until(deadline_after_ms(ms))
+}
+
+/// Returns a future which completes once the number of timer interrupts reaches the deadline.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep { deadline }
+}
+
+/// Returns the number of timer interrupts after which at least the given number of milliseconds
+/// elapsed, for use with `sleep_until`.
+pub fn deadline_after_ms(ms: u64) -> u64 {
+    TICKS.load(Ordering::SeqCst).saturating_add(ms_to_ticks(ms))
+}
+
+/// Returns a future which gives control back to the scheduler once before completing, letting
+/// other ready tasks run.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct Sleep {
+    /// The tick count after which the future completes.
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        // Interrupts are disabled to prevent deadlocks with the timer interrupt handler.
+        interrupts::without_interrupts(|| {
+            if TICKS.load(Ordering::SeqCst) >= self.deadline {
+                return Poll::Ready(());
+            }
+            SLEEPERS.lock().push(ctx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        ctx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wakes itself twice on the first poll, completes on the second one.
+    struct WakeTwice {
+        polls: Arc<AtomicU64>,
+    }
+
+    impl Future for WakeTwice {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+            if self.polls.fetch_add(1, Ordering::SeqCst) > 0 {
+                return Poll::Ready(());
+            }
+            ctx.waker().wake_by_ref();
+            ctx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test_case]
+    fn tasks_are_queued_once() {
+        let scheduler = Scheduler::new();
+        let polls = Arc::new(AtomicU64::new(0));
+        scheduler.spawn(WakeTwice {
+            polls: polls.clone(),
+        });
+        scheduler.run_ready_tasks();
+
+        // Woken twice, but polled once more and never after completion
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+        assert!(scheduler.task_queue.is_empty());
+    }
+
+    #[test_case]
+    fn ms_to_ticks_rounds_up() {
+        assert_eq!(ms_to_ticks(0), 0);
+        assert_eq!(ms_to_ticks(1), 1);
+        assert_eq!(ms_to_ticks(54), 1);
+        assert_eq!(ms_to_ticks(55), 2);
+        assert_eq!(ms_to_ticks(1000), 19);
+    }
+}
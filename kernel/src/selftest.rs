@@ -0,0 +1,289 @@
+//! Self-Tests
+//!
+//! A battery of checks exercising the kernel subsystems on the running system. The QEMU test
+//! harness is not available on real hardware, the self-tests are instead run on demand (e.g. from
+//! the userboot shell through the `selftest_run` syscall) and report to the console.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use futures::stream::Stream;
+
+use crate::events::{Event, EventDispatcher, EventKind, SourceStream};
+use crate::interrupts::{self, InterruptIndex};
+use crate::memory::{VmaState, VmaStateError, PAGE_SIZE};
+use crate::runtime::{self, get_runtime};
+use crate::sched_trace;
+use crate::wasm::Component;
+use crate::{allocator, kprintln, scheduler};
+use wasm::{Args, ExitStatus};
+
+/// Number of allocations performed by the allocator stress test.
+const ALLOC_ROUNDS: usize = 512;
+/// Maximum size of the allocations of the allocator stress test, in bytes.
+const MAX_ALLOC_SIZE: usize = 4096;
+/// Number of events sent through the event source.
+const EVENT_ROUNDS: u64 = 64;
+
+/// A module whose `main` function stores 20 in its memory, then returns it plus 22.
+///
+/// ```wat
+/// (module
+///   (memory 1)
+///   (func (export "main") (result i32)
+///     (i32.store (i32.const 0) (i32.const 20))
+///     (i32.add (i32.load (i32.const 0)) (i32.const 22))))
+/// ```
+const SELFTEST_MODULE: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f, 0x03,
+    0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x08, 0x01, 0x04, 0x6d, 0x61, 0x69, 0x6e,
+    0x00, 0x00, 0x0a, 0x13, 0x01, 0x11, 0x00, 0x41, 0x00, 0x41, 0x14, 0x36, 0x02, 0x00, 0x41, 0x00,
+    0x28, 0x02, 0x00, 0x41, 0x16, 0x6a, 0x0b,
+];
+
+type SelfTest = fn() -> Result<String, String>;
+
+/// The self-tests, in execution order.
+const SELF_TESTS: &[(&str, SelfTest)] = &[
+    ("allocator", allocator_stress),
+    ("vma", vma_permissions),
+    ("events", event_round_trip),
+    ("wasm", compile_and_run),
+    ("stats", timer_liveness),
+];
+
+/// The outcome of a self-test, with either details about the run or the reason of the failure.
+pub struct Report {
+    pub name: &'static str,
+    pub outcome: Result<String, String>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.outcome.is_ok()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Ok(details) => write!(f, "PASS {}: {}", self.name, details),
+            Err(reason) => write!(f, "FAIL {}: {}", self.name, reason),
+        }
+    }
+}
+
+/// Runs all the self-tests, and prints their outcome to the console.
+pub fn run() -> Vec<Report> {
+    let reports: Vec<Report> = SELF_TESTS
+        .iter()
+        .map(|(name, test)| {
+            let report = Report {
+                name,
+                outcome: test(),
+            };
+            kprintln!("selftest: {}", report);
+            report
+        })
+        .collect();
+    let passed = reports.iter().filter(|report| report.passed()).count();
+    kprintln!("selftest: {}/{} passed", passed, reports.len());
+    reports
+}
+
+// ————————————————————————————————— Tests —————————————————————————————————— //
+
+/// Allocates and frees blocks of random sizes, checks that their content is preserved and that
+/// all the memory is given back to the heap.
+fn allocator_stress() -> Result<String, String> {
+    let heap_free = allocator::heap_free();
+    let mut seed: u32 = 0x2545_f491;
+    let mut blocks: Vec<Vec<u8>> = Vec::with_capacity(ALLOC_ROUNDS);
+    for round in 0..ALLOC_ROUNDS {
+        seed = xorshift(seed);
+        let size = 1 + seed as usize % MAX_ALLOC_SIZE;
+        blocks.push(vec![round as u8; size]);
+
+        // Free some blocks along the way, so that freed blocks get reused
+        if round % 3 == 2 {
+            blocks.swap_remove(seed as usize % blocks.len());
+        }
+    }
+    for block in &blocks {
+        if block.iter().any(|byte| *byte != block[0]) {
+            return Err(String::from("corrupted block"));
+        }
+    }
+    drop(blocks);
+
+    let leaked = heap_free.saturating_sub(allocator::heap_free());
+    if leaked > 0 {
+        return Err(format!("{} bytes leaked", leaked));
+    }
+    Ok(format!("{} allocations", ALLOC_ROUNDS))
+}
+
+/// Flips the permissions of a VMA back and forth, checks that its content is preserved, then
+/// seals it.
+fn vma_permissions() -> Result<String, String> {
+    let mut vma = get_runtime()
+        .vma_allocator()
+        .with_capacity(PAGE_SIZE)
+        .map_err(|_| String::from("allocation failed"))?;
+    let check = |bytes: &[u8], expected: u8| {
+        if bytes.iter().all(|byte| *byte == expected) {
+            Ok(())
+        } else {
+            Err(format!("content lost, expected {:#x}", expected))
+        }
+    };
+
+    vma.set_write();
+    vma.as_bytes_mut().fill(0x2a);
+    vma.set_read_only();
+    check(vma.as_bytes(), 0x2a)?;
+    vma.set_executable();
+    check(vma.as_bytes(), 0x2a)?;
+    vma.set_write();
+    vma.as_bytes_mut().fill(0x42);
+    check(vma.as_bytes(), 0x42)?;
+
+    vma.seal()
+        .map_err(|err| format!("seal failed: {:?}", err))?;
+    if vma.state() != VmaState::Sealed {
+        return Err(String::from("not sealed"));
+    }
+    if vma.with_exclusive(|_| ()) != Err(VmaStateError::Sealed) {
+        return Err(String::from("sealed VMA is writable"));
+    }
+    check(vma.as_bytes(), 0x42)?;
+    Ok(String::from("4 permission flips, sealed"))
+}
+
+/// Sends events through an event source, and measures the time until they are received by a
+/// consumer waiting on it, as a dispatcher would.
+fn event_round_trip() -> Result<String, String> {
+    let dispatcher = EventDispatcher::new(1);
+    let source = Arc::clone(dispatcher.source());
+    let mut stream = SourceStream::new(Arc::clone(&source));
+    let woken = Arc::new(WakeFlag(AtomicBool::new(false)));
+    let waker = Waker::from(Arc::clone(&woken));
+    let mut ctx = Context::from_waker(&waker);
+
+    let mut total = 0;
+    let mut max = 0;
+    for _ in 0..EVENT_ROUNDS {
+        // Wait for the next event, which registers the waker
+        if stream.as_mut().poll_next(&mut ctx).is_ready() {
+            return Err(String::from("spurious event"));
+        }
+        woken.0.store(false, Ordering::SeqCst);
+
+        let start = sched_trace::now();
+        if source.try_dispatch(Event::new(EventKind::Timer)).is_err() {
+            return Err(String::from("queue full"));
+        }
+        if !woken.0.load(Ordering::SeqCst) {
+            return Err(String::from("consumer not woken"));
+        }
+        if !matches!(stream.as_mut().poll_next(&mut ctx), Poll::Ready(Some(_))) {
+            return Err(String::from("event lost"));
+        }
+        let cycles = sched_trace::now() - start;
+        total += cycles;
+        max = max.max(cycles);
+    }
+    Ok(format!(
+        "{} cycles on average, {} at most",
+        total / EVENT_ROUNDS,
+        max
+    ))
+}
+
+/// Compiles and instantiates an embedded module, then runs it.
+fn compile_and_run() -> Result<String, String> {
+    let start = sched_trace::now();
+    let module =
+        runtime::compile(SELFTEST_MODULE).map_err(|_| String::from("compilation failed"))?;
+    let component = Component::new();
+    let idx = component
+        .add_instance(&module)
+        .map_err(|err| format!("instantiation failed: {:?}", err))?;
+    match component.call_nested(idx, "main", &Args::new()) {
+        Ok(ExitStatus::Returned(values)) if values == [42] => Ok(format!(
+            "returned 42 after {} cycles",
+            sched_trace::now() - start
+        )),
+        Ok(status) => Err(format!("unexpected exit status: {:?}", status)),
+        Err(err) => Err(format!("call failed: {:?}", err)),
+    }
+}
+
+// ————————————————————————————————— Utils —————————————————————————————————— //
+
+/// A waker recording whether it has been woken.
+struct WakeFlag(AtomicBool);
+
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A xorshift pseudo-random number generator, the seed must not be 0.
+fn xorshift(mut x: u32) -> u32 {
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    x
+}
+
+/// Checks that the timer is ticking, and that each tick has been counted as a timer interrupt.
+fn timer_liveness() -> Result<String, String> {
+    // Interrupts are counted before the tick is recorded, read the ticks first
+    let ticks = scheduler::ticks();
+    let mut timer_interrupts = 0;
+    interrupts::for_each_interrupt_count(|vector, count| {
+        if vector == InterruptIndex::Timer as u8 {
+            timer_interrupts = count;
+        }
+    });
+    if ticks == 0 {
+        return Err(String::from("the timer is not ticking"));
+    }
+    if timer_interrupts < ticks {
+        return Err(format!(
+            "{} timer interrupts for {} ticks",
+            timer_interrupts, ticks
+        ));
+    }
+    Ok(format!("{} ticks", ticks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn report_format() {
+        let passed = Report {
+            name: "vma",
+            outcome: Ok(String::from("sealed")),
+        };
+        let failed = Report {
+            name: "wasm",
+            outcome: Err(String::from("compilation failed")),
+        };
+        assert!(passed.passed());
+        assert!(!failed.passed());
+        assert_eq!(format!("{}", passed), "PASS vma: sealed");
+        assert_eq!(format!("{}", failed), "FAIL wasm: compilation failed");
+    }
+}
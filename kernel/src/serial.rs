@@ -1,13 +1,29 @@
 use core::fmt;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+/// Base port of the first serial interface.
+const SERIAL1_PORT: u16 = 0x3F8;
+/// Line status register, relative to the base port.
+const LINE_STATUS: u16 = 5;
+/// Set in the line status register once the transmit buffer can accept a new byte.
+const TRANSMIT_READY: u8 = 1 << 5;
+/// Set in the line status register once all the data has been transmitted.
+const TRANSMITTER_EMPTY: u8 = 1 << 6;
+/// Set in the line status register when a received byte is available.
+const DATA_READY: u8 = 1;
+
+/// Whether output still goes through the early console, see `init_early`.
+static EARLY_BOOT: AtomicBool = AtomicBool::new(true);
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(SERIAL1_PORT) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -16,7 +32,7 @@ lazy_static! {
 #[macro_export]
 macro_rules! debug_print {
     ($($args:tt)*) => {
-        $crate::serial::_print(core::format_args!($($args)*));
+        $crate::test_device::_print(core::format_args!($($args)*));
     };
 }
 
@@ -42,8 +58,20 @@ macro_rules! kprintln {
     ($($arg:tt)*) => ($crate::kprint!("{}\n", core::format_args!($($arg)*)))
 }
 
+/// Prints to the serial interface through the early console, see `EarlyConsole`.
+#[macro_export]
+macro_rules! early_println {
+    ($($arg:tt)*) => {
+        $crate::serial::_early_print(core::format_args!("{}\n", core::format_args!($($arg)*)))
+    };
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    if EARLY_BOOT.load(Ordering::Relaxed) {
+        _early_print(args);
+        return;
+    }
     without_interrupts(|| {
         SERIAL1
             .lock()
@@ -51,3 +79,74 @@ pub fn _print(args: fmt::Arguments) {
             .expect("Printing to serial failed");
     });
 }
+
+#[doc(hidden)]
+pub fn _early_print(args: fmt::Arguments) {
+    // Errors can not be reported anywhere
+    let _ = EarlyConsole.write_fmt(args);
+}
+
+// —————————————————————————————— Early Console ————————————————————————————— //
+
+/// A console writing directly to the serial interface, without locks nor allocations.
+///
+/// The early console is used from the first instruction of the kernel until memory is
+/// initialized, when little is known to work, and by the panic handler, which might be called
+/// while the serial lock is held. Output of concurrent writers may be interleaved.
+pub struct EarlyConsole;
+
+impl fmt::Write for EarlyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut line_status: Port<u8> = Port::new(SERIAL1_PORT + LINE_STATUS);
+        let mut data: Port<u8> = Port::new(SERIAL1_PORT);
+        for byte in s.bytes() {
+            // SAFETY: reading the line status and writing data have no side effects beyond the
+            // serial interface.
+            unsafe {
+                while line_status.read() & TRANSMIT_READY == 0 {
+                    core::hint::spin_loop();
+                }
+                data.write(byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Initializes the serial interface for the early console, to be called first thing at boot.
+pub fn init_early() {
+    // SAFETY: the port is the one of the first serial interface, which is not in use yet.
+    unsafe { SerialPort::new(SERIAL1_PORT) }.init();
+}
+
+/// Switches from the early console to the locked serial interface, once memory is initialized.
+pub fn end_early_boot() {
+    EARLY_BOOT.store(false, Ordering::Relaxed);
+}
+
+/// Waits until all the pending data has been sent over the serial interface.
+pub fn flush() {
+    without_interrupts(|| {
+        // Hold the lock so that no one writes while flushing
+        let _serial = SERIAL1.lock();
+        let mut line_status: Port<u8> = Port::new(SERIAL1_PORT + LINE_STATUS);
+        while unsafe { line_status.read() } & TRANSMITTER_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Returns the next byte received over the serial interface, if any, without blocking.
+pub fn try_read() -> Option<u8> {
+    without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        let mut line_status: Port<u8> = Port::new(SERIAL1_PORT + LINE_STATUS);
+        let mut data: Port<u8> = Port::new(SERIAL1_PORT);
+        unsafe {
+            if line_status.read() & DATA_READY == 0 {
+                return None;
+            }
+            Some(data.read())
+        }
+    })
+}
@@ -0,0 +1,154 @@
+//! Service Registry
+//!
+//! Components are given handles when they are instantiated, the registry lets them discover
+//! handles published later on: a driver registers a handle under a name (e.g. "vga"), and
+//! components started afterward look it up by name.
+//!
+//! The registry holds a handle derived from the registered one, and each lookup returns a handle
+//! derived from it in turn. Revoking the registered handle therefore withdraws the service
+//! together with all the handles obtained through lookups, after which the name can be registered
+//! again.
+//!
+//! Components can only register and lookup the names allowed by their `ServicePermissions`.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::syscalls::ExternRef;
+
+/// Maximum length of a service name, in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+static SERVICES: Mutex<BTreeMap<String, ExternRef>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceError {
+    /// Names are made of 1 to `MAX_NAME_LEN` printable ASCII characters, without spaces.
+    InvalidName,
+    /// A valid handle is already registered under that name.
+    AlreadyRegistered,
+    /// No valid handle is registered under that name.
+    NotFound,
+}
+
+// —————————————————————————————— Permissions ——————————————————————————————— //
+
+/// The names a component is allowed to register and to lookup.
+///
+/// Names are matched against patterns, which are either a plain name or a prefix followed by `*`
+/// (e.g. "drivers/*"). The pattern "*" matches all names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServicePermissions {
+    register: Vec<String>,
+    lookup: Vec<String>,
+}
+
+impl ServicePermissions {
+    /// No service can be registered nor looked up.
+    pub const fn none() -> Self {
+        Self {
+            register: Vec::new(),
+            lookup: Vec::new(),
+        }
+    }
+
+    pub fn new(register: Vec<String>, lookup: Vec<String>) -> Self {
+        Self { register, lookup }
+    }
+
+    pub fn can_register(&self, name: &str) -> bool {
+        self.register.iter().any(|pattern| matches(pattern, name))
+    }
+
+    pub fn can_lookup(&self, name: &str) -> bool {
+        self.lookup.iter().any(|pattern| matches(pattern, name))
+    }
+
+    /// Returns the permissions of a component created by a component with these permissions: it
+    /// can lookup the same services, but can not register any.
+    pub fn inherited(&self) -> Self {
+        Self {
+            register: Vec::new(),
+            lookup: self.lookup.clone(),
+        }
+    }
+}
+
+/// Returns true if the name matches the pattern.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+// ———————————————————————————————— Registry ———————————————————————————————— //
+
+/// Registers a handle under a name, the handle must have been derived for the registry.
+///
+/// A name can only be registered again once the handle it refers to has been revoked.
+pub fn register(name: &str, handle: ExternRef) -> Result<(), ServiceError> {
+    check_name(name)?;
+    let mut services = SERVICES.lock();
+    if let Some(registered) = services.get(name) {
+        if registered.is_valid() {
+            return Err(ServiceError::AlreadyRegistered);
+        }
+    }
+    services.insert(String::from(name), handle);
+    Ok(())
+}
+
+/// Returns the handle registered under a name, to be derived before being handed out.
+pub fn lookup(name: &str) -> Result<ExternRef, ServiceError> {
+    check_name(name)?;
+    match SERVICES.lock().get(name) {
+        Some(handle) if handle.is_valid() => Ok(*handle),
+        _ => Err(ServiceError::NotFound),
+    }
+}
+
+fn check_name(name: &str) -> Result<(), ServiceError> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !name.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(ServiceError::InvalidName);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn permission_patterns() {
+        let permissions = ServicePermissions::new(
+            vec![String::from("vga"), String::from("drivers/*")],
+            vec![String::from("*")],
+        );
+        assert!(permissions.can_register("vga"));
+        assert!(permissions.can_register("drivers/net"));
+        assert!(!permissions.can_register("vga2"));
+        assert!(!permissions.can_register("drivers"));
+        assert!(permissions.can_lookup("anything"));
+
+        let inherited = permissions.inherited();
+        assert!(!inherited.can_register("vga"));
+        assert!(inherited.can_lookup("vga"));
+        assert!(!ServicePermissions::none().can_lookup("vga"));
+    }
+
+    #[test_case]
+    fn service_names() {
+        assert_eq!(check_name("vga"), Ok(()));
+        assert_eq!(check_name("drivers/net-0"), Ok(()));
+        assert_eq!(check_name(""), Err(ServiceError::InvalidName));
+        assert_eq!(check_name("two words"), Err(ServiceError::InvalidName));
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        assert_eq!(check_name(&long), Err(ServiceError::InvalidName));
+        assert_eq!(lookup("selftest/missing"), Err(ServiceError::NotFound));
+    }
+}
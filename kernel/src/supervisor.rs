@@ -0,0 +1,236 @@
+//! Init Supervision
+//!
+//! The kernel supervises the `init` function of the boot component: its exit status is logged, and
+//! failed executions are restarted according to a restart policy. Once the policy gives up, the
+//! kernel drops into an emergency console over the serial interface.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::scheduler::{self, Task};
+use crate::wasm::{Component, ComponentFunc};
+use crate::{kprint, kprintln, power, profiler, serial};
+use wasm::{Args, ExitStatus};
+
+/// Default number of restarts before giving up.
+pub const DEFAULT_MAX_RESTARTS: u32 = 3;
+/// Default delay before restarting init, in milliseconds.
+pub const DEFAULT_RESTART_DELAY_MS: u64 = 500;
+
+/// Delay between two polls of the serial interface by the emergency console, in milliseconds.
+const CONSOLE_POLL_MS: u64 = 50;
+/// Maximum length of a command of the emergency console.
+const CONSOLE_MAX_LINE: usize = 64;
+
+/// What to do when init fails.
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Number of consecutive restarts before dropping into the emergency console.
+    pub max_restarts: u32,
+    /// Delay before each restart, in milliseconds.
+    pub delay_ms: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            delay_ms: DEFAULT_RESTART_DELAY_MS,
+        }
+    }
+}
+
+/// Runs the init function of a component, and restarts it when it fails.
+///
+/// A restart calls init again within the same instance, the instance itself is not re-created.
+pub struct Supervisor {
+    component: Arc<Component>,
+    init: ComponentFunc,
+    policy: RestartPolicy,
+}
+
+impl Supervisor {
+    pub fn new(component: Arc<Component>, init: ComponentFunc, policy: RestartPolicy) -> Self {
+        Self {
+            component,
+            init,
+            policy,
+        }
+    }
+
+    /// Creates a task supervising init, which completes once init succeeds.
+    pub fn run(self) -> Task {
+        Task::new(self.supervise())
+    }
+
+    async fn supervise(self) {
+        let mut restarts = 0;
+        loop {
+            let status = Arc::clone(&self.component)
+                .run_promise(self.init, Args::new())
+                .await;
+            if !is_failure(&status) {
+                kprintln!("Init exited: {:?}", status);
+                return;
+            }
+
+            kprintln!("Init failed: {:?}", status);
+            if restarts < self.policy.max_restarts {
+                restarts += 1;
+                kprintln!(
+                    "Restarting init ({}/{})",
+                    restarts,
+                    self.policy.max_restarts
+                );
+                scheduler::sleep_ms(self.policy.delay_ms).await;
+            } else {
+                kprintln!("Init failed {} times, giving up", restarts + 1);
+                emergency_console(&status).await;
+                restarts = 0;
+            }
+        }
+    }
+}
+
+/// Returns true if init failed: it trapped, was killed, or returned a negative value.
+///
+/// The first returned value, if any, is interpreted as a 32 bits signed integer.
+pub fn is_failure(status: &ExitStatus) -> bool {
+    match status {
+        ExitStatus::Returned(values) => matches!(values.first(), Some(v) if (*v as i32) < 0),
+        ExitStatus::Trapped { .. } | ExitStatus::Killed => true,
+    }
+}
+
+// ——————————————————————————— Emergency Console ———————————————————————————— //
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Help,
+    Status,
+    Profile,
+    ProfileStart,
+    ProfileStop,
+    Restart,
+    Reboot,
+    Shutdown,
+}
+
+impl Command {
+    fn parse(line: &[u8]) -> Option<Self> {
+        match trim(line) {
+            b"help" => Some(Command::Help),
+            b"status" => Some(Command::Status),
+            b"profile" => Some(Command::Profile),
+            b"profile start" => Some(Command::ProfileStart),
+            b"profile stop" => Some(Command::ProfileStop),
+            b"restart" => Some(Command::Restart),
+            b"reboot" => Some(Command::Reboot),
+            b"shutdown" => Some(Command::Shutdown),
+            _ => None,
+        }
+    }
+}
+
+/// Removes the leading and trailing ASCII whitespaces.
+fn trim(line: &[u8]) -> &[u8] {
+    let start = line
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |idx| idx + 1);
+    &line[start..end]
+}
+
+/// Reads commands from the serial interface, returns once a restart is requested.
+///
+/// The serial interface is polled, so that the other tasks keep running in the meantime.
+async fn emergency_console(status: &ExitStatus) {
+    kprintln!("Emergency console, type 'help' for the list of commands");
+    kprint!("> ");
+
+    let mut line = Vec::with_capacity(CONSOLE_MAX_LINE);
+    loop {
+        let byte = match serial::try_read() {
+            Some(byte) => byte,
+            None => {
+                scheduler::sleep_ms(CONSOLE_POLL_MS).await;
+                continue;
+            }
+        };
+
+        match byte {
+            b'\r' | b'\n' => {
+                kprint!("\n");
+                match Command::parse(&line) {
+                    Some(Command::Help) => {
+                        kprintln!("help      print this message");
+                        kprintln!("status    print the exit status of init");
+                        kprintln!("profile   print the samples of the profiler");
+                        kprintln!("profile start|stop");
+                        kprintln!("          start (and reset) or stop the profiler");
+                        kprintln!("restart   restart init");
+                        kprintln!("reboot    reboot the machine");
+                        kprintln!("shutdown  power off the machine");
+                    }
+                    Some(Command::Status) => kprintln!("{:?}", status),
+                    Some(Command::Profile) => profiler::for_each_line(|line| {
+                        kprintln!("{}", line);
+                        Ok(())
+                    }),
+                    Some(Command::ProfileStart) => profiler::set_enabled(true),
+                    Some(Command::ProfileStop) => profiler::set_enabled(false),
+                    Some(Command::Restart) => return,
+                    Some(Command::Reboot) => power::reboot(),
+                    Some(Command::Shutdown) => power::shutdown(),
+                    None if trim(&line).is_empty() => {}
+                    None => kprintln!("Unknown command"),
+                }
+                line.clear();
+                kprint!("> ");
+            }
+            // Backspace and delete
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    kprint!("\x08 \x08");
+                }
+            }
+            byte if byte.is_ascii_graphic() || byte == b' ' => {
+                if line.len() < CONSOLE_MAX_LINE {
+                    line.push(byte);
+                    kprint!("{}", byte as char);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn init_failures() {
+        assert!(!is_failure(&ExitStatus::Returned(vec![])));
+        assert!(!is_failure(&ExitStatus::Returned(vec![42])));
+        assert!(is_failure(&ExitStatus::Returned(vec![
+            (-1i32) as u32 as u64
+        ])));
+        assert!(is_failure(&ExitStatus::Killed));
+    }
+
+    #[test_case]
+    fn parse_commands() {
+        assert_eq!(Command::parse(b"status"), Some(Command::Status));
+        assert_eq!(Command::parse(b"  restart "), Some(Command::Restart));
+        assert_eq!(Command::parse(b"profile"), Some(Command::Profile));
+        assert_eq!(Command::parse(b"profile stop"), Some(Command::ProfileStop));
+        assert_eq!(Command::parse(b"restart now"), None);
+        assert_eq!(Command::parse(b""), None);
+    }
+}
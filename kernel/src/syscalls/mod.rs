@@ -1,35 +1,161 @@
 //! Coral System Calls
 //!
 //! System Calls in Coral are provided as a native module, that can be linked to any Wasm module.
+//!
+//! Most system calls allocate from the kernel heap, e.g. to create kernel objects or to compile
+//! modules. The simplest ones are alloc-free fast paths, which run in an alloc-free context (see
+//! `allocator::alloc_free`): `handle_kind`, `vma_size`, `vma_state` and `vma_write`. Those must
+//! not allocate on any path, including the error paths: `kprintln` formats straight into the
+//! serial port without allocating. Writes to the VMA of a presented surface refresh the screen,
+//! which is allocation free as well (see `compositor::damage`).
+
+pub mod trace;
 
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::mem;
+use core::fmt::Write;
 
-use crate::memory::Vma;
-use crate::runtime::compile;
+use crate::boot::SYSCALL_MODULE;
+use crate::compositor::{self, Geometry, Surface, SurfaceError};
+use crate::events::{self, Encoding, MODULE_DISPATCHER, POINTER_DISPATCHER, VMA_DISPATCHER};
+use crate::fiber::Suspend;
+use crate::memory::{Blob, Vma, VmaState, VmaStateError};
+use crate::profiler;
+use crate::runtime::compilation::{self, KernelBundle, KernelModule, ModuleStatus, Source};
 use crate::runtime::{
-    ComponentIndex, KoIndex, ModuleIndex, VmaIndex, ACTIVE_COMPONENTS, ACTIVE_MODULES, ACTIVE_VMA,
+    get_runtime, BlobIndex, BundleIndex, ComponentIndex, KoIndex, ModuleIndex, SurfaceIndex,
+    VmaIndex, ACTIVE_BLOBS, ACTIVE_BUNDLES, ACTIVE_COMPONENTS, ACTIVE_MODULES, ACTIVE_SURFACES,
+    ACTIVE_VMA,
+};
+use crate::sched_trace;
+use crate::selftest;
+use crate::services::{self, ServiceError, ServicePermissions};
+use crate::traced_syscall;
+use crate::wasm::{
+    suspend_execution, with_caller_memory, with_current_component, BundleMember, Component,
+    InstanceIndex,
+};
+use crate::{allocator, interrupts, scheduler};
+use wasm::{
+    as_native_func, ExitStatus, ExternHandle, ExternRef64, InstanceMetrics, ModuleError,
+    NativeFunc, NativeModule, NativeModuleBuilder, ValueType, WasmModule, WasmParams, WasmResults,
+    WasmType,
 };
-use crate::wasm::Component;
-use wasm::{as_native_func, ExternRef64, NativeModule, NativeModuleBuilder, WasmModule, WasmType};
 
 // ————————————————————————————— Native Module —————————————————————————————— //
 
+/// Capacity of the handle table of each instance of the syscall module.
+pub const HANDLES_CAPACITY: u32 = 16;
+
 /// Build a native module exposing all the Coral system calls.
-pub fn build_syscall_module(handles_table: Vec<ExternRef>) -> NativeModule {
+///
+/// Each instance of the module gets its own `handles` table, which must be populated through the
+/// instance before being used.
+pub fn build_syscall_module() -> NativeModule {
+    build_restricted_syscall_module(|_| true)
+}
+
+/// Build a native module exposing the Coral system calls accepted by `filter`, given their name.
+///
+/// This is how programs are granted a subset of the system calls: a restricted module is linked in
+/// place of the full one, the other system calls can not even be imported.
+pub fn build_restricted_syscall_module<F>(filter: F) -> NativeModule
+where
+    F: Fn(&str) -> bool,
+{
     unsafe {
-        NativeModuleBuilder::new()
-            .add_func(String::from("handle_kind"), &HANDLE_KIND)
-            .add_func(String::from("vma_write"), &VMA_WRITE)
-            .add_func(String::from("module_create"), &MODULE_CREATE)
-            .add_func(String::from("component_create"), &COMPONENT_CREATE)
-            .add_func(
-                String::from("component_add_instance"),
-                &COMPONENT_ADD_INSTANCE,
-            )
-            .add_table(String::from("handles"), handles_table)
+        SyscallModuleBuilder::new(filter)
+            .add_func("handle_kind", &HANDLE_KIND)
+            .add_func("handle_derive", &HANDLE_DERIVE)
+            .add_func("handle_revoke", &HANDLE_REVOKE)
+            .add_func("handle_wait", &HANDLE_WAIT)
+            .add_func("vma_write", &VMA_WRITE)
+            .add_func("vma_read", &VMA_READ)
+            .add_func("vma_size", &VMA_SIZE)
+            .add_func("vma_seal", &VMA_SEAL)
+            .add_func("vma_state", &VMA_STATE)
+            .add_func("vma_watch", &VMA_WATCH)
+            .add_func("vma_register", &VMA_REGISTER)
+            .add_func("blob_from_vma", &BLOB_FROM_VMA)
+            .add_func("surface_create", &SURFACE_CREATE)
+            .add_func("surface_vma", &SURFACE_VMA)
+            .add_func("surface_resize", &SURFACE_RESIZE)
+            .add_func("surface_move", &SURFACE_MOVE)
+            .add_func("surface_present", &SURFACE_PRESENT)
+            .add_func("module_create", &MODULE_CREATE)
+            .add_func("module_status", &MODULE_STATUS)
+            .add_func("module_info", &MODULE_INFO)
+            .add_func("module_register", &MODULE_REGISTER)
+            .add_func("bundle_create", &BUNDLE_CREATE)
+            .add_func("bundle_status", &BUNDLE_STATUS)
+            .add_func("component_create", &COMPONENT_CREATE)
+            .add_func("component_add_instance", &COMPONENT_ADD_INSTANCE)
+            .add_func("component_add_bundle", &COMPONENT_ADD_BUNDLE)
+            .add_func("component_spawn", &COMPONENT_SPAWN)
+            .add_func("component_add_native_module", &COMPONENT_ADD_NATIVE_MODULE)
+            .add_func("component_push_import", &COMPONENT_PUSH_IMPORT)
+            .add_func("component_trace", &COMPONENT_TRACE)
+            .add_func("instance_fork", &INSTANCE_FORK)
+            .add_func("component_reload_instance", &COMPONENT_RELOAD_INSTANCE)
+            .add_func("component_exit_status", &COMPONENT_EXIT_STATUS)
+            .add_func("component_start_status", &COMPONENT_START_STATUS)
+            .add_func("component_metrics", &COMPONENT_METRICS)
+            .add_func("pointer_register", &POINTER_REGISTER)
+            .add_func("env_set", &ENV_SET)
+            .add_func("env_get", &ENV_GET)
+            .add_func("env_list", &ENV_LIST)
+            .add_func("service_register", &SERVICE_REGISTER)
+            .add_func("service_lookup", &SERVICE_LOOKUP)
+            .add_func("task_yield", &TASK_YIELD)
+            .add_func("task_sleep_ms", &TASK_SLEEP_MS)
+            .add_func("trace_read", &TRACE_READ)
+            .add_func("profile_enable", &PROFILE_ENABLE)
+            .add_func("profile_read", &PROFILE_READ)
+            .add_func("sched_trace_enable", &SCHED_TRACE_ENABLE)
+            .add_func("sched_trace_read", &SCHED_TRACE_READ)
+            .add_func("selftest_run", &SELFTEST_RUN)
+            .add_func("system_stats", &SYSTEM_STATS)
+            .add_func("system_shutdown", &SYSTEM_SHUTDOWN)
+            .add_func("system_reboot", &SYSTEM_REBOOT)
+            .build()
+    }
+}
+
+/// A native module builder which skips the system calls rejected by its filter.
+struct SyscallModuleBuilder<F> {
+    builder: NativeModuleBuilder,
+    filter: F,
+}
+
+impl<F> SyscallModuleBuilder<F>
+where
+    F: Fn(&str) -> bool,
+{
+    fn new(filter: F) -> Self {
+        Self {
+            builder: NativeModuleBuilder::new(),
+            filter,
+        }
+    }
+
+    /// Adds a system call, if accepted by the filter.
+    ///
+    /// SAFETY: see `NativeModuleBuilder::add_func`.
+    unsafe fn add_func<P, R>(mut self, name: &str, func: &NativeFunc<P, R>) -> Self
+    where
+        P: WasmParams,
+        R: WasmResults,
+    {
+        if (self.filter)(name) {
+            self.builder = self.builder.add_func(String::from(name), func);
+        }
+        self
+    }
+
+    fn build(self) -> NativeModule {
+        self.builder
+            .add_handle_table(String::from("handles"), HANDLES_CAPACITY)
             .build()
     }
 }
@@ -37,266 +163,2349 @@ pub fn build_syscall_module(handles_table: Vec<ExternRef>) -> NativeModule {
 // ————————————————————————————————— Types —————————————————————————————————— //
 
 /// A WebAssembly externref.
-#[repr(u8)]
+///
+/// References are exposed to WebAssembly as tagged handles (see `wasm::ExternHandle`), whose tag
+/// is the kind of the handle and whose index is the index of the object within its collection.
 #[derive(Debug, Clone, Copy)]
 pub enum ExternRef {
     /// An invalid handle.
     Invalid,
     /// A virtual memory area.
     Vma(VmaIndex),
+    /// An immutable blob of bytes.
+    Blob(BlobIndex),
     /// A WebAssembly module.
     Module(ModuleIndex),
     /// A component.
     Component(ComponentIndex),
+    /// The capability to shutdown or reboot the system.
+    Power,
+    /// The capability to read the syscall trace.
+    Trace,
+    /// A bundle of WebAssembly modules.
+    Bundle(BundleIndex),
+    /// A surface of the compositor.
+    Surface(SurfaceIndex),
 }
 
-/// This value is used to assert a compile time that ExternRef is 8 bytes long.
-#[doc(hidden)]
-const _EXTERNREF_SIZE_ASSERT: [u8; 8] = [0; mem::size_of::<ExternRef>()];
+impl ExternRef {
+    /// Encodes the reference as a tagged handle, the invalid reference is the null handle.
+    pub fn into_handle(self) -> ExternHandle {
+        let (kind, index, generation) = match self {
+            ExternRef::Invalid => return ExternHandle::NULL,
+            ExternRef::Vma(idx) => (HandleKind::Vma, idx.into_usize(), idx.generation()),
+            ExternRef::Blob(idx) => (HandleKind::Blob, idx.into_usize(), idx.generation()),
+            ExternRef::Module(idx) => (HandleKind::Module, idx.into_usize(), idx.generation()),
+            ExternRef::Component(idx) => {
+                (HandleKind::Component, idx.into_usize(), idx.generation())
+            }
+            ExternRef::Power => (HandleKind::Power, 0, 0),
+            ExternRef::Trace => (HandleKind::Trace, 0, 0),
+            ExternRef::Bundle(idx) => (HandleKind::Bundle, idx.into_usize(), idx.generation()),
+            ExternRef::Surface(idx) => (HandleKind::Surface, idx.into_usize(), idx.generation()),
+        };
+        ExternHandle::new(kind.into_abi() as u8, index as u32, generation)
+            .expect("Invalid handle kind")
+    }
+
+    /// Returns true if the reference refers to an object, i.e. it has not been revoked.
+    pub fn is_valid(self) -> bool {
+        match self {
+            ExternRef::Invalid => false,
+            ExternRef::Vma(idx) => ACTIVE_VMA.get(idx).is_some(),
+            ExternRef::Blob(idx) => ACTIVE_BLOBS.get(idx).is_some(),
+            ExternRef::Module(idx) => ACTIVE_MODULES.get(idx).is_some(),
+            ExternRef::Component(idx) => ACTIVE_COMPONENTS.get(idx).is_some(),
+            ExternRef::Power => true,
+            ExternRef::Trace => true,
+            ExternRef::Bundle(idx) => ACTIVE_BUNDLES.get(idx).is_some(),
+            ExternRef::Surface(idx) => ACTIVE_SURFACES.get(idx).is_some(),
+        }
+    }
+
+    /// Decodes a tagged handle, unknown tags decode as the invalid reference.
+    pub fn from_handle(handle: ExternHandle) -> Self {
+        let index = handle.index() as usize;
+        let generation = handle.generation();
+        match HandleKind::from_abi(handle.tag() as u32) {
+            HandleKind::Invalid => ExternRef::Invalid,
+            HandleKind::Vma => ExternRef::Vma(KoIndex::new(index, generation)),
+            HandleKind::Blob => ExternRef::Blob(KoIndex::new(index, generation)),
+            HandleKind::Module => ExternRef::Module(KoIndex::new(index, generation)),
+            HandleKind::Component => ExternRef::Component(KoIndex::new(index, generation)),
+            HandleKind::Power => ExternRef::Power,
+            HandleKind::Trace => ExternRef::Trace,
+            HandleKind::Bundle => ExternRef::Bundle(KoIndex::new(index, generation)),
+            HandleKind::Surface => ExternRef::Surface(KoIndex::new(index, generation)),
+        }
+    }
+}
 
 unsafe impl WasmType for ExternRef {
     type Abi = ExternRef64;
 
     fn into_abi(self) -> u64 {
-        // SAFETY: All valid ExternRef are valid u64.
-        unsafe { mem::transmute(self) }
+        self.into_handle().bits()
     }
 
     fn from_abi(val: u64) -> Self {
-        // SAFETY: as WebAssembly can not modifie or create new reference values, all the values
-        // received here are emitted by `Self::into_abi`.
-        unsafe { mem::transmute(val) }
+        Self::from_handle(ExternHandle::from_abi(val))
     }
 }
 
 // —————————————————————————————— Return Types —————————————————————————————— //
 
-#[derive(Clone, Copy)]
-#[repr(i32)]
-pub enum SyscallResult {
-    Success = 0,
-    InvalidParams = 1,
-    InternalError = 2,
-    UnknownError = 3,
+/// The domain of a syscall error.
+///
+/// Syscalls return an i64 status, which is 0 on success. Errors hold their domain in the upper
+/// 32 bits and a code specific to that domain in the lower 32 bits. Codes are never re-assigned,
+/// new errors get new codes or new domains.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ErrorDomain {
+    General = 0,
+    Handle = 1,
+    Memory = 2,
+    Vma = 3,
+    Module = 4,
+    Component = 5,
+    Env = 6,
+    Task = 7,
+    Service = 8,
+}
+
+/// Encodes an error status from its domain and code.
+const fn error(domain: ErrorDomain, code: u32) -> i64 {
+    ((domain as i64) << 32) | code as i64
+}
+
+wasm::wasm_type! {
+    /// The status returned by syscalls, see `ErrorDomain` for its encoding.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum SyscallResult: i64 {
+        Success = 0,
+        /// The kernel failed for reasons unrelated to the parameters.
+        InternalError = error(ErrorDomain::General, 1),
+        UnknownError = error(ErrorDomain::General, 2),
+        /// The requested service is not available on this system.
+        Unavailable = error(ErrorDomain::General, 3),
+        /// The handle is not of the expected kind.
+        WrongHandleKind = error(ErrorDomain::Handle, 1),
+        /// The handle does not refer to an existing object.
+        InvalidHandle = error(ErrorDomain::Handle, 2),
+        /// The range is out of the bounds of the buffer.
+        OutOfBounds = error(ErrorDomain::Memory, 1),
+        /// The caller has no linear memory.
+        NoMemory = error(ErrorDomain::Memory, 2),
+        /// The kernel is running low on memory.
+        OutOfMemory = error(ErrorDomain::Memory, 3),
+        VmaBorrowed = error(ErrorDomain::Vma, 1),
+        VmaSealed = error(ErrorDomain::Vma, 2),
+        VmaNotSealed = error(ErrorDomain::Vma, 3),
+        /// The module is still waiting for its compilation.
+        ModuleNotReady = error(ErrorDomain::Module, 1),
+        /// The module could not be parsed or compiled.
+        CompilationFailed = error(ErrorDomain::Module, 2),
+        /// The module uses CPU features which are not supported by this machine.
+        UnsupportedFeatures = error(ErrorDomain::Module, 3),
+        /// The imports of the module do not match the items provided to it.
+        LinkError = error(ErrorDomain::Module, 4),
+        /// The component can not allocate the resources of the instance.
+        QuotaExceeded = error(ErrorDomain::Module, 5),
+        /// The start function of the module trapped.
+        StartTrapped = error(ErrorDomain::Module, 6),
+        InstantiationFailed = error(ErrorDomain::Module, 7),
+        /// The bundle is malformed.
+        InvalidBundle = error(ErrorDomain::Module, 8),
+        /// The instance does not export the expected function.
+        MissingExport = error(ErrorDomain::Component, 1),
+        /// The exported function does not have the expected signature.
+        ExportTypeMismatch = error(ErrorDomain::Component, 2),
+        /// The instance does not exist within the component.
+        InvalidInstance = error(ErrorDomain::Component, 3),
+        /// The environment variable does not exist.
+        EnvNotFound = error(ErrorDomain::Env, 1),
+        /// The key or value of the environment variable is invalid.
+        InvalidEnv = error(ErrorDomain::Env, 2),
+        /// The caller can not be suspended.
+        NotSuspendable = error(ErrorDomain::Task, 1),
+        /// None of the awaited objects became ready before the timeout.
+        TimedOut = error(ErrorDomain::Task, 2),
+        /// No service is registered under that name.
+        ServiceNotFound = error(ErrorDomain::Service, 1),
+        /// A service is already registered under that name.
+        ServiceExists = error(ErrorDomain::Service, 2),
+        /// The service name is invalid, see `services::MAX_NAME_LEN`.
+        InvalidServiceName = error(ErrorDomain::Service, 3),
+        /// The component is not allowed to register or lookup that service.
+        ServiceDenied = error(ErrorDomain::Service, 4),
+    } else UnknownError
 }
 
-unsafe impl WasmType for SyscallResult {
-    type Abi = i32;
+impl SyscallResult {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SyscallResult::Success => "Success",
+            SyscallResult::InternalError => "InternalError",
+            SyscallResult::UnknownError => "UnknownError",
+            SyscallResult::Unavailable => "Unavailable",
+            SyscallResult::WrongHandleKind => "WrongHandleKind",
+            SyscallResult::InvalidHandle => "InvalidHandle",
+            SyscallResult::OutOfBounds => "OutOfBounds",
+            SyscallResult::NoMemory => "NoMemory",
+            SyscallResult::OutOfMemory => "OutOfMemory",
+            SyscallResult::VmaBorrowed => "VmaBorrowed",
+            SyscallResult::VmaSealed => "VmaSealed",
+            SyscallResult::VmaNotSealed => "VmaNotSealed",
+            SyscallResult::ModuleNotReady => "ModuleNotReady",
+            SyscallResult::CompilationFailed => "CompilationFailed",
+            SyscallResult::UnsupportedFeatures => "UnsupportedFeatures",
+            SyscallResult::LinkError => "LinkError",
+            SyscallResult::QuotaExceeded => "QuotaExceeded",
+            SyscallResult::StartTrapped => "StartTrapped",
+            SyscallResult::InstantiationFailed => "InstantiationFailed",
+            SyscallResult::InvalidBundle => "InvalidBundle",
+            SyscallResult::MissingExport => "MissingExport",
+            SyscallResult::ExportTypeMismatch => "ExportTypeMismatch",
+            SyscallResult::InvalidInstance => "InvalidInstance",
+            SyscallResult::EnvNotFound => "EnvNotFound",
+            SyscallResult::InvalidEnv => "InvalidEnv",
+            SyscallResult::NotSuspendable => "NotSuspendable",
+            SyscallResult::TimedOut => "TimedOut",
+            SyscallResult::ServiceNotFound => "ServiceNotFound",
+            SyscallResult::ServiceExists => "ServiceExists",
+            SyscallResult::InvalidServiceName => "InvalidServiceName",
+            SyscallResult::ServiceDenied => "ServiceDenied",
+        }
+    }
 
-    fn into_abi(self) -> Self::Abi {
-        self as i32
+    /// Returns the domain of the status, `ErrorDomain::General` on success.
+    pub fn domain(self) -> ErrorDomain {
+        match (self as i64 >> 32) as u32 {
+            1 => ErrorDomain::Handle,
+            2 => ErrorDomain::Memory,
+            3 => ErrorDomain::Vma,
+            4 => ErrorDomain::Module,
+            5 => ErrorDomain::Component,
+            6 => ErrorDomain::Env,
+            7 => ErrorDomain::Task,
+            8 => ErrorDomain::Service,
+            _ => ErrorDomain::General,
+        }
     }
 
-    fn from_abi(_val: Self::Abi) -> Self {
-        // Do we need that conversion? Userspace has no reason to send errors
-        todo!();
+    /// Returns the code of the status within its domain.
+    pub fn code(self) -> u32 {
+        self as i64 as u32
     }
 }
 
-#[derive(Clone, Copy)]
-#[repr(u32)]
-pub enum HandleKind {
-    Invalid = 0,
-    Vma = 1,
-    Module = 2,
-    Component = 3,
+impl From<ModuleError> for SyscallResult {
+    fn from(err: ModuleError) -> Self {
+        match err {
+            ModuleError::TypeError => SyscallResult::LinkError,
+            ModuleError::QuotaExceeded => SyscallResult::QuotaExceeded,
+            ModuleError::StartTrapped => SyscallResult::StartTrapped,
+            ModuleError::MissingCpuFeatures(_) => SyscallResult::UnsupportedFeatures,
+            ModuleError::OutOfMemory => SyscallResult::OutOfMemory,
+            ModuleError::FailedToInstantiate
+            | ModuleError::RuntimeError
+            | ModuleError::DataSegmentOutOfBounds
+            | ModuleError::ImportedTableSegment
+            | ModuleError::Unsupported => SyscallResult::InstantiationFailed,
+        }
+    }
 }
 
-unsafe impl WasmType for HandleKind {
-    type Abi = u32;
-
-    fn into_abi(self) -> <Self::Abi as wasm::WasmBaseType>::Abi {
-        self as u32
-    }
+wasm::wasm_type! {
+    pub enum HandleKind: u32 {
+        Invalid = 0,
+        Vma = 1,
+        Module = 2,
+        Component = 3,
+        Power = 4,
+        Blob = 5,
+        Bundle = 6,
+        Surface = 7,
+        Trace = 8,
+    } else Invalid
+}
 
-    fn from_abi(_val: <Self::Abi as wasm::WasmBaseType>::Abi) -> Self {
-        // We should never need that conversion
-        todo!();
+impl HandleKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HandleKind::Invalid => "invalid",
+            HandleKind::Vma => "vma",
+            HandleKind::Module => "module",
+            HandleKind::Component => "component",
+            HandleKind::Power => "power",
+            HandleKind::Blob => "blob",
+            HandleKind::Bundle => "bundle",
+            HandleKind::Surface => "surface",
+            HandleKind::Trace => "trace",
+        }
     }
 }
 
 // —————————————————————————————— System Calls —————————————————————————————— //
 
-as_native_func!(handle_kind; HANDLE_KIND; args: ExternRef; ret: HandleKind);
+as_native_func!(traced_handle_kind; HANDLE_KIND; args: ExternRef; ret: HandleKind);
+traced_syscall!(handle_kind => traced_handle_kind(handle: ExternRef) -> HandleKind);
 fn handle_kind(handle: ExternRef) -> HandleKind {
-    match handle {
+    allocator::alloc_free(|| match handle {
         ExternRef::Invalid => HandleKind::Invalid,
         ExternRef::Vma(_) => HandleKind::Vma,
+        ExternRef::Blob(_) => HandleKind::Blob,
         ExternRef::Module(_) => HandleKind::Module,
         ExternRef::Component(_) => HandleKind::Component,
+        ExternRef::Power => HandleKind::Power,
+        ExternRef::Trace => HandleKind::Trace,
+        ExternRef::Bundle(_) => HandleKind::Bundle,
+        ExternRef::Surface(_) => HandleKind::Surface,
+    })
+}
+
+as_native_func!(
+    traced_handle_derive;
+    HANDLE_DERIVE;
+    args: ExternRef;
+    ret: (SyscallResult, ExternRef)
+);
+traced_syscall!(
+    handle_derive => traced_handle_derive(handle: ExternRef) -> (SyscallResult, ExternRef)
+);
+/// Derives a new handle to the object referred to by a handle, which can be shared and later
+/// revoked with `handle_revoke` without affecting the original handle.
+fn handle_derive(handle: ExternRef) -> (SyscallResult, ExternRef) {
+    let derived = match handle {
+        ExternRef::Vma(idx) => ACTIVE_VMA.derive(idx).map(KoIndex::into_externref),
+        ExternRef::Blob(idx) => ACTIVE_BLOBS.derive(idx).map(KoIndex::into_externref),
+        ExternRef::Module(idx) => ACTIVE_MODULES.derive(idx).map(KoIndex::into_externref),
+        ExternRef::Component(idx) => ACTIVE_COMPONENTS.derive(idx).map(KoIndex::into_externref),
+        ExternRef::Bundle(idx) => ACTIVE_BUNDLES.derive(idx).map(KoIndex::into_externref),
+        ExternRef::Surface(idx) => ACTIVE_SURFACES.derive(idx).map(KoIndex::into_externref),
+        ExternRef::Invalid | ExternRef::Power | ExternRef::Trace => {
+            crate::kprintln!("Syscall Error: can not derive '{:?}'", handle);
+            return (SyscallResult::WrongHandleKind, ExternRef::Invalid);
+        }
+    };
+    match derived {
+        Some(derived) => (SyscallResult::Success, derived),
+        None => (SyscallResult::InvalidHandle, ExternRef::Invalid),
+    }
+}
+
+as_native_func!(traced_handle_revoke; HANDLE_REVOKE; args: ExternRef; ret: SyscallResult);
+traced_syscall!(handle_revoke => traced_handle_revoke(handle: ExternRef) -> SyscallResult);
+/// Revokes a handle: all the copies of the handle and all the handles derived from it become
+/// invalid, the handle it has been derived from, if any, is left untouched.
+fn handle_revoke(handle: ExternRef) -> SyscallResult {
+    let revoked = match handle {
+        ExternRef::Vma(idx) => ACTIVE_VMA.revoke(idx),
+        ExternRef::Blob(idx) => ACTIVE_BLOBS.revoke(idx),
+        ExternRef::Module(idx) => ACTIVE_MODULES.revoke(idx),
+        ExternRef::Component(idx) => ACTIVE_COMPONENTS.revoke(idx),
+        ExternRef::Bundle(idx) => ACTIVE_BUNDLES.revoke(idx),
+        ExternRef::Surface(idx) => ACTIVE_SURFACES.revoke(idx),
+        ExternRef::Invalid | ExternRef::Power | ExternRef::Trace => {
+            crate::kprintln!("Syscall Error: can not revoke '{:?}'", handle);
+            return SyscallResult::WrongHandleKind;
+        }
+    };
+    if revoked {
+        SyscallResult::Success
+    } else {
+        SyscallResult::InvalidHandle
+    }
+}
+
+as_native_func!(traced_handle_wait; HANDLE_WAIT; args: ExternRef u64; ret: (SyscallResult, u32));
+traced_syscall!(
+    handle_wait => traced_handle_wait(handles: ExternRef, timeout_ms: u64) -> (SyscallResult, u32)
+);
+/// Suspends the calling execution until one of the listed kernel objects is ready, and returns
+/// the index of that object within the list.
+///
+/// The list is read from a VMA holding consecutive handles, encoded as little-endian tagged
+/// handles (see `ExternRef::into_handle`) and terminated by the null handle or the end of the VMA.
+/// Modules and bundles are ready once their compilation completed, successfully or not, and
+/// components once no execution is in progress (a component waiting on itself is never ready).
+///
+/// Fails with `TimedOut` if no object is ready after `timeout_ms` milliseconds, a timeout of 0
+/// checks the objects without suspending the caller.
+fn handle_wait(handles: ExternRef, timeout_ms: u64) -> (SyscallResult, u32) {
+    let handles = match read_handle_list(handles) {
+        Ok(handles) => handles,
+        Err(err) => return (err, 0),
+    };
+
+    // Objects are checked again after each notification, in case a handle has been revoked
+    let deadline = scheduler::deadline_after_ms(timeout_ms);
+    loop {
+        for (idx, handle) in handles.iter().enumerate() {
+            match is_ready(*handle) {
+                Ok(true) => return (SyscallResult::Success, idx as u32),
+                Ok(false) => (),
+                Err(err) => return (err, idx as u32),
+            }
+        }
+        if scheduler::ticks() >= deadline {
+            return (SyscallResult::TimedOut, 0);
+        }
+        match suspend(Suspend::Wait(deadline)) {
+            SyscallResult::Success => (),
+            err => return (err, 0),
+        }
     }
 }
 
-as_native_func!(module_create; MODULE_CREATE; args: ExternRef u64 u64; ret: (SyscallResult, ExternRef));
+as_native_func!(traced_module_create; MODULE_CREATE; args: ExternRef u64 u64; ret: (SyscallResult, ExternRef));
+traced_syscall!(
+    module_create => traced_module_create(source: ExternRef, offset: u64, size: u64)
+        -> (SyscallResult, ExternRef)
+);
+/// Submits a module for compilation, from either a VMA or a blob.
+///
+/// The module is compiled asynchronously, its progress can be followed with `module_status` or
+/// through module events. Blobs are immutable, and are therefore compiled in place. VMAs must be
+/// sealed first, so that their content can not change during compilation.
 fn module_create(source: ExternRef, offset: u64, size: u64) -> (SyscallResult, ExternRef) {
-    let source_vma = match get_vma(source) {
-        Ok(vma) => vma,
+    let source = match get_source(source, offset, size) {
+        Ok(source) => source,
         Err(err) => return (err, ExternRef::Invalid),
     };
 
-    let source = match vma_as_buf(&source_vma, offset, size) {
-        Ok(buf) => buf,
-        Err(err) => return (err, ExternRef::Invalid),
+    // The range has been checked above
+    let handle = compilation::submit(source, offset as usize, size as usize).into_externref();
+    (SyscallResult::Success, handle)
+}
+
+as_native_func!(traced_module_status; MODULE_STATUS; args: ExternRef; ret: (SyscallResult, u32, u32));
+traced_syscall!(
+    module_status => traced_module_status(module: ExternRef) -> (SyscallResult, u32, u32)
+);
+/// Returns the compilation status of a module: 0 if pending, 1 if compiling, 2 if ready and 3 if
+/// the compilation failed, together with the percentage of compiled functions.
+fn module_status(module: ExternRef) -> (SyscallResult, u32, u32) {
+    let status = match get_kernel_module(module) {
+        Ok(module) => module.status(),
+        Err(err) => return (err, 0, 0),
+    };
+    (SyscallResult::Success, status.code(), status.percent())
+}
+
+as_native_func!(traced_module_info; MODULE_INFO; args: ExternRef u32 u32; ret: (SyscallResult, u64, u64));
+traced_syscall!(
+    module_info => traced_module_info(module: ExternRef, target: u32, target_len: u32)
+        -> (SyscallResult, u64, u64)
+);
+/// Writes the name of a compiled module into the caller memory, returns the hash of its bytecode
+/// and the size of the name. Anonymous modules have an empty name.
+///
+/// If the name does not fit into the target buffer nothing is written, the caller can retry with a
+/// large enough buffer.
+fn module_info(module: ExternRef, target: u32, target_len: u32) -> (SyscallResult, u64, u64) {
+    let module = match get_module(module) {
+        Ok(module) => module,
+        Err(err) => return (err, 0, 0),
     };
 
-    let module = match compile(&source) {
-        Ok(module) => Arc::new(module),
-        Err(_) => return (SyscallResult::InvalidParams, ExternRef::Invalid),
+    let metadata = module.metadata();
+    let name = metadata.name.as_deref().unwrap_or("").as_bytes();
+    let result = with_memory(|memory| {
+        let target = caller_slice_mut(memory, target, target_len)?;
+        if let Some(target) = target.get_mut(..name.len()) {
+            target.copy_from_slice(name);
+        }
+        Ok(name.len() as u64)
+    });
+    match result {
+        Ok(size) => (SyscallResult::Success, metadata.hash, size),
+        Err(err) => (err, 0, 0),
+    }
+}
+
+as_native_func!(
+    traced_bundle_create;
+    BUNDLE_CREATE;
+    args: ExternRef u64 u64;
+    ret: (SyscallResult, ExternRef)
+);
+traced_syscall!(
+    bundle_create => traced_bundle_create(source: ExternRef, offset: u64, size: u64)
+        -> (SyscallResult, ExternRef)
+);
+/// Submits the modules of a bundle for compilation, from either a VMA or a blob (see
+/// `module_create`). The bundle can be instantiated with `component_add_bundle` once all its
+/// modules are compiled.
+fn bundle_create(source: ExternRef, offset: u64, size: u64) -> (SyscallResult, ExternRef) {
+    let source = match get_source(source, offset, size) {
+        Ok(source) => source,
+        Err(err) => return (err, ExternRef::Invalid),
     };
 
-    let handle = ACTIVE_MODULES.insert(module).into_externref();
-    (SyscallResult::Success, handle)
+    // The range has been checked above
+    match compilation::submit_bundle(source, offset as usize, size as usize) {
+        Ok(bundle) => (SyscallResult::Success, bundle.into_externref()),
+        Err(err) => {
+            crate::kprintln!("Syscall Error: invalid bundle: {:?}", err);
+            (SyscallResult::InvalidBundle, ExternRef::Invalid)
+        }
+    }
+}
+
+as_native_func!(traced_bundle_status; BUNDLE_STATUS; args: ExternRef; ret: (SyscallResult, u32, u32));
+traced_syscall!(
+    bundle_status => traced_bundle_status(bundle: ExternRef) -> (SyscallResult, u32, u32)
+);
+/// Returns the compilation status of a bundle, with the same codes as `module_status`, together
+/// with the average percentage of compiled functions of its modules.
+fn bundle_status(bundle: ExternRef) -> (SyscallResult, u32, u32) {
+    let bundle = match get_bundle(bundle) {
+        Ok(bundle) => bundle,
+        Err(err) => return (err, 0, 0),
+    };
+    let status = bundle.status();
+    (SyscallResult::Success, status.code(), status.percent())
 }
 
-as_native_func!(component_create; COMPONENT_CREATE; ret: (SyscallResult, ExternRef));
+as_native_func!(traced_component_create; COMPONENT_CREATE; ret: (SyscallResult, ExternRef));
+traced_syscall!(component_create => traced_component_create() -> (SyscallResult, ExternRef));
+/// Creates a component, which inherits the environment and the service lookup permissions of the
+/// calling component.
 fn component_create() -> (SyscallResult, ExternRef) {
-    let component = Arc::new(Component::new());
+    let services = with_current_component(|parent| parent.services().inherited());
+    let component = Arc::new(Component::new().with_services(services.unwrap_or_default()));
+    with_current_component(|parent| component.inherit_env(parent));
     let handle = ACTIVE_COMPONENTS.insert(component).into_externref();
     (SyscallResult::Success, handle)
 }
 
 as_native_func!(
-    component_add_instance;
+    traced_component_add_instance;
     COMPONENT_ADD_INSTANCE;
     args: ExternRef ExternRef;
     ret: (SyscallResult, u32)
 );
+traced_syscall!(
+    component_add_instance => traced_component_add_instance(component: ExternRef, module: ExternRef)
+        -> (SyscallResult, u32)
+);
 fn component_add_instance(component: ExternRef, module: ExternRef) -> (SyscallResult, u32) {
     let component = match get_component(component) {
         Ok(component) => component,
         Err(err) => return (err, 0),
     };
 
-    let module = match get_module(module) {
+    let kernel_module = match get_kernel_module(module) {
+        Ok(module) => module,
+        Err(err) => return (err, 0),
+    };
+    let module = match compiled_module(&kernel_module) {
         Ok(module) => module,
         Err(err) => return (err, 0),
     };
 
-    match component.add_instance(module.as_ref()) {
+    match component.add_instance_from_image(module.as_ref(), kernel_module.image()) {
         Ok(idx) => (SyscallResult::Success, idx.as_u32()),
-        Err(_) => (SyscallResult::InvalidParams, 0),
+        Err(err) => (err.into(), 0),
     }
 }
 
-as_native_func!(vma_write; VMA_WRITE; args: ExternRef ExternRef u64 u64 u64; ret: SyscallResult);
-fn vma_write(
-    source: ExternRef,
-    target: ExternRef,
-    source_offset: u64,
-    target_offset: u64,
-    size: u64,
-) -> SyscallResult {
-    let source_vma = match get_vma(source) {
-        Ok(vma) => vma,
-        Err(err) => return err,
+as_native_func!(
+    traced_component_add_bundle;
+    COMPONENT_ADD_BUNDLE;
+    args: ExternRef ExternRef;
+    ret: (SyscallResult, u32)
+);
+traced_syscall!(
+    component_add_bundle => traced_component_add_bundle(component: ExternRef, bundle: ExternRef)
+        -> (SyscallResult, u32)
+);
+/// Adds the instances of a bundle to a component, in order, and returns the index of the first
+/// one. All the modules of the bundle must be compiled.
+///
+/// The imports between the modules of the bundle are bound as resolved by the bundle, the other
+/// imports are resolved against the imports of the component.
+fn component_add_bundle(component: ExternRef, bundle: ExternRef) -> (SyscallResult, u32) {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return (err, 0),
     };
-    let mut target_vma = match get_vma(target) {
-        Ok(vma) => vma,
-        Err(err) => return err,
+    let bundle = match get_bundle(bundle) {
+        Ok(bundle) => bundle,
+        Err(err) => return (err, 0),
     };
 
-    let source = match vma_as_buf(&source_vma, source_offset, size) {
-        Ok(buf) => buf,
-        Err(err) => return err,
-    };
-    let target = match vma_as_buf_mut(&mut target_vma, target_offset, size) {
-        Ok(buf) => buf,
-        Err(err) => return err,
+    let modules = match bundle
+        .modules()
+        .iter()
+        .map(|member| compiled_module(&member.module))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(modules) => modules,
+        Err(err) => return (err, 0),
     };
+    let members: Vec<BundleMember<'_, WasmModule>> = bundle
+        .modules()
+        .iter()
+        .zip(modules.iter())
+        .map(|(member, module)| BundleMember {
+            module: module.as_ref(),
+            image: member.module.image(),
+            links: &member.links,
+        })
+        .collect();
 
-    target.copy_from_slice(source);
-    SyscallResult::Success
+    match component.add_bundle(&members) {
+        Ok(idx) => (SyscallResult::Success, idx.as_u32()),
+        Err(err) => (err.into(), 0),
+    }
 }
 
-// ————————————————————————————————— Utils —————————————————————————————————— //
-
-/// Returns the component corresponding to the given handle, if any.
-fn get_component(handle: ExternRef) -> Result<Arc<Component>, SyscallResult> {
-    let component_idx = match handle {
-        ExternRef::Component(component) => component,
-        _ => {
-            crate::kprintln!("Syscall Error: expected component, got '{:?}'", handle);
-            return Err(SyscallResult::InvalidParams);
-        }
+as_native_func!(
+    traced_component_spawn;
+    COMPONENT_SPAWN;
+    args: ExternRef u32 u32;
+    ret: (SyscallResult, ExternRef)
+);
+traced_syscall!(
+    component_spawn => traced_component_spawn(module: ExternRef, imports: u32, imports_len: u32)
+        -> (SyscallResult, ExternRef)
+);
+/// Creates a component running a single instance of a module, and returns its handle.
+///
+/// The imports are given as a list of names in the caller memory, each followed by a null byte.
+/// Each name must be an import of the calling component, which is shared with the new component.
+/// The new component inherits the environment of the calling component, and the start function of
+/// the instance is executed before returning.
+///
+/// If the start function traps, `StartTrapped` is returned together with the handle of the (empty)
+/// component, so that the trap can be inspected with `component_start_status`.
+fn component_spawn(
+    module: ExternRef,
+    imports: u32,
+    imports_len: u32,
+) -> (SyscallResult, ExternRef) {
+    let kernel_module = match get_kernel_module(module) {
+        Ok(module) => module,
+        Err(err) => return (err, ExternRef::Invalid),
     };
-    match ACTIVE_COMPONENTS.get(component_idx) {
-        Some(component) => Ok(component),
-        None => {
-            crate::kprintln!("Syscall Error: component does not exists");
-            Err(SyscallResult::InvalidParams)
+    let module = match compiled_module(&kernel_module) {
+        Ok(module) => module,
+        Err(err) => return (err, ExternRef::Invalid),
+    };
+
+    let component = Component::new();
+    with_current_component(|parent| component.inherit_env(parent));
+    let result = with_memory(|memory| {
+        let imports = caller_slice(memory, imports, imports_len)?;
+        let names = imports
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty());
+        for name in names {
+            let shared = with_current_component(|parent| match core::str::from_utf8(name) {
+                Ok(name) => parent.share_import(name, &component),
+                Err(_) => false,
+            });
+            if shared != Some(true) {
+                crate::kprintln!("Syscall Error: the caller has no such import");
+                return Err(SyscallResult::LinkError);
+            }
         }
+        Ok(())
+    });
+    if let Err(err) = result {
+        return (err, ExternRef::Invalid);
     }
+
+    let result = match component.add_instance_from_image(module.as_ref(), kernel_module.image()) {
+        Ok(_) => SyscallResult::Success,
+        Err(ModuleError::StartTrapped) => SyscallResult::StartTrapped,
+        Err(err) => return (err.into(), ExternRef::Invalid),
+    };
+    let handle = ACTIVE_COMPONENTS
+        .insert(Arc::new(component))
+        .into_externref();
+    (result, handle)
 }
 
-/// Returns the module corresponding to the given handle, if any.
-fn get_module(handle: ExternRef) -> Result<Arc<WasmModule>, SyscallResult> {
-    let module_idx = match handle {
-        ExternRef::Module(module) => module,
-        _ => {
-            crate::kprintln!("Syscall Error: expected module , got '{:?}'", handle);
-            return Err(SyscallResult::InvalidParams);
+as_native_func!(
+    traced_component_add_native_module;
+    COMPONENT_ADD_NATIVE_MODULE;
+    args: ExternRef u32 u32;
+    ret: (SyscallResult, u32)
+);
+traced_syscall!(
+    component_add_native_module => traced_component_add_native_module(
+        component: ExternRef,
+        syscalls: u32,
+        syscalls_len: u32
+    ) -> (SyscallResult, u32)
+);
+/// Adds an instance of a syscall module restricted to a subset of the syscalls to a component, and
+/// returns its index. The instance is imported as `coral` by the future instantiations of the
+/// component, giving them access to these syscalls only.
+///
+/// The syscalls are given as a list of names in the caller memory, each followed by a null byte.
+/// The caller can only grant the syscalls it has access to, and the component must not import a
+/// syscall module already, otherwise `LinkError` is returned.
+fn component_add_native_module(
+    component: ExternRef,
+    syscalls: u32,
+    syscalls_len: u32,
+) -> (SyscallResult, u32) {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return (err, 0),
+    };
+    if component.has_import(SYSCALL_MODULE) {
+        crate::kprintln!("Syscall Error: the component already imports a syscall module");
+        return (SyscallResult::LinkError, 0);
+    }
+
+    let result = with_memory(|memory| {
+        let syscalls = caller_slice(memory, syscalls, syscalls_len)?;
+        let mut names = Vec::new();
+        for name in syscalls
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+        {
+            let granted = with_current_component(|parent| match core::str::from_utf8(name) {
+                Ok(name) if parent.import_exports_func(SYSCALL_MODULE, name) => {
+                    Some(String::from(name))
+                }
+                _ => None,
+            });
+            match granted.flatten() {
+                Some(name) => names.push(name),
+                None => {
+                    crate::kprintln!("Syscall Error: the caller has no such syscall");
+                    return Err(SyscallResult::LinkError);
+                }
+            }
         }
+        Ok(names)
+    });
+    let names = match result {
+        Ok(names) => names,
+        Err(err) => return (err, 0),
     };
-    match ACTIVE_MODULES.get(module_idx) {
-        Some(module) => Ok(module),
-        None => {
-            crate::kprintln!("Syscall Error: component does not exists");
-            Err(SyscallResult::InvalidParams)
+
+    let module = build_restricted_syscall_module(|syscall| names.iter().any(|n| n == syscall));
+    match component.add_instance(&module) {
+        Ok(idx) => {
+            component.push_import(String::from(SYSCALL_MODULE), idx);
+            (SyscallResult::Success, idx.as_u32())
         }
+        Err(err) => (err.into(), 0),
     }
 }
 
-/// Returns the VMA corresponding to the given handle, if any.
-fn get_vma(handle: ExternRef) -> Result<Arc<Vma>, SyscallResult> {
-    let vma_idx = match handle {
-        ExternRef::Vma(vma) => vma,
-        _ => {
-            crate::kprintln!("Syscall Error: expected VMA, got {:?}", handle);
-            return Err(SyscallResult::InvalidParams);
-        }
+as_native_func!(
+    traced_component_push_import;
+    COMPONENT_PUSH_IMPORT;
+    args: ExternRef u32 u32 u32;
+    ret: SyscallResult
+);
+traced_syscall!(
+    component_push_import => traced_component_push_import(
+        component: ExternRef,
+        instance: u32,
+        name: u32,
+        name_len: u32
+    ) -> SyscallResult
+);
+/// Exposes an instance of a component under a name, read from the caller memory, to the future
+/// instantiations of that component, which can then import it.
+///
+/// The `coral` name is reserved for the syscall modules (see `component_add_native_module`),
+/// `LinkError` is returned for that name and for names which are not valid UTF-8.
+fn component_push_import(
+    component: ExternRef,
+    instance: u32,
+    name: u32,
+    name_len: u32,
+) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
     };
-    match ACTIVE_VMA.get(vma_idx) {
-        Some(vma) => Ok(vma),
-        None => {
-            crate::kprintln!("Syscall Error: VMA does not exists");
-            Err(SyscallResult::InvalidParams)
+
+    let result = with_memory(|memory| {
+        let name = caller_slice(memory, name, name_len)?;
+        match core::str::from_utf8(name) {
+            Ok(name) if name != SYSCALL_MODULE => Ok(String::from(name)),
+            _ => {
+                crate::kprintln!("Syscall Error: invalid import name");
+                Err(SyscallResult::LinkError)
+            }
         }
+    });
+    let name = match result {
+        Ok(name) => name,
+        Err(err) => return err,
+    };
+
+    if component.push_import(name, InstanceIndex::from_u32(instance)) {
+        SyscallResult::Success
+    } else {
+        SyscallResult::InvalidInstance
     }
 }
 
-/// Returns a view of the given VMA at the given offset and with the given size.
-fn vma_as_buf(vma: &Vma, offset: u64, size: u64) -> Result<&[u8], SyscallResult> {
-    // TODO: handle permissions here
-    let offset = usize::try_from(offset).map_err(|_| SyscallResult::InvalidParams)?;
-    let size = usize::try_from(size).map_err(|_| SyscallResult::InvalidParams)?;
-    let end = match offset.checked_add(size) {
-        Some(end) => end,
-        None => return Err(SyscallResult::InvalidParams),
+as_native_func!(
+    traced_instance_fork;
+    INSTANCE_FORK;
+    args: ExternRef u32 ExternRef;
+    ret: (SyscallResult, u32)
+);
+traced_syscall!(
+    instance_fork => traced_instance_fork(component: ExternRef, instance: u32, target: ExternRef)
+        -> (SyscallResult, u32)
+);
+/// Forks an instance of a component into the target component, which can be the same component.
+///
+/// The heaps of the new instance are copy-on-write copies of those of the forked instance, while
+/// its globals are reset to their initial values. Returns the index of the new instance within the
+/// target component.
+fn instance_fork(component: ExternRef, instance: u32, target: ExternRef) -> (SyscallResult, u32) {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return (err, 0),
+    };
+    let target = match get_component(target) {
+        Ok(target) => target,
+        Err(err) => return (err, 0),
     };
 
-    let buf = vma.as_bytes();
-    if buf.len() < end {
-        Err(SyscallResult::InvalidParams)
-    } else {
-        Ok(&buf[offset..end])
+    match component.fork_instance(InstanceIndex::from_u32(instance), &target) {
+        Some(Ok(idx)) => (SyscallResult::Success, idx.as_u32()),
+        Some(Err(err)) => (err.into(), 0),
+        None => (SyscallResult::InvalidInstance, 0),
     }
 }
 
-/// Returns a mutable view of the given VMA at the given offset and with the given size.
-fn vma_as_buf_mut(vma: &mut Arc<Vma>, offset: u64, size: u64) -> Result<&mut [u8], SyscallResult> {
-    // TODO: handle permissions here
-    let offset = usize::try_from(offset).map_err(|_| SyscallResult::InvalidParams)?;
-    let size = usize::try_from(size).map_err(|_| SyscallResult::InvalidParams)?;
-    let end = match offset.checked_add(size) {
-        Some(end) => end,
-        None => return Err(SyscallResult::InvalidParams),
+as_native_func!(
+    traced_component_reload_instance;
+    COMPONENT_RELOAD_INSTANCE;
+    args: ExternRef u32 ExternRef;
+    ret: SyscallResult
+);
+traced_syscall!(
+    component_reload_instance => traced_component_reload_instance(
+        component: ExternRef,
+        instance: u32,
+        module: ExternRef
+    ) -> SyscallResult
+);
+/// Swaps a new instance of a module in place of an instance of a component, while keeping its
+/// index.
+///
+/// The module is compiled asynchronously as any other (see `module_create`), reloading fails with
+/// `ModuleNotReady` until its compilation completes. The new instance starts from a fresh state,
+/// and must export the same functions as the previous one (see `Component::reload_instance`).
+fn component_reload_instance(
+    component: ExternRef,
+    instance: u32,
+    module: ExternRef,
+) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
     };
 
-    // TODO: what are the safety conditions here?
-    let buf = unsafe { vma.unsafe_as_bytes_mut() };
-    if buf.len() < end {
-        Err(SyscallResult::InvalidParams)
-    } else {
-        Ok(&mut buf[offset..end])
+    let kernel_module = match get_kernel_module(module) {
+        Ok(module) => module,
+        Err(err) => return err,
+    };
+    let module = match compiled_module(&kernel_module) {
+        Ok(module) => module,
+        Err(err) => return err,
+    };
+
+    let idx = InstanceIndex::from_u32(instance);
+    match component.reload_instance(idx, module.as_ref(), kernel_module.image()) {
+        Some(Ok(())) => SyscallResult::Success,
+        Some(Err(err)) => err.into(),
+        None => SyscallResult::InvalidInstance,
+    }
+}
+
+as_native_func!(traced_vma_write; VMA_WRITE; args: ExternRef ExternRef u64 u64 u64; ret: SyscallResult);
+traced_syscall!(
+    vma_write => traced_vma_write(
+        source: ExternRef,
+        target: ExternRef,
+        source_offset: u64,
+        target_offset: u64,
+        size: u64
+    ) -> SyscallResult
+);
+fn vma_write(
+    source: ExternRef,
+    target: ExternRef,
+    source_offset: u64,
+    target_offset: u64,
+    size: u64,
+) -> SyscallResult {
+    allocator::alloc_free(|| {
+        let source_vma = match get_vma(source) {
+            Ok(vma) => vma,
+            Err(err) => return err,
+        };
+        let target_vma = match get_vma(target) {
+            Ok(vma) => vma,
+            Err(err) => return err,
+        };
+
+        let source_vma = source_vma.borrow_shared();
+        let source = match vma_as_buf(&source_vma, source_offset, size) {
+            Ok(buf) => buf,
+            Err(err) => return err,
+        };
+        let result = target_vma.with_exclusive(|target| {
+            let target = slice_at_mut(target, target_offset, size)?;
+            target.copy_from_slice(source);
+            Ok(())
+        });
+        match result {
+            Ok(Ok(())) => {
+                if target_vma.is_watched() {
+                    events::push_vma_event(target, target_offset, size);
+                }
+                compositor::damage(&target_vma);
+                SyscallResult::Success
+            }
+            Ok(Err(err)) => err,
+            Err(err) => vma_state_error(err),
+        }
+    })
+}
+
+as_native_func!(traced_vma_read; VMA_READ; args: ExternRef u64 u32 u32; ret: SyscallResult);
+traced_syscall!(
+    vma_read => traced_vma_read(source: ExternRef, source_offset: u64, target: u32, size: u32)
+        -> SyscallResult
+);
+/// Copies bytes from a VMA into the linear memory of the caller, at address `target`.
+fn vma_read(source: ExternRef, source_offset: u64, target: u32, size: u32) -> SyscallResult {
+    let source_vma = match get_vma(source) {
+        Ok(vma) => vma,
+        Err(err) => return err,
+    };
+    let source_vma = source_vma.borrow_shared();
+    let source = match vma_as_buf(&source_vma, source_offset, size as u64) {
+        Ok(buf) => buf,
+        Err(err) => return err,
+    };
+
+    let result = with_memory(|memory| {
+        let target = caller_slice_mut(memory, target, size)?;
+        target.copy_from_slice(source);
+        Ok(())
+    });
+    match result {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => err,
+    }
+}
+
+as_native_func!(traced_vma_size; VMA_SIZE; args: ExternRef; ret: (SyscallResult, u64));
+traced_syscall!(vma_size => traced_vma_size(vma: ExternRef) -> (SyscallResult, u64));
+/// Returns the size of a VMA, in bytes.
+fn vma_size(vma: ExternRef) -> (SyscallResult, u64) {
+    allocator::alloc_free(|| match get_vma(vma) {
+        Ok(vma) => (SyscallResult::Success, vma.size() as u64),
+        Err(err) => (err, 0),
+    })
+}
+
+as_native_func!(traced_vma_seal; VMA_SEAL; args: ExternRef; ret: SyscallResult);
+traced_syscall!(vma_seal => traced_vma_seal(vma: ExternRef) -> SyscallResult);
+/// Seals a VMA: its content can not be modified afterward, including by its owner.
+fn vma_seal(vma: ExternRef) -> SyscallResult {
+    let vma = match get_vma(vma) {
+        Ok(vma) => vma,
+        Err(err) => return err,
+    };
+    match vma.seal() {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => vma_state_error(err),
+    }
+}
+
+as_native_func!(traced_vma_state; VMA_STATE; args: ExternRef; ret: (SyscallResult, u32));
+traced_syscall!(vma_state => traced_vma_state(vma: ExternRef) -> (SyscallResult, u32));
+/// Returns the ownership state of a VMA: 0 if exclusive, 1 if borrowed by the kernel and 2 if
+/// sealed.
+fn vma_state(vma: ExternRef) -> (SyscallResult, u32) {
+    allocator::alloc_free(|| {
+        let state = match get_vma(vma) {
+            Ok(vma) => vma.state(),
+            Err(err) => return (err, 0),
+        };
+        let state = match state {
+            VmaState::Exclusive => 0,
+            VmaState::Shared(_) => 1,
+            VmaState::Sealed => 2,
+        };
+        (SyscallResult::Success, state)
+    })
+}
+
+as_native_func!(traced_vma_watch; VMA_WATCH; args: ExternRef; ret: SyscallResult);
+traced_syscall!(vma_watch => traced_vma_watch(vma: ExternRef) -> SyscallResult);
+/// Watches a VMA: each write through `vma_write` then emits a VMA event, which is delivered to the
+/// instances subscribed with `vma_register`. Watching is idempotent.
+fn vma_watch(vma: ExternRef) -> SyscallResult {
+    match get_vma(vma) {
+        Ok(vma) => {
+            vma.watch();
+            SyscallResult::Success
+        }
+        Err(err) => err,
+    }
+}
+
+as_native_func!(traced_vma_register; VMA_REGISTER; args: ExternRef u32; ret: SyscallResult);
+traced_syscall!(
+    vma_register => traced_vma_register(component: ExternRef, instance: u32) -> SyscallResult
+);
+/// Subscribes an instance to VMA events, emitted when a watched VMA is written.
+///
+/// The instance must export a `vma_event` function, which receives the VMA handle and the offset
+/// and size of the write as two i64.
+fn vma_register(component: ExternRef, instance: u32) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
+    };
+    let dispatcher = match VMA_DISPATCHER.try_get() {
+        Ok(dispatcher) => dispatcher,
+        Err(_) => {
+            crate::kprintln!("Syscall Error: VMA events are not available");
+            return SyscallResult::Unavailable;
+        }
+    };
+
+    let handler = match component.get_func("vma_event", InstanceIndex::from_u32(instance)) {
+        Some(handler) => handler,
+        None => {
+            crate::kprintln!("Syscall Error: instance does not export 'vma_event'");
+            return SyscallResult::MissingExport;
+        }
+    };
+    let ty = component.get_func_type(handler);
+    let expected = [ValueType::ExternRef, ValueType::I64, ValueType::I64];
+    if ty.args() != expected || !ty.ret().is_empty() {
+        crate::kprintln!("Syscall Error: invalid 'vma_event' signature");
+        return SyscallResult::ExportTypeMismatch;
+    }
+
+    dispatcher.add_listener(component, handler, Encoding::Scalars);
+    SyscallResult::Success
+}
+
+as_native_func!(traced_blob_from_vma; BLOB_FROM_VMA; args: ExternRef u64 u64; ret: (SyscallResult, ExternRef));
+traced_syscall!(
+    blob_from_vma => traced_blob_from_vma(source: ExternRef, offset: u64, size: u64)
+        -> (SyscallResult, ExternRef)
+);
+fn blob_from_vma(source: ExternRef, offset: u64, size: u64) -> (SyscallResult, ExternRef) {
+    let source_vma = match get_vma(source) {
+        Ok(vma) => vma,
+        Err(err) => return (err, ExternRef::Invalid),
+    };
+    let source_vma = source_vma.borrow_shared();
+    let source = match vma_as_buf(&source_vma, offset, size) {
+        Ok(buf) => buf,
+        Err(err) => return (err, ExternRef::Invalid),
+    };
+
+    let blob = Arc::new(Blob::from_bytes(source));
+    let handle = ACTIVE_BLOBS.insert(blob).into_externref();
+    (SyscallResult::Success, handle)
+}
+
+as_native_func!(
+    traced_surface_create;
+    SURFACE_CREATE;
+    args: u32 u32 u32 u32;
+    ret: (SyscallResult, ExternRef)
+);
+traced_syscall!(
+    surface_create => traced_surface_create(x: u32, y: u32, width: u32, height: u32)
+        -> (SyscallResult, ExternRef)
+);
+/// Creates a surface of the compositor, which stays hidden until presented with
+/// `surface_present`. The surface must fit within the screen.
+fn surface_create(x: u32, y: u32, width: u32, height: u32) -> (SyscallResult, ExternRef) {
+    let geometry = Geometry {
+        x,
+        y,
+        width,
+        height,
+    };
+    match Surface::new(geometry, get_runtime().vma_allocator()) {
+        Ok(surface) => (
+            SyscallResult::Success,
+            ACTIVE_SURFACES.insert(surface).into_externref(),
+        ),
+        Err(err) => (surface_error(err), ExternRef::Invalid),
+    }
+}
+
+as_native_func!(traced_surface_vma; SURFACE_VMA; args: ExternRef; ret: (SyscallResult, ExternRef));
+traced_syscall!(surface_vma => traced_surface_vma(surface: ExternRef) -> (SyscallResult, ExternRef));
+/// Returns a handle to the VMA holding the cells of a surface, laid out row by row as in the VGA
+/// text buffer. Writes through `vma_write` are displayed right away if the surface is presented.
+fn surface_vma(surface: ExternRef) -> (SyscallResult, ExternRef) {
+    match get_surface(surface) {
+        Ok(surface) => {
+            let vma = ACTIVE_VMA.insert(Arc::clone(surface.vma()));
+            (SyscallResult::Success, vma.into_externref())
+        }
+        Err(err) => (err, ExternRef::Invalid),
+    }
+}
+
+as_native_func!(traced_surface_resize; SURFACE_RESIZE; args: ExternRef u32 u32; ret: SyscallResult);
+traced_syscall!(
+    surface_resize => traced_surface_resize(surface: ExternRef, width: u32, height: u32)
+        -> SyscallResult
+);
+/// Changes the size of a surface, the cells are then laid out with the new width.
+fn surface_resize(surface: ExternRef, width: u32, height: u32) -> SyscallResult {
+    let surface = match get_surface(surface) {
+        Ok(surface) => surface,
+        Err(err) => return err,
+    };
+    match surface.resize(width, height) {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => surface_error(err),
+    }
+}
+
+as_native_func!(traced_surface_move; SURFACE_MOVE; args: ExternRef u32 u32; ret: SyscallResult);
+traced_syscall!(
+    surface_move => traced_surface_move(surface: ExternRef, x: u32, y: u32) -> SyscallResult
+);
+fn surface_move(surface: ExternRef, x: u32, y: u32) -> SyscallResult {
+    let surface = match get_surface(surface) {
+        Ok(surface) => surface,
+        Err(err) => return err,
+    };
+    match surface.move_to(x, y) {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => surface_error(err),
+    }
+}
+
+as_native_func!(traced_surface_present; SURFACE_PRESENT; args: ExternRef; ret: SyscallResult);
+traced_syscall!(surface_present => traced_surface_present(surface: ExternRef) -> SyscallResult);
+/// Brings a surface on top of the others and refreshes the screen.
+fn surface_present(surface: ExternRef) -> SyscallResult {
+    match get_surface(surface) {
+        Ok(surface) => {
+            surface.present();
+            SyscallResult::Success
+        }
+        Err(err) => err,
+    }
+}
+
+as_native_func!(traced_component_trace; COMPONENT_TRACE; args: ExternRef u32; ret: SyscallResult);
+traced_syscall!(
+    component_trace => traced_component_trace(component: ExternRef, enabled: u32) -> SyscallResult
+);
+fn component_trace(component: ExternRef, enabled: u32) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
+    };
+
+    component.set_syscall_tracing(enabled != 0);
+    SyscallResult::Success
+}
+
+as_native_func!(
+    traced_component_exit_status;
+    COMPONENT_EXIT_STATUS;
+    args: ExternRef u32 u32;
+    ret: SyscallResult
+);
+traced_syscall!(
+    component_exit_status => traced_component_exit_status(
+        component: ExternRef,
+        instance: u32,
+        target: u32
+    ) -> SyscallResult
+);
+/// Writes the exit status of the last execution of an instance into the caller memory, as a
+/// record of `EXIT_STATUS_SIZE` bytes (see `encode_exit_status`).
+fn component_exit_status(component: ExternRef, instance: u32, target: u32) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
+    };
+
+    let status = component.exit_status(InstanceIndex::from_u32(instance));
+    let record = encode_exit_status(status.as_ref());
+    let result = with_memory(|memory| {
+        caller_slice_mut(memory, target, EXIT_STATUS_SIZE as u32)?.copy_from_slice(&record);
+        Ok(())
+    });
+    match result {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => err,
+    }
+}
+
+as_native_func!(
+    traced_component_start_status;
+    COMPONENT_START_STATUS;
+    args: ExternRef u32;
+    ret: SyscallResult
+);
+traced_syscall!(
+    component_start_status => traced_component_start_status(component: ExternRef, target: u32)
+        -> SyscallResult
+);
+/// Writes the exit status of the last start function of the component which trapped into the
+/// caller memory, with the same encoding as `component_exit_status`.
+///
+/// Syscalls which run start functions (`component_add_instance`, `component_spawn` and
+/// `component_reload_instance`) return `StartTrapped` in that case, and discard the instance.
+fn component_start_status(component: ExternRef, target: u32) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
+    };
+
+    let record = encode_exit_status(component.start_trap().as_ref());
+    let result = with_memory(|memory| {
+        caller_slice_mut(memory, target, EXIT_STATUS_SIZE as u32)?.copy_from_slice(&record);
+        Ok(())
+    });
+    match result {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => err,
+    }
+}
+
+as_native_func!(
+    traced_component_metrics;
+    COMPONENT_METRICS;
+    args: ExternRef u32 u32;
+    ret: SyscallResult
+);
+traced_syscall!(
+    component_metrics => traced_component_metrics(
+        component: ExternRef,
+        instance: u32,
+        target: u32
+    ) -> SyscallResult
+);
+/// Writes the execution metrics of an instance into the caller memory, as a record of
+/// `METRICS_SIZE` bytes (see `encode_metrics`).
+fn component_metrics(component: ExternRef, instance: u32, target: u32) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
+    };
+
+    let metrics = match component.instance_metrics(InstanceIndex::from_u32(instance)) {
+        Some(metrics) => metrics,
+        None => {
+            crate::kprintln!("Syscall Error: instance does not exist");
+            return SyscallResult::InvalidInstance;
+        }
+    };
+    let record = encode_metrics(&metrics);
+    let result = with_memory(|memory| {
+        caller_slice_mut(memory, target, METRICS_SIZE as u32)?.copy_from_slice(&record);
+        Ok(())
+    });
+    match result {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => err,
+    }
+}
+
+as_native_func!(traced_pointer_register; POINTER_REGISTER; args: ExternRef u32; ret: SyscallResult);
+traced_syscall!(
+    pointer_register => traced_pointer_register(component: ExternRef, instance: u32) -> SyscallResult
+);
+/// Subscribes an instance to pointer events.
+///
+/// The instance must export a `pointer_event` function, which receives the horizontal and
+/// vertical motions and the buttons state as three i32.
+fn pointer_register(component: ExternRef, instance: u32) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
+    };
+    let dispatcher = match POINTER_DISPATCHER.try_get() {
+        Ok(dispatcher) => dispatcher,
+        Err(_) => {
+            crate::kprintln!("Syscall Error: pointer events are not available");
+            return SyscallResult::Unavailable;
+        }
+    };
+
+    let handler = match component.get_func("pointer_event", InstanceIndex::from_u32(instance)) {
+        Some(handler) => handler,
+        None => {
+            crate::kprintln!("Syscall Error: instance does not export 'pointer_event'");
+            return SyscallResult::MissingExport;
+        }
+    };
+    let ty = component.get_func_type(handler);
+    if ty.args() != [ValueType::I32; 3] || !ty.ret().is_empty() {
+        crate::kprintln!("Syscall Error: invalid 'pointer_event' signature");
+        return SyscallResult::ExportTypeMismatch;
+    }
+
+    dispatcher.add_listener(component, handler, Encoding::Scalars);
+    SyscallResult::Success
+}
+
+as_native_func!(traced_module_register; MODULE_REGISTER; args: ExternRef u32; ret: SyscallResult);
+traced_syscall!(
+    module_register => traced_module_register(component: ExternRef, instance: u32) -> SyscallResult
+);
+/// Subscribes an instance to module events.
+///
+/// The instance must export a `module_event` function, which receives the module handle, its
+/// status (see `module_status`) and the number of compiled and total functions as three i32.
+fn module_register(component: ExternRef, instance: u32) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
+    };
+    let dispatcher = match MODULE_DISPATCHER.try_get() {
+        Ok(dispatcher) => dispatcher,
+        Err(_) => {
+            crate::kprintln!("Syscall Error: module events are not available");
+            return SyscallResult::Unavailable;
+        }
+    };
+
+    let handler = match component.get_func("module_event", InstanceIndex::from_u32(instance)) {
+        Some(handler) => handler,
+        None => {
+            crate::kprintln!("Syscall Error: instance does not export 'module_event'");
+            return SyscallResult::MissingExport;
+        }
+    };
+    let ty = component.get_func_type(handler);
+    let expected = [
+        ValueType::ExternRef,
+        ValueType::I32,
+        ValueType::I32,
+        ValueType::I32,
+    ];
+    if ty.args() != expected || !ty.ret().is_empty() {
+        crate::kprintln!("Syscall Error: invalid 'module_event' signature");
+        return SyscallResult::ExportTypeMismatch;
+    }
+
+    dispatcher.add_listener(component, handler, Encoding::Scalars);
+    SyscallResult::Success
+}
+
+as_native_func!(traced_env_set; ENV_SET; args: ExternRef u32 u32 u32 u32; ret: SyscallResult);
+traced_syscall!(
+    env_set => traced_env_set(
+        component: ExternRef,
+        key: u32,
+        key_len: u32,
+        value: u32,
+        value_len: u32
+    ) -> SyscallResult
+);
+/// Sets an environment variable of a component, the key and value are read from the caller
+/// memory.
+fn env_set(
+    component: ExternRef,
+    key: u32,
+    key_len: u32,
+    value: u32,
+    value_len: u32,
+) -> SyscallResult {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return err,
+    };
+
+    let result = with_memory(|memory| {
+        let key = caller_slice(memory, key, key_len)?;
+        let value = caller_slice(memory, value, value_len)?;
+        component.env().set(key, value).map_err(|err| {
+            crate::kprintln!("Syscall Error: invalid environment variable: {:?}", err);
+            SyscallResult::InvalidEnv
+        })
+    });
+    match result {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => err,
+    }
+}
+
+as_native_func!(traced_env_get; ENV_GET; args: ExternRef u32 u32 u32 u32; ret: (SyscallResult, u64));
+traced_syscall!(
+    env_get => traced_env_get(
+        component: ExternRef,
+        key: u32,
+        key_len: u32,
+        target: u32,
+        target_len: u32
+    ) -> (SyscallResult, u64)
+);
+/// Reads an environment variable of a component into the caller memory, returns the size of the
+/// value.
+///
+/// If the value does not fit into the target buffer nothing is written, the caller can retry with a
+/// large enough buffer.
+fn env_get(
+    component: ExternRef,
+    key: u32,
+    key_len: u32,
+    target: u32,
+    target_len: u32,
+) -> (SyscallResult, u64) {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return (err, 0),
+    };
+
+    let result = with_memory(|memory| {
+        let key = caller_slice(memory, key, key_len)?.to_vec();
+        let target = caller_slice_mut(memory, target, target_len)?;
+        let env = component.env();
+        let value = match env.get(&key) {
+            Some(value) => value,
+            None => {
+                crate::kprintln!("Syscall Error: environment variable does not exists");
+                return Err(SyscallResult::EnvNotFound);
+            }
+        };
+        if let Some(target) = target.get_mut(..value.len()) {
+            target.copy_from_slice(value);
+        }
+        Ok(value.len() as u64)
+    });
+    match result {
+        Ok(size) => (SyscallResult::Success, size),
+        Err(err) => (err, 0),
+    }
+}
+
+as_native_func!(traced_env_list; ENV_LIST; args: ExternRef u32 u32; ret: (SyscallResult, u64));
+traced_syscall!(
+    env_list => traced_env_list(component: ExternRef, target: u32, target_len: u32)
+        -> (SyscallResult, u64)
+);
+/// Writes the keys of the environment of a component into the caller memory, each followed by a
+/// null byte. Returns the size of the list.
+///
+/// If the list does not fit into the target buffer nothing is written, the caller can retry with a
+/// large enough buffer.
+fn env_list(component: ExternRef, target: u32, target_len: u32) -> (SyscallResult, u64) {
+    let component = match get_component(component) {
+        Ok(component) => component,
+        Err(err) => return (err, 0),
+    };
+
+    let result = with_memory(|memory| {
+        let target = caller_slice_mut(memory, target, target_len)?;
+        let env = component.env();
+        let size = env.keys_size();
+        if let Some(target) = target.get_mut(..size) {
+            env.write_keys(target);
+        }
+        Ok(size as u64)
+    });
+    match result {
+        Ok(size) => (SyscallResult::Success, size),
+        Err(err) => (err, 0),
+    }
+}
+
+as_native_func!(traced_service_register; SERVICE_REGISTER; args: u32 u32 ExternRef; ret: SyscallResult);
+traced_syscall!(
+    service_register => traced_service_register(name: u32, name_len: u32, handle: ExternRef)
+        -> SyscallResult
+);
+/// Publishes a handle under a name, read from the caller memory, so that other components can
+/// look it up.
+///
+/// The registry keeps a handle derived from the given one: revoking the given handle withdraws the
+/// service, and the handles obtained by looking it up.
+fn service_register(name: u32, name_len: u32, handle: ExternRef) -> SyscallResult {
+    let result = with_memory(|memory| {
+        let name = service_name(memory, name, name_len)?;
+        check_service_permission(name, ServicePermissions::can_register)?;
+        let derived = match handle_derive(handle) {
+            (SyscallResult::Success, derived) => derived,
+            (err, _) => return Err(err),
+        };
+        services::register(name, derived).map_err(|err| {
+            // Nobody else got the derived handle yet
+            handle_revoke(derived);
+            service_error(err)
+        })
+    });
+    match result {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => err,
+    }
+}
+
+as_native_func!(traced_service_lookup; SERVICE_LOOKUP; args: u32 u32; ret: (SyscallResult, ExternRef));
+traced_syscall!(
+    service_lookup => traced_service_lookup(name: u32, name_len: u32) -> (SyscallResult, ExternRef)
+);
+/// Returns a handle to the service registered under a name, read from the caller memory.
+fn service_lookup(name: u32, name_len: u32) -> (SyscallResult, ExternRef) {
+    let result = with_memory(|memory| {
+        let name = service_name(memory, name, name_len)?;
+        check_service_permission(name, ServicePermissions::can_lookup)?;
+        services::lookup(name).map_err(service_error)
+    });
+    match result {
+        Ok(handle) => handle_derive(handle),
+        Err(err) => (err, ExternRef::Invalid),
+    }
+}
+
+as_native_func!(traced_task_yield; TASK_YIELD; ret: SyscallResult);
+traced_syscall!(task_yield => traced_task_yield() -> SyscallResult);
+/// Suspends the calling execution, which is resumed once the other ready tasks had a chance to run.
+fn task_yield() -> SyscallResult {
+    suspend(Suspend::Yield)
+}
+
+as_native_func!(traced_task_sleep_ms; TASK_SLEEP_MS; args: u64; ret: SyscallResult);
+traced_syscall!(task_sleep_ms => traced_task_sleep_ms(ms: u64) -> SyscallResult);
+/// Suspends the calling execution for at least the given number of milliseconds.
+fn task_sleep_ms(ms: u64) -> SyscallResult {
+    suspend(Suspend::Sleep(ms))
+}
+
+// NOTE: `system_stats` is not traced, it is meant to be polled (e.g. by a status bar).
+as_native_func!(system_stats; SYSTEM_STATS; args: u32 u32; ret: SyscallResult);
+/// Writes statistics about the system into a buffer of the caller memory: a record of
+/// `STATS_SIZE` bytes (see `encode_stats`), followed by the count of each interrupt and then of
+/// each traced syscall since boot, as many as fit in the buffer (see `encode_interrupt_count` and
+/// `encode_syscall_count`).
+fn system_stats(target: u32, size: u32) -> SyscallResult {
+    let mut stats = SystemStats {
+        ticks: scheduler::ticks(),
+        modules: ACTIVE_MODULES.len() as u32,
+        components: ACTIVE_COMPONENTS.len() as u32,
+        heap_free: allocator::heap_free() as u64,
+        heap_size: allocator::HEAP_SIZE as u64,
+        frames_free: get_runtime().vma_allocator().free_frames() as u64,
+        run_queue: scheduler::run_queue_len() as u32,
+        interrupts: 0,
+        syscalls: 0,
+    };
+    let result = with_memory(|memory| {
+        let buffer = caller_slice_mut(memory, target, size)?;
+        if buffer.len() < STATS_SIZE {
+            crate::kprintln!("Syscall Error: buffer is too small for system statistics");
+            return Err(SyscallResult::OutOfBounds);
+        }
+
+        let mut pos = STATS_SIZE;
+        interrupts::for_each_interrupt_count(|vector, count| {
+            if let Some(entry) = buffer.get_mut(pos..(pos + INTERRUPT_COUNT_SIZE)) {
+                entry.copy_from_slice(&encode_interrupt_count(vector, count));
+                pos += INTERRUPT_COUNT_SIZE;
+                stats.interrupts += 1;
+            }
+        });
+        // Syscall counts are larger than interrupt counts: none fits if an interrupt did not.
+        trace::for_each_count(|name, count| {
+            if let Some(entry) = buffer.get_mut(pos..(pos + SYSCALL_COUNT_SIZE)) {
+                entry.copy_from_slice(&encode_syscall_count(name, count));
+                pos += SYSCALL_COUNT_SIZE;
+                stats.syscalls += 1;
+            }
+        });
+        buffer[..STATS_SIZE].copy_from_slice(&encode_stats(&stats));
+        Ok(())
+    });
+    match result {
+        Ok(()) => SyscallResult::Success,
+        Err(err) => err,
+    }
+}
+
+as_native_func!(traced_system_shutdown; SYSTEM_SHUTDOWN; args: ExternRef; ret: SyscallResult);
+traced_syscall!(system_shutdown => traced_system_shutdown(capability: ExternRef) -> SyscallResult);
+fn system_shutdown(capability: ExternRef) -> SyscallResult {
+    if let Err(err) = check_power_capability(capability) {
+        return err;
+    }
+    crate::power::shutdown();
+}
+
+as_native_func!(traced_system_reboot; SYSTEM_REBOOT; args: ExternRef; ret: SyscallResult);
+traced_syscall!(system_reboot => traced_system_reboot(capability: ExternRef) -> SyscallResult);
+fn system_reboot(capability: ExternRef) -> SyscallResult {
+    if let Err(err) = check_power_capability(capability) {
+        return err;
+    }
+    crate::power::reboot();
+}
+
+// NOTE: `trace_read` is not traced itself, otherwise reading the trace would pollute it.
+as_native_func!(
+    trace_read;
+    TRACE_READ;
+    args: ExternRef ExternRef u64 u64;
+    ret: (SyscallResult, u64)
+);
+/// Writes the syscall trace into a VMA, the caller must hold the trace capability: the trace
+/// records the syscalls of all the traced components.
+fn trace_read(
+    capability: ExternRef,
+    target: ExternRef,
+    offset: u64,
+    size: u64,
+) -> (SyscallResult, u64) {
+    if let Err(err) = check_trace_capability(capability) {
+        return (err, 0);
+    }
+    let target_vma = match get_vma(target) {
+        Ok(vma) => vma,
+        Err(err) => return (err, 0),
+    };
+    let result = target_vma.with_exclusive(|target| {
+        let target = slice_at_mut(target, offset, size)?;
+
+        // Write as many complete lines as possible, from the oldest to the most recent record.
+        let mut writer = SliceWriter::new(target);
+        trace::for_each_record(|record| {
+            let line_start = writer.pos;
+            if writeln!(writer, "{}", record).is_err() {
+                writer.pos = line_start;
+                return Err(());
+            }
+            Ok(())
+        });
+        Ok(writer.pos as u64)
+    });
+    match result {
+        Ok(Ok(written)) => (SyscallResult::Success, written),
+        Ok(Err(err)) => (err, 0),
+        Err(err) => (vma_state_error(err), 0),
+    }
+}
+
+as_native_func!(traced_profile_enable; PROFILE_ENABLE; args: u32; ret: SyscallResult);
+traced_syscall!(profile_enable => traced_profile_enable(enabled: u32) -> SyscallResult);
+/// Enables or disables the sampling profiler, the profile is reset when enabled.
+fn profile_enable(enabled: u32) -> SyscallResult {
+    profiler::set_enabled(enabled != 0);
+    SyscallResult::Success
+}
+
+// NOTE: `profile_read` is not traced, for consistency with `trace_read`.
+as_native_func!(profile_read; PROFILE_READ; args: ExternRef u64 u64; ret: (SyscallResult, u64));
+/// Writes the profile into a VMA as text, one line per profiled function after a summary line.
+fn profile_read(target: ExternRef, offset: u64, size: u64) -> (SyscallResult, u64) {
+    let target_vma = match get_vma(target) {
+        Ok(vma) => vma,
+        Err(err) => return (err, 0),
+    };
+    let result = target_vma.with_exclusive(|target| {
+        let target = slice_at_mut(target, offset, size)?;
+
+        // Write as many complete lines as possible.
+        let mut writer = SliceWriter::new(target);
+        profiler::for_each_line(|line| {
+            let line_start = writer.pos;
+            if writeln!(writer, "{}", line).is_err() {
+                writer.pos = line_start;
+                return Err(());
+            }
+            Ok(())
+        });
+        Ok(writer.pos as u64)
+    });
+    match result {
+        Ok(Ok(written)) => (SyscallResult::Success, written),
+        Ok(Err(err)) => (err, 0),
+        Err(err) => (vma_state_error(err), 0),
+    }
+}
+
+as_native_func!(traced_sched_trace_enable; SCHED_TRACE_ENABLE; args: u32; ret: SyscallResult);
+traced_syscall!(sched_trace_enable => traced_sched_trace_enable(enabled: u32) -> SyscallResult);
+/// Enables or disables scheduler tracing, the trace is reset when enabled.
+fn sched_trace_enable(enabled: u32) -> SyscallResult {
+    sched_trace::set_enabled(enabled != 0);
+    SyscallResult::Success
+}
+
+// NOTE: `sched_trace_read` is not traced, for consistency with `trace_read`.
+as_native_func!(
+    sched_trace_read;
+    SCHED_TRACE_READ;
+    args: ExternRef u64 u64;
+    ret: (SyscallResult, u64)
+);
+/// Writes the scheduler trace into a VMA as text, one line per record from the oldest to the most
+/// recent, each starting with its timestamp in CPU cycles.
+fn sched_trace_read(target: ExternRef, offset: u64, size: u64) -> (SyscallResult, u64) {
+    let target_vma = match get_vma(target) {
+        Ok(vma) => vma,
+        Err(err) => return (err, 0),
+    };
+    let result = target_vma.with_exclusive(|target| {
+        let target = slice_at_mut(target, offset, size)?;
+
+        // Write as many complete lines as possible.
+        let mut writer = SliceWriter::new(target);
+        sched_trace::for_each_record(|record| {
+            let line_start = writer.pos;
+            if writeln!(writer, "{}", record).is_err() {
+                writer.pos = line_start;
+                return Err(());
+            }
+            Ok(())
+        });
+        Ok(writer.pos as u64)
+    });
+    match result {
+        Ok(Ok(written)) => (SyscallResult::Success, written),
+        Ok(Err(err)) => (err, 0),
+        Err(err) => (vma_state_error(err), 0),
+    }
+}
+
+as_native_func!(
+    traced_selftest_run;
+    SELFTEST_RUN;
+    args: ExternRef u64 u64;
+    ret: (SyscallResult, u64)
+);
+traced_syscall!(
+    selftest_run => traced_selftest_run(target: ExternRef, offset: u64, size: u64)
+        -> (SyscallResult, u64)
+);
+/// Runs the kernel self-tests, and writes their reports into a VMA as text, one line per test.
+fn selftest_run(target: ExternRef, offset: u64, size: u64) -> (SyscallResult, u64) {
+    let target_vma = match get_vma(target) {
+        Ok(vma) => vma,
+        Err(err) => return (err, 0),
+    };
+    let reports = selftest::run();
+    let result = target_vma.with_exclusive(|target| {
+        let target = slice_at_mut(target, offset, size)?;
+
+        // Write as many complete lines as possible.
+        let mut writer = SliceWriter::new(target);
+        for report in &reports {
+            let line_start = writer.pos;
+            if writeln!(writer, "{}", report).is_err() {
+                writer.pos = line_start;
+                break;
+            }
+        }
+        Ok(writer.pos as u64)
+    });
+    match result {
+        Ok(Ok(written)) => (SyscallResult::Success, written),
+        Ok(Err(err)) => (err, 0),
+        Err(err) => (vma_state_error(err), 0),
+    }
+}
+
+// ————————————————————————————————— Utils —————————————————————————————————— //
+
+/// Returns an error if the handle is not a power capability.
+fn check_power_capability(handle: ExternRef) -> Result<(), SyscallResult> {
+    match handle {
+        ExternRef::Power => Ok(()),
+        _ => {
+            crate::kprintln!("Syscall Error: expected power capability, got {:?}", handle);
+            Err(SyscallResult::WrongHandleKind)
+        }
+    }
+}
+
+/// Returns an error if the handle is not a trace capability.
+fn check_trace_capability(handle: ExternRef) -> Result<(), SyscallResult> {
+    match handle {
+        ExternRef::Trace => Ok(()),
+        _ => {
+            crate::kprintln!("Syscall Error: expected trace capability, got {:?}", handle);
+            Err(SyscallResult::WrongHandleKind)
+        }
+    }
+}
+
+/// Maximum number of handles awaited by `handle_wait`.
+const MAX_WAIT_HANDLES: usize = 64;
+
+/// Reads the list of handles awaited by `handle_wait` from a VMA.
+fn read_handle_list(handles: ExternRef) -> Result<Vec<ExternRef>, SyscallResult> {
+    let vma = get_vma(handles)?;
+    let vma = vma.borrow_shared();
+    let mut list = Vec::new();
+    for bytes in vma.as_bytes().chunks_exact(8) {
+        let handle = ExternRef::from_abi(u64::from_le_bytes(bytes.try_into().unwrap()));
+        if let ExternRef::Invalid = handle {
+            break;
+        }
+        if list.len() >= MAX_WAIT_HANDLES {
+            crate::kprintln!(
+                "Syscall Error: can not wait on more than {} handles",
+                MAX_WAIT_HANDLES
+            );
+            return Err(SyscallResult::OutOfBounds);
+        }
+        list.push(handle);
+    }
+    Ok(list)
+}
+
+/// Returns whether an object awaited by `handle_wait` is ready.
+fn is_ready(handle: ExternRef) -> Result<bool, SyscallResult> {
+    match handle {
+        ExternRef::Module(_) => Ok(get_kernel_module(handle)?.status().is_complete()),
+        ExternRef::Bundle(_) => Ok(get_bundle(handle)?.status().is_complete()),
+        ExternRef::Component(_) => Ok(!get_component(handle)?.is_busy()),
+        _ => {
+            crate::kprintln!("Syscall Error: can not wait on '{:?}'", handle);
+            Err(SyscallResult::WrongHandleKind)
+        }
+    }
+}
+
+/// Suspends the calling execution.
+fn suspend(reason: Suspend) -> SyscallResult {
+    if suspend_execution(reason) {
+        SyscallResult::Success
+    } else {
+        crate::kprintln!("Syscall Error: the caller can not be suspended");
+        SyscallResult::NotSuspendable
+    }
+}
+
+/// Executes `f` on the linear memory of the caller.
+fn with_memory<F, R>(f: F) -> Result<R, SyscallResult>
+where
+    F: FnOnce(&mut [u8]) -> Result<R, SyscallResult>,
+{
+    match with_caller_memory(f) {
+        Some(Ok(result)) => result,
+        Some(Err(err)) => Err(vma_state_error(err)),
+        None => {
+            crate::kprintln!("Syscall Error: caller has no memory");
+            Err(SyscallResult::NoMemory)
+        }
+    }
+}
+
+/// Returns a view of the caller memory at the given address and with the given size.
+fn caller_slice(memory: &[u8], addr: u32, size: u32) -> Result<&[u8], SyscallResult> {
+    slice_at(memory, addr as u64, size as u64).map_err(|err| {
+        crate::kprintln!("Syscall Error: buffer is out of the caller memory");
+        err
+    })
+}
+
+/// Returns a mutable view of the caller memory at the given address and with the given size.
+fn caller_slice_mut(memory: &mut [u8], addr: u32, size: u32) -> Result<&mut [u8], SyscallResult> {
+    slice_at_mut(memory, addr as u64, size as u64).map_err(|err| {
+        crate::kprintln!("Syscall Error: buffer is out of the caller memory");
+        err
+    })
+}
+
+/// Size of an encoded exit status, in bytes.
+const EXIT_STATUS_SIZE: usize = 32;
+
+/// Encodes an exit status as a little-endian record made of:
+///
+/// - The kind of status as an u32: 0 if the instance never executed, 1 if it returned, 2 if it
+///   trapped and 3 if it was killed.
+/// - The trap code as an u32, 0 if the instance did not trap.
+/// - The index of the function which trapped as an u32, `u32::MAX` if unknown.
+/// - The offset of the trapping instruction within the module bytecode as an u32, `u32::MAX` if
+///   unknown.
+/// - The two returned values as u64, 0 if the function returned less values.
+fn encode_exit_status(status: Option<&ExitStatus>) -> [u8; EXIT_STATUS_SIZE] {
+    let mut kind = 0;
+    let mut code = 0;
+    let mut func = u32::MAX;
+    let mut offset = u32::MAX;
+    let mut values = [0; 2];
+    match status {
+        None => {}
+        Some(ExitStatus::Returned(returned)) => {
+            kind = 1;
+            for (value, returned) in values.iter_mut().zip(returned) {
+                *value = *returned;
+            }
+        }
+        Some(ExitStatus::Trapped {
+            code: trap_code,
+            location,
+        }) => {
+            kind = 2;
+            code = *trap_code as u32;
+            if let Some(location) = location {
+                func = location.func.as_u32();
+                offset = location.offset.unwrap_or(u32::MAX);
+            }
+        }
+        Some(ExitStatus::Killed) => kind = 3,
+    }
+
+    let mut record = [0; EXIT_STATUS_SIZE];
+    record[0..4].copy_from_slice(&u32::to_le_bytes(kind));
+    record[4..8].copy_from_slice(&u32::to_le_bytes(code));
+    record[8..12].copy_from_slice(&u32::to_le_bytes(func));
+    record[12..16].copy_from_slice(&u32::to_le_bytes(offset));
+    record[16..24].copy_from_slice(&u64::to_le_bytes(values[0]));
+    record[24..32].copy_from_slice(&u64::to_le_bytes(values[1]));
+    record
+}
+
+/// Size of encoded instance metrics, in bytes.
+const METRICS_SIZE: usize = 24;
+
+/// Encodes the metrics of an instance as a little-endian record made of three u64: the number of
+/// calls into the instance, the CPU cycles spent in those calls, and the total number of calls to
+/// imported functions (0 if the module does not count them).
+fn encode_metrics(metrics: &InstanceMetrics) -> [u8; METRICS_SIZE] {
+    let imported_calls: u64 = metrics.imported_calls.iter().map(|(_, count)| count).sum();
+    let mut record = [0; METRICS_SIZE];
+    record[0..8].copy_from_slice(&u64::to_le_bytes(metrics.calls));
+    record[8..16].copy_from_slice(&u64::to_le_bytes(metrics.ticks));
+    record[16..24].copy_from_slice(&u64::to_le_bytes(imported_calls));
+    record
+}
+
+/// Statistics about the system, as reported by `system_stats`.
+struct SystemStats {
+    /// Number of timer interrupts since boot.
+    ticks: u64,
+    /// Number of modules created since boot.
+    modules: u32,
+    /// Number of components created since boot.
+    components: u32,
+    /// Free bytes on the kernel heap.
+    heap_free: u64,
+    /// Size of the kernel heap, in bytes.
+    heap_size: u64,
+    /// Number of free physical frames.
+    frames_free: u64,
+    /// Number of tasks ready to run.
+    run_queue: u32,
+    /// Number of interrupt counts following the record.
+    interrupts: u32,
+    /// Number of syscall counts following the interrupt counts.
+    syscalls: u32,
+}
+
+/// Size of encoded system statistics, in bytes.
+const STATS_SIZE: usize = 56;
+/// Size of an encoded interrupt count, in bytes.
+const INTERRUPT_COUNT_SIZE: usize = 16;
+/// Size of an encoded syscall count, in bytes.
+const SYSCALL_COUNT_SIZE: usize = 40;
+/// Maximum length of an encoded syscall name, longer names are truncated.
+const SYSCALL_NAME_SIZE: usize = 32;
+
+/// Encodes system statistics as a little-endian record: the ticks since boot as a u64, the number
+/// of modules and components as two u32, the free and total size of the kernel heap and the free
+/// frames as three u64, then the length of the run queue and the number of interrupt and syscall
+/// counts as three u32, followed by 4 bytes of padding.
+fn encode_stats(stats: &SystemStats) -> [u8; STATS_SIZE] {
+    let mut record = [0; STATS_SIZE];
+    record[0..8].copy_from_slice(&u64::to_le_bytes(stats.ticks));
+    record[8..12].copy_from_slice(&u32::to_le_bytes(stats.modules));
+    record[12..16].copy_from_slice(&u32::to_le_bytes(stats.components));
+    record[16..24].copy_from_slice(&u64::to_le_bytes(stats.heap_free));
+    record[24..32].copy_from_slice(&u64::to_le_bytes(stats.heap_size));
+    record[32..40].copy_from_slice(&u64::to_le_bytes(stats.frames_free));
+    record[40..44].copy_from_slice(&u32::to_le_bytes(stats.run_queue));
+    record[44..48].copy_from_slice(&u32::to_le_bytes(stats.interrupts));
+    record[48..52].copy_from_slice(&u32::to_le_bytes(stats.syscalls));
+    record
+}
+
+/// Encodes the number of occurrences of an interrupt as a little-endian record: the vector as a
+/// u32, 4 bytes of padding and the count as a u64.
+fn encode_interrupt_count(vector: u8, count: u64) -> [u8; INTERRUPT_COUNT_SIZE] {
+    let mut record = [0; INTERRUPT_COUNT_SIZE];
+    record[0..4].copy_from_slice(&u32::to_le_bytes(vector as u32));
+    record[8..16].copy_from_slice(&u64::to_le_bytes(count));
+    record
+}
+
+/// Encodes the number of invocations of a syscall as a little-endian record: the count as a u64,
+/// followed by the name of the syscall padded with zeroes to `SYSCALL_NAME_SIZE` bytes.
+fn encode_syscall_count(name: &str, count: u64) -> [u8; SYSCALL_COUNT_SIZE] {
+    let mut record = [0; SYSCALL_COUNT_SIZE];
+    record[0..8].copy_from_slice(&u64::to_le_bytes(count));
+    let name = &name.as_bytes()[..name.len().min(SYSCALL_NAME_SIZE)];
+    record[8..(8 + name.len())].copy_from_slice(name);
+    record
+}
+
+/// Returns the component corresponding to the given handle, if any.
+fn get_component(handle: ExternRef) -> Result<Arc<Component>, SyscallResult> {
+    let component_idx = match handle {
+        ExternRef::Component(component) => component,
+        _ => {
+            crate::kprintln!("Syscall Error: expected component, got '{:?}'", handle);
+            return Err(SyscallResult::WrongHandleKind);
+        }
+    };
+    match ACTIVE_COMPONENTS.get(component_idx) {
+        Some(component) => Ok(component),
+        None => {
+            crate::kprintln!("Syscall Error: component does not exists");
+            Err(SyscallResult::InvalidHandle)
+        }
+    }
+}
+
+/// Returns the module corresponding to the given handle, if any.
+fn get_kernel_module(handle: ExternRef) -> Result<Arc<KernelModule>, SyscallResult> {
+    let module_idx = match handle {
+        ExternRef::Module(module) => module,
+        _ => {
+            crate::kprintln!("Syscall Error: expected module , got '{:?}'", handle);
+            return Err(SyscallResult::WrongHandleKind);
+        }
+    };
+    match ACTIVE_MODULES.get(module_idx) {
+        Some(module) => Ok(module),
+        None => {
+            crate::kprintln!("Syscall Error: module does not exists");
+            Err(SyscallResult::InvalidHandle)
+        }
+    }
+}
+
+/// Returns the compiled module corresponding to the given handle, fails if the module is not
+/// ready yet.
+fn get_module(handle: ExternRef) -> Result<Arc<WasmModule>, SyscallResult> {
+    compiled_module(get_kernel_module(handle)?.as_ref())
+}
+
+/// Returns the compiled module, fails if the module is not ready yet.
+fn compiled_module(module: &KernelModule) -> Result<Arc<WasmModule>, SyscallResult> {
+    match module.get() {
+        Some(module) => Ok(module),
+        None if module.status() == ModuleStatus::Failed => {
+            crate::kprintln!("Syscall Error: module failed to compile");
+            Err(SyscallResult::CompilationFailed)
+        }
+        None => {
+            crate::kprintln!("Syscall Error: module is not compiled");
+            Err(SyscallResult::ModuleNotReady)
+        }
+    }
+}
+
+/// Returns the bundle corresponding to the given handle, if any.
+fn get_bundle(handle: ExternRef) -> Result<Arc<KernelBundle>, SyscallResult> {
+    let bundle_idx = match handle {
+        ExternRef::Bundle(bundle) => bundle,
+        _ => {
+            crate::kprintln!("Syscall Error: expected bundle, got '{:?}'", handle);
+            return Err(SyscallResult::WrongHandleKind);
+        }
+    };
+    match ACTIVE_BUNDLES.get(bundle_idx) {
+        Some(bundle) => Ok(bundle),
+        None => {
+            crate::kprintln!("Syscall Error: bundle does not exists");
+            Err(SyscallResult::InvalidHandle)
+        }
+    }
+}
+
+/// Returns the source of a compilation, from either a blob or a sealed VMA, and checks that the
+/// range is within its bounds.
+fn get_source(handle: ExternRef, offset: u64, size: u64) -> Result<Source, SyscallResult> {
+    match handle {
+        ExternRef::Blob(_) => get_blob(handle).and_then(|blob| {
+            blob_as_buf(&blob, offset, size)?;
+            Ok(Source::Blob(blob))
+        }),
+        _ => get_vma(handle).and_then(|vma| {
+            if vma.state() != VmaState::Sealed {
+                crate::kprintln!("Syscall Error: VMA must be sealed before compiling a module");
+                return Err(SyscallResult::VmaNotSealed);
+            }
+            vma_as_buf(&vma, offset, size)?;
+            Ok(Source::Vma(vma))
+        }),
+    }
+}
+
+/// Returns the VMA corresponding to the given handle, if any.
+fn get_surface(handle: ExternRef) -> Result<Arc<Surface>, SyscallResult> {
+    let surface_idx = match handle {
+        ExternRef::Surface(surface) => surface,
+        _ => {
+            crate::kprintln!("Syscall Error: expected surface, got '{:?}'", handle);
+            return Err(SyscallResult::WrongHandleKind);
+        }
+    };
+    match ACTIVE_SURFACES.get(surface_idx) {
+        Some(surface) => Ok(surface),
+        None => {
+            crate::kprintln!("Syscall Error: surface does not exists");
+            Err(SyscallResult::InvalidHandle)
+        }
+    }
+}
+
+/// Logs a failed surface operation.
+fn surface_error(err: SurfaceError) -> SyscallResult {
+    match err {
+        SurfaceError::OutOfBounds => {
+            crate::kprintln!("Syscall Error: surface does not fit within the screen");
+            SyscallResult::OutOfBounds
+        }
+        SurfaceError::OutOfMemory => {
+            crate::kprintln!("Syscall Error: failed to allocate surface");
+            SyscallResult::OutOfMemory
+        }
+    }
+}
+
+/// Reads a service name from the caller memory.
+fn service_name(memory: &[u8], name: u32, name_len: u32) -> Result<&str, SyscallResult> {
+    let name = caller_slice(memory, name, name_len)?;
+    core::str::from_utf8(name).map_err(|_| service_error(ServiceError::InvalidName))
+}
+
+/// Checks that the current component is allowed to use a service, the kernel itself is not
+/// allowed any.
+fn check_service_permission<F>(name: &str, allowed: F) -> Result<(), SyscallResult>
+where
+    F: FnOnce(&ServicePermissions, &str) -> bool,
+{
+    match with_current_component(|component| allowed(component.services(), name)) {
+        Some(true) => Ok(()),
+        _ => {
+            crate::kprintln!("Syscall Error: service '{}' is not allowed", name);
+            Err(SyscallResult::ServiceDenied)
+        }
+    }
+}
+
+/// Logs a failed service operation.
+fn service_error(err: ServiceError) -> SyscallResult {
+    match err {
+        ServiceError::InvalidName => {
+            crate::kprintln!("Syscall Error: invalid service name");
+            SyscallResult::InvalidServiceName
+        }
+        ServiceError::AlreadyRegistered => {
+            crate::kprintln!("Syscall Error: service is already registered");
+            SyscallResult::ServiceExists
+        }
+        ServiceError::NotFound => {
+            crate::kprintln!("Syscall Error: service does not exist");
+            SyscallResult::ServiceNotFound
+        }
+    }
+}
+
+fn get_vma(handle: ExternRef) -> Result<Arc<Vma>, SyscallResult> {
+    let vma_idx = match handle {
+        ExternRef::Vma(vma) => vma,
+        _ => {
+            crate::kprintln!("Syscall Error: expected VMA, got {:?}", handle);
+            return Err(SyscallResult::WrongHandleKind);
+        }
+    };
+    match ACTIVE_VMA.get(vma_idx) {
+        Some(vma) => Ok(vma),
+        None => {
+            crate::kprintln!("Syscall Error: VMA does not exists");
+            Err(SyscallResult::InvalidHandle)
+        }
+    }
+}
+
+/// Returns the blob corresponding to the given handle, if any.
+fn get_blob(handle: ExternRef) -> Result<Arc<Blob>, SyscallResult> {
+    let blob_idx = match handle {
+        ExternRef::Blob(blob) => blob,
+        _ => {
+            crate::kprintln!("Syscall Error: expected blob, got {:?}", handle);
+            return Err(SyscallResult::WrongHandleKind);
+        }
+    };
+    match ACTIVE_BLOBS.get(blob_idx) {
+        Some(blob) => Ok(blob),
+        None => {
+            crate::kprintln!("Syscall Error: blob does not exists");
+            Err(SyscallResult::InvalidHandle)
+        }
+    }
+}
+
+/// Returns a view of the given VMA at the given offset and with the given size.
+fn vma_as_buf(vma: &Vma, offset: u64, size: u64) -> Result<&[u8], SyscallResult> {
+    // TODO: handle permissions here
+    slice_at(vma.as_bytes(), offset, size)
+}
+
+/// Returns a view of the given blob at the given offset and with the given size.
+fn blob_as_buf(blob: &Blob, offset: u64, size: u64) -> Result<&[u8], SyscallResult> {
+    slice_at(blob.as_bytes(), offset, size)
+}
+
+/// Returns the sub-slice at the given offset and with the given size.
+fn slice_at(buf: &[u8], offset: u64, size: u64) -> Result<&[u8], SyscallResult> {
+    let offset = usize::try_from(offset).map_err(|_| SyscallResult::OutOfBounds)?;
+    let size = usize::try_from(size).map_err(|_| SyscallResult::OutOfBounds)?;
+    let end = match offset.checked_add(size) {
+        Some(end) => end,
+        None => return Err(SyscallResult::OutOfBounds),
+    };
+
+    if buf.len() < end {
+        Err(SyscallResult::OutOfBounds)
+    } else {
+        Ok(&buf[offset..end])
+    }
+}
+
+/// Returns the mutable sub-slice at the given offset and with the given size.
+fn slice_at_mut(buf: &mut [u8], offset: u64, size: u64) -> Result<&mut [u8], SyscallResult> {
+    let offset = usize::try_from(offset).map_err(|_| SyscallResult::OutOfBounds)?;
+    let size = usize::try_from(size).map_err(|_| SyscallResult::OutOfBounds)?;
+    let end = match offset.checked_add(size) {
+        Some(end) => end,
+        None => return Err(SyscallResult::OutOfBounds),
+    };
+
+    if buf.len() < end {
+        Err(SyscallResult::OutOfBounds)
+    } else {
+        Ok(&mut buf[offset..end])
+    }
+}
+
+/// A formatter writing to a fixed-size buffer, fails if the buffer is full.
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+}
+
+impl<'a> Write for SliceWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        let end = self.pos + bytes.len();
+        if end > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+/// Logs an invalid VMA state transition.
+fn vma_state_error(err: VmaStateError) -> SyscallResult {
+    match err {
+        VmaStateError::Borrowed => {
+            crate::kprintln!("Syscall Error: VMA is borrowed");
+            SyscallResult::VmaBorrowed
+        }
+        VmaStateError::Sealed => {
+            crate::kprintln!("Syscall Error: VMA is sealed");
+            SyscallResult::VmaSealed
+        }
+        VmaStateError::Mapping => {
+            crate::kprintln!("Syscall Error: failed to re-map VMA");
+            SyscallResult::InternalError
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn syscall_result_encoding() {
+        assert_eq!(SyscallResult::Success.into_abi(), 0);
+        assert_eq!(SyscallResult::OutOfBounds.into_abi(), 0x2_0000_0001);
+        assert_eq!(SyscallResult::OutOfBounds.domain(), ErrorDomain::Memory);
+        assert_eq!(SyscallResult::OutOfBounds.code(), 1);
+        assert_eq!(SyscallResult::ServiceDenied.into_abi(), 0x8_0000_0004);
+        assert_eq!(SyscallResult::ServiceDenied.domain(), ErrorDomain::Service);
+        assert_eq!(
+            SyscallResult::from_abi(0x4_0000_0002),
+            SyscallResult::CompilationFailed
+        );
+        assert_eq!(
+            SyscallResult::from_abi(0x7fff_0000_0001),
+            SyscallResult::UnknownError
+        );
+    }
+
+    #[test_case]
+    fn externref_encoding() {
+        let component = ExternRef::Component(KoIndex::new(7, 0));
+        assert_eq!(component.into_abi(), 0x0300_0000_0000_0007);
+        assert!(matches!(
+            ExternRef::from_abi(component.into_abi()),
+            ExternRef::Component(idx) if idx.into_usize() == 7
+        ));
+        assert_eq!(ExternRef::Invalid.into_abi(), 0);
+        assert!(matches!(ExternRef::from_abi(0), ExternRef::Invalid));
+
+        // The generation of the slot is part of the handle
+        let reused = ExternRef::Component(KoIndex::new(7, 1));
+        assert_eq!(reused.into_abi(), 0x0300_0001_0000_0007);
+        assert!(matches!(
+            ExternRef::from_abi(reused.into_abi()),
+            ExternRef::Component(idx) if idx.into_usize() == 7 && idx.generation() == 1
+        ));
+
+        // Unknown tags and untagged values are invalid
+        assert!(matches!(
+            ExternRef::from_abi(0x7f00_0000_0000_0001),
+            ExternRef::Invalid
+        ));
+        assert!(matches!(ExternRef::from_abi(7), ExternRef::Invalid));
+    }
+
+    #[test_case]
+    fn restricted_syscall_module() {
+        use wasm::Module;
+
+        let module = build_restricted_syscall_module(|name| name == "task_yield");
+        let items = module.public_items();
+        assert!(items.contains_key("task_yield"));
+        assert!(items.contains_key("handles"));
+        assert!(!items.contains_key("vma_write"));
+        assert_eq!(items.len(), 2);
     }
 }
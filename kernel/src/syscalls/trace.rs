@@ -0,0 +1,383 @@
+//! Syscall Tracing
+//!
+//! Syscalls can be traced on a per-component basis. When tracing is active, each syscall records
+//! its name, arguments, result and duration (in CPU cycles) into a global ring buffer. Handles are
+//! redacted to their kind and index, so that the trace never leaks raw kernel values.
+//!
+//! Independently of tracing, the number of invocations of each traced syscall is always counted.
+
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use super::{ExternRef, HandleKind, SyscallResult};
+use wasm::WasmType;
+
+/// Number of records kept in the trace buffer, older records are overwritten.
+const TRACE_CAPACITY: usize = 64;
+/// Maximum number of arguments or return values recorded per syscall.
+const MAX_TRACE_VALUES: usize = 5;
+/// Maximum number of distinct syscalls counted, further syscalls are not counted.
+const MAX_COUNTED_SYSCALLS: usize = 64;
+
+/// The global trace buffer.
+static TRACE_BUFFER: Mutex<TraceBuffer> = Mutex::new(TraceBuffer::new());
+
+/// Wether the currently executing component has tracing enabled.
+static IS_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The number of invocations of each syscall since boot.
+static SYSCALL_COUNTS: Mutex<SyscallCounts> = Mutex::new(SyscallCounts::new());
+
+// ———————————————————————————————— Tracing ————————————————————————————————— //
+
+/// Enable or disable tracing for the code about to be executed, returns the previous value.
+///
+/// This is called by components before running, so that only syscalls emitted by components with
+/// tracing enabled are recorded.
+pub fn set_active(active: bool) -> bool {
+    IS_ACTIVE.swap(active, Ordering::SeqCst)
+}
+
+/// Returns true if syscalls should be traced.
+pub fn is_active() -> bool {
+    IS_ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Executes a syscall and records it into the trace buffer.
+pub fn trace<R, F>(name: &'static str, args: TraceValues, syscall: F) -> R
+where
+    R: Traceable,
+    F: FnOnce() -> R,
+{
+    let start = unsafe { _rdtsc() };
+    let result = syscall();
+    let cycles = unsafe { _rdtsc() } - start;
+
+    let mut ret = TraceValues::new();
+    result.record(&mut ret);
+    TRACE_BUFFER.lock().push(TraceRecord {
+        name,
+        args,
+        ret,
+        cycles,
+    });
+    result
+}
+
+/// Calls `f` on each record of the trace buffer, from the oldest to the most recent.
+pub fn for_each_record<F>(f: F)
+where
+    F: FnMut(&TraceRecord) -> Result<(), ()>,
+{
+    TRACE_BUFFER.lock().for_each(f);
+}
+
+/// Counts an invocation of a syscall.
+pub fn count(name: &'static str) {
+    SYSCALL_COUNTS.lock().increment(name);
+}
+
+/// Calls `f` with the name and the number of invocations of each syscall invoked since boot, in
+/// order of first invocation.
+pub fn for_each_count<F>(f: F)
+where
+    F: FnMut(&'static str, u64),
+{
+    SYSCALL_COUNTS.lock().for_each(f);
+}
+
+/// Wraps a syscall into a function that records the call when tracing is active.
+///
+/// The wrapper has the exact same signature as the syscall, and can therefore be passed to
+/// `as_native_func!` in place of the syscall.
+#[macro_export]
+macro_rules! traced_syscall {
+    ($syscall:ident => $traced:ident($($arg:ident: $ty:ty),*) -> $ret:ty) => {
+        fn $traced($($arg: $ty),*) -> $ret {
+            $crate::syscalls::trace::count(stringify!($syscall));
+            if !$crate::syscalls::trace::is_active() {
+                return $syscall($($arg),*);
+            }
+
+            #[allow(unused_mut)]
+            let mut args = $crate::syscalls::trace::TraceValues::new();
+            $($crate::syscalls::trace::Traceable::record(&$arg, &mut args);)*
+            $crate::syscalls::trace::trace(stringify!($syscall), args, || $syscall($($arg),*))
+        }
+    };
+}
+
+// ————————————————————————————— Trace Records —————————————————————————————— //
+
+/// A single syscall invocation.
+#[derive(Clone, Copy)]
+pub struct TraceRecord {
+    name: &'static str,
+    args: TraceValues,
+    ret: TraceValues,
+    cycles: u64,
+}
+
+/// A value recorded as part of a trace.
+#[derive(Clone, Copy)]
+pub enum TraceValue {
+    /// A handle, redacted to its kind and index.
+    Handle { kind: HandleKind, index: u32 },
+    /// A plain integer.
+    Int(u64),
+    /// The status of a syscall.
+    Result(SyscallResult),
+    /// A kind of handle.
+    Kind(HandleKind),
+}
+
+/// A bounded list of trace values.
+#[derive(Clone, Copy)]
+pub struct TraceValues {
+    values: [TraceValue; MAX_TRACE_VALUES],
+    len: usize,
+}
+
+impl TraceValues {
+    pub const fn new() -> Self {
+        Self {
+            values: [TraceValue::Int(0); MAX_TRACE_VALUES],
+            len: 0,
+        }
+    }
+
+    /// Records a value, silently dropped if the list is already full.
+    pub fn push(&mut self, value: TraceValue) {
+        if self.len < MAX_TRACE_VALUES {
+            self.values[self.len] = value;
+            self.len += 1;
+        }
+    }
+
+    fn as_slice(&self) -> &[TraceValue] {
+        &self.values[..self.len]
+    }
+}
+
+/// A trait for values that can be recorded in a syscall trace.
+pub trait Traceable {
+    fn record(&self, values: &mut TraceValues);
+}
+
+impl Traceable for ExternRef {
+    fn record(&self, values: &mut TraceValues) {
+        let handle = self.into_handle();
+        values.push(TraceValue::Handle {
+            kind: HandleKind::from_abi(handle.tag() as u32),
+            index: handle.index(),
+        });
+    }
+}
+
+impl Traceable for u64 {
+    fn record(&self, values: &mut TraceValues) {
+        values.push(TraceValue::Int(*self));
+    }
+}
+
+impl Traceable for u32 {
+    fn record(&self, values: &mut TraceValues) {
+        values.push(TraceValue::Int(*self as u64));
+    }
+}
+
+impl Traceable for SyscallResult {
+    fn record(&self, values: &mut TraceValues) {
+        values.push(TraceValue::Result(*self));
+    }
+}
+
+impl Traceable for HandleKind {
+    fn record(&self, values: &mut TraceValues) {
+        values.push(TraceValue::Kind(*self));
+    }
+}
+
+impl<A, B> Traceable for (A, B)
+where
+    A: Traceable,
+    B: Traceable,
+{
+    fn record(&self, values: &mut TraceValues) {
+        self.0.record(values);
+        self.1.record(values);
+    }
+}
+
+impl<A, B, C> Traceable for (A, B, C)
+where
+    A: Traceable,
+    B: Traceable,
+    C: Traceable,
+{
+    fn record(&self, values: &mut TraceValues) {
+        self.0.record(values);
+        self.1.record(values);
+        self.2.record(values);
+    }
+}
+
+impl fmt::Display for TraceValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceValue::Handle { kind, index } => write!(f, "{}#{}", kind.as_str(), index),
+            TraceValue::Int(x) => write!(f, "0x{:x}", x),
+            TraceValue::Result(result) => write!(f, "{}", result.as_str()),
+            TraceValue::Kind(kind) => write!(f, "{}", kind.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (idx, arg) in self.args.as_slice().iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", arg)?;
+        }
+        write!(f, ") ->")?;
+        for ret in self.ret.as_slice() {
+            write!(f, " {}", ret)?;
+        }
+        write!(f, " [{} cycles]", self.cycles)
+    }
+}
+
+// —————————————————————————————— Ring Buffer ——————————————————————————————— //
+
+/// A fixed capacity ring buffer of trace records.
+struct TraceBuffer {
+    records: [Option<TraceRecord>; TRACE_CAPACITY],
+    /// Index of the next record to write.
+    next: usize,
+}
+
+impl TraceBuffer {
+    const fn new() -> Self {
+        Self {
+            records: [None; TRACE_CAPACITY],
+            next: 0,
+        }
+    }
+
+    /// Pushes a record, overwriting the oldest one if the buffer is full.
+    fn push(&mut self, record: TraceRecord) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % TRACE_CAPACITY;
+    }
+
+    /// Iterates over the records from the oldest to the most recent, stops on the first error.
+    fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&TraceRecord) -> Result<(), ()>,
+    {
+        let (recent, old) = self.records.split_at(self.next);
+        for record in old.iter().chain(recent.iter()).flatten() {
+            if f(record).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// ————————————————————————————— Syscall Counts ————————————————————————————— //
+
+/// The number of invocations of each syscall, in a fixed capacity table so that counting never
+/// allocates.
+struct SyscallCounts {
+    counts: [Option<(&'static str, u64)>; MAX_COUNTED_SYSCALLS],
+}
+
+impl SyscallCounts {
+    const fn new() -> Self {
+        Self {
+            counts: [None; MAX_COUNTED_SYSCALLS],
+        }
+    }
+
+    /// Increments the count of a syscall, which is dropped if the table is full.
+    fn increment(&mut self, name: &'static str) {
+        for slot in self.counts.iter_mut() {
+            match slot {
+                Some((counted, count)) if *counted == name => {
+                    *count += 1;
+                    return;
+                }
+                Some(_) => continue,
+                None => {
+                    *slot = Some((name, 1));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&'static str, u64),
+    {
+        for (name, count) in self.counts.iter().flatten() {
+            f(name, *count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cycles: u64) -> TraceRecord {
+        TraceRecord {
+            name: "test",
+            args: TraceValues::new(),
+            ret: TraceValues::new(),
+            cycles,
+        }
+    }
+
+    #[test_case]
+    fn ring_buffer() {
+        let mut buffer = TraceBuffer::new();
+        for cycles in 0..(TRACE_CAPACITY + 2) as u64 {
+            buffer.push(record(cycles));
+        }
+
+        // The two oldest records must have been overwritten
+        let mut expected = 2;
+        buffer.for_each(|record| {
+            assert_eq!(record.cycles, expected);
+            expected += 1;
+            Ok(())
+        });
+        assert_eq!(expected, (TRACE_CAPACITY + 2) as u64);
+    }
+
+    #[test_case]
+    fn syscall_counts() {
+        let mut counts = SyscallCounts::new();
+        counts.increment("vma_write");
+        counts.increment("vma_read");
+        counts.increment("vma_write");
+        for idx in 0..MAX_COUNTED_SYSCALLS {
+            // Syscall names are static, leak the names of the test
+            let name = alloc::boxed::Box::leak(alloc::format!("syscall_{}", idx).into_boxed_str());
+            counts.increment(name);
+        }
+
+        let mut seen = alloc::vec::Vec::new();
+        counts.for_each(|name, count| seen.push((name, count)));
+        assert_eq!(seen.len(), MAX_COUNTED_SYSCALLS);
+        assert_eq!(seen[0], ("vma_write", 2));
+        assert_eq!(seen[1], ("vma_read", 1));
+    }
+}
@@ -0,0 +1,74 @@
+//! Test Devices
+//!
+//! The test harness reports its output and exit code through a test device. QEMU provides the
+//! `isa-debug-exit` device to exit with a custom code, but other virtual machine monitors (such as
+//! Cloud Hypervisor or Firecracker) do not, in which case the exit code is printed before powering
+//! off and must be recovered from the logs.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::qemu::{ExitCode, Qemu};
+use crate::{power, serial};
+
+/// The device used by the test harness, QEMU's by default.
+static DEVICE: Mutex<&'static dyn TestDevice> = Mutex::new(&Qemu);
+
+/// A mechanism for reporting test results to the host.
+pub trait TestDevice: Sync {
+    /// Writes test output, to the serial interface by default.
+    fn print(&self, args: fmt::Arguments) {
+        serial::_print(args);
+    }
+
+    /// Reports the exit code to the host, and terminates the virtual machine if supported.
+    fn exit(&self, exit_code: ExitCode);
+}
+
+/// A test device for virtual machine monitors without an exit device.
+///
+/// The exit code is printed on a line of its own, prefixed by `EXIT_PREFIX`, before powering off.
+pub struct PowerOff;
+
+impl PowerOff {
+    pub const EXIT_PREFIX: &'static str = "coral-test-exit: ";
+}
+
+impl TestDevice for PowerOff {
+    fn exit(&self, exit_code: ExitCode) {
+        self.print(format_args!(
+            "\n{}{:#x}\n",
+            Self::EXIT_PREFIX,
+            exit_code as u32
+        ));
+        power::shutdown();
+    }
+}
+
+/// Selects the test device, from the `CORAL_TEST_DEVICE` environment variable at build time.
+///
+/// Supported values are `qemu` (the default) and `poweroff`.
+pub fn init() {
+    match option_env!("CORAL_TEST_DEVICE") {
+        None | Some("qemu") => select(&Qemu),
+        Some("poweroff") => select(&PowerOff),
+        Some(device) => panic!("Unknown test device: {}", device),
+    }
+}
+
+/// Replaces the device used by the test harness.
+pub fn select(device: &'static dyn TestDevice) {
+    *DEVICE.lock() = device;
+}
+
+/// Returns the device used by the test harness.
+pub fn device() -> &'static dyn TestDevice {
+    // Release the lock before using the device, which might panic
+    *DEVICE.lock()
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    device().print(args);
+}
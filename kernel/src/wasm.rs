@@ -4,18 +4,74 @@ use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
 
+use crate::allocator;
+use crate::env::Environment;
+use crate::events;
+use crate::fiber::{self, Fiber, FiberState, Suspend};
 use crate::kprintln;
-use crate::memory::Vma;
+use crate::memory::{Vma, VmaStateError};
 use crate::runtime::get_runtime;
-use crate::scheduler::Task;
-use collections::{entity_impl, PrimaryMap};
-use wasm::{FuncIndex, Instance, Module, ModuleResult};
+use crate::scheduler::{self, Task};
+use crate::services::ServicePermissions;
+use crate::syscalls::trace;
+use collections::{entity_impl, PrimaryMap, SecondaryMap};
+use wasm::{
+    AllocPolicy, Args, ExitStatus, FuncIndex, FuncType, HeapIndex, Instance, InstanceMetrics,
+    ItemRef, Module, ModuleError, ModuleImage, ModuleResult, Placement, TrapCode, ValueType,
+};
 
 use spin::{Mutex, MutexGuard};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// The placement group of the next component.
+static NEXT_PLACEMENT_GROUP: AtomicU64 = AtomicU64::new(0);
+
+/// The component currently executing, null if no component is executing.
+static CURRENT_COMPONENT: AtomicPtr<Component> = AtomicPtr::new(ptr::null_mut());
+
+/// Executes `f` on the component currently executing, if any.
+pub fn with_current_component<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&Component) -> R,
+{
+    let component = CURRENT_COMPONENT.load(Ordering::SeqCst);
+    if component.is_null() {
+        return None;
+    }
+
+    // SAFETY: the component is borrowed for as long as it is the current component.
+    Some(f(unsafe { &*component }))
+}
 
 pub struct Component {
     inner: Mutex<InnerComponent>,
+    /// Whether a function of this component is executing, executions are serialized.
+    busy: AtomicBool,
+    /// The tasks waiting for the component to be available.
+    waiters: Mutex<Vec<Waker>>,
+    /// Wether syscalls emitted by this component are traced.
+    syscall_tracing: AtomicBool,
+    /// The allocation policy used when instantiating modules within this component.
+    policy: AllocPolicy,
+    /// The environment variables of this component.
+    env: Mutex<Environment>,
+    /// The exit status of the last execution of each instance, if any.
+    exits: Mutex<SecondaryMap<InstanceIndex, Option<ExitStatus>>>,
+    /// The exit status of the last start function which trapped, if any. The instance was
+    /// discarded, so the status is not tied to an instance index.
+    start_trap: Mutex<Option<ExitStatus>>,
+    /// Privileged components are never killed to reclaim memory.
+    privileged: bool,
+    /// The services the component can register and lookup.
+    services: ServicePermissions,
+    /// Whether the component has been killed, its instances are then discarded.
+    killed: AtomicBool,
 }
 
 struct InnerComponent {
@@ -30,6 +86,14 @@ struct InnerComponent {
 pub struct InstanceIndex(u32);
 entity_impl!(InstanceIndex);
 
+/// A module of a bundle, see `Component::add_bundle`.
+pub struct BundleMember<'a, M> {
+    pub module: &'a M,
+    pub image: Option<&'a ModuleImage<Arc<Vma>>>,
+    /// The imports bound to a previous module of the bundle, identified by its index.
+    pub links: &'a [(String, usize)],
+}
+
 /// The ID of a function withing a component.
 #[derive(Clone, Copy)]
 pub struct ComponentFunc {
@@ -37,10 +101,29 @@ pub struct ComponentFunc {
     func: FuncIndex,
 }
 
+/// The reasons a nested call can not be performed, see `Component::call_nested`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    /// The instance does not exist.
+    InvalidInstance,
+    /// The instance does not export a function with that name.
+    MissingExport,
+    /// The arguments do not match the type of the function.
+    TypeMismatch,
+    /// The instance is already running, for instance because it issued the current syscall.
+    Reentrant,
+    /// The component is already executing, for instance because one of its instances issued the
+    /// current syscall.
+    Busy,
+}
+
 #[must_use]
 pub enum RunStatus {
     Ok,
     Busy,
+    Trapped,
+    /// The component has been killed, the function was not executed.
+    Killed,
 }
 
 impl RunStatus {
@@ -49,264 +132,986 @@ impl RunStatus {
 }
 
 impl Component {
+    /// Creates a component whose instances are placed next to each other, without quota.
     pub fn new() -> Self {
-        let component = Self {
+        let group = NEXT_PLACEMENT_GROUP.fetch_add(1, Ordering::SeqCst);
+        Self::with_policy(AllocPolicy {
+            placement: Placement::Grouped(group),
+            ..AllocPolicy::default()
+        })
+    }
+
+    /// Creates a component with a custom allocation policy.
+    ///
+    /// The quota of the policy, if any, is shared by all the instances of the component.
+    pub fn with_policy(policy: AllocPolicy) -> Self {
+        Self {
             inner: Mutex::new(InnerComponent {
                 instances: PrimaryMap::new(),
                 next_imports: Vec::new(),
             }),
+            busy: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            syscall_tracing: AtomicBool::new(false),
+            policy,
+            env: Mutex::new(Environment::new()),
+            exits: Mutex::new(SecondaryMap::new()),
+            start_trap: Mutex::new(None),
+            privileged: false,
+            services: ServicePermissions::none(),
+            killed: AtomicBool::new(false),
+        }
+    }
+
+    /// Marks the component as privileged: it is never killed to reclaim memory.
+    pub fn privileged(mut self) -> Self {
+        self.privileged = true;
+        self
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        self.privileged
+    }
+
+    /// Sets the services the component can register and lookup, by default none.
+    pub fn with_services(mut self, services: ServicePermissions) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn services(&self) -> &ServicePermissions {
+        &self.services
+    }
+
+    /// Returns true if an execution of the component is in progress.
+    pub fn is_busy(&self) -> bool {
+        self.busy.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the component has been killed.
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    /// Kills the component: its instances and imports are discarded, no instance can be added
+    /// anymore and future executions return `ExitStatus::Killed`. Executions in progress run to
+    /// completion, the memory of their instance is released afterward.
+    ///
+    /// Returns false if the instances are locked (e.g. by an instantiation in progress), in which
+    /// case the component is left untouched.
+    pub fn try_kill(&self) -> bool {
+        let mut component = match self.inner.try_lock() {
+            Some(component) => component,
+            None => return false,
         };
+        self.killed.store(true, Ordering::SeqCst);
+        component.instances = PrimaryMap::new();
+        component.next_imports.clear();
+        true
+    }
+
+    /// Returns the number of bytes of memory owned by the instances of this component, or `None`
+    /// if the instances are locked.
+    pub fn try_memory_footprint(&self) -> Option<usize> {
+        let component = self.inner.try_lock()?;
+        let footprint = component
+            .instances
+            .values()
+            .map(|instance| instance.memory_footprint())
+            .sum();
+        Some(footprint)
+    }
+
+    /// Returns the environment variables of this component.
+    pub fn env(&self) -> MutexGuard<Environment> {
+        self.env.lock()
+    }
+
+    /// Replaces the environment of this component by a copy of the environment of `parent`.
+    pub fn inherit_env(&self, parent: &Component) {
+        let env = parent.env().clone();
+        *self.env() = env;
+    }
 
-        component
+    /// Enables or disables syscall tracing for this component.
+    pub fn set_syscall_tracing(&self, enabled: bool) {
+        self.syscall_tracing.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Returns the instance and function whose code contains `ip`, if any.
+    ///
+    /// This can be called from an interrupt handler: `None` is returned if the instances are
+    /// locked rather than waiting for them.
+    pub fn locate_func(&self, ip: u64) -> Option<(InstanceIndex, FuncIndex)> {
+        let component = self.inner.try_lock()?;
+        component.instances.iter().find_map(|(idx, instance)| {
+            let (_, location) = instance.locate_trap(ip as usize)?;
+            Some((idx, location.func))
+        })
     }
 
     /// Add an import, which can be used by instances during future instantiations.
-    pub fn push_import(&self, name: String, idx: InstanceIndex) {
+    ///
+    /// Imports can not form cycles: the imported instance already exists, and instances are
+    /// bound to their imports once and for all when instantiated. Reloading an instance does not
+    /// rebind the instances which imported the previous one.
+    ///
+    /// Returns false if the component has no such instance.
+    pub fn push_import(&self, name: String, idx: InstanceIndex) -> bool {
         let mut component = self.lock();
-        let instance = Arc::clone(&component.instances[idx]);
-        component.next_imports.push((name, instance));
+        // The instance has been discarded if the component was killed
+        match component.instances.get(idx) {
+            Some(instance) => {
+                let instance = Arc::clone(instance);
+                component.next_imports.push((name, instance));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the import `name` of this component available to the future instantiations of
+    /// `target`, which then shares the imported instance with this component.
+    ///
+    /// Returns false if this component has no such import.
+    pub fn share_import(&self, name: &str, target: &Component) -> bool {
+        // Pick the first matching import, as done when resolving imports
+        let instance = match self.lock().next_imports.iter().find(|(n, _)| n == name) {
+            Some((_, instance)) => Arc::clone(instance),
+            None => return false,
+        };
+        target
+            .lock()
+            .next_imports
+            .push((String::from(name), instance));
+        true
+    }
+
+    /// Returns true if this component has an import named `name`.
+    pub fn has_import(&self, name: &str) -> bool {
+        self.lock().next_imports.iter().any(|(n, _)| n == name)
+    }
+
+    /// Returns true if the import `name` of this component exports a function named `func`.
+    pub fn import_exports_func(&self, name: &str, func: &str) -> bool {
+        // Pick the first matching import, as done when resolving imports
+        match self.lock().next_imports.iter().find(|(n, _)| n == name) {
+            Some((_, instance)) => instance.get_func_index_by_name(func).is_some(),
+            None => false,
+        }
     }
 
     /// Add an instance to this component.
+    ///
+    /// The start function of the instance, if any, is executed before the instance is added to the
+    /// component. If it traps the instance is discarded and `ModuleError::StartTrapped` returned.
     pub fn add_instance(&self, module: &impl Module) -> ModuleResult<InstanceIndex> {
-        let runtime = get_runtime();
+        self.add_instance_from_image(module, None)
+    }
+
+    /// Add an instance to this component, whose heaps are forked from the image of the module if
+    /// any (see `ModuleImage`), and otherwise behaves as `add_instance`.
+    pub fn add_instance_from_image(
+        &self,
+        module: &impl Module,
+        image: Option<&ModuleImage<Arc<Vma>>>,
+    ) -> ModuleResult<InstanceIndex> {
+        let instance = {
+            let component = self.lock();
+            if self.is_killed() {
+                return Err(ModuleError::FailedToInstantiate);
+            }
+            self.instantiate(&component, module, image, &[])?
+        };
+        let start_status = self.run_start(&instance)?;
+
+        // The component might have been killed while the start function executed
         let mut component = self.lock();
+        if self.is_killed() {
+            return Err(ModuleError::FailedToInstantiate);
+        }
+        let idx = component.instances.push(Arc::new(instance));
+        self.exits.lock()[idx] = start_status;
+        Ok(idx)
+    }
+
+    /// Adds the instances of a bundle of modules to this component, in order, and returns the index
+    /// of the first one. The instances of the bundle get consecutive indices.
+    ///
+    /// The linked imports of each module are bound to the instances of the previous modules of the
+    /// bundle, and take precedence over the imports of the component. The start functions are
+    /// executed as for `add_instance`, and either all the instances are added or none of them.
+    pub fn add_bundle<M: Module>(
+        &self,
+        members: &[BundleMember<'_, M>],
+    ) -> ModuleResult<InstanceIndex> {
+        let mut instances: Vec<(Arc<Instance<Arc<Vma>>>, Option<ExitStatus>)> =
+            Vec::with_capacity(members.len());
+        for member in members {
+            let links = member
+                .links
+                .iter()
+                .map(|(name, idx)| match instances.get(*idx) {
+                    Some((instance, _)) => Ok((name.as_str(), Arc::clone(instance))),
+                    None => Err(ModuleError::FailedToInstantiate),
+                })
+                .collect::<ModuleResult<Vec<_>>>()?;
+            let instance = {
+                let component = self.lock();
+                if self.is_killed() {
+                    return Err(ModuleError::FailedToInstantiate);
+                }
+                self.instantiate(&component, member.module, member.image, &links)?
+            };
+            let start_status = self.run_start(&instance)?;
+            instances.push((Arc::new(instance), start_status));
+        }
+
+        // The component might have been killed while the start functions executed
+        let mut component = self.lock();
+        if self.is_killed() {
+            return Err(ModuleError::FailedToInstantiate);
+        }
+        let first = component.instances.next_key();
+        let mut exits = self.exits.lock();
+        for (instance, start_status) in instances {
+            let idx = component.instances.push(instance);
+            exits[idx] = start_status;
+        }
+        Ok(first)
+    }
+
+    /// Replaces an instance of this component by a new instance of `module`, from the image of the
+    /// module if any, keeping its index.
+    ///
+    /// The new instance starts afresh with the current imports of the component, and its start
+    /// function is executed as for `add_instance`. Function handles (e.g. event listeners) refer
+    /// to functions by index: the functions exported by the previous instance must be exported by
+    /// the new one under the same name, index and type, otherwise `ModuleError::TypeError` is
+    /// returned. Future instantiations import the new instance, but the instances which imported
+    /// the previous one keep using it.
+    ///
+    /// Returns `None` if the instance does not exist.
+    pub fn reload_instance(
+        &self,
+        idx: InstanceIndex,
+        module: &impl Module,
+        image: Option<&ModuleImage<Arc<Vma>>>,
+    ) -> Option<ModuleResult<()>> {
+        let (previous, result) = {
+            let component = self.lock();
+            // Killed components have no instances left
+            let previous = Arc::clone(component.instances.get(idx)?);
+            let result = self.instantiate(&component, module, image, &[]);
+            (previous, result)
+        };
+        let result = result.and_then(|instance| {
+            if !same_exported_funcs(&previous, &instance) {
+                kprintln!("WARNING: reloaded instance does not preserve exported functions");
+                return Err(ModuleError::TypeError);
+            }
+            let start_status = self.run_start(&instance)?;
+            Ok((instance, start_status))
+        });
+        let (instance, start_status) = match result {
+            Ok(reloaded) => reloaded,
+            Err(err) => return Some(Err(err)),
+        };
+
+        // The instance might have been killed or reloaded while the start function executed
+        let mut component = self.lock();
+        match component.instances.get(idx) {
+            Some(current) if Arc::ptr_eq(current, &previous) => (),
+            _ => return Some(Err(ModuleError::FailedToInstantiate)),
+        }
+        let instance = Arc::new(instance);
+        for (_, import) in component.next_imports.iter_mut() {
+            if Arc::ptr_eq(import, &previous) {
+                *import = Arc::clone(&instance);
+            }
+        }
+        component.instances[idx] = instance;
+        self.exits.lock()[idx] = start_status;
+        Some(Ok(()))
+    }
+
+    /// Instantiates a module with the imports of this component, from the image of the module if
+    /// any. The `links` are resolved before the imports of the component.
+    fn instantiate(
+        &self,
+        component: &InnerComponent,
+        module: &impl Module,
+        image: Option<&ModuleImage<Arc<Vma>>>,
+        links: &[(&str, Arc<Instance<Arc<Vma>>>)],
+    ) -> ModuleResult<Instance<Arc<Vma>>> {
         // TODO: find a more elegant way of resolving imports
-        let imports: Vec<(&str, Arc<Instance<Arc<Vma>>>)> = component
-            .next_imports
+        let imports: Vec<(&str, Arc<Instance<Arc<Vma>>>)> = links
             .iter()
-            .map(|(name, instance)| (name.as_str(), instance.clone()))
+            .cloned()
+            .chain(
+                component
+                    .next_imports
+                    .iter()
+                    .map(|(name, instance)| (name.as_str(), instance.clone())),
+            )
             .collect();
-        let instance = Arc::new(Instance::instantiate(module, &imports, runtime)?);
-        let idx = component.instances.push(instance);
-        if let Some(func) = component.instances[idx].get_start() {
-            let func = ComponentFunc {
-                instance: idx,
-                func,
-            };
-            match self.try_run(func, &Args::new()) {
-                RunStatus::Ok => {} // Fine
-                RunStatus::Busy => {
-                    // TODO: How can we run init while the component is already executing?
-                    kprintln!("WARNING: component is buzy, instance can't be initialized");
-                    todo!("Handle buzy component initialization");
-                }
+        match image {
+            Some(image) => Instance::instantiate_from_image(
+                module,
+                image,
+                &imports,
+                get_runtime(),
+                &self.policy,
+            ),
+            None => {
+                Instance::instantiate_with_policy(module, &imports, get_runtime(), &self.policy)
             }
         }
-        Ok(idx)
     }
 
-    /// Get a function handle.
+    /// Executes the start function of a new instance, if any, and returns its exit status.
+    ///
+    /// The component must not be locked by the caller: the start function can call back into the
+    /// component, for instance through the syscalls sharing its imports. The instance is not part
+    /// of the component yet, so the start function is called directly rather than going through
+    /// `try_run`.
+    fn run_start(&self, instance: &Instance<Arc<Vma>>) -> ModuleResult<Option<ExitStatus>> {
+        let start = match instance.take_start() {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        let result = self.enter(|| call_instance(instance, start, &Args::new()));
+        if let Err(trap) = result {
+            let status = trap.status(instance);
+            kprintln!(
+                "WARNING: start function trapped, instance discarded: {:?}",
+                status
+            );
+            *self.start_trap.lock() = Some(status);
+            return Err(ModuleError::StartTrapped);
+        }
+        Ok(Some(ExitStatus::Returned(Vec::new())))
+    }
+
+    /// Forks an instance of this component into `target`, which may be this component, and returns
+    /// the index of the new instance.
+    ///
+    /// The heaps of the new instance are copy-on-write copies of the heaps of the forked instance,
+    /// and its globals are reset to their initial values (see `Instance::fork`). The new instance
+    /// shares the imports of the forked instance, even if it belongs to another component, and its
+    /// start function is not executed again. The memory is charged to the policy of `target`.
+    ///
+    /// Returns `None` if the instance does not exist.
+    pub fn fork_instance(
+        &self,
+        idx: InstanceIndex,
+        target: &Component,
+    ) -> Option<ModuleResult<InstanceIndex>> {
+        let instance = Arc::clone(self.lock().instances.get(idx)?);
+        let fork = match instance.fork(get_runtime(), &target.policy) {
+            Ok(fork) => fork,
+            Err(err) => return Some(Err(err)),
+        };
+        let mut target_component = target.lock();
+        if target.is_killed() {
+            return Some(Err(ModuleError::FailedToInstantiate));
+        }
+        let fork_idx = target_component.instances.push(Arc::new(fork));
+        drop(target_component);
+        target.exits.lock()[fork_idx] = None;
+        Some(Ok(fork_idx))
+    }
+
+    /// Returns the exit status of the last execution of an instance, or `None` if the instance
+    /// never executed or does not exist.
+    pub fn exit_status(&self, idx: InstanceIndex) -> Option<ExitStatus> {
+        self.exits.lock()[idx].clone()
+    }
+
+    /// Returns the exit status of the last start function which trapped, if any.
+    ///
+    /// This is where the cause of a `ModuleError::StartTrapped` can be found, as the instance is
+    /// discarded.
+    pub fn start_trap(&self) -> Option<ExitStatus> {
+        self.start_trap.lock().clone()
+    }
+
+    /// Returns the execution metrics of an instance, or `None` if the instance does not exist.
+    ///
+    /// Ticks are CPU cycles.
+    pub fn instance_metrics(&self, idx: InstanceIndex) -> Option<InstanceMetrics> {
+        let component = self.lock();
+        Some(component.instances.get(idx)?.metrics())
+    }
+
+    /// Returns an instance of this component.
+    pub fn get_instance(&self, idx: InstanceIndex) -> Arc<Instance<Arc<Vma>>> {
+        let component = self.lock();
+        Arc::clone(&component.instances[idx])
+    }
+
+    /// Returns the type of a function.
+    pub fn get_func_type(&self, func: ComponentFunc) -> FuncType {
+        let component = self.lock();
+        component.instances[func.instance]
+            .get_func_type_by_index(func.func)
+            .clone()
+    }
+
+    /// Get a function handle, returns `None` if either the instance or the function doesn't exist.
     pub fn get_func(&self, func: &str, instance: InstanceIndex) -> Option<ComponentFunc> {
         let component = self.lock();
-        match component.instances[instance].get_func_index_by_name(func) {
+        match component
+            .instances
+            .get(instance)?
+            .get_func_index_by_name(func)
+        {
             Some(func) => Some(ComponentFunc { instance, func }),
             None => None,
         }
     }
 
+    /// Runs a function to completion, returns `RunStatus::Busy` if the component is already
+    /// executing.
+    ///
+    /// The execution can not be suspended, see `run` for suspendable executions.
     pub fn try_run(&self, func: ComponentFunc, args: &Args) -> RunStatus {
-        let mut component = match self.inner.try_lock() {
-            Some(inner) => inner,
-            None => {
-                return RunStatus::Busy;
-            }
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return RunStatus::Busy;
+        }
+        if self.is_killed() {
+            self.release();
+            return RunStatus::Killed;
+        }
+
+        let instance = self.get_instance(func.instance);
+        let result = self.enter(|| call_instance(&instance, func.func, args));
+        let status = match self.record_exit(func.instance, &instance, result) {
+            ExitStatus::Returned(_) => RunStatus::Ok,
+            _ => RunStatus::Trapped,
         };
+        self.release();
+        status
+    }
 
-        component.call(func, args);
+    /// Calls an exported function of one of the instances of this component from within the
+    /// kernel, while executing on behalf of another instance (e.g. to invoke a driver callback from
+    /// a syscall), and returns its exit status.
+    ///
+    /// The callee runs with its own VMContext and memory, and becomes the current component for
+    /// the duration of the call; the caller state is restored afterward, even if the callee traps.
+    /// Instances can not be re-entered: the call fails with `CallError::Reentrant` if the instance
+    /// is already running, such as the instance which issued the syscall or any instance on the
+    /// path to it. The component is marked as busy for the duration of the call, which fails with
+    /// `CallError::Busy` if the component is already executing. Nested calls can not suspend
+    /// themselves.
+    pub fn call_nested(
+        &self,
+        idx: InstanceIndex,
+        func: &str,
+        args: &Args,
+    ) -> Result<ExitStatus, CallError> {
+        let instance = match self.lock().instances.get(idx) {
+            Some(instance) => Arc::clone(instance),
+            None => return Err(CallError::InvalidInstance),
+        };
+        let func = instance
+            .get_func_index_by_name(func)
+            .ok_or(CallError::MissingExport)?;
+        if !args.matches(instance.get_func_type_by_index(func)) {
+            return Err(CallError::TypeMismatch);
+        }
 
-        RunStatus::Ok
+        // The instance is entered right after, the kernel does not execute guest code concurrently
+        if instance.is_running() {
+            return Err(CallError::Reentrant);
+        }
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return Err(CallError::Busy);
+        }
+        if self.is_killed() {
+            self.release();
+            return Ok(ExitStatus::Killed);
+        }
+        let result = self.enter(|| call_instance(&instance, func, args));
+        let status = self.record_exit(idx, &instance, result);
+        self.release();
+        Ok(status)
     }
 
+    /// Creates a task running the given function.
+    ///
+    /// The task waits for the previous executions of the component to complete, the function is
+    /// then executed within a fiber so that the guest can suspend itself (e.g. by yielding).
     pub fn run(self: Arc<Self>, func: ComponentFunc, args: Args) -> Task {
-        Task::new(self.run_promise(func, args))
+        Task::new(async move {
+            self.run_promise(func, args).await;
+        })
+    }
+
+    /// Run the given function from a component, and returns its exit status.
+    pub async fn run_promise(self: Arc<Self>, func: ComponentFunc, args: Args) -> ExitStatus {
+        futures::future::poll_fn(|ctx| self.poll_acquire(ctx)).await;
+        if self.is_killed() {
+            self.release();
+            return ExitStatus::Killed;
+        }
+
+        let instance = self.get_instance(func.instance);
+        let component = Arc::clone(&self);
+        let callee = Arc::clone(&instance);
+        let fiber = Fiber::new(get_runtime().vma_allocator(), move || {
+            component.enter(|| call_instance(&callee, func.func, &args))
+        });
+        let mut fiber = match fiber {
+            Ok(fiber) => fiber,
+            Err(()) => {
+                kprintln!("WARNING: not enough memory to allocate a fiber stack");
+                self.release();
+                return ExitStatus::Killed;
+            }
+        };
+
+        let mut guest = GuestState::empty();
+        let result = loop {
+            match guest.resume(&mut fiber) {
+                FiberState::Done(result) => break result,
+                FiberState::Suspended(Suspend::Yield) => scheduler::yield_now().await,
+                FiberState::Suspended(Suspend::Sleep(ms)) => scheduler::sleep_ms(ms).await,
+                FiberState::Suspended(Suspend::Wait(deadline)) => {
+                    events::wait_ready(deadline).await
+                }
+            }
+        };
+
+        let status = self.record_exit(func.instance, &instance, result);
+        if let ExitStatus::Trapped { .. } = status {
+            kprintln!("WARNING: component trapped: {:?}", status);
+        }
+        self.release();
+        status
     }
 
-    /// Run the given function from a component.
-    async fn run_promise(self: Arc<Self>, func: ComponentFunc, args: Args) {
-        match self.try_run(func, &args) {
-            RunStatus::Ok => {}
-            RunStatus::Busy => todo!("Handle busy components"),
+    /// Marks the component as busy, or registers the task to be woken up once the component is
+    /// available.
+    fn poll_acquire(&self, ctx: &mut Context) -> Poll<()> {
+        let mut waiters = self.waiters.lock();
+        if self.busy.swap(true, Ordering::SeqCst) {
+            waiters.push(ctx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(())
         }
     }
 
+    /// Marks the component as available, and wakes up the tasks waiting for it.
+    fn release(&self) {
+        let mut waiters = self.waiters.lock();
+        self.busy.store(false, Ordering::SeqCst);
+        for waker in waiters.drain(..) {
+            waker.wake();
+        }
+        events::notify_ready();
+    }
+
+    /// Records the exit status of an execution of the given instance.
+    fn record_exit(
+        &self,
+        idx: InstanceIndex,
+        instance: &Instance<Arc<Vma>>,
+        result: Result<Vec<u64>, Trap>,
+    ) -> ExitStatus {
+        let status = match result {
+            Ok(values) => ExitStatus::Returned(values),
+            Err(trap) => trap.status(instance),
+        };
+        self.exits.lock()[idx] = Some(status.clone());
+        status
+    }
+
+    /// Executes `f` as this component: it becomes the current component, and syscall tracing is
+    /// enabled if tracing is active for this component.
+    fn enter<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let was_tracing = trace::set_active(self.syscall_tracing.load(Ordering::SeqCst));
+        let previous = CURRENT_COMPONENT.swap(self as *const _ as *mut _, Ordering::SeqCst);
+        let result = f();
+        CURRENT_COMPONENT.store(previous, Ordering::SeqCst);
+        trace::set_active(was_tracing);
+        result
+    }
+
     fn lock(&self) -> MutexGuard<InnerComponent> {
         self.inner.lock()
     }
 }
 
-impl InnerComponent {
-    /// Call an instance function using the SytemV ABI.
-    ///
-    /// See [OsDev wiki](https://wiki.osdev.org/System_V_ABI), [(old but rendered)
-    /// spec](https://www.uclibc.org/docs/psABI-x86_64.pdf), and [newer
-    /// spec](https://gitlab.com/x86-psABIs).
-    fn call(&mut self, func: ComponentFunc, args: &Args) {
-        let args = args.as_slice();
-
-        // Instance pointers
-        let instance = &self.instances[func.instance];
-        let func_ptr = instance.get_func_addr_by_index(func.func);
-        let func_ty = instance.get_func_type_by_index(func.func);
-        let vmctx = instance.get_vmctx_ptr() as u64;
-
-        assert_eq!(
-            func_ty.args().len(),
-            args.len(),
-            "Mismatching types, should have been typechecked earlier!"
-        );
-        assert!(
-            func_ty.ret().len() <= 2,
-            "Returning more than 2 values from instances is not yet supported"
-        );
-
-        // Registers used to pass arguments
-        let rdi;
-        let mut rsi = 0;
-        let mut rdx = 0;
-        let mut rcx = 0;
-        let mut r8 = 0;
-        let mut r9 = 0;
-
-        match args.len() {
-            0 => rdi = vmctx,
-            1 => {
-                rdi = args[0];
-                rsi = vmctx;
-            }
-            2 => {
-                rdi = args[0];
-                rsi = args[1];
-                rdx = vmctx;
-            }
-            3 => {
-                rdi = args[0];
-                rsi = args[1];
-                rdx = args[2];
-                rcx = vmctx;
-            }
-            4 => {
-                rdi = args[0];
-                rsi = args[1];
-                rdx = args[2];
-                rcx = args[3];
-                r8 = vmctx;
-            }
-            5 => {
-                rdi = args[0];
-                rsi = args[1];
-                rdx = args[2];
-                rcx = args[3];
-                r8 = args[4];
-                r9 = vmctx;
+/// Returns true if all the functions exported by `previous` are exported by `instance` under the
+/// same name, index and type.
+fn same_exported_funcs(previous: &Instance<Arc<Vma>>, instance: &Instance<Arc<Vma>>) -> bool {
+    previous
+        .public_items()
+        .iter()
+        .all(|(name, item)| match item {
+            ItemRef::Func(func) => {
+                if instance.get_func_index_by_name(name) != Some(*func) {
+                    return false;
+                }
+                let previous_ty = previous.get_func_type_by_index(*func);
+                let ty = instance.get_func_type_by_index(*func);
+                ty.args() == previous_ty.args() && ty.ret() == previous_ty.ret()
             }
-            // Other registers must be passed on the stack.
-            // We will implement that once each component/instance has its own stack.
-            _ => todo!("At most 5 arguments can be passed for now"),
-        }
+            _ => true,
+        })
+}
 
-        unsafe {
-            asm!(
-                "call {func_ptr}",
-                func_ptr = in(reg) func_ptr,
-                // Function arguments
-                in("rdi") rdi,
-                in("rsi") rsi,
-                in("rdx") rdx,
-                in("rcx") rcx,
-                in("r8")  r8,
-                in("r9")  r9,
-                // Clobbered registers
-                out("rax") _,
-                out("r10") _,
-                out("r11") _,
-            );
+// The register assignment of `call_instance` follows the System V calling convention.
+const _: () = assert!(
+    matches!(wasm::CALL_CONV, wasm::CallConv::SystemV),
+    "Calls into instances are only implemented for the System V calling convention"
+);
+
+/// Call an instance function using the SytemV ABI.
+///
+/// See [OsDev wiki](https://wiki.osdev.org/System_V_ABI), [(old but rendered)
+/// spec](https://www.uclibc.org/docs/psABI-x86_64.pdf), and [newer
+/// spec](https://gitlab.com/x86-psABIs).
+///
+/// Integer and reference arguments are passed through the general purpose registers, followed by
+/// the VMContext, and floating point arguments through SSE registers. The arguments must match the
+/// type of the function, see `Args::matches`.
+///
+/// Returns the raw values returned by the function, floating point values are returned as their
+/// bits.
+fn call_instance(
+    instance: &Instance<Arc<Vma>>,
+    func: FuncIndex,
+    args: &Args,
+) -> Result<Vec<u64>, Trap> {
+    // Instance pointers
+    let func_ptr = instance.get_func_addr_by_index(func);
+    let func_ty = instance.get_func_type_by_index(func);
+    let vmctx = instance.get_vmctx_ptr() as u64;
+
+    assert!(
+        args.matches(func_ty),
+        "Mismatching types, should have been typechecked earlier!"
+    );
+    assert!(
+        func_ty.ret().len() <= 2,
+        "Returning more than 2 values from instances is not yet supported"
+    );
+    assert!(
+        instance.try_enter(),
+        "Entering a running instance, should have been checked earlier!"
+    );
+
+    // Registers used to pass arguments, the VMContext comes right after the integer arguments.
+    // There are at most 5 arguments, so they all fit in registers.
+    let mut int_regs = [0; 6];
+    let mut float_regs = [0; 5];
+    let mut nb_ints = 0;
+    let mut nb_floats = 0;
+    for arg in args.as_slice() {
+        if arg.is_float() {
+            float_regs[nb_floats] = arg.to_bits();
+            nb_floats += 1;
+        } else {
+            int_regs[nb_ints] = arg.to_bits();
+            nb_ints += 1;
         }
     }
-}
+    int_regs[nb_ints] = vmctx;
+    let [rdi, rsi, mut rdx, rcx, r8, r9] = int_regs;
+    let [xmm0, xmm1, xmm2, xmm3, xmm4] = float_regs.map(f64::from_bits);
+    let rax: u64;
+    let float_ret: [f64; 2];
 
-// ——————————————————————————————— Arguments ———————————————————————————————— //
+    let mut recovery = RecoveryPoint {
+        rsp: 0,
+        rip: 0,
+        trapped: false,
+        fault_ip: 0,
+        nested: !RECOVERY_POINT.load(Ordering::SeqCst).is_null(),
+        instance,
+    };
+    let recovery_ptr: *mut RecoveryPoint = &mut recovery;
+    let alloc_free_depth = allocator::alloc_free_depth();
+    let start = unsafe { _rdtsc() };
+    let previous = RECOVERY_POINT.swap(recovery_ptr, Ordering::SeqCst);
+    let previous_caller = CALLER.swap(instance as *const _ as *mut _, Ordering::SeqCst);
+    unsafe {
+        let ret0: f64;
+        let ret1: f64;
+        asm!(
+            // If the guest traps the callee-saved registers are not restored, but LLVM does not
+            // let us mark rbx and rbp as clobbered.
+            "push rbx",
+            "push rbp",
+            // Register the recovery point, execution resumes at label 2 if the guest traps
+            "mov [{recovery}], rsp",
+            "lea r12, [rip + 2f]",
+            "mov [{recovery} + 8], r12",
+            "call {func_ptr}",
+            "2:",
+            "pop rbp",
+            "pop rbx",
+            recovery = in(reg) recovery_ptr,
+            func_ptr = in(reg) func_ptr,
+            // Function arguments and results
+            out("rax") rax,
+            inout("rdi") rdi => _,
+            inout("rsi") rsi => _,
+            inout("rdx") rdx,
+            inout("rcx") rcx => _,
+            inout("r8")  r8 => _,
+            inout("r9")  r9 => _,
+            inout("xmm0") xmm0 => ret0,
+            inout("xmm1") xmm1 => ret1,
+            inout("xmm2") xmm2 => _,
+            inout("xmm3") xmm3 => _,
+            inout("xmm4") xmm4 => _,
+            // Callee-saved registers, clobbered if the guest traps
+            out("r12") _,
+            out("r13") _,
+            out("r14") _,
+            out("r15") _,
+            clobber_abi("C"),
+        );
+        float_ret = [ret0, ret1];
+    }
+    CALLER.store(previous_caller, Ordering::SeqCst);
+    RECOVERY_POINT.store(previous, Ordering::SeqCst);
+    // A request issued after the last preemption check would preempt the next call otherwise
+    instance.clear_preemption();
+    instance.leave();
+    // NOTE: the cycles during which the call was suspended, if any, are counted as well.
+    instance.record_call(unsafe { _rdtsc() } - start);
 
-/// Wasm function call arguments.
-#[derive(Debug, Clone)]
-pub struct Args {
-    // We support at most 5 arguments for now
-    args: [u64; 5],
-    len: usize,
-}
+    // SAFETY: the recovery point might have been updated by the fault handler.
+    if unsafe { ptr::read_volatile(&recovery.trapped) } {
+        // The alloc-free contexts entered by the guest's syscalls did not exit
+        allocator::restore_alloc_free_depth(alloc_free_depth);
+        return Err(Trap {
+            ip: unsafe { ptr::read_volatile(&recovery.fault_ip) },
+        });
+    }
 
-/// A trait for values that can be converted to a sequence of Wasm arguments.
-pub trait AsArgs {
-    fn as_args(&self) -> Args;
+    // Integer results are returned in rax then rdx, floating point results in xmm0 then xmm1
+    let mut int_results = [rax, rdx].into_iter();
+    let mut float_results = float_ret.into_iter();
+    let values = func_ty
+        .ret()
+        .iter()
+        .map(|ty| match ty {
+            ValueType::F32 => float_results
+                .next()
+                .map_or(0, |val| val.to_bits() as u32 as u64),
+            ValueType::F64 => float_results.next().map_or(0, f64::to_bits),
+            _ => int_results.next().unwrap_or(0),
+        })
+        .collect();
+    Ok(values)
 }
 
-/// A trait for values that can be converted to a single Wasm argument.
-pub trait AsArg {
-    fn as_arg(&self) -> u64;
+// ————————————————————————————————— Traps —————————————————————————————————— //
+
+/// A WebAssembly trap, raised by a fault while executing guest code.
+#[derive(Debug)]
+pub struct Trap {
+    /// The address of the faulting instruction.
+    ip: u64,
 }
 
-impl Args {
-    /// Creates an empty argument object.
-    pub fn new() -> Self {
-        Self {
-            args: [0x41; 5],
-            len: 0,
+impl Trap {
+    /// Returns the exit status of a call into `instance` which raised this trap.
+    fn status(&self, instance: &Instance<Arc<Vma>>) -> ExitStatus {
+        match instance.locate_trap(self.ip as usize) {
+            Some((code, location)) => ExitStatus::Trapped {
+                code,
+                location: Some(location),
+            },
+            None => ExitStatus::Trapped {
+                code: TrapCode::Unknown,
+                location: None,
+            },
         }
     }
+}
 
-    /// Adds an argument.
-    pub fn push<T>(mut self, arg: T) -> Self
-    where
-        T: AsArg,
-    {
-        if self.len >= self.args.len() {
-            panic!("Too many arguments, we support at most 5 for now");
-        }
-        self.args[self.len] = arg.as_arg();
-        self.len += 1;
-        self
+/// The state to restore if the guest traps.
+#[repr(C)]
+struct RecoveryPoint {
+    /// The stack pointer right before calling into the guest.
+    rsp: u64,
+    /// The address of the instruction following the call into the guest.
+    rip: u64,
+    /// Set by the fault handler when the guest traps.
+    trapped: bool,
+    /// The address of the faulting instruction, set by the fault handler when the guest traps.
+    fault_ip: u64,
+    /// Whether the call is nested within another call into guest code.
+    nested: bool,
+    /// The instance called into, faults are traps only if raised by code reachable from it.
+    instance: *const Instance<Arc<Vma>>,
+}
+
+/// The recovery point of the innermost call into guest code, null if no guest code is executing.
+static RECOVERY_POINT: AtomicPtr<RecoveryPoint> = AtomicPtr::new(ptr::null_mut());
+
+/// Resumes execution right after the innermost call into guest code, which will report a trap.
+///
+/// Returns false if no guest code is executing or if the faulting instruction is not within the
+/// code of the called instance or of the instances it imports. Such faults are raised by the
+/// kernel (e.g. in a syscall) and can not be recovered from.
+///
+/// SAFETY: must only be called from a fault handler, with the stack frame of that fault.
+pub unsafe fn recover_from_trap(stack_frame: &mut InterruptStackFrame) -> bool {
+    let recovery = RECOVERY_POINT.load(Ordering::SeqCst);
+    if recovery.is_null() {
+        return false;
     }
 
-    fn as_slice(&self) -> &[u64] {
-        &self.args[0..self.len]
+    let recovery = &mut *recovery;
+    let ip = stack_frame.instruction_pointer.as_u64();
+    // SAFETY: the instance is kept alive by the caller for the duration of the call.
+    if !(*recovery.instance).reaches_code(ip as usize) {
+        return false;
     }
+    ptr::write_volatile(&mut recovery.trapped, true);
+    ptr::write_volatile(&mut recovery.fault_ip, ip);
+    stack_frame.as_mut().update(|frame| {
+        frame.instruction_pointer = VirtAddr::new(recovery.rip);
+        frame.stack_pointer = VirtAddr::new(recovery.rsp);
+    });
+    true
 }
 
-impl<T> AsArgs for T
+// ————————————————————————————————— Caller ————————————————————————————————— //
+
+/// The instance of the innermost call into guest code, null if no guest code is executing.
+static CALLER: AtomicPtr<Instance<Arc<Vma>>> = AtomicPtr::new(ptr::null_mut());
+
+/// Executes `f` on the linear memory of the calling instance, that is the memory 0 of the
+/// instance entered by the innermost call into guest code.
+///
+/// Returns `None` if no guest code is executing or if the calling instance has no memory, and an
+/// error if the memory is sealed or borrowed by the kernel.
+///
+/// NOTE: the caller is the instance the kernel called into, if that instance forwarded the call
+/// to one of its imports (e.g. through a syscall wrapper within another instance), the memory is
+/// still the one of the entered instance.
+pub fn with_caller_memory<F, R>(f: F) -> Option<Result<R, VmaStateError>>
 where
-    T: AsArg,
+    F: FnOnce(&mut [u8]) -> R,
 {
-    fn as_args(&self) -> Args {
-        Args::new().push(self.as_arg())
+    let caller = CALLER.load(Ordering::SeqCst);
+    if caller.is_null() {
+        return None;
     }
+
+    // SAFETY: the instance is kept alive by its component for the duration of the call, and the
+    // guest is blocked on the syscall while we access its memory.
+    let caller = unsafe { &*caller };
+    let heap = HeapIndex::from_u32(0);
+    let (_, size) = caller.get_heap_ptr_and_size(heap)?;
+    let area = caller.get_heap_area(heap)?;
+    // The heap spans the beginning of its area
+    Some(area.with_exclusive(|memory| f(&mut memory[..size])))
 }
 
-impl AsArgs for () {
-    fn as_args(&self) -> Args {
-        Args::new()
+// ——————————————————————————————— Suspension ——————————————————————————————— //
+
+/// Suspends the guest execution calling into the kernel, until its task resumes it.
+///
+/// Returns false if the execution can not be suspended: only executions started by
+/// `Component::run` can be suspended, and only if the kernel is called from the function the task
+/// called into rather than from a nested call (e.g. a start function executed by a syscall).
+pub fn suspend_execution(reason: Suspend) -> bool {
+    let recovery = RECOVERY_POINT.load(Ordering::SeqCst);
+    // SAFETY: the recovery point is valid while guest code is executing.
+    if recovery.is_null() || unsafe { (*recovery).nested } {
+        return false;
     }
+    fiber::suspend(reason)
 }
 
-impl AsArg for u64 {
-    fn as_arg(&self) -> u64 {
-        *self
+/// Requests the guest execution running on this core, if any, to yield at its next preemption
+/// check (see `CompilerOptions::preemption_checks`).
+///
+/// Called on each timer interrupt, so that guests compiled with preemption checks run for at most
+/// one timer period before the other ready tasks get a chance to run. The request is lock-free.
+pub fn request_preemption() {
+    let caller = CALLER.load(Ordering::SeqCst);
+    if !caller.is_null() {
+        // SAFETY: the caller is kept alive by its component while it executes.
+        unsafe { (*caller).request_preemption() };
     }
 }
 
-impl AsArg for u32 {
-    fn as_arg(&self) -> u64 {
-        *self as u64
-    }
+// Guest code calls `preempt` with the calling convention of instances.
+const _: () = assert!(
+    matches!(wasm::CALL_CONV, wasm::CallConv::SystemV),
+    "The preemption handler is only implemented for the System V calling convention"
+);
+
+/// The preemption handler of the runtime, called by guest code once preemption has been
+/// requested.
+///
+/// Executions which can not be suspended (see `suspend_execution`) simply continue.
+pub(crate) unsafe extern "sysv64" fn preempt(_vmctx: *mut u8) {
+    suspend_execution(Suspend::Yield);
 }
 
-impl AsArg for u16 {
-    fn as_arg(&self) -> u64 {
-        *self as u64
-    }
+/// The kernel state tied to a guest execution, which is swapped when the execution is suspended
+/// or resumed.
+struct GuestState {
+    component: *mut Component,
+    caller: *mut Instance<Arc<Vma>>,
+    recovery: *mut RecoveryPoint,
+    tracing: bool,
 }
 
-impl AsArg for u8 {
-    fn as_arg(&self) -> u64 {
-        *self as u64
+// SAFETY: the pointers are only dereferenced while the execution they belong to is executing.
+unsafe impl Send for GuestState {}
+
+impl GuestState {
+    /// The state outside of any guest execution.
+    fn empty() -> Self {
+        Self {
+            component: ptr::null_mut(),
+            caller: ptr::null_mut(),
+            recovery: ptr::null_mut(),
+            tracing: false,
+        }
+    }
+
+    fn current() -> Self {
+        Self {
+            component: CURRENT_COMPONENT.load(Ordering::SeqCst),
+            caller: CALLER.load(Ordering::SeqCst),
+            recovery: RECOVERY_POINT.load(Ordering::SeqCst),
+            tracing: trace::is_active(),
+        }
+    }
+
+    fn install(&self) {
+        CURRENT_COMPONENT.store(self.component, Ordering::SeqCst);
+        CALLER.store(self.caller, Ordering::SeqCst);
+        RECOVERY_POINT.store(self.recovery, Ordering::SeqCst);
+        trace::set_active(self.tracing);
+    }
+
+    /// Resumes a guest execution with this state, which is updated if the execution suspends
+    /// itself again.
+    fn resume<T>(&mut self, fiber: &mut Fiber<T>) -> FiberState<T> {
+        let outer = Self::current();
+        self.install();
+        let state = fiber.resume();
+        *self = Self::current();
+        outer.install();
+        state
     }
 }
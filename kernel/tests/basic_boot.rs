@@ -9,6 +9,7 @@ use kernel::kprintln;
 
 #[no_mangle] // don't mangle the name of this function
 pub extern "C" fn _start() -> ! {
+    kernel::test_device::init();
     test_main();
 
     kernel::hlt_loop();
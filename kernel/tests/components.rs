@@ -0,0 +1,193 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use compiler::{Compiler, X86_64Compiler};
+use kernel;
+use kernel::runtime::compilation::KernelModule;
+use kernel::runtime::{KoIndex, PageSizes, ACTIVE_MODULES};
+use kernel::syscalls::build_restricted_syscall_module;
+use kernel::wasm::{CallError, Component, InstanceIndex};
+use wasm::{Args, ExitStatus, WasmModule};
+
+/// A module without any item.
+///
+/// ```wat
+/// (module)
+/// ```
+const EMPTY_MODULE: &[u8] = &[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+/// A module whose start function spawns a component running the module of its first handle,
+/// sharing its `coral` import, and traps if the spawn fails.
+///
+/// ```wat
+/// (module
+///   (import "coral" "component_spawn"
+///     (func $spawn (param externref i32 i32) (result i64 externref)))
+///   (import "coral" "handles" (table $handles 1 externref))
+///   (memory 1)
+///   (data (i32.const 0) "coral\00")
+///   (func $start
+///     (call $spawn (table.get $handles (i32.const 0)) (i32.const 0) (i32.const 6))
+///     drop
+///     i64.const 0
+///     i64.ne
+///     if
+///       unreachable
+///     end)
+///   (start $start))
+/// ```
+const SPAWN_MODULE: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x02, 0x60, 0x03, 0x6f, 0x7f, 0x7f,
+    0x02, 0x7e, 0x6f, 0x60, 0x00, 0x00, 0x02, 0x2b, 0x02, 0x05, 0x63, 0x6f, 0x72, 0x61, 0x6c, 0x0f,
+    0x63, 0x6f, 0x6d, 0x70, 0x6f, 0x6e, 0x65, 0x6e, 0x74, 0x5f, 0x73, 0x70, 0x61, 0x77, 0x6e, 0x00,
+    0x00, 0x05, 0x63, 0x6f, 0x72, 0x61, 0x6c, 0x07, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65, 0x73, 0x01,
+    0x6f, 0x00, 0x01, 0x03, 0x02, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x08, 0x01, 0x01, 0x0a,
+    0x16, 0x01, 0x14, 0x00, 0x41, 0x00, 0x25, 0x00, 0x41, 0x00, 0x41, 0x06, 0x10, 0x00, 0x1a, 0x42,
+    0x00, 0x52, 0x04, 0x40, 0x00, 0x0b, 0x0b, 0x0b, 0x0c, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x06, 0x63,
+    0x6f, 0x72, 0x61, 0x6c, 0x00,
+];
+
+/// A counter exporting `increment`, `get` and `reset`.
+///
+/// ```wat
+/// (module
+///   (global $count (mut i32) (i32.const 0))
+///   (func (export "increment") (result i32)
+///     global.get $count
+///     i32.const 1
+///     i32.add
+///     global.set $count
+///     global.get $count)
+///   (func (export "get") (result i32)
+///     global.get $count)
+///   (func (export "reset")
+///     i32.const 0
+///     global.set $count))
+/// ```
+const COUNTER_MODULE: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x60, 0x00, 0x01, 0x7f, 0x60,
+    0x00, 0x00, 0x03, 0x04, 0x03, 0x00, 0x00, 0x01, 0x06, 0x06, 0x01, 0x7f, 0x01, 0x41, 0x00, 0x0b,
+    0x07, 0x1b, 0x03, 0x09, 0x69, 0x6e, 0x63, 0x72, 0x65, 0x6d, 0x65, 0x6e, 0x74, 0x00, 0x00, 0x03,
+    0x67, 0x65, 0x74, 0x00, 0x01, 0x05, 0x72, 0x65, 0x73, 0x65, 0x74, 0x00, 0x02, 0x0a, 0x19, 0x03,
+    0x0b, 0x00, 0x23, 0x00, 0x41, 0x01, 0x6a, 0x24, 0x00, 0x23, 0x00, 0x0b, 0x04, 0x00, 0x23, 0x00,
+    0x0b, 0x06, 0x00, 0x41, 0x00, 0x24, 0x00, 0x0b,
+];
+
+/// A client of the counter, whose start function traps unless the counter counts.
+///
+/// ```wat
+/// (module
+///   (import "counter" "increment" (func $increment (result i32)))
+///   (import "counter" "get" (func $get (result i32)))
+///   (import "counter" "reset" (func $reset))
+///   (func $start
+///     call $increment
+///     drop
+///     call $increment
+///     drop
+///     call $get
+///     i32.const 2
+///     i32.ne
+///     if
+///       unreachable
+///     end
+///     call $reset)
+///   (start $start))
+/// ```
+const COUNTER_CLIENT_MODULE: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x60, 0x00, 0x01, 0x7f, 0x60,
+    0x00, 0x00, 0x02, 0x33, 0x03, 0x07, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65, 0x72, 0x09, 0x69, 0x6e,
+    0x63, 0x72, 0x65, 0x6d, 0x65, 0x6e, 0x74, 0x00, 0x00, 0x07, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65,
+    0x72, 0x03, 0x67, 0x65, 0x74, 0x00, 0x00, 0x07, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65, 0x72, 0x05,
+    0x72, 0x65, 0x73, 0x65, 0x74, 0x00, 0x01, 0x03, 0x02, 0x01, 0x01, 0x08, 0x01, 0x03, 0x0a, 0x15,
+    0x01, 0x13, 0x00, 0x10, 0x00, 0x1a, 0x10, 0x00, 0x1a, 0x10, 0x01, 0x41, 0x02, 0x47, 0x04, 0x40,
+    0x00, 0x0b, 0x10, 0x02, 0x0b,
+];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::test_device::init();
+    kernel::init();
+    let allocator = unsafe { kernel::init_memory(boot_info).unwrap() };
+    kernel::runtime::init(allocator, PageSizes::default());
+
+    test_main();
+
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+fn compile(wasm: &[u8]) -> WasmModule {
+    let mut compiler = X86_64Compiler::new();
+    compiler.parse(wasm).unwrap();
+    compiler.compile().unwrap()
+}
+
+#[test_case]
+fn start_function_spawns_component() {
+    let spawned = ACTIVE_MODULES.insert(Arc::new(KernelModule::ready(compile(EMPTY_MODULE))));
+    let component = Component::new();
+    let syscalls = component
+        .add_instance(&build_restricted_syscall_module(|name| {
+            name == "component_spawn"
+        }))
+        .unwrap();
+    let handle = component
+        .get_instance(syscalls)
+        .insert_handle(spawned.into_externref().into_handle());
+    assert_eq!(handle, Some(0));
+    assert!(component.push_import(String::from("coral"), syscalls));
+
+    // Sharing the `coral` import with the spawned component looks up the imports of this
+    // component while its start function executes.
+    let idx = component.add_instance(&compile(SPAWN_MODULE)).unwrap();
+    assert!(matches!(
+        component.exit_status(idx),
+        Some(ExitStatus::Returned(_))
+    ));
+}
+
+#[test_case]
+fn start_function_calls_pushed_import() {
+    let component = Component::new();
+    let counter = component.add_instance(&compile(COUNTER_MODULE)).unwrap();
+    assert!(!component.push_import(String::from("counter"), InstanceIndex::from_u32(1)));
+    assert!(component.push_import(String::from("counter"), counter));
+
+    let idx = component
+        .add_instance(&compile(COUNTER_CLIENT_MODULE))
+        .unwrap();
+    assert!(matches!(
+        component.exit_status(idx),
+        Some(ExitStatus::Returned(_))
+    ));
+}
+
+#[test_case]
+fn nested_call_releases_component() {
+    let component = Component::new();
+    let counter = component.add_instance(&compile(COUNTER_MODULE)).unwrap();
+    let status = component.call_nested(counter, "increment", &Args::new());
+    assert!(matches!(status, Ok(ExitStatus::Returned(values)) if values == [1]));
+    assert!(!component.is_busy());
+
+    assert_eq!(
+        component.call_nested(counter, "missing", &Args::new()),
+        Err(CallError::MissingExport)
+    );
+}
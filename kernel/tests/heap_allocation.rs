@@ -11,15 +11,18 @@ use alloc::vec::Vec;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use spin::Mutex;
+use x86_64::VirtAddr;
 
 use kernel;
-use kernel::memory::VmaAllocator;
+use kernel::fiber::{self, Fiber, FiberState, Suspend};
+use kernel::memory::{PageSize, VmaAllocator, PAGE_SIZE};
 
 entry_point!(main);
 
 static ALLOCATOR: Mutex<Option<VmaAllocator>> = Mutex::new(None);
 
 fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::test_device::init();
     kernel::init();
     let allocator = unsafe { kernel::init_memory(boot_info).unwrap() };
     **&mut ALLOCATOR.lock() = Some(allocator);
@@ -62,3 +65,94 @@ fn alloc_vma() {
         }
     }
 }
+
+#[test_case]
+fn zero_on_demand_vma() {
+    let allocator = ALLOCATOR.lock();
+    let allocator = allocator.as_ref().unwrap();
+    let nb_pages = 64;
+    let virt_addr = allocator.reserve(nb_pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+    let free_frames = allocator.free_frames();
+    let mut vma = allocator
+        .with_zeroed_capacity_at(nb_pages * PAGE_SIZE, virt_addr, PageSize::Small)
+        .unwrap();
+    assert!(vma.as_bytes().iter().all(|byte| *byte == 0));
+
+    // The pages share the zero frame until they are written to, but their frames are reserved
+    let reserved_frames = allocator.free_frames();
+    assert!(free_frames - reserved_frames >= nb_pages);
+    assert!(allocator.resolve_copy_on_write(VirtAddr::from_ptr(vma.as_bytes().as_ptr())));
+    assert_eq!(allocator.free_frames(), reserved_frames);
+    vma.as_bytes_mut()[0] = 1;
+    assert_eq!(vma.as_bytes()[1], 0);
+
+    // Both the written frame and the remaining reservations are given back
+    drop(vma);
+    assert!(allocator.free_frames() >= free_frames);
+}
+
+#[test_case]
+fn fork_vma() {
+    let allocator = ALLOCATOR.lock();
+    let allocator = allocator.as_ref().unwrap();
+    let nb_pages = 4;
+    let mut vma = allocator.with_capacity(nb_pages * PAGE_SIZE).unwrap();
+    vma.as_bytes_mut().fill(1);
+    let virt_addr = allocator.reserve(nb_pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+    let fork = allocator.fork_at(&vma, virt_addr).unwrap();
+    let free_frames = allocator.free_frames();
+    assert_eq!(fork.as_bytes(), vma.as_bytes());
+
+    // The first write to a shared page copies it, the last mapping is made writable in place
+    assert!(allocator.resolve_copy_on_write(VirtAddr::from_ptr(fork.as_bytes().as_ptr())));
+    assert_eq!(allocator.free_frames(), free_frames - 1);
+    assert!(allocator.resolve_copy_on_write(VirtAddr::from_ptr(vma.as_bytes().as_ptr())));
+    assert_eq!(allocator.free_frames(), free_frames - 1);
+    vma.as_bytes_mut()[0] = 2;
+    assert_eq!(fork.as_bytes()[0], 1);
+
+    // Shared frames are released with their last mapping
+    drop(vma);
+    assert_eq!(allocator.free_frames(), free_frames);
+    drop(fork);
+    assert_eq!(allocator.free_frames(), free_frames + nb_pages);
+}
+
+#[test_case]
+fn alloc_free_context() {
+    let heap_value = Box::new(42);
+    assert!(!kernel::allocator::is_alloc_free());
+    let value = kernel::allocator::alloc_free(|| {
+        assert!(kernel::allocator::is_alloc_free());
+        kernel::allocator::alloc_free(|| assert!(kernel::allocator::is_alloc_free()));
+        assert!(kernel::allocator::is_alloc_free());
+
+        // Freeing memory is allowed
+        let value = *heap_value;
+        drop(heap_value);
+        value
+    });
+    assert_eq!(value, 42);
+    assert!(!kernel::allocator::is_alloc_free());
+}
+
+#[test_case]
+fn fiber_suspend_and_resume() {
+    let allocator = ALLOCATOR.lock();
+    let allocator = allocator.as_ref().unwrap();
+    let mut fiber = Fiber::new(allocator, || {
+        assert!(fiber::suspend(Suspend::Yield));
+        assert!(fiber::suspend(Suspend::Sleep(10)));
+        42
+    })
+    .unwrap();
+    assert!(matches!(
+        fiber.resume(),
+        FiberState::Suspended(Suspend::Yield)
+    ));
+    assert!(matches!(
+        fiber.resume(),
+        FiberState::Suspended(Suspend::Sleep(10))
+    ));
+    assert!(matches!(fiber.resume(), FiberState::Done(42)));
+}
@@ -5,11 +5,13 @@
 #![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use kernel::qemu;
+use kernel::qemu::ExitCode;
+use kernel::test_device;
 use kernel::{debug_print, debug_println};
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
+    test_device::init();
     test_main();
 
     kernel::hlt_loop();
@@ -19,15 +21,15 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
     for test in tests {
         test();
         debug_println!("[test did not panic]");
-        qemu::exit(qemu::ExitCode::Failed);
+        test_device::device().exit(ExitCode::Failed);
     }
-    qemu::exit(qemu::ExitCode::Success);
+    test_device::device().exit(ExitCode::Success);
 }
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     debug_println!("[ok]");
-    qemu::exit(qemu::ExitCode::Success);
+    test_device::device().exit(ExitCode::Success);
     kernel::hlt_loop();
 }
 
@@ -22,6 +22,7 @@ lazy_static! {
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
+    kernel::test_device::init();
     debug_print!("stack_overflow::stack_overflow...\t");
 
     kernel::gdt::init();
@@ -43,7 +44,7 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _error_code: u64,
 ) -> ! {
     debug_print!("[ok]");
-    kernel::qemu::exit(kernel::qemu::ExitCode::Success);
+    kernel::test_device::device().exit(kernel::qemu::ExitCode::Success);
     kernel::hlt_loop();
 }
 
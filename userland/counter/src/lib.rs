@@ -0,0 +1,35 @@
+//! Counter Service
+//!
+//! A minimal service holding a counter. Its functions are made available to the other instances
+//! of the component through the component import mechanism, under the `counter` module name.
+#![no_std]
+
+static mut COUNTER: u32 = 0;
+
+/// Increments the counter and returns the new value.
+#[no_mangle]
+pub fn increment() -> u32 {
+    unsafe {
+        COUNTER += 1;
+        COUNTER
+    }
+}
+
+/// Returns the current value of the counter.
+#[no_mangle]
+pub fn get() -> u32 {
+    unsafe { COUNTER }
+}
+
+/// Resets the counter to zero.
+#[no_mangle]
+pub fn reset() {
+    unsafe { COUNTER = 0 };
+}
+
+// ————————————————————————————— Panic Handler —————————————————————————————— //
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
@@ -2,14 +2,20 @@
 
 mod keyboard;
 mod shell;
+mod status;
 mod syscalls;
 mod vga;
 
 const COLOR: vga::ColorCode = vga::ColorCode::new(vga::Color::Pink, vga::Color::Black);
 static mut SHELL: Option<shell::Shell> = None;
+static STATUS_BAR: status::StatusBar = status::StatusBar::new(
+    0,
+    vga::ColorCode::new(vga::Color::Black, vga::Color::LightGray),
+);
 
 #[no_mangle]
 pub fn init() -> u32 {
+    draw_status();
     vga::write_str("Coral - userboot", COLOR, 1, 1);
     for x in 0..vga::BUFFER_WIDTH {
         vga::write_char(COLOR.char(b'_'), x, 2);
@@ -23,16 +29,21 @@ pub fn init() -> u32 {
         0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f,
         0x03, 0x02, 0x01, 0x00, 0x0a, 0x06, 0x01, 0x04, 0x00, 0x41, 0x2a, 0x0b,
     ];
+    // Our own memory can not be sealed, the module is compiled from a blob instead.
     unsafe {
-        console.write("Create module:      ");
-        let (module, result) = syscalls::module_create(0, wasm.as_ptr() as u64, wasm.len() as u64);
+        console.write("Create blob:        ");
+        let (blob, result) = syscalls::blob_from_vma(0, wasm.as_ptr() as u64, wasm.len() as u64);
         console.writeln(result.str());
-        console.write("Create component:   ");
-        let (component, result) = syscalls::component_create();
+        console.write("Module from blob:   ");
+        let (module, result) = syscalls::module_create_from_blob(blob, 0, wasm.len() as u64);
         console.writeln(result.str());
-        console.write("Instantiate module: ");
-        let (result, _) = syscalls::component_add_instance(component, module);
+        console.write("Compile module:     ");
+        console.writeln(wait_compilation(module));
+        console.write("Spawn component:    ");
+        let (_, result) = syscalls::component_spawn(module, [].as_ptr(), 0);
         console.writeln(result.str());
+        console.write("Counter service:    ");
+        console.writeln(start_counter());
         console.prompt();
         console.flush();
     }
@@ -44,6 +55,102 @@ pub fn init() -> u32 {
     42
 }
 
+/// The counter service, see `userland/counter`.
+const COUNTER_SERVICE: &[u8] = include_bytes!("../wasm/counter.wasm");
+
+/// A client of the counter service, whose start function traps unless the counter counts.
+///
+/// ```wat
+/// (module
+///   (import "counter" "increment" (func $increment (result i32)))
+///   (import "counter" "get" (func $get (result i32)))
+///   (import "counter" "reset" (func $reset))
+///   (func $start
+///     call $increment
+///     drop
+///     call $increment
+///     drop
+///     call $get
+///     i32.const 2
+///     i32.ne
+///     if
+///       unreachable
+///     end
+///     call $reset)
+///   (start $start))
+/// ```
+const COUNTER_CLIENT: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x60, 0x00, 0x01, 0x7f, 0x60,
+    0x00, 0x00, 0x02, 0x33, 0x03, 0x07, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65, 0x72, 0x09, 0x69, 0x6e,
+    0x63, 0x72, 0x65, 0x6d, 0x65, 0x6e, 0x74, 0x00, 0x00, 0x07, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65,
+    0x72, 0x03, 0x67, 0x65, 0x74, 0x00, 0x00, 0x07, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65, 0x72, 0x05,
+    0x72, 0x65, 0x73, 0x65, 0x74, 0x00, 0x01, 0x03, 0x02, 0x01, 0x01, 0x08, 0x01, 0x03, 0x0a, 0x15,
+    0x01, 0x13, 0x00, 0x10, 0x00, 0x1a, 0x10, 0x00, 0x1a, 0x10, 0x01, 0x41, 0x02, 0x47, 0x04, 0x40,
+    0x00, 0x0b, 0x10, 0x02, 0x0b,
+];
+
+/// Builds a component running the counter service, and a client importing it as `counter`.
+/// Returns the outcome of the instantiation of the client.
+unsafe fn start_counter() -> &'static str {
+    // Both modules are compiled before building the component
+    let modules = [COUNTER_SERVICE, COUNTER_CLIENT].map(|wasm| load_module(wasm));
+    let (counter, client) = match modules {
+        [Ok(counter), Ok(client)] => (counter, client),
+        [Err(err), _] | [_, Err(err)] => return err,
+    };
+    let (component, result) = syscalls::component_create();
+    if !result.is_ok() {
+        return result.str();
+    }
+    let (result, counter) = syscalls::component_add_instance(component, counter);
+    if !result.is_ok() {
+        return result.str();
+    }
+    let name = "counter";
+    let result =
+        syscalls::component_push_import(component, counter, name.as_ptr(), name.len() as u32);
+    if !result.is_ok() {
+        return result.str();
+    }
+
+    // The client calls into the counter instance from its start function
+    let (result, _) = syscalls::component_add_instance(component, client);
+    result.str()
+}
+
+/// Creates a module from bytes of our memory through a blob, and waits until it is compiled.
+unsafe fn load_module(wasm: &[u8]) -> Result<syscalls::Module, &'static str> {
+    let (blob, result) = syscalls::blob_from_vma(0, wasm.as_ptr() as u64, wasm.len() as u64);
+    if !result.is_ok() {
+        return Err(result.str());
+    }
+    let (module, result) = syscalls::module_create_from_blob(blob, 0, wasm.len() as u64);
+    if !result.is_ok() {
+        return Err(result.str());
+    }
+    match wait_compilation(module) {
+        "Ready" => Ok(module),
+        err => Err(err),
+    }
+}
+
+/// Waits until the module is compiled, returns the outcome of the compilation.
+unsafe fn wait_compilation(module: syscalls::Module) -> &'static str {
+    loop {
+        let (result, status, _percent) = syscalls::module_status(module);
+        if !result.is_ok() {
+            return result.str();
+        }
+        match status {
+            2 => return "Ready",
+            3 => return "Failed",
+            _ => {
+                syscalls::task_yield();
+            }
+        }
+    }
+}
+
 static mut COUNTER: usize = 0;
 
 #[no_mangle]
@@ -62,9 +169,42 @@ pub fn tick() {
         _ => unreachable!(),
     };
     vga::write_char(COLOR.char(char), vga::BUFFER_WIDTH - 2, 1);
+    draw_status();
     vga::flush();
 }
 
+/// Draws the status bar with up to date system statistics.
+fn draw_status() {
+    let mut stats = syscalls::SystemStats::default();
+    let size = core::mem::size_of::<syscalls::SystemStats>() as u32;
+    let result = unsafe { syscalls::system_stats(&mut stats as *mut _ as *mut u8, size) };
+    if !result.is_ok() {
+        return;
+    }
+    STATUS_BAR.draw(&[
+        status::Field {
+            label: "ticks",
+            value: stats.ticks,
+            unit: "",
+        },
+        status::Field {
+            label: "modules",
+            value: stats.modules as u64,
+            unit: "",
+        },
+        status::Field {
+            label: "components",
+            value: stats.components as u64,
+            unit: "",
+        },
+        status::Field {
+            label: "free heap",
+            value: stats.heap_free / 1024,
+            unit: " KiB",
+        },
+    ]);
+}
+
 #[no_mangle]
 pub fn press_key(scancode: u8) {
     let key = match keyboard::process_event(scancode) {
@@ -81,12 +221,216 @@ pub fn press_key(scancode: u8) {
                     return;
                 }
             };
-            console.input(char);
+            let command = match console.input(char) {
+                Some(line) => Command::parse(line),
+                None => {
+                    console.flush();
+                    return;
+                }
+            };
+            command.execute(console);
+            console.prompt();
             console.flush();
         }
     }
 }
 
+// ———————————————————————————————— Commands ———————————————————————————————— //
+
+/// Size of the buffer used to read the syscall and scheduler traces, the self-test reports and
+/// the system statistics.
+const TRACE_BUFFER_SIZE: usize = 4096;
+/// Maximum number of trace lines to display.
+const TRACE_MAX_LINES: usize = 8;
+
+static mut TRACE_BUFFER: [u8; TRACE_BUFFER_SIZE] = [0; TRACE_BUFFER_SIZE];
+
+enum Command {
+    Empty,
+    TraceOn,
+    TraceOff,
+    Trace,
+    SchedOn,
+    SchedOff,
+    Sched,
+    SelfTest,
+    Stats,
+    Shutdown,
+    Reboot,
+    Sleep,
+    Unknown,
+}
+
+impl Command {
+    fn parse(line: &str) -> Self {
+        match line.trim() {
+            "" => Command::Empty,
+            "trace on" => Command::TraceOn,
+            "trace off" => Command::TraceOff,
+            "trace" => Command::Trace,
+            "sched on" => Command::SchedOn,
+            "sched off" => Command::SchedOff,
+            "sched" => Command::Sched,
+            "selftest" => Command::SelfTest,
+            "stats" => Command::Stats,
+            "shutdown" => Command::Shutdown,
+            "reboot" => Command::Reboot,
+            "sleep" => Command::Sleep,
+            _ => Command::Unknown,
+        }
+    }
+
+    fn execute(self, console: &mut shell::Shell) {
+        if let Command::Empty = self {
+            return;
+        }
+
+        console.next_line();
+        match self {
+            Command::Empty => (),
+            Command::TraceOn => {
+                let result = unsafe { syscalls::self_trace(1) };
+                console.write(result.str());
+            }
+            Command::TraceOff => {
+                let result = unsafe { syscalls::self_trace(0) };
+                console.write(result.str());
+            }
+            Command::Trace => print_trace(console, syscalls::trace_read),
+            Command::SchedOn => {
+                let result = unsafe { syscalls::sched_trace_enable(1) };
+                console.write(result.str());
+            }
+            Command::SchedOff => {
+                let result = unsafe { syscalls::sched_trace_enable(0) };
+                console.write(result.str());
+            }
+            Command::Sched => print_trace(console, syscalls::sched_trace_read),
+            Command::SelfTest => print_trace(console, syscalls::selftest_run),
+            Command::Stats => print_stats(console),
+            Command::Shutdown => {
+                // Only returns on failure
+                let result = unsafe { syscalls::system_shutdown() };
+                console.write(result.str());
+            }
+            Command::Reboot => {
+                // Only returns on failure
+                let result = unsafe { syscalls::system_reboot() };
+                console.write(result.str());
+            }
+            Command::Sleep => {
+                let result = unsafe { syscalls::task_sleep_ms(1000) };
+                console.write(result.str());
+            }
+            Command::Unknown => console.write("Unknown command"),
+        }
+    }
+}
+
+/// Display the most recent records of a trace, read with the given syscall.
+fn print_trace(
+    console: &mut shell::Shell,
+    read: unsafe extern "C" fn(syscalls::ExternRef, u64, u64) -> (syscalls::SyscallResult, u64),
+) {
+    // SAFETY: we only have a single thread in webassembly.
+    let buffer = unsafe { &mut TRACE_BUFFER };
+    let (result, size) = unsafe { read(0, buffer.as_mut_ptr() as u64, buffer.len() as u64) };
+    if !result.is_ok() {
+        console.write(result.str());
+        return;
+    }
+
+    let trace = match core::str::from_utf8(&buffer[..size as usize]) {
+        Ok(trace) => trace,
+        Err(_) => return console.write("Invalid trace"),
+    };
+    let nb_lines = trace.lines().count();
+    let skip = nb_lines.saturating_sub(TRACE_MAX_LINES);
+    for (idx, line) in trace.lines().skip(skip).enumerate() {
+        if idx > 0 {
+            console.next_line();
+        }
+        console.write(line);
+    }
+}
+
+/// Display the first system statistics, the global ones come before the per interrupt and per
+/// syscall counts.
+fn print_stats(console: &mut shell::Shell) {
+    // SAFETY: we only have a single thread in webassembly.
+    let buffer = unsafe { &mut TRACE_BUFFER };
+    let result = unsafe { syscalls::system_stats(buffer.as_mut_ptr(), buffer.len() as u32) };
+    if !result.is_ok() {
+        console.write(result.str());
+        return;
+    }
+
+    // SAFETY: the statistics are followed by the number of counts they announce.
+    let stats: syscalls::SystemStats = unsafe { read_record(buffer, 0) };
+    let globals = [
+        ("uptime_ticks", stats.ticks),
+        ("heap_free", stats.heap_free),
+        ("heap_size", stats.heap_size),
+        ("frames_free", stats.frames_free),
+        ("run_queue", stats.run_queue as u64),
+    ];
+    let mut nb_lines = 0;
+    for (name, value) in globals {
+        if !stats_line(console, &mut nb_lines) {
+            return;
+        }
+        console.write(name);
+        console.write(" ");
+        console.write_dec(value);
+    }
+
+    let mut offset = core::mem::size_of::<syscalls::SystemStats>();
+    for _ in 0..stats.interrupts {
+        let interrupt: syscalls::InterruptCount = unsafe { read_record(buffer, offset) };
+        offset += core::mem::size_of::<syscalls::InterruptCount>();
+        if !stats_line(console, &mut nb_lines) {
+            return;
+        }
+        console.write("interrupt ");
+        console.write_dec(interrupt.vector as u64);
+        console.write(" ");
+        console.write_dec(interrupt.count);
+    }
+    for _ in 0..stats.syscalls {
+        let syscall: syscalls::SyscallCount = unsafe { read_record(buffer, offset) };
+        offset += core::mem::size_of::<syscalls::SyscallCount>();
+        if !stats_line(console, &mut nb_lines) {
+            return;
+        }
+        let len = syscall.name.iter().position(|c| *c == 0);
+        let name = &syscall.name[..len.unwrap_or(syscall.name.len())];
+        console.write("syscall ");
+        console.write(core::str::from_utf8(name).unwrap_or("?"));
+        console.write(" ");
+        console.write_dec(syscall.count);
+    }
+}
+
+/// Starts a new line of statistics, returns false once `TRACE_MAX_LINES` lines are displayed.
+fn stats_line(console: &mut shell::Shell, nb_lines: &mut usize) -> bool {
+    if *nb_lines == TRACE_MAX_LINES {
+        return false;
+    }
+    if *nb_lines > 0 {
+        console.next_line();
+    }
+    *nb_lines += 1;
+    true
+}
+
+/// Reads a record written by the kernel at the given offset of a buffer.
+///
+/// SAFETY: the bytes at that offset must be a valid `T`.
+unsafe fn read_record<T: Copy>(buffer: &[u8], offset: usize) -> T {
+    let record = &buffer[offset..(offset + core::mem::size_of::<T>())];
+    core::ptr::read_unaligned(record.as_ptr() as *const T)
+}
+
 // ————————————————————————————— Panic Handler —————————————————————————————— //
 
 #[panic_handler]
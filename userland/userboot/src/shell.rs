@@ -2,6 +2,9 @@
 
 use crate::vga;
 
+/// Maximum length of a command line.
+const LINE_CAPACITY: usize = 64;
+
 pub struct Shell {
     #[allow(dead_code)]
     shell_start: usize,
@@ -9,6 +12,8 @@ pub struct Shell {
     y: usize,
     color: vga::ColorCode,
     prompt: vga::ColorCode,
+    line: [u8; LINE_CAPACITY],
+    line_len: usize,
 }
 
 impl Shell {
@@ -19,6 +24,8 @@ impl Shell {
             y: shell_start,
             color,
             prompt: color.with_foreground(vga::Color::Green),
+            line: [0; LINE_CAPACITY],
+            line_len: 0,
         }
     }
 
@@ -49,12 +56,41 @@ impl Shell {
         }
     }
 
-    pub fn input(&mut self, c: char) {
+    /// Write a number in decimal.
+    pub fn write_dec(&mut self, mut num: u64) {
+        // u64::MAX has 20 decimal digits
+        let mut digits = [0u8; 20];
+        let mut len = 0;
+        loop {
+            digits[len] = b'0' + (num % 10) as u8;
+            len += 1;
+            num /= 10;
+            if num == 0 {
+                break;
+            }
+        }
+        for digit in digits[..len].iter().rev() {
+            self.write_char(*digit as char);
+        }
+    }
+
+    /// Process an input character, returns the command line once it has been submitted.
+    ///
+    /// The caller is responsible for displaying a new prompt after executing the command.
+    pub fn input(&mut self, c: char) -> Option<&str> {
         if c == '\n' {
-            self.prompt();
+            let len = self.line_len;
+            self.line_len = 0;
+            // Only ASCII characters are pushed to the line buffer
+            return core::str::from_utf8(&self.line[..len]).ok();
         } else if c.is_ascii() {
+            if self.line_len < LINE_CAPACITY {
+                self.line[self.line_len] = c as u8;
+                self.line_len += 1;
+            }
             self.write_char(c);
         }
+        None
     }
 
     pub fn prompt(&mut self) {
@@ -71,7 +107,7 @@ impl Shell {
         self.next_char();
     }
 
-    fn next_line(&mut self) {
+    pub fn next_line(&mut self) {
         self.y += 1;
         self.x = 2;
     }
@@ -0,0 +1,67 @@
+//! Status Bar
+//!
+//! A widget drawing a row of labelled values, such as `modules: 3`. It only relies on the VGA
+//! driver, so that it can be reused by other programs.
+
+use crate::vga;
+
+/// Drawn between two consecutive fields.
+const SEPARATOR: &str = " | ";
+
+/// A labelled value.
+pub struct Field<'a> {
+    pub label: &'a str,
+    pub value: u64,
+    /// Displayed after the value, can be empty.
+    pub unit: &'a str,
+}
+
+pub struct StatusBar {
+    row: usize,
+    color: vga::ColorCode,
+}
+
+impl StatusBar {
+    pub const fn new(row: usize, color: vga::ColorCode) -> Self {
+        Self { row, color }
+    }
+
+    /// Draws the fields from left to right and clears the remainder of the row.
+    ///
+    /// Fields which do not fit within the row are truncated. The caller is responsible for
+    /// flushing the VGA buffer.
+    pub fn draw(&self, fields: &[Field]) {
+        vga::write_char(self.color.char(b' '), 0, self.row);
+        let mut x = 1;
+        for (idx, field) in fields.iter().enumerate() {
+            if idx > 0 {
+                x = vga::write_str(SEPARATOR, self.color, x, self.row);
+            }
+            x = vga::write_str(field.label, self.color, x, self.row);
+            x = vga::write_str(": ", self.color, x, self.row);
+            x = self.write_dec(field.value, x);
+            x = vga::write_str(field.unit, self.color, x, self.row);
+        }
+        for x in x..vga::BUFFER_WIDTH {
+            vga::write_char(self.color.char(b' '), x, self.row);
+        }
+    }
+
+    /// Writes a number in decimal, returns the next column.
+    fn write_dec(&self, mut num: u64, x: usize) -> usize {
+        // u64::MAX has 20 decimal digits
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (num % 10) as u8;
+            num /= 10;
+            if num == 0 {
+                break;
+            }
+        }
+        // Digits are ASCII
+        let digits = core::str::from_utf8(&digits[start..]).unwrap_or("?");
+        vga::write_str(digits, self.color, x, self.row)
+    }
+}
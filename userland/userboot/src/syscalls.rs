@@ -1,7 +1,7 @@
 //! Coral System Calls
 #![allow(improper_ctypes)]
 
-type ExternRef = u32;
+pub type ExternRef = u32;
 
 #[derive(Clone, Copy)]
 #[repr(transparent)]
@@ -13,19 +13,174 @@ pub struct Module(u32);
 
 #[derive(Clone, Copy)]
 #[repr(transparent)]
-pub struct SyscallResult(pub i32);
+pub struct Blob(u32);
+
+/// The status returned by syscalls: 0 on success, otherwise the error domain in the upper 32 bits
+/// and a code specific to that domain in the lower 32 bits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SyscallResult(pub i64);
 
 #[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct InstanceIndex(u32);
 
+/// The exit status of the last execution of an instance.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct ExitStatus {
+    /// 0 if the instance never executed, 1 if it returned, 2 if it trapped and 3 if it was killed.
+    pub kind: u32,
+    /// The trap code, if the instance trapped.
+    pub code: u32,
+    /// The index of the function which trapped, `u32::MAX` if unknown.
+    pub func: u32,
+    /// The offset of the trapping instruction within the module, `u32::MAX` if unknown.
+    pub offset: u32,
+    /// The returned values, if the instance returned.
+    pub values: [u64; 2],
+}
+
+/// The execution metrics of an instance.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct InstanceMetrics {
+    /// The number of calls into the instance.
+    pub calls: u64,
+    /// The number of cycles spent within the instance.
+    pub ticks: u64,
+    /// The number of calls from the instance to imported functions.
+    pub imported_calls: u64,
+}
+
+/// Statistics about the system.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct SystemStats {
+    /// Number of timer interrupts since boot.
+    pub ticks: u64,
+    /// Number of modules created since boot.
+    pub modules: u32,
+    /// Number of components created since boot.
+    pub components: u32,
+    /// Free bytes on the kernel heap.
+    pub heap_free: u64,
+    /// Size of the kernel heap, in bytes.
+    pub heap_size: u64,
+    /// Number of free physical frames.
+    pub frames_free: u64,
+    /// Number of tasks ready to run.
+    pub run_queue: u32,
+    /// Number of `InterruptCount` following the statistics.
+    pub interrupts: u32,
+    /// Number of `SyscallCount` following the interrupt counts.
+    pub syscalls: u32,
+    _padding: u32,
+}
+
+/// The number of occurrences of an interrupt since boot.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct InterruptCount {
+    pub vector: u32,
+    _padding: u32,
+    pub count: u64,
+}
+
+/// The number of invocations of a syscall since boot.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SyscallCount {
+    pub count: u64,
+    /// The name of the syscall, padded with zeroes.
+    pub name: [u8; 32],
+}
+
+/// Syscall error domains, must match the kernel's `ErrorDomain`.
+#[allow(dead_code)]
+pub mod domain {
+    pub const GENERAL: u32 = 0;
+    pub const HANDLE: u32 = 1;
+    pub const MEMORY: u32 = 2;
+    pub const VMA: u32 = 3;
+    pub const MODULE: u32 = 4;
+    pub const COMPONENT: u32 = 5;
+    pub const ENV: u32 = 6;
+    pub const TASK: u32 = 7;
+}
+
+const fn error(domain: u32, code: u32) -> SyscallResult {
+    SyscallResult(((domain as i64) << 32) | code as i64)
+}
+
+/// Syscall statuses, must match the kernel's `SyscallResult`.
+#[allow(dead_code)]
 impl SyscallResult {
+    pub const SUCCESS: Self = SyscallResult(0);
+    pub const INTERNAL_ERROR: Self = error(domain::GENERAL, 1);
+    pub const UNKNOWN_ERROR: Self = error(domain::GENERAL, 2);
+    pub const UNAVAILABLE: Self = error(domain::GENERAL, 3);
+    pub const WRONG_HANDLE_KIND: Self = error(domain::HANDLE, 1);
+    pub const INVALID_HANDLE: Self = error(domain::HANDLE, 2);
+    pub const OUT_OF_BOUNDS: Self = error(domain::MEMORY, 1);
+    pub const NO_MEMORY: Self = error(domain::MEMORY, 2);
+    pub const VMA_BORROWED: Self = error(domain::VMA, 1);
+    pub const VMA_SEALED: Self = error(domain::VMA, 2);
+    pub const VMA_NOT_SEALED: Self = error(domain::VMA, 3);
+    pub const MODULE_NOT_READY: Self = error(domain::MODULE, 1);
+    pub const COMPILATION_FAILED: Self = error(domain::MODULE, 2);
+    pub const UNSUPPORTED_FEATURES: Self = error(domain::MODULE, 3);
+    pub const LINK_ERROR: Self = error(domain::MODULE, 4);
+    pub const QUOTA_EXCEEDED: Self = error(domain::MODULE, 5);
+    pub const START_TRAPPED: Self = error(domain::MODULE, 6);
+    pub const INSTANTIATION_FAILED: Self = error(domain::MODULE, 7);
+    pub const MISSING_EXPORT: Self = error(domain::COMPONENT, 1);
+    pub const EXPORT_TYPE_MISMATCH: Self = error(domain::COMPONENT, 2);
+    pub const INVALID_INSTANCE: Self = error(domain::COMPONENT, 3);
+    pub const ENV_NOT_FOUND: Self = error(domain::ENV, 1);
+    pub const INVALID_ENV: Self = error(domain::ENV, 2);
+    pub const NOT_SUSPENDABLE: Self = error(domain::TASK, 1);
+
+    pub fn is_ok(self) -> bool {
+        self == Self::SUCCESS
+    }
+
+    pub fn domain(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    pub fn code(self) -> u32 {
+        self.0 as u32
+    }
+
     pub fn str(self) -> &'static str {
-        match self.0 {
-            0 => "Success",
-            1 => "Invalid Params",
-            2 => "Internal Error",
-            _ => "Unkonwn Error",
+        match self {
+            Self::SUCCESS => "Success",
+            Self::INTERNAL_ERROR => "Internal Error",
+            Self::UNAVAILABLE => "Unavailable",
+            Self::WRONG_HANDLE_KIND => "Wrong Handle Kind",
+            Self::INVALID_HANDLE => "Invalid Handle",
+            Self::OUT_OF_BOUNDS => "Out Of Bounds",
+            Self::NO_MEMORY => "No Memory",
+            Self::VMA_BORROWED => "VMA Borrowed",
+            Self::VMA_SEALED => "VMA Sealed",
+            Self::VMA_NOT_SEALED => "VMA Not Sealed",
+            Self::MODULE_NOT_READY => "Module Not Ready",
+            Self::COMPILATION_FAILED => "Compilation Failed",
+            Self::UNSUPPORTED_FEATURES => "Unsupported Features",
+            Self::LINK_ERROR => "Link Error",
+            Self::QUOTA_EXCEEDED => "Quota Exceeded",
+            Self::START_TRAPPED => "Start Trapped",
+            Self::INSTANTIATION_FAILED => "Instantiation Failed",
+            Self::MISSING_EXPORT => "Missing Export",
+            Self::EXPORT_TYPE_MISMATCH => "Export Type Mismatch",
+            Self::INVALID_INSTANCE => "Invalid Instance",
+            Self::ENV_NOT_FOUND => "Env Not Found",
+            Self::INVALID_ENV => "Invalid Env",
+            Self::NOT_SUSPENDABLE => "Not Suspendable",
+            _ => "Unknown Error",
         }
     }
 }
@@ -40,12 +195,184 @@ extern "C" {
         size: u64,
     ) -> SyscallResult;
 
+    #[allow(dead_code)]
+    pub fn vma_read(
+        source: ExternRef,
+        source_offset: u64,
+        target: *mut u8,
+        size: u32,
+    ) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn vma_size(vma: ExternRef) -> (SyscallResult, u64);
+
+    #[allow(dead_code)]
+    pub fn vma_seal(vma: ExternRef) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn vma_state(vma: ExternRef) -> (SyscallResult, u32);
+
+    /// Writes to the VMA through `vma_write` then emit VMA events.
+    #[allow(dead_code)]
+    pub fn vma_watch(vma: ExternRef) -> SyscallResult;
+
+    /// The instance must export `vma_event(vma, offset, size)`.
+    #[allow(dead_code)]
+    pub fn vma_register(component: Component, instance: InstanceIndex) -> SyscallResult;
+
+    /// The VMA must be sealed.
+    #[allow(dead_code)]
     pub fn module_create(source: ExternRef, offset: u64, size: u64) -> (Module, SyscallResult);
 
+    pub fn blob_from_vma(source: ExternRef, offset: u64, size: u64) -> (Blob, SyscallResult);
+
+    pub fn module_create_from_blob(blob: Blob, offset: u64, size: u64) -> (Module, SyscallResult);
+
+    /// Returns the status of the module: 0 if pending, 1 if compiling, 2 if ready and 3 if failed,
+    /// and the percentage of compiled functions.
+    pub fn module_status(module: Module) -> (SyscallResult, u32, u32);
+
+    /// Writes the name of a compiled module into the target buffer if it fits, returns the hash
+    /// of the module bytecode and the size of the name.
+    #[allow(dead_code)]
+    pub fn module_info(
+        module: Module,
+        target: *mut u8,
+        target_len: u32,
+    ) -> (SyscallResult, u64, u64);
+
+    /// The instance must export `module_event(module, status, compiled, total)`.
+    #[allow(dead_code)]
+    pub fn module_register(component: Component, instance: InstanceIndex) -> SyscallResult;
+
+    #[allow(dead_code)]
     pub fn component_create() -> (Component, SyscallResult);
 
+    #[allow(dead_code)]
     pub fn component_add_instance(
         component: Component,
         module: Module,
     ) -> (SyscallResult, InstanceIndex);
+
+    /// Spawns a component running the module, the imports are null-terminated names of imports
+    /// of our own component.
+    pub fn component_spawn(
+        module: Module,
+        imports: *const u8,
+        imports_len: u32,
+    ) -> (Component, SyscallResult);
+
+    /// Imports a syscall module restricted to the given syscalls into the component, the syscalls
+    /// are null-terminated names of syscalls we have access to.
+    #[allow(dead_code)]
+    pub fn component_add_native_module(
+        component: Component,
+        syscalls: *const u8,
+        syscalls_len: u32,
+    ) -> (SyscallResult, InstanceIndex);
+
+    /// Exposes an instance of the component to its future instances under the given name.
+    pub fn component_push_import(
+        component: Component,
+        instance: InstanceIndex,
+        name: *const u8,
+        name_len: u32,
+    ) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn component_trace(component: Component, enabled: u32) -> SyscallResult;
+
+    pub fn self_trace(enabled: u32) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn instance_fork(
+        component: Component,
+        instance: InstanceIndex,
+        target: Component,
+    ) -> (SyscallResult, InstanceIndex);
+
+    /// Replaces the instance by an instance of the module, which must export the same functions.
+    #[allow(dead_code)]
+    pub fn component_reload_instance(
+        component: Component,
+        instance: InstanceIndex,
+        module: Module,
+    ) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn component_exit_status(
+        component: Component,
+        instance: InstanceIndex,
+        target: *mut ExitStatus,
+    ) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn component_start_status(component: Component, target: *mut ExitStatus) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn component_metrics(
+        component: Component,
+        instance: InstanceIndex,
+        target: *mut InstanceMetrics,
+    ) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn pointer_register(component: Component, instance: InstanceIndex) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn env_set(
+        component: Component,
+        key: *const u8,
+        key_len: u32,
+        value: *const u8,
+        value_len: u32,
+    ) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn env_get(
+        component: Component,
+        key: *const u8,
+        key_len: u32,
+        target: *mut u8,
+        target_len: u32,
+    ) -> (SyscallResult, u64);
+
+    #[allow(dead_code)]
+    pub fn self_env_get(
+        key: *const u8,
+        key_len: u32,
+        target: *mut u8,
+        target_len: u32,
+    ) -> (SyscallResult, u64);
+
+    #[allow(dead_code)]
+    pub fn env_list(component: Component, target: *mut u8, target_len: u32)
+        -> (SyscallResult, u64);
+
+    #[allow(dead_code)]
+    pub fn self_env_list(target: *mut u8, target_len: u32) -> (SyscallResult, u64);
+
+    pub fn task_yield() -> SyscallResult;
+
+    pub fn task_sleep_ms(ms: u64) -> SyscallResult;
+
+    pub fn trace_read(target: ExternRef, offset: u64, size: u64) -> (SyscallResult, u64);
+
+    #[allow(dead_code)]
+    pub fn profile_enable(enabled: u32) -> SyscallResult;
+
+    #[allow(dead_code)]
+    pub fn profile_read(target: ExternRef, offset: u64, size: u64) -> (SyscallResult, u64);
+
+    pub fn sched_trace_enable(enabled: u32) -> SyscallResult;
+
+    pub fn sched_trace_read(target: ExternRef, offset: u64, size: u64) -> (SyscallResult, u64);
+
+    pub fn selftest_run(target: ExternRef, offset: u64, size: u64) -> (SyscallResult, u64);
+
+    pub fn system_stats(target: *mut u8, size: u32) -> SyscallResult;
+
+    pub fn system_shutdown() -> SyscallResult;
+
+    pub fn system_reboot() -> SyscallResult;
 }
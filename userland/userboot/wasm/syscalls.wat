@@ -7,7 +7,7 @@
       (param $source_offset i64)
       (param $target_offset i64)
       (param $size i64)
-      (result i32)))
+      (result i64)))
   (type $pub_vma_write
     (func
       (param $source i32)
@@ -15,13 +15,69 @@
       (param $source_offset i64)
       (param $target_offset i64)
       (param $size i64)
-      (result i32)))
+      (result i64)))
+  (type $vma_read
+    (func
+      (param $source        externref)
+      (param $source_offset i64)
+      (param $target        i32)
+      (param $size          i32)
+      (result i64)))
+  (type $pub_vma_read
+    (func
+      (param $source        i32)
+      (param $source_offset i64)
+      (param $target        i32)
+      (param $size          i32)
+      (result i64)))
+  (type $vma_size
+    (func
+      (param $vma externref)
+      (result i64 i64)))
+  (type $pub_vma_size
+    (func
+      (param $vma i32)
+      (result i64 i64)))
+  (type $vma_seal
+    (func
+      (param $vma externref)
+      (result i64)))
+  (type $pub_vma_seal
+    (func
+      (param $vma i32)
+      (result i64)))
+  (type $vma_state
+    (func
+      (param $vma externref)
+      (result i64 i32)))
+  (type $pub_vma_state
+    (func
+      (param $vma i32)
+      (result i64 i32)))
+  (type $vma_watch
+    (func
+      (param $vma externref)
+      (result i64)))
+  (type $pub_vma_watch
+    (func
+      (param $vma i32)
+      (result i64)))
+  (type $vma_register
+    (func
+      (param $component externref)
+      (param $instance  i32)
+      (result i64)))
+  (type $pub_vma_register
+    (func
+      (param $component i32)
+      (param $instance  i32)
+      (result i64)))
   (type $module_create
     (func
       (param $source externref)
       (param $offset i64)
       (param $size   i64)
-      (result i32)
+      (result i64)
       (result externref)))
   (type $pub_module_create
     (func
@@ -29,44 +85,407 @@
       (param $offset i64)
       (param $size   i64)
       (result i32)
-      (result i32)))
+      (result i64)))
+  (type $module_status
+    (func
+      (param $module externref)
+      (result i64 i32 i32)))
+  (type $pub_module_status
+    (func
+      (param $module i32)
+      (result i64 i32 i32)))
+  (type $module_info
+    (func
+      (param $module     externref)
+      (param $target     i32)
+      (param $target_len i32)
+      (result i64 i64 i64)))
+  (type $pub_module_info
+    (func
+      (param $module     i32)
+      (param $target     i32)
+      (param $target_len i32)
+      (result i64 i64 i64)))
+  (type $module_register
+    (func
+      (param $component externref)
+      (param $instance  i32)
+      (result i64)))
+  (type $pub_module_register
+    (func
+      (param $component i32)
+      (param $instance  i32)
+      (result i64)))
+  (type $blob_from_vma
+    (func
+      (param $source externref)
+      (param $offset i64)
+      (param $size   i64)
+      (result i64 externref)))
+  (type $pub_blob_from_vma
+    (func
+      (param $source i32)
+      (param $offset i64)
+      (param $size   i64)
+      (result i32 i64)))
   (type $component_create
-    (func (result i32 externref)))
+    (func (result i64 externref)))
   (type $pub_component_create
-    (func (result i32 i32)))
+    (func (result i32 i64)))
   (type $component_add_instance
     (func
       (param $component externref)
       (param $module    externref)
-      (result i32 i32)))
+      (result i64 i32)))
   (type $pub_component_add_instance
     (func
       (param $component i32)
       (param $module    i32)
-      (result i32 i32)))
+      (result i64 i32)))
+  (type $component_spawn
+    (func
+      (param $module      externref)
+      (param $imports     i32)
+      (param $imports_len i32)
+      (result i64 externref)))
+  (type $pub_component_spawn
+    (func
+      (param $module      i32)
+      (param $imports     i32)
+      (param $imports_len i32)
+      (result i32 i64)))
+  (type $component_add_native_module
+    (func
+      (param $component    externref)
+      (param $syscalls     i32)
+      (param $syscalls_len i32)
+      (result i64 i32)))
+  (type $pub_component_add_native_module
+    (func
+      (param $component    i32)
+      (param $syscalls     i32)
+      (param $syscalls_len i32)
+      (result i64 i32)))
+  (type $component_push_import
+    (func
+      (param $component externref)
+      (param $instance  i32)
+      (param $name      i32)
+      (param $name_len  i32)
+      (result i64)))
+  (type $pub_component_push_import
+    (func
+      (param $component i32)
+      (param $instance  i32)
+      (param $name      i32)
+      (param $name_len  i32)
+      (result i64)))
+  (type $component_trace
+    (func
+      (param $component externref)
+      (param $enabled   i32)
+      (result i64)))
+  (type $pub_component_trace
+    (func
+      (param $component i32)
+      (param $enabled   i32)
+      (result i64)))
+  (type $instance_fork
+    (func
+      (param $component externref)
+      (param $instance  i32)
+      (param $target    externref)
+      (result i64 i32)))
+  (type $pub_instance_fork
+    (func
+      (param $component i32)
+      (param $instance  i32)
+      (param $target    i32)
+      (result i64 i32)))
+  (type $component_reload_instance
+    (func
+      (param $component externref)
+      (param $instance  i32)
+      (param $module    externref)
+      (result i64)))
+  (type $pub_component_reload_instance
+    (func
+      (param $component i32)
+      (param $instance  i32)
+      (param $module    i32)
+      (result i64)))
+  (type $task_yield
+    (func
+      (result i64)))
+  (type $task_sleep_ms
+    (func
+      (param $ms i64)
+      (result i64)))
+  (type $component_exit_status
+    (func
+      (param $component externref)
+      (param $instance  i32)
+      (param $target    i32)
+      (result i64)))
+  (type $pub_component_exit_status
+    (func
+      (param $component i32)
+      (param $instance  i32)
+      (param $target    i32)
+      (result i64)))
+  (type $component_start_status
+    (func
+      (param $component externref)
+      (param $target    i32)
+      (result i64)))
+  (type $pub_component_start_status
+    (func
+      (param $component i32)
+      (param $target    i32)
+      (result i64)))
+  (type $component_metrics
+    (func
+      (param $component externref)
+      (param $instance  i32)
+      (param $target    i32)
+      (result i64)))
+  (type $pub_component_metrics
+    (func
+      (param $component i32)
+      (param $instance  i32)
+      (param $target    i32)
+      (result i64)))
+  (type $pointer_register
+    (func
+      (param $component externref)
+      (param $instance  i32)
+      (result i64)))
+  (type $pub_pointer_register
+    (func
+      (param $component i32)
+      (param $instance  i32)
+      (result i64)))
+  (type $pub_self_trace
+    (func
+      (param $enabled i32)
+      (result i64)))
+  (type $env_set
+    (func
+      (param $component externref)
+      (param $key       i32)
+      (param $key_len   i32)
+      (param $value     i32)
+      (param $value_len i32)
+      (result i64)))
+  (type $pub_env_set
+    (func
+      (param $component i32)
+      (param $key       i32)
+      (param $key_len   i32)
+      (param $value     i32)
+      (param $value_len i32)
+      (result i64)))
+  (type $env_get
+    (func
+      (param $component  externref)
+      (param $key        i32)
+      (param $key_len    i32)
+      (param $target     i32)
+      (param $target_len i32)
+      (result i64 i64)))
+  (type $pub_env_get
+    (func
+      (param $component  i32)
+      (param $key        i32)
+      (param $key_len    i32)
+      (param $target     i32)
+      (param $target_len i32)
+      (result i64 i64)))
+  (type $pub_self_env_get
+    (func
+      (param $key        i32)
+      (param $key_len    i32)
+      (param $target     i32)
+      (param $target_len i32)
+      (result i64 i64)))
+  (type $env_list
+    (func
+      (param $component  externref)
+      (param $target     i32)
+      (param $target_len i32)
+      (result i64 i64)))
+  (type $pub_env_list
+    (func
+      (param $component  i32)
+      (param $target     i32)
+      (param $target_len i32)
+      (result i64 i64)))
+  (type $pub_self_env_list
+    (func
+      (param $target     i32)
+      (param $target_len i32)
+      (result i64 i64)))
+  (type $trace_read
+    (func
+      (param $target externref)
+      (param $offset i64)
+      (param $size   i64)
+      (result i64 i64)))
+  (type $capability_trace_read
+    (func
+      (param $capability externref)
+      (param $target     externref)
+      (param $offset     i64)
+      (param $size       i64)
+      (result i64 i64)))
+  (type $pub_trace_read
+    (func
+      (param $target i32)
+      (param $offset i64)
+      (param $size   i64)
+      (result i64 i64)))
+  (type $profile_enable
+    (func
+      (param $enabled i32)
+      (result i64)))
+  (type $system_stats
+    (func
+      (param $target i32)
+      (param $size   i32)
+      (result i64)))
+  (type $system_power
+    (func
+      (param $capability externref)
+      (result i64)))
+  (type $pub_system_power
+    (func (result i64)))
 
   ;; Imports
   (import "coral" "vma_write"
     (func $vma_write
       (type $vma_write)))
+  (import "coral" "vma_read"
+    (func $vma_read
+      (type $vma_read)))
+  (import "coral" "vma_size"
+    (func $vma_size
+      (type $vma_size)))
+  (import "coral" "vma_seal"
+    (func $vma_seal
+      (type $vma_seal)))
+  (import "coral" "vma_state"
+    (func $vma_state
+      (type $vma_state)))
+  (import "coral" "vma_watch"
+    (func $vma_watch
+      (type $vma_watch)))
+  (import "coral" "vma_register"
+    (func $vma_register
+      (type $vma_register)))
   (import "coral" "module_create"
     (func $module_create
       (type $module_create)))
+  (import "coral" "module_status"
+    (func $module_status
+      (type $module_status)))
+  (import "coral" "module_info"
+    (func $module_info
+      (type $module_info)))
+  (import "coral" "module_register"
+    (func $module_register
+      (type $module_register)))
+  (import "coral" "blob_from_vma"
+    (func $blob_from_vma
+      (type $blob_from_vma)))
   (import "coral" "component_create"
     (func $component_create
       (type $component_create)))
   (import "coral" "component_add_instance"
     (func $component_add_instance
       (type $component_add_instance)))
+  (import "coral" "component_spawn"
+    (func $component_spawn
+      (type $component_spawn)))
+  (import "coral" "component_add_native_module"
+    (func $component_add_native_module
+      (type $component_add_native_module)))
+  (import "coral" "component_push_import"
+    (func $component_push_import
+      (type $component_push_import)))
+  (import "coral" "component_trace"
+    (func $component_trace
+      (type $component_trace)))
+  (import "coral" "instance_fork"
+    (func $instance_fork
+      (type $instance_fork)))
+  (import "coral" "component_reload_instance"
+    (func $component_reload_instance
+      (type $component_reload_instance)))
+  (import "coral" "component_exit_status"
+    (func $component_exit_status
+      (type $component_exit_status)))
+  (import "coral" "component_start_status"
+    (func $component_start_status
+      (type $component_start_status)))
+  (import "coral" "component_metrics"
+    (func $component_metrics
+      (type $component_metrics)))
+  (import "coral" "pointer_register"
+    (func $pointer_register
+      (type $pointer_register)))
+  (import "coral" "env_set"
+    (func $env_set
+      (type $env_set)))
+  (import "coral" "env_get"
+    (func $env_get
+      (type $env_get)))
+  (import "coral" "env_list"
+    (func $env_list
+      (type $env_list)))
+  (import "coral" "task_yield"
+    (func $task_yield
+      (type $task_yield)))
+  (import "coral" "task_sleep_ms"
+    (func $task_sleep_ms
+      (type $task_sleep_ms)))
+  (import "coral" "trace_read"
+    (func $trace_read
+      (type $capability_trace_read)))
+  (import "coral" "profile_enable"
+    (func $profile_enable
+      (type $profile_enable)))
+  (import "coral" "profile_read"
+    (func $profile_read
+      (type $trace_read)))
+  (import "coral" "sched_trace_enable"
+    (func $sched_trace_enable
+      (type $profile_enable)))
+  (import "coral" "sched_trace_read"
+    (func $sched_trace_read
+      (type $trace_read)))
+  (import "coral" "selftest_run"
+    (func $selftest_run
+      (type $trace_read)))
+  (import "coral" "system_stats"
+    (func $system_stats
+      (type $system_stats)))
+  (import "coral" "system_shutdown"
+    (func $system_shutdown
+      (type $system_power)))
+  (import "coral" "system_reboot"
+    (func $system_reboot
+      (type $system_power)))
   (import "coral" "handles"
-    (table $handles 2 4 externref))
+    (table $handles 3 16 externref))
 
   ;; Definitions
   (table $vma       4 externref)
   (table $module    4 externref)
+  (table $blob      4 externref)
   (table $component 4 externref)
   (global $nb_modules    (mut i32) (i32.const 0))
   (global $nb_components (mut i32) (i32.const 0))
+  (global $nb_blobs      (mut i32) (i32.const 0))
 
   (func $pub_vma_write
     (export "vma_write")
@@ -80,47 +499,187 @@
       local.get 4
       call $vma_write)
 
+  (func $pub_vma_read
+    (export "vma_read")
+    (type $pub_vma_read)
+      local.get 0
+      table.get $vma
+      local.get 1
+      local.get 2
+      local.get 3
+      call $vma_read)
+
+  (func $pub_vma_size
+    (export "vma_size")
+    (type $pub_vma_size)
+      local.get 0
+      table.get $vma
+      call $vma_size)
+
+  (func $pub_vma_seal
+    (export "vma_seal")
+    (type $pub_vma_seal)
+      local.get 0
+      table.get $vma
+      call $vma_seal)
+
+  (func $pub_vma_state
+    (export "vma_state")
+    (type $pub_vma_state)
+      local.get 0
+      table.get $vma
+      call $vma_state)
+
+  (func $pub_vma_watch
+    (export "vma_watch")
+    (type $pub_vma_watch)
+      local.get 0
+      table.get $vma
+      call $vma_watch)
+
+  (func $pub_vma_register
+    (export "vma_register")
+    (type $pub_vma_register)
+      local.get 0
+      table.get $component
+      local.get 1
+      call $vma_register)
+
   (func $pub_module_create
     (export "module_create")
     (type $pub_module_create)
-      ;; Prepare index in module table
-      global.get $nb_modules ;; return value
-      global.get $nb_modules ;; used by table.set
+    (local $handle externref)
+    (local $result i64)
+      ;; Execute syscall
+      local.get 0
+      table.get $vma
+      local.get 1
+      local.get 2
+      call $module_create
+      local.set $handle
+      local.set $result
+
+      ;; Store the module handle
+      global.get $nb_modules
+      local.get $handle
+      table.set $module
+
+      ;; Return the module index and the result
+      global.get $nb_modules
+      local.get $result
 
       ;; Increment number of modules
       global.get $nb_modules
       i32.const 1
       i32.add
-      global.set $nb_modules
+      global.set $nb_modules)
 
-      ;; Prepare syscall arguments & execute syscall
+  (func $pub_blob_from_vma
+    (export "blob_from_vma")
+    (type $pub_blob_from_vma)
+    (local $handle externref)
+    (local $result i64)
+      ;; Execute syscall
       local.get 0
       table.get $vma
       local.get 1
       local.get 2
+      call $blob_from_vma
+      local.set $handle
+      local.set $result
+
+      ;; Store the blob handle
+      global.get $nb_blobs
+      local.get $handle
+      table.set $blob
+
+      ;; Return the blob index and the result
+      global.get $nb_blobs
+      local.get $result
+
+      ;; Increment number of blobs
+      global.get $nb_blobs
+      i32.const 1
+      i32.add
+      global.set $nb_blobs)
+
+  (func $pub_module_create_from_blob
+    (export "module_create_from_blob")
+    (type $pub_module_create)
+    (local $handle externref)
+    (local $result i64)
+      ;; Execute syscall
+      local.get 0
+      table.get $blob
+      local.get 1
+      local.get 2
       call $module_create
+      local.set $handle
+      local.set $result
 
       ;; Store the module handle
-      table.set $module)
+      global.get $nb_modules
+      local.get $handle
+      table.set $module
 
-  (func $pub_component_create
-    (export "component_create")
-    (type $pub_component_create)
-      ;; Prepare index in module table
-      global.get $nb_components ;; return value
-      global.get $nb_components ;; used by table.set
+      ;; Return the module index and the result
+      global.get $nb_modules
+      local.get $result
 
-      ;; Increment number of components
+      ;; Increment number of modules
       global.get $nb_modules
       i32.const 1
       i32.add
-      global.set $nb_components
+      global.set $nb_modules)
+
+  (func $pub_module_status
+    (export "module_status")
+    (type $pub_module_status)
+      local.get 0
+      table.get $module
+      call $module_status)
+
+  (func $pub_module_info
+    (export "module_info")
+    (type $pub_module_info)
+      local.get 0
+      table.get $module
+      local.get 1
+      local.get 2
+      call $module_info)
 
+  (func $pub_module_register
+    (export "module_register")
+    (type $pub_module_register)
+      local.get 0
+      table.get $component
+      local.get 1
+      call $module_register)
+
+  (func $pub_component_create
+    (export "component_create")
+    (type $pub_component_create)
+    (local $handle externref)
+    (local $result i64)
       ;; Execute syscall
       call $component_create
+      local.set $handle
+      local.set $result
+
+      ;; Store the component handle
+      global.get $nb_components
+      local.get $handle
+      table.set $component
 
-      ;; Store component handle
-      table.set $component)
+      ;; Return the component index and the result
+      global.get $nb_components
+      local.get $result
+
+      ;; Increment number of components
+      global.get $nb_components
+      i32.const 1
+      i32.add
+      global.set $nb_components)
 
   (func $pub_component_add_instance
     (export "component_add_instance")
@@ -131,4 +690,261 @@
       table.get $module
       call $component_add_instance
     )
+
+  (func $pub_component_spawn
+    (export "component_spawn")
+    (type $pub_component_spawn)
+    (local $handle externref)
+    (local $result i64)
+      ;; Execute syscall
+      local.get 0
+      table.get $module
+      local.get 1
+      local.get 2
+      call $component_spawn
+      local.set $handle
+      local.set $result
+
+      ;; Store the component handle
+      global.get $nb_components
+      local.get $handle
+      table.set $component
+
+      ;; Return the component index and the result
+      global.get $nb_components
+      local.get $result
+
+      ;; Increment number of components
+      global.get $nb_components
+      i32.const 1
+      i32.add
+      global.set $nb_components)
+
+  (func $pub_component_add_native_module
+    (export "component_add_native_module")
+    (type $pub_component_add_native_module)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      call $component_add_native_module)
+
+  (func $pub_component_push_import
+    (export "component_push_import")
+    (type $pub_component_push_import)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      local.get 3
+      call $component_push_import)
+
+  (func $pub_component_trace
+    (export "component_trace")
+    (type $pub_component_trace)
+      local.get 0
+      table.get $component
+      local.get 1
+      call $component_trace)
+
+  (func $pub_component_exit_status
+    (export "component_exit_status")
+    (type $pub_component_exit_status)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      call $component_exit_status)
+
+  (func $pub_component_start_status
+    (export "component_start_status")
+    (type $pub_component_start_status)
+      local.get 0
+      table.get $component
+      local.get 1
+      call $component_start_status)
+
+  (func $pub_component_metrics
+    (export "component_metrics")
+    (type $pub_component_metrics)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      call $component_metrics)
+
+  (func $pub_instance_fork
+    (export "instance_fork")
+    (type $pub_instance_fork)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      table.get $component
+      call $instance_fork)
+
+  (func $pub_component_reload_instance
+    (export "component_reload_instance")
+    (type $pub_component_reload_instance)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      table.get $module
+      call $component_reload_instance)
+
+  (func $pub_self_trace
+    (export "self_trace")
+    (type $pub_self_trace)
+      ;; The handle to our own component is at index 1 in the handles table
+      i32.const 1
+      table.get $handles
+      local.get 0
+      call $component_trace)
+
+  (func $pub_pointer_register
+    (export "pointer_register")
+    (type $pub_pointer_register)
+      local.get 0
+      table.get $component
+      local.get 1
+      call $pointer_register)
+
+  (func $pub_env_set
+    (export "env_set")
+    (type $pub_env_set)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      local.get 3
+      local.get 4
+      call $env_set)
+
+  (func $pub_env_get
+    (export "env_get")
+    (type $pub_env_get)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      local.get 3
+      local.get 4
+      call $env_get)
+
+  (func $pub_self_env_get
+    (export "self_env_get")
+    (type $pub_self_env_get)
+      ;; The handle to our own component is at index 1 in the handles table
+      i32.const 1
+      table.get $handles
+      local.get 0
+      local.get 1
+      local.get 2
+      local.get 3
+      call $env_get)
+
+  (func $pub_env_list
+    (export "env_list")
+    (type $pub_env_list)
+      local.get 0
+      table.get $component
+      local.get 1
+      local.get 2
+      call $env_list)
+
+  (func $pub_self_env_list
+    (export "self_env_list")
+    (type $pub_self_env_list)
+      ;; The handle to our own component is at index 1 in the handles table
+      i32.const 1
+      table.get $handles
+      local.get 0
+      local.get 1
+      call $env_list)
+
+  (func $pub_trace_read
+    (export "trace_read")
+    (type $pub_trace_read)
+      ;; The trace capability is at index 3 in the handles table
+      i32.const 3
+      table.get $handles
+      local.get 0
+      table.get $vma
+      local.get 1
+      local.get 2
+      call $trace_read)
+
+  (func $pub_profile_enable
+    (export "profile_enable")
+    (type $profile_enable)
+      local.get 0
+      call $profile_enable)
+
+  (func $pub_profile_read
+    (export "profile_read")
+    (type $pub_trace_read)
+      local.get 0
+      table.get $vma
+      local.get 1
+      local.get 2
+      call $profile_read)
+
+  (func $pub_sched_trace_enable
+    (export "sched_trace_enable")
+    (type $profile_enable)
+      local.get 0
+      call $sched_trace_enable)
+
+  (func $pub_sched_trace_read
+    (export "sched_trace_read")
+    (type $pub_trace_read)
+      local.get 0
+      table.get $vma
+      local.get 1
+      local.get 2
+      call $sched_trace_read)
+
+  (func $pub_selftest_run
+    (export "selftest_run")
+    (type $pub_trace_read)
+      local.get 0
+      table.get $vma
+      local.get 1
+      local.get 2
+      call $selftest_run)
+
+  (func $pub_task_yield
+    (export "task_yield")
+    (type $task_yield)
+      call $task_yield)
+
+  (func $pub_task_sleep_ms
+    (export "task_sleep_ms")
+    (type $task_sleep_ms)
+      local.get 0
+      call $task_sleep_ms)
+
+  (func $pub_system_stats
+    (export "system_stats")
+    (type $system_stats)
+      local.get 0
+      local.get 1
+      call $system_stats)
+
+  (func $pub_system_shutdown
+    (export "system_shutdown")
+    (type $pub_system_power)
+      ;; The power capability is at index 2 in the handles table
+      i32.const 2
+      table.get $handles
+      call $system_shutdown)
+
+  (func $pub_system_reboot
+    (export "system_reboot")
+    (type $pub_system_power)
+      ;; The power capability is at index 2 in the handles table
+      i32.const 2
+      table.get $handles
+      call $system_reboot)
 )